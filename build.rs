@@ -0,0 +1,286 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[path = "build/fat.rs"]
+mod fat;
+
+// 测试用磁盘镜像的大小和第0扇区的固定内容, 需与tests/block_device.rs保持一致
+const IMAGE_SECTORS: usize = 2048;
+const SECTOR_SIZE: usize = 512;
+const SIGNATURE: &[u8] = b"TOY_OS ATA TEST IMAGE";
+// ATA测试盘在这些扇区之后附加一个FAT16卷, 需与tests/block_device.rs保持一致
+const FAT16_SECTORS: u32 = 16384;
+// FAT32至少需要65525个簇
+const FAT32_SECTORS: u32 = 70000;
+// 测试盘上的内核日志区域: 第1扇区是区域头, 后半部分是数据扇区, 格式见src/klog/record.rs
+const KLOG_HEADER_LBA: usize = 1;
+const KLOG_START: u64 = 1024;
+const KLOG_SECTORS: u64 = IMAGE_SECTORS as u64 - KLOG_START;
+// user模式网络内置TFTP服务器提供的文件, 需与tests/net.rs保持一致
+const TFTP_FILE: (&str, &[u8]) = ("hello.txt", b"hello from the host\n");
+// guestfwd命令输出的HTTP响应, 响应体需与tests/net.rs保持一致
+const HTTP_RESPONSE: &[u8] =
+    b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 15\r\n\r\nhello over tcp\n";
+// 打包进initrd的目录
+const INITRD_DIR: &str = "initrd";
+// 用户程序, 构建后放入initrd的/bin
+const USER_PROGRAMS: &[&str] = &["hello", "forktest", "mmaptest", "crash", "limittest"];
+const USER_TARGET: &str = "x86_64-unknown-none";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=build/fat.rs");
+    println!("cargo:rerun-if-changed={}", INITRD_DIR);
+    println!("cargo:rerun-if-changed=userspace");
+    emit_build_info();
+
+    let mut image = vec![0u8; IMAGE_SECTORS * SECTOR_SIZE];
+    image[..SIGNATURE.len()].copy_from_slice(SIGNATURE);
+    for (i, byte) in image[SIGNATURE.len()..SECTOR_SIZE].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let header = &mut image[KLOG_HEADER_LBA * SECTOR_SIZE..][..SECTOR_SIZE];
+    header.copy_from_slice(&klog_header(KLOG_START, KLOG_SECTORS));
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target");
+    fs::create_dir_all(&dir).expect("failed to create target directory");
+    fs::write(dir.join("virtio-test.img"), &image).expect("failed to write test disk image");
+    let fat16 = fat::image(fat::FatType::Fat16, FAT16_SECTORS, &fat::fixture_tree());
+    fs::write(dir.join("ata-test.img"), [image, fat16.clone()].concat())
+        .expect("failed to write test disk image");
+
+    let tftp = dir.join("tftp");
+    fs::create_dir_all(&tftp).expect("failed to create tftp directory");
+    fs::write(tftp.join(TFTP_FILE.0), TFTP_FILE.1).expect("failed to write tftp file");
+    fs::write(dir.join("http-response.txt"), HTTP_RESPONSE).expect("failed to write http response");
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR not set"));
+    let mut entries = Vec::new();
+    collect_initrd(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join(INITRD_DIR),
+        "",
+        &mut entries,
+    );
+    if std::env::var_os("CARGO_FEATURE_INITRD").is_some() {
+        entries.push(TarEntry::Dir("bin/".into()));
+        for name in USER_PROGRAMS {
+            let binary = build_user_program(name, &out_dir.join("userspace"));
+            entries.push(TarEntry::File(format!("bin/{}", name), binary));
+        }
+    }
+    fs::write(out_dir.join("initrd.tar"), tar(&entries)).expect("failed to write initrd");
+    fs::write(out_dir.join("ramfs-fixture.tar"), tar(&ramfs_fixture()))
+        .expect("failed to write ramfs fixture");
+
+    // 单元测试用的FAT镜像大部分是0, 只保存非零扇区
+    let fat32 = fat::image(fat::FatType::Fat32, FAT32_SECTORS, &fat::fixture_tree());
+    for (name, image) in [("fat16-fixture.bin", &fat16), ("fat32-fixture.bin", &fat32)] {
+        fs::write(out_dir.join(name), fat::sparse(image)).expect("failed to write FAT fixture");
+    }
+}
+
+// 会话号为0的空日志区域, 与src/klog/record.rs中的Header::encode相同
+fn klog_header(start: u64, sectors: u64) -> [u8; SECTOR_SIZE] {
+    let mut sector = [0u8; SECTOR_SIZE];
+    sector[..8].copy_from_slice(b"TOYOSKLG");
+    sector[8..12].copy_from_slice(&1u32.to_le_bytes());
+    sector[12..20].copy_from_slice(&start.to_le_bytes());
+    sector[20..28].copy_from_slice(&sectors.to_le_bytes());
+    let crc = crc32(&sector[..32]);
+    sector[32..36].copy_from_slice(&crc.to_le_bytes());
+    sector
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// 启动横幅使用的git提交和构建时间, 见src/version.rs
+fn emit_build_info() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // 切换分支或提交后重新生成
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let hash = Command::new("git")
+        .current_dir(root)
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=TOY_OS_GIT_HASH={}", hash);
+
+    // 可复现构建时使用SOURCE_DATE_EPOCH
+    let seconds = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        });
+    println!("cargo:rustc-env=TOY_OS_BUILD_DATE={}", format_utc(seconds));
+    println!("cargo:rustc-env=TOY_OS_BUILD_EPOCH={}", seconds);
+}
+
+// 按UTC格式化为`YYYY-MM-DD HH:MM UTC`
+fn format_utc(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let minutes = seconds % 86400 / 60;
+    // 从1970-01-01起的天数换算为公历日期
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+// 用内核使用的工具链构建userspace/<name>, 返回ELF文件的内容
+fn build_user_program(name: &str, target_dir: &Path) -> Vec<u8> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("userspace").join(name);
+    // 内核的编译参数和clippy包装不能传给用户程序
+    let status = Command::new(cargo)
+        .current_dir(&dir)
+        .args(["build", "--release", "--target", USER_TARGET, "--target-dir"])
+        .arg(target_dir)
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("RUSTFLAGS")
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .status()
+        .expect("failed to run cargo for user program");
+    assert!(status.success(), "failed to build user program {}", name);
+    let binary = target_dir.join(USER_TARGET).join("release").join(name);
+    fs::read(&binary).expect("failed to read user program")
+}
+
+enum TarEntry {
+    File(String, Vec<u8>),
+    Dir(String),
+    Symlink(String, String),
+}
+
+// 按名称排序递归收集目录中的文件, 路径相对于initrd的根
+fn collect_initrd(dir: &Path, prefix: &str, entries: &mut Vec<TarEntry>) {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut children: Vec<_> = read_dir
+        .map(|entry| entry.expect("failed to read initrd").path())
+        .collect();
+    children.sort();
+    for child in children {
+        let name = child
+            .file_name()
+            .unwrap()
+            .to_str()
+            .expect("non-UTF-8 initrd path");
+        let path = format!("{}{}", prefix, name);
+        if child.is_dir() {
+            entries.push(TarEntry::Dir(format!("{}/", path)));
+            collect_initrd(&child, &format!("{}/", path), entries);
+        } else {
+            entries.push(TarEntry::File(
+                path,
+                fs::read(&child).expect("failed to read initrd file"),
+            ));
+        }
+    }
+}
+
+// 单元测试使用的归档, 需与src/ramfs.rs中的测试保持一致
+fn ramfs_fixture() -> Vec<TarEntry> {
+    let big = (0..1300).map(|i| (i % 251) as u8).collect();
+    let long_dir = format!("long/{}", "d".repeat(120));
+    vec![
+        TarEntry::Dir("./docs/".into()),
+        TarEntry::File("./docs/readme.txt".into(), b"ramfs fixture\n".to_vec()),
+        // 没有单独的目录项, 需要自动创建中间目录
+        TarEntry::File("./docs/nested/deep/note.txt".into(), b"deep\n".to_vec()),
+        TarEntry::File("empty".into(), Vec::new()),
+        TarEntry::File("data/big.bin".into(), big),
+        // 超过100字节的路径使用prefix字段
+        TarEntry::File(format!("{}/file.txt", long_dir), b"long\n".to_vec()),
+        TarEntry::Symlink("docs/link".into(), "readme.txt".into()),
+    ]
+}
+
+// 生成ustar格式的归档
+fn tar(entries: &[TarEntry]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for entry in entries {
+        let (path, data, typeflag, link): (&str, &[u8], u8, &str) = match entry {
+            TarEntry::File(path, data) => (path, data, b'0', ""),
+            TarEntry::Dir(path) => (path, &[], b'5', ""),
+            TarEntry::Symlink(path, target) => (path, &[], b'2', target),
+        };
+        archive.extend_from_slice(&tar_header(path, data.len(), typeflag, link));
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(512), 0);
+    }
+    // 以两个全零的块结束
+    archive.resize(archive.len() + 1024, 0);
+    archive
+}
+
+fn tar_header(path: &str, size: usize, typeflag: u8, link: &str) -> [u8; 512] {
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let split = path.rfind('/').expect("path too long for ustar");
+        (&path[..split], &path[split + 1..])
+    };
+    assert!(
+        prefix.len() <= 155 && name.len() <= 100,
+        "path too long for ustar: {}",
+        path
+    );
+
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    let mode = if typeflag == b'5' { 0o755 } else { 0o644 };
+    header[100..108].copy_from_slice(format!("{:07o}\0", mode).as_bytes());
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = typeflag;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // 校验和按校验和字段全为空格计算
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
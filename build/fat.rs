@@ -0,0 +1,404 @@
+// 生成测试用的FAT16/FAT32文件系统镜像, 目录内容需与src/fat.rs中的测试保持一致
+
+const SECTOR_SIZE: usize = 512;
+const DIR_ENTRY_SIZE: usize = 32;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+// 短文件名的主名和扩展名为小写(Windows NT的扩展)
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+pub enum Node {
+    File(String, Vec<u8>),
+    Dir(String, Vec<Node>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// 测试镜像中的目录树
+pub fn fixture_tree() -> Vec<Node> {
+    let long_data = (0..3000u32).map(|i| (i * 7 % 256) as u8).collect();
+    let many = (0..20)
+        .map(|i| {
+            Node::File(
+                format!("FILE{:02}.TXT", i),
+                format!("file {}\n", i).into_bytes(),
+            )
+        })
+        .collect();
+    vec![
+        Node::File("HELLO.TXT".into(), b"hello from fat\n".to_vec()),
+        Node::File("notes.txt".into(), b"lowercase short name\n".to_vec()),
+        Node::File(
+            "Long File Name.txt".into(),
+            b"this file has a long name\n".to_vec(),
+        ),
+        Node::File("EMPTY.TXT".into(), Vec::new()),
+        Node::Dir(
+            "DOCS".into(),
+            vec![
+                Node::File("README.TXT".into(), b"docs readme\n".to_vec()),
+                Node::File(
+                    "A much longer name that needs several LFN entries.bin".into(),
+                    long_data,
+                ),
+                Node::Dir(
+                    "SUB".into(),
+                    vec![Node::File("NOTE.TXT".into(), b"nested note\n".to_vec())],
+                ),
+            ],
+        ),
+        // 超过一个簇的目录
+        Node::Dir("MANY".into(), many),
+        Node::File("README.TXT".into(), b"fat volume readme\n".to_vec()),
+    ]
+}
+
+/// 格式化`sectors`个扇区的卷并写入`tree`, 每簇一个扇区
+pub fn image(fat_type: FatType, sectors: u32, tree: &[Node]) -> Vec<u8> {
+    let mut volume = Volume::format(fat_type, sectors);
+    let root = match fat_type {
+        FatType::Fat16 => None,
+        FatType::Fat32 => Some(volume.allocate(directory_len(tree).div_ceil(SECTOR_SIZE))),
+    };
+    volume.write_directory(tree, root.as_deref(), None);
+    volume.image
+}
+
+/// 只保存非零扇区的紧凑格式: "SPRS", 总扇区数, 非零扇区数, 然后是(LBA, 扇区内容)
+pub fn sparse(image: &[u8]) -> Vec<u8> {
+    let sectors: Vec<(u32, &[u8])> = image
+        .chunks(SECTOR_SIZE)
+        .enumerate()
+        .filter(|(_, sector)| sector.iter().any(|&b| b != 0))
+        .map(|(lba, sector)| (lba as u32, sector))
+        .collect();
+    let mut out = b"SPRS".to_vec();
+    out.extend_from_slice(&((image.len() / SECTOR_SIZE) as u32).to_le_bytes());
+    out.extend_from_slice(&(sectors.len() as u32).to_le_bytes());
+    for (lba, sector) in sectors {
+        out.extend_from_slice(&lba.to_le_bytes());
+        out.extend_from_slice(sector);
+    }
+    out
+}
+
+struct Volume {
+    fat_type: FatType,
+    image: Vec<u8>,
+    fat_start: usize,
+    fat_sectors: usize,
+    root_start: usize,
+    data_start: usize,
+    next_cluster: u32,
+}
+
+impl Volume {
+    fn format(fat_type: FatType, sectors: u32) -> Volume {
+        let (reserved, root_entries, entry_size) = match fat_type {
+            FatType::Fat16 => (1, 512, 2),
+            FatType::Fat32 => (32, 0, 4),
+        };
+        let fat_sectors = ((sectors as usize + 2) * entry_size).div_ceil(SECTOR_SIZE);
+        let root_sectors = root_entries * DIR_ENTRY_SIZE / SECTOR_SIZE;
+        let root_start = reserved + 2 * fat_sectors;
+        let mut volume = Volume {
+            fat_type,
+            image: vec![0; sectors as usize * SECTOR_SIZE],
+            fat_start: reserved,
+            fat_sectors,
+            root_start,
+            data_start: root_start + root_sectors,
+            next_cluster: 2,
+        };
+
+        let boot = &mut volume.image[..SECTOR_SIZE];
+        boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        boot[3..11].copy_from_slice(b"TOYOS   ");
+        boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot[13] = 1;
+        boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+        boot[21] = 0xF8;
+        boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+        boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+        match fat_type {
+            FatType::Fat16 if sectors < 0x10000 => {
+                boot[19..21].copy_from_slice(&(sectors as u16).to_le_bytes())
+            }
+            _ => boot[32..36].copy_from_slice(&sectors.to_le_bytes()),
+        }
+        let ext = match fat_type {
+            FatType::Fat16 => {
+                boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+                36
+            }
+            FatType::Fat32 => {
+                boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+                // 根目录的簇号, FSInfo和备份引导扇区的位置
+                boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+                boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+                boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+                64
+            }
+        };
+        boot[ext] = 0x80;
+        boot[ext + 2] = 0x29;
+        boot[ext + 3..ext + 7].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        boot[ext + 7..ext + 18].copy_from_slice(b"TOYOS FAT  ");
+        let label: &[u8] = if fat_type == FatType::Fat16 {
+            b"FAT16   "
+        } else {
+            b"FAT32   "
+        };
+        boot[ext + 18..ext + 26].copy_from_slice(label);
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+
+        if fat_type == FatType::Fat32 {
+            let fsinfo = &mut volume.image[SECTOR_SIZE..2 * SECTOR_SIZE];
+            fsinfo[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+            fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+            fsinfo[488..496].copy_from_slice(&[0xFF; 8]);
+            fsinfo[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+            let boot: Vec<u8> = volume.image[..2 * SECTOR_SIZE].to_vec();
+            volume.image[6 * SECTOR_SIZE..8 * SECTOR_SIZE].copy_from_slice(&boot);
+        }
+
+        // 第0、1项保存介质类型和结束标记
+        volume.set_fat(0, 0x0FFF_FFF8);
+        volume.set_fat(1, 0x0FFF_FFFF);
+        volume
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    // 两份FAT同时写入
+    fn set_fat(&mut self, cluster: u32, value: u32) {
+        for copy in 0..2 {
+            let base = (self.fat_start + copy * self.fat_sectors) * SECTOR_SIZE;
+            match self.fat_type {
+                FatType::Fat16 => {
+                    let offset = base + cluster as usize * 2;
+                    self.image[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                }
+                FatType::Fat32 => {
+                    let offset = base + cluster as usize * 4;
+                    self.image[offset..offset + 4]
+                        .copy_from_slice(&(value & 0x0FFF_FFFF).to_le_bytes());
+                }
+            }
+        }
+    }
+
+    // 分配`count`个簇并链接起来, 每隔一个簇分配一次, 使簇链不连续
+    fn allocate(&mut self, count: usize) -> Vec<u32> {
+        let clusters: Vec<u32> = (0..count as u32)
+            .map(|i| self.next_cluster + 2 * i)
+            .collect();
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let next = clusters.get(i + 1).copied().unwrap_or(self.end_of_chain());
+            self.set_fat(cluster, next);
+        }
+        self.next_cluster += 2 * count as u32;
+        clusters
+    }
+
+    fn write_clusters(&mut self, clusters: &[u32], data: &[u8]) {
+        for (&cluster, chunk) in clusters.iter().zip(data.chunks(SECTOR_SIZE)) {
+            let offset = (self.data_start + (cluster as usize - 2)) * SECTOR_SIZE;
+            self.image[offset..offset + chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    // `clusters`为None表示FAT16的固定根目录; `parent`为None表示根目录, 否则是".."指向的簇
+    fn write_directory(
+        &mut self,
+        children: &[Node],
+        clusters: Option<&[u32]>,
+        parent: Option<u32>,
+    ) {
+        let own = clusters.map_or(0, |clusters| clusters[0]);
+        let mut bytes = Vec::new();
+        if let Some(parent) = parent {
+            bytes.extend(dir_entry(b".          ", ATTR_DIRECTORY, 0, own, 0));
+            bytes.extend(dir_entry(b"..         ", ATTR_DIRECTORY, 0, parent, 0));
+        }
+
+        let mut names = Vec::new();
+        for child in children {
+            let (name, first, size, attr) = match child {
+                Node::File(name, data) if data.is_empty() => (name, 0, 0, ATTR_ARCHIVE),
+                Node::File(name, data) => {
+                    let clusters = self.allocate(data.len().div_ceil(SECTOR_SIZE));
+                    self.write_clusters(&clusters, data);
+                    (name, clusters[0], data.len() as u32, ATTR_ARCHIVE)
+                }
+                Node::Dir(name, grandchildren) => {
+                    let clusters =
+                        self.allocate(directory_len(grandchildren).div_ceil(SECTOR_SIZE));
+                    // 指向根目录的".."写0, FAT32也是如此
+                    self.write_directory(
+                        grandchildren,
+                        Some(&clusters),
+                        Some(parent.map_or(0, |_| own)),
+                    );
+                    (name, clusters[0], 0, ATTR_DIRECTORY)
+                }
+            };
+            let (short, case, lfn) = short_name(name, &mut names);
+            if lfn {
+                bytes.extend(lfn_entries(name, checksum(&short)).concat());
+            }
+            bytes.extend(dir_entry(&short, attr, case, first, size));
+        }
+
+        match clusters {
+            Some(clusters) => {
+                assert!(bytes.len() <= clusters.len() * SECTOR_SIZE);
+                self.write_clusters(clusters, &bytes);
+            }
+            None => {
+                assert!(bytes.len() <= (self.data_start - self.root_start) * SECTOR_SIZE);
+                let offset = self.root_start * SECTOR_SIZE;
+                self.image[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            }
+        }
+    }
+}
+
+// 目录内容的字节数, 包括"."和".."
+fn directory_len(children: &[Node]) -> usize {
+    let mut names = Vec::new();
+    let entries: usize = children
+        .iter()
+        .map(|child| {
+            let name = match child {
+                Node::File(name, _) | Node::Dir(name, _) => name,
+            };
+            let (_, _, lfn) = short_name(name, &mut names);
+            1 + if lfn { lfn_count(name) } else { 0 }
+        })
+        .sum();
+    (entries + 2) * DIR_ENTRY_SIZE
+}
+
+// 返回11字节的短文件名、大小写标志以及是否需要长文件名项
+fn short_name(name: &str, used: &mut Vec<[u8; 11]>) -> ([u8; 11], u8, bool) {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let valid = |part: &str, max: usize| {
+        !part.is_empty()
+            && part.len() <= max
+            && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    };
+    let fits = valid(base, 8) && (ext.is_empty() || valid(ext, 3));
+    let uniform = |part: &str| {
+        if part.bytes().all(|b| !b.is_ascii_lowercase()) {
+            Some(false)
+        } else if part.bytes().all(|b| !b.is_ascii_uppercase()) {
+            Some(true)
+        } else {
+            None
+        }
+    };
+    let mut short = [b' '; 11];
+    if fits {
+        if let (Some(lower_base), Some(lower_ext)) = (uniform(base), uniform(ext)) {
+            short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+            short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+            let case = if lower_base { CASE_LOWER_BASE } else { 0 }
+                | if lower_ext { CASE_LOWER_EXT } else { 0 };
+            used.push(short);
+            return (short, case, false);
+        }
+    }
+
+    // 生成"主名前6个字符~n"形式的短文件名
+    let clean = |part: &str| -> Vec<u8> {
+        part.bytes()
+            .filter(|b| b.is_ascii_alphanumeric())
+            .map(|b| b.to_ascii_uppercase())
+            .collect()
+    };
+    let base = clean(base);
+    let ext = clean(ext);
+    let ext_len = ext.len().min(3);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut candidate = short;
+        candidate[..8].fill(b' ');
+        candidate[..keep].copy_from_slice(&base[..keep]);
+        candidate[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !used.contains(&candidate) {
+            used.push(candidate);
+            return (candidate, 0, true);
+        }
+    }
+    unreachable!()
+}
+
+fn checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+fn lfn_count(name: &str) -> usize {
+    name.encode_utf16().count().div_ceil(13)
+}
+
+// 按目录中的顺序返回长文件名项, 序号最大的在前
+fn lfn_entries(name: &str, checksum: u8) -> Vec<Vec<u8>> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = lfn_count(name);
+    // 未占满最后一项时以0结尾, 其余填充0xFFFF
+    if units.len() < count * 13 {
+        units.push(0);
+    }
+    units.resize(count * 13, 0xFFFF);
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = vec![0u8; DIR_ENTRY_SIZE];
+            entry[0] = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+            entry[11] = ATTR_LFN;
+            entry[13] = checksum;
+            let chunk = &units[i * 13..i * 13 + 13];
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2));
+            for (offset, unit) in offsets.zip(chunk) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+fn dir_entry(short: &[u8; 11], attr: u8, case: u8, cluster: u32, size: u32) -> Vec<u8> {
+    let mut entry = vec![0u8; DIR_ENTRY_SIZE];
+    entry[..11].copy_from_slice(short);
+    entry[11] = attr;
+    entry[12] = case;
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
@@ -0,0 +1,154 @@
+// 把内核ELF中的函数符号写入预留的.ksyms段, 格式见src/symbols.rs. 用法: ksyms <kernel>
+//
+// 段的大小和位置在链接时已确定, 原地写入不会移动任何地址. 失败时只给出警告,
+// 内核在没有符号表时输出原始地址
+use std::{env, fs, process};
+
+const SECTION: &str = ".ksyms";
+const ANCHOR: &str = "ksyms_anchor";
+const MAGIC: &[u8; 4] = b"KSYM";
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+struct Section {
+    name: u32,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    if elf.get(..4) != Some(b"\x7fELF") || elf.get(4) != Some(&2) {
+        return Err("not an ELF64 file".into());
+    }
+    let offset = u64_at(elf, 0x28) as usize;
+    let entry_size = u16_at(elf, 0x3a) as usize;
+    let count = u16_at(elf, 0x3c) as usize;
+    (0..count)
+        .map(|i| {
+            let header = elf
+                .get(offset + i * entry_size..offset + (i + 1) * entry_size)
+                .ok_or("truncated section headers")?;
+            Ok(Section {
+                name: u32_at(header, 0),
+                kind: u32_at(header, 4),
+                offset: u64_at(header, 24) as usize,
+                size: u64_at(header, 32) as usize,
+                link: u32_at(header, 40) as usize,
+            })
+        })
+        .collect()
+}
+
+fn c_str(data: &[u8], offset: usize) -> &[u8] {
+    let tail = data.get(offset..).unwrap_or_default();
+    &tail[..tail.iter().position(|&b| b == 0).unwrap_or(tail.len())]
+}
+
+fn contents<'a>(elf: &'a [u8], section: &Section) -> Result<&'a [u8], String> {
+    elf.get(section.offset..section.offset + section.size)
+        .ok_or_else(|| "section outside the file".into())
+}
+
+// 生成符号表, 返回.ksyms段在文件中的偏移和填满整个段的内容
+fn build(elf: &[u8]) -> Result<(usize, Vec<u8>), String> {
+    let sections = sections(elf)?;
+    let names = sections
+        .get(u16_at(elf, 0x3e) as usize)
+        .ok_or("bad section name table index")?;
+    let names = contents(elf, names)?;
+    let target = sections
+        .iter()
+        .find(|section| c_str(names, section.name as usize) == SECTION.as_bytes())
+        .ok_or("no .ksyms section")?;
+    let symtab = sections
+        .iter()
+        .find(|section| section.kind == SHT_SYMTAB)
+        .ok_or("no symbol table, is the kernel stripped?")?;
+    let strtab = contents(elf, sections.get(symtab.link).ok_or("bad strtab index")?)?;
+
+    let mut anchor = None;
+    let mut symbols = Vec::new();
+    for symbol in contents(elf, symtab)?.chunks_exact(SYMBOL_SIZE) {
+        let name = c_str(strtab, u32_at(symbol, 0) as usize);
+        let value = u64_at(symbol, 8);
+        if symbol[4] & 0xf != STT_FUNC || value == 0 || name.is_empty() {
+            continue;
+        }
+        if name == ANCHOR.as_bytes() {
+            anchor = Some(value);
+        }
+        symbols.push((value, name));
+    }
+    let anchor = anchor.ok_or("no ksyms_anchor symbol")?;
+    // 同一地址有多个名称时保留最短的一个
+    symbols.sort_by_key(|&(value, name)| (value, name.len()));
+    symbols.dedup_by_key(|&mut (value, _)| value);
+
+    let strings_len: usize = symbols.iter().map(|(_, name)| name.len()).sum();
+    let mut table = Vec::with_capacity(target.size);
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(strings_len as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    table.extend_from_slice(&anchor.to_le_bytes());
+    let mut offset = 0u32;
+    for (value, name) in &symbols {
+        table.extend_from_slice(&value.to_le_bytes());
+        table.extend_from_slice(&offset.to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        offset += name.len() as u32;
+    }
+    for (_, name) in &symbols {
+        table.extend_from_slice(name);
+    }
+    if table.len() > target.size {
+        return Err(format!(
+            "{} symbols need {} bytes but .ksyms has {}",
+            symbols.len(),
+            table.len(),
+            target.size
+        ));
+    }
+    // 覆盖上一次写入的内容
+    table.resize(target.size, 0);
+    Ok((target.offset, table))
+}
+
+fn main() {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("usage: ksyms <kernel>");
+        process::exit(2);
+    };
+    let mut elf = fs::read(&path).unwrap_or_else(|err| {
+        eprintln!("ksyms: {}: {}", path, err);
+        process::exit(1);
+    });
+    match build(&elf) {
+        Ok((offset, table)) => {
+            elf[offset..offset + table.len()].copy_from_slice(&table);
+            if let Err(err) = fs::write(&path, &elf) {
+                eprintln!("ksyms: {}: {}", path, err);
+                process::exit(1);
+            }
+        }
+        Err(err) => eprintln!(
+            "ksyms: {}: {}, backtraces will show raw addresses",
+            path, err
+        ),
+    }
+}
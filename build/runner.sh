@@ -0,0 +1,10 @@
+#!/bin/sh
+# cargo run/test的runner: 先把符号表写入内核的.ksyms段, 再交给bootimage启动
+set -e
+dir="$(cd "$(dirname "$0")/.." && pwd)"
+tool="$dir/target/ksyms"
+if [ ! -x "$tool" ] || [ "$dir/build/ksyms.rs" -nt "$tool" ]; then
+    rustc --edition 2021 -O -o "$tool" "$dir/build/ksyms.rs"
+fi
+"$tool" "$1"
+exec bootimage runner "$@"
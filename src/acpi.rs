@@ -0,0 +1,706 @@
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::bootmem::{self, BootMem};
+use crate::memory;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// ACPI 1.0的RSDP长度, 2.0起扩展到36字节
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_V2_LENGTH: usize = 36;
+const HEADER_LENGTH: usize = 36;
+
+// BIOS数据区中保存EBDA段地址的位置
+const EBDA_POINTER: u64 = 0x40E;
+const EBDA_SCAN_LENGTH: usize = 1024;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+// MADT条目类型
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+// FADT字段偏移
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INTERRUPT: usize = 46;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+const FADT_CENTURY: usize = 108;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REGISTER: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const FADT_X_DSDT: usize = 140;
+const FADT_RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+// HPET表字段偏移
+const HPET_EVENT_TIMER_BLOCK_ID: usize = 36;
+const HPET_BASE_ADDRESS: usize = 40;
+const HPET_NUMBER: usize = 52;
+const HPET_MIN_TICK: usize = 53;
+const HPET_LENGTH: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    BadChecksum([u8; 4]),
+    BadSignature,
+    Truncated,
+}
+
+/// 根系统描述指针
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub rsdt_address: u32,
+    /// ACPI 2.0起才有
+    pub xsdt_address: Option<u64>,
+}
+
+/// 通用地址结构
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// ISA中断到全局系统中断的重映射
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    /// 极性和触发方式
+    pub flags: u16,
+}
+
+/// 各条目列表在堆初始化之前分配, 来自bootmem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MadtInfo {
+    pub local_apic_address: u64,
+    /// 是否同时存在8259 PIC
+    pub pcat_compat: bool,
+    pub processors: &'static [LocalApic],
+    pub io_apics: &'static [IoApic],
+    pub overrides: &'static [InterruptOverride],
+}
+
+impl MadtInfo {
+    /// ISA IRQ对应的全局系统中断, 没有重映射时两者相同
+    pub fn gsi_for_irq(&self, irq: u8) -> u32 {
+        self.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .map_or(u32::from(irq), |o| o.gsi)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FadtInfo {
+    pub dsdt_address: u64,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    /// CMOS中世纪寄存器的索引, 0表示不存在
+    pub century: u8,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// HPET表描述的一个定时器块
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetInfo {
+    /// 与能力寄存器的低32位相同
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// 周期模式下不丢中断的最小间隔(计数值)
+    pub min_tick: u16,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// 所有字节之和的低8位为0
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn signature(table: &[u8]) -> [u8; 4] {
+    table[..4].try_into().unwrap()
+}
+
+/// 解析RSDP并校验校验和
+pub fn parse_rsdp(bytes: &[u8]) -> Result<Rsdp, AcpiError> {
+    if bytes.len() < RSDP_V1_LENGTH {
+        return Err(AcpiError::Truncated);
+    }
+    if &bytes[..8] != RSDP_SIGNATURE {
+        return Err(AcpiError::BadSignature);
+    }
+    if !checksum_ok(&bytes[..RSDP_V1_LENGTH]) {
+        return Err(AcpiError::BadChecksum(*b"RSD "));
+    }
+
+    let revision = bytes[15];
+    let rsdt_address = u32_at(bytes, 16);
+    let xsdt_address = if revision >= 2 {
+        if bytes.len() < RSDP_V2_LENGTH {
+            return Err(AcpiError::Truncated);
+        }
+        let length = u32_at(bytes, 20) as usize;
+        if length < RSDP_V2_LENGTH || bytes.len() < length {
+            return Err(AcpiError::Truncated);
+        }
+        if !checksum_ok(&bytes[..length]) {
+            return Err(AcpiError::BadChecksum(*b"RSD "));
+        }
+        Some(u64_at(bytes, 24)).filter(|&address| address != 0)
+    } else {
+        None
+    };
+
+    Ok(Rsdp {
+        revision,
+        rsdt_address,
+        xsdt_address,
+    })
+}
+
+/// 校验系统描述表的签名、长度和校验和, 返回去掉多余字节后的表
+pub fn validate_table<'a>(table: &'a [u8], expected: &[u8; 4]) -> Result<&'a [u8], AcpiError> {
+    if table.len() < HEADER_LENGTH {
+        return Err(AcpiError::Truncated);
+    }
+    if &table[..4] != expected {
+        return Err(AcpiError::BadSignature);
+    }
+    let length = u32_at(table, 4) as usize;
+    if length < HEADER_LENGTH || table.len() < length {
+        return Err(AcpiError::Truncated);
+    }
+    let table = &table[..length];
+    if !checksum_ok(table) {
+        return Err(AcpiError::BadChecksum(*expected));
+    }
+    Ok(table)
+}
+
+/// 解析RSDT(4字节条目)或XSDT(8字节条目), 逐个返回其余各表的物理地址
+pub fn parse_root_table(
+    table: &[u8],
+    xsdt: bool,
+) -> Result<impl Iterator<Item = u64> + Clone + '_, AcpiError> {
+    let (expected, entry_size) = if xsdt { (b"XSDT", 8) } else { (b"RSDT", 4) };
+    let table = validate_table(table, expected)?;
+    Ok(table[HEADER_LENGTH..]
+        .chunks_exact(entry_size)
+        .map(move |entry| {
+            if xsdt {
+                u64_at(entry, 0)
+            } else {
+                u64::from(u32_at(entry, 0))
+            }
+        }))
+}
+
+/// 已检查过长度的MADT条目, 产生类型和整个条目
+#[derive(Clone)]
+struct MadtEntries<'a>(&'a [u8]);
+
+impl<'a> Iterator for MadtEntries<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.len() < 2 {
+            return None;
+        }
+        let (entry, rest) = self.0.split_at(usize::from(self.0[1]));
+        self.0 = rest;
+        Some((entry[0], entry))
+    }
+}
+
+/// 解析MADT, 不认识的条目被跳过. 条目列表从`bootmem`分配, 可以在堆初始化之前调用
+pub fn parse_madt(table: &[u8], bootmem: &BootMem) -> Result<MadtInfo, AcpiError> {
+    let table = validate_table(table, b"APIC")?;
+    if table.len() < HEADER_LENGTH + 8 {
+        return Err(AcpiError::Truncated);
+    }
+    // 先检查所有条目的长度, 出错时不分配
+    let entries = &table[HEADER_LENGTH + 8..];
+    let mut rest = entries;
+    while rest.len() >= 2 {
+        let length = usize::from(rest[1]);
+        if length < 2 || length > rest.len() {
+            return Err(AcpiError::Truncated);
+        }
+        rest = &rest[length..];
+    }
+    let entries = MadtEntries(entries);
+
+    let local_apic_address = entries
+        .clone()
+        .filter_map(|(kind, entry)| match (kind, entry.len()) {
+            (MADT_LOCAL_APIC_ADDRESS_OVERRIDE, 12..) => Some(u64_at(entry, 4)),
+            _ => None,
+        })
+        .last()
+        .unwrap_or(u64::from(u32_at(table, HEADER_LENGTH)));
+    let processors = entries
+        .clone()
+        .filter_map(|(kind, entry)| match (kind, entry.len()) {
+            (MADT_LOCAL_APIC, 8..) => Some(LocalApic {
+                processor_id: u32::from(entry[2]),
+                apic_id: u32::from(entry[3]),
+                enabled: u32_at(entry, 4) & LOCAL_APIC_ENABLED != 0,
+            }),
+            (MADT_LOCAL_X2APIC, 16..) => Some(LocalApic {
+                processor_id: u32_at(entry, 12),
+                apic_id: u32_at(entry, 4),
+                enabled: u32_at(entry, 8) & LOCAL_APIC_ENABLED != 0,
+            }),
+            _ => None,
+        });
+    let io_apics = entries
+        .clone()
+        .filter_map(|(kind, entry)| match (kind, entry.len()) {
+            (MADT_IO_APIC, 12..) => Some(IoApic {
+                id: entry[2],
+                address: u32_at(entry, 4),
+                gsi_base: u32_at(entry, 8),
+            }),
+            _ => None,
+        });
+    let overrides = entries.filter_map(|(kind, entry)| match (kind, entry.len()) {
+        (MADT_INTERRUPT_OVERRIDE, 10..) => Some(InterruptOverride {
+            bus: entry[2],
+            source: entry[3],
+            gsi: u32_at(entry, 4),
+            flags: u16_at(entry, 8),
+        }),
+        _ => None,
+    });
+    Ok(MadtInfo {
+        local_apic_address,
+        pcat_compat: u32_at(table, HEADER_LENGTH + 4) & MADT_PCAT_COMPAT != 0,
+        processors: bootmem.alloc_slice_from(processors),
+        io_apics: bootmem.alloc_slice_from(io_apics),
+        overrides: bootmem.alloc_slice_from(overrides),
+    })
+}
+
+/// 解析FADT, 旧版本中不存在的字段取默认值
+pub fn parse_fadt(table: &[u8]) -> Result<FadtInfo, AcpiError> {
+    let table = validate_table(table, b"FACP")?;
+    if table.len() < FADT_PM1B_CONTROL + 4 {
+        return Err(AcpiError::Truncated);
+    }
+    let has = |offset: usize, size: usize| table.len() >= offset + size;
+
+    let flags = if has(FADT_FLAGS, 4) { u32_at(table, FADT_FLAGS) } else { 0 };
+    let reset_register = if flags & FADT_RESET_REG_SUPPORTED != 0 && has(FADT_RESET_VALUE, 1) {
+        Some(parse_gas(&table[FADT_RESET_REGISTER..]))
+    } else {
+        None
+    };
+
+    // 优先使用64位的X_DSDT
+    let dsdt_address = if has(FADT_X_DSDT, 8) && u64_at(table, FADT_X_DSDT) != 0 {
+        u64_at(table, FADT_X_DSDT)
+    } else {
+        u64::from(u32_at(table, FADT_DSDT))
+    };
+
+    Ok(FadtInfo {
+        dsdt_address,
+        sci_interrupt: u16_at(table, FADT_SCI_INTERRUPT),
+        smi_command_port: u32_at(table, FADT_SMI_COMMAND),
+        acpi_enable: table[FADT_ACPI_ENABLE],
+        pm1a_control_block: u32_at(table, FADT_PM1A_CONTROL),
+        pm1b_control_block: u32_at(table, FADT_PM1B_CONTROL),
+        century: if has(FADT_CENTURY, 1) { table[FADT_CENTURY] } else { 0 },
+        reset_register,
+        reset_value: if reset_register.is_some() { table[FADT_RESET_VALUE] } else { 0 },
+    })
+}
+
+/// 解析HPET表
+pub fn parse_hpet(table: &[u8]) -> Result<HpetInfo, AcpiError> {
+    let table = validate_table(table, b"HPET")?;
+    if table.len() < HPET_LENGTH {
+        return Err(AcpiError::Truncated);
+    }
+    Ok(HpetInfo {
+        event_timer_block_id: u32_at(table, HPET_EVENT_TIMER_BLOCK_ID),
+        base_address: parse_gas(&table[HPET_BASE_ADDRESS..]),
+        hpet_number: table[HPET_NUMBER],
+        min_tick: u16_at(table, HPET_MIN_TICK),
+    })
+}
+
+// 12字节的通用地址结构
+fn parse_gas(bytes: &[u8]) -> GenericAddress {
+    GenericAddress {
+        address_space: bytes[0],
+        bit_width: bytes[1],
+        bit_offset: bytes[2],
+        access_size: bytes[3],
+        address: u64_at(bytes, 4),
+    }
+}
+
+// 通过物理内存映射访问物理内存
+unsafe fn physical_slice(address: u64, length: usize) -> &'static [u8] {
+    let ptr: *const u8 = memory::phys_to_virt(PhysAddr::new(address)).as_ptr();
+    core::slice::from_raw_parts(ptr, length)
+}
+
+// 长度取自表头, 调用前不知道整张表有多长
+unsafe fn table_at(address: u64) -> &'static [u8] {
+    let length = u32_at(physical_slice(address, HEADER_LENGTH), 4) as usize;
+    physical_slice(address, length.max(HEADER_LENGTH))
+}
+
+// 在16字节对齐的位置上查找RSDP
+fn scan_for_rsdp(start: u64, length: usize) -> Option<Rsdp> {
+    let area = unsafe { physical_slice(start, length) };
+    (0..length.saturating_sub(RSDP_V2_LENGTH - 1))
+        .step_by(16)
+        .find_map(|offset| parse_rsdp(&area[offset..]).ok())
+}
+
+/// 先搜索EBDA的前1KiB, 再搜索BIOS只读区域
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = u64::from(u16_at(unsafe { physical_slice(EBDA_POINTER, 2) }, 0)) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = scan_for_rsdp(ebda, EBDA_SCAN_LENGTH) {
+            return Some(rsdp);
+        }
+    }
+    scan_for_rsdp(BIOS_AREA_START, (BIOS_AREA_END - BIOS_AREA_START) as usize)
+}
+
+struct Tables {
+    // 所有校验通过的表的签名和物理地址
+    tables: &'static [([u8; 4], u64)],
+    madt: Option<MadtInfo>,
+    fadt: Option<FadtInfo>,
+    hpet: Option<HpetInfo>,
+}
+
+static TABLES: Once<Tables> = Once::new();
+
+/// 查找并解析ACPI表, 需要在物理内存映射可用之后、堆初始化之前调用, 使用bootmem保存结果
+pub fn init() -> Result<(), AcpiError> {
+    // bootloader提供了RSDP的地址时直接使用, 否则(如0.9)扫描BIOS区域
+    let rsdp = crate::bootinfo::rsdp_address()
+        .and_then(|address| parse_rsdp(unsafe { physical_slice(address.as_u64(), RSDP_V2_LENGTH) }).ok())
+        .or_else(find_rsdp)
+        .ok_or(AcpiError::RsdpNotFound)?;
+    let addresses = match rsdp.xsdt_address {
+        Some(xsdt) => parse_root_table(unsafe { table_at(xsdt) }, true)?,
+        None => parse_root_table(unsafe { table_at(u64::from(rsdp.rsdt_address)) }, false)?,
+    };
+
+    let mut tables = Tables {
+        tables: &[],
+        madt: None,
+        fadt: None,
+        hpet: None,
+    };
+    // 单张表损坏时跳过, 不影响其他表
+    for address in addresses.clone() {
+        let table = unsafe { table_at(address) };
+        let _ = match &signature(table) {
+            b"APIC" => parse_madt(table, bootmem::global()).map(|madt| tables.madt = Some(madt)),
+            b"FACP" => parse_fadt(table).map(|fadt| tables.fadt = Some(fadt)),
+            b"HPET" => parse_hpet(table).map(|hpet| tables.hpet = Some(hpet)),
+            _ => Ok(()),
+        };
+    }
+    tables.tables = bootmem::alloc_slice_from(addresses.filter_map(|address| {
+        let table = unsafe { table_at(address) };
+        let signature = signature(table);
+        validate_table(table, &signature).ok()?;
+        Some((signature, address))
+    }));
+    TABLES.call_once(|| tables);
+    Ok(())
+}
+
+pub fn madt() -> Option<&'static MadtInfo> {
+    TABLES.get()?.madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static FadtInfo> {
+    TABLES.get()?.fadt.as_ref()
+}
+
+pub fn hpet() -> Option<&'static HpetInfo> {
+    TABLES.get()?.hpet.as_ref()
+}
+
+/// 按签名查找已校验的表, 返回整张表的字节
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let &(_, address) = TABLES.get()?.tables.iter().find(|(s, _)| s == signature)?;
+    Some(unsafe { table_at(address) })
+}
+
+/// FADT指向的DSDT, 它不在RSDT中
+pub fn dsdt() -> Option<&'static [u8]> {
+    let address = fadt()?.dsdt_address;
+    validate_table(unsafe { table_at(address) }, b"DSDT").ok()
+}
+
+// AML操作码
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+/// 在AML中查找`Name(\_S5, Package() {SLP_TYPa, SLP_TYPb, ...})`, 不解释AML, 只匹配字节
+pub fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let position = aml.windows(4).enumerate().find_map(|(i, window)| {
+        let name_op = match i {
+            1.. if aml[i - 1] == AML_NAME_OP => true,
+            2.. if aml[i - 1] == AML_ROOT_CHAR && aml[i - 2] == AML_NAME_OP => true,
+            _ => false,
+        };
+        (window == b"_S5_" && name_op).then_some(i + 4)
+    })?;
+
+    let mut bytes = aml.get(position..)?.iter().copied();
+    if bytes.next()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLength第一个字节的高2位是后续字节数
+    let lead = bytes.next()?;
+    for _ in 0..(lead >> 6) {
+        bytes.next()?;
+    }
+    // NumElements
+    bytes.next()?;
+
+    // 0和1分别是ZeroOp和OneOp, 其他值带BytePrefix
+    let mut integer = || match bytes.next()? {
+        AML_BYTE_PREFIX => bytes.next(),
+        value => Some(value),
+    };
+    let slp_typ_a = integer()?;
+    let slp_typ_b = integer()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+// 测试中解析MADT使用的区域, 全局区域在启动时已经封存
+#[cfg(test)]
+fn test_bootmem() -> &'static BootMem {
+    static STORAGE: bootmem::Storage<1024> = bootmem::Storage::new();
+    static BOOTMEM: BootMem = BootMem::new(&STORAGE);
+    &BOOTMEM
+}
+
+#[test_case]
+fn test_parse_rsdp() {
+    let rsdp = parse_rsdp(&fixture::RSDP).unwrap();
+    assert_eq!(rsdp, Rsdp { revision: 0, rsdt_address: 0x07FE_1812, xsdt_address: None });
+
+    let mut corrupted = fixture::RSDP;
+    corrupted[16] ^= 1;
+    assert_eq!(parse_rsdp(&corrupted), Err(AcpiError::BadChecksum(*b"RSD ")));
+}
+
+#[test_case]
+fn test_parse_rsdt() {
+    let addresses: alloc::vec::Vec<_> = parse_root_table(&fixture::RSDT, false).unwrap().collect();
+    assert_eq!(addresses, [0x07FE_1726, 0x07FE_179A, 0x07FE_180A, 0x07FE_1842]);
+    assert_eq!(
+        parse_root_table(&fixture::RSDT, true).err(),
+        Some(AcpiError::BadSignature)
+    );
+}
+
+#[test_case]
+fn test_parse_madt() {
+    let madt = parse_madt(&fixture::MADT, test_bootmem()).unwrap();
+    assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+    assert!(madt.pcat_compat);
+    assert_eq!(
+        madt.processors,
+        [
+            LocalApic { processor_id: 0, apic_id: 0, enabled: true },
+            LocalApic { processor_id: 1, apic_id: 1, enabled: false },
+        ]
+    );
+    assert_eq!(madt.io_apics, [IoApic { id: 0, address: 0xFEC0_0000, gsi_base: 0 }]);
+    assert_eq!(madt.overrides.len(), 5);
+    assert_eq!(madt.overrides[1], InterruptOverride { bus: 0, source: 5, gsi: 5, flags: 0xD });
+    // 时钟中断被重映射到GSI 2
+    assert_eq!(madt.gsi_for_irq(0), 2);
+    assert_eq!(madt.gsi_for_irq(1), 1);
+}
+
+#[test_case]
+fn test_parse_fadt() {
+    let fadt = parse_fadt(&fixture::FADT).unwrap();
+    assert_eq!(fadt.dsdt_address, 0x07FE_0040);
+    assert_eq!(fadt.sci_interrupt, 9);
+    assert_eq!(fadt.smi_command_port, 0xB2);
+    assert_eq!(fadt.acpi_enable, 0xF1);
+    assert_eq!(fadt.pm1a_control_block, 0x604);
+    assert_eq!(fadt.pm1b_control_block, 0);
+    assert_eq!(fadt.century, 0x32);
+    assert_eq!(
+        fadt.reset_register,
+        Some(GenericAddress {
+            address_space: 1,
+            bit_width: 8,
+            bit_offset: 0,
+            access_size: 0,
+            address: 0xCF9,
+        })
+    );
+    assert_eq!(fadt.reset_value, 0x0F);
+}
+
+#[test_case]
+fn test_parse_hpet() {
+    let hpet = parse_hpet(&fixture::HPET).unwrap();
+    assert_eq!(hpet.event_timer_block_id, 0x8086_A201);
+    assert_eq!(hpet.base_address.address_space, 0);
+    assert_eq!(hpet.base_address.address, 0xFED0_0000);
+    assert_eq!((hpet.hpet_number, hpet.min_tick), (0, 0));
+    assert_eq!(parse_hpet(&fixture::HPET[..40]), Err(AcpiError::Truncated));
+}
+
+#[test_case]
+fn test_truncated_madt_entry() {
+    let mut madt = fixture::MADT;
+    // 最后一个条目声明的长度超出表尾, 重新计算校验和以单独测试长度检查
+    let last = madt.len() - 5;
+    madt[last] = 0x20;
+    madt[9] = madt[9].wrapping_sub(0x20 - 0x06);
+    let used = test_bootmem().used();
+    assert_eq!(parse_madt(&madt, test_bootmem()), Err(AcpiError::Truncated));
+    assert_eq!(test_bootmem().used(), used);
+}
+
+#[test_case]
+fn test_parse_s5() {
+    // SeaBIOS的DSDT片段: Name(_S5, Package(0x04) {Zero, Zero, Zero, Zero})
+    let seabios = [0x5B, 0x80, 0x08, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+    assert_eq!(parse_s5(&seabios), Some((0, 0)));
+
+    // Name(\_S5, Package(0x02) {0x05, 0x07}), 带根前缀和BytePrefix
+    let prefixed = [0x08, 0x5C, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x08, 0x02, 0x0A, 0x05, 0x0A, 0x07];
+    assert_eq!(parse_s5(&prefixed), Some((5, 7)));
+
+    // 方法名中出现的_S5_不是Name定义
+    let method = [0x14, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x04, 0x02, 0x00, 0x00];
+    assert_eq!(parse_s5(&method), None);
+}
+
+#[test_case]
+fn test_runtime_tables() {
+    let madt = madt().expect("MADT not found");
+    assert!(madt.processors.iter().any(|p| p.enabled));
+    assert!(fadt().is_some());
+}
+
+// QEMU i440fx机器(SeaBIOS)上导出的表
+#[cfg(test)]
+mod fixture {
+    pub const RSDP: [u8; 20] = [
+        0x52, 0x53, 0x44, 0x20, 0x50, 0x54, 0x52, 0x20, 0x23, 0x42, 0x4f, 0x43,
+        0x48, 0x53, 0x20, 0x00, 0x12, 0x18, 0xfe, 0x07,
+    ];
+
+    pub const RSDT: [u8; 52] = [
+        0x52, 0x53, 0x44, 0x54, 0x34, 0x00, 0x00, 0x00, 0x01, 0xa5, 0x42, 0x4f,
+        0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20,
+        0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+        0x26, 0x17, 0xfe, 0x07, 0x9a, 0x17, 0xfe, 0x07, 0x0a, 0x18, 0xfe, 0x07,
+        0x42, 0x18, 0xfe, 0x07,
+    ];
+
+    pub const MADT: [u8; 128] = [
+        0x41, 0x50, 0x49, 0x43, 0x80, 0x00, 0x00, 0x00, 0x01, 0x78, 0x42, 0x4f,
+        0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20,
+        0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x01, 0x0c, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xfe, 0x00, 0x00, 0x00, 0x00,
+        0x02, 0x0a, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a,
+        0x00, 0x05, 0x05, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x09,
+        0x09, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0a, 0x0a, 0x00,
+        0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0b, 0x0b, 0x00, 0x00, 0x00,
+        0x0d, 0x00, 0x04, 0x06, 0xff, 0x00, 0x00, 0x01,
+    ];
+
+    pub const FADT: [u8; 244] = [
+        0x46, 0x41, 0x43, 0x50, 0xf4, 0x00, 0x00, 0x00, 0x03, 0x85, 0x42, 0x4f,
+        0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20,
+        0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xfe, 0x07, 0x40, 0x00, 0xfe, 0x07, 0x00, 0x00, 0x09, 0x00,
+        0xb2, 0x00, 0x00, 0x00, 0xf1, 0xf0, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x04, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x08, 0x06, 0x00, 0x00, 0xe0, 0xaf, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x04, 0x02, 0x00, 0x04, 0x04, 0x00, 0x00, 0x00,
+        0xff, 0x0f, 0xff, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x32, 0x00, 0x00, 0x00, 0xa5, 0x04, 0x00, 0x00, 0x01, 0x08, 0x00, 0x00,
+        0xf9, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xfe, 0x07, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0xfe, 0x07,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    pub const HPET: [u8; 56] = [
+        0x48, 0x50, 0x45, 0x54, 0x38, 0x00, 0x00, 0x00, 0x01, 0xb4, 0x42, 0x4f,
+        0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20,
+        0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0xa2, 0x86, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xd0, 0xfe,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+}
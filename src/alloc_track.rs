@@ -0,0 +1,376 @@
+//! 记录每个存活分配的大小和调用位置, 用于查找泄漏. 只在启用`alloc-track` feature时编译
+//!
+//! 分配记录在以指针为键、线性探测的定长散列表中, 表满后的分配不再记录
+
+use core::cmp::Reverse;
+use core::fmt::{self, Write};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{backtrace, symbols};
+
+/// 最多记录的存活分配数
+pub const CAPACITY: usize = 2048;
+// 从分配器向上记录的返回地址数, 需要越过Box、Vec和alloc内部的几层
+const TRACE_DEPTH: usize = 10;
+// 报告中分别列出的调用位置数, 其余的合并为一行
+const MAX_SITES: usize = 32;
+
+type Trace = [u64; TRACE_DEPTH];
+
+#[derive(Clone, Copy)]
+struct Entry {
+    // 0表示空槽
+    ptr: usize,
+    size: usize,
+    // 分配的序号, 用于区分检查点之前和之后的分配
+    seq: u64,
+    trace: Trace,
+}
+
+const EMPTY: Entry = Entry {
+    ptr: 0,
+    size: 0,
+    seq: 0,
+    trace: [0; TRACE_DEPTH],
+};
+
+// 容量必须是2的幂
+struct Table<const N: usize> {
+    entries: [Entry; N],
+    len: usize,
+    next_seq: u64,
+    // 表满时没有记录的分配数
+    dropped: u64,
+}
+
+impl<const N: usize> Table<N> {
+    const MASK: usize = N - 1;
+
+    const fn new() -> Self {
+        Table {
+            entries: [EMPTY; N],
+            len: 0,
+            next_seq: 0,
+            dropped: 0,
+        }
+    }
+
+    fn home(ptr: usize) -> usize {
+        // 分配至少8字节对齐, 低位没有信息
+        ((ptr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 20) & Self::MASK
+    }
+
+    // 保留一个空槽, 查找总能结束
+    fn insert(&mut self, ptr: usize, size: usize, trace: Trace) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.len == N - 1 {
+            self.dropped += 1;
+            return;
+        }
+        let mut slot = Self::home(ptr);
+        while self.entries[slot].ptr != 0 {
+            slot = (slot + 1) & Self::MASK;
+        }
+        self.entries[slot] = Entry {
+            ptr,
+            size,
+            seq,
+            trace,
+        };
+        self.len += 1;
+    }
+
+    fn slot_of(&self, ptr: usize) -> Option<usize> {
+        let mut slot = Self::home(ptr);
+        loop {
+            match self.entries[slot].ptr {
+                0 => return None,
+                found if found == ptr => return Some(slot),
+                _ => slot = (slot + 1) & Self::MASK,
+            }
+        }
+    }
+
+    #[cfg(any(test, feature = "heap-canaries"))]
+    fn get(&self, ptr: usize) -> Option<&Entry> {
+        self.slot_of(ptr).map(|slot| &self.entries[slot])
+    }
+
+    // 删除后把之后同一探测链上的项前移, 不需要墓碑
+    fn remove(&mut self, ptr: usize) -> bool {
+        let Some(slot) = self.slot_of(ptr) else {
+            return false;
+        };
+        let mut hole = slot;
+        let mut next = slot;
+        loop {
+            next = (next + 1) & Self::MASK;
+            let ptr = self.entries[next].ptr;
+            if ptr == 0 {
+                break;
+            }
+            // 空槽位于这一项的起始位置和当前位置之间时才能前移
+            let home = Self::home(ptr);
+            if next.wrapping_sub(home) & Self::MASK >= next.wrapping_sub(hole) & Self::MASK {
+                self.entries[hole] = self.entries[next];
+                hole = next;
+            }
+        }
+        self.entries[hole] = EMPTY;
+        self.len -= 1;
+        true
+    }
+
+    fn live(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().filter(|entry| entry.ptr != 0)
+    }
+}
+
+static TABLE: Mutex<Table<CAPACITY>> = Mutex::new(Table::new());
+
+/// 由全局分配器在关中断时调用
+#[inline(never)]
+pub(crate) fn on_alloc(ptr: *mut u8, size: usize) {
+    let mut trace = [0; TRACE_DEPTH];
+    for (slot, ret) in trace.iter_mut().zip(backtrace::frames()) {
+        *slot = ret.as_u64();
+    }
+    TABLE.lock().insert(ptr as usize, size, trace);
+}
+
+/// 由全局分配器在关中断时调用, 表满时没有记录的分配被忽略
+pub(crate) fn on_dealloc(ptr: *mut u8) {
+    TABLE.lock().remove(ptr as usize);
+}
+
+/// `ptr`处存活分配的调用位置, 没有记录时为None
+#[cfg(feature = "heap-canaries")]
+pub(crate) fn call_site_of(ptr: *const u8) -> Option<u64> {
+    without_interrupts(|| {
+        let table = TABLE.lock();
+        table.get(ptr as usize).map(|entry| call_site(&entry.trace))
+    })
+}
+
+/// 第一个使`pred(ptr, size)`成立的存活分配的地址、大小和调用位置. 检查期间分配和释放都会等待
+#[cfg(feature = "heap-canaries")]
+pub(crate) fn find_live(mut pred: impl FnMut(usize, usize) -> bool) -> Option<(usize, usize, u64)> {
+    without_interrupts(|| {
+        let table = TABLE.lock();
+        let found = table
+            .live()
+            .find(|entry| pred(entry.ptr, entry.size))
+            .map(|entry| (entry.ptr, entry.size, call_site(&entry.trace)));
+        found
+    })
+}
+
+/// 某一时刻之后的分配, 由`checkpoint`取得
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint(u64);
+
+/// 存活分配的总量
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    pub bytes: usize,
+    pub allocations: usize,
+}
+
+pub fn checkpoint() -> Checkpoint {
+    Checkpoint(without_interrupts(|| TABLE.lock().next_seq))
+}
+
+/// `checkpoint`之后分配且仍然存活的内存
+pub fn diff_since(checkpoint: Checkpoint) -> Usage {
+    without_interrupts(|| {
+        let table = TABLE.lock();
+        let mut usage = Usage::default();
+        for entry in table.live().filter(|entry| entry.seq >= checkpoint.0) {
+            usage.bytes += entry.size;
+            usage.allocations += 1;
+        }
+        usage
+    })
+}
+
+/// 按调用位置汇总所有存活的分配
+pub fn live_report(out: &mut dyn fmt::Write) -> fmt::Result {
+    report_since(Checkpoint(0), out)
+}
+
+/// 按调用位置汇总`checkpoint`之后分配且仍然存活的内存, 字节数多的在前
+pub fn report_since(checkpoint: Checkpoint, out: &mut dyn fmt::Write) -> fmt::Result {
+    // 在锁内汇总到栈上, 打印时可能分配内存
+    let mut sites = [Site::default(); MAX_SITES];
+    let mut other = Site::default();
+    let dropped = without_interrupts(|| {
+        let table = TABLE.lock();
+        for entry in table.live().filter(|entry| entry.seq >= checkpoint.0) {
+            let addr = call_site(&entry.trace);
+            let index = sites
+                .iter()
+                .position(|site| site.count == 0 || site.addr == addr);
+            let site = match index {
+                Some(index) => &mut sites[index],
+                None => &mut other,
+            };
+            site.addr = addr;
+            site.bytes += entry.size;
+            site.count += 1;
+        }
+        table.dropped
+    });
+    sites.sort_unstable_by_key(|site| Reverse(site.bytes));
+
+    writeln!(out, "{:>8} {:>6}  call site", "bytes", "count")?;
+    for site in sites.iter().filter(|site| site.count != 0) {
+        write!(out, "{:>8} {:>6}  {:#x}", site.bytes, site.count, site.addr)?;
+        if let Some((name, offset)) = symbols::resolve(site.addr) {
+            write!(out, " <{}+{:#x}>", symbols::Demangle(name), offset)?;
+        }
+        writeln!(out)?;
+    }
+    if other.count != 0 {
+        writeln!(
+            out,
+            "{:>8} {:>6}  (other call sites)",
+            other.bytes, other.count
+        )?;
+    }
+    if dropped != 0 {
+        writeln!(out, "{} allocations not tracked, table was full", dropped)?;
+    }
+    Ok(())
+}
+
+/// `checkpoint`之后分配的内存都已释放, 否则输出泄漏的调用位置并panic
+#[track_caller]
+pub fn assert_no_leaks(checkpoint: Checkpoint) {
+    let leaked = diff_since(checkpoint);
+    if leaked != Usage::default() {
+        let _ = report_since(checkpoint, &mut crate::serial::Serial);
+        panic!(
+            "leaked {} bytes in {} allocations since checkpoint",
+            leaked.bytes, leaked.allocations
+        );
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Site {
+    addr: u64,
+    bytes: usize,
+    count: usize,
+}
+
+// 跳过分配器、alloc和core内部的返回地址. 没有符号表时无法区分, 使用最内层的地址
+fn call_site(trace: &Trace) -> u64 {
+    let frames = trace.iter().copied().take_while(|&addr| addr != 0);
+    frames
+        .clone()
+        .find(|&addr| symbols::resolve(addr).is_some_and(|(name, _)| !is_plumbing(name)))
+        .or_else(|| frames.clone().next())
+        .unwrap_or(0)
+}
+
+const PLUMBING: [&str; 7] = [
+    "alloc::",
+    "core::",
+    "<alloc::",
+    "<core::",
+    "__rust",
+    "__rg_",
+    "<toy_os::allocator::",
+];
+
+fn is_plumbing(name: &str) -> bool {
+    let mut prefix = Prefix {
+        bytes: [0; 32],
+        len: 0,
+    };
+    let _ = write!(prefix, "{}", symbols::Demangle(name));
+    let prefix = &prefix.bytes[..prefix.len];
+    PLUMBING
+        .iter()
+        .any(|plumbing| prefix.starts_with(plumbing.as_bytes()))
+}
+
+// 只保留还原后名称的开头
+struct Prefix {
+    bytes: [u8; 32],
+    len: usize,
+}
+
+impl fmt::Write for Prefix {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_table_insert_remove() {
+    type Small = Table<16>;
+
+    let mut table = Small::new();
+    let trace = [0; TRACE_DEPTH];
+    // 落在同一个起始位置的指针形成探测链
+    let colliding: alloc::vec::Vec<usize> = (1..)
+        .map(|i| i * 8)
+        .filter(|&ptr| Small::home(ptr) == Small::home(8))
+        .take(4)
+        .collect();
+    for &ptr in &colliding {
+        table.insert(ptr, 16, trace);
+    }
+    table.insert(0x1000, 32, trace);
+    assert_eq!(table.len, 5);
+    // 删除链中间的一项后, 之后的项仍然能找到
+    assert!(table.remove(colliding[1]));
+    assert!(!table.remove(colliding[1]));
+    assert_eq!(table.get(colliding[2]).map(|entry| entry.size), Some(16));
+    assert!(table.get(colliding[1]).is_none());
+    for &ptr in [colliding[0], colliding[2], colliding[3], 0x1000].iter() {
+        assert!(table.remove(ptr));
+    }
+    assert_eq!(table.len, 0);
+    assert!(table.live().next().is_none());
+
+    for ptr in 1..=16 {
+        table.insert(ptr * 8, 1, trace);
+    }
+    assert_eq!((table.len, table.dropped), (15, 1));
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn leak_one_box() -> *mut [u8; 24] {
+    alloc::boxed::Box::into_raw(core::hint::black_box(alloc::boxed::Box::new([7u8; 24])))
+}
+
+#[test_case]
+fn test_leak_is_reported() {
+    let checkpoint = checkpoint();
+    let leaked = leak_one_box();
+    assert_eq!(
+        diff_since(checkpoint),
+        Usage {
+            bytes: 24,
+            allocations: 1
+        }
+    );
+    let mut report = alloc::string::String::new();
+    report_since(checkpoint, &mut report).unwrap();
+    assert!(report.contains("24      1"));
+    if symbols::kernel_symbols().is_some() {
+        assert!(report.contains("leak_one_box"), "{}", report);
+    }
+    drop(report);
+    drop(unsafe { alloc::boxed::Box::from_raw(leaked) });
+    assert_no_leaks(checkpoint);
+}
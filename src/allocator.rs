@@ -0,0 +1,245 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::bootmem;
+use crate::layout;
+use crate::memory::{BootInfoFrameAllocator, FrameUsage};
+use crate::selftest::{self, Check, Outcome};
+
+pub mod bump;
+#[cfg(feature = "heap-canaries")]
+pub mod canary;
+pub mod fixed_size_block;
+pub mod linked_list;
+
+pub const HEAP_START: usize = layout::HEAP.start as usize;
+pub const HEAP_SIZE: usize = 100 * 1024;
+
+const _: () = assert!(layout::HEAP.contains_range(HEAP_START as u64, HEAP_SIZE as u64));
+
+// 通过cargo feature选择分配器, 默认使用固定大小块分配器
+#[cfg(feature = "bump_allocator")]
+#[global_allocator]
+static ALLOCATOR: Counting<Locked<bump::BumpAllocator>> =
+    Counting::new(Locked::new(bump::BumpAllocator::new()));
+
+#[cfg(all(feature = "linked_list_allocator", not(feature = "bump_allocator")))]
+#[global_allocator]
+static ALLOCATOR: Counting<Locked<linked_list::LinkedListAllocator>> =
+    Counting::new(Locked::new(linked_list::LinkedListAllocator::new()));
+
+#[cfg(not(any(feature = "bump_allocator", feature = "linked_list_allocator")))]
+#[global_allocator]
+static ALLOCATOR: Counting<Locked<fixed_size_block::FixedSizeBlockAllocator>> =
+    Counting::new(Locked::new(fixed_size_block::FixedSizeBlockAllocator::new()));
+
+/// 当前启用的分配器名称
+pub const fn allocator_name() -> &'static str {
+    if cfg!(feature = "bump_allocator") {
+        "bump"
+    } else if cfg!(feature = "linked_list_allocator") {
+        "linked_list"
+    } else {
+        "fixed_size_block"
+    }
+}
+
+/// 映射堆所在的页并初始化分配器, 然后封存bootmem
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate(FrameUsage::Heap)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+
+    unsafe {
+        ALLOCATOR.inner.lock().init(HEAP_START, HEAP_SIZE);
+    }
+    // 之后的分配都使用堆
+    bootmem::seal();
+
+    Ok(())
+}
+
+/// 堆的使用情况, 按请求的大小统计, 不包括分配器内部的对齐和碎片
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    /// 尚未释放的分配次数
+    pub allocations: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        size: HEAP_SIZE,
+        used: ALLOCATOR.used.load(Ordering::Relaxed),
+        allocations: ALLOCATOR.allocations.load(Ordering::Relaxed),
+    }
+}
+
+/// 登记堆的自检
+pub fn register_selftests() {
+    static HEAP: Check = Check::new("heap", heap_selftest);
+    selftest::register(&HEAP).expect("duplicate heap selftest");
+}
+
+// 分配不同大小和对齐的块并写满, 检查互不覆盖, 全部释放后使用量应回到开始时
+fn heap_selftest() -> Outcome {
+    let before = heap_stats();
+    let mut blocks: Vec<Vec<u8>> = Vec::new();
+    for (index, size) in [1, 8, 24, 100, 512, 1500, 4000].into_iter().enumerate() {
+        blocks.push(alloc::vec![index as u8; size]);
+    }
+    let aligned = Box::new(Aligned([0x5a; 64]));
+    if !(aligned.as_ref() as *const Aligned as usize).is_multiple_of(PAGE_ALIGN) {
+        return Outcome::Fail("a page-aligned allocation is misaligned".into());
+    }
+    let intact = blocks
+        .iter()
+        .enumerate()
+        .all(|(index, block)| block.iter().all(|&byte| byte == index as u8));
+    if !intact || aligned.0.iter().any(|&byte| byte != 0x5a) {
+        return Outcome::Fail("heap blocks overlap".into());
+    }
+    drop(blocks);
+    drop(aligned);
+    let after = heap_stats();
+    if after.used != before.used || after.allocations != before.allocations {
+        return Outcome::Fail(format!(
+            "heap usage changed from {} bytes in {} allocations to {} in {}",
+            before.used, before.allocations, after.used, after.allocations
+        ));
+    }
+    Outcome::Pass
+}
+
+const PAGE_ALIGN: usize = 4096;
+
+#[repr(align(4096))]
+struct Aligned([u8; 64]);
+
+/// 统计已分配字节数和分配次数的GlobalAlloc包装
+pub struct Counting<A> {
+    inner: A,
+    used: AtomicUsize,
+    allocations: AtomicUsize,
+}
+
+impl<A> Counting<A> {
+    pub const fn new(inner: A) -> Self {
+        Counting {
+            inner,
+            used: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+        }
+    }
+}
+
+// 分配器的锁在关中断时持有: 线程可能被时钟中断抢占, 而调度器在中断中也会释放内存
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Counting<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = without_interrupts(|| {
+            let ptr = self.inner_alloc(layout);
+            #[cfg(feature = "alloc-track")]
+            if !ptr.is_null() {
+                crate::alloc_track::on_alloc(ptr, layout.size());
+            }
+            ptr
+        });
+        if !ptr.is_null() {
+            self.used.fetch_add(layout.size(), Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // 先从分配表中删除再释放, 后台检查哨兵时表中的分配都是存活的
+        without_interrupts(|| {
+            #[cfg(feature = "heap-canaries")]
+            canary::verify_on_free(ptr, layout);
+            #[cfg(feature = "alloc-track")]
+            crate::alloc_track::on_dealloc(ptr);
+            self.inner_dealloc(ptr, layout);
+        });
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<A: GlobalAlloc> Counting<A> {
+    // 启用heap-canaries时在用户区域前后多分配哨兵的空间
+    #[cfg(feature = "heap-canaries")]
+    unsafe fn inner_alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(outer) = canary::outer_layout(layout) else {
+            return core::ptr::null_mut();
+        };
+        let ptr = self.inner.alloc(outer);
+        if ptr.is_null() {
+            return ptr;
+        }
+        canary::arm(ptr, layout)
+    }
+
+    #[cfg(not(feature = "heap-canaries"))]
+    unsafe fn inner_alloc(&self, layout: Layout) -> *mut u8 {
+        self.inner.alloc(layout)
+    }
+
+    #[cfg(feature = "heap-canaries")]
+    unsafe fn inner_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let outer = canary::outer_layout(layout).expect("layout was checked at allocation");
+        self.inner.dealloc(canary::outer_ptr(ptr, layout), outer);
+    }
+
+    #[cfg(not(feature = "heap-canaries"))]
+    unsafe fn inner_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+/// spin::Mutex的包装, 用于在本crate中为分配器实现GlobalAlloc
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+/// 将`addr`向上对齐到`align`, `align`必须是2的幂
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
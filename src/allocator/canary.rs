@@ -0,0 +1,197 @@
+//! 堆哨兵: 每个分配的前后各写入8字节的固定值, 释放时和后台任务中检查是否被改写
+//!
+//! 只在启用`heap-canaries` feature时编译. 用户区域之前留`header_size`字节,
+//! 至少8字节且是对齐的整数倍, 因此用户指针的对齐不变. 对齐不超过8时每个分配多占16字节
+
+use core::alloc::Layout;
+use core::fmt;
+use core::ptr;
+
+use crate::log::Level;
+use crate::{alloc_track, log, symbols, time};
+
+const CANARY_SIZE: usize = 8;
+const HEAD: u64 = 0xFBFB_FBFB_FBFB_FBFB;
+const TAIL: u64 = 0xFDFD_FDFD_FDFD_FDFD;
+/// 后台检查的间隔
+pub const SCRUB_INTERVAL_MS: u64 = 5000;
+
+fn header_size(layout: Layout) -> usize {
+    layout.align().max(CANARY_SIZE)
+}
+
+/// 内部分配器实际分配的大小和对齐
+pub(crate) fn outer_layout(layout: Layout) -> Option<Layout> {
+    let size = header_size(layout)
+        .checked_add(layout.size())?
+        .checked_add(CANARY_SIZE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// 在`outer`分配的内存中写入哨兵, 返回交给用户的指针
+///
+/// 调用者需保证`outer`按`outer_layout(layout)`分配
+pub(crate) unsafe fn arm(outer: *mut u8, layout: Layout) -> *mut u8 {
+    let user = outer.add(header_size(layout));
+    ptr::write_unaligned(user.sub(CANARY_SIZE).cast::<u64>(), HEAD);
+    ptr::write_unaligned(user.add(layout.size()).cast::<u64>(), TAIL);
+    user
+}
+
+/// `arm`返回的指针对应的内部分配
+pub(crate) fn outer_ptr(user: *mut u8, layout: Layout) -> *mut u8 {
+    user.wrapping_sub(header_size(layout))
+}
+
+/// 被改写的哨兵
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Damage {
+    /// 用户区域之前, 通常是前一个分配越界写入或下标为负
+    Head,
+    /// 用户区域之后, 越界写入
+    Tail,
+}
+
+/// 检查`size`字节的用户区域`user`前后的哨兵
+///
+/// # Safety
+///
+/// 调用者需保证`user`是仍然存活的分配
+pub unsafe fn check(user: *const u8, size: usize) -> Result<(), Damage> {
+    if ptr::read_unaligned(user.sub(CANARY_SIZE).cast::<u64>()) != HEAD {
+        return Err(Damage::Head);
+    }
+    if ptr::read_unaligned(user.add(size).cast::<u64>()) != TAIL {
+        return Err(Damage::Tail);
+    }
+    Ok(())
+}
+
+/// 发现的损坏和分配时的调用位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    pub ptr: usize,
+    pub size: usize,
+    pub damage: Damage,
+    /// 没有记录(分配表已满)时为None
+    pub call_site: Option<u64>,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let side = match self.damage {
+            Damage::Head => "before",
+            Damage::Tail => "after",
+        };
+        write!(
+            f,
+            "heap corruption: canary {} {}-byte allocation at {:#x} overwritten",
+            side, self.size, self.ptr
+        )?;
+        let Some(site) = self.call_site else {
+            return f.write_str(", call site unknown");
+        };
+        write!(f, ", allocated at {:#x}", site)?;
+        if let Some((name, offset)) = symbols::resolve(site) {
+            write!(f, " <{}+{:#x}>", symbols::Demangle(name), offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// 由全局分配器在释放前关中断调用, 哨兵被改写时panic
+///
+/// 调用者需保证`user`是仍然存活的分配
+pub(crate) unsafe fn verify_on_free(user: *mut u8, layout: Layout) {
+    if let Err(damage) = check(user, layout.size()) {
+        let corruption = Corruption {
+            ptr: user as usize,
+            size: layout.size(),
+            damage,
+            call_site: alloc_track::call_site_of(user),
+        };
+        panic!("{}", corruption);
+    }
+}
+
+/// 检查分配表中所有存活分配的哨兵, 返回第一处损坏
+pub fn verify_all() -> Result<(), Corruption> {
+    // 检查期间分配表被锁住, 表中的分配不会被释放
+    let found =
+        alloc_track::find_live(|ptr, size| unsafe { check(ptr as *const u8, size) }.is_err());
+    match found {
+        None => Ok(()),
+        Some((ptr, size, site)) => Err(Corruption {
+            ptr,
+            size,
+            damage: unsafe { check(ptr as *const u8, size) }.unwrap_err(),
+            call_site: Some(site),
+        }),
+    }
+}
+
+/// 后台任务: 定期检查所有存活分配, 报告第一处损坏后结束
+pub async fn scrub() {
+    loop {
+        time::sleep_ms(SCRUB_INTERVAL_MS).await;
+        if let Err(corruption) = verify_all() {
+            log!(Level::Error, "{}", corruption);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn overrun_box() -> alloc::boxed::Box<[u8]> {
+    alloc::boxed::Box::new([0u8; 24])
+}
+
+#[test_case]
+fn test_overrun_is_detected() {
+    let mut boxed = core::hint::black_box(overrun_box());
+    let user = boxed.as_mut_ptr();
+    assert_eq!(verify_all(), Ok(()));
+    // 越界写入一个字节
+    unsafe { user.add(24).write(0) };
+    assert_eq!(unsafe { check(user, 24) }, Err(Damage::Tail));
+    let corruption = verify_all().unwrap_err();
+    assert_eq!((corruption.ptr, corruption.size), (user as usize, 24));
+    assert_eq!(corruption.damage, Damage::Tail);
+    if symbols::kernel_symbols().is_some() {
+        let site = corruption.call_site.and_then(symbols::resolve);
+        assert!(
+            site.is_some_and(|(name, _)| name.contains("overrun_box")),
+            "{}",
+            corruption
+        );
+    }
+    // 恢复哨兵后正常释放
+    unsafe { ptr::write_unaligned(user.add(24).cast::<u64>(), TAIL) };
+    assert_eq!(verify_all(), Ok(()));
+    drop(boxed);
+}
+
+#[test_case]
+fn test_aligned_allocations_round_trip() {
+    use alloc::alloc::{alloc, dealloc};
+
+    for align in [1, 2, 8, 16, 32, 64, 256] {
+        for size in [1, 7, 24, 100] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0, "align {}", align);
+                for i in 0..size {
+                    ptr.add(i).write(i as u8);
+                }
+                assert_eq!(check(ptr, size), Ok(()));
+                for i in 0..size {
+                    assert_eq!(ptr.add(i).read(), i as u8);
+                }
+                dealloc(ptr, layout);
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::cpu::msr::{self, ApicBase};
+use crate::{acpi, cpu, memory};
+
+pub mod ioapic;
+
+pub use ioapic::RedirectionEntry;
+
+// local APIC寄存器偏移
+const REG_ID: u64 = 0x20;
+const REG_VERSION: u64 = 0x30;
+const REG_EOI: u64 = 0xB0;
+const REG_SPURIOUS: u64 = 0xF0;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+// 中断命令寄存器: 投递模式在8..11位, bit12为投递状态, bit14为电平有效
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// local APIC伪中断使用的向量, 低4位必须全为1
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    NotSupported,
+    NoMadt,
+    MapFailed,
+    /// 没有IO-APIC覆盖这个GSI
+    NoIoApic(u32),
+    NotInitialized,
+}
+
+// x2APIC模式下寄存器映射到从0x800开始的MSR, 编号为MMIO偏移除以16
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// local APIC寄存器的访问方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// xAPIC, 寄存器通过映射的MMIO页访问
+    XApic(VirtAddr),
+    /// x2APIC, 寄存器通过rdmsr/wrmsr访问
+    X2Apic,
+}
+
+/// local APIC, 上层代码不区分xAPIC和x2APIC
+pub struct LocalApic {
+    mode: ApicMode,
+}
+
+impl LocalApic {
+    fn read(&self, reg: u64) -> u32 {
+        match self.mode {
+            ApicMode::XApic(base) => unsafe { read_volatile((base + reg).as_ptr()) },
+            ApicMode::X2Apic => unsafe { msr::read(x2apic_msr(reg)) as u32 },
+        }
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+        match self.mode {
+            ApicMode::XApic(base) => unsafe { write_volatile((base + reg).as_mut_ptr(), value) },
+            ApicMode::X2Apic => unsafe { msr::write(x2apic_msr(reg), u64::from(value)) },
+        }
+    }
+
+    pub fn mode(&self) -> ApicMode {
+        self.mode
+    }
+
+    /// xAPIC的ID在高8位, x2APIC的ID占满32位
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            ApicMode::XApic(_) => self.read(REG_ID) >> 24,
+            ApicMode::X2Apic => self.read(REG_ID),
+        }
+    }
+
+    /// 低8位是版本号, 16..24位是最后一个LVT项的索引
+    pub fn version(&self) -> u32 {
+        self.read(REG_VERSION)
+    }
+
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    // 软件启用local APIC, 固件已把LINT0设为ExtINT, 8259的中断仍然经由它送达
+    fn enable(&self) {
+        let spurious = self.read(REG_SPURIOUS) & !0xFF;
+        self.write(REG_SPURIOUS, spurious | SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+
+    /// 向APIC ID为`apic_id`的处理器发送处理器间中断, `command`是ICR的低32位
+    pub fn send_ipi(&self, apic_id: u32, command: u32) {
+        match self.mode {
+            ApicMode::XApic(_) => {
+                // 写低32位时发送, 目标在高32位的最高8位
+                self.write(REG_ICR_HIGH, apic_id << 24);
+                self.write(REG_ICR_LOW, command);
+                while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            }
+            // x2APIC的ICR是一个64位MSR, 没有投递状态位
+            ApicMode::X2Apic => unsafe {
+                msr::write(x2apic_msr(REG_ICR_LOW), u64::from(apic_id) << 32 | u64::from(command));
+            },
+        }
+    }
+
+    /// 使目标处理器复位并进入等待SIPI的状态
+    pub fn send_init(&self, apic_id: u32) {
+        self.send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// 使等待SIPI的处理器从实模式地址`page * 4096`开始执行
+    pub fn send_startup(&self, apic_id: u32, page: u8) {
+        self.send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+    }
+}
+
+fn x2apic_msr(reg: u64) -> u32 {
+    X2APIC_MSR_BASE + (reg >> 4) as u32
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+// 切换到x2APIC之前经MMIO读到的(ID, 版本), 用于核对两种访问方式
+static XAPIC_REGISTERS: Once<(u32, u32)> = Once::new();
+
+/// 启用BSP的local APIC并映射MADT中的IO-APIC, 8259 PIC保持原样继续工作
+///
+/// 处理器支持时切换到x2APIC模式, 固件已经切换过时直接沿用
+pub fn init() -> Result<(), ApicError> {
+    let features = cpu::features();
+    if !features.apic {
+        return Err(ApicError::NotSupported);
+    }
+    let apic_base = ApicBase::read();
+    if !apic_base.enabled() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+
+    let mode = if apic_base.x2apic_enabled() {
+        // x2APIC不能直接切回xAPIC, MMIO接口此时不可用
+        ApicMode::X2Apic
+    } else {
+        let base = memory::map_mmio(PhysAddr::new(madt.local_apic_address), 4096)
+            .map_err(|_| ApicError::MapFailed)?;
+        let xapic = LocalApic { mode: ApicMode::XApic(base) };
+        if features.x2apic {
+            XAPIC_REGISTERS.call_once(|| (xapic.id(), xapic.version()));
+            unsafe { apic_base.with_x2apic(true).write() };
+            ApicMode::X2Apic
+        } else {
+            xapic.mode
+        }
+    };
+
+    let lapic = LocalApic { mode };
+    lapic.enable();
+    LOCAL_APIC.call_once(|| lapic);
+
+    ioapic::init(madt.io_apics)
+}
+
+/// 在AP上启用它自己的local APIC, 访问方式与BSP相同. BSP没有启用APIC时返回None
+pub fn init_ap() -> Option<&'static LocalApic> {
+    let lapic = LOCAL_APIC.get()?;
+    let apic_base = ApicBase::read();
+    if lapic.mode == ApicMode::X2Apic && !apic_base.x2apic_enabled() {
+        unsafe { apic_base.with_x2apic(true).write() };
+    }
+    lapic.enable();
+    Some(lapic)
+}
+
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// 经IO-APIC投递的中断处理完毕时调用, 8259的中断仍然向PIC发送EOI
+pub fn eoi() {
+    if let Some(lapic) = LOCAL_APIC.get() {
+        lapic.eoi();
+    }
+}
+
+#[test_case]
+fn test_local_apic_registers() {
+    let Some(lapic) = local_apic() else {
+        return;
+    };
+    // 集成在处理器中的APIC版本号为0x1X
+    assert_eq!(lapic.version() & 0xF0, 0x10);
+    let madt = acpi::madt().unwrap();
+    assert!(madt.processors.iter().any(|p| p.enabled && p.apic_id == lapic.id()));
+}
+
+#[test_case]
+fn test_x2apic_matches_mmio() {
+    assert_eq!(x2apic_msr(REG_ID), 0x802);
+    assert_eq!(x2apic_msr(REG_EOI), 0x80B);
+    let Some(lapic) = local_apic() else {
+        return;
+    };
+    assert_eq!(lapic.mode() == ApicMode::X2Apic, ApicBase::read().x2apic_enabled());
+    // 只有由本内核完成切换时才有MMIO读到的值可供比较
+    let Some(&(id, version)) = XAPIC_REGISTERS.get() else {
+        return;
+    };
+    assert_eq!(lapic.mode(), ApicMode::X2Apic);
+    assert_eq!(lapic.id(), id);
+    assert_eq!(lapic.version(), version);
+}
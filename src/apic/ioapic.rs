@@ -0,0 +1,155 @@
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::ApicError;
+use crate::{acpi, memory};
+
+// 间接访问: 先把寄存器号写入IOREGSEL, 再读写IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+/// IO-APIC重定向表中的一项
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry(pub u64);
+
+impl RedirectionEntry {
+    const ACTIVE_LOW: u64 = 1 << 13;
+    const LEVEL_TRIGGERED: u64 = 1 << 15;
+    const MASKED: u64 = 1 << 16;
+    const DESTINATION_SHIFT: u64 = 56;
+
+    /// 固定投递到物理APIC ID为`apic_id`的处理器, 高电平有效, 边沿触发
+    pub fn new(vector: u8, apic_id: u8) -> RedirectionEntry {
+        RedirectionEntry(u64::from(vector) | u64::from(apic_id) << Self::DESTINATION_SHIFT)
+    }
+
+    pub fn active_low(self) -> RedirectionEntry {
+        RedirectionEntry(self.0 | Self::ACTIVE_LOW)
+    }
+
+    pub fn level_triggered(self) -> RedirectionEntry {
+        RedirectionEntry(self.0 | Self::LEVEL_TRIGGERED)
+    }
+
+    pub fn masked(self) -> RedirectionEntry {
+        RedirectionEntry(self.0 | Self::MASKED)
+    }
+
+    pub fn vector(self) -> u8 {
+        self.0 as u8
+    }
+
+    pub fn destination(self) -> u8 {
+        (self.0 >> Self::DESTINATION_SHIFT) as u8
+    }
+
+    pub fn is_masked(self) -> bool {
+        self.0 & Self::MASKED != 0
+    }
+}
+
+struct IoApic {
+    gsi_base: u32,
+    entries: u32,
+    // 寄存器选择和窗口访问之间不能被打断
+    base: Mutex<VirtAddr>,
+}
+
+unsafe fn read(base: VirtAddr, reg: u32) -> u32 {
+    write_volatile((base + IOREGSEL).as_mut_ptr(), reg);
+    read_volatile((base + IOWIN).as_ptr())
+}
+
+unsafe fn write(base: VirtAddr, reg: u32, value: u32) {
+    write_volatile((base + IOREGSEL).as_mut_ptr(), reg);
+    write_volatile((base + IOWIN).as_mut_ptr(), value);
+}
+
+impl IoApic {
+    fn covers(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+
+    fn entry(&self, pin: u32) -> RedirectionEntry {
+        let base = self.base.lock();
+        let reg = REG_REDIRECTION + pin * 2;
+        let (low, high) = unsafe { (read(*base, reg), read(*base, reg + 1)) };
+        RedirectionEntry(u64::from(high) << 32 | u64::from(low))
+    }
+
+    fn set_entry(&self, pin: u32, entry: RedirectionEntry) {
+        let base = self.base.lock();
+        let reg = REG_REDIRECTION + pin * 2;
+        // 先屏蔽再写高32位, 避免写到一半的表项投递中断
+        unsafe {
+            write(*base, reg, RedirectionEntry::MASKED as u32);
+            write(*base, reg + 1, (entry.0 >> 32) as u32);
+            write(*base, reg, entry.0 as u32);
+        }
+    }
+}
+
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+
+pub(super) fn init(io_apics: &[acpi::IoApic]) -> Result<(), ApicError> {
+    let mut mapped = Vec::new();
+    for info in io_apics {
+        let base = memory::map_mmio(PhysAddr::new(u64::from(info.address)), 4096)
+            .map_err(|_| ApicError::MapFailed)?;
+        let version = unsafe { read(base, REG_VERSION) };
+        mapped.push(IoApic {
+            gsi_base: info.gsi_base,
+            entries: (version >> 16 & 0xFF) + 1,
+            base: Mutex::new(base),
+        });
+    }
+    IO_APICS.call_once(|| mapped);
+    Ok(())
+}
+
+fn find(gsi: u32) -> Result<&'static IoApic, ApicError> {
+    let io_apics = IO_APICS.get().ok_or(ApicError::NotInitialized)?;
+    io_apics
+        .iter()
+        .find(|io_apic| io_apic.covers(gsi))
+        .ok_or(ApicError::NoIoApic(gsi))
+}
+
+/// 是否有IO-APIC覆盖全局系统中断`gsi`
+pub fn covers(gsi: u32) -> bool {
+    find(gsi).is_ok()
+}
+
+/// 按`entry`投递全局系统中断`gsi`
+pub fn route(gsi: u32, entry: RedirectionEntry) -> Result<(), ApicError> {
+    let io_apic = find(gsi)?;
+    interrupts::without_interrupts(|| io_apic.set_entry(gsi - io_apic.gsi_base, entry));
+    Ok(())
+}
+
+/// 屏蔽`gsi`, 保留表项的其他内容
+pub fn mask(gsi: u32) -> Result<(), ApicError> {
+    let io_apic = find(gsi)?;
+    let pin = gsi - io_apic.gsi_base;
+    interrupts::without_interrupts(|| io_apic.set_entry(pin, io_apic.entry(pin).masked()));
+    Ok(())
+}
+
+#[test_case]
+fn test_redirection_entry_bits() {
+    let entry = RedirectionEntry::new(0x30, 1);
+    assert_eq!(entry.0, 0x0100_0000_0000_0030);
+    assert_eq!((entry.vector(), entry.destination()), (0x30, 1));
+    assert!(!entry.is_masked());
+
+    let entry = entry.active_low().level_triggered().masked();
+    assert_eq!(entry.0 & 0xFFFF_FFFF, 0x0001_A030);
+    assert!(entry.is_masked());
+}
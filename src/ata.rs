@@ -0,0 +1,442 @@
+use alloc::format;
+use core::str;
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::selftest::{self, Check, Outcome};
+use crate::time::{self, Elapsed};
+
+pub use crate::block::SECTOR_SIZE;
+
+// 主通道端口
+const PRIMARY_IO_BASE: u16 = 0x1F0;
+const PRIMARY_CONTROL: u16 = 0x3F6;
+
+// 状态寄存器
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+// 总线上没有任何设备时读到的值
+const STATUS_FLOATING: u8 = 0xFF;
+
+// 设备控制寄存器, 使用轮询, 关闭设备中断
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const DRIVE_SELECT: u8 = 0xA0;
+const DRIVE_LBA: u8 = 0x40;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// IDENTIFY数据中的字偏移
+const IDENTIFY_MODEL: usize = 27;
+const IDENTIFY_MODEL_WORDS: usize = 20;
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+
+const LBA28_LIMIT: u64 = 1 << 28;
+// 一条命令最多传输的扇区数, 扇区数寄存器写0表示256
+const MAX_SECTORS_PER_COMMAND: u16 = 256;
+
+const TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    NoDevice,
+    Timeout,
+    /// 错误寄存器的值
+    DeviceError(u8),
+    DeviceFault,
+    BufferSize,
+    InvalidCount,
+    OutOfRange,
+}
+
+impl From<Elapsed> for AtaError {
+    fn from(_: Elapsed) -> Self {
+        AtaError::Timeout
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Master,
+    Slave,
+}
+
+/// IDENTIFY返回的驱动器信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriveInfo {
+    model: [u8; IDENTIFY_MODEL_WORDS * 2],
+    model_len: usize,
+    sectors: u32,
+}
+
+impl DriveInfo {
+    /// 解析IDENTIFY返回的256个字
+    pub fn parse(words: &[u16; 256]) -> Self {
+        // 型号字符串每个字的高字节在前, 尾部以空格填充
+        let mut model = [0; IDENTIFY_MODEL_WORDS * 2];
+        for (i, word) in words[IDENTIFY_MODEL..IDENTIFY_MODEL + IDENTIFY_MODEL_WORDS]
+            .iter()
+            .enumerate()
+        {
+            model[2 * i..2 * i + 2].copy_from_slice(&word.to_be_bytes());
+        }
+        let model_len = model
+            .iter()
+            .rposition(|&b| b != b' ' && b != 0)
+            .map_or(0, |last| last + 1);
+
+        let sectors = u32::from(words[IDENTIFY_LBA28_SECTORS])
+            | u32::from(words[IDENTIFY_LBA28_SECTORS + 1]) << 16;
+
+        DriveInfo {
+            model,
+            model_len,
+            sectors,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        str::from_utf8(&self.model[..self.model_len]).unwrap_or("<invalid>")
+    }
+
+    /// LBA28可访问的扇区数
+    pub fn sector_count(&self) -> u32 {
+        self.sectors
+    }
+
+    // 检查一次读写请求是否合法
+    fn check_request(&self, lba: u32, count: u16, len: usize) -> Result<(), AtaError> {
+        if count == 0 || count > MAX_SECTORS_PER_COMMAND {
+            return Err(AtaError::InvalidCount);
+        }
+        if len != usize::from(count) * SECTOR_SIZE {
+            return Err(AtaError::BufferSize);
+        }
+        let end = u64::from(lba) + u64::from(count);
+        if end > u64::from(self.sectors) || end > LBA28_LIMIT {
+            return Err(AtaError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+struct Channel {
+    data: Port<u16>,
+    error: Port<u8>,
+    sector_count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive_head: Port<u8>,
+    status_command: Port<u8>,
+    // 读为备用状态寄存器(不清除中断), 写为设备控制寄存器
+    control: Port<u8>,
+    drives: [Option<DriveInfo>; 2],
+}
+
+impl Channel {
+    const fn new(io_base: u16, control: u16) -> Self {
+        Channel {
+            data: Port::new(io_base),
+            error: Port::new(io_base + 1),
+            sector_count: Port::new(io_base + 2),
+            lba_low: Port::new(io_base + 3),
+            lba_mid: Port::new(io_base + 4),
+            lba_high: Port::new(io_base + 5),
+            drive_head: Port::new(io_base + 6),
+            status_command: Port::new(io_base + 7),
+            control: Port::new(control),
+            drives: [None; 2],
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status_command.read() }
+    }
+
+    // 选择驱动器后需要约400ns设备才会更新状态, 读4次备用状态寄存器
+    fn delay_400ns(&mut self) {
+        for _ in 0..4 {
+            unsafe {
+                self.control.read();
+            }
+        }
+    }
+
+    fn wait_not_busy(&mut self) -> Result<u8, AtaError> {
+        let status = &mut self.status_command;
+        time::wait_until(TIMEOUT_MS, || unsafe { status.read() } & STATUS_BSY == 0)?;
+        Ok(self.status())
+    }
+
+    // 等待设备准备好传输数据, 并检查错误位
+    fn wait_data_request(&mut self) -> Result<(), AtaError> {
+        let status = &mut self.status_command;
+        time::wait_until(TIMEOUT_MS, || {
+            let value = unsafe { status.read() };
+            value & STATUS_BSY == 0 && value & (STATUS_DRQ | STATUS_ERR | STATUS_DF) != 0
+        })?;
+        self.check_error()
+    }
+
+    fn check_error(&mut self) -> Result<(), AtaError> {
+        let status = self.status();
+        if status & STATUS_ERR != 0 {
+            return Err(AtaError::DeviceError(unsafe { self.error.read() }));
+        }
+        if status & STATUS_DF != 0 {
+            return Err(AtaError::DeviceFault);
+        }
+        Ok(())
+    }
+
+    fn identify(&mut self, position: Position) -> Result<DriveInfo, AtaError> {
+        if self.status() == STATUS_FLOATING {
+            return Err(AtaError::NoDevice);
+        }
+        unsafe {
+            self.drive_head.write(DRIVE_SELECT | slave_bit(position));
+        }
+        self.delay_400ns();
+        unsafe {
+            self.sector_count.write(0);
+            self.lba_low.write(0);
+            self.lba_mid.write(0);
+            self.lba_high.write(0);
+            self.status_command.write(CMD_IDENTIFY);
+        }
+        if self.status() == 0 {
+            return Err(AtaError::NoDevice);
+        }
+        self.wait_not_busy()?;
+        // ATAPI等设备会在LBA寄存器中留下签名
+        if unsafe { self.lba_mid.read() != 0 || self.lba_high.read() != 0 } {
+            return Err(AtaError::NoDevice);
+        }
+        self.wait_data_request()?;
+
+        let mut words = [0u16; 256];
+        for word in words.iter_mut() {
+            *word = unsafe { self.data.read() };
+        }
+        Ok(DriveInfo::parse(&words))
+    }
+
+    fn detect(&mut self) {
+        unsafe {
+            self.control.write(CONTROL_NIEN);
+        }
+        for (slot, position) in [Position::Master, Position::Slave].into_iter().enumerate() {
+            self.drives[slot] = self.identify(position).ok();
+        }
+    }
+
+    fn drive(&self, position: Position) -> Result<DriveInfo, AtaError> {
+        self.drives[position as usize].ok_or(AtaError::NoDevice)
+    }
+
+    fn start_command(
+        &mut self,
+        position: Position,
+        lba: u32,
+        count: u16,
+        command: u8,
+    ) -> Result<(), AtaError> {
+        self.wait_not_busy()?;
+        unsafe {
+            let lba_high_bits = (lba >> 24) as u8 & 0x0F;
+            self.drive_head
+                .write(DRIVE_SELECT | DRIVE_LBA | slave_bit(position) | lba_high_bits);
+        }
+        self.delay_400ns();
+        self.wait_not_busy()?;
+        unsafe {
+            // 256个扇区写为0
+            self.sector_count.write(count as u8);
+            self.lba_low.write(lba as u8);
+            self.lba_mid.write((lba >> 8) as u8);
+            self.lba_high.write((lba >> 16) as u8);
+            self.status_command.write(command);
+        }
+        Ok(())
+    }
+
+    fn read_sectors(
+        &mut self,
+        position: Position,
+        lba: u32,
+        count: u16,
+        buf: &mut [u8],
+    ) -> Result<(), AtaError> {
+        self.drive(position)?.check_request(lba, count, buf.len())?;
+        self.start_command(position, lba, count, CMD_READ_SECTORS)?;
+
+        for sector in buf.chunks_exact_mut(SECTOR_SIZE) {
+            self.delay_400ns();
+            self.wait_data_request()?;
+            for bytes in sector.chunks_exact_mut(2) {
+                let word: u16 = unsafe { self.data.read() };
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        position: Position,
+        lba: u32,
+        count: u16,
+        buf: &[u8],
+    ) -> Result<(), AtaError> {
+        self.drive(position)?.check_request(lba, count, buf.len())?;
+        self.start_command(position, lba, count, CMD_WRITE_SECTORS)?;
+
+        for sector in buf.chunks_exact(SECTOR_SIZE) {
+            self.delay_400ns();
+            self.wait_data_request()?;
+            for bytes in sector.chunks_exact(2) {
+                unsafe {
+                    self.data.write(u16::from_le_bytes([bytes[0], bytes[1]]));
+                }
+            }
+        }
+
+        // 确保数据写入介质后才返回
+        self.delay_400ns();
+        self.wait_not_busy()?;
+        unsafe {
+            self.status_command.write(CMD_CACHE_FLUSH);
+        }
+        self.delay_400ns();
+        self.wait_not_busy()?;
+        self.check_error()
+    }
+}
+
+fn slave_bit(position: Position) -> u8 {
+    match position {
+        Position::Master => 0,
+        Position::Slave => DRIVE_SLAVE,
+    }
+}
+
+// 整个命令期间持有锁, 防止不同任务的命令交错. 等待超时依赖时钟中断, 不能在中断中调用
+static PRIMARY: Mutex<Channel> = Mutex::new(Channel::new(PRIMARY_IO_BASE, PRIMARY_CONTROL));
+
+/// 检测主通道上的驱动器, 依赖时钟中断实现超时
+pub fn init() {
+    PRIMARY.lock().detect();
+}
+
+/// 主通道上的一个驱动器
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Drive {
+    position: Position,
+    info: DriveInfo,
+}
+
+impl Drive {
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    pub fn model(&self) -> &str {
+        self.info.model()
+    }
+
+    pub fn sector_count(&self) -> u32 {
+        self.info.sector_count()
+    }
+
+    /// 从`lba`开始读取`count`个扇区, `buf`的长度必须为`count * 512`
+    pub fn read_sectors(&self, lba: u32, count: u16, buf: &mut [u8]) -> Result<(), AtaError> {
+        PRIMARY.lock().read_sectors(self.position, lba, count, buf)
+    }
+
+    /// 从`lba`开始写入`count`个扇区, `buf`的长度必须为`count * 512`
+    pub fn write_sectors(&self, lba: u32, count: u16, buf: &[u8]) -> Result<(), AtaError> {
+        PRIMARY.lock().write_sectors(self.position, lba, count, buf)
+    }
+}
+
+/// 获取检测到的驱动器
+pub fn drive(position: Position) -> Option<Drive> {
+    let info = PRIMARY.lock().drive(position).ok()?;
+    Some(Drive { position, info })
+}
+
+/// 登记驱动器的自检, 超时依赖时钟中断
+pub fn register_selftests() {
+    static IDENTIFY: Check = Check::new("ata", identify_selftest).requires(&["timer"]);
+    selftest::register(&IDENTIFY).expect("duplicate ATA selftest");
+}
+
+// 重新IDENTIFY检测到的驱动器, 结果应与检测时相同
+fn identify_selftest() -> Outcome {
+    let mut channel = PRIMARY.lock();
+    let mut found = 0;
+    for position in [Position::Master, Position::Slave] {
+        let Ok(expected) = channel.drive(position) else {
+            continue;
+        };
+        found += 1;
+        match channel.identify(position) {
+            Ok(info) if info == expected => {}
+            Ok(info) => {
+                return Outcome::Fail(format!(
+                    "{:?} drive now identifies as {:?}, {} sectors",
+                    position,
+                    info.model(),
+                    info.sector_count()
+                ))
+            }
+            Err(err) => {
+                return Outcome::Fail(format!("{:?} drive: IDENTIFY failed: {:?}", position, err))
+            }
+        }
+    }
+    if found == 0 {
+        Outcome::Skipped("no drives on the primary channel".into())
+    } else {
+        Outcome::Pass
+    }
+}
+
+#[cfg(test)]
+fn identify_fixture(model: &[u8], sectors: u32) -> [u16; 256] {
+    let mut words = [0u16; 256];
+    let mut padded = [b' '; IDENTIFY_MODEL_WORDS * 2];
+    padded[..model.len()].copy_from_slice(model);
+    for (i, pair) in padded.chunks_exact(2).enumerate() {
+        words[IDENTIFY_MODEL + i] = u16::from_be_bytes([pair[0], pair[1]]);
+    }
+    words[IDENTIFY_LBA28_SECTORS] = sectors as u16;
+    words[IDENTIFY_LBA28_SECTORS + 1] = (sectors >> 16) as u16;
+    words
+}
+
+#[test_case]
+fn test_identify_parse() {
+    let info = DriveInfo::parse(&identify_fixture(b"QEMU HARDDISK", 0x0012_3456));
+    assert_eq!(info.model(), "QEMU HARDDISK");
+    assert_eq!(info.sector_count(), 0x0012_3456);
+}
+
+#[test_case]
+fn test_request_validation() {
+    let info = DriveInfo::parse(&identify_fixture(b"disk", 1024));
+    assert_eq!(info.check_request(0, 2, 1024), Ok(()));
+    assert_eq!(info.check_request(1022, 2, 1024), Ok(()));
+    assert_eq!(info.check_request(0, 2, 512), Err(AtaError::BufferSize));
+    assert_eq!(info.check_request(0, 0, 0), Err(AtaError::InvalidCount));
+    assert_eq!(info.check_request(0, 257, 257 * 512), Err(AtaError::InvalidCount));
+    assert_eq!(info.check_request(1023, 2, 1024), Err(AtaError::OutOfRange));
+}
@@ -0,0 +1,231 @@
+use core::arch::asm;
+use core::fmt;
+use core::ops::Range;
+
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::{memory, symbols};
+
+/// 最多打印的栈帧数
+pub const MAX_DEPTH: usize = 32;
+// 查找栈顶时最多向上检查的页数
+const MAX_STACK_PAGES: u64 = 256;
+
+/// 沿rbp链向上得到的返回地址. 内核以保留帧指针的方式编译, `[rbp]`是上一帧的rbp, `[rbp+8]`是返回地址
+///
+/// 每个rbp必须8字节对齐、高于上一帧、整个帧在栈的范围内且已映射, 否则停止, 不会触发页错误
+pub struct Frames {
+    rbp: u64,
+    // 下一帧允许的范围, 下界随着向上遍历提高
+    stack: Range<u64>,
+    depth: usize,
+}
+
+impl Frames {
+    /// 从`rbp`指向的栈帧开始, `stack`是它所在的栈
+    pub fn new(rbp: u64, stack: Range<u64>) -> Frames {
+        Frames {
+            rbp,
+            stack,
+            depth: 0,
+        }
+    }
+
+    // 读出当前帧保存的rbp和返回地址, 帧不合法时为None
+    fn frame(&self) -> Option<(u64, u64)> {
+        let rbp = self.rbp;
+        let end = rbp.checked_add(16)?;
+        if !rbp.is_multiple_of(8) || rbp < self.stack.start || end > self.stack.end {
+            return None;
+        }
+        Some((read_u64(rbp)?, read_u64(rbp + 8)?))
+    }
+
+    // 跳到上一帧
+    fn advance(&mut self, next: u64) {
+        self.stack.start = self.rbp + 16;
+        self.rbp = next;
+    }
+}
+
+impl Iterator for Frames {
+    type Item = VirtAddr;
+
+    fn next(&mut self) -> Option<VirtAddr> {
+        if self.depth >= MAX_DEPTH {
+            return None;
+        }
+        let (next, ret) = self.frame()?;
+        let ret = VirtAddr::try_new(ret)
+            .ok()
+            .filter(|ret| ret.as_u64() != 0)?;
+        self.depth += 1;
+        self.advance(next);
+        Some(ret)
+    }
+}
+
+// 不加锁地检查映射后读取, panic时页表锁可能被打断的代码持有
+fn read_u64(addr: u64) -> Option<u64> {
+    let addr = VirtAddr::try_new(addr).ok()?;
+    unsafe { memory::translate_unlocked(addr) }.ok()?;
+    Some(unsafe { addr.as_ptr::<u64>().read_volatile() })
+}
+
+/// 包含`rsp`的栈的顶端: 从rsp所在页向上直到第一个未映射的页, 最多`MAX_STACK_PAGES`页.
+/// `alloc_stack`分配的栈上方是下一个栈的保护页, 因此正好停在栈顶
+pub fn stack_top(rsp: u64) -> u64 {
+    let mut top = rsp & !0xfff;
+    for _ in 0..MAX_STACK_PAGES {
+        match VirtAddr::try_new(top) {
+            Ok(page) if unsafe { memory::translate_unlocked(page) }.is_ok() => top += 4096,
+            _ => break,
+        }
+    }
+    top
+}
+
+/// 调用者的栈帧链, 第一项是调用者的返回地址
+#[inline(always)]
+pub fn frames() -> Frames {
+    let (rbp, rsp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rbp", "mov {}, rsp", out(reg) rbp, out(reg) rsp,
+            options(nomem, nostack, preserves_flags));
+    }
+    Frames::new(rbp, rsp..stack_top(rsp))
+}
+
+/// 打印调用者的返回地址链
+#[inline(never)]
+pub fn print(out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(out, "backtrace:")?;
+    print_frames(out, frames(), 0)
+}
+
+/// 在异常处理函数中打印: 先是异常发生处的指令地址, 然后是被打断的代码的返回地址链
+///
+/// 处理函数的帧上方是CPU压入的中断栈帧而不是返回地址, 因此跳过处理函数自己的帧,
+/// 并按被打断时的rsp确定栈的范围, 使用IST时也能找到原来的栈
+#[inline(always)]
+pub fn print_exception(out: &mut dyn fmt::Write, stack_frame: &InterruptStackFrame) -> fmt::Result {
+    let handler = frames();
+    writeln!(out, "backtrace:")?;
+    print_frame(out, 0, stack_frame.instruction_pointer)?;
+    let Some((interrupted, _)) = handler.frame() else {
+        return Ok(());
+    };
+    let rsp = stack_frame.stack_pointer.as_u64();
+    print_frames(out, Frames::new(interrupted, rsp..stack_top(rsp)), 1)
+}
+
+fn print_frames(out: &mut dyn fmt::Write, frames: Frames, first: usize) -> fmt::Result {
+    for (i, ret) in frames.enumerate() {
+        print_frame(out, first + i, ret)?;
+    }
+    Ok(())
+}
+
+// 有符号表时在地址后附上所在的函数, 如`<toy_os::memory::translate+0x24>`
+pub(crate) fn print_frame(out: &mut dyn fmt::Write, index: usize, addr: VirtAddr) -> fmt::Result {
+    write!(out, "  {:2}: {:#018x}", index, addr.as_u64())?;
+    if let Some((name, offset)) = symbols::resolve(addr.as_u64()) {
+        write!(out, " <{}+{:#x}>", symbols::Demangle(name), offset)?;
+    }
+    writeln!(out)
+}
+
+// 每一层在调用之后还使用结果, 避免尾调用优化掉自己的栈帧
+#[cfg(test)]
+#[inline(never)]
+fn depth3() -> alloc::vec::Vec<VirtAddr> {
+    core::hint::black_box(frames().collect())
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn depth2() -> alloc::vec::Vec<VirtAddr> {
+    core::hint::black_box(depth3())
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn depth1() -> alloc::vec::Vec<VirtAddr> {
+    core::hint::black_box(depth2())
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn depth0() -> alloc::vec::Vec<VirtAddr> {
+    core::hint::black_box(depth1())
+}
+
+#[test_case]
+fn test_three_calls_deep() {
+    let frames = depth0();
+    assert!(frames.len() >= 3, "{:?}", frames);
+    // 返回地址依次落在各层调用者的函数体中
+    let callers: [fn() -> alloc::vec::Vec<VirtAddr>; 3] = [depth2, depth1, depth0];
+    for (ret, caller) in frames.iter().zip(callers) {
+        let (ret, caller) = (ret.as_u64() as usize, caller as usize);
+        assert!(
+            ret > caller && ret < caller + 0x100,
+            "{:#x} {:#x}",
+            ret,
+            caller
+        );
+    }
+
+    let mut out = alloc::string::String::new();
+    print(&mut out).unwrap();
+    assert!(
+        out.starts_with("backtrace:\n") && out.lines().count() > 2,
+        "{}",
+        out
+    );
+}
+
+#[test_case]
+fn test_corrupt_frame_pointers() {
+    // 在一个新栈上伪造帧链, 栈顶之上是下一个栈的保护页
+    let top = memory::alloc_stack(1).unwrap().as_u64();
+    let bottom = top - 4096;
+    let stack = bottom..top + 4096;
+    let write = |addr: u64, value: u64| unsafe { (addr as *mut u64).write(value) };
+    let frame = |i: u64| bottom + 16 * i;
+    for i in 0..64 {
+        write(frame(i), frame(i + 1));
+        write(frame(i) + 8, 0x1000 + i);
+    }
+    let walk = |start: u64| -> alloc::vec::Vec<u64> {
+        Frames::new(start, stack.clone())
+            .map(VirtAddr::as_u64)
+            .collect()
+    };
+    // 深度限制
+    assert_eq!(
+        walk(frame(0)),
+        (0x1000..0x1000 + MAX_DEPTH as u64).collect::<alloc::vec::Vec<_>>()
+    );
+
+    // 指向未映射的页
+    write(frame(3), top);
+    assert_eq!(walk(frame(0)), [0x1000, 0x1001, 0x1002, 0x1003]);
+    // 没有对齐
+    write(frame(3), frame(4) + 3);
+    assert_eq!(walk(frame(0)).len(), 4);
+    // 指回下方的帧, 否则会成环
+    write(frame(3), frame(1));
+    assert_eq!(walk(frame(0)).len(), 4);
+    // 非规范地址, 以及跨到未映射页的帧
+    write(frame(3), 0x8000_0000_0000);
+    assert_eq!(walk(frame(0)).len(), 4);
+    assert!(walk(top - 8).is_empty());
+    // 返回地址为0时结束
+    write(frame(3), frame(4));
+    write(frame(2) + 8, 0);
+    assert_eq!(walk(frame(0)), [0x1000, 0x1001]);
+
+    assert_eq!(stack_top(bottom + 8), top);
+}
@@ -0,0 +1,269 @@
+//! 批处理模式: 按命令行`run=<name>[,<name>...]`依次运行工作负载, 输出报告后退出
+//!
+//! 工作负载用`register`注册, 名称不是工作负载时作为不带参数的外壳命令执行.
+//! 内核不展开栈, 工作负载panic后不能继续运行其他工作负载: panic处理函数调用`on_panic`,
+//! 记录失败并输出报告, 之后的工作负载记为未运行
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::testing::FormatDuration;
+use crate::time::Instant;
+use crate::{
+    allocator, cmdline, exit_qemu, power, print, println, serial, serial_println, shell,
+    QemuExitCode,
+};
+
+/// 工作负载返回的错误信息
+pub type WorkloadResult = Result<(), String>;
+
+type Start = Box<dyn Fn() -> Pin<Box<dyn Future<Output = WorkloadResult>>> + Send>;
+
+struct Workload {
+    name: &'static str,
+    start: Start,
+}
+
+/// 同名的工作负载已经注册
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplicateWorkload;
+
+// 只在任务上下文中访问
+static WORKLOADS: Mutex<Vec<Workload>> = Mutex::new(Vec::new());
+
+/// 注册工作负载, 每次运行时调用`workload`创建新的future. 需要堆
+pub fn register<F, Fut>(name: &'static str, workload: F) -> Result<(), DuplicateWorkload>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = WorkloadResult> + 'static,
+{
+    let mut workloads = WORKLOADS.lock();
+    if workloads.iter().any(|workload| workload.name == name) {
+        return Err(DuplicateWorkload);
+    }
+    workloads.push(Workload {
+        name,
+        start: Box::new(move || Box::pin(workload())),
+    });
+    Ok(())
+}
+
+/// 注册内置的工作负载
+pub fn init() {
+    register("hello", hello).expect("duplicate batch workload");
+    register("heap_stress", heap_stress).expect("duplicate batch workload");
+}
+
+/// 命令行中`run=`列出的工作负载, 没有时为None
+pub fn requested() -> Option<Vec<&'static str>> {
+    cmdline::get("run").map(parse_names)
+}
+
+// 逗号分隔, 忽略空项
+fn parse_names(list: &'static str) -> Vec<&'static str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// 一个工作负载的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed(Duration),
+    Failed(String),
+    /// panic信息已经输出, 不在报告中重复, panic时分配内存可能死锁
+    Panicked,
+    NotRun,
+}
+
+struct Report {
+    names: Vec<&'static str>,
+    outcomes: Vec<Outcome>,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static REPORT: Mutex<Report> = Mutex::new(Report {
+    names: Vec::new(),
+    outcomes: Vec::new(),
+});
+
+/// 是否正在运行批处理, panic处理函数据此决定是否调用`on_panic`
+pub fn running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// 依次运行`names`中的工作负载, 结束后退出qemu或关机, 不会返回
+pub async fn run(names: Vec<&'static str>) {
+    {
+        let mut report = REPORT.lock();
+        report.outcomes = names.iter().map(|_| Outcome::NotRun).collect();
+        report.names = names.clone();
+    }
+    RUNNING.store(true, Ordering::SeqCst);
+    for (index, name) in names.iter().enumerate() {
+        println!("batch: running {}", name);
+        let start = Instant::now();
+        let result = run_one(name).await;
+        let outcome = match result {
+            Ok(()) => Outcome::Passed(start.elapsed()),
+            Err(message) => Outcome::Failed(message),
+        };
+        REPORT.lock().outcomes[index] = outcome;
+    }
+    finish();
+}
+
+async fn run_one(name: &str) -> WorkloadResult {
+    let future = WORKLOADS
+        .lock()
+        .iter()
+        .find(|workload| workload.name == name)
+        .map(|workload| (workload.start)());
+    if let Some(future) = future {
+        return future.await;
+    }
+    let mut out = String::new();
+    let result = shell::dispatch(name, &mut out).await;
+    print!("{}", out);
+    result.map_err(|err| match err {
+        shell::CmdError::UnknownCommand => "unknown workload".to_string(),
+        err => alloc::format!("command failed: {:?}", err),
+    })
+}
+
+// 输出报告, 有失败时以失败退出. 没有isa-debug-exit设备时端口写入无效, 改为关机
+fn finish() -> ! {
+    RUNNING.store(false, Ordering::SeqCst);
+    let failed = match REPORT.try_lock() {
+        Some(report) => {
+            let _ = write_report(&report, &mut serial::Serial);
+            report
+                .outcomes
+                .iter()
+                .any(|outcome| !matches!(outcome, Outcome::Passed(_)))
+        }
+        None => true,
+    };
+    exit_qemu(if failed {
+        QemuExitCode::Failed
+    } else {
+        QemuExitCode::Success
+    });
+    power::shutdown();
+}
+
+/// 由panic处理函数调用: 正在运行的工作负载记为失败, 输出报告后退出
+pub fn on_panic(info: &PanicInfo) -> ! {
+    serial_println!("batch: {}", info);
+    if let Some(mut report) = REPORT.try_lock() {
+        let current = report
+            .outcomes
+            .iter()
+            .position(|outcome| *outcome == Outcome::NotRun);
+        if let Some(index) = current {
+            report.outcomes[index] = Outcome::Panicked;
+        }
+    }
+    finish();
+}
+
+fn write_report(report: &Report, out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(out, "batch report:")?;
+    let mut failed = 0;
+    for (name, outcome) in report.names.iter().zip(&report.outcomes) {
+        match outcome {
+            Outcome::Passed(elapsed) => {
+                writeln!(out, "  {:<16}passed   {}", name, FormatDuration(*elapsed))?
+            }
+            Outcome::Failed(message) => {
+                failed += 1;
+                writeln!(out, "  {:<16}FAILED   {}", name, message)?
+            }
+            Outcome::Panicked => {
+                failed += 1;
+                writeln!(out, "  {:<16}PANICKED", name)?
+            }
+            Outcome::NotRun => {
+                failed += 1;
+                writeln!(out, "  {:<16}not run", name)?
+            }
+        }
+    }
+    writeln!(
+        out,
+        "batch result: {} workloads, {} failed",
+        report.names.len(),
+        failed
+    )
+}
+
+async fn hello() -> WorkloadResult {
+    println!("hello from batch mode");
+    Ok(())
+}
+
+// 反复分配和释放不同大小的内存, 结束后堆的使用量应回到开始时
+async fn heap_stress() -> WorkloadResult {
+    let before = allocator::heap_stats();
+    for round in 0..64usize {
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        for size in (1..=16).map(|i| i * 64 + round) {
+            blocks.push(alloc::vec![round as u8; size]);
+        }
+        let wrong_size = blocks
+            .iter()
+            .zip(1..)
+            .any(|(block, i)| block.len() != i * 64 + round);
+        if wrong_size {
+            return Err("block has the wrong size".to_string());
+        }
+        drop(blocks);
+        // 让出处理器, 其他任务和中断处理可以在两轮之间运行
+        crate::time::sleep_ticks(1).await;
+    }
+    let after = allocator::heap_stats();
+    if after.used != before.used {
+        return Err(alloc::format!(
+            "heap usage changed from {} to {} bytes",
+            before.used,
+            after.used
+        ));
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_parse_names() {
+    assert_eq!(parse_names("hello,heap_stress"), ["hello", "heap_stress"]);
+    assert_eq!(parse_names(" hello, ,mem,"), ["hello", "mem"]);
+    assert!(parse_names("").is_empty());
+}
+
+#[test_case]
+fn test_write_report() {
+    let report = Report {
+        names: alloc::vec!["hello", "heap_stress", "mem", "uptime"],
+        outcomes: alloc::vec![
+            Outcome::Passed(Duration::from_micros(250)),
+            Outcome::Failed("heap usage changed".to_string()),
+            Outcome::Panicked,
+            Outcome::NotRun,
+        ],
+    };
+    let mut out = String::new();
+    write_report(&report, &mut out).unwrap();
+    assert_eq!(
+        out,
+        "batch report:\n  hello           passed   250µs\n  heap_stress     FAILED   heap usage changed\n  mem             PANICKED\n  uptime          not run\nbatch result: 4 workloads, 3 failed\n"
+    );
+}
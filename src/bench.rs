@@ -0,0 +1,92 @@
+use alloc::vec::Vec;
+
+use crate::cpu::rdtsc_serialized;
+use crate::serial_println;
+
+// 编译时设置RUN_BENCHES才运行基准测试, 如`RUN_BENCHES=1 cargo test --lib`
+const RUN_BENCHES: Option<&str> = option_env!("RUN_BENCHES");
+
+// 正式计时前的预热次数
+const WARMUP_ITERATIONS: usize = 3;
+
+/// 每次迭代消耗的cycle数统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    pub min: u64,
+    pub median: u64,
+    pub mean: u64,
+}
+
+/// 是否启用了基准测试
+pub fn enabled() -> bool {
+    RUN_BENCHES.is_some()
+}
+
+/// 预热后运行`iterations`次`f`, 通过串口打印单行报告
+pub fn run(name: &str, iterations: usize, mut f: impl FnMut()) -> BenchResult {
+    assert!(iterations > 0, "bench {} needs at least one iteration", name);
+
+    for _ in 0..WARMUP_ITERATIONS {
+        f();
+    }
+
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = rdtsc_serialized();
+        f();
+        let end = rdtsc_serialized();
+        samples.push(end - start);
+    }
+
+    let result = summarize(&mut samples);
+    serial_println!(
+        "bench {}: {} iters, min {} / median {} / mean {} cycles/iter",
+        name, iterations, result.min, result.median, result.mean
+    );
+    result
+}
+
+// 样本会被排序
+fn summarize(samples: &mut [u64]) -> BenchResult {
+    samples.sort_unstable();
+    let total: u64 = samples.iter().sum();
+    BenchResult {
+        min: samples[0],
+        median: samples[samples.len() / 2],
+        mean: total / samples.len() as u64,
+    }
+}
+
+/// 定义一个基准测试用例, 中位数超过`max_median`个cycle时测试失败
+///
+/// ```ignore
+/// bench_case!(bench_name, 100, 1_000_000, || { /* ... */ });
+/// ```
+#[macro_export]
+macro_rules! bench_case {
+    ($name:ident, $iterations:expr, $max_median:expr, $body:expr) => {
+        #[test_case]
+        fn $name() {
+            if !$crate::bench::enabled() {
+                return;
+            }
+            $crate::with_timeout(60, || {
+                let result = $crate::bench::run(stringify!($name), $iterations, $body);
+                assert!(
+                    result.median <= $max_median,
+                    "bench {} regressed: median {} > {} cycles",
+                    stringify!($name),
+                    result.median,
+                    $max_median
+                );
+            });
+        }
+    };
+}
+
+#[test_case]
+fn test_summarize() {
+    let mut samples = [7, 3, 9, 1, 5];
+    let result = summarize(&mut samples);
+    assert_eq!(result, BenchResult { min: 1, median: 5, mean: 5 });
+}
@@ -0,0 +1,195 @@
+use alloc::vec::Vec;
+
+use crate::ata::{self, AtaError};
+use crate::error::KernelError;
+
+pub mod cache;
+pub mod request;
+pub mod threaded;
+
+pub use cache::{Cache, CacheStats};
+pub use request::{BlockOp, BlockRequest, RequestHandle, RequestQueue};
+pub use threaded::IoThread;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// 缓冲区长度不等于`count * 512`
+    BufferSize,
+    InvalidCount,
+    OutOfRange,
+    ReadOnly,
+    Timeout,
+    /// 设备报告的错误
+    Io,
+}
+
+/// 以512字节扇区为单位访问的块设备, 文件系统只依赖这个接口
+///
+/// 设备自己的错误作为`Context::Block`上下文返回
+pub trait BlockDevice: Sync {
+    fn sector_count(&self) -> u64;
+
+    /// 从`lba`开始读取`count`个扇区, `buf`的长度必须为`count * 512`
+    fn read_sectors(&self, lba: u64, count: usize, buf: &mut [u8]) -> Result<(), KernelError>;
+
+    /// 从`lba`开始写入`count`个扇区, `buf`的长度必须为`count * 512`
+    fn write_sectors(&self, lba: u64, count: usize, buf: &[u8]) -> Result<(), KernelError>;
+}
+
+/// 异步访问的块设备. 请求提交后立即返回, 等待结果的任务不占用执行器
+///
+/// 队列深度大于1的设备可以同时处理多个请求, 它们可能不按提交的顺序完成
+pub trait AsyncBlockDevice: Sync {
+    fn sector_count(&self) -> u64;
+
+    /// 提交一次读写. 超出设备范围等错误在返回的句柄中报告
+    fn submit(&self, request: BlockRequest) -> RequestHandle;
+
+    /// 从`lba`开始读取`count`个扇区, 结果是读到的内容
+    fn read(&self, lba: u64, count: usize) -> RequestHandle {
+        self.submit(BlockRequest::read(lba, count))
+    }
+
+    /// 从`lba`开始写入`data`, 长度必须是512的整数倍
+    fn write(&self, lba: u64, data: Vec<u8>) -> RequestHandle {
+        self.submit(BlockRequest::write(lba, data))
+    }
+}
+
+/// 在提交时直接执行请求的同步设备, 返回的句柄已经完成. 用于内存盘和测试设备,
+/// 需要等待硬件的设备应使用`IoThread`
+pub struct Inline<'a>(pub &'a dyn BlockDevice);
+
+impl AsyncBlockDevice for Inline<'_> {
+    fn sector_count(&self) -> u64 {
+        self.0.sector_count()
+    }
+
+    fn submit(&self, request: BlockRequest) -> RequestHandle {
+        RequestHandle::ready(execute(self.0, request))
+    }
+}
+
+// 在同步设备上执行一次请求, 返回请求的缓冲区
+fn execute(device: &dyn BlockDevice, mut request: BlockRequest) -> Result<Vec<u8>, KernelError> {
+    let (lba, count) = (request.lba, request.count());
+    match request.op {
+        BlockOp::Read => device.read_sectors(lba, count, &mut request.buf)?,
+        BlockOp::Write => device.write_sectors(lba, count, &request.buf)?,
+    }
+    Ok(request.buf)
+}
+
+/// 检查一次读写请求是否在设备范围内
+pub fn check_request(
+    sector_count: u64,
+    lba: u64,
+    count: usize,
+    len: usize,
+) -> Result<(), BlockError> {
+    if count == 0 {
+        return Err(BlockError::InvalidCount);
+    }
+    if count.checked_mul(SECTOR_SIZE) != Some(len) {
+        return Err(BlockError::BufferSize);
+    }
+    match lba.checked_add(count as u64) {
+        Some(end) if end <= sector_count => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// 设备上从`start`开始的连续`sectors`个扇区, 读写时加上起始偏移
+pub struct Partition<'a> {
+    device: &'a dyn BlockDevice,
+    start: u64,
+    sectors: u64,
+}
+
+impl<'a> Partition<'a> {
+    /// 分区超出设备末尾时返回`OutOfRange`
+    pub fn new(device: &'a dyn BlockDevice, start: u64, sectors: u64) -> Result<Self, BlockError> {
+        match start.checked_add(sectors) {
+            Some(end) if end <= device.sector_count() => Ok(Partition {
+                device,
+                start,
+                sectors,
+            }),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition<'_> {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, count: usize, buf: &mut [u8]) -> Result<(), KernelError> {
+        check_request(self.sectors, lba, count, buf.len())?;
+        self.device.read_sectors(self.start + lba, count, buf)
+    }
+
+    fn write_sectors(&self, lba: u64, count: usize, buf: &[u8]) -> Result<(), KernelError> {
+        check_request(self.sectors, lba, count, buf.len())?;
+        self.device.write_sectors(self.start + lba, count, buf)
+    }
+}
+
+impl From<AtaError> for BlockError {
+    fn from(err: AtaError) -> Self {
+        match err {
+            AtaError::BufferSize => BlockError::BufferSize,
+            AtaError::InvalidCount => BlockError::InvalidCount,
+            AtaError::OutOfRange => BlockError::OutOfRange,
+            AtaError::Timeout => BlockError::Timeout,
+            AtaError::NoDevice | AtaError::DeviceError(_) | AtaError::DeviceFault => BlockError::Io,
+        }
+    }
+}
+
+// 一条ATA命令最多传输256个扇区, 更大的请求拆分成多条命令
+const ATA_SECTORS_PER_COMMAND: usize = 256;
+
+impl BlockDevice for ata::Drive {
+    fn sector_count(&self) -> u64 {
+        u64::from(ata::Drive::sector_count(self))
+    }
+
+    fn read_sectors(&self, lba: u64, count: usize, buf: &mut [u8]) -> Result<(), KernelError> {
+        check_request(BlockDevice::sector_count(self), lba, count, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(ATA_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let start = lba + (i * ATA_SECTORS_PER_COMMAND) as u64;
+            let sectors = (chunk.len() / SECTOR_SIZE) as u16;
+            ata::Drive::read_sectors(self, start as u32, sectors, chunk)
+                .map_err(BlockError::from)?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, count: usize, buf: &[u8]) -> Result<(), KernelError> {
+        check_request(BlockDevice::sector_count(self), lba, count, buf.len())?;
+        for (i, chunk) in buf.chunks(ATA_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let start = lba + (i * ATA_SECTORS_PER_COMMAND) as u64;
+            let sectors = (chunk.len() / SECTOR_SIZE) as u16;
+            ata::Drive::write_sectors(self, start as u32, sectors, chunk)
+                .map_err(BlockError::from)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_check_request() {
+    assert_eq!(check_request(8, 0, 8, 8 * SECTOR_SIZE), Ok(()));
+    assert_eq!(check_request(8, 0, 1, 511), Err(BlockError::BufferSize));
+    assert_eq!(check_request(8, 0, 0, 0), Err(BlockError::InvalidCount));
+    assert_eq!(check_request(8, 7, 2, 2 * SECTOR_SIZE), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(8, u64::MAX, 1, SECTOR_SIZE), Err(BlockError::OutOfRange));
+}
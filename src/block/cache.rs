@@ -0,0 +1,611 @@
+//! 块设备的写回缓存
+//!
+//! 设备按4KiB(8个扇区)分块缓存, 每块占一个槽位. 索引记录各槽位对应的块, 槽位各有自己的锁,
+//! 两者都只在查找、替换和复制数据时短暂加锁, 等待设备时不持有任何锁: 一个任务在等待未命中的块时,
+//! 其他任务仍然可以读写已缓存的块. 使用中的槽位被钉住, 不会被替换. 被替换的脏块先写回,
+//! 写回期间仍留在索引中, 其他任务不会从设备读到旧的内容
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use super::{check_request, AsyncBlockDevice, BlockError, SECTOR_SIZE};
+use crate::error::KernelError;
+use crate::task;
+
+/// 每块的扇区数
+pub const BLOCK_SECTORS: usize = 8;
+/// 每块的字节数
+pub const BLOCK_SIZE: usize = BLOCK_SECTORS * SECTOR_SIZE;
+
+/// 缓存的统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// 访问的块已在缓存中
+    pub hits: u64,
+    /// 访问的块需要从设备读入, 或整块写入时分配了槽位
+    pub misses: u64,
+    /// 为其他块替换掉的块
+    pub evictions: u64,
+    /// 尚未写回的槽位数
+    pub dirty: usize,
+}
+
+struct Entry {
+    block: Option<u64>,
+    // 正在使用这个槽位的读写数
+    pins: u32,
+    last_used: u64,
+}
+
+struct Index {
+    entries: Vec<Entry>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+struct Slot {
+    // 槽中实际的内容, 为None或与索引不同时需要读入
+    block: Option<u64>,
+    dirty: bool,
+    // 每次写入加1, 写回完成时据此判断期间有没有新的修改
+    version: u64,
+    data: Box<[u8]>,
+}
+
+/// 包装任意块设备的缓存, 读取时按块读入, 写入只修改缓存中的块, 直到被替换或`flush`时才写回设备
+///
+/// 加锁顺序总是先索引后槽位. drop时提交脏块的写回但不等待完成, 写回失败的数据丢失
+pub struct Cache<'a> {
+    device: &'a dyn AsyncBlockDevice,
+    index: Mutex<Index>,
+    slots: Vec<Mutex<Slot>>,
+}
+
+// 钉住的槽位, drop时解除. 等待设备的任务被取消时也不会一直占着槽位
+struct Pinned<'c> {
+    index: &'c Mutex<Index>,
+    slot: usize,
+}
+
+impl Drop for Pinned<'_> {
+    fn drop(&mut self) {
+        self.index.lock().entries[self.slot].pins -= 1;
+    }
+}
+
+impl<'a> Cache<'a> {
+    /// 使用`slots`个槽位, 至少为1
+    pub fn new(device: &'a dyn AsyncBlockDevice, slots: usize) -> Self {
+        assert!(slots > 0, "block cache without slots");
+        let entries = (0..slots)
+            .map(|_| Entry {
+                block: None,
+                pins: 0,
+                last_used: 0,
+            })
+            .collect();
+        let slots = (0..slots)
+            .map(|_| {
+                Mutex::new(Slot {
+                    block: None,
+                    dirty: false,
+                    version: 0,
+                    data: vec![0; BLOCK_SIZE].into_boxed_slice(),
+                })
+            })
+            .collect();
+        Cache {
+            device,
+            index: Mutex::new(Index {
+                entries,
+                clock: 0,
+                hits: 0,
+                misses: 0,
+                evictions: 0,
+            }),
+            slots,
+        }
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let index = self.index.lock();
+        CacheStats {
+            hits: index.hits,
+            misses: index.misses,
+            evictions: index.evictions,
+            dirty: self.slots.iter().filter(|slot| slot.lock().dirty).count(),
+        }
+    }
+
+    /// 从`lba`开始读取`count`个扇区, `buf`的长度必须为`count * 512`
+    pub async fn read_sectors(
+        &self,
+        lba: u64,
+        count: usize,
+        buf: &mut [u8],
+    ) -> Result<(), KernelError> {
+        check_request(self.sector_count(), lba, count, buf.len())?;
+        for (block, in_block, in_buf) in Self::blocks(lba, count) {
+            self.with_block(block, false, |slot| {
+                buf[in_buf].copy_from_slice(&slot.data[in_block]);
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// 从`lba`开始写入`count`个扇区, 只修改缓存, `buf`的长度必须为`count * 512`
+    pub async fn write_sectors(
+        &self,
+        lba: u64,
+        count: usize,
+        buf: &[u8],
+    ) -> Result<(), KernelError> {
+        check_request(self.sector_count(), lba, count, buf.len())?;
+        for (block, in_block, in_buf) in Self::blocks(lba, count) {
+            let whole = in_block.len() == self.block_sectors(block) * SECTOR_SIZE;
+            self.with_block(block, whole, |slot| {
+                slot.data[in_block].copy_from_slice(&buf[in_buf]);
+                slot.dirty = true;
+                slot.version += 1;
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// 写回所有脏块
+    pub async fn flush(&self) -> Result<(), KernelError> {
+        self.flush_blocks(|_| true).await
+    }
+
+    /// 写回与`[lba, lba + count)`相交的脏块
+    pub async fn flush_range(&self, lba: u64, count: usize) -> Result<(), KernelError> {
+        if count == 0 {
+            return Err(BlockError::InvalidCount.into());
+        }
+        match lba.checked_add(count as u64) {
+            Some(end) if end <= self.sector_count() => {}
+            _ => return Err(BlockError::OutOfRange.into()),
+        }
+        let first = lba / BLOCK_SECTORS as u64;
+        let end = (lba + count as u64).div_ceil(BLOCK_SECTORS as u64);
+        self.flush_blocks(|block| (first..end).contains(&block))
+            .await
+    }
+
+    // 逐个钉住选中的槽位后写回, 写回过程中槽位不会被替换
+    async fn flush_blocks(&self, mut selected: impl FnMut(u64) -> bool) -> Result<(), KernelError> {
+        for i in 0..self.slots.len() {
+            let pinned = {
+                let mut index = self.index.lock();
+                let entry = &mut index.entries[i];
+                if !entry.block.is_some_and(&mut selected) {
+                    continue;
+                }
+                entry.pins += 1;
+                Pinned {
+                    index: &self.index,
+                    slot: i,
+                }
+            };
+            self.write_back(pinned.slot).await?;
+        }
+        Ok(())
+    }
+
+    // 块中的扇区数, 设备末尾的块可能不满
+    fn block_sectors(&self, block: u64) -> usize {
+        let start = block * BLOCK_SECTORS as u64;
+        (self.sector_count() - start).min(BLOCK_SECTORS as u64) as usize
+    }
+
+    // 写回钉住的槽位`i`. 写回期间又被修改过的块仍然是脏的
+    async fn write_back(&self, i: usize) -> Result<(), KernelError> {
+        let (lba, version, data) = {
+            let slot = self.slots[i].lock();
+            let Some(block) = slot.block.filter(|_| slot.dirty) else {
+                return Ok(());
+            };
+            let sectors = self.block_sectors(block);
+            let data = slot.data[..sectors * SECTOR_SIZE].to_vec();
+            (block * BLOCK_SECTORS as u64, slot.version, data)
+        };
+        self.device.write(lba, data).await?;
+        let mut slot = self.slots[i].lock();
+        if slot.version == version {
+            slot.dirty = false;
+        }
+        Ok(())
+    }
+
+    // 找到或分配`block`的槽位并钉住. 所有槽位都在使用时让出执行器等待
+    async fn pin(&self, block: u64) -> Result<Pinned<'_>, KernelError> {
+        loop {
+            let dirty = {
+                let mut index = self.index.lock();
+                index.clock += 1;
+                let now = index.clock;
+                if let Some(i) = index
+                    .entries
+                    .iter()
+                    .position(|entry| entry.block == Some(block))
+                {
+                    index.hits += 1;
+                    let entry = &mut index.entries[i];
+                    entry.pins += 1;
+                    entry.last_used = now;
+                    return Ok(Pinned {
+                        index: &self.index,
+                        slot: i,
+                    });
+                }
+                // 优先使用空槽位, 否则替换最久未使用的块
+                let victim = index
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, entry)| entry.pins == 0)
+                    .min_by_key(|(_, entry)| (entry.block.is_some(), entry.last_used))
+                    .map(|(i, _)| i);
+                match victim {
+                    // 脏块钉住后在锁外写回, 写回后重新选择
+                    Some(i) if self.slots[i].lock().dirty => {
+                        index.entries[i].pins += 1;
+                        Some(Pinned {
+                            index: &self.index,
+                            slot: i,
+                        })
+                    }
+                    Some(i) => {
+                        self.slots[i].lock().block = None;
+                        if index.entries[i].block.is_some() {
+                            index.evictions += 1;
+                        }
+                        index.misses += 1;
+                        index.entries[i] = Entry {
+                            block: Some(block),
+                            pins: 1,
+                            last_used: now,
+                        };
+                        return Ok(Pinned {
+                            index: &self.index,
+                            slot: i,
+                        });
+                    }
+                    None => None,
+                }
+            };
+            match dirty {
+                Some(pinned) => self.write_back(pinned.slot).await?,
+                None => task::yield_now().await,
+            }
+        }
+    }
+
+    // 在`block`的槽位上调用`f`. `whole`为true时`f`覆盖整块, 不需要先从设备读入
+    async fn with_block<R>(
+        &self,
+        block: u64,
+        whole: bool,
+        f: impl FnOnce(&mut Slot) -> R,
+    ) -> Result<R, KernelError> {
+        let pinned = self.pin(block).await?;
+        {
+            let mut slot = self.slots[pinned.slot].lock();
+            if slot.block == Some(block) || whole {
+                slot.block = Some(block);
+                return Ok(f(&mut slot));
+            }
+        }
+        // 读入时不持有槽位锁. 同时读入同一块的任务只有先完成的填入槽位, 失败时槽位保持无效
+        let sectors = self.block_sectors(block);
+        let data = self
+            .device
+            .read(block * BLOCK_SECTORS as u64, sectors)
+            .await?;
+        let mut slot = self.slots[pinned.slot].lock();
+        if slot.block != Some(block) {
+            slot.data[..data.len()].copy_from_slice(&data);
+            slot.block = Some(block);
+        }
+        Ok(f(&mut slot))
+    }
+
+    // 把`[lba, lba + count)`按块拆开, 每项是(块号, 块内的字节范围, 缓冲区中的字节范围)
+    fn blocks(lba: u64, count: usize) -> impl Iterator<Item = (u64, Range<usize>, Range<usize>)> {
+        let mut done = 0;
+        core::iter::from_fn(move || {
+            if done >= count {
+                return None;
+            }
+            let sector = lba + done as u64;
+            let block = sector / BLOCK_SECTORS as u64;
+            let offset = (sector % BLOCK_SECTORS as u64) as usize;
+            let sectors = (BLOCK_SECTORS - offset).min(count - done);
+            let in_block = offset * SECTOR_SIZE..(offset + sectors) * SECTOR_SIZE;
+            let in_buf = done * SECTOR_SIZE..(done + sectors) * SECTOR_SIZE;
+            done += sectors;
+            Some((block, in_block, in_buf))
+        })
+    }
+}
+
+// drop时不能等待设备: 只提交脏块的写回, 不等待完成
+impl Drop for Cache<'_> {
+    fn drop(&mut self) {
+        for slot in &self.slots {
+            let slot = slot.lock();
+            if let Some(block) = slot.block.filter(|_| slot.dirty) {
+                let sectors = self.block_sectors(block);
+                let data = slot.data[..sectors * SECTOR_SIZE].to_vec();
+                self.device
+                    .write(block * BLOCK_SECTORS as u64, data)
+                    .detach();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{BlockDevice, BlockRequest, Inline, RequestHandle, RequestQueue};
+
+// 测试用的内存块设备, 记录每次读写
+#[cfg(test)]
+struct MockDisk {
+    data: Mutex<Vec<u8>>,
+    log: Mutex<Vec<MockOp>>,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MockOp {
+    Read(u64, usize),
+    Write(u64, usize),
+}
+
+#[cfg(test)]
+impl MockDisk {
+    fn new(sectors: usize) -> MockDisk {
+        let data = (0..sectors * SECTOR_SIZE)
+            .map(|i| (i / SECTOR_SIZE) as u8)
+            .collect();
+        MockDisk {
+            data: Mutex::new(data),
+            log: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn take_log(&self) -> Vec<MockOp> {
+        core::mem::take(&mut *self.log.lock())
+    }
+
+    fn sector(&self, lba: u64) -> Vec<u8> {
+        let start = lba as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + SECTOR_SIZE].to_vec()
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for MockDisk {
+    fn sector_count(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, lba: u64, count: usize, buf: &mut [u8]) -> Result<(), KernelError> {
+        check_request(self.sector_count(), lba, count, buf.len())?;
+        self.log.lock().push(MockOp::Read(lba, count));
+        let start = lba as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, count: usize, buf: &[u8]) -> Result<(), KernelError> {
+        check_request(self.sector_count(), lba, count, buf.len())?;
+        self.log.lock().push(MockOp::Write(lba, count));
+        let start = lba as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_repeated_reads_hit() {
+    task::complete_now(async {
+        let disk = MockDisk::new(64);
+        let device = Inline(&disk);
+        let cache = Cache::new(&device, 2);
+        let mut buf = [0u8; 3 * SECTOR_SIZE];
+        cache.read_sectors(9, 3, &mut buf).await.unwrap();
+        assert_eq!(buf[0], 9);
+        assert_eq!(buf[2 * SECTOR_SIZE], 11);
+        cache
+            .read_sectors(10, 1, &mut buf[..SECTOR_SIZE])
+            .await
+            .unwrap();
+        cache.read_sectors(8, 3, &mut buf).await.unwrap();
+        assert_eq!(buf[SECTOR_SIZE], 9);
+        // 只读入一次整块
+        assert_eq!(disk.take_log(), [MockOp::Read(8, 8)]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 1, 0));
+        // 跨两块的请求读入两块, 第二块替换最久未使用的块
+        let mut buf = [0u8; 2 * SECTOR_SIZE];
+        cache.read_sectors(23, 2, &mut buf).await.unwrap();
+        assert_eq!((buf[0], buf[SECTOR_SIZE]), (23, 24));
+        assert_eq!(disk.take_log(), [MockOp::Read(16, 8), MockOp::Read(24, 8)]);
+        assert_eq!(cache.stats().evictions, 1);
+    });
+}
+
+#[test_case]
+fn test_writes_coalesce_until_flush() {
+    task::complete_now(async {
+        let disk = MockDisk::new(36);
+        let device = Inline(&disk);
+        let cache = Cache::new(&device, 4);
+        // 不满一块的写入先读入整块, 整块写入不需要读
+        cache
+            .write_sectors(1, 1, &[0xaa; SECTOR_SIZE])
+            .await
+            .unwrap();
+        cache
+            .write_sectors(2, 1, &[0xbb; SECTOR_SIZE])
+            .await
+            .unwrap();
+        cache
+            .write_sectors(8, 8, &[0xcc; BLOCK_SIZE])
+            .await
+            .unwrap();
+        assert_eq!(disk.take_log(), [MockOp::Read(0, 8)]);
+        assert_eq!(disk.sector(1)[0], 1);
+        let mut buf = [0u8; SECTOR_SIZE];
+        cache.read_sectors(2, 1, &mut buf).await.unwrap();
+        assert_eq!(buf[0], 0xbb);
+        assert_eq!(cache.stats().dirty, 2);
+
+        cache.flush_range(9, 1).await.unwrap();
+        assert_eq!(disk.take_log(), [MockOp::Write(8, 8)]);
+        cache.flush().await.unwrap();
+        assert_eq!(disk.take_log(), [MockOp::Write(0, 8)]);
+        assert_eq!(cache.stats().dirty, 0);
+        assert_eq!(disk.sector(1)[0], 0xaa);
+        assert_eq!(disk.sector(3)[0], 3);
+        // 已写回的块不再写
+        cache.flush().await.unwrap();
+        assert!(disk.take_log().is_empty());
+        // 设备末尾不满的块只读写存在的扇区
+        cache
+            .write_sectors(35, 1, &[0xdd; SECTOR_SIZE])
+            .await
+            .unwrap();
+        cache.flush().await.unwrap();
+        assert_eq!(disk.take_log(), [MockOp::Read(32, 4), MockOp::Write(32, 4)]);
+        assert_eq!(
+            cache.flush_range(36, 1).await,
+            Err(BlockError::OutOfRange.into())
+        );
+    });
+}
+
+#[test_case]
+fn test_eviction_writes_back() {
+    task::complete_now(async {
+        let disk = MockDisk::new(36);
+        let device = Inline(&disk);
+        let cache = Cache::new(&device, 2);
+        cache
+            .write_sectors(0, 1, &[0xaa; SECTOR_SIZE])
+            .await
+            .unwrap();
+        let mut buf = [0u8; SECTOR_SIZE];
+        cache.read_sectors(8, 1, &mut buf).await.unwrap();
+        disk.take_log();
+        // 块0最久未使用, 替换前写回
+        cache.read_sectors(16, 1, &mut buf).await.unwrap();
+        assert_eq!(disk.take_log(), [MockOp::Write(0, 8), MockOp::Read(16, 8)]);
+        assert_eq!(disk.sector(0)[0], 0xaa);
+        // 干净的块直接替换
+        cache.read_sectors(24, 1, &mut buf).await.unwrap();
+        assert_eq!(disk.take_log(), [MockOp::Read(24, 8)]);
+        // 不满的最后一块
+        cache.read_sectors(35, 1, &mut buf).await.unwrap();
+        assert_eq!(buf[0], 35);
+        assert_eq!(disk.take_log(), [MockOp::Read(32, 4)]);
+        let stats = cache.stats();
+        assert_eq!((stats.misses, stats.evictions, stats.dirty), (5, 3, 0));
+        // drop时提交写回, 同步设备上立即完成
+        cache
+            .write_sectors(32, 4, &[0xee; 4 * SECTOR_SIZE])
+            .await
+            .unwrap();
+        drop(cache);
+        assert_eq!(disk.take_log(), [MockOp::Write(32, 4)]);
+    });
+}
+
+// 请求留在队列中, 直到测试调用`finish`
+#[cfg(test)]
+struct QueuedDisk {
+    disk: MockDisk,
+    queue: RequestQueue,
+}
+
+#[cfg(test)]
+impl QueuedDisk {
+    fn finish(&self) {
+        while let Some((tag, request)) = self.queue.start() {
+            self.queue
+                .complete(tag, super::execute(&self.disk, request));
+        }
+    }
+}
+
+#[cfg(test)]
+impl AsyncBlockDevice for QueuedDisk {
+    fn sector_count(&self) -> u64 {
+        self.disk.sector_count()
+    }
+
+    fn submit(&self, request: BlockRequest) -> RequestHandle {
+        self.queue.submit(request)
+    }
+}
+
+#[test_case]
+fn test_miss_does_not_block_hits() {
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    let disk = QueuedDisk {
+        disk: MockDisk::new(16),
+        queue: RequestQueue::new(4),
+    };
+    let cache = Cache::new(&disk, 2);
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut buf = [0u8; SECTOR_SIZE];
+    {
+        let mut read = pin!(cache.read_sectors(0, 1, &mut buf));
+        assert!(read.as_mut().poll(&mut cx).is_pending());
+        disk.finish();
+        assert_eq!(read.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+    }
+    // 等待块1读入期间, 已缓存的块0仍然可以读写
+    let mut missed = [0u8; SECTOR_SIZE];
+    {
+        let mut miss = pin!(cache.read_sectors(8, 1, &mut missed));
+        assert!(miss.as_mut().poll(&mut cx).is_pending());
+        let mut hit = pin!(cache.write_sectors(0, 1, &[0xaa; SECTOR_SIZE]));
+        assert_eq!(hit.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        disk.finish();
+        assert_eq!(miss.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+    }
+    assert_eq!(missed[0], 8);
+    // 写回期间再次修改的块仍是脏的, 写回的是修改前的内容
+    {
+        let mut flush = pin!(cache.flush());
+        assert!(flush.as_mut().poll(&mut cx).is_pending());
+        let mut hit = pin!(cache.write_sectors(0, 1, &[0xbb; SECTOR_SIZE]));
+        assert_eq!(hit.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        disk.finish();
+        assert_eq!(flush.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+    }
+    assert_eq!(disk.disk.sector(0)[0], 0xaa);
+    assert_eq!(cache.stats().dirty, 1);
+    let mut flush = pin!(cache.flush());
+    assert!(flush.as_mut().poll(&mut cx).is_pending());
+    disk.finish();
+    assert_eq!(flush.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+    assert_eq!(disk.disk.sector(0)[0], 0xbb);
+}
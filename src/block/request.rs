@@ -0,0 +1,384 @@
+//! 块设备的异步请求
+//!
+//! 提交的请求先在设备的请求队列中等待, 驱动取出时为它分配一个空闲的标签, 完成时按标签找到请求,
+//! 所以同时可以有多个请求在设备中, 完成的顺序不必与提交的顺序相同. 提交者通过`RequestHandle`等待结果,
+//! 它是一个Future, 由驱动的中断处理函数或I/O线程经过waker唤醒. 句柄被drop时请求被取消:
+//! 还在等待的请求不再交给驱动, 已经交给驱动的请求完成后丢弃结果. `detach`的句柄不取消请求
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use super::SECTOR_SIZE;
+use crate::error::KernelError;
+use crate::sync::IrqMutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    Read,
+    Write,
+}
+
+/// 一次读写, 缓冲区随请求交给驱动, 完成后还给提交者
+#[derive(Debug)]
+pub struct BlockRequest {
+    pub op: BlockOp,
+    pub lba: u64,
+    /// 长度是扇区数乘以512. 读请求的内容由设备填入
+    pub buf: Vec<u8>,
+}
+
+impl BlockRequest {
+    /// 从`lba`开始读取`count`个扇区
+    pub fn read(lba: u64, count: usize) -> BlockRequest {
+        BlockRequest {
+            op: BlockOp::Read,
+            lba,
+            buf: vec![0; count * SECTOR_SIZE],
+        }
+    }
+
+    /// 从`lba`开始写入`data`, 长度必须是512的整数倍
+    pub fn write(lba: u64, data: Vec<u8>) -> BlockRequest {
+        BlockRequest {
+            op: BlockOp::Write,
+            lba,
+            buf: data,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.buf.len() / SECTOR_SIZE
+    }
+}
+
+/// 设备中的请求的编号, 从0到队列深度减1, 请求完成后给下一个请求使用
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tag(u16);
+
+impl Tag {
+    pub fn index(self) -> usize {
+        usize::from(self.0)
+    }
+}
+
+// 提交者和队列共享的完成状态
+struct Completion {
+    result: Option<Result<Vec<u8>, KernelError>>,
+    waker: Option<Waker>,
+    // 句柄已被drop
+    cancelled: bool,
+}
+
+type Shared = Arc<IrqMutex<Completion>>;
+
+fn shared(result: Option<Result<Vec<u8>, KernelError>>) -> Shared {
+    Arc::new(IrqMutex::new(
+        "block::Completion",
+        Completion {
+            result,
+            waker: None,
+            cancelled: false,
+        },
+    ))
+}
+
+/// 等待一个请求完成, 结果是请求的缓冲区: 读请求中是读到的内容
+pub struct RequestHandle {
+    shared: Shared,
+    // 调用了`detach`, drop时不取消请求
+    detached: bool,
+}
+
+impl RequestHandle {
+    /// 已经有结果的句柄, 用于提交时就能发现的错误
+    pub fn ready(result: Result<Vec<u8>, KernelError>) -> RequestHandle {
+        RequestHandle {
+            shared: shared(Some(result)),
+            detached: false,
+        }
+    }
+
+    /// 请求已完成而结果还没有取走
+    pub fn is_finished(&self) -> bool {
+        self.shared.lock().result.is_some()
+    }
+
+    /// 取走结果, 还没有完成时返回None. 供不在任务中的同步调用者使用
+    pub fn take(&mut self) -> Option<Result<Vec<u8>, KernelError>> {
+        self.shared.lock().result.take()
+    }
+
+    /// 不再等待结果, 请求照常执行, 完成后丢弃结果. 用于不能等待的写回
+    pub fn detach(mut self) {
+        self.detached = true;
+    }
+}
+
+impl Future for RequestHandle {
+    type Output = Result<Vec<u8>, KernelError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut completion = self.shared.lock();
+        match completion.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                // 先登记waker再释放锁, 完成时一定能看到它
+                completion.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for RequestHandle {
+    fn drop(&mut self) {
+        if self.detached {
+            return;
+        }
+        let mut completion = self.shared.lock();
+        completion.cancelled = true;
+        completion.waker = None;
+    }
+}
+
+/// 请求队列的统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueStats {
+    /// 等待驱动取出的请求
+    pub waiting: usize,
+    /// 已交给驱动而没有完成的请求
+    pub in_flight: usize,
+    pub completed: u64,
+    /// 句柄在完成之前被drop的请求
+    pub cancelled: u64,
+}
+
+struct Inner {
+    waiting: VecDeque<(BlockRequest, Shared)>,
+    // 按标签排列, 已交给驱动的请求
+    tags: Vec<Option<Shared>>,
+    completed: u64,
+    cancelled: u64,
+}
+
+/// 一个设备的请求队列, 最多`depth`个请求同时在设备中
+///
+/// 中断处理函数会调用`complete`, 所以内部的锁是`IrqMutex`. 加锁顺序总是先队列后请求的完成状态
+pub struct RequestQueue {
+    inner: IrqMutex<Inner>,
+}
+
+impl RequestQueue {
+    /// `depth`在1到65536之间
+    pub fn new(depth: usize) -> RequestQueue {
+        assert!(
+            (1..=1 << 16).contains(&depth),
+            "bad block request queue depth"
+        );
+        RequestQueue {
+            inner: IrqMutex::new(
+                "block::RequestQueue",
+                Inner {
+                    waiting: VecDeque::new(),
+                    tags: (0..depth).map(|_| None).collect(),
+                    completed: 0,
+                    cancelled: 0,
+                },
+            ),
+        }
+    }
+
+    /// 把请求排到队列末尾, 驱动之后用`start`取出
+    pub fn submit(&self, request: BlockRequest) -> RequestHandle {
+        let shared = shared(None);
+        self.inner
+            .lock()
+            .waiting
+            .push_back((request, shared.clone()));
+        RequestHandle {
+            shared,
+            detached: false,
+        }
+    }
+
+    /// 取出最早提交的、没有被取消的请求并分配最小的空闲标签. 没有空闲标签或没有请求时返回None
+    pub fn start(&self) -> Option<(Tag, BlockRequest)> {
+        let mut inner = self.inner.lock();
+        let free = inner.tags.iter().position(Option::is_none)?;
+        while let Some((request, shared)) = inner.waiting.pop_front() {
+            if shared.lock().cancelled {
+                inner.cancelled += 1;
+                continue;
+            }
+            inner.tags[free] = Some(shared);
+            return Some((Tag(free as u16), request));
+        }
+        None
+    }
+
+    /// 驱动完成了`tag`的请求, 唤醒等待的任务, 之后标签可以分配给其他请求. 请求已被取消时丢弃结果
+    ///
+    /// 可以在中断中调用. `tag`没有在使用时panic
+    pub fn complete(&self, tag: Tag, result: Result<Vec<u8>, KernelError>) {
+        let shared = {
+            let mut inner = self.inner.lock();
+            let shared = inner.tags[tag.index()]
+                .take()
+                .expect("completing a free block request tag");
+            inner.completed += 1;
+            shared
+        };
+        let waker = {
+            let mut completion = shared.lock();
+            if completion.cancelled {
+                None
+            } else {
+                completion.result = Some(result);
+                Some(completion.waker.take())
+            }
+        };
+        match waker {
+            // 在锁外唤醒, waker可能直接轮询任务
+            Some(Some(waker)) => waker.wake(),
+            Some(None) => {}
+            None => self.inner.lock().cancelled += 1,
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let inner = self.inner.lock();
+        QueueStats {
+            waiting: inner.waiting.len(),
+            in_flight: inner.tags.iter().filter(|tag| tag.is_some()).count(),
+            completed: inner.completed,
+            cancelled: inner.cancelled,
+        }
+    }
+}
+
+#[cfg(test)]
+use super::BlockError;
+
+// 测试用的waker, 记录被唤醒的次数
+#[cfg(test)]
+struct CountingWaker(core::sync::atomic::AtomicUsize);
+
+#[cfg(test)]
+impl alloc::task::Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl CountingWaker {
+    fn new() -> Arc<CountingWaker> {
+        Arc::new(CountingWaker(core::sync::atomic::AtomicUsize::new(0)))
+    }
+
+    fn count(&self) -> usize {
+        self.0.load(core::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+fn poll_handle(
+    handle: &mut RequestHandle,
+    waker: &Arc<CountingWaker>,
+) -> Poll<Result<Vec<u8>, KernelError>> {
+    let waker = Waker::from(waker.clone());
+    Pin::new(handle).poll(&mut Context::from_waker(&waker))
+}
+
+#[test_case]
+fn test_tag_allocation() {
+    let queue = RequestQueue::new(2);
+    let _handles: Vec<_> = (0..3)
+        .map(|lba| queue.submit(BlockRequest::read(lba, 1)))
+        .collect();
+    let (first, request) = queue.start().unwrap();
+    assert_eq!((first, request.lba, request.count()), (Tag(0), 0, 1));
+    let (second, request) = queue.start().unwrap();
+    assert_eq!((second, request.lba), (Tag(1), 1));
+    // 标签用完时第三个请求继续等待
+    assert!(queue.start().is_none());
+    assert_eq!(queue.stats().waiting, 1);
+    assert_eq!(queue.stats().in_flight, 2);
+    // 完成的请求的标签分配给下一个请求
+    queue.complete(second, Ok(vec![0; SECTOR_SIZE]));
+    let (third, request) = queue.start().unwrap();
+    assert_eq!((third, request.lba), (Tag(1), 2));
+    assert!(queue.start().is_none());
+    queue.complete(first, Ok(Vec::new()));
+    queue.complete(third, Ok(Vec::new()));
+    let stats = queue.stats();
+    assert_eq!((stats.waiting, stats.in_flight, stats.completed), (0, 0, 3));
+}
+
+#[test_case]
+fn test_out_of_order_completion() {
+    let queue = RequestQueue::new(4);
+    let waker = CountingWaker::new();
+    let mut first = queue.submit(BlockRequest::read(10, 1));
+    let mut second = queue.submit(BlockRequest::write(20, vec![0xaa; SECTOR_SIZE]));
+    let (first_tag, _) = queue.start().unwrap();
+    let (second_tag, request) = queue.start().unwrap();
+    assert_eq!(request.op, BlockOp::Write);
+    assert!(poll_handle(&mut first, &waker).is_pending());
+    assert!(poll_handle(&mut second, &waker).is_pending());
+
+    // 后提交的请求先完成, 只唤醒它的等待者
+    queue.complete(second_tag, Ok(request.buf));
+    assert_eq!(waker.count(), 1);
+    assert!(second.is_finished() && !first.is_finished());
+    match poll_handle(&mut second, &waker) {
+        Poll::Ready(Ok(buf)) => assert_eq!(buf, [0xaa; SECTOR_SIZE]),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(poll_handle(&mut first, &waker).is_pending());
+    queue.complete(first_tag, Err(BlockError::Io.into()));
+    assert_eq!(waker.count(), 2);
+    assert_eq!(first.take(), Some(Err(BlockError::Io.into())));
+    // 提交时就失败的请求不经过队列
+    let mut failed = RequestHandle::ready(Err(BlockError::OutOfRange.into()));
+    assert_eq!(
+        poll_handle(&mut failed, &waker),
+        Poll::Ready(Err(BlockError::OutOfRange.into()))
+    );
+}
+
+#[test_case]
+fn test_dropped_request_is_cancelled() {
+    let queue = RequestQueue::new(1);
+    let waker = CountingWaker::new();
+    // 等待中的请求被取消后不再交给驱动
+    drop(queue.submit(BlockRequest::read(1, 1)));
+    let mut kept = queue.submit(BlockRequest::read(2, 1));
+    let (tag, request) = queue.start().unwrap();
+    assert_eq!(request.lba, 2);
+    assert_eq!(queue.stats().cancelled, 1);
+    assert!(poll_handle(&mut kept, &waker).is_pending());
+
+    // 已在设备中的请求被取消时, 完成只释放标签, 不唤醒任何人
+    drop(kept);
+    queue.complete(tag, Ok(request.buf));
+    assert_eq!(waker.count(), 0);
+    let stats = queue.stats();
+    assert_eq!((stats.in_flight, stats.cancelled), (0, 2));
+    let _next = queue.submit(BlockRequest::read(3, 1));
+    let (next_tag, next) = queue.start().unwrap();
+    assert_eq!(next_tag, tag);
+
+    // detach的请求照常交给驱动
+    queue
+        .submit(BlockRequest::write(4, vec![0; SECTOR_SIZE]))
+        .detach();
+    queue.complete(next_tag, Ok(next.buf));
+    assert_eq!(queue.start().map(|(_, request)| request.lba), Some(4));
+    assert_eq!(queue.stats().cancelled, 2);
+}
@@ -0,0 +1,130 @@
+//! 在内核线程中执行同步驱动的请求
+//!
+//! ATA这样只能轮询的设备没有完成中断, 直接在任务中读写会让执行器在整个传输期间停下来.
+//! `IoThread`把请求交给一个普通优先级的线程, 执行器空闲时才轮到它; 中断唤醒了任务时,
+//! 高优先级的启动线程立即抢回处理器, 传输在之后继续
+
+use alloc::boxed::Box;
+
+use spin::Once;
+
+use super::{check_request, execute, AsyncBlockDevice, BlockDevice, BlockRequest};
+use super::{RequestHandle, RequestQueue};
+use crate::memory::MemError;
+use crate::thread::{self, Priority, ThreadId};
+
+pub struct IoThread {
+    device: &'static dyn BlockDevice,
+    // 同步驱动一次只执行一个请求
+    queue: RequestQueue,
+    thread: Once<ThreadId>,
+}
+
+impl IoThread {
+    /// 创建名为`name`的线程执行`device`的请求. 线程和返回的对象一直存在
+    pub fn spawn(
+        name: &str,
+        device: &'static dyn BlockDevice,
+    ) -> Result<&'static IoThread, MemError> {
+        let io: &'static IoThread = Box::leak(Box::new(IoThread {
+            device,
+            queue: RequestQueue::new(1),
+            thread: Once::new(),
+        }));
+        let id = thread::spawn(name, Priority::Normal, move || io.run())?;
+        io.thread.call_once(|| id);
+        Ok(io)
+    }
+
+    pub fn queue(&self) -> &RequestQueue {
+        &self.queue
+    }
+
+    fn run(&self) -> ! {
+        loop {
+            while let Some((tag, request)) = self.queue.start() {
+                self.queue.complete(tag, execute(self.device, request));
+            }
+            // `start`之后提交的请求的unpark让这里立即返回
+            thread::park();
+        }
+    }
+}
+
+impl AsyncBlockDevice for IoThread {
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn submit(&self, request: BlockRequest) -> RequestHandle {
+        let sectors = self.device.sector_count();
+        if let Err(err) = check_request(sectors, request.lba, request.count(), request.buf.len()) {
+            return RequestHandle::ready(Err(err.into()));
+        }
+        let handle = self.queue.submit(request);
+        thread::unpark(*self.thread.get().expect("I/O thread not started"));
+        handle
+    }
+}
+
+#[cfg(test)]
+use super::{BlockError, SECTOR_SIZE};
+#[cfg(test)]
+use crate::error::KernelError;
+#[cfg(test)]
+use alloc::vec::Vec;
+
+// 测试用的内存盘
+#[cfg(test)]
+struct RamDisk(spin::Mutex<Vec<u8>>);
+
+#[cfg(test)]
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        (self.0.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, lba: u64, count: usize, buf: &mut [u8]) -> Result<(), KernelError> {
+        check_request(self.sector_count(), lba, count, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.0.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, count: usize, buf: &[u8]) -> Result<(), KernelError> {
+        check_request(self.sector_count(), lba, count, buf.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        self.0.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+// 让出处理器直到请求完成
+#[cfg(test)]
+fn wait(mut handle: RequestHandle) -> Result<Vec<u8>, KernelError> {
+    loop {
+        if let Some(result) = handle.take() {
+            return result;
+        }
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn test_io_thread() {
+    use alloc::vec;
+
+    let sectors = vec![0; 16 * SECTOR_SIZE];
+    let disk = Box::leak(Box::new(RamDisk(spin::Mutex::new(sectors))));
+    let io = IoThread::spawn("blk-test", disk).unwrap();
+    assert_eq!(AsyncBlockDevice::sector_count(io), 16);
+
+    // 同时提交的请求按顺序执行
+    let write = io.write(3, vec![0x5a; 2 * SECTOR_SIZE]);
+    let read = io.read(4, 1);
+    assert!(wait(write).is_ok());
+    assert_eq!(wait(read).unwrap(), [0x5a; SECTOR_SIZE]);
+    // 越界的请求不进入队列
+    assert_eq!(wait(io.read(15, 2)), Err(BlockError::OutOfRange.into()));
+    assert_eq!(io.queue().stats().completed, 2);
+}
@@ -0,0 +1,271 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::println;
+
+// 图形控制器的杂项寄存器, bit0为1表示图形模式
+const VGA_GC_INDEX: u16 = 0x3CE;
+const VGA_GC_DATA: u16 = 0x3CF;
+const VGA_GC_MISC: u8 = 0x06;
+const VGA_GC_GRAPHICS_MODE: u8 = 1 << 0;
+
+const MODE_13H_ADDRESS: u64 = 0xA0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 调色板索引, VGA默认调色板的前16项与文本模式的颜色相同
+    Indexed8,
+    Rgb,
+    Bgr,
+}
+
+/// bootloader提供的线性帧缓冲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub address: PhysAddr,
+    pub width: usize,
+    pub height: usize,
+    /// 每行的字节数
+    pub pitch: usize,
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+impl FramebufferInfo {
+    pub fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+}
+
+/// 内存映射按类型汇总的字节数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryTotals {
+    pub regions: usize,
+    pub total: u64,
+    pub usable: u64,
+    /// 内核、页表、bootloader等已经占用的内存
+    pub in_use: u64,
+    pub reserved: u64,
+}
+
+/// 内核映像在物理内存中的范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelImage {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+}
+
+impl KernelImage {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+struct Info {
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+    framebuffer: Option<FramebufferInfo>,
+    rsdp_address: Option<PhysAddr>,
+}
+
+static INFO: Once<Info> = Once::new();
+
+/// 保存bootloader传来的信息, 之后通过本模块的函数读取. 只有第一次调用有效
+pub fn init(boot_info: &'static BootInfo) {
+    assert_eq!(
+        boot_info.physical_memory_offset,
+        crate::layout::PHYSICAL_MEMORY.start,
+        "physical memory offset does not match package.metadata.bootloader"
+    );
+    INFO.call_once(|| Info {
+        memory_map: &boot_info.memory_map,
+        physical_memory_offset: VirtAddr::new(boot_info.physical_memory_offset),
+        framebuffer: detect_framebuffer(),
+        // bootloader 0.9不提供RSDP的地址
+        rsdp_address: None,
+    });
+}
+
+fn info() -> &'static Info {
+    INFO.get().expect("boot information not initialized")
+}
+
+// 只知道feature对应的模式, 再读VGA寄存器确认bootloader确实切换到了图形模式
+fn detect_framebuffer() -> Option<FramebufferInfo> {
+    if !cfg!(feature = "vga_320x200") {
+        return None;
+    }
+    let misc = unsafe {
+        Port::<u8>::new(VGA_GC_INDEX).write(VGA_GC_MISC);
+        Port::<u8>::new(VGA_GC_DATA).read()
+    };
+    (misc & VGA_GC_GRAPHICS_MODE != 0).then(mode_13h)
+}
+
+/// 启用`vga_320x200` feature时bootloader设置的13h模式
+fn mode_13h() -> FramebufferInfo {
+    FramebufferInfo {
+        address: PhysAddr::new(MODE_13H_ADDRESS),
+        width: 320,
+        height: 200,
+        pitch: 320,
+        bytes_per_pixel: 1,
+        format: PixelFormat::Indexed8,
+    }
+}
+
+pub fn framebuffer() -> Option<FramebufferInfo> {
+    info().framebuffer
+}
+
+pub fn physical_memory_offset() -> VirtAddr {
+    info().physical_memory_offset
+}
+
+pub fn memory_map() -> &'static MemoryMap {
+    info().memory_map
+}
+
+pub fn rsdp_address() -> Option<PhysAddr> {
+    info().rsdp_address
+}
+
+pub fn memory_totals() -> MemoryTotals {
+    sum_regions(
+        memory_map()
+            .iter()
+            .map(|r| (r.region_type, r.range.end_addr() - r.range.start_addr())),
+    )
+}
+
+fn sum_regions(regions: impl Iterator<Item = (MemoryRegionType, u64)>) -> MemoryTotals {
+    let mut totals = MemoryTotals::default();
+    for (kind, size) in regions {
+        totals.regions += 1;
+        totals.total += size;
+        match kind {
+            MemoryRegionType::Usable => totals.usable += size,
+            MemoryRegionType::InUse
+            | MemoryRegionType::Kernel
+            | MemoryRegionType::KernelStack
+            | MemoryRegionType::PageTable
+            | MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::Package => totals.in_use += size,
+            _ => totals.reserved += size,
+        }
+    }
+    totals
+}
+
+/// 内核映像可能被分成几段, 返回覆盖所有段的范围
+pub fn kernel_image() -> Option<KernelImage> {
+    memory_map()
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Kernel)
+        .map(|r| (r.range.start_addr(), r.range.end_addr()))
+        .reduce(|(start, end), (s, e)| (start.min(s), end.max(e)))
+        .map(|(start, end)| KernelImage {
+            start: PhysAddr::new(start),
+            end: PhysAddr::new(end),
+        })
+}
+
+/// 打印启动信息摘要
+pub fn print_summary() {
+    println!(
+        "boot: physical memory offset {:?}",
+        physical_memory_offset()
+    );
+
+    let totals = memory_totals();
+    println!(
+        "boot: {} memory regions, {} KiB total, {} KiB usable, {} KiB in use, {} KiB reserved",
+        totals.regions,
+        totals.total / 1024,
+        totals.usable / 1024,
+        totals.in_use / 1024,
+        totals.reserved / 1024
+    );
+
+    match kernel_image() {
+        Some(kernel) => println!(
+            "boot: kernel image {:#x}..{:#x} ({} KiB)",
+            kernel.start.as_u64(),
+            kernel.end.as_u64(),
+            kernel.size() / 1024
+        ),
+        None => println!("boot: kernel image not in memory map"),
+    }
+
+    match framebuffer() {
+        Some(fb) => println!(
+            "boot: framebuffer {}x{} pitch {} {:?} at {:#x}",
+            fb.width,
+            fb.height,
+            fb.pitch,
+            fb.format,
+            fb.address.as_u64()
+        ),
+        None => println!("boot: no framebuffer, using VGA text mode"),
+    }
+
+    match rsdp_address() {
+        Some(rsdp) => println!("boot: RSDP at {:#x}", rsdp.as_u64()),
+        None => println!("boot: RSDP not provided by the bootloader"),
+    }
+}
+
+#[test_case]
+fn test_memory_totals() {
+    let totals = memory_totals();
+    assert_eq!(totals.regions, memory_map().iter().count());
+    assert_eq!(
+        totals.total,
+        totals.usable + totals.in_use + totals.reserved
+    );
+    assert!(totals.usable > 0);
+
+    let regions = [
+        (MemoryRegionType::Usable, 4096),
+        (MemoryRegionType::Kernel, 8192),
+        (MemoryRegionType::PageTable, 4096),
+        (MemoryRegionType::Reserved, 1024),
+        (MemoryRegionType::FrameZero, 4096),
+    ];
+    let totals = sum_regions(regions.into_iter());
+    assert_eq!(
+        totals,
+        MemoryTotals {
+            regions: 5,
+            total: 21504,
+            usable: 4096,
+            in_use: 12288,
+            reserved: 5120,
+        }
+    );
+}
+
+#[test_case]
+fn test_kernel_image() {
+    let kernel = kernel_image().expect("no kernel region");
+    assert!(kernel.size() > 0);
+    // 帧分配器不会分配到内核所在的内存
+    let overlaps = memory_map().iter().any(|r| {
+        r.region_type == MemoryRegionType::Usable
+            && r.range.start_addr() < kernel.end.as_u64()
+            && kernel.start.as_u64() < r.range.end_addr()
+    });
+    assert!(!overlaps);
+}
+
+#[test_case]
+fn test_framebuffer_matches_configuration() {
+    assert_eq!(framebuffer().is_some(), cfg!(feature = "vga_320x200"));
+    if let Some(fb) = framebuffer() {
+        assert!(fb.pitch >= fb.width * fb.bytes_per_pixel);
+    }
+}
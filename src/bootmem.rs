@@ -0,0 +1,224 @@
+//! 堆初始化之前使用的只增分配器, 从.bss中预留的区域分配, 分配出的内存永不释放
+//!
+//! `allocator::init_heap`完成后调用`seal`, 之后再分配会panic并报告调用位置.
+//! 封存时输出用量的峰值, 据此调整`RESERVE`
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::log;
+use crate::log::Level;
+
+/// 全局区域的大小
+pub const RESERVE: usize = 64 * 1024;
+
+/// 分配区域的存储, 页对齐以满足最大4096字节的对齐而不浪费空间
+#[repr(C, align(4096))]
+pub struct Storage<const N: usize>([UnsafeCell<u8>; N]);
+
+// 只通过`BootMem`访问, 它保证各次分配互不重叠
+unsafe impl<const N: usize> Sync for Storage<N> {}
+
+impl<const N: usize> Storage<N> {
+    pub const fn new() -> Self {
+        Storage([const { UnsafeCell::new(0) }; N])
+    }
+}
+
+impl<const N: usize> Default for Storage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 在一块静态存储上的只增分配器
+pub struct BootMem {
+    memory: &'static [UnsafeCell<u8>],
+    // 已分配的字节数, 包括对齐的填充
+    next: AtomicUsize,
+    sealed: AtomicBool,
+}
+
+// 分配通过原子操作划分互不重叠的范围
+unsafe impl Sync for BootMem {}
+
+impl BootMem {
+    pub const fn new<const N: usize>(storage: &'static Storage<N>) -> BootMem {
+        BootMem {
+            memory: &storage.0,
+            next: AtomicUsize::new(0),
+            sealed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// 已分配的字节数, 只增不减, 所以也是峰值
+    pub fn used(&self) -> usize {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed.load(Ordering::Acquire)
+    }
+
+    /// 禁止之后的分配, 返回用量
+    pub fn seal(&self) -> usize {
+        self.sealed.store(true, Ordering::Release);
+        self.used()
+    }
+
+    /// 分配满足`layout`的内存, 空间不足时返回None且不改变已分配的部分. 封存后panic
+    #[track_caller]
+    pub fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        if self.is_sealed() {
+            panic!(
+                "bootmem: allocation of {} bytes after seal at {}",
+                layout.size(),
+                Location::caller()
+            );
+        }
+        let base = self.memory.as_ptr() as usize;
+        let mut start = 0;
+        self.next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                // 按绝对地址对齐
+                start = (base + next).checked_next_multiple_of(layout.align())? - base;
+                let end = start.checked_add(layout.size())?;
+                (end <= self.capacity()).then_some(end)
+            })
+            .ok()?;
+        Some(UnsafeCell::raw_get(
+            self.memory.as_ptr().wrapping_add(start),
+        ))
+    }
+
+    /// 同`try_alloc`, 空间不足时panic并报告调用位置
+    #[track_caller]
+    pub fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.try_alloc(layout) {
+            Some(ptr) => ptr,
+            None => panic!(
+                "bootmem: out of memory allocating {} bytes at {}, {} of {} used",
+                layout.size(),
+                Location::caller(),
+                self.used(),
+                self.capacity()
+            ),
+        }
+    }
+
+    /// 未初始化的`n`个`T`
+    #[track_caller]
+    pub fn alloc_slice<T>(&self, n: usize) -> &'static mut [MaybeUninit<T>] {
+        let layout = Layout::array::<T>(n).expect("bootmem: slice too large");
+        let ptr = self.alloc(layout) as *mut MaybeUninit<T>;
+        // 每次分配的范围互不重叠, 且存储是静态的
+        unsafe { core::slice::from_raw_parts_mut(ptr, n) }
+    }
+
+    /// 把`items`复制到新分配的切片中, 迭代器会被遍历两次
+    #[track_caller]
+    pub fn alloc_slice_from<T, I>(&self, items: I) -> &'static [T]
+    where
+        I: Iterator<Item = T> + Clone,
+    {
+        let slice = self.alloc_slice(items.clone().count());
+        for (slot, item) in slice.iter_mut().zip(items) {
+            slot.write(item);
+        }
+        // 两次遍历的长度相同, 所有元素都已写入
+        unsafe { &*(slice as *const [MaybeUninit<T>] as *const [T]) }
+    }
+}
+
+static STORAGE: Storage<RESERVE> = Storage::new();
+static BOOTMEM: BootMem = BootMem::new(&STORAGE);
+
+/// 全局区域
+pub fn global() -> &'static BootMem {
+    &BOOTMEM
+}
+
+#[track_caller]
+pub fn alloc(layout: Layout) -> *mut u8 {
+    BOOTMEM.alloc(layout)
+}
+
+#[track_caller]
+pub fn alloc_slice<T>(n: usize) -> &'static mut [MaybeUninit<T>] {
+    BOOTMEM.alloc_slice(n)
+}
+
+#[track_caller]
+pub fn alloc_slice_from<T, I>(items: I) -> &'static [T]
+where
+    I: Iterator<Item = T> + Clone,
+{
+    BOOTMEM.alloc_slice_from(items)
+}
+
+/// 由`allocator::init_heap`在堆可用后调用
+pub fn seal() {
+    let used = BOOTMEM.seal();
+    log!(
+        Level::Info,
+        "bootmem: sealed with {} of {} bytes used",
+        used,
+        RESERVE
+    );
+}
+
+#[test_case]
+fn test_alignment() {
+    static STORAGE: Storage<8192> = Storage::new();
+    static MEM: BootMem = BootMem::new(&STORAGE);
+    let mut previous_end = 0;
+    for align in [1, 8, 64, 4096, 1] {
+        let layout = Layout::from_size_align(3, align).unwrap();
+        let ptr = MEM.alloc(layout) as usize;
+        assert_eq!(ptr % align, 0, "align {}", align);
+        assert!(ptr >= previous_end);
+        previous_end = ptr + 3;
+    }
+    // 4096字节对齐的分配跳过了这一页的剩余部分
+    assert!(MEM.used() > 4096 && MEM.used() < 4096 + 16);
+}
+
+#[test_case]
+fn test_exhaustion() {
+    static STORAGE: Storage<256> = Storage::new();
+    static MEM: BootMem = BootMem::new(&STORAGE);
+    let first = MEM.alloc_slice_from([1u64, 2, 3].into_iter());
+    let used = MEM.used();
+    // 放不下时不分配, 已分配的内容不变
+    assert!(MEM.try_alloc(Layout::new::<[u8; 256]>()).is_none());
+    let huge = Layout::from_size_align(isize::MAX as usize, 1).unwrap();
+    assert!(MEM.try_alloc(huge).is_none());
+    assert_eq!(MEM.used(), used);
+    assert_eq!(first, [1, 2, 3]);
+    // 剩余的空间仍然可用
+    let rest = MEM.alloc_slice::<u8>(256 - used);
+    assert_eq!(rest.len(), 256 - used);
+    assert_eq!(MEM.used(), MEM.capacity());
+    assert!(MEM.try_alloc(Layout::new::<u8>()).is_none());
+}
+
+#[test_case]
+fn test_seal() {
+    static STORAGE: Storage<64> = Storage::new();
+    static MEM: BootMem = BootMem::new(&STORAGE);
+    let value = MEM.alloc_slice_from(core::iter::once(0x5a5a_u32));
+    assert_eq!(MEM.seal(), 4);
+    assert!(MEM.is_sealed());
+    // 封存前分配的内存仍然有效
+    assert_eq!(value, [0x5a5a]);
+    // 封存后的分配panic, 见tests/bootmem_seal.rs; 全局区域在启动时已经封存
+    assert!(global().is_sealed());
+    assert!(global().used() <= RESERVE);
+}
@@ -0,0 +1,291 @@
+//! 启动各阶段的耗时, 单位为TSC周期, 报告时换算成毫秒
+//!
+//! 每个阶段开始和结束时用同一个名称调用`mark`, 阶段中的步骤嵌套一层. 记录表是固定大小的静态数组,
+//! 堆初始化之前也能使用, 写满后新的记录被丢弃并计数. 只有BSP在启动期间记录, 所以表只有一个写者
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::log::Level;
+use crate::shell::{self, CmdError};
+use crate::{cpu, log, time};
+
+/// 全局记录表的容量
+pub const CAPACITY: usize = 64;
+
+// 报告中瀑布图的宽度
+const BAR_WIDTH: u64 = 32;
+
+/// 一次`mark`调用
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    pub name: &'static str,
+    pub tsc: u64,
+}
+
+/// 固定容量的记录表
+pub struct Table<const N: usize> {
+    marks: [UnsafeCell<Mark>; N],
+    // 已写入的记录数, 写入记录之后才增加
+    len: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// 只有一个写者, 读者只访问`len`之前已经写完的记录
+unsafe impl<const N: usize> Sync for Table<N> {}
+
+impl<const N: usize> Table<N> {
+    pub const fn new() -> Self {
+        Table {
+            marks: [const { UnsafeCell::new(Mark { name: "", tsc: 0 }) }; N],
+            len: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// 记录当前的TSC
+    #[inline(always)]
+    pub fn mark(&self, name: &'static str) {
+        self.record(name, cpu::rdtsc());
+    }
+
+    #[inline(always)]
+    fn record(&self, name: &'static str, tsc: u64) {
+        let len = self.len.load(Ordering::Relaxed);
+        if len == N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // 只有一个写者, 这条记录还没有被读者看到
+        unsafe { *self.marks[len].get() = Mark { name, tsc } };
+        self.len.store(len + 1, Ordering::Release);
+    }
+
+    /// 已记录的标记, 按记录的顺序
+    pub fn marks(&self) -> impl Iterator<Item = Mark> + '_ {
+        let len = self.len.load(Ordering::Acquire);
+        // 前`len`条记录已经写完, 不会再被修改
+        self.marks[..len].iter().map(|mark| unsafe { *mark.get() })
+    }
+
+    /// 表满后被丢弃的标记数
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<const N: usize> Default for Table<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static TABLE: Table<CAPACITY> = Table::new();
+
+/// 在全局记录表中记录一个标记, 只由BSP在启动期间调用
+#[inline(always)]
+pub fn mark(name: &'static str) {
+    TABLE.mark(name);
+}
+
+/// 一对标记之间的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Phase {
+    pub name: &'static str,
+    /// 开始时已经打开的阶段数
+    pub depth: usize,
+    pub start: u64,
+    /// 还没有结束时为None
+    pub end: Option<u64>,
+}
+
+/// 把标记配对成阶段, 按开始的顺序. 与打开的阶段同名的标记结束它, 以及在它之后打开而没有结束的阶段
+pub fn phases(marks: impl Iterator<Item = Mark>) -> Vec<Phase> {
+    let mut phases: Vec<Phase> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    for mark in marks {
+        match open
+            .iter()
+            .rposition(|&index| phases[index].name == mark.name)
+        {
+            Some(position) => {
+                for index in open.drain(position..) {
+                    phases[index].end = Some(mark.tsc);
+                }
+            }
+            None => {
+                open.push(phases.len());
+                phases.push(Phase {
+                    name: mark.name,
+                    depth: open.len() - 1,
+                    start: mark.tsc,
+                    end: None,
+                });
+            }
+        }
+    }
+    phases
+}
+
+// 周期数换算成微秒
+fn micros(cycles: u64, hz: u64) -> u64 {
+    (u128::from(cycles) * 1_000_000 / u128::from(hz)) as u64
+}
+
+struct Millis(u64);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = alloc::format!("{}.{:03}", self.0 / 1000, self.0 % 1000);
+        f.pad(&text)
+    }
+}
+
+// 每个阶段一行, 缩进表示嵌套, 右侧的条表示阶段在整个启动过程中的位置
+fn render(out: &mut dyn fmt::Write, marks: &[Mark], dropped: usize, hz: u64) -> fmt::Result {
+    let phases = phases(marks.iter().copied());
+    let (Some(first), Some(last)) = (marks.first(), marks.iter().map(|mark| mark.tsc).max()) else {
+        return writeln!(out, "bootprof: no marks recorded");
+    };
+    let total = last - first.tsc;
+    writeln!(
+        out,
+        "boot: {} ms since reset before the first mark, TSC at {} MHz",
+        Millis(micros(first.tsc, hz)),
+        hz / 1_000_000
+    )?;
+    writeln!(out, "{:>10}{:>10}  {:<24}", "START", "MS", "PHASE")?;
+    for phase in &phases {
+        let start = phase.start - first.tsc;
+        let end = phase.end.map_or(total, |end| end - first.tsc);
+        let mut name = String::new();
+        for _ in 0..phase.depth {
+            name.push_str("  ");
+        }
+        name.push_str(phase.name);
+        if phase.end.is_none() {
+            name.push_str(" (open)");
+        }
+        let (left, right) = match total {
+            0 => (0, 1),
+            total => (start * BAR_WIDTH / total, end * BAR_WIDTH / total),
+        };
+        let mut bar = String::new();
+        for column in 0..BAR_WIDTH {
+            let filled = column >= left && (column < right || column == left);
+            bar.push(if filled { '#' } else { ' ' });
+        }
+        writeln!(
+            out,
+            "{:>10}{:>10}  {:<24}|{}|",
+            Millis(micros(start, hz)),
+            Millis(micros(end - start, hz)),
+            name,
+            bar
+        )?;
+    }
+    writeln!(out, "total {} ms", Millis(micros(total, hz)))?;
+    if dropped > 0 {
+        writeln!(out, "{} marks dropped after the table filled up", dropped)?;
+    }
+    Ok(())
+}
+
+/// 输出各阶段的耗时. TSC未校准时按`time::ASSUMED_TSC_HZ`换算
+pub fn report(out: &mut dyn fmt::Write) -> fmt::Result {
+    let marks: Vec<Mark> = TABLE.marks().collect();
+    let hz = time::tsc_frequency().unwrap_or(time::ASSUMED_TSC_HZ);
+    render(out, &marks, TABLE.dropped(), hz)
+}
+
+/// 以info级别输出报告, 在外壳启动之前调用
+pub fn log_report() {
+    let mut text = String::new();
+    if report(&mut text).is_err() {
+        return;
+    }
+    for line in text.lines() {
+        log!(Level::Info, "{}", line);
+    }
+}
+
+/// 注册`bootprof`命令
+pub fn register_commands() {
+    shell::register_command("bootprof", "bootprof: boot phase timing", bootprof_command)
+        .expect("duplicate bootprof command");
+}
+
+fn bootprof_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage("bootprof"));
+    }
+    report(out)?;
+    Ok(())
+}
+
+#[test_case]
+fn test_boot_marks() {
+    let marks: Vec<Mark> = TABLE.marks().collect();
+    // 测试内核也经过了`init`和`init_memory`
+    let position = |name| marks.iter().position(|mark| mark.name == name);
+    let init = position("init").expect("init not marked");
+    let memory = position("memory").expect("memory not marked");
+    assert!(init < memory);
+    assert!(marks.windows(2).all(|pair| pair[0].tsc <= pair[1].tsc));
+    let phases = phases(marks.iter().copied());
+    let init = phases.iter().find(|phase| phase.name == "init").unwrap();
+    assert_eq!(init.depth, 0);
+    assert!(init.end.is_some());
+    let ps2 = phases.iter().find(|phase| phase.name == "ps2").unwrap();
+    assert_eq!(ps2.depth, 1);
+    assert!(ps2.start >= init.start && ps2.end <= init.end);
+    assert_eq!(TABLE.dropped(), 0);
+}
+
+#[test_case]
+fn test_table_full() {
+    static TABLE: Table<4> = Table::new();
+    for name in ["a", "b", "b", "c", "c", "a"] {
+        TABLE.mark(name);
+    }
+    let names: Vec<_> = TABLE.marks().map(|mark| mark.name).collect();
+    assert_eq!(names, ["a", "b", "b", "c"]);
+    assert_eq!(TABLE.dropped(), 2);
+    // 丢弃之后的阶段没有结束
+    let phases = phases(TABLE.marks());
+    assert_eq!(phases.len(), 3);
+    assert_eq!(phases[0].end, None);
+    assert!(phases[1].end.is_some());
+}
+
+#[test_case]
+fn test_report_layout() {
+    static TABLE: Table<8> = Table::new();
+    // 1MHz时一个周期是一微秒
+    for (name, tsc) in [("init", 1000), ("ps2", 1500), ("ps2", 3500), ("init", 5000)] {
+        TABLE.record(name, tsc);
+    }
+    TABLE.record("smp", 5000);
+    let marks: Vec<Mark> = TABLE.marks().collect();
+    let mut out = String::new();
+    render(&mut out, &marks, 0, 1_000_000).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert!(lines[0].contains("1.000 ms since reset"), "{}", lines[0]);
+    assert!(lines[2].contains("     4.000  init "), "{}", lines[2]);
+    assert!(
+        lines[3].contains("     0.500     2.000    ps2 "),
+        "{}",
+        lines[3]
+    );
+    assert!(lines[4].contains("smp (open)"), "{}", lines[4]);
+    assert_eq!(lines[5], "total 4.000 ms");
+    // ps2从总时间的1/8持续到5/8
+    assert!(
+        lines[3].ends_with("|    ################            |"),
+        "{}",
+        lines[3]
+    );
+}
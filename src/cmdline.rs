@@ -0,0 +1,167 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use futures_util::FutureExt;
+use spin::Once;
+
+use crate::shell::args;
+use crate::vfs;
+
+/// 内核命令行所在的文件. bootloader 0.9不传递命令行, 因此从initrd读取
+pub const CMDLINE_PATH: &str = "/etc/cmdline";
+// 命令行最多读取的字节数, 多余的部分忽略
+const MAX_CMDLINE_LEN: usize = 4096;
+
+/// 解析后的命令行: `key=value`和不带值的`flag`, 以空白分隔
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cmdline {
+    args: Vec<(String, Option<String>)>,
+}
+
+impl Cmdline {
+    /// 解析命令行, 不会失败. 引号内的空白不分隔参数, 缺少结束引号时引号持续到行尾
+    pub fn parse(line: &str) -> Cmdline {
+        Cmdline {
+            args: tokenize(line)
+                .into_iter()
+                .map(|token| match token.split_once('=') {
+                    Some((key, value)) => (String::from(key), Some(String::from(value))),
+                    None => (token, None),
+                })
+                .filter(|(key, _)| !key.is_empty())
+                .collect(),
+        }
+    }
+
+    /// `key`的值, 重复出现时取最后一个. 不带值的flag为None
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.args
+            .iter()
+            .rev()
+            .find(|(name, _)| name == key)?
+            .1
+            .as_deref()
+    }
+
+    /// `key`是否作为flag出现, 或者值不是`0`、`no`、`off`、`false`
+    pub fn flag(&self, key: &str) -> bool {
+        match self.args.iter().rev().find(|(name, _)| name == key) {
+            Some((_, None)) => true,
+            Some((_, Some(value))) => !matches!(value.as_str(), "0" | "no" | "off" | "false"),
+            None => false,
+        }
+    }
+
+    /// 按`shell::args::parse_number`解析`key`的值, 没有或不是数字时为None
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        args::parse_number(self.get(key)?).ok()
+    }
+}
+
+// 按空白切分, 单引号和双引号内的空白保留. 不处理转义, 命令行里没有需要转义的内容
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '\'' | '"') => {
+                in_token = true;
+                quote = Some(c);
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(core::mem::take(&mut current));
+                    in_token = false;
+                }
+            }
+            (None, c) => {
+                in_token = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_token {
+        tokens.push(current);
+    }
+    tokens
+}
+
+static CMDLINE: Once<Cmdline> = Once::new();
+
+/// 读取并解析`CMDLINE_PATH`, 需要VFS. 文件不存在或无法读取时命令行为空
+pub fn init() {
+    // 启动时只挂载了内存中的文件系统, 读取不会等待, 也还不能等待
+    let text = read_cmdline().now_or_never().flatten();
+    CMDLINE.call_once(|| Cmdline::parse(&text.unwrap_or_default()));
+}
+
+async fn read_cmdline() -> Option<String> {
+    let mut file = vfs::open(CMDLINE_PATH).await.ok()?;
+    let mut data = alloc::vec![0; file.metadata().len.min(MAX_CMDLINE_LEN)];
+    let mut read = 0;
+    while read < data.len() {
+        match file.read(&mut data[read..]).await.ok()? {
+            0 => break,
+            len => read += len,
+        }
+    }
+    data.truncate(read);
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// 启动时的命令行, `init`之前为空
+pub fn cmdline() -> &'static Cmdline {
+    static EMPTY: Cmdline = Cmdline { args: Vec::new() };
+    CMDLINE.get().unwrap_or(&EMPTY)
+}
+
+/// 见[`Cmdline::get`]
+pub fn get(key: &str) -> Option<&'static str> {
+    cmdline().get(key)
+}
+
+/// 见[`Cmdline::flag`]
+pub fn flag(key: &str) -> bool {
+    cmdline().flag(key)
+}
+
+/// 见[`Cmdline::get_u64`]
+pub fn get_u64(key: &str) -> Option<u64> {
+    cmdline().get_u64(key)
+}
+
+#[test_case]
+fn test_tokenize() {
+    assert!(tokenize("").is_empty());
+    assert!(tokenize("   \n").is_empty());
+    assert_eq!(tokenize("a  b\tc \n"), ["a", "b", "c"]);
+    assert_eq!(tokenize("a=\"b c\" d"), ["a=b c", "d"]);
+    assert_eq!(tokenize("'x y'z \"\""), ["x yz", ""]);
+    // 缺少结束引号时引号持续到行尾
+    assert_eq!(tokenize("a='b c"), ["a=b c"]);
+    assert_eq!(tokenize("a=\"it's\""), ["a=it's"]);
+}
+
+#[test_case]
+fn test_parse_cmdline() {
+    let cmdline = Cmdline::parse(
+        "loglevel=debug console=\"serial\" nowatchdog loglevel=warn =x a= n=0x10 off=0 ",
+    );
+    assert_eq!(cmdline.get("loglevel"), Some("warn"));
+    assert_eq!(cmdline.get("console"), Some("serial"));
+    assert_eq!(cmdline.get("nowatchdog"), None);
+    assert!(cmdline.flag("nowatchdog"));
+    assert!(!cmdline.flag("missing"));
+    assert!(!cmdline.flag("off"));
+    assert_eq!(cmdline.get("a"), Some(""));
+    assert_eq!(cmdline.get(""), None);
+    assert_eq!(cmdline.get_u64("n"), Some(16));
+    assert_eq!(cmdline.get_u64("loglevel"), None);
+    assert_eq!(Cmdline::parse(""), Cmdline::default());
+    // 值中可以再出现'='
+    assert_eq!(Cmdline::parse("k=a=b").get("k"), Some("a=b"));
+}
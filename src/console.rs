@@ -0,0 +1,297 @@
+//! 捕获`print!`和日志宏的输出, 供测试、命令的管道和批处理的报告使用
+//!
+//! 捕获属于当前上下文: 执行器正在poll的任务, 或者不在任务中运行的线程. 同一上下文中的捕获可以嵌套,
+//! 输出进入最内层的捕获和包含它的各层, 直到`capture_silent`开始的一层为止, 遇到这一层时也不再输出到控制台.
+//! 关中断时(包括中断处理函数中)的输出不属于任何任务, 从不捕获
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::str::Lines;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use x86_64::instructions::interrupts;
+
+use crate::sync::IrqMutex;
+use crate::task;
+use crate::thread::{self, ThreadId};
+
+/// 捕获到的输出
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedOutput {
+    text: String,
+}
+
+impl CapturedOutput {
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn lines(&self) -> Lines<'_> {
+        self.text.lines()
+    }
+
+    pub fn into_string(self) -> String {
+        self.text
+    }
+}
+
+impl fmt::Display for CapturedOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// 输出所属的上下文
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Owner {
+    Task(u64),
+    Thread(Option<ThreadId>),
+}
+
+impl Owner {
+    fn current() -> Owner {
+        match task::current_id() {
+            Some(id) => Owner::Task(id),
+            None => Owner::Thread(thread::current_id_unlocked()),
+        }
+    }
+}
+
+// 一层捕获
+struct Frame {
+    owner: Owner,
+    silent: bool,
+    text: String,
+}
+
+// 所有上下文中正在进行的捕获, 同一上下文的各层按嵌套顺序排列
+static FRAMES: IrqMutex<Vec<Frame>> = IrqMutex::new("console::FRAMES", Vec::new());
+// FRAMES的长度, 没有捕获时输出不加锁
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+fn push(owner: Owner, silent: bool, text: String) {
+    FRAMES.lock().push(Frame {
+        owner,
+        silent,
+        text,
+    });
+    ACTIVE.fetch_add(1, Ordering::Release);
+}
+
+// 取下`owner`最内层的捕获
+fn pop(owner: Owner) -> String {
+    let mut frames = FRAMES.lock();
+    let position = frames
+        .iter()
+        .rposition(|frame| frame.owner == owner)
+        .expect("console: capture ended in another context");
+    ACTIVE.fetch_sub(1, Ordering::Release);
+    frames.remove(position).text
+}
+
+/// 捕获`f`执行期间当前上下文的输出, 输出仍然出现在控制台上
+pub fn capture(f: impl FnOnce()) -> CapturedOutput {
+    capture_with(false, f)
+}
+
+/// 同`capture`, 但输出不出现在控制台上, 也不进入外层的捕获
+pub fn capture_silent(f: impl FnOnce()) -> CapturedOutput {
+    capture_with(true, f)
+}
+
+fn capture_with(silent: bool, f: impl FnOnce()) -> CapturedOutput {
+    let owner = Owner::current();
+    push(owner, silent, String::new());
+    f();
+    CapturedOutput { text: pop(owner) }
+}
+
+/// 捕获`future`每次被poll时的输出, 包括它await的子future. 两次poll之间同一任务之外的输出不捕获
+pub fn capture_async<F: Future<Output = ()>>(future: F) -> Captured<F> {
+    Captured {
+        future: Box::pin(future),
+        silent: false,
+        text: String::new(),
+    }
+}
+
+/// 同`capture_async`, 输出不出现在控制台上
+pub fn capture_silent_async<F: Future<Output = ()>>(future: F) -> Captured<F> {
+    Captured {
+        silent: true,
+        ..capture_async(future)
+    }
+}
+
+/// `capture_async`返回的future, 结束时得到捕获的输出
+pub struct Captured<F> {
+    future: Pin<Box<F>>,
+    silent: bool,
+    text: String,
+}
+
+impl<F: Future<Output = ()>> Future for Captured<F> {
+    type Output = CapturedOutput;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<CapturedOutput> {
+        let this = self.get_mut();
+        let owner = Owner::current();
+        push(owner, this.silent, mem::take(&mut this.text));
+        let result = this.future.as_mut().poll(cx);
+        this.text = pop(owner);
+        result.map(|()| CapturedOutput {
+            text: mem::take(&mut this.text),
+        })
+    }
+}
+
+/// 由`_print`在输出到控制台之前调用, 把输出追加到当前上下文的各层捕获中. 返回是否还应输出到控制台
+pub(crate) fn record(args: fmt::Arguments) -> bool {
+    if ACTIVE.load(Ordering::Acquire) == 0 || !interrupts::are_enabled() {
+        return true;
+    }
+    let owner = Owner::current();
+    let mut frames = FRAMES.lock();
+    for frame in frames.iter_mut().rev().filter(|frame| frame.owner == owner) {
+        let _ = frame.text.write_fmt(args);
+        if frame.silent {
+            return false;
+        }
+    }
+    true
+}
+
+// 让出一次的future, 用于测试两个任务交替运行
+#[cfg(test)]
+struct YieldOnce(bool);
+
+#[cfg(test)]
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_capture_println() {
+    use crate::log::Level;
+    use crate::vga_buffer::{start_recording, stop_recording};
+    use crate::{log, println};
+
+    start_recording(4096);
+    let captured = capture(|| {
+        println!("capture: first line");
+        println!("capture: second line {}", 2);
+        log!(Level::Warn, "capture: a warning");
+    });
+    let transcript = stop_recording();
+    assert_eq!(
+        captured.as_str(),
+        "capture: first line\ncapture: second line 2\ncapture: a warning\n"
+    );
+    assert_eq!(captured.lines().count(), 3);
+    // 屏幕上也有同样的输出
+    for line in captured.lines() {
+        assert!(transcript.contains(line), "{:?} not on screen", line);
+    }
+}
+
+#[test_case]
+fn test_nested_capture() {
+    use crate::println;
+    use crate::vga_buffer::{start_recording, stop_recording};
+
+    let mut inner = CapturedOutput::default();
+    let mut silent = CapturedOutput::default();
+    start_recording(4096);
+    let outer = capture(|| {
+        println!("nested: outer");
+        inner = capture(|| println!("nested: inner"));
+        silent = capture_silent(|| println!("nested: silent"));
+        println!("nested: outer again");
+    });
+    let transcript = stop_recording();
+    assert_eq!(inner.as_str(), "nested: inner\n");
+    assert_eq!(silent.as_str(), "nested: silent\n");
+    assert_eq!(
+        outer.as_str(),
+        "nested: outer\nnested: inner\nnested: outer again\n"
+    );
+    assert!(transcript.contains("nested: inner"));
+    assert!(!transcript.contains("nested: silent"));
+}
+
+#[test_case]
+fn test_timer_print_not_captured() {
+    use crate::interrupts::{set_timer_print_marker, TIMER_MARKER};
+    use crate::println;
+    use crate::time::{pit_ticks, wait_until};
+    use crate::vga_buffer::{start_recording, stop_recording};
+
+    start_recording(4096);
+    let captured = capture(|| {
+        set_timer_print_marker(true);
+        println!("timer: before ticks");
+        let start = pit_ticks();
+        wait_until(1000, || pit_ticks() >= start + 2).expect("timer stalled");
+        println!("timer: after ticks");
+        set_timer_print_marker(false);
+    });
+    let transcript = stop_recording();
+    assert!(
+        transcript.contains(TIMER_MARKER),
+        "timer handler never printed during the capture"
+    );
+    assert_eq!(
+        captured.as_str(),
+        "timer: before ticks\ntimer: after ticks\n"
+    );
+}
+
+#[test_case]
+fn test_capture_async() {
+    use spin::Mutex;
+
+    use crate::println;
+    use crate::task::executor::Executor;
+    use crate::task::Task;
+
+    static CAPTURED: Mutex<Option<CapturedOutput>> = Mutex::new(None);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::named("capturing", async {
+        let captured = capture_async(async {
+            println!("async: first");
+            YieldOnce(false).await;
+            println!("async: second");
+        })
+        .await;
+        *CAPTURED.lock() = Some(captured);
+    }));
+    // 在两次poll之间输出, 不属于上面的任务
+    executor.spawn(Task::named("other", async {
+        println!("async: other task");
+        YieldOnce(false).await;
+        println!("async: other task again");
+    }));
+    executor.run_until_idle();
+    let captured = CAPTURED
+        .lock()
+        .take()
+        .expect("capturing task did not finish");
+    assert_eq!(captured.as_str(), "async: first\nasync: second\n");
+    assert_eq!(task::current_id(), None);
+}
@@ -0,0 +1,99 @@
+use core::arch::asm;
+
+pub mod msr;
+
+/// 读取时间戳计数器
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    }
+    (u64::from(hi) << 32) | u64::from(lo)
+}
+
+/// 前后用lfence隔开的rdtsc, 防止乱序执行把被测代码移出计时区间
+pub fn rdtsc_serialized() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!(
+            "lfence",
+            "rdtsc",
+            "lfence",
+            out("eax") lo,
+            out("edx") hi,
+            options(nostack, preserves_flags),
+        );
+    }
+    (u64::from(hi) << 32) | u64::from(lo)
+}
+
+/// CPUID报告的处理器特性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub tsc: bool,
+    pub apic: bool,
+    pub x2apic: bool,
+    pub rdrand: bool,
+    pub rdseed: bool,
+    /// TSC频率不随电源状态变化, 可以作为时钟源
+    pub invariant_tsc: bool,
+}
+
+static FEATURES: spin::Once<CpuFeatures> = spin::Once::new();
+
+/// 第一次调用时执行CPUID, 之后返回缓存的结果
+pub fn features() -> &'static CpuFeatures {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    FEATURES.call_once(|| {
+        #[allow(unused_unsafe)]
+        let (leaf1, leaf7, power) = unsafe {
+            let max_leaf = __cpuid(0).eax;
+            let leaf7 = if max_leaf >= 7 { Some(__cpuid_count(7, 0)) } else { None };
+            let max_extended = __cpuid(0x8000_0000).eax;
+            let power = if max_extended >= 0x8000_0007 { Some(__cpuid(0x8000_0007)) } else { None };
+            (__cpuid(1), leaf7, power)
+        };
+        CpuFeatures {
+            tsc: leaf1.edx & (1 << 4) != 0,
+            apic: leaf1.edx & (1 << 9) != 0,
+            x2apic: leaf1.ecx & (1 << 21) != 0,
+            rdrand: leaf1.ecx & (1 << 30) != 0,
+            rdseed: leaf7.is_some_and(|leaf| leaf.ebx & (1 << 18) != 0),
+            invariant_tsc: power.is_some_and(|leaf| leaf.edx & (1 << 8) != 0),
+        }
+    })
+}
+
+static BRAND: spin::Once<Option<[u8; 48]>> = spin::Once::new();
+
+/// CPUID扩展叶0x80000002..0x80000004中的处理器名称, 去掉首尾的空格
+pub fn brand_string() -> Option<&'static str> {
+    use core::arch::x86_64::__cpuid;
+
+    let brand = BRAND.call_once(|| {
+        #[allow(unused_unsafe)]
+        unsafe {
+            if __cpuid(0x8000_0000).eax < 0x8000_0004 {
+                return None;
+            }
+            let mut brand = [0u8; 48];
+            for (i, chunk) in brand.chunks_exact_mut(16).enumerate() {
+                let leaf = __cpuid(0x8000_0002 + i as u32);
+                let regs = [leaf.eax, leaf.ebx, leaf.ecx, leaf.edx];
+                for (bytes, reg) in chunk.chunks_exact_mut(4).zip(regs) {
+                    bytes.copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+            Some(brand)
+        }
+    });
+    let brand = brand.as_ref()?;
+    let len = brand.iter().position(|&b| b == 0).unwrap_or(brand.len());
+    core::str::from_utf8(&brand[..len])
+        .ok()
+        .map(str::trim)
+        .filter(|brand| !brand.is_empty())
+}
@@ -0,0 +1,277 @@
+use core::arch::asm;
+
+use x86_64::registers::rflags::RFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{fault, println};
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_STAR: u32 = 0xC000_0081;
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+pub const IA32_FMASK: u32 = 0xC000_0084;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// 读取MSR
+///
+/// # Safety
+///
+/// `msr`必须存在, 不存在的MSR会触发一般保护异常
+pub unsafe fn read(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    (u64::from(hi) << 32) | u64::from(lo)
+}
+
+/// 写入MSR
+///
+/// # Safety
+///
+/// 调用者保证写入的值不会破坏内存安全
+pub unsafe fn write(msr: u32, value: u64) {
+    let lo = value as u32;
+    let hi = (value >> 32) as u32;
+    asm!("wrmsr", in("ecx") msr, in("eax") lo, in("edx") hi, options(nostack, preserves_flags));
+}
+
+/// 探测性读取, MSR不存在时返回None而不是触发异常
+pub fn try_read(msr: u32) -> Option<u64> {
+    fault::try_with_recovery(|| unsafe { read(msr) }).ok()
+}
+
+/// IA32_EFER
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Efer(pub u64);
+
+impl Efer {
+    const SYSCALL_ENABLE: u64 = 1 << 0;
+    const LONG_MODE_ENABLE: u64 = 1 << 8;
+    const LONG_MODE_ACTIVE: u64 = 1 << 10;
+    const NO_EXECUTE_ENABLE: u64 = 1 << 11;
+
+    pub fn read() -> Efer {
+        Efer(unsafe { read(IA32_EFER) })
+    }
+
+    /// # Safety
+    ///
+    /// 关闭长模式或NXE会使当前的页表和代码失效
+    pub unsafe fn write(self) {
+        write(IA32_EFER, self.0)
+    }
+
+    pub fn syscall_enabled(self) -> bool {
+        self.0 & Self::SYSCALL_ENABLE != 0
+    }
+
+    pub fn long_mode_enabled(self) -> bool {
+        self.0 & Self::LONG_MODE_ENABLE != 0
+    }
+
+    /// 只读位, 由处理器在进入长模式时置位
+    pub fn long_mode_active(self) -> bool {
+        self.0 & Self::LONG_MODE_ACTIVE != 0
+    }
+
+    pub fn nx_enabled(self) -> bool {
+        self.0 & Self::NO_EXECUTE_ENABLE != 0
+    }
+
+    pub fn with_syscall(self, enabled: bool) -> Efer {
+        Efer(set_bit(self.0, Self::SYSCALL_ENABLE, enabled))
+    }
+
+    pub fn with_nx(self, enabled: bool) -> Efer {
+        Efer(set_bit(self.0, Self::NO_EXECUTE_ENABLE, enabled))
+    }
+}
+
+/// IA32_APIC_BASE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase(pub u64);
+
+impl ApicBase {
+    const BSP: u64 = 1 << 8;
+    const X2APIC_ENABLE: u64 = 1 << 10;
+    const GLOBAL_ENABLE: u64 = 1 << 11;
+    // 12..52位是4K对齐的物理地址
+    const BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+    pub fn read() -> ApicBase {
+        ApicBase(unsafe { read(IA32_APIC_BASE) })
+    }
+
+    /// # Safety
+    ///
+    /// 移动或关闭APIC会影响已经映射的寄存器和正在使用的中断
+    pub unsafe fn write(self) {
+        write(IA32_APIC_BASE, self.0)
+    }
+
+    /// 当前处理器是否为启动处理器
+    pub fn bsp(self) -> bool {
+        self.0 & Self::BSP != 0
+    }
+
+    pub fn x2apic_enabled(self) -> bool {
+        self.0 & Self::X2APIC_ENABLE != 0
+    }
+
+    pub fn enabled(self) -> bool {
+        self.0 & Self::GLOBAL_ENABLE != 0
+    }
+
+    pub fn base_address(self) -> PhysAddr {
+        PhysAddr::new(self.0 & Self::BASE_MASK)
+    }
+
+    pub fn with_enabled(self, enabled: bool) -> ApicBase {
+        ApicBase(set_bit(self.0, Self::GLOBAL_ENABLE, enabled))
+    }
+
+    pub fn with_x2apic(self, enabled: bool) -> ApicBase {
+        ApicBase(set_bit(self.0, Self::X2APIC_ENABLE, enabled))
+    }
+
+    pub fn with_base_address(self, address: PhysAddr) -> ApicBase {
+        ApicBase((self.0 & !Self::BASE_MASK) | (address.as_u64() & Self::BASE_MASK))
+    }
+}
+
+/// IA32_STAR: syscall/sysret使用的段选择子
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Star(pub u64);
+
+impl Star {
+    /// `syscall_cs`是syscall加载的内核CS, SS为其后一项;
+    /// `sysret_cs`是sysret加载用户段的基准, 64位CS为其后第二项, SS为其后一项
+    pub fn new(syscall_cs: u16, sysret_cs: u16) -> Star {
+        Star(u64::from(syscall_cs) << 32 | u64::from(sysret_cs) << 48)
+    }
+
+    pub fn read() -> Star {
+        Star(unsafe { read(IA32_STAR) })
+    }
+
+    /// # Safety
+    ///
+    /// 选择子必须符合syscall/sysret对GDT布局的要求
+    pub unsafe fn write(self) {
+        write(IA32_STAR, self.0)
+    }
+
+    pub fn syscall_cs(self) -> u16 {
+        (self.0 >> 32) as u16
+    }
+
+    pub fn sysret_cs(self) -> u16 {
+        (self.0 >> 48) as u16
+    }
+}
+
+/// IA32_LSTAR: 64位syscall的入口地址
+pub struct Lstar;
+
+impl Lstar {
+    pub fn read() -> VirtAddr {
+        VirtAddr::new_truncate(unsafe { read(IA32_LSTAR) })
+    }
+
+    /// # Safety
+    ///
+    /// `entry`必须是有效的syscall入口
+    pub unsafe fn write(entry: VirtAddr) {
+        write(IA32_LSTAR, entry.as_u64())
+    }
+}
+
+/// IA32_FMASK: syscall时要清除的RFLAGS位
+pub struct Fmask;
+
+impl Fmask {
+    pub fn read() -> RFlags {
+        RFlags::from_bits_truncate(unsafe { read(IA32_FMASK) })
+    }
+
+    /// # Safety
+    ///
+    /// 不清除IF时syscall入口会在切换到内核栈之前被中断
+    pub unsafe fn write(mask: RFlags) {
+        write(IA32_FMASK, mask.bits())
+    }
+}
+
+fn set_bit(value: u64, bit: u64, enabled: bool) -> u64 {
+    if enabled {
+        value | bit
+    } else {
+        value & !bit
+    }
+}
+
+/// 打印启动时值得关注的MSR
+pub fn report() {
+    let efer = Efer::read();
+    println!(
+        "EFER: {:#x} (LMA={}, NXE={}, SCE={})",
+        efer.0,
+        efer.long_mode_active(),
+        efer.nx_enabled(),
+        efer.syscall_enabled()
+    );
+
+    match try_read(IA32_APIC_BASE).map(ApicBase) {
+        Some(apic) => println!(
+            "APIC_BASE: {:?} (enabled={}, x2apic={}, bsp={})",
+            apic.base_address(),
+            apic.enabled(),
+            apic.x2apic_enabled(),
+            apic.bsp()
+        ),
+        None => println!("APIC_BASE: not available"),
+    }
+
+    let star = Star::read();
+    println!(
+        "STAR: syscall cs={:#x} sysret cs={:#x}, LSTAR: {:?}, FMASK: {:?}",
+        star.syscall_cs(),
+        star.sysret_cs(),
+        Lstar::read(),
+        Fmask::read()
+    );
+}
+
+#[test_case]
+fn test_try_read_bogus_msr() {
+    // 保留范围内的MSR, 读取会触发一般保护异常
+    assert_eq!(try_read(0xDEAD_BEEF), None);
+    // 异常被恢复后, 正常的读取仍然可用
+    assert_eq!(try_read(IA32_EFER), Some(Efer::read().0));
+}
+
+#[test_case]
+fn test_efer_long_mode() {
+    let efer = Efer::read();
+    assert!(efer.long_mode_enabled());
+    assert!(efer.long_mode_active());
+}
+
+#[test_case]
+fn test_bitfields() {
+    let apic = ApicBase(0xFEE0_0900);
+    assert!(apic.bsp());
+    assert!(apic.enabled());
+    assert!(!apic.x2apic_enabled());
+    assert_eq!(apic.base_address(), PhysAddr::new(0xFEE0_0000));
+    let moved = apic.with_base_address(PhysAddr::new(0x1234_5000)).with_enabled(false);
+    assert_eq!(moved.0, 0x1234_5100);
+
+    let star = Star::new(0x08, 0x1B);
+    assert_eq!((star.syscall_cs(), star.sysret_cs()), (0x08, 0x1B));
+
+    let efer = Efer(0).with_nx(true).with_syscall(true);
+    assert!(efer.nx_enabled() && efer.syscall_enabled());
+    assert_eq!(efer.with_nx(false).0, 1);
+}
@@ -0,0 +1,176 @@
+//! 串口上的调试命令行, 不依赖键盘和VGA, 只需要执行器还在运行
+//!
+//! 输入经过`tty`的行规程, 命令与外壳使用同一个注册表, 输出的换行转换为CRLF.
+//! 另外提供`readmem`, 以一行base64或十六进制输出内存内容, 便于主机端脚本读取
+
+use core::fmt::{self, Write};
+
+use futures_util::future;
+use futures_util::stream::StreamExt;
+use x86_64::VirtAddr;
+
+use crate::memdebug::{self, MemAccessError};
+use crate::serial::{self, Serial};
+use crate::serial_println;
+use crate::shell::{self, args, CmdError};
+use crate::tty::{SerialDecoder, Terminal, Tty};
+
+const PROMPT: &str = "debug> ";
+const MAX_LINE_LEN: usize = 256;
+// readmem一次最多读取的字节数
+const MAX_READ_LEN: u64 = 4096;
+const READMEM_USAGE: &str = "readmem <addr> <len> [hex|base64]";
+
+static TTY: Tty<Terminal<Crlf<Serial>>> =
+    Tty::new(MAX_LINE_LEN, Terminal::new(PROMPT, Crlf(Serial)));
+
+/// 注册`readmem`命令, 外壳中同样可用
+pub fn register_commands() {
+    shell::register_command(
+        "readmem",
+        "readmem <addr> <len> [hex|base64]: memory as one encoded line",
+        readmem_command,
+    )
+    .expect("duplicate debugcon command");
+}
+
+/// 读取串口输入并执行命令, 不会结束. 串口接收中断无法注册时直接返回
+pub async fn run() {
+    if let Err(err) = serial::enable_receive() {
+        serial_println!("debugcon: serial receive interrupt unavailable: {:?}", err);
+        return;
+    }
+    let mut lines = TTY.lines();
+    let mut decoder = SerialDecoder::new();
+    let keys = serial::received().filter_map(move |byte| future::ready(decoder.feed(byte)));
+    let _ = Crlf(Serial).write_str("\n");
+    TTY.redraw();
+    let commands = async {
+        let mut out = Crlf(Serial);
+        while let Some(line) = lines.next().await {
+            execute(&line, &mut out).await;
+            TTY.redraw();
+        }
+    };
+    future::join(TTY.run(keys), commands).await;
+}
+
+async fn execute(line: &str, out: &mut (dyn fmt::Write + Send)) {
+    let result = shell::dispatch(line, out).await;
+    let name = line.split_whitespace().next().unwrap_or("");
+    let _ = match result {
+        Ok(()) => Ok(()),
+        Err(CmdError::UnknownCommand) => writeln!(out, "unknown command: {} (try `help`)", name),
+        Err(err) => writeln!(out, "{}: {}", name, err),
+    };
+}
+
+// 把输出中的LF转换为CRLF, 终端仿真器在原始模式下不会自动回车
+struct Crlf<W>(W);
+
+impl<W: fmt::Write> fmt::Write for Crlf<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+        if let Some(first) = lines.next() {
+            self.0.write_str(first)?;
+        }
+        for line in lines {
+            self.0.write_str("\r\n")?;
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+fn readmem_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let (addr, len, hex) = match args {
+        [addr, len] | [addr, len, "base64"] => (addr, len, false),
+        [addr, len, "hex"] => (addr, len, true),
+        _ => return Err(CmdError::Usage(READMEM_USAGE)),
+    };
+    let addr = args::parse_number(addr)?;
+    let len = args::parse_number(len)?;
+    if len > MAX_READ_LEN {
+        return Err(CmdError::Usage(READMEM_USAGE));
+    }
+    let start = VirtAddr::try_new(addr)
+        .map_err(|_| CmdError::Memory(MemAccessError::NonCanonical(addr)))?;
+    let mut bytes = alloc::vec![0u8; len as usize];
+    let read = memdebug::safe_read_bytes(start, &mut bytes).map_err(CmdError::Memory)?;
+    if read < bytes.len() {
+        // 只输出完整的内容, 脚本不需要处理部分结果
+        let next = start + read as u64;
+        return Err(CmdError::Memory(MemAccessError::Unmapped(next)));
+    }
+    if hex {
+        for byte in &bytes {
+            write!(out, "{:02x}", byte)?;
+        }
+        writeln!(out)?;
+    } else {
+        writeln!(out, "{}", Base64(&bytes))?;
+    }
+    Ok(())
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// 标准base64编码, 带`=`填充
+struct Base64<'a>(&'a [u8]);
+
+impl fmt::Display for Base64<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.0.chunks(3) {
+            let mut group = [0u8; 3];
+            group[..chunk.len()].copy_from_slice(chunk);
+            let bits = u32::from(group[0]) << 16 | u32::from(group[1]) << 8 | u32::from(group[2]);
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    let index = (bits >> (18 - 6 * i)) & 0x3F;
+                    f.write_char(char::from(BASE64_ALPHABET[index as usize]))?;
+                } else {
+                    f.write_char('=')?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_crlf_output() {
+    let mut out = Crlf(alloc::string::String::new());
+    write!(out, "a\nb\n\nc").unwrap();
+    assert_eq!(out.0, "a\r\nb\r\n\r\nc");
+}
+
+#[test_case]
+fn test_readmem() {
+    use alloc::format;
+    use alloc::string::String;
+
+    assert_eq!(format!("{}", Base64(b"")), "");
+    assert_eq!(format!("{}", Base64(b"f")), "Zg==");
+    assert_eq!(format!("{}", Base64(b"fo")), "Zm8=");
+    assert_eq!(format!("{}", Base64(b"foo")), "Zm9v");
+    assert_eq!(format!("{}", Base64(b"foobar")), "Zm9vYmFy");
+    assert_eq!(format!("{}", Base64(&[0xff, 0xfe, 0x00])), "//4A");
+
+    static DATA: [u8; 4] = *b"toy!";
+    let addr = format!("{:#x}", DATA.as_ptr() as u64);
+    let mut out = String::new();
+    readmem_command(&[&addr, "4"], &mut out).unwrap();
+    assert_eq!(out, "dG95IQ==\n");
+    out.clear();
+    readmem_command(&[&addr, "4", "hex"], &mut out).unwrap();
+    assert_eq!(out, "746f7921\n");
+    assert!(matches!(
+        readmem_command(&["0", "4"], &mut out),
+        Err(CmdError::Memory(MemAccessError::Unmapped(_)))
+    ));
+    assert!(matches!(
+        readmem_command(&[&addr, "4097"], &mut out),
+        Err(CmdError::Usage(_))
+    ));
+}
@@ -0,0 +1,86 @@
+//! 启动早期的输出: 直接写屏幕首行和COM1端口
+//!
+//! 不加锁、不使用堆和惰性初始化的静态变量, 只要页表还映射着VGA文本缓冲区, 任何时刻都能调用.
+//! `toy_os::init`完成之前异常处理函数通过这里报告, 避免在控制台初始化到一半时获取锁
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::serial;
+
+const VGA_BUFFER_ADDR: usize = 0xb8000;
+const BUFFER_WIDTH: usize = 80;
+// 黑底白字
+const ATTRIBUTE: u16 = 0x0F << 8;
+
+// `toy_os::init`是否已经完成
+static BOOTED: AtomicBool = AtomicBool::new(false);
+
+/// 是否仍处于启动早期, 这时异常应通过earlycon报告
+pub fn active() -> bool {
+    !BOOTED.load(Ordering::Acquire)
+}
+
+/// 由`toy_os::init`在控制台、GDT和IDT都已初始化后调用
+pub(crate) fn end_early_boot() {
+    BOOTED.store(true, Ordering::Release);
+}
+
+/// 同时输出到屏幕首行和COM1
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = Vga::new().write_fmt(args);
+    let _ = serial::unlocked_writer().write_fmt(args);
+}
+
+/// 不加锁也不保存光标, 每次输出都从首行行首开始覆盖
+pub struct Vga {
+    column: usize,
+}
+
+impl Vga {
+    pub const fn new() -> Self {
+        Vga { column: 0 }
+    }
+
+    fn put(&mut self, col: usize, byte: u8) {
+        let row = VGA_BUFFER_ADDR as *mut u16;
+        unsafe { row.add(col).write_volatile(ATTRIBUTE | u16::from(byte)) };
+    }
+}
+
+impl Default for Vga {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for Vga {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            let byte = match byte {
+                // 换行时清除本行余下的部分, 之后的文本从行首覆盖
+                b'\n' => {
+                    for col in self.column..BUFFER_WIDTH {
+                        self.put(col, b' ');
+                    }
+                    self.column = 0;
+                    continue;
+                }
+                0x20..=0x7e => byte,
+                _ => 0xfe,
+            };
+            if self.column < BUFFER_WIDTH {
+                self.put(self.column, byte);
+                self.column += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 通过earlycon输出并换行, 启动早期的异常处理函数使用
+#[macro_export]
+macro_rules! early_println {
+    ($($arg:tt)*) => ($crate::earlycon::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
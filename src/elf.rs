@@ -0,0 +1,436 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::cpu::msr::Efer;
+use crate::error::KernelError;
+use crate::layout::{self, Range};
+use crate::memory::{self, AddressSpace, MemError};
+use crate::vfs;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3e;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// 用户栈的页数, 栈顶下方紧接着栈, 上方留一个不映射的页
+pub const STACK_PAGES: u64 = 8;
+/// 用户栈顶
+pub const STACK_TOP: u64 = layout::USER.end - 4096;
+// 段只能加载到用户空间中栈以下的部分
+const LOAD_RANGE: Range = Range::new(layout::USER.start, STACK_TOP - STACK_PAGES * 4096);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// 头部、程序头或段内容超出文件末尾
+    Truncated,
+    BadMagic,
+    /// 不是64位小端格式
+    UnsupportedFormat,
+    WrongMachine,
+    /// 不是静态链接的可执行文件, 如共享库、PIE或需要动态链接器的程序
+    NotStaticExecutable,
+    /// 程序头大小不对或段的文件大小超过内存大小
+    BadProgramHeader,
+    /// 段不在用户程序可用的地址范围内
+    SegmentOutOfRange,
+    /// 两个段占用了同一页
+    OverlappingSegments,
+    /// 入口不在可执行段中
+    BadEntry,
+    /// 映射用户页失败, 如内存不足或地址已被占用
+    MapFailed(MemError),
+    Io(KernelError),
+}
+
+impl From<KernelError> for ElfError {
+    fn from(err: KernelError) -> Self {
+        ElfError::Io(err)
+    }
+}
+
+impl From<MemError> for ElfError {
+    fn from(err: MemError) -> Self {
+        ElfError::MapFailed(err)
+    }
+}
+
+/// 需要加载的段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub mem_size: u64,
+    pub file_offset: u64,
+    pub file_size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    fn first_page(&self) -> u64 {
+        self.vaddr & !0xfff
+    }
+
+    // 最后一页之后的地址
+    fn end_page(&self) -> u64 {
+        (self.vaddr + self.mem_size).next_multiple_of(4096)
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        (self.vaddr..self.vaddr + self.mem_size).contains(&addr)
+    }
+
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.executable {
+            flags |= no_execute();
+        }
+        flags
+    }
+}
+
+/// 不可执行的页的标志. 没有启用NX时NO_EXECUTE是保留位, 返回空
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().nx_enabled() {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// 解析过的静态可执行文件, 段按地址排序
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    segments: Vec<Segment>,
+}
+
+impl<'a> Elf<'a> {
+    /// 解析并检查头部和程序头, 不访问用户内存
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let header = data.get(..HEADER_SIZE).ok_or(ElfError::Truncated)?;
+        if &header[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header[4] != CLASS_64 || header[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if u16_at(header, 18) != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+        if u16_at(header, 16) != TYPE_EXEC {
+            return Err(ElfError::NotStaticExecutable);
+        }
+        let entry = u64_at(header, 24);
+        let phoff = u64_at(header, 32);
+        let phentsize = usize::from(u16_at(header, 54));
+        let phnum = usize::from(u16_at(header, 56));
+        if phnum > 0 && phentsize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let table = usize::try_from(phoff)
+            .ok()
+            .and_then(|start| data.get(start..start.checked_add(phnum * PROGRAM_HEADER_SIZE)?))
+            .ok_or(ElfError::Truncated)?;
+
+        let mut segments = Vec::new();
+        for header in table.chunks_exact(PROGRAM_HEADER_SIZE) {
+            match u32_at(header, 0) {
+                PT_LOAD => {}
+                PT_DYNAMIC | PT_INTERP => return Err(ElfError::NotStaticExecutable),
+                _ => continue,
+            }
+            let flags = u32_at(header, 4);
+            let segment = Segment {
+                vaddr: u64_at(header, 16),
+                mem_size: u64_at(header, 40),
+                file_offset: u64_at(header, 8),
+                file_size: u64_at(header, 32),
+                writable: flags & PF_W != 0,
+                executable: flags & PF_X != 0,
+            };
+            if segment.file_size > segment.mem_size {
+                return Err(ElfError::BadProgramHeader);
+            }
+            let in_file = segment
+                .file_offset
+                .checked_add(segment.file_size)
+                .is_some_and(|end| end <= data.len() as u64);
+            if !in_file {
+                return Err(ElfError::Truncated);
+            }
+            if segment.mem_size == 0 {
+                continue;
+            }
+            if !LOAD_RANGE.contains_range(segment.vaddr, segment.mem_size) {
+                return Err(ElfError::SegmentOutOfRange);
+            }
+            segments.push(segment);
+        }
+
+        // 以页为单位映射, 相邻的段不能共用一页
+        segments.sort_unstable_by_key(|segment| segment.vaddr);
+        if segments
+            .windows(2)
+            .any(|pair| pair[1].first_page() < pair[0].end_page())
+        {
+            return Err(ElfError::OverlappingSegments);
+        }
+        if !segments
+            .iter()
+            .any(|segment| segment.executable && segment.contains(entry))
+        {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(Elf {
+            data,
+            entry,
+            segments,
+        })
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    fn segment_data(&self, segment: &Segment) -> &'a [u8] {
+        let start = segment.file_offset as usize;
+        &self.data[start..start + segment.file_size as usize]
+    }
+}
+
+/// 已加载的程序, 可以交给`usermode::enter`或`usermode::run`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Program {
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+    /// 从第一个段的第一页到最后一个段的最后一页
+    pub image: Range,
+}
+
+/// 把各段和用户栈映射到`space`的用户空间. 地址已被占用时返回`MapFailed(Overlap)`,
+/// 已映射的页不会撤销
+pub fn load(space: &mut AddressSpace, elf: &Elf) -> Result<Program, ElfError> {
+    for segment in elf.segments() {
+        load_segment(space, elf, segment)?;
+    }
+    let stack_bottom = VirtAddr::new(STACK_TOP - STACK_PAGES * 4096);
+    let flags = PageTableFlags::WRITABLE | no_execute();
+    space.map_user_pages(stack_bottom, STACK_PAGES, flags)?;
+    // 入口所在的段总是存在
+    let segments = elf.segments();
+    let image = Range::new(
+        segments[0].first_page(),
+        segments[segments.len() - 1].end_page(),
+    );
+    Ok(Program {
+        entry: elf.entry(),
+        stack_top: VirtAddr::new(STACK_TOP),
+        image,
+    })
+}
+
+// 新映射的页已清零, 只需复制文件中的部分, bss保持为0
+fn load_segment(space: &mut AddressSpace, elf: &Elf, segment: &Segment) -> Result<(), ElfError> {
+    let start = VirtAddr::new(segment.first_page());
+    let pages = (segment.end_page() - segment.first_page()) / 4096;
+    space.map_user_pages(start, pages, segment.page_flags())?;
+
+    // 段可能是只读的, 地址空间也不一定是当前的, 通过物理内存映射写入
+    let data = elf.segment_data(segment);
+    let mut copied = 0;
+    while copied < data.len() {
+        let addr = VirtAddr::new(segment.vaddr + copied as u64);
+        let len = (4096 - addr.as_u64() as usize % 4096).min(data.len() - copied);
+        let phys = space.translate(addr).expect("user page was just mapped");
+        let dst: *mut u8 = memory::phys_to_virt(phys).as_mut_ptr();
+        unsafe {
+            core::ptr::copy_nonoverlapping(data[copied..].as_ptr(), dst, len);
+        }
+        copied += len;
+    }
+    Ok(())
+}
+
+/// 从VFS读取`path`并加载到`space`
+pub async fn load_path(space: &mut AddressSpace, path: &str) -> Result<Program, ElfError> {
+    let mut file = vfs::open(path).await?;
+    let mut data = vec![0; file.metadata().len];
+    let mut read = 0;
+    while read < data.len() {
+        match file.read(&mut data[read..]).await? {
+            0 => break,
+            len => read += len,
+        }
+    }
+    data.truncate(read);
+    load(space, &Elf::parse(&data)?)
+}
+
+#[cfg(test)]
+const TEST_BASE: u64 = layout::USER.start + 0x40_0000;
+
+// 程序头中的(类型, 标志, 文件偏移, 地址, 文件大小, 内存大小)
+#[cfg(test)]
+type FixtureSegment = (u32, u32, u64, u64, u64, u64);
+
+// 构造只有程序头的ELF文件, 0x1000之后的0x100字节用作段内容
+#[cfg(test)]
+fn fixture(entry: u64, segments: &[FixtureSegment]) -> Vec<u8> {
+    let mut data = vec![0u8; HEADER_SIZE];
+    data[..4].copy_from_slice(MAGIC);
+    data[4] = CLASS_64;
+    data[5] = DATA_LITTLE_ENDIAN;
+    data[6] = 1;
+    data[16..18].copy_from_slice(&TYPE_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+    data[24..32].copy_from_slice(&entry.to_le_bytes());
+    data[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    data[52..54].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    data[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    data[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for &(kind, flags, offset, vaddr, file_size, mem_size) in segments {
+        let mut header = [0u8; PROGRAM_HEADER_SIZE];
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[4..8].copy_from_slice(&flags.to_le_bytes());
+        header[8..16].copy_from_slice(&offset.to_le_bytes());
+        header[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        header[24..32].copy_from_slice(&vaddr.to_le_bytes());
+        header[32..40].copy_from_slice(&file_size.to_le_bytes());
+        header[40..48].copy_from_slice(&mem_size.to_le_bytes());
+        header[48..56].copy_from_slice(&4096u64.to_le_bytes());
+        data.extend_from_slice(&header);
+    }
+    data.resize(0x1000 + 0x100, 0xcc);
+    data
+}
+
+#[test_case]
+fn test_parse_good_fixture() {
+    const PF_R: u32 = 4;
+    let data = fixture(
+        TEST_BASE + 0x10,
+        &[
+            // 数据段在代码段之后, 解析后按地址排序
+            (
+                PT_LOAD,
+                PF_R | PF_W,
+                0x1080,
+                TEST_BASE + 0x1000,
+                0x80,
+                0x3000,
+            ),
+            (PT_LOAD, PF_R | PF_X, 0x1000, TEST_BASE, 0x80, 0x80),
+            // 其他类型的程序头被忽略
+            (0x6474_e551, PF_R | PF_W, 0, 0, 0, 0),
+        ],
+    );
+    let elf = Elf::parse(&data).unwrap();
+    assert_eq!(elf.entry().as_u64(), TEST_BASE + 0x10);
+    let segments = elf.segments();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].vaddr, TEST_BASE);
+    assert!(segments[0].executable && !segments[0].writable);
+    assert_eq!(segments[1].mem_size, 0x3000);
+    assert!(segments[1].writable && !segments[1].executable);
+    assert_eq!(elf.segment_data(&segments[1]).len(), 0x80);
+}
+
+#[test_case]
+fn test_parse_malformed_fixtures() {
+    let code = (PT_LOAD, PF_X, 0x1000, TEST_BASE, 0x10, 0x10);
+    let good = fixture(TEST_BASE, &[code]);
+    assert!(Elf::parse(&good).is_ok());
+
+    let parse = |data: &[u8]| Elf::parse(data).err();
+    assert_eq!(parse(&good[..40]), Some(ElfError::Truncated));
+    // 程序头表被截断
+    assert_eq!(parse(&good[..HEADER_SIZE + 10]), Some(ElfError::Truncated));
+    // 段内容超出文件末尾
+    let past_end = (PT_LOAD, PF_X, 0x1000, TEST_BASE, 0x200, 0x200);
+    assert_eq!(
+        parse(&fixture(TEST_BASE, &[past_end])),
+        Some(ElfError::Truncated)
+    );
+
+    let mut bad = good.clone();
+    bad[0] = 0;
+    assert_eq!(parse(&bad), Some(ElfError::BadMagic));
+    let mut bad = good.clone();
+    bad[4] = 1;
+    assert_eq!(parse(&bad), Some(ElfError::UnsupportedFormat));
+    let mut bad = good.clone();
+    bad[18] = 0x28;
+    assert_eq!(parse(&bad), Some(ElfError::WrongMachine));
+    // ET_DYN
+    let mut bad = good.clone();
+    bad[16] = 3;
+    assert_eq!(parse(&bad), Some(ElfError::NotStaticExecutable));
+    let interp = (PT_INTERP, 0, 0x1000, 0, 0x10, 0x10);
+    assert_eq!(
+        parse(&fixture(TEST_BASE, &[code, interp])),
+        Some(ElfError::NotStaticExecutable)
+    );
+
+    let too_big = (PT_LOAD, PF_X, 0x1000, TEST_BASE, 0x20, 0x10);
+    assert_eq!(
+        parse(&fixture(TEST_BASE, &[too_big])),
+        Some(ElfError::BadProgramHeader)
+    );
+    for vaddr in [0x40_0000, layout::USER.start - 0x1000, LOAD_RANGE.end - 8, u64::MAX - 4] {
+        let outside = (PT_LOAD, PF_X, 0x1000, vaddr, 0x10, 0x10);
+        assert_eq!(
+            parse(&fixture(vaddr, &[outside])),
+            Some(ElfError::SegmentOutOfRange),
+            "{:#x}",
+            vaddr
+        );
+    }
+    // 第二个段与第一个段共用一页
+    let data = (PT_LOAD, PF_W, 0x1000, TEST_BASE + 0x800, 0x10, 0x10);
+    assert_eq!(
+        parse(&fixture(TEST_BASE, &[code, data])),
+        Some(ElfError::OverlappingSegments)
+    );
+    // 入口在不可执行的段中或不在任何段中
+    let data = (PT_LOAD, PF_W, 0x1000, TEST_BASE + 0x1000, 0x10, 0x10);
+    assert_eq!(
+        parse(&fixture(TEST_BASE + 0x1000, &[code, data])),
+        Some(ElfError::BadEntry)
+    );
+    assert_eq!(
+        parse(&fixture(TEST_BASE + 0x10, &[code])),
+        Some(ElfError::BadEntry)
+    );
+}
@@ -0,0 +1,418 @@
+//! 各子系统共用的错误类型
+//!
+//! `KernelError`只区分调用者需要分辨的几类错误, 子系统的具体错误作为上下文携带, 不需要堆.
+//! 系统调用返回`to_errno`的负值, 外壳和日志通过`Display`输出, 两者说明的是同一个错误
+
+use core::fmt;
+
+use crate::block::BlockError;
+use crate::fat::FatError;
+use crate::memory::MemError;
+use crate::process::{ForkError, MapError, RegionError};
+use crate::syscall::{SyscallError, UserAccessError};
+use crate::vfs::VfsError;
+
+// 错误码与Linux x86_64相同
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EBUSY: i64 = 16;
+pub const EEXIST: i64 = 17;
+pub const ENOTDIR: i64 = 20;
+pub const EISDIR: i64 = 21;
+pub const EINVAL: i64 = 22;
+pub const EMFILE: i64 = 24;
+pub const ENOSPC: i64 = 28;
+pub const EROFS: i64 = 30;
+pub const ENAMETOOLONG: i64 = 36;
+pub const ENOSYS: i64 = 38;
+pub const EOPNOTSUPP: i64 = 95;
+pub const ETIMEDOUT: i64 = 110;
+
+/// 产生错误的子系统给出的具体原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Context {
+    None,
+    Mem(MemError),
+    Block(BlockError),
+    Fat(FatError),
+    Vfs(VfsError),
+    UserAccess(UserAccessError),
+    Map(MapError),
+    Fork(ForkError),
+    Syscall(SyscallError),
+}
+
+impl Context {
+    // 比所在类别更具体的错误码
+    fn errno(&self) -> Option<i64> {
+        Some(match self {
+            Context::Syscall(SyscallError::BadFd) => EBADF,
+            Context::Syscall(SyscallError::NoSys) => ENOSYS,
+            Context::Fork(ForkError::TooManyChildren) => EAGAIN,
+            Context::UserAccess(UserAccessError::Unterminated) => ENAMETOOLONG,
+            Context::Vfs(VfsError::NotADirectory) | Context::Fat(FatError::NotADirectory) => {
+                ENOTDIR
+            }
+            Context::Vfs(VfsError::IsADirectory) | Context::Fat(FatError::IsADirectory) => EISDIR,
+            Context::Vfs(VfsError::AlreadyExists) | Context::Fat(FatError::AlreadyExists) => EEXIST,
+            Context::Vfs(VfsError::NoSpace) | Context::Fat(FatError::NoSpace) => ENOSPC,
+            Context::Vfs(VfsError::ReadOnly) | Context::Block(BlockError::ReadOnly) => EROFS,
+            _ => return None,
+        })
+    }
+}
+
+/// 内核接口返回的错误
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    NotFound(Context),
+    /// 包括写入只读的文件系统或设备
+    PermissionDenied(Context),
+    InvalidInput(Context),
+    /// 包括设备上没有空间
+    OutOfMemory(Context),
+    Busy(Context),
+    TimedOut(Context),
+    Io(Context),
+    Unsupported(Context),
+    /// 地址不可访问
+    Fault(Context),
+    /// 进程打开的文件达到上限
+    TooManyOpenFiles(Context),
+}
+
+// `from_errno`认识的错误码和对应的错误, 覆盖`to_errno`的所有结果
+const ERRNOS: [(i64, KernelError); 19] = [
+    (ENOENT, KernelError::NotFound(Context::None)),
+    (EIO, KernelError::Io(Context::None)),
+    (
+        EBADF,
+        KernelError::InvalidInput(Context::Syscall(SyscallError::BadFd)),
+    ),
+    (
+        EAGAIN,
+        KernelError::Busy(Context::Fork(ForkError::TooManyChildren)),
+    ),
+    (ENOMEM, KernelError::OutOfMemory(Context::None)),
+    (EACCES, KernelError::PermissionDenied(Context::None)),
+    (EFAULT, KernelError::Fault(Context::None)),
+    (EBUSY, KernelError::Busy(Context::None)),
+    (
+        EEXIST,
+        KernelError::InvalidInput(Context::Vfs(VfsError::AlreadyExists)),
+    ),
+    (
+        ENOTDIR,
+        KernelError::InvalidInput(Context::Vfs(VfsError::NotADirectory)),
+    ),
+    (
+        EISDIR,
+        KernelError::InvalidInput(Context::Vfs(VfsError::IsADirectory)),
+    ),
+    (EINVAL, KernelError::InvalidInput(Context::None)),
+    (EMFILE, KernelError::TooManyOpenFiles(Context::None)),
+    (
+        ENOSPC,
+        KernelError::OutOfMemory(Context::Vfs(VfsError::NoSpace)),
+    ),
+    (
+        EROFS,
+        KernelError::PermissionDenied(Context::Vfs(VfsError::ReadOnly)),
+    ),
+    (
+        ENAMETOOLONG,
+        KernelError::InvalidInput(Context::UserAccess(UserAccessError::Unterminated)),
+    ),
+    (
+        ENOSYS,
+        KernelError::Unsupported(Context::Syscall(SyscallError::NoSys)),
+    ),
+    (EOPNOTSUPP, KernelError::Unsupported(Context::None)),
+    (ETIMEDOUT, KernelError::TimedOut(Context::None)),
+];
+
+impl KernelError {
+    pub fn context(&self) -> Context {
+        match *self {
+            KernelError::NotFound(context)
+            | KernelError::PermissionDenied(context)
+            | KernelError::InvalidInput(context)
+            | KernelError::OutOfMemory(context)
+            | KernelError::Busy(context)
+            | KernelError::TimedOut(context)
+            | KernelError::Io(context)
+            | KernelError::Unsupported(context)
+            | KernelError::Fault(context)
+            | KernelError::TooManyOpenFiles(context) => context,
+        }
+    }
+
+    /// 系统调用边界使用的错误码, 为正数, 在rax中返回它的负值. 上下文能说明得更具体时使用更具体的错误码
+    pub fn to_errno(&self) -> i64 {
+        if let Some(errno) = self.context().errno() {
+            return errno;
+        }
+        match self {
+            KernelError::NotFound(_) => ENOENT,
+            KernelError::PermissionDenied(_) => EACCES,
+            KernelError::InvalidInput(_) => EINVAL,
+            KernelError::OutOfMemory(_) => ENOMEM,
+            KernelError::Busy(_) => EBUSY,
+            KernelError::TimedOut(_) => ETIMEDOUT,
+            KernelError::Io(_) => EIO,
+            KernelError::Unsupported(_) => EOPNOTSUPP,
+            KernelError::Fault(_) => EFAULT,
+            KernelError::TooManyOpenFiles(_) => EMFILE,
+        }
+    }
+
+    /// `to_errno`的逆映射, 不认识的错误码返回None. 只恢复类别和决定错误码的上下文
+    pub fn from_errno(errno: i64) -> Option<KernelError> {
+        ERRNOS
+            .iter()
+            .find(|(code, _)| *code == errno)
+            .map(|(_, err)| *err)
+    }
+}
+
+// 按错误码说明错误
+fn message(errno: i64) -> &'static str {
+    match errno {
+        ENOENT => "not found",
+        EIO => "i/o error",
+        EBADF => "bad file descriptor",
+        EAGAIN => "resource temporarily unavailable",
+        ENOMEM => "out of memory",
+        EACCES => "permission denied",
+        EFAULT => "bad address",
+        EBUSY => "busy",
+        EEXIST => "already exists",
+        ENOTDIR => "not a directory",
+        EISDIR => "is a directory",
+        EINVAL => "invalid argument",
+        EMFILE => "too many open files",
+        ENOSPC => "no space left on device",
+        EROFS => "read-only file system",
+        ENAMETOOLONG => "name too long",
+        ENOSYS => "no such system call",
+        EOPNOTSUPP => "not supported",
+        ETIMEDOUT => "timed out",
+        _ => "unknown error",
+    }
+}
+
+impl fmt::Display for KernelError {
+    /// 错误码的说明, 内存错误附带地址, 文件系统的内部错误附带原因
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let errno = self.to_errno();
+        f.write_str(message(errno))?;
+        match self.context() {
+            Context::Mem(err) => write!(f, ": {}", err),
+            Context::Fat(err) if errno == EIO => write!(f, ": {:?}", err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<MemError> for KernelError {
+    fn from(err: MemError) -> Self {
+        let context = Context::Mem(err);
+        match err {
+            MemError::NotMapped(_) => KernelError::Fault(context),
+            MemError::OutOfPhysicalMemory(_) => KernelError::OutOfMemory(context),
+            MemError::NonCanonical(_) | MemError::Misaligned { .. } | MemError::Overlap(_) => {
+                KernelError::InvalidInput(context)
+            }
+        }
+    }
+}
+
+impl From<BlockError> for KernelError {
+    fn from(err: BlockError) -> Self {
+        let context = Context::Block(err);
+        match err {
+            BlockError::BufferSize | BlockError::InvalidCount | BlockError::OutOfRange => {
+                KernelError::InvalidInput(context)
+            }
+            BlockError::ReadOnly => KernelError::PermissionDenied(context),
+            BlockError::Timeout => KernelError::TimedOut(context),
+            BlockError::Io => KernelError::Io(context),
+        }
+    }
+}
+
+impl From<FatError> for KernelError {
+    fn from(err: FatError) -> Self {
+        let context = Context::Fat(err);
+        match err {
+            FatError::Block(err) => err.into(),
+            FatError::NotFound => KernelError::NotFound(context),
+            FatError::NotADirectory
+            | FatError::IsADirectory
+            | FatError::InvalidPath
+            | FatError::AlreadyExists
+            | FatError::FileTooLarge => KernelError::InvalidInput(context),
+            FatError::NoSpace => KernelError::OutOfMemory(context),
+            FatError::UnsupportedSectorSize(_) | FatError::Fat12 => {
+                KernelError::Unsupported(context)
+            }
+            FatError::BadBootSector
+            | FatError::BadCluster(_)
+            | FatError::ChainLoop
+            | FatError::ChainTooShort => KernelError::Io(context),
+        }
+    }
+}
+
+impl From<VfsError> for KernelError {
+    fn from(err: VfsError) -> Self {
+        let context = Context::Vfs(err);
+        match err {
+            VfsError::NotFound => KernelError::NotFound(context),
+            VfsError::ReadOnly => KernelError::PermissionDenied(context),
+            VfsError::NotADirectory
+            | VfsError::IsADirectory
+            | VfsError::InvalidPath
+            | VfsError::InvalidSeek
+            | VfsError::AlreadyExists => KernelError::InvalidInput(context),
+            VfsError::AlreadyMounted => KernelError::Busy(context),
+            VfsError::NoSpace => KernelError::OutOfMemory(context),
+            VfsError::Io => KernelError::Io(context),
+        }
+    }
+}
+
+impl From<UserAccessError> for KernelError {
+    fn from(err: UserAccessError) -> Self {
+        let context = Context::UserAccess(err);
+        match err {
+            UserAccessError::TooLarge | UserAccessError::Unterminated => {
+                KernelError::InvalidInput(context)
+            }
+            UserAccessError::OutOfRange
+            | UserAccessError::Inaccessible(_)
+            | UserAccessError::Fault(_) => KernelError::Fault(context),
+        }
+    }
+}
+
+impl From<MapError> for KernelError {
+    fn from(err: MapError) -> Self {
+        let context = Context::Map(err);
+        match err {
+            MapError::NotAProcess => KernelError::Unsupported(context),
+            MapError::Region(RegionError::NoSpace) => KernelError::OutOfMemory(context),
+            MapError::BadProtection | MapError::Region(_) => KernelError::InvalidInput(context),
+        }
+    }
+}
+
+impl From<ForkError> for KernelError {
+    fn from(err: ForkError) -> Self {
+        let context = Context::Fork(err);
+        match err {
+            ForkError::NotAProcess => KernelError::Unsupported(context),
+            ForkError::TooManyChildren => KernelError::Busy(context),
+        }
+    }
+}
+
+impl From<SyscallError> for KernelError {
+    fn from(err: SyscallError) -> Self {
+        let context = Context::Syscall(err);
+        match err {
+            SyscallError::BadFd => KernelError::InvalidInput(context),
+            SyscallError::NoSys => KernelError::Unsupported(context),
+            SyscallError::NotAProcess => KernelError::Unsupported(context),
+        }
+    }
+}
+
+#[test_case]
+fn test_errno_round_trip() {
+    let categories = [
+        KernelError::NotFound(Context::None),
+        KernelError::PermissionDenied(Context::None),
+        KernelError::InvalidInput(Context::None),
+        KernelError::OutOfMemory(Context::None),
+        KernelError::Busy(Context::None),
+        KernelError::TimedOut(Context::None),
+        KernelError::Io(Context::None),
+        KernelError::Unsupported(Context::None),
+        KernelError::Fault(Context::None),
+        KernelError::TooManyOpenFiles(Context::None),
+    ];
+    for err in categories {
+        assert_eq!(KernelError::from_errno(err.to_errno()), Some(err));
+    }
+    // 每个认识的错误码都能映射回自己
+    for (errno, err) in ERRNOS {
+        assert_eq!(err.to_errno(), errno);
+        let back = KernelError::from_errno(errno).unwrap();
+        assert_eq!(back.to_errno(), errno);
+        assert_ne!(message(errno), "unknown error", "{}", errno);
+    }
+    assert_eq!(KernelError::from_errno(0), None);
+    assert_eq!(KernelError::from_errno(-ENOENT), None);
+}
+
+#[test_case]
+fn test_subsystem_errors() {
+    use alloc::string::ToString;
+
+    let missing = KernelError::from(VfsError::NotFound);
+    assert_eq!(
+        missing,
+        KernelError::NotFound(Context::Vfs(VfsError::NotFound))
+    );
+    assert_eq!(
+        (missing.to_errno(), missing.to_string().as_str()),
+        (ENOENT, "not found")
+    );
+    // 上下文决定更具体的错误码, 但不改变类别
+    let dir = KernelError::from(FatError::IsADirectory);
+    assert!(matches!(dir, KernelError::InvalidInput(_)));
+    assert_eq!(dir.to_errno(), EISDIR);
+    assert_eq!(dir.to_string(), "is a directory");
+    let read_only = KernelError::from(FatError::Block(BlockError::ReadOnly));
+    assert_eq!(
+        read_only,
+        KernelError::PermissionDenied(Context::Block(BlockError::ReadOnly))
+    );
+    assert_eq!(read_only.to_errno(), EROFS);
+    assert_eq!(KernelError::from(BlockError::Timeout).to_errno(), ETIMEDOUT);
+    // 块设备返回的错误经过FAT后不变
+    let device = KernelError::from(BlockError::Timeout);
+    assert_eq!(KernelError::from(FatError::from(device)), device);
+    assert_eq!(
+        KernelError::from(FatError::BadCluster(7)).to_string(),
+        "i/o error: BadCluster(7)"
+    );
+    assert_eq!(
+        KernelError::from(MemError::OutOfPhysicalMemory(None)).to_string(),
+        "out of memory: out of physical memory"
+    );
+    assert_eq!(
+        KernelError::from(UserAccessError::Unterminated).to_errno(),
+        ENAMETOOLONG
+    );
+    assert_eq!(
+        KernelError::from(MapError::NotAProcess).to_errno(),
+        EOPNOTSUPP
+    );
+    // 达到资源上限
+    assert_eq!(
+        KernelError::TooManyOpenFiles(Context::None).to_errno(),
+        EMFILE
+    );
+    assert_eq!(
+        KernelError::from(ForkError::TooManyChildren).to_errno(),
+        EAGAIN
+    );
+}
@@ -0,0 +1,935 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::block::{AsyncBlockDevice, BlockError, Cache, CacheStats, SECTOR_SIZE};
+use crate::error::{Context, KernelError};
+use crate::task::mutex::AsyncMutex;
+
+mod dir;
+mod name;
+mod write;
+
+pub use dir::DirEntry;
+use dir::{DirParser, Record};
+
+// 缓存的块数, 每块4KiB
+const CACHE_SLOTS: usize = 4;
+// 按簇数区分FAT类型, 这是规范中唯一的判断方法
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Block(BlockError),
+    /// 引导扇区的签名或BPB中的字段无效
+    BadBootSector,
+    /// 只支持512字节的扇区
+    UnsupportedSectorSize(u16),
+    /// 簇数太少, FAT12不受支持
+    Fat12,
+    /// 簇链中出现空闲簇、坏簇或超出范围的簇号
+    BadCluster(u32),
+    /// 簇链形成环
+    ChainLoop,
+    /// 簇链在文件末尾之前结束
+    ChainTooShort,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// 路径中包含"..", 或者要创建的文件名不合法
+    InvalidPath,
+    AlreadyExists,
+    /// 没有空闲的簇, 或者FAT16的根目录已满
+    NoSpace,
+    /// 文件超过4GiB
+    FileTooLarge,
+}
+
+impl From<BlockError> for FatError {
+    fn from(err: BlockError) -> Self {
+        FatError::Block(err)
+    }
+}
+
+// 块设备返回的错误, 上下文不是块设备的错误时记为`Io`
+impl From<KernelError> for FatError {
+    fn from(err: KernelError) -> Self {
+        match err.context() {
+            Context::Block(err) => FatError::Block(err),
+            Context::Fat(err) => err,
+            _ => FatError::Block(BlockError::Io),
+        }
+    }
+}
+
+// 从BPB换算出的各区域位置, 单位为扇区
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    fat_type: FatType,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_count: u64,
+    fat_sectors: u64,
+    // FAT32的FSInfo扇区
+    fsinfo: Option<u64>,
+    // FAT16固定根目录的位置, FAT32为空
+    root_start: u64,
+    data_start: u64,
+    cluster_count: u32,
+    // FAT32根目录的起始簇
+    root_cluster: u32,
+}
+
+impl Geometry {
+    fn parse(boot: &[u8; SECTOR_SIZE], device_sectors: u64) -> Result<Geometry, FatError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([boot[offset], boot[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                boot[offset],
+                boot[offset + 1],
+                boot[offset + 2],
+                boot[offset + 3],
+            ])
+        };
+        if boot[510..] != [0x55, 0xAA] {
+            return Err(FatError::BadBootSector);
+        }
+        let bytes_per_sector = u16_at(11);
+        if usize::from(bytes_per_sector) != SECTOR_SIZE {
+            return Err(FatError::UnsupportedSectorSize(bytes_per_sector));
+        }
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved = u64::from(u16_at(14));
+        let fat_count = u64::from(boot[16]);
+        let root_entries = u64::from(u16_at(17));
+        let total = match u16_at(19) {
+            0 => u64::from(u32_at(32)),
+            total => u64::from(total),
+        };
+        let fat_sectors = match u16_at(22) {
+            0 => u64::from(u32_at(36)),
+            sectors => u64::from(sectors),
+        };
+        if !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+            || total > device_sectors
+        {
+            return Err(FatError::BadBootSector);
+        }
+
+        let root_start = reserved + fat_count * fat_sectors;
+        let data_start = root_start + (root_entries * dir::ENTRY_SIZE as u64).div_ceil(512);
+        let cluster_count = total
+            .checked_sub(data_start)
+            .ok_or(FatError::BadBootSector)?
+            / sectors_per_cluster;
+        let cluster_count = cluster_count as u32;
+        let (fat_type, root_cluster, fsinfo) = if cluster_count < FAT16_MIN_CLUSTERS {
+            return Err(FatError::Fat12);
+        } else if cluster_count < FAT32_MIN_CLUSTERS {
+            (FatType::Fat16, 0, None)
+        } else {
+            let fsinfo = u64::from(u16_at(48));
+            let fsinfo = (fsinfo != 0 && fsinfo < reserved).then_some(fsinfo);
+            (FatType::Fat32, u32_at(44), fsinfo)
+        };
+        let geometry = Geometry {
+            fat_type,
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_count,
+            fat_sectors,
+            fsinfo,
+            root_start,
+            data_start,
+            cluster_count,
+            root_cluster,
+        };
+        // FAT要能容纳所有簇, FAT32没有固定根目录
+        let fat_entries = fat_sectors * SECTOR_SIZE as u64 / geometry.fat_entry_size();
+        let root_ok = match fat_type {
+            FatType::Fat16 => root_entries != 0,
+            FatType::Fat32 => root_entries == 0 && geometry.is_data_cluster(root_cluster),
+        };
+        if fat_entries < u64::from(cluster_count) + 2 || !root_ok {
+            return Err(FatError::BadBootSector);
+        }
+        Ok(geometry)
+    }
+
+    fn fat_entry_size(&self) -> u64 {
+        match self.fat_type {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    // 第一份FAT中`cluster`的项所在的扇区和扇区内的偏移
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = u64::from(cluster) * self.fat_entry_size();
+        (
+            self.fat_start + offset / SECTOR_SIZE as u64,
+            (offset % SECTOR_SIZE as u64) as usize,
+        )
+    }
+
+    // 数据区的簇从2开始编号
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+}
+
+/// 块设备上的FAT16/FAT32文件系统
+///
+/// 读写都是异步的, 等待设备时不占用执行器, 其他任务照常运行
+pub struct FatFs<'a> {
+    cache: Cache<'a>,
+    geometry: Geometry,
+    // 修改卷的操作持有这个锁, 依次进行. 持有期间会等待设备
+    writer: AsyncMutex<write::Writer>,
+}
+
+impl<'a> FatFs<'a> {
+    /// 读取引导扇区并按簇数确定FAT类型
+    pub async fn mount(device: &'a dyn AsyncBlockDevice) -> Result<FatFs<'a>, FatError> {
+        let cache = Cache::new(device, CACHE_SLOTS);
+        let mut boot = [0u8; SECTOR_SIZE];
+        cache.read_sectors(0, 1, &mut boot).await?;
+        let geometry = Geometry::parse(&boot, device.sector_count())?;
+        Ok(FatFs {
+            cache,
+            geometry,
+            writer: AsyncMutex::new(write::Writer::new()),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.geometry.fat_type
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// 打开文件或目录, 不区分大小写地匹配长文件名或短文件名;
+    /// 忽略开头的"/"和路径中的"./", 拒绝".."
+    pub async fn open(&self, path: &str) -> Result<File<'_>, FatError> {
+        Ok(File::new(self, self.lookup(path).await?))
+    }
+
+    async fn lookup(&self, path: &str) -> Result<DirEntry, FatError> {
+        let mut entry = DirEntry::root();
+        for part in path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            if part == ".." {
+                return Err(FatError::InvalidPath);
+            }
+            if !entry.is_dir {
+                return Err(FatError::NotADirectory);
+            }
+            entry = self
+                .read_dir(entry.cluster)
+                .await?
+                .into_iter()
+                .find(|child| child.matches(part))
+                .ok_or(FatError::NotFound)?;
+        }
+        Ok(entry)
+    }
+
+    // 经过缓存读取一个扇区
+    async fn with_sector<R>(
+        &self,
+        lba: u64,
+        f: impl FnOnce(&[u8; SECTOR_SIZE]) -> R,
+    ) -> Result<R, FatError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        self.cache.read_sectors(lba, 1, &mut sector).await?;
+        Ok(f(&sector))
+    }
+
+    // FAT中`cluster`的项, 0表示空闲
+    async fn fat_entry(&self, cluster: u32) -> Result<u32, FatError> {
+        let geometry = &self.geometry;
+        let (lba, i) = geometry.fat_position(cluster);
+        self.with_sector(lba, |sector| match geometry.fat_type {
+            FatType::Fat16 => u32::from(u16::from_le_bytes([sector[i], sector[i + 1]])),
+            // 高4位保留
+            FatType::Fat32 => {
+                u32::from_le_bytes([sector[i], sector[i + 1], sector[i + 2], sector[i + 3]])
+                    & 0x0FFF_FFFF
+            }
+        })
+        .await
+    }
+
+    // 查FAT得到`cluster`的下一个簇, 簇链结束时返回None
+    async fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let geometry = &self.geometry;
+        let value = self.fat_entry(cluster).await?;
+        let end_of_chain = match geometry.fat_type {
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        };
+        if value >= end_of_chain {
+            Ok(None)
+        } else if geometry.is_data_cluster(value) {
+            Ok(Some(value))
+        } else {
+            Err(FatError::BadCluster(value))
+        }
+    }
+
+    fn chain(&self, first: u32) -> Chain<'_, 'a> {
+        Chain {
+            fs: self,
+            next: (first != 0).then_some(first),
+            previous: None,
+            saved: 0,
+            power: 1,
+            steps: 0,
+        }
+    }
+
+    // 目录的各扇区, 簇号0表示根目录
+    fn dir_sectors(&self, cluster: u32) -> DirSectors<'_, 'a> {
+        let geometry = &self.geometry;
+        let first = match (cluster, geometry.fat_type) {
+            (0, FatType::Fat16) => {
+                return DirSectors {
+                    chain: None,
+                    sectors: geometry.root_start..geometry.data_start,
+                }
+            }
+            (0, FatType::Fat32) => geometry.root_cluster,
+            (cluster, _) => cluster,
+        };
+        DirSectors {
+            chain: Some(self.chain(first)),
+            sectors: 0..0,
+        }
+    }
+
+    // 读出目录的全部项, 簇号0表示根目录
+    async fn read_dir(&self, cluster: u32) -> Result<Vec<DirEntry>, FatError> {
+        let mut parser = DirParser::new(self.geometry.fat_type, cluster);
+        let mut entries = Vec::new();
+        let mut sectors = self.dir_sectors(cluster);
+        while let Some(lba) = sectors.next().await {
+            // 遇到结束标记时停止
+            let ended = self
+                .with_sector(lba?, |sector| {
+                    for raw in sector.chunks_exact(dir::ENTRY_SIZE) {
+                        match parser.feed(raw) {
+                            Record::End => return true,
+                            Record::Entry(entry) => entries.push(entry),
+                            Record::Skip => {}
+                        }
+                    }
+                    false
+                })
+                .await?;
+            if ended {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// 沿FAT遍历簇链, 用Brent算法检测环, 不需要额外的内存
+struct Chain<'f, 'a> {
+    fs: &'f FatFs<'a>,
+    next: Option<u32>,
+    // 下一簇在取下一项时才查FAT, 以免多读簇链末尾之后的项
+    previous: Option<u32>,
+    saved: u32,
+    power: u32,
+    steps: u32,
+}
+
+impl Chain<'_, '_> {
+    // 簇链中的下一簇, 结束时返回None
+    async fn next(&mut self) -> Option<Result<u32, FatError>> {
+        if let Some(previous) = self.previous.take() {
+            match self.fs.next_cluster(previous).await {
+                Ok(next) => self.next = next,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        let cluster = self.next.take()?;
+        if !self.fs.geometry.is_data_cluster(cluster) {
+            return Some(Err(FatError::BadCluster(cluster)));
+        }
+        if cluster == self.saved {
+            return Some(Err(FatError::ChainLoop));
+        }
+        self.steps += 1;
+        if self.steps == self.power {
+            self.saved = cluster;
+            self.power *= 2;
+            self.steps = 0;
+        }
+        self.previous = Some(cluster);
+        Some(Ok(cluster))
+    }
+
+    // 同`next`, 但簇链已经结束时返回`ChainTooShort`
+    async fn next_required(&mut self) -> Result<u32, FatError> {
+        self.next().await.unwrap_or(Err(FatError::ChainTooShort))
+    }
+}
+
+// 按顺序给出目录的各扇区. FAT16的根目录是固定的一段扇区, 其他目录沿簇链逐簇给出
+struct DirSectors<'f, 'a> {
+    chain: Option<Chain<'f, 'a>>,
+    // 当前簇中还没有给出的扇区
+    sectors: Range<u64>,
+}
+
+impl DirSectors<'_, '_> {
+    async fn next(&mut self) -> Option<Result<u64, FatError>> {
+        loop {
+            if let Some(lba) = self.sectors.next() {
+                return Some(Ok(lba));
+            }
+            let chain = self.chain.as_mut()?;
+            let fs = chain.fs;
+            let geometry = &fs.geometry;
+            match chain.next().await? {
+                Ok(cluster) => {
+                    let start = geometry.cluster_lba(cluster);
+                    self.sectors = start..start + geometry.sectors_per_cluster;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// 打开的文件或目录. 写入只更新这个句柄记录的长度, 其他句柄需要重新打开
+pub struct File<'a> {
+    fs: &'a FatFs<'a>,
+    entry: DirEntry,
+    // 当前的首簇和长度, 写入和截短时更新
+    extent: Mutex<(u32, usize)>,
+}
+
+impl Clone for File<'_> {
+    fn clone(&self) -> Self {
+        File {
+            fs: self.fs,
+            entry: self.entry.clone(),
+            extent: Mutex::new(*self.extent.lock()),
+        }
+    }
+}
+
+impl<'a> File<'a> {
+    fn new(fs: &'a FatFs<'a>, entry: DirEntry) -> File<'a> {
+        let extent = Mutex::new((entry.cluster, entry.len));
+        File { fs, entry, extent }
+    }
+
+    /// 长文件名, 根目录为空
+    pub fn name(&self) -> &str {
+        &self.entry.name
+    }
+
+    pub fn short_name(&self) -> &str {
+        &self.entry.short_name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.entry.is_dir
+    }
+
+    /// 文件的字节数, 目录为0
+    pub fn len(&self) -> usize {
+        self.extent.lock().1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 从`offset`开始读取到`buf`, 返回读取的字节数, 到达末尾或是目录时返回0
+    pub async fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FatError> {
+        let (first, file_len) = *self.extent.lock();
+        let Some(rest) = file_len.checked_sub(offset) else {
+            return Ok(0);
+        };
+        let len = rest.min(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        let geometry = &self.fs.geometry;
+        let cluster_bytes = geometry.cluster_bytes();
+        let mut chain = self.fs.chain(first);
+        for _ in 0..offset / cluster_bytes {
+            chain.next_required().await?;
+        }
+        let mut cluster = chain.next_required().await?;
+        let mut position = offset % cluster_bytes;
+        let mut done = 0;
+        let mut sector = [0u8; SECTOR_SIZE];
+        while done < len {
+            if position == cluster_bytes {
+                cluster = chain.next_required().await?;
+                position = 0;
+            }
+            let lba = geometry.cluster_lba(cluster) + (position / SECTOR_SIZE) as u64;
+            self.fs.cache.read_sectors(lba, 1, &mut sector).await?;
+            let start = position % SECTOR_SIZE;
+            let count = (SECTOR_SIZE - start).min(len - done);
+            buf[done..done + count].copy_from_slice(&sector[start..start + count]);
+            done += count;
+            position += count;
+        }
+        Ok(len)
+    }
+
+    /// 目录中的各项, 按在目录中出现的顺序, 不包括"."和".."; 文件没有目录项
+    pub async fn entries(&self) -> Result<Vec<DirEntry>, FatError> {
+        if !self.entry.is_dir {
+            return Ok(Vec::new());
+        }
+        self.fs.read_dir(self.entry.cluster).await
+    }
+}
+
+// build.rs生成的稀疏镜像, 用`patch`修改的扇区保存在内存中
+#[cfg(test)]
+struct FixtureDisk {
+    image: &'static [u8],
+    sectors: u64,
+    patched: spin::Mutex<Vec<(u64, [u8; SECTOR_SIZE])>>,
+}
+
+#[cfg(test)]
+impl FixtureDisk {
+    const RECORD_SIZE: usize = 4 + SECTOR_SIZE;
+
+    fn new(image: &'static [u8]) -> FixtureDisk {
+        assert_eq!(&image[..4], b"SPRS");
+        let sectors = u32::from_le_bytes(image[4..8].try_into().unwrap());
+        FixtureDisk {
+            image,
+            sectors: u64::from(sectors),
+            patched: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn fat16() -> FixtureDisk {
+        FixtureDisk::new(include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/fat16-fixture.bin"
+        )))
+    }
+
+    fn fat32() -> FixtureDisk {
+        FixtureDisk::new(include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/fat32-fixture.bin"
+        )))
+    }
+
+    // 按LBA排序保存的非零扇区
+    fn stored(&self, lba: u64) -> Option<&'static [u8]> {
+        let records = &self.image[12..];
+        let record = |i: usize| &records[i * Self::RECORD_SIZE..(i + 1) * Self::RECORD_SIZE];
+        let (mut low, mut high) = (0, records.len() / Self::RECORD_SIZE);
+        while low < high {
+            let mid = (low + high) / 2;
+            let found = u64::from(u32::from_le_bytes(record(mid)[..4].try_into().unwrap()));
+            match found.cmp(&lba) {
+                core::cmp::Ordering::Less => low = mid + 1,
+                core::cmp::Ordering::Greater => high = mid,
+                core::cmp::Ordering::Equal => return Some(&record(mid)[4..]),
+            }
+        }
+        None
+    }
+
+    fn read(&self, lba: u64) -> [u8; SECTOR_SIZE] {
+        let patched = self.patched.lock();
+        if let Some((_, data)) = patched.iter().find(|(patched, _)| *patched == lba) {
+            return *data;
+        }
+        let mut data = [0u8; SECTOR_SIZE];
+        if let Some(stored) = self.stored(lba) {
+            data.copy_from_slice(stored);
+        }
+        data
+    }
+
+    fn patch(&self, lba: u64, f: impl FnOnce(&mut [u8; SECTOR_SIZE])) {
+        let mut data = self.read(lba);
+        f(&mut data);
+        let mut patched = self.patched.lock();
+        patched.retain(|(patched, _)| *patched != lba);
+        patched.push((lba, data));
+    }
+}
+
+#[cfg(test)]
+use crate::block::{BlockDevice, BlockRequest, Inline, RequestHandle};
+
+#[cfg(test)]
+impl BlockDevice for FixtureDisk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, count: usize, buf: &mut [u8]) -> Result<(), KernelError> {
+        crate::block::check_request(self.sectors, lba, count, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            chunk.copy_from_slice(&self.read(lba + i as u64));
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, count: usize, buf: &[u8]) -> Result<(), KernelError> {
+        crate::block::check_request(self.sectors, lba, count, buf.len())?;
+        for (i, chunk) in buf.chunks(SECTOR_SIZE).enumerate() {
+            self.patch(lba + i as u64, |data| data.copy_from_slice(chunk));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl AsyncBlockDevice for FixtureDisk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn submit(&self, request: BlockRequest) -> RequestHandle {
+        Inline(self).submit(request)
+    }
+}
+
+// 只修改第一份FAT中`cluster`的项
+#[cfg(test)]
+async fn set_fat(disk: &FixtureDisk, cluster: u32, value: u32) {
+    let geometry = FatFs::mount(disk).await.unwrap().geometry;
+    let offset = u64::from(cluster) * geometry.fat_entry_size();
+    let i = (offset % SECTOR_SIZE as u64) as usize;
+    disk.patch(
+        geometry.fat_start + offset / SECTOR_SIZE as u64,
+        |sector| match geometry.fat_type {
+            FatType::Fat16 => sector[i..i + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+            FatType::Fat32 => sector[i..i + 4].copy_from_slice(&value.to_le_bytes()),
+        },
+    );
+}
+
+#[cfg(test)]
+const LONG_FILE: &str = "DOCS/A much longer name that needs several LFN entries.bin";
+
+#[cfg(test)]
+fn fixtures() -> [(FatType, FixtureDisk); 2] {
+    [
+        (FatType::Fat16, FixtureDisk::fat16()),
+        (FatType::Fat32, FixtureDisk::fat32()),
+    ]
+}
+
+#[test_case]
+fn test_mount() {
+    crate::task::complete_now(async {
+        for (fat_type, disk) in fixtures() {
+            assert_eq!(FatFs::mount(&disk).await.unwrap().fat_type(), fat_type);
+        }
+
+        let disk = FixtureDisk::fat16();
+        disk.patch(0, |boot| boot[510] = 0);
+        assert_eq!(
+            FatFs::mount(&disk).await.err(),
+            Some(FatError::BadBootSector)
+        );
+        let disk = FixtureDisk::fat16();
+        disk.patch(0, |boot| {
+            boot[11..13].copy_from_slice(&1024u16.to_le_bytes())
+        });
+        assert_eq!(
+            FatFs::mount(&disk).await.err(),
+            Some(FatError::UnsupportedSectorSize(1024))
+        );
+        // 缩小卷使簇数不足4085
+        let disk = FixtureDisk::fat16();
+        disk.patch(0, |boot| {
+            boot[19..21].copy_from_slice(&4000u16.to_le_bytes())
+        });
+        assert_eq!(FatFs::mount(&disk).await.err(), Some(FatError::Fat12));
+    });
+}
+
+#[test_case]
+fn test_read_files() {
+    crate::task::complete_now(async {
+        for (_, disk) in fixtures() {
+            let fs = FatFs::mount(&disk).await.unwrap();
+            let hello = fs.open("HELLO.TXT").await.unwrap();
+            let mut buf = [0u8; 64];
+            assert_eq!(hello.read_at(0, &mut buf).await, Ok(15));
+            assert_eq!(&buf[..15], b"hello from fat\n");
+
+            // 3000字节分布在6个不连续的簇中
+            let long = fs.open(LONG_FILE).await.unwrap();
+            assert_eq!(long.len(), 3000);
+            let expected = |i: usize| (i * 7 % 256) as u8;
+            let mut data = [0u8; 3100];
+            assert_eq!(long.read_at(0, &mut data).await, Ok(3000));
+            assert!(data[..3000]
+                .iter()
+                .enumerate()
+                .all(|(i, &b)| b == expected(i)));
+            let mut buf = [0u8; 100];
+            assert_eq!(long.read_at(500, &mut buf).await, Ok(100));
+            assert!(buf.iter().enumerate().all(|(i, &b)| b == expected(500 + i)));
+            assert_eq!(long.read_at(2990, &mut buf).await, Ok(10));
+            assert_eq!(buf[9], expected(2999));
+            assert_eq!(long.read_at(3000, &mut buf).await, Ok(0));
+            assert_eq!(long.read_at(5000, &mut buf).await, Ok(0));
+
+            let empty = fs.open("EMPTY.TXT").await.unwrap();
+            assert!(empty.is_empty());
+            assert_eq!(empty.read_at(0, &mut buf).await, Ok(0));
+            assert_eq!(
+                fs.open("DOCS").await.unwrap().read_at(0, &mut buf).await,
+                Ok(0)
+            );
+        }
+    });
+}
+
+#[test_case]
+fn test_list_directories() {
+    use alloc::format;
+
+    crate::task::complete_now(async {
+        for (_, disk) in fixtures() {
+            let fs = FatFs::mount(&disk).await.unwrap();
+            let root = fs.open("/").await.unwrap();
+            assert!(root.is_dir());
+            let entries = root.entries().await.unwrap();
+            let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(
+                names,
+                [
+                    "HELLO.TXT",
+                    "notes.txt",
+                    "Long File Name.txt",
+                    "EMPTY.TXT",
+                    "DOCS",
+                    "MANY",
+                    "README.TXT"
+                ]
+            );
+            let short: Vec<_> = entries
+                .iter()
+                .map(|entry| entry.short_name.as_str())
+                .collect();
+            assert_eq!(
+                short,
+                [
+                    "HELLO.TXT",
+                    "notes.txt",
+                    "LONGFI~1.TXT",
+                    "EMPTY.TXT",
+                    "DOCS",
+                    "MANY",
+                    "README.TXT"
+                ]
+            );
+            assert!(entries[4].is_dir && !entries[0].is_dir);
+            assert_eq!(entries[2].len, 26);
+
+            let docs = fs.open("DOCS").await.unwrap().entries().await.unwrap();
+            let names: Vec<_> = docs
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.short_name.as_str()))
+                .collect();
+            assert_eq!(
+                names,
+                [
+                    ("README.TXT", "README.TXT"),
+                    (
+                        "A much longer name that needs several LFN entries.bin",
+                        "AMUCHL~1.BIN"
+                    ),
+                    ("SUB", "SUB"),
+                ]
+            );
+
+            // 目录占用两个簇
+            let many = fs.open("MANY").await.unwrap().entries().await.unwrap();
+            assert_eq!(many.len(), 20);
+            for (i, entry) in many.iter().enumerate() {
+                assert_eq!(entry.name, format!("FILE{:02}.TXT", i));
+                assert_eq!(entry.len, format!("file {}\n", i).len());
+            }
+            assert!(fs
+                .open("HELLO.TXT")
+                .await
+                .unwrap()
+                .entries()
+                .await
+                .unwrap()
+                .is_empty());
+        }
+    });
+}
+
+#[test_case]
+fn test_path_lookup() {
+    crate::task::complete_now(async {
+        for (_, disk) in fixtures() {
+            let fs = FatFs::mount(&disk).await.unwrap();
+            for path in [
+                "DOCS/SUB/NOTE.TXT",
+                "/docs/sub/note.txt",
+                "./Docs/./Sub//Note.txt",
+            ] {
+                assert_eq!(
+                    fs.open(path).await.map(|file| file.len()),
+                    Ok(12),
+                    "{}",
+                    path
+                );
+            }
+            let long = fs.open("long file name.txt").await.unwrap();
+            assert_eq!(long.name(), "Long File Name.txt");
+            assert_eq!(
+                fs.open("LONGFI~1.TXT").await.unwrap().name(),
+                "Long File Name.txt"
+            );
+            assert_eq!(
+                fs.open("DOCS/../HELLO.TXT").await.err(),
+                Some(FatError::InvalidPath)
+            );
+            assert_eq!(fs.open("missing").await.err(), Some(FatError::NotFound));
+            assert_eq!(
+                fs.open("HELLO.TXT/more").await.err(),
+                Some(FatError::NotADirectory)
+            );
+        }
+    });
+}
+
+#[test_case]
+fn test_lfn_checksum_mismatch() {
+    crate::task::complete_now(async {
+        for (_, disk) in fixtures() {
+            let geometry = FatFs::mount(&disk).await.unwrap().geometry;
+            let root = match geometry.fat_type {
+                FatType::Fat16 => geometry.root_start,
+                FatType::Fat32 => geometry.cluster_lba(geometry.root_cluster),
+            };
+            // 根目录中只有"Long File Name.txt"有长文件名项
+            disk.patch(root, |sector| {
+                for raw in sector.chunks_exact_mut(dir::ENTRY_SIZE) {
+                    if raw[11] == 0x0F {
+                        raw[13] ^= 0xFF;
+                    }
+                }
+            });
+            let fs = FatFs::mount(&disk).await.unwrap();
+            let entries = fs.open("/").await.unwrap().entries().await.unwrap();
+            assert_eq!(entries.len(), 7);
+            assert_eq!(entries[2].name, "LONGFI~1.TXT");
+            assert_eq!(
+                fs.open("Long File Name.txt").await.err(),
+                Some(FatError::NotFound)
+            );
+            // 其他目录项不受影响
+            assert_eq!(entries[3].name, "EMPTY.TXT");
+        }
+    });
+}
+
+#[test_case]
+fn test_chain_errors() {
+    // 每次重新挂载, 用完即释放缓存
+    async fn read_long(disk: &FixtureDisk, len: usize) -> Result<usize, FatError> {
+        let mut buf = [0u8; 3000];
+        let fs = FatFs::mount(disk).await.unwrap();
+        let long = fs.open(LONG_FILE).await.unwrap();
+        long.read_at(0, &mut buf[..len]).await
+    }
+
+    crate::task::complete_now(async {
+        for (_, disk) in fixtures() {
+            let (long, many, cluster_count) = {
+                let fs = FatFs::mount(&disk).await.unwrap();
+                let long = fs.open(LONG_FILE).await.unwrap().entry.cluster;
+                let many = fs.open("MANY").await.unwrap().entry.cluster;
+                (long, many, fs.geometry.cluster_count)
+            };
+
+            // 第一个簇指向自己
+            set_fat(&disk, long, long).await;
+            assert_eq!(read_long(&disk, 3000).await, Err(FatError::ChainLoop));
+            // 超出范围的簇号和空闲簇
+            for value in [cluster_count + 10, 0] {
+                set_fat(&disk, long, value).await;
+                assert_eq!(
+                    read_long(&disk, 3000).await,
+                    Err(FatError::BadCluster(value))
+                );
+            }
+            // 第一个簇没有受影响
+            assert_eq!(read_long(&disk, 512).await, Ok(512));
+
+            set_fat(&disk, many, many).await;
+            let fs = FatFs::mount(&disk).await.unwrap();
+            assert_eq!(
+                fs.open("MANY").await.unwrap().entries().await.err(),
+                Some(FatError::ChainLoop)
+            );
+            assert_eq!(
+                fs.open("MANY/FILE19.TXT").await.err(),
+                Some(FatError::ChainLoop)
+            );
+        }
+    });
+}
+
+#[test_case]
+fn test_sector_cache() {
+    crate::task::complete_now(async {
+        for (_, disk) in fixtures() {
+            let fs = FatFs::mount(&disk).await.unwrap();
+            let first = fs.open("MANY").await.unwrap().entries().await.unwrap();
+            let stats = fs.cache_stats();
+            assert!(stats.misses > 0);
+            // 第二次遍历的FAT和目录扇区全部来自缓存
+            let second = fs.open("MANY").await.unwrap().entries().await.unwrap();
+            assert_eq!(first, second);
+            let new_stats = fs.cache_stats();
+            assert_eq!(new_stats.misses, stats.misses);
+            assert!(new_stats.hits > stats.hits);
+        }
+    });
+}
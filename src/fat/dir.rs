@@ -0,0 +1,362 @@
+use alloc::string::String;
+
+use super::FatType;
+
+pub const ENTRY_SIZE: usize = 32;
+
+/// 已删除的目录项的首字节
+pub const DELETED: u8 = 0xE5;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LFN: u8 = 0x0F;
+// 短文件名的主名和扩展名以小写显示(Windows NT的扩展)
+pub const CASE_LOWER_BASE: u8 = 0x08;
+pub const CASE_LOWER_EXT: u8 = 0x10;
+// 每个长文件名项保存13个UTF-16单元, 一个名字最多20项
+pub const LFN_UNITS: usize = 13;
+pub const LFN_MAX_ENTRIES: usize = 20;
+pub const LFN_OFFSETS: [usize; LFN_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// 目录中的一项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// 长文件名, 没有有效的长文件名时与`short_name`相同
+    pub name: String,
+    /// 按大小写标志显示的8.3短文件名
+    pub short_name: String,
+    pub is_dir: bool,
+    pub len: usize,
+    pub(super) cluster: u32,
+    /// 在父目录中的位置, 根目录为None
+    pub(super) location: Option<Location>,
+}
+
+/// 一个文件的各目录项在父目录中的序号, 每项32字节
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    /// 父目录的首簇, 0表示根目录
+    pub dir: u32,
+    /// 第一个长文件名项, 没有长文件名时与`short`相同
+    pub first: u32,
+    /// 短文件名项
+    pub short: u32,
+}
+
+impl DirEntry {
+    pub(super) fn root() -> DirEntry {
+        DirEntry {
+            name: String::new(),
+            short_name: String::new(),
+            is_dir: true,
+            len: 0,
+            cluster: 0,
+            location: None,
+        }
+    }
+
+    /// 长文件名或短文件名与`name`相同, 不区分大小写
+    pub(super) fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short_name.eq_ignore_ascii_case(name)
+    }
+}
+
+pub enum Record {
+    /// 目录到此结束
+    End,
+    Entry(DirEntry),
+    /// 长文件名项、已删除的项、卷标以及"."和".."
+    Skip,
+}
+
+// 正在组合的长文件名, 各项按序号从大到小出现
+struct LongName {
+    units: [u16; LFN_MAX_ENTRIES * LFN_UNITS],
+    count: usize,
+    // 下一项应有的序号, 为0时已经收齐
+    expected: usize,
+    checksum: u8,
+    // 第一项在目录中的序号
+    start: u32,
+    active: bool,
+}
+
+impl LongName {
+    fn reset(&mut self) {
+        self.active = false;
+    }
+
+    fn push(&mut self, raw: &[u8], index: u32) {
+        let ordinal = raw[0];
+        let seq = usize::from(ordinal & 0x1F);
+        if ordinal & 0x40 != 0 {
+            if seq == 0 || seq > LFN_MAX_ENTRIES {
+                self.reset();
+                return;
+            }
+            self.active = true;
+            self.start = index;
+            self.count = seq;
+            self.expected = seq;
+            self.checksum = raw[13];
+        } else if !self.active || seq == 0 || seq != self.expected || raw[13] != self.checksum {
+            self.reset();
+            return;
+        }
+        let units = &mut self.units[(seq - 1) * LFN_UNITS..seq * LFN_UNITS];
+        for (unit, &offset) in units.iter_mut().zip(LFN_OFFSETS.iter()) {
+            *unit = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        }
+        self.expected = seq - 1;
+    }
+
+    // 取出属于短文件名`short`的长文件名, 序号不连续或校验和不符时返回None
+    fn take(&mut self, short: &[u8]) -> Option<String> {
+        let complete = self.active && self.expected == 0 && self.checksum == checksum(short);
+        self.reset();
+        if !complete {
+            return None;
+        }
+        let units = self.units[..self.count * LFN_UNITS]
+            .iter()
+            .copied()
+            .take_while(|&unit| unit != 0);
+        let name: String = char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        (!name.is_empty()).then_some(name)
+    }
+}
+
+/// 逐项解析目录内容, 把长文件名项和其后的短文件名项组合成一个`DirEntry`
+pub struct DirParser {
+    fat_type: FatType,
+    // 目录的首簇和下一项的序号
+    dir: u32,
+    index: u32,
+    long_name: LongName,
+}
+
+impl DirParser {
+    /// 解析首簇为`dir`的目录, 0表示根目录
+    pub fn new(fat_type: FatType, dir: u32) -> DirParser {
+        DirParser {
+            fat_type,
+            dir,
+            index: 0,
+            long_name: LongName {
+                units: [0; LFN_MAX_ENTRIES * LFN_UNITS],
+                count: 0,
+                expected: 0,
+                checksum: 0,
+                start: 0,
+                active: false,
+            },
+        }
+    }
+
+    /// 按顺序解析32字节的目录项
+    pub fn feed(&mut self, raw: &[u8]) -> Record {
+        let index = self.index;
+        self.index += 1;
+        match raw[0] {
+            0x00 => return Record::End,
+            DELETED => {
+                self.long_name.reset();
+                return Record::Skip;
+            }
+            _ => {}
+        }
+        let attr = raw[11];
+        if attr & 0x3F == ATTR_LFN {
+            self.long_name.push(raw, index);
+            return Record::Skip;
+        }
+        let short = &raw[..11];
+        let start = self.long_name.start;
+        let long_name = self.long_name.take(short);
+        let first = if long_name.is_some() { start } else { index };
+        if attr & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+            return Record::Skip;
+        }
+
+        let short_name = short_name(short, raw[12]);
+        let is_dir = attr & ATTR_DIRECTORY != 0;
+        let (cluster, len) = extent(raw, self.fat_type);
+        Record::Entry(DirEntry {
+            name: long_name.unwrap_or_else(|| short_name.clone()),
+            short_name,
+            is_dir,
+            len: if is_dir { 0 } else { len },
+            cluster,
+            location: Some(Location {
+                dir: self.dir,
+                first,
+                short: index,
+            }),
+        })
+    }
+}
+
+/// 短文件名项中的首簇和文件长度
+pub fn extent(raw: &[u8], fat_type: FatType) -> (u32, usize) {
+    let low = u32::from(u16::from_le_bytes([raw[26], raw[27]]));
+    // FAT16的高16位另有用途
+    let high = match fat_type {
+        FatType::Fat16 => 0,
+        FatType::Fat32 => u32::from(u16::from_le_bytes([raw[20], raw[21]])),
+    };
+    let len = u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]);
+    (high << 16 | low, len as usize)
+}
+
+/// 修改短文件名项中的首簇、文件长度和修改时间
+pub fn set_extent(raw: &mut [u8], cluster: u32, len: u32, now: Timestamp) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[22..24].copy_from_slice(&now.time.to_le_bytes());
+    raw[24..26].copy_from_slice(&now.date.to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    raw[28..32].copy_from_slice(&len.to_le_bytes());
+    // 访问日期
+    raw[18..20].copy_from_slice(&now.date.to_le_bytes());
+}
+
+/// 新建的空文件的短文件名项, 创建、访问和修改时间都是`now`
+pub fn new_entry(short: &[u8; 11], case: u8, now: Timestamp) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[..11].copy_from_slice(short);
+    raw[11] = ATTR_ARCHIVE;
+    raw[12] = case;
+    raw[14..16].copy_from_slice(&now.time.to_le_bytes());
+    raw[16..18].copy_from_slice(&now.date.to_le_bytes());
+    set_extent(&mut raw, 0, 0, now);
+    raw
+}
+
+/// 目录项中的日期和时间, 时间的精度为2秒
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    pub date: u16,
+    pub time: u16,
+}
+
+impl Timestamp {
+    /// 从1970-01-01 00:00 UTC起的秒数换算, 超出1980到2107年的时间取最近的一端
+    pub fn from_unix(seconds: u64) -> Timestamp {
+        let days = (seconds / 86400) as i64;
+        let secs = seconds % 86400;
+        // 从1970-01-01起的天数换算为公历日期
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        if year < 1980 {
+            return Timestamp {
+                date: 1 << 5 | 1,
+                time: 0,
+            };
+        }
+        if year > 2107 {
+            return Timestamp {
+                date: 127 << 9 | 12 << 5 | 31,
+                time: 23 << 11 | 59 << 5 | 29,
+            };
+        }
+        Timestamp {
+            date: ((year - 1980) << 9 | month << 5 | day) as u16,
+            time: ((secs / 3600) << 11 | (secs % 3600 / 60) << 5 | (secs % 60 / 2)) as u16,
+        }
+    }
+}
+
+/// 短文件名的校验和, 保存在属于它的各长文件名项中
+pub fn checksum(short: &[u8]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// "NAME    EXT"显示为"NAME.EXT"
+pub fn short_name(short: &[u8], case: u8) -> String {
+    // 0x05表示首字节实际是0xE5
+    let mut base = [0u8; 8];
+    base.copy_from_slice(&short[..8]);
+    if base[0] == 0x05 {
+        base[0] = 0xE5;
+    }
+    let mut name: String = name_part(&base, case & CASE_LOWER_BASE != 0).collect();
+    let mut ext = name_part(&short[8..11], case & CASE_LOWER_EXT != 0).peekable();
+    if ext.peek().is_some() {
+        name.push('.');
+        name.extend(ext);
+    }
+    name
+}
+
+// 去掉末尾的空格, 非ASCII字符显示为替换字符
+fn name_part(bytes: &[u8], lower: bool) -> impl Iterator<Item = char> + '_ {
+    let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    bytes[..len].iter().map(move |&b| match b {
+        b if !b.is_ascii() => char::REPLACEMENT_CHARACTER,
+        b if lower => char::from(b.to_ascii_lowercase()),
+        b => char::from(b),
+    })
+}
+
+#[test_case]
+fn test_short_name() {
+    assert_eq!(short_name(b"HELLO   TXT", 0), "HELLO.TXT");
+    assert_eq!(short_name(b"NOTES   TXT", CASE_LOWER_BASE), "notes.TXT");
+    assert_eq!(
+        short_name(b"NOTES   TXT", CASE_LOWER_BASE | CASE_LOWER_EXT),
+        "notes.txt"
+    );
+    assert_eq!(short_name(b"DOCS       ", 0), "DOCS");
+    assert_eq!(short_name(b"\x05BC     TXT", 0), "\u{FFFD}BC.TXT");
+    assert_eq!(checksum(b"LONGFI~1TXT"), {
+        let mut sum = 0u8;
+        for &b in b"LONGFI~1TXT" {
+            sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b);
+        }
+        sum
+    });
+}
+
+#[test_case]
+fn test_timestamp() {
+    // 2024-02-29 13:45:07 UTC
+    let stamp = Timestamp::from_unix(1_709_214_307);
+    assert_eq!(stamp.date, (2024 - 1980) << 9 | 2 << 5 | 29);
+    assert_eq!(stamp.time, 13 << 11 | 45 << 5 | 3);
+    assert_eq!(Timestamp::from_unix(0).date, 1 << 5 | 1);
+    assert_eq!(Timestamp::from_unix(u64::MAX / 2).date >> 9, 127);
+
+    let now = Timestamp::from_unix(1_709_214_307);
+    let mut raw = new_entry(b"BOOT    LOG", CASE_LOWER_BASE, now);
+    assert_eq!(raw[11], ATTR_ARCHIVE);
+    assert_eq!(extent(&raw, FatType::Fat32), (0, 0));
+    set_extent(&mut raw, 0x12_3456, 1000, now);
+    assert_eq!(extent(&raw, FatType::Fat32), (0x12_3456, 1000));
+    assert_eq!(extent(&raw, FatType::Fat16), (0x3456, 1000));
+    assert_eq!(&raw[16..18], &raw[24..26]);
+    let mut parser = DirParser::new(FatType::Fat32, 5);
+    let Record::Entry(entry) = parser.feed(&raw) else {
+        panic!("entry not parsed");
+    };
+    assert_eq!(entry.short_name, "boot.LOG");
+    assert_eq!(
+        entry.location,
+        Some(Location {
+            dir: 5,
+            first: 0,
+            short: 0
+        })
+    );
+}
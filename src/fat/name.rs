@@ -0,0 +1,175 @@
+//! 为新文件生成8.3短文件名和长文件名项, 规则与build/fat.rs生成测试镜像时相同
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use super::dir::{self, ATTR_LFN, CASE_LOWER_BASE, CASE_LOWER_EXT, ENTRY_SIZE, LFN_UNITS};
+
+// 长文件名最多255个UTF-16单元
+const MAX_NAME_UNITS: usize = 255;
+// 数字后缀"~n"的最大值
+const MAX_TAIL: u32 = 999_999;
+
+/// 是否可以作为文件名: 不为空, 不是"."或"..", 不含控制字符和`"*/:<>?\|`, 不以点或空格结尾,
+/// 不超过255个UTF-16单元
+pub fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && !name.ends_with(['.', ' '])
+        && name.encode_utf16().count() <= MAX_NAME_UNITS
+        && !name
+            .chars()
+            .any(|c| c.is_control() || "\"*/:<>?\\|".contains(c))
+}
+
+/// 返回11字节的短文件名、大小写标志以及是否需要长文件名项. 名字本身符合8.3格式且每部分大小写一致时
+/// 直接使用, 否则生成"主名前几个字符~n"形式的短文件名, `used`判断是否已被同一目录中的文件使用.
+/// 后缀用完时返回None
+pub fn short_name(name: &str, used: impl Fn(&[u8; 11]) -> bool) -> Option<([u8; 11], u8, bool)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let valid = |part: &str, max: usize| {
+        !part.is_empty()
+            && part.len() <= max
+            && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    };
+    let fits = valid(base, 8) && (ext.is_empty() || valid(ext, 3));
+    // Some(true)表示全部小写
+    let uniform = |part: &str| {
+        if part.bytes().all(|b| !b.is_ascii_lowercase()) {
+            Some(false)
+        } else if part.bytes().all(|b| !b.is_ascii_uppercase()) {
+            Some(true)
+        } else {
+            None
+        }
+    };
+    let mut short = [b' '; 11];
+    if fits {
+        if let (Some(lower_base), Some(lower_ext)) = (uniform(base), uniform(ext)) {
+            short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+            short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+            let case = if lower_base { CASE_LOWER_BASE } else { 0 }
+                | if lower_ext { CASE_LOWER_EXT } else { 0 };
+            return Some((short, case, false));
+        }
+    }
+
+    let clean = |part: &str| -> Vec<u8> {
+        part.bytes()
+            .filter(|b| b.is_ascii_alphanumeric())
+            .map(|b| b.to_ascii_uppercase())
+            .collect()
+    };
+    let base = clean(base);
+    let ext = clean(ext);
+    let ext_len = ext.len().min(3);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    (1..=MAX_TAIL).find_map(|n| {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut candidate = short;
+        candidate[..keep].copy_from_slice(&base[..keep]);
+        candidate[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        (!used(&candidate)).then_some((candidate, 0, true))
+    })
+}
+
+/// 按目录中的顺序返回`name`的长文件名项, 序号最大的在前
+pub fn long_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_UNITS);
+    // 未占满最后一项时以0结尾, 其余填充0xFFFF
+    if units.len() < count * LFN_UNITS {
+        units.push(0);
+    }
+    units.resize(count * LFN_UNITS, 0xFFFF);
+    let checksum = dir::checksum(short);
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut raw = [0u8; ENTRY_SIZE];
+            raw[0] = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+            raw[11] = ATTR_LFN;
+            raw[13] = checksum;
+            let chunk = &units[i * LFN_UNITS..(i + 1) * LFN_UNITS];
+            for (&offset, unit) in dir::LFN_OFFSETS.iter().zip(chunk) {
+                raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            raw
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_valid_names() {
+    for name in ["boot.log", "Long File Name.txt", "a", ".hidden", "名字.txt"] {
+        assert!(is_valid(name), "{}", name);
+    }
+    for name in ["", ".", "..", "a/b", "what?", "tab\t", "dot.", "space "] {
+        assert!(!is_valid(name), "{:?}", name);
+    }
+    let long = "x".repeat(256);
+    assert!(!is_valid(&long));
+    assert!(is_valid(&long[..255]));
+}
+
+#[test_case]
+fn test_short_names() {
+    let none = |_: &[u8; 11]| false;
+    assert_eq!(
+        short_name("HELLO.TXT", none),
+        Some((*b"HELLO   TXT", 0, false))
+    );
+    assert_eq!(
+        short_name("boot.log", none),
+        Some((*b"BOOT    LOG", CASE_LOWER_BASE | CASE_LOWER_EXT, false))
+    );
+    assert_eq!(
+        short_name("Mixed.txt", none),
+        Some((*b"MIXED~1 TXT", 0, true))
+    );
+    assert_eq!(
+        short_name("Long File Name.txt", none),
+        Some((*b"LONGFI~1TXT", 0, true))
+    );
+    assert_eq!(
+        short_name("a.b.c.jpeg", none),
+        Some((*b"ABC~1   JPE", 0, true))
+    );
+    // 与已有的短文件名冲突时增加后缀
+    let used = |short: &[u8; 11]| short == b"LONGFI~1TXT" || short == b"LONGFI~2TXT";
+    assert_eq!(
+        short_name("Long File Names.txt", used),
+        Some((*b"LONGFI~3TXT", 0, true))
+    );
+    let used = |short: &[u8; 11]| &short[6..8] != b"10";
+    assert_eq!(
+        short_name("Long File Name.txt", used),
+        Some((*b"LONGF~10TXT", 0, true))
+    );
+}
+
+#[test_case]
+fn test_long_entries() {
+    // 与build/fat.rs生成的测试镜像中的项相同
+    let name = "A much longer name that needs several LFN entries.bin";
+    let short = *b"AMUCHL~1BIN";
+    let entries = long_entries(name, &short);
+    assert_eq!(entries.len(), 5);
+    assert_eq!(entries[0][0], 0x45);
+    assert_eq!(entries[4][0], 0x01);
+    let mut parser = dir::DirParser::new(super::FatType::Fat16, 0);
+    for raw in &entries {
+        assert!(matches!(parser.feed(raw), dir::Record::Skip));
+    }
+    let short_entry = dir::new_entry(&short, 0, dir::Timestamp::from_unix(0));
+    let dir::Record::Entry(entry) = parser.feed(&short_entry) else {
+        panic!("entry not parsed");
+    };
+    assert_eq!(entry.name, name);
+    assert_eq!(entry.short_name, "AMUCHL~1.BIN");
+    assert_eq!(entry.location.map(|location| location.first), Some(0));
+    assert_eq!(entry.location.map(|location| location.short), Some(5));
+}
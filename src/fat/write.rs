@@ -0,0 +1,672 @@
+//! 修改FAT卷: 分配和释放簇、创建和删除目录项、写入文件内容
+//!
+//! 加长文件时按"数据、FAT、目录项"的顺序写回设备, 每一步之后刷新缓存. 中途断电最多留下没有被目录项
+//! 引用的簇, 或者没有短文件名项的长文件名项, fsck可以回收, 不会出现交叉链接.
+//! 删除和截短的顺序相反, 先修改目录项再释放簇
+
+use alloc::vec::Vec;
+
+use super::dir::{self, Location, Timestamp, ATTR_LFN, DELETED, ENTRY_SIZE};
+use super::{name, DirEntry, FatError, FatFs, FatType, File};
+use crate::block::SECTOR_SIZE;
+use crate::{time, version};
+
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+// FSInfo扇区的签名
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+
+/// 写入操作之间保留的状态
+pub struct Writer {
+    // 从这里开始查找空闲簇
+    next_free: u32,
+    // FAT32的FSInfo中的空闲簇数是否已标记为未知
+    fsinfo_cleared: bool,
+}
+
+impl Writer {
+    pub const fn new() -> Writer {
+        Writer {
+            next_free: 2,
+            fsinfo_cleared: false,
+        }
+    }
+}
+
+// 没有实时时钟, 以构建时间加上运行时间作为当前时间
+fn now() -> Timestamp {
+    Timestamp::from_unix(version::build_epoch() + time::uptime().ms / 1000)
+}
+
+// 把路径分成父目录和最后一项, 忽略末尾的"/"
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+impl FatFs<'_> {
+    /// 在已有的目录中创建空文件. 名字不合法时返回`InvalidPath`,
+    /// 与目录中已有项的长文件名或短文件名相同(不区分大小写)时返回`AlreadyExists`
+    pub async fn create(&self, path: &str) -> Result<File<'_>, FatError> {
+        let (parent, file_name) = split_path(path);
+        if !name::is_valid(file_name) {
+            return Err(FatError::InvalidPath);
+        }
+        let parent = self.lookup(parent).await?;
+        if !parent.is_dir {
+            return Err(FatError::NotADirectory);
+        }
+        let mut writer = self.writer.lock().await;
+        // 持有锁时列出目录, 其他任务不会同时创建同名的文件
+        let siblings = self.read_dir(parent.cluster).await?;
+        if siblings.iter().any(|sibling| sibling.matches(file_name)) {
+            return Err(FatError::AlreadyExists);
+        }
+        let (short, case, lfn) = name::short_name(file_name, |candidate| {
+            let candidate = dir::short_name(candidate, 0);
+            siblings
+                .iter()
+                .any(|sibling| sibling.short_name.eq_ignore_ascii_case(&candidate))
+        })
+        .ok_or(FatError::NoSpace)?;
+        let mut entries = if lfn {
+            name::long_entries(file_name, &short)
+        } else {
+            Vec::new()
+        };
+        entries.push(dir::new_entry(&short, case, now()));
+
+        let count = entries.len() as u32;
+        let first = self
+            .reserve_slots(&mut writer, parent.cluster, count)
+            .await?;
+        // 短文件名项最后写入
+        for (index, raw) in (first..).zip(&entries) {
+            self.update_dir_entry(parent.cluster, index, |slot| slot.copy_from_slice(raw))
+                .await?;
+        }
+        self.sync().await?;
+        let entry = DirEntry {
+            name: file_name.into(),
+            short_name: dir::short_name(&short, case),
+            is_dir: false,
+            len: 0,
+            cluster: 0,
+            location: Some(Location {
+                dir: parent.cluster,
+                first,
+                short: first + count - 1,
+            }),
+        };
+        Ok(File::new(self, entry))
+    }
+
+    /// 删除文件: 先把它的各目录项标记为已删除, 再释放簇链. 不能删除目录
+    pub async fn remove(&self, path: &str) -> Result<(), FatError> {
+        let mut writer = self.writer.lock().await;
+        let entry = self.lookup(path).await?;
+        let location = match entry.location {
+            Some(location) if !entry.is_dir => location,
+            _ => return Err(FatError::IsADirectory),
+        };
+        // 簇链损坏时不修改卷
+        let clusters = self.clusters(entry.cluster).await?;
+        for index in location.first..=location.short {
+            self.update_dir_entry(location.dir, index, |raw| raw[0] = DELETED)
+                .await?;
+        }
+        self.sync().await?;
+        self.free(&mut writer, &clusters).await?;
+        self.sync().await
+    }
+
+    // 读出扇区, 用`f`修改后写回缓存
+    async fn update_sector(
+        &self,
+        lba: u64,
+        f: impl FnOnce(&mut [u8; SECTOR_SIZE]),
+    ) -> Result<(), FatError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        self.cache.read_sectors(lba, 1, &mut sector).await?;
+        f(&mut sector);
+        self.cache.write_sectors(lba, 1, &sector).await?;
+        Ok(())
+    }
+
+    // 把缓存中的修改写回设备, 作为各步骤之间的顺序点
+    async fn sync(&self) -> Result<(), FatError> {
+        Ok(self.cache.flush().await?)
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.geometry.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    // 修改每一份FAT中`cluster`的项
+    async fn set_fat(&self, writer: &mut Writer, cluster: u32, value: u32) -> Result<(), FatError> {
+        let geometry = &self.geometry;
+        let (lba, i) = geometry.fat_position(cluster);
+        for copy in 0..geometry.fat_count {
+            self.update_sector(lba + copy * geometry.fat_sectors, |sector| {
+                match geometry.fat_type {
+                    FatType::Fat16 => {
+                        sector[i..i + 2].copy_from_slice(&(value as u16).to_le_bytes())
+                    }
+                    // 保留高4位
+                    FatType::Fat32 => {
+                        let old = u32::from_le_bytes(sector[i..i + 4].try_into().unwrap());
+                        let value = old & 0xF000_0000 | value & 0x0FFF_FFFF;
+                        sector[i..i + 4].copy_from_slice(&value.to_le_bytes());
+                    }
+                }
+            })
+            .await?;
+        }
+        self.clear_fsinfo(writer).await
+    }
+
+    // FSInfo中的空闲簇数和下一个空闲簇只是提示, 第一次修改FAT时标记为未知, 之后不需要更新
+    async fn clear_fsinfo(&self, writer: &mut Writer) -> Result<(), FatError> {
+        let Some(lba) = self.geometry.fsinfo.filter(|_| !writer.fsinfo_cleared) else {
+            return Ok(());
+        };
+        self.update_sector(lba, |sector| {
+            if sector[..4] == FSINFO_LEAD.to_le_bytes()
+                && sector[484..488] == FSINFO_STRUCT.to_le_bytes()
+            {
+                sector[488..496].fill(0xFF);
+            }
+        })
+        .await?;
+        writer.fsinfo_cleared = true;
+        Ok(())
+    }
+
+    // 找出`count`个空闲簇. 只查找不标记, 写入FAT之前持有写锁, 不会被其他操作占用
+    async fn find_free(&self, writer: &mut Writer, count: usize) -> Result<Vec<u32>, FatError> {
+        let total = self.geometry.cluster_count;
+        let start = writer.next_free - 2;
+        let mut found = Vec::with_capacity(count);
+        for i in 0..total {
+            if found.len() == count {
+                break;
+            }
+            let cluster = 2 + (start + i) % total;
+            if self.fat_entry(cluster).await? == 0 {
+                found.push(cluster);
+            }
+        }
+        if found.len() < count {
+            return Err(FatError::NoSpace);
+        }
+        if let Some(&last) = found.last() {
+            writer.next_free = 2 + (last - 1) % total;
+        }
+        Ok(found)
+    }
+
+    // 把`clusters`连成链并接在`last`之后. 先写新簇的项再接上, 中途断电只留下不属于任何文件的簇
+    async fn link(
+        &self,
+        writer: &mut Writer,
+        last: Option<u32>,
+        clusters: &[u32],
+    ) -> Result<(), FatError> {
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let next = clusters.get(i + 1).copied();
+            self.set_fat(writer, cluster, next.unwrap_or(self.end_of_chain()))
+                .await?;
+        }
+        match (last, clusters.first()) {
+            (Some(last), Some(&first)) => self.set_fat(writer, last, first).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn free(&self, writer: &mut Writer, clusters: &[u32]) -> Result<(), FatError> {
+        for &cluster in clusters {
+            self.set_fat(writer, cluster, 0).await?;
+        }
+        Ok(())
+    }
+
+    // 簇链中的全部簇, 首簇为0时为空
+    async fn clusters(&self, first: u32) -> Result<Vec<u32>, FatError> {
+        let mut chain = self.chain(first);
+        let mut clusters = Vec::new();
+        while let Some(cluster) = chain.next().await {
+            clusters.push(cluster?);
+        }
+        Ok(clusters)
+    }
+
+    // 目录中第`index`项所在的扇区和扇区内的偏移, 超出目录末尾时返回None
+    async fn dir_slot(&self, dir: u32, index: u32) -> Result<Option<(u64, usize)>, FatError> {
+        let mut sectors = self.dir_sectors(dir);
+        let mut skip = index / ENTRIES_PER_SECTOR;
+        while let Some(lba) = sectors.next().await {
+            let lba = lba?;
+            if skip == 0 {
+                let offset = (index % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE;
+                return Ok(Some((lba, offset)));
+            }
+            skip -= 1;
+        }
+        Ok(None)
+    }
+
+    // 用`f`修改目录中的第`index`项
+    async fn update_dir_entry(
+        &self,
+        dir: u32,
+        index: u32,
+        f: impl FnOnce(&mut [u8]),
+    ) -> Result<(), FatError> {
+        let (lba, offset) = self
+            .dir_slot(dir, index)
+            .await?
+            .ok_or(FatError::ChainTooShort)?;
+        self.update_sector(lba, |sector| f(&mut sector[offset..offset + ENTRY_SIZE]))
+            .await
+    }
+
+    // 从短文件名项读出文件当前的首簇和长度, 文件已被删除时返回`NotFound`
+    async fn read_extent(&self, location: Location) -> Result<(u32, usize), FatError> {
+        let (lba, offset) = self
+            .dir_slot(location.dir, location.short)
+            .await?
+            .ok_or(FatError::NotFound)?;
+        let fat_type = self.geometry.fat_type;
+        self.with_sector(lba, |sector| {
+            let raw = &sector[offset..offset + ENTRY_SIZE];
+            let live = raw[0] != 0 && raw[0] != DELETED && raw[11] & 0x3F != ATTR_LFN;
+            live.then(|| dir::extent(raw, fat_type))
+        })
+        .await?
+        .ok_or(FatError::NotFound)
+    }
+
+    // 在目录中找到连续`count`个空闲项, 返回第一项的序号. 结束标记之后的项都是空闲的.
+    // 不够时为簇链目录加上一个清零的簇, FAT16的根目录不能加长
+    async fn reserve_slots(
+        &self,
+        writer: &mut Writer,
+        dir: u32,
+        count: u32,
+    ) -> Result<u32, FatError> {
+        let (mut index, mut run, mut ended) = (0, 0, false);
+        let mut sectors = self.dir_sectors(dir);
+        while let Some(lba) = sectors.next().await {
+            let found = self
+                .with_sector(lba?, |sector| {
+                    for raw in sector.chunks_exact(ENTRY_SIZE) {
+                        index += 1;
+                        ended |= raw[0] == 0;
+                        run = if ended || raw[0] == DELETED {
+                            run + 1
+                        } else {
+                            0
+                        };
+                        if run == count {
+                            return true;
+                        }
+                    }
+                    false
+                })
+                .await?;
+            if found {
+                return Ok(index - count);
+            }
+        }
+        let first = match (dir, self.geometry.fat_type) {
+            (0, FatType::Fat16) => return Err(FatError::NoSpace),
+            (0, FatType::Fat32) => self.geometry.root_cluster,
+            (dir, _) => dir,
+        };
+        let last = self.clusters(first).await?.last().copied();
+        let per_cluster = self.geometry.sectors_per_cluster as u32 * ENTRIES_PER_SECTOR;
+        let added = self
+            .find_free(writer, (count - run).div_ceil(per_cluster) as usize)
+            .await?;
+        for &cluster in &added {
+            let start = self.geometry.cluster_lba(cluster);
+            for lba in start..start + self.geometry.sectors_per_cluster {
+                self.cache.write_sectors(lba, 1, &[0; SECTOR_SIZE]).await?;
+            }
+        }
+        self.sync().await?;
+        self.link(writer, last, &added).await?;
+        self.sync().await?;
+        // 原来末尾的空闲项和新的簇相连
+        Ok(index - run)
+    }
+
+    // 写入文件中从`position`开始的`len`个字节, `fill(i, buf)`填入其中第`i`个字节开始的部分
+    async fn write_data(
+        &self,
+        clusters: &[u32],
+        position: usize,
+        len: usize,
+        fill: impl Fn(usize, &mut [u8]),
+    ) -> Result<(), FatError> {
+        let geometry = &self.geometry;
+        let cluster_bytes = geometry.cluster_bytes();
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let at = position + done;
+            let lba = geometry.cluster_lba(clusters[at / cluster_bytes])
+                + (at % cluster_bytes / SECTOR_SIZE) as u64;
+            let start = at % SECTOR_SIZE;
+            let count = (SECTOR_SIZE - start).min(len - done);
+            // 覆盖整个扇区时不需要先读
+            if count < SECTOR_SIZE {
+                self.cache.read_sectors(lba, 1, &mut sector).await?;
+            }
+            fill(done, &mut sector[start..start + count]);
+            self.cache.write_sectors(lba, 1, &sector).await?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl File<'_> {
+    /// 从`offset`开始写入`buf`并返回写入的字节数. 需要时加长文件, `offset`在文件末尾之后时中间填0
+    pub async fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FatError> {
+        let location = self.location()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset
+            .checked_add(buf.len())
+            .filter(|&end| end <= u32::MAX as usize)
+            .ok_or(FatError::FileTooLarge)?;
+        let fs = self.fs;
+        let mut writer = fs.writer.lock().await;
+        // 以目录项为准, 其他句柄可能已经写入
+        let (first, len) = fs.read_extent(location).await?;
+        let mut clusters = fs.clusters(first).await?;
+        let cluster_bytes = fs.geometry.cluster_bytes();
+        if clusters.len() < len.div_ceil(cluster_bytes) {
+            return Err(FatError::ChainTooShort);
+        }
+        let old = clusters.len();
+        let needed = end.div_ceil(cluster_bytes).saturating_sub(old);
+        let added = fs.find_free(&mut writer, needed).await?;
+        clusters.extend_from_slice(&added);
+
+        if offset > len {
+            fs.write_data(&clusters, len, offset - len, |_, dst| dst.fill(0))
+                .await?;
+        }
+        fs.write_data(&clusters, offset, buf.len(), |i, dst| {
+            dst.copy_from_slice(&buf[i..i + dst.len()])
+        })
+        .await?;
+        fs.sync().await?;
+        fs.link(&mut writer, clusters[..old].last().copied(), &added)
+            .await?;
+        fs.sync().await?;
+        let extent = (clusters[0], len.max(end));
+        fs.update_dir_entry(location.dir, location.short, |raw| {
+            dir::set_extent(raw, extent.0, extent.1 as u32, now())
+        })
+        .await?;
+        fs.sync().await?;
+        *self.extent.lock() = extent;
+        Ok(buf.len())
+    }
+
+    /// 把文件截短到`len`字节并释放不再需要的簇, `len`不小于当前长度时不变
+    pub async fn truncate(&self, len: usize) -> Result<(), FatError> {
+        let location = self.location()?;
+        let fs = self.fs;
+        let mut writer = fs.writer.lock().await;
+        let (first, old_len) = fs.read_extent(location).await?;
+        if len >= old_len {
+            *self.extent.lock() = (first, old_len);
+            return Ok(());
+        }
+        let clusters = fs.clusters(first).await?;
+        let keep = len
+            .div_ceil(fs.geometry.cluster_bytes())
+            .min(clusters.len());
+        let extent = (if keep == 0 { 0 } else { first }, len);
+        // 中途断电时簇链只是比文件长
+        fs.update_dir_entry(location.dir, location.short, |raw| {
+            dir::set_extent(raw, extent.0, len as u32, now())
+        })
+        .await?;
+        fs.sync().await?;
+        if let Some(&last) = clusters[..keep].last() {
+            fs.set_fat(&mut writer, last, fs.end_of_chain()).await?;
+        }
+        fs.free(&mut writer, &clusters[keep..]).await?;
+        fs.sync().await?;
+        *self.extent.lock() = extent;
+        Ok(())
+    }
+
+    // 文件在父目录中的位置, 目录不能写入
+    fn location(&self) -> Result<Location, FatError> {
+        match self.entry.location {
+            Some(location) if !self.entry.is_dir => Ok(location),
+            _ => Err(FatError::IsADirectory),
+        }
+    }
+}
+
+// 两份FAT中`cluster`的项
+#[cfg(test)]
+async fn fat_copies(fs: &FatFs<'_>, cluster: u32) -> [u32; 2] {
+    let geometry = &fs.geometry;
+    let (lba, i) = geometry.fat_position(cluster);
+    let mut copies = [0; 2];
+    for (copy, value) in copies.iter_mut().enumerate() {
+        let lba = lba + copy as u64 * geometry.fat_sectors;
+        *value = fs
+            .with_sector(lba, |sector| match geometry.fat_type {
+                FatType::Fat16 => u32::from(u16::from_le_bytes([sector[i], sector[i + 1]])),
+                FatType::Fat32 => u32::from_le_bytes(sector[i..i + 4].try_into().unwrap()),
+            })
+            .await
+            .unwrap();
+    }
+    copies
+}
+
+// 目录中第`index`项的原始内容
+#[cfg(test)]
+async fn raw_entry(fs: &FatFs<'_>, dir: u32, index: u32) -> [u8; ENTRY_SIZE] {
+    let (lba, offset) = fs.dir_slot(dir, index).await.unwrap().unwrap();
+    fs.with_sector(lba, |sector| {
+        sector[offset..offset + ENTRY_SIZE].try_into().unwrap()
+    })
+    .await
+    .unwrap()
+}
+
+#[test_case]
+fn test_create_and_append() {
+    crate::task::complete_now(async {
+        for (fat_type, disk) in super::fixtures() {
+            let data: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+            {
+                let fs = FatFs::mount(&disk).await.unwrap();
+                let log = fs.create("boot.log").await.unwrap();
+                assert_eq!(log.short_name(), "boot.log");
+                assert_eq!(log.write_at(0, b"boot 1\n").await, Ok(7));
+                assert_eq!(log.write_at(7, &data).await, Ok(1500));
+                assert_eq!(log.len(), 1507);
+            }
+
+            // 重新挂载后读到的是设备上的内容
+            let fs = FatFs::mount(&disk).await.unwrap();
+            let log = fs.open("/BOOT.LOG").await.unwrap();
+            let mut buf = [0u8; 1600];
+            assert_eq!(log.read_at(0, &mut buf).await, Ok(1507));
+            assert_eq!(&buf[..7], b"boot 1\n");
+            assert_eq!(&buf[7..1507], &data[..]);
+            let raw = raw_entry(&fs, 0, log.entry.location.unwrap().short).await;
+            assert_eq!(&raw[..11], b"BOOT    LOG");
+            assert_eq!(raw[11], dir::ATTR_ARCHIVE);
+            assert_eq!(dir::extent(&raw, fat_type), (log.entry.cluster, 1507));
+            // 每簇一个扇区, 两份FAT相同
+            let clusters = fs.clusters(log.entry.cluster).await.unwrap();
+            assert_eq!(clusters.len(), 3);
+            for (i, &cluster) in clusters.iter().enumerate() {
+                let next = clusters.get(i + 1).copied();
+                assert_eq!(
+                    fat_copies(&fs, cluster).await,
+                    [next.unwrap_or(fs.end_of_chain()); 2]
+                );
+            }
+
+            // 在末尾之后写入时中间填0
+            assert_eq!(log.write_at(2000, b"end").await, Ok(3));
+            assert_eq!(log.read_at(1507, &mut buf[..600]).await, Ok(496));
+            assert!(buf[..493].iter().all(|&b| b == 0));
+            assert_eq!(&buf[493..496], b"end");
+        }
+    });
+}
+
+#[test_case]
+fn test_create_names() {
+    crate::task::complete_now(async {
+        for (_, disk) in super::fixtures() {
+            let location = {
+                let fs = FatFs::mount(&disk).await.unwrap();
+                // "Long File Name.txt"已经使用了LONGFI~1
+                let file = fs.create("Long File Names.txt").await.unwrap();
+                assert_eq!(file.short_name(), "LONGFI~2.TXT");
+                for (path, err) in [
+                    ("LONG FILE NAMES.TXT", FatError::AlreadyExists),
+                    ("hello.txt", FatError::AlreadyExists),
+                    ("what?", FatError::InvalidPath),
+                    ("DOCS/../new.txt", FatError::InvalidPath),
+                    ("missing/new.txt", FatError::NotFound),
+                    ("HELLO.TXT/new.txt", FatError::NotADirectory),
+                ] {
+                    assert_eq!(fs.create(path).await.err(), Some(err), "{}", path);
+                }
+                assert_eq!(
+                    fs.open("DOCS").await.unwrap().write_at(0, b"x").await,
+                    Err(FatError::IsADirectory)
+                );
+                file.entry.location.unwrap()
+            };
+
+            // 两个长文件名项在短文件名项之前
+            assert_eq!(location.short - location.first, 2);
+            let fs = FatFs::mount(&disk).await.unwrap();
+            let entries = fs.open("/").await.unwrap().entries().await.unwrap();
+            let last = entries.last().unwrap();
+            assert_eq!(
+                (last.name.as_str(), last.short_name.as_str()),
+                ("Long File Names.txt", "LONGFI~2.TXT")
+            );
+            let raw = raw_entry(&fs, 0, location.first).await;
+            assert_eq!(raw[0], 0x42);
+            assert_eq!(raw[13], dir::checksum(b"LONGFI~2TXT"));
+        }
+    });
+}
+
+#[test_case]
+fn test_remove_frees_chain() {
+    crate::task::complete_now(async {
+        for (_, disk) in super::fixtures() {
+            let (location, clusters) = {
+                let fs = FatFs::mount(&disk).await.unwrap();
+                let long = fs.open(super::LONG_FILE).await.unwrap();
+                let clusters = fs.clusters(long.entry.cluster).await.unwrap();
+                assert_eq!(clusters.len(), 6);
+                assert_eq!(fs.remove("DOCS").await, Err(FatError::IsADirectory));
+                fs.remove(super::LONG_FILE).await.unwrap();
+                assert_eq!(
+                    fs.open(super::LONG_FILE).await.err(),
+                    Some(FatError::NotFound)
+                );
+                // 已打开的句柄不能再写入
+                assert_eq!(long.write_at(0, b"x").await, Err(FatError::NotFound));
+                (long.entry.location.unwrap(), clusters)
+            };
+
+            let fs = FatFs::mount(&disk).await.unwrap();
+            for index in location.first..=location.short {
+                assert_eq!(raw_entry(&fs, location.dir, index).await[0], DELETED);
+            }
+            for &cluster in &clusters {
+                assert_eq!(fat_copies(&fs, cluster).await, [0, 0]);
+            }
+            assert_eq!(
+                fs.open("DOCS/SUB/NOTE.TXT").await.map(|file| file.len()),
+                Ok(12)
+            );
+            // 空出的目录项被重新使用
+            let new = fs.create("DOCS/new.txt").await.unwrap();
+            assert_eq!(new.entry.location.unwrap().first, location.first);
+            assert_eq!(
+                fs.open("DOCS")
+                    .await
+                    .unwrap()
+                    .entries()
+                    .await
+                    .unwrap()
+                    .len(),
+                3
+            );
+        }
+    });
+}
+
+#[test_case]
+fn test_grow_directory_and_truncate() {
+    use alloc::format;
+
+    crate::task::complete_now(async {
+        for (fat_type, disk) in super::fixtures() {
+            let many = {
+                let fs = FatFs::mount(&disk).await.unwrap();
+                let many = fs.open("MANY").await.unwrap().entry.cluster;
+                assert_eq!(fs.clusters(many).await.unwrap().len(), 2);
+                // 两个簇共32项, 已有"."、".."和20个文件
+                for i in 20..31 {
+                    fs.create(&format!("MANY/FILE{}.TXT", i)).await.unwrap();
+                }
+                many
+            };
+            let fs = FatFs::mount(&disk).await.unwrap();
+            assert_eq!(fs.clusters(many).await.unwrap().len(), 3);
+            let entries = fs.open("MANY").await.unwrap().entries().await.unwrap();
+            assert_eq!(entries.len(), 31);
+            assert_eq!(entries[30].name, "FILE30.TXT");
+
+            let file = fs.open("MANY/FILE00.TXT").await.unwrap();
+            assert_eq!(file.write_at(0, &[0x5A; 1200]).await, Ok(1200));
+            let first = fs.open("MANY/FILE00.TXT").await.unwrap().entry.cluster;
+            let clusters = fs.clusters(first).await.unwrap();
+            assert_eq!(clusters.len(), 3);
+            file.truncate(100).await.unwrap();
+            assert_eq!(file.len(), 100);
+            assert_eq!(fat_copies(&fs, clusters[0]).await, [fs.end_of_chain(); 2]);
+            assert_eq!(fat_copies(&fs, clusters[1]).await, [0, 0]);
+            assert_eq!(fat_copies(&fs, clusters[2]).await, [0, 0]);
+            let mut buf = [0u8; 200];
+            assert_eq!(file.read_at(0, &mut buf).await, Ok(100));
+            assert!(buf[..100].iter().all(|&b| b == 0x5A));
+
+            file.truncate(0).await.unwrap();
+            assert_eq!(fat_copies(&fs, clusters[0]).await, [0, 0]);
+            let location = file.entry.location.unwrap();
+            let raw = raw_entry(&fs, location.dir, location.short).await;
+            assert_eq!(dir::extent(&raw, fat_type), (0, 0));
+            assert_eq!(file.write_at(0, b"again").await, Ok(5));
+        }
+    });
+}
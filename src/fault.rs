@@ -0,0 +1,178 @@
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::percpu;
+
+global_asm!(include_str!("fault/recovery.s"), options(att_syntax));
+
+extern "C" {
+    // 在`slot`中记录落点和栈指针后调用`f(data)`. 正常返回0, 异常后从落点返回1
+    fn recovery_run(slot: *mut RecoverySlot, f: extern "C" fn(*mut u8), data: *mut u8) -> u64;
+}
+
+pub const DIVIDE_ERROR_VECTOR: u8 = 0;
+pub const INVALID_OPCODE_VECTOR: u8 = 6;
+pub const GENERAL_PROTECTION_VECTOR: u8 = 13;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+
+/// `try_with_recovery`中发生并被恢复的异常
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultInfo {
+    pub vector: u8,
+    pub error_code: u64,
+    /// 页错误时访问的地址, 其他异常为0
+    pub cr2: VirtAddr,
+}
+
+// 一层恢复区域, 位于`try_with_recovery`的栈上. recovery.s按偏移访问前两个字段
+#[repr(C)]
+struct RecoverySlot {
+    rsp: u64,
+    rip: u64,
+    // 外层的区域, 形成每个处理器一个的栈
+    prev: *mut RecoverySlot,
+    // 处理函数返回到落点时置位, 之后`fault`才有意义
+    faulted: bool,
+    fault: FaultInfo,
+}
+
+// 启动早期还没有每处理器数据时使用, 那时只有BSP在运行
+static EARLY_SLOT: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+// 当前处理器最内层的恢复区域
+fn innermost() -> &'static AtomicPtr<()> {
+    match percpu::try_get() {
+        Some(percpu) => percpu.recovery_slot(),
+        None => &EARLY_SLOT,
+    }
+}
+
+/// 运行`f`, 其中的一般保护异常和页错误不再致命: 异常处理函数返回到这里, 得到异常的信息
+///
+/// 可以嵌套, 异常由最内层处理. `f`在关中断时运行. 发生异常时`f`余下的部分不再执行,
+/// 它持有的值不会被drop、锁不会被释放, 因此`f`只应做简单的探测
+pub fn try_with_recovery<F: FnOnce() -> T, T>(f: F) -> Result<T, FaultInfo> {
+    struct Call<F, T> {
+        f: Option<F>,
+        result: Option<T>,
+    }
+
+    extern "C" fn call<F: FnOnce() -> T, T>(data: *mut u8) {
+        let closure = unsafe { &mut *data.cast::<Call<F, T>>() };
+        if let Some(f) = closure.f.take() {
+            closure.result = Some(f());
+        }
+    }
+
+    interrupts::without_interrupts(|| {
+        let head = innermost();
+        let mut slot = RecoverySlot {
+            rsp: 0,
+            rip: 0,
+            prev: head.load(Ordering::Relaxed).cast(),
+            faulted: false,
+            fault: FaultInfo {
+                vector: 0,
+                error_code: 0,
+                cr2: VirtAddr::zero(),
+            },
+        };
+        let mut closure = Call {
+            f: Some(f),
+            result: None,
+        };
+        head.store(ptr::addr_of_mut!(slot).cast(), Ordering::Relaxed);
+        unsafe {
+            recovery_run(&mut slot, call::<F, T>, ptr::addr_of_mut!(closure).cast());
+        }
+        // 发生异常时处理函数已经恢复了外层区域
+        head.store(slot.prev.cast(), Ordering::Relaxed);
+        if slot.faulted {
+            return Err(slot.fault);
+        }
+        Ok(closure
+            .result
+            .expect("recovery region returned without a result"))
+    })
+}
+
+/// 读取`addr`处的值, 未映射或非规范地址返回异常信息而不是panic
+///
+/// # Safety
+///
+/// 调用者需保证读取没有副作用, 比如不是设备寄存器
+pub unsafe fn try_read<T: Copy>(addr: VirtAddr) -> Result<T, FaultInfo> {
+    try_with_recovery(|| unsafe { addr.as_ptr::<T>().read_volatile() })
+}
+
+/// 由内核态的一般保护异常和页错误处理函数调用. 有恢复区域时记录异常并让处理函数返回到它的落点
+pub(crate) fn recover(
+    stack_frame: &mut InterruptStackFrame,
+    vector: u8,
+    error_code: u64,
+    cr2: Option<VirtAddr>,
+) -> bool {
+    let head = innermost();
+    let slot = head.load(Ordering::Relaxed).cast::<RecoverySlot>();
+    if slot.is_null() {
+        return false;
+    }
+    let slot = unsafe { &mut *slot };
+    slot.faulted = true;
+    slot.fault = FaultInfo {
+        vector,
+        error_code,
+        cr2: cr2.unwrap_or(VirtAddr::zero()),
+    };
+    head.store(slot.prev.cast(), Ordering::Relaxed);
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(slot.rip);
+            frame.stack_pointer = VirtAddr::new(slot.rsp);
+        });
+    }
+    true
+}
+
+#[test_case]
+fn test_recover_from_faults() {
+    static VALUE: u64 = 0x1234_5678;
+
+    // 内核栈保护页
+    let guard = crate::memory::alloc_stack(1).unwrap() - 2 * 4096u64;
+    let fault = unsafe { try_read::<u8>(guard) }.unwrap_err();
+    assert_eq!(fault.vector, PAGE_FAULT_VECTOR);
+    assert_eq!(fault.cr2, guard);
+    // 不存在的页, 内核态读取
+    assert_eq!(fault.error_code, 0);
+
+    assert_eq!(
+        unsafe { try_read::<u64>(VirtAddr::from_ptr(&VALUE)) },
+        Ok(0x1234_5678)
+    );
+    // 非规范地址产生一般保护异常
+    let fault = try_with_recovery(|| unsafe { (0x8000_0000_0000 as *const u8).read_volatile() })
+        .unwrap_err();
+    assert_eq!(fault.vector, GENERAL_PROTECTION_VECTOR);
+    assert_eq!(fault.cr2, VirtAddr::zero());
+}
+
+#[test_case]
+fn test_nested_recovery() {
+    let guard = crate::memory::alloc_stack(1).unwrap() - 2 * 4096u64;
+    let result = try_with_recovery(|| {
+        // 内层区域处理自己的异常, 外层继续执行
+        let inner = unsafe { try_read::<u8>(guard) };
+        assert!(inner.is_err());
+        let again = unsafe { try_read::<u8>(guard + 1u64) };
+        (inner.is_err(), again.map_err(|fault| fault.cr2))
+    });
+    assert_eq!(result, Ok((true, Err(guard + 1u64))));
+    assert!(innermost().load(Ordering::Relaxed).is_null());
+}
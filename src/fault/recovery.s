@@ -0,0 +1,35 @@
+# try_with_recovery的入口和异常后的落点
+
+.pushsection .text
+# rdi = RecoverySlot, rsi = 要调用的函数, rdx = 它的参数. 正常返回0, 从落点返回1
+.global recovery_run
+recovery_run:
+    push %rbp
+    mov %rsp, %rbp
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    # 6次push之后rsp模16余8, 调用前对齐到16字节
+    sub $8, %rsp
+    mov %rsp, 0(%rdi)
+    lea recovery_landing(%rip), %rax
+    mov %rax, 8(%rdi)
+    mov %rdx, %rdi
+    call *%rsi
+    xor %eax, %eax
+    jmp 1f
+# 异常处理函数把rip和rsp换成这里和上面保存的栈指针后返回, 被调用者保存的寄存器在栈上
+recovery_landing:
+    mov $1, %eax
+1:
+    add $8, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    ret
+.popsection
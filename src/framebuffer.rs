@@ -0,0 +1,345 @@
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::bootinfo::{self, FramebufferInfo, PixelFormat};
+use crate::vga_buffer::{Color, Theme, BACKSPACE};
+
+pub mod font;
+
+use font::{GLYPH_HEIGHT, GLYPH_WIDTH};
+
+// 文本模式16色对应的RGB值
+const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xAA],
+    [0x00, 0xAA, 0x00],
+    [0x00, 0xAA, 0xAA],
+    [0xAA, 0x00, 0x00],
+    [0xAA, 0x00, 0xAA],
+    [0xAA, 0x55, 0x00],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+    [0x55, 0x55, 0xFF],
+    [0x55, 0xFF, 0x55],
+    [0x55, 0xFF, 0xFF],
+    [0xFF, 0x55, 0x55],
+    [0xFF, 0x55, 0xFF],
+    [0xFF, 0xFF, 0x55],
+    [0xFF, 0xFF, 0xFF],
+];
+
+/// 在帧缓冲上按字符网格绘制文本, 行为与文本模式的Writer相同
+pub struct Console<'a> {
+    info: FramebufferInfo,
+    pixels: &'a mut [u8],
+    columns: usize,
+    rows: usize,
+    // column == columns表示本行已写满, 写下一个字符前再换行
+    row: usize,
+    column: usize,
+    foreground: Color,
+    background: Color,
+}
+
+impl<'a> Console<'a> {
+    pub fn new(info: FramebufferInfo, pixels: &'a mut [u8]) -> Console<'a> {
+        assert!(pixels.len() >= info.size(), "framebuffer smaller than its mode");
+        Console {
+            info,
+            pixels,
+            columns: info.width / GLYPH_WIDTH,
+            rows: info.height / GLYPH_HEIGHT,
+            row: 0,
+            column: 0,
+            foreground: Theme::CLASSIC.normal,
+            background: Theme::CLASSIC.background,
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            BACKSPACE => {
+                if self.column > 0 {
+                    self.column -= 1;
+                    self.draw_glyph(self.row, self.column, b' ');
+                }
+            }
+            byte => {
+                if self.column >= self.columns {
+                    self.new_line();
+                }
+                self.draw_glyph(self.row, self.column, byte);
+                self.column += 1;
+            }
+        }
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    /// 与`Writer::rewrite_line`相同
+    pub fn rewrite_line(&mut self, text: &str, cursor: Option<usize>) {
+        self.rewrite_prompt_line("", (self.foreground, self.background), text, cursor);
+    }
+
+    /// 与`Writer::rewrite_prompt_line`相同
+    pub fn rewrite_prompt_line(
+        &mut self,
+        prompt: &str,
+        colors: (Color, Color),
+        text: &str,
+        cursor: Option<usize>,
+    ) {
+        self.clear_row(self.row);
+        self.column = 0;
+        let saved = (self.foreground, self.background);
+        (self.foreground, self.background) = colors;
+        self.write_within_line(prompt);
+        (self.foreground, self.background) = saved;
+        self.write_within_line(text);
+        if let Some(column) = cursor.filter(|&column| column < self.columns) {
+            // 光标处的字符前景色和背景色互换
+            let byte = prompt.bytes().chain(text.bytes()).nth(column).unwrap_or(b' ');
+            core::mem::swap(&mut self.foreground, &mut self.background);
+            self.draw_glyph(self.row, column, byte);
+            core::mem::swap(&mut self.foreground, &mut self.background);
+        }
+    }
+
+    // 写到行尾为止, 不换行
+    fn write_within_line(&mut self, text: &str) {
+        for byte in text.bytes() {
+            if self.column >= self.columns {
+                break;
+            }
+            self.write_byte(byte);
+        }
+    }
+
+    fn new_line(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            // 像素整体上移一个字符行, 再清空最后一行
+            let line = self.info.pitch * GLYPH_HEIGHT;
+            let end = line * self.rows;
+            self.pixels.copy_within(line..end, 0);
+            self.clear_row(self.rows - 1);
+        }
+        self.column = 0;
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        for y in row * GLYPH_HEIGHT..(row + 1) * GLYPH_HEIGHT {
+            for x in 0..self.columns * GLYPH_WIDTH {
+                self.put_pixel(x, y, self.background);
+            }
+        }
+    }
+
+    /// 清空整屏, 光标回到左上角
+    pub fn clear(&mut self) {
+        for row in 0..self.rows {
+            self.clear_row(row);
+        }
+        self.row = 0;
+        self.column = 0;
+    }
+
+    fn draw_glyph(&mut self, row: usize, column: usize, byte: u8) {
+        let glyph = font::glyph(byte);
+        for (dy, bits) in glyph.iter().enumerate() {
+            for dx in 0..GLYPH_WIDTH {
+                let color = if bits & (1 << dx) != 0 {
+                    self.foreground
+                } else {
+                    self.background
+                };
+                self.put_pixel(column * GLYPH_WIDTH + dx, row * GLYPH_HEIGHT + dy, color);
+            }
+        }
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, color: Color) {
+        let bytes = pixel_bytes(self.info.format, color);
+        let bpp = self.info.bytes_per_pixel;
+        let offset = y * self.info.pitch + x * bpp;
+        self.pixels[offset..offset + bpp].copy_from_slice(&bytes[..bpp]);
+    }
+}
+
+// 一个像素在帧缓冲中的字节, 按bytes_per_pixel截取
+fn pixel_bytes(format: PixelFormat, color: Color) -> [u8; 4] {
+    let [r, g, b] = PALETTE[color as usize];
+    match format {
+        PixelFormat::Indexed8 => [color as u8, 0, 0, 0],
+        PixelFormat::Rgb => [r, g, b, 0],
+        PixelFormat::Bgr => [b, g, r, 0],
+    }
+}
+
+impl fmt::Write for Console<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+static CONSOLE: Mutex<Option<Console<'static>>> = Mutex::new(None);
+
+/// 有帧缓冲时创建像素控制台, 通过物理内存映射访问显存. 需要先调用`bootinfo::init`
+pub fn init() -> bool {
+    let Some(info) = bootinfo::framebuffer() else {
+        return false;
+    };
+    let address = bootinfo::physical_memory_offset() + info.address.as_u64();
+    let pixels = unsafe { core::slice::from_raw_parts_mut(address.as_mut_ptr::<u8>(), info.size()) };
+    let mut console = Console::new(info, pixels);
+    console.clear();
+    interrupts::without_interrupts(|| *CONSOLE.lock() = Some(console));
+    true
+}
+
+/// 调用者需已关闭中断. 指定了`colors`时以它输出, 之后恢复原来的颜色
+pub(crate) fn write_fmt(colors: Option<(Color, Color)>, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        let saved = (console.foreground, console.background);
+        if let Some((foreground, background)) = colors {
+            console.set_color(foreground, background);
+        }
+        console.write_fmt(args).unwrap();
+        (console.foreground, console.background) = saved;
+    }
+}
+
+/// 调用者需已关闭中断. 与`write_fmt`相同, 字节原样绘制, 用于按文本缓冲区的内容重绘
+pub(crate) fn write_bytes(colors: Option<(Color, Color)>, bytes: &[u8]) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        let saved = (console.foreground, console.background);
+        if let Some((foreground, background)) = colors {
+            console.set_color(foreground, background);
+        }
+        for &byte in bytes {
+            console.write_byte(byte);
+        }
+        (console.foreground, console.background) = saved;
+    }
+}
+
+/// 调用者需已关闭中断
+pub(crate) fn clear() {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.clear();
+    }
+}
+
+/// 调用者需已关闭中断
+pub(crate) fn rewrite_line(text: &str, cursor: Option<usize>) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.rewrite_line(text, cursor);
+    }
+}
+
+/// 调用者需已关闭中断
+pub(crate) fn rewrite_prompt_line(
+    prompt: &str,
+    colors: (Color, Color),
+    text: &str,
+    cursor: Option<usize>,
+) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.rewrite_prompt_line(prompt, colors, text, cursor);
+    }
+}
+
+/// 调用者需已关闭中断
+pub(crate) fn set_color(foreground: Color, background: Color) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.set_color(foreground, background);
+    }
+}
+
+#[cfg(test)]
+fn test_mode(format: PixelFormat, bytes_per_pixel: usize) -> FramebufferInfo {
+    // 2x2个字符
+    FramebufferInfo {
+        address: x86_64::PhysAddr::new(0),
+        width: 16,
+        height: 16,
+        pitch: 16 * bytes_per_pixel,
+        bytes_per_pixel,
+        format,
+    }
+}
+
+#[test_case]
+fn test_draw_glyph() {
+    let info = test_mode(PixelFormat::Indexed8, 1);
+    let mut pixels = alloc::vec![0xFFu8; info.size()];
+    let mut console = Console::new(info, &mut pixels);
+    assert_eq!((console.columns(), console.rows()), (2, 2));
+    console.clear();
+    console.write_string("A");
+
+    let glyph = font::glyph(b'A');
+    for (y, bits) in glyph.iter().enumerate() {
+        for x in 0..GLYPH_WIDTH {
+            let expected = if bits & (1 << x) != 0 { Color::Green } else { Color::Black };
+            assert_eq!(pixels[y * info.pitch + x], expected as u8);
+        }
+    }
+    // 第二列还是空白
+    assert!(pixels[8..16].iter().all(|&p| p == Color::Black as u8));
+}
+
+#[test_case]
+fn test_scroll() {
+    let info = test_mode(PixelFormat::Indexed8, 1);
+    let mut pixels = alloc::vec![0u8; info.size()];
+    let mut console = Console::new(info, &mut pixels);
+    console.clear();
+    // 最后一行之后的换行使第一行滚出屏幕
+    console.write_string("ab\ncd\n");
+
+    let line = info.pitch * GLYPH_HEIGHT;
+    let mut expected = alloc::vec![0u8; info.size()];
+    let mut reference = Console::new(info, &mut expected);
+    reference.clear();
+    reference.write_string("cd");
+    assert_eq!(pixels[..line], expected[..line]);
+    assert!(pixels[line..].iter().all(|&p| p == Color::Black as u8));
+}
+
+#[test_case]
+fn test_rgb_pixels() {
+    let info = test_mode(PixelFormat::Bgr, 4);
+    let mut pixels = alloc::vec![0u8; info.size()];
+    let mut console = Console::new(info, &mut pixels);
+    console.clear();
+    // '_'只有最后一行全亮
+    console.write_byte(b'_');
+    let offset = (GLYPH_HEIGHT - 1) * info.pitch;
+    assert_eq!(pixels[offset..offset + 4], [0x00, 0xAA, 0x00, 0x00]);
+    assert_eq!(pixels[..4], [0x00, 0x00, 0x00, 0x00]);
+}
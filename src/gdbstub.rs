@@ -0,0 +1,727 @@
+//! 通过COM2上的GDB远程串行协议调试内核, 不依赖QEMU内置的gdbstub
+//!
+//! 命令行中有`gdb`时启用. 执行`int3`(包括插入的软件断点)、单步完成、COM2收到gdb的中断请求或新的包,
+//! 以及命令行中有`gdb_on_panic`时的panic都会进入调试器. 调试器中关中断轮询COM2, 其他处理器继续运行.
+//! QEMU中把第二个串口接到TCP端口, 比如`-serial vc -serial tcp::1234,server,nowait`,
+//! 然后在gdb中`target remote :1234`
+
+use core::arch::global_asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::VirtAddr;
+
+use crate::interrupts::{self, IrqUnavailable};
+use crate::io::HardwareBus;
+use crate::log::Level;
+use crate::memdebug::{self, MemAccessError};
+use crate::selftest::{self, Check, Outcome};
+use crate::serial::SerialPort;
+use crate::usermode::{self, UserExit};
+use crate::{cmdline, earlycon, early_println, log, memory, println};
+
+pub mod packet;
+
+use packet::{decode_hex, parse_hex, Event, Reader, Writer};
+
+global_asm!(include_str!("gdbstub/entry.s"), options(att_syntax));
+
+extern "C" {
+    fn gdbstub_breakpoint_entry();
+    fn gdbstub_debug_entry();
+}
+
+const COM2: u16 = 0x2F8;
+const COM2_IRQ: u8 = 3;
+
+pub const DEBUG_VECTOR: u8 = 1;
+pub const BREAKPOINT_VECTOR: u8 = 3;
+
+const TRAP_FLAG: u64 = 1 << 8;
+const INT3: u8 = 0xCC;
+const MAX_BREAKPOINTS: usize = 32;
+// 一个`m`回复最多包含的字节数, 每个字节两位十六进制
+const MAX_MEMORY: usize = packet::MAX_PACKET / 2;
+
+// 停止原因, 按gdb的信号编号
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+// 错误回复`Enn`中的errno
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+const ENOSPC: u8 = 28;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PANIC_ENTRY: AtomicBool = AtomicBool::new(false);
+// 上一次离开调试器是因为gdb让目标继续运行, 再次进入时gdb在等待停止的回复
+static RESUMED: AtomicBool = AtomicBool::new(false);
+// 下一次进入调试器的原因, 0为SIGTRAP
+static ENTRY_SIGNAL: AtomicU8 = AtomicU8::new(0);
+// 中断处理函数已经读走的包的第一个字节
+static ENTRY_BYTE: AtomicU8 = AtomicU8::new(0);
+static BREAKPOINTS: spin::Mutex<Breakpoints> = spin::Mutex::new(Breakpoints::new());
+// 自检执行的int3, 处理函数清除它并直接返回, 不输出断点信息
+static SELFTEST_PROBE: AtomicBool = AtomicBool::new(false);
+
+/// entry.s保存的寄存器和CPU压入的中断帧, 修改后在异常返回时生效
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// gdb的amd64寄存器顺序: 16个通用寄存器和rip各8字节, 之后是4字节的eflags和cs、ss、ds、es、fs、gs
+const GDB_REGS: usize = 17;
+
+impl TrapFrame {
+    fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+
+    fn gdb_registers(&self) -> [u64; GDB_REGS] {
+        [
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rbp, self.rsp,
+            self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15, self.rip,
+        ]
+    }
+
+    fn set_gdb_registers(&mut self, regs: &[u64; GDB_REGS]) {
+        [
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rbp, self.rsp,
+            self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15, self.rip,
+        ] = *regs;
+    }
+}
+
+/// 断点异常和调试异常的入口, 由`interrupts`放入IDT
+pub fn breakpoint_entry_addr() -> VirtAddr {
+    VirtAddr::new(gdbstub_breakpoint_entry as unsafe extern "C" fn() as usize as u64)
+}
+
+pub fn debug_entry_addr() -> VirtAddr {
+    VirtAddr::new(gdbstub_debug_entry as unsafe extern "C" fn() as usize as u64)
+}
+
+/// 命令行中有`gdb`时初始化COM2并注册它的接收中断, 之后的断点进入调试器. 需要PIC已经初始化
+pub fn init() -> Result<(), IrqUnavailable> {
+    if !cmdline::flag("gdb") {
+        return Ok(());
+    }
+    port().init();
+    interrupts::register_irq(COM2_IRQ, on_interrupt)?;
+    interrupts::unmask_irq(COM2_IRQ);
+    PANIC_ENTRY.store(cmdline::flag("gdb_on_panic"), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+    log!(Level::Info, "gdbstub: listening on COM2");
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// 启用时停在调试器中, gdb让目标继续运行后返回
+pub fn breakpoint() {
+    enter(SIGTRAP);
+}
+
+/// 由panic处理函数调用, 命令行中有`gdb_on_panic`时进入调试器
+pub fn on_panic() {
+    if PANIC_ENTRY.load(Ordering::Relaxed) {
+        enter(SIGABRT);
+    }
+}
+
+/// 登记断点异常的自检
+pub fn register_selftests() {
+    static BREAKPOINT: Check = Check::new("breakpoint", breakpoint_selftest);
+    selftest::register(&BREAKPOINT).expect("duplicate gdbstub selftest");
+}
+
+// 执行int3, 处理函数清除标记说明IDT中的断点入口可用. 启用调试器时int3会停下等待gdb, 跳过
+fn breakpoint_selftest() -> Outcome {
+    if enabled() {
+        return Outcome::Skipped("gdbstub is enabled".into());
+    }
+    SELFTEST_PROBE.store(true, Ordering::Relaxed);
+    x86_64::instructions::interrupts::int3();
+    if SELFTEST_PROBE.swap(false, Ordering::Relaxed) {
+        Outcome::Fail("int3 returned without reaching the breakpoint handler".into())
+    } else {
+        Outcome::Pass
+    }
+}
+
+fn enter(signal: u8) {
+    if enabled() {
+        ENTRY_SIGNAL.store(signal, Ordering::Relaxed);
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+// COM2只由调试器使用, 不需要锁
+fn port() -> SerialPort<HardwareBus> {
+    SerialPort::new(unsafe { HardwareBus::new() }, COM2)
+}
+
+// 目标运行时gdb发送中断请求或新的包(比如刚连接时的qSupported)都进入调试器
+fn on_interrupt() {
+    let mut port = port();
+    while let Some(byte) = port.receive() {
+        match byte {
+            packet::INTERRUPT => return enter(SIGINT),
+            b'$' => {
+                ENTRY_BYTE.store(byte, Ordering::Relaxed);
+                return enter(SIGINT);
+            }
+            _ => {}
+        }
+    }
+}
+
+// 由entry.s调用, 中断已关闭
+#[no_mangle]
+extern "C" fn gdbstub_trap(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    if frame.is_user() {
+        // 用户代码可以用int3回到内核, 也可以自己置位TF
+        if vector == BREAKPOINT_VECTOR {
+            usermode::exit(UserExit::Breakpoint);
+        }
+        frame.rflags &= !TRAP_FLAG;
+        return;
+    }
+    if !enabled() {
+        if vector == BREAKPOINT_VECTOR && SELFTEST_PROBE.swap(false, Ordering::Relaxed) {
+            // 自检只确认处理函数被调用
+        } else if vector == BREAKPOINT_VECTOR && earlycon::active() {
+            // 启动早期控制台可能还没有初始化完
+            early_println!("EXCEPTION: BREAKPOINT during early boot at {:#x}", frame.rip);
+        } else if vector == BREAKPOINT_VECTOR {
+            println!("EXCEPTION: BREAKPOINT at {:#x}\n{:#x?}", frame.rip, frame);
+        }
+        frame.rflags &= !TRAP_FLAG;
+        return;
+    }
+    frame.rflags &= !TRAP_FLAG;
+    let mut breakpoints = BREAKPOINTS.lock();
+    // 插入的断点处停止时rip指向int3之后, 退回断点的地址
+    if vector == BREAKPOINT_VECTOR && breakpoints.contains(frame.rip.wrapping_sub(1)) {
+        frame.rip -= 1;
+    }
+    let signal = match ENTRY_SIGNAL.swap(0, Ordering::Relaxed) {
+        0 => SIGTRAP,
+        signal => signal,
+    };
+    session(frame, &mut breakpoints, signal);
+}
+
+// 处理gdb的命令直到目标继续运行或gdb断开
+fn session(frame: &mut TrapFrame, breakpoints: &mut Breakpoints, signal: u8) {
+    let mut conn = Connection {
+        port: port(),
+        reader: Reader::new(),
+    };
+    if let byte @ 1.. = ENTRY_BYTE.swap(0, Ordering::Relaxed) {
+        conn.reader.feed(byte);
+    }
+    let mut reply = Writer::new();
+    if RESUMED.swap(false, Ordering::Relaxed) {
+        stop_reply(signal, &mut reply);
+        conn.send(&reply);
+    }
+    loop {
+        reply.clear();
+        match handle(conn.receive(), frame, breakpoints, signal, &mut reply) {
+            Action::Reply => conn.send(&reply),
+            Action::Resume => {
+                RESUMED.store(true, Ordering::Relaxed);
+                return;
+            }
+            Action::Detach => {
+                if !reply.is_empty() {
+                    conn.send(&reply);
+                }
+                breakpoints.clear();
+                return;
+            }
+        }
+    }
+}
+
+struct Connection {
+    port: SerialPort<HardwareBus>,
+    reader: Reader,
+}
+
+impl Connection {
+    fn receive_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.port.receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // 等待下一个校验和正确的包并确认
+    fn receive(&mut self) -> &[u8] {
+        loop {
+            let byte = self.receive_byte();
+            match self.reader.feed(byte) {
+                Some(Event::Packet) => break,
+                Some(Event::Corrupt) => self.port.send_raw(b'-'),
+                _ => {}
+            }
+        }
+        self.port.send_raw(b'+');
+        self.reader.packet()
+    }
+
+    // 发送后等待确认, gdb要求重发时再发一次
+    fn send(&mut self, packet: &Writer) {
+        loop {
+            packet.frame(|byte| self.port.send_raw(byte));
+            loop {
+                match self.receive_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Reply,
+    Resume,
+    /// 删除所有断点后继续运行, 回复为空时不发送
+    Detach,
+}
+
+// 处理一个包, 回复写入`reply`. 不支持的命令回复空包
+fn handle(
+    packet: &[u8],
+    frame: &mut TrapFrame,
+    breakpoints: &mut Breakpoints,
+    signal: u8,
+    reply: &mut Writer,
+) -> Action {
+    let Some((&command, args)) = packet.split_first() else {
+        return Action::Reply;
+    };
+    let result = match command {
+        b'?' => {
+            stop_reply(signal, reply);
+            Ok(())
+        }
+        b'g' => {
+            write_registers(frame, reply);
+            Ok(())
+        }
+        b'G' => read_registers(frame, args).map(|()| reply.push_str("OK")),
+        b'm' => read_memory(args, reply),
+        b'M' => write_memory(args).map(|()| reply.push_str("OK")),
+        b'c' | b's' => match resume(frame, args, command == b's') {
+            Ok(()) => return Action::Resume,
+            Err(errno) => Err(errno),
+        },
+        b'Z' | b'z' => match args.strip_prefix(b"0,") {
+            Some(args) => software_breakpoint(breakpoints, args, command == b'Z')
+                .map(|()| reply.push_str("OK")),
+            // 不支持硬件断点和观察点, gdb会改用软件断点
+            None => Ok(()),
+        },
+        b'D' => {
+            reply.push_str("OK");
+            return Action::Detach;
+        }
+        b'k' => return Action::Detach,
+        // 只有一个线程
+        b'H' => {
+            reply.push_str("OK");
+            Ok(())
+        }
+        b'q' => {
+            query(args, reply);
+            Ok(())
+        }
+        _ => Ok(()),
+    };
+    if let Err(errno) = result {
+        reply.clear();
+        let _ = write!(reply, "E{:02x}", errno);
+    }
+    Action::Reply
+}
+
+fn stop_reply(signal: u8, reply: &mut Writer) {
+    let _ = write!(reply, "S{:02x}", signal);
+}
+
+fn query(args: &[u8], reply: &mut Writer) {
+    if args.starts_with(b"Supported") {
+        let _ = write!(reply, "PacketSize={:x}", packet::MAX_PACKET);
+    } else if args == b"Attached" {
+        // 断开时目标继续运行而不是结束
+        reply.push_str("1");
+    }
+}
+
+fn write_registers(frame: &TrapFrame, reply: &mut Writer) {
+    for reg in frame.gdb_registers() {
+        reply.push_hex(&reg.to_le_bytes());
+    }
+    // ds、es、fs和gs在长模式下不使用, 报告为0
+    for reg in [frame.rflags, frame.cs, frame.ss, 0, 0, 0, 0] {
+        reply.push_hex(&(reg as u32).to_le_bytes());
+    }
+}
+
+// 写入通用寄存器、rip和eflags, 段寄存器不能修改
+fn read_registers(frame: &mut TrapFrame, hex: &[u8]) -> Result<(), u8> {
+    let mut bytes = [0u8; GDB_REGS * 8 + 4];
+    let hex = hex.get(..bytes.len() * 2).ok_or(EINVAL)?;
+    decode_hex(hex, &mut bytes).ok_or(EINVAL)?;
+    let (gprs, eflags) = bytes.split_at(GDB_REGS * 8);
+    let mut regs = [0u64; GDB_REGS];
+    for (reg, chunk) in regs.iter_mut().zip(gprs.chunks_exact(8)) {
+        *reg = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    frame.set_gdb_registers(&regs);
+    frame.rflags = u64::from(u32::from_le_bytes(eflags.try_into().unwrap()));
+    Ok(())
+}
+
+// `addr,len`
+fn parse_addr_len(args: &[u8]) -> Result<(VirtAddr, usize), u8> {
+    let comma = args.iter().position(|&byte| byte == b',').ok_or(EINVAL)?;
+    let addr = parse_hex(&args[..comma]).ok_or(EINVAL)?;
+    let len = parse_hex(&args[comma + 1..]).ok_or(EINVAL)?;
+    let addr = VirtAddr::try_new(addr).map_err(|_| EFAULT)?;
+    Ok((addr, usize::try_from(len).map_err(|_| EINVAL)?))
+}
+
+// 只回复能读到的部分, gdb再读剩下的部分时得到错误
+fn read_memory(args: &[u8], reply: &mut Writer) -> Result<(), u8> {
+    let (addr, len) = parse_addr_len(args)?;
+    let mut buf = [0u8; MAX_MEMORY];
+    let buf = &mut buf[..len.min(MAX_MEMORY)];
+    let read = memdebug::safe_read_bytes(addr, buf).map_err(|_| EFAULT)?;
+    reply.push_hex(&buf[..read]);
+    Ok(())
+}
+
+// `addr,len:十六进制数据`
+fn write_memory(args: &[u8]) -> Result<(), u8> {
+    let colon = args.iter().position(|&byte| byte == b':').ok_or(EINVAL)?;
+    let (addr, len) = parse_addr_len(&args[..colon])?;
+    let mut buf = [0u8; MAX_MEMORY];
+    let data = &args[colon + 1..];
+    if data.len() != len.checked_mul(2).ok_or(EINVAL)? {
+        return Err(EINVAL);
+    }
+    let written = decode_hex(data, &mut buf).ok_or(EINVAL)?;
+    for (i, &byte) in buf[..written].iter().enumerate() {
+        memdebug::safe_write_byte(addr + i as u64, byte).map_err(|_| EFAULT)?;
+    }
+    Ok(())
+}
+
+// `c [addr]`和`s [addr]`, 单步通过TF实现
+fn resume(frame: &mut TrapFrame, args: &[u8], step: bool) -> Result<(), u8> {
+    if !args.is_empty() {
+        frame.rip = parse_hex(args).ok_or(EINVAL)?;
+    }
+    if step {
+        frame.rflags |= TRAP_FLAG;
+    }
+    Ok(())
+}
+
+// `Z0,addr,kind`和`z0,addr,kind`, kind总是1
+fn software_breakpoint(breakpoints: &mut Breakpoints, args: &[u8], insert: bool) -> Result<(), u8> {
+    let (addr, _kind) = parse_addr_len(args)?;
+    if insert {
+        breakpoints.insert(addr)
+    } else {
+        breakpoints.remove(addr);
+        Ok(())
+    }
+}
+
+/// 插入的软件断点和被替换的原字节
+///
+/// gdb默认在停止时删除所有断点、继续运行前重新插入, 从断点处继续时先单步越过它, 这里不需要处理
+struct Breakpoints {
+    slots: [Option<(VirtAddr, u8)>; MAX_BREAKPOINTS],
+}
+
+impl Breakpoints {
+    const fn new() -> Self {
+        Breakpoints {
+            slots: [None; MAX_BREAKPOINTS],
+        }
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        self.slots
+            .iter()
+            .flatten()
+            .any(|(inserted, _)| inserted.as_u64() == addr)
+    }
+
+    fn insert(&mut self, addr: VirtAddr) -> Result<(), u8> {
+        if self.contains(addr.as_u64()) {
+            return Ok(());
+        }
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ENOSPC)?;
+        let original = unsafe { patch_text(addr, INT3) }.map_err(|_| EFAULT)?;
+        *slot = Some((addr, original));
+        Ok(())
+    }
+
+    fn remove(&mut self, addr: VirtAddr) {
+        for slot in &mut self.slots {
+            if let Some((inserted, original)) = *slot {
+                if inserted == addr {
+                    let _ = unsafe { patch_text(inserted, original) };
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        for slot in &mut self.slots {
+            if let Some((addr, original)) = slot.take() {
+                let _ = unsafe { patch_text(addr, original) };
+            }
+        }
+    }
+}
+
+// 临时清除CR0.WP, 写入只读的代码页. 返回原来的字节
+//
+// 调用者需保证没有其他处理器正在执行被修改的指令
+unsafe fn patch_text(addr: VirtAddr, byte: u8) -> Result<u8, MemAccessError> {
+    if !memory::is_mapped(addr) {
+        return Err(MemAccessError::Unmapped(addr));
+    }
+    without_interrupts(|| {
+        let cr0 = Cr0::read();
+        let mut unprotected = cr0;
+        unprotected.remove(Cr0Flags::WRITE_PROTECT);
+        let ptr = addr.as_mut_ptr::<u8>();
+        Cr0::write(unprotected);
+        let original = ptr.read_volatile();
+        ptr.write_volatile(byte);
+        Cr0::write(cr0);
+        Ok(original)
+    })
+}
+
+#[cfg(test)]
+fn handle_str(
+    packet: &str,
+    frame: &mut TrapFrame,
+    breakpoints: &mut Breakpoints,
+) -> (Action, alloc::string::String) {
+    let mut reply = Writer::new();
+    let action = handle(packet.as_bytes(), frame, breakpoints, SIGTRAP, &mut reply);
+    let reply = core::str::from_utf8(reply.data()).unwrap().into();
+    (action, reply)
+}
+
+#[test_case]
+fn test_register_packets() {
+    let mut breakpoints = Breakpoints::new();
+    let mut frame = TrapFrame {
+        rax: 1,
+        rsp: 0xffff_8000_0000_1234,
+        r15: 0x0f,
+        rip: 0x2_0000,
+        cs: 8,
+        rflags: 0x246,
+        ss: 0x10,
+        ..TrapFrame::default()
+    };
+    let (action, reply) = handle_str("g", &mut frame, &mut breakpoints);
+    assert_eq!(action, Action::Reply);
+    assert_eq!(reply.len(), GDB_REGS * 16 + 7 * 8);
+    assert!(reply.starts_with("0100000000000000"));
+    // rsp是第8个, rip是第17个
+    assert_eq!(&reply[7 * 16..8 * 16], "341200000080ffff");
+    assert_eq!(&reply[16 * 16..17 * 16], "0000020000000000");
+    assert_eq!(
+        &reply[17 * 16..],
+        "46020000080000001000000000000000000000000000000000000000"
+    );
+
+    // 写回修改后的寄存器
+    let mut written = reply.clone();
+    written.replace_range(..16, "efbeadde00000000");
+    written.replace_range(17 * 16..17 * 16 + 8, "46030000");
+    let (_, reply) = handle_str(
+        &alloc::format!("G{}", written),
+        &mut frame,
+        &mut breakpoints,
+    );
+    assert_eq!(reply, "OK");
+    assert_eq!(
+        (frame.rax, frame.rflags, frame.r15),
+        (0xdead_beef, 0x346, 0x0f)
+    );
+    assert_eq!((frame.cs, frame.ss), (8, 0x10));
+    assert_eq!(handle_str("G0011", &mut frame, &mut breakpoints).1, "E16");
+
+    assert_eq!(handle_str("?", &mut frame, &mut breakpoints).1, "S05");
+    assert_eq!(
+        handle_str("s", &mut frame, &mut breakpoints).0,
+        Action::Resume
+    );
+    assert_ne!(frame.rflags & TRAP_FLAG, 0);
+    assert_eq!(
+        handle_str("c3000", &mut frame, &mut breakpoints).0,
+        Action::Resume
+    );
+    assert_eq!(frame.rip, 0x3000);
+    assert_eq!(
+        handle_str("vMustReplyEmpty", &mut frame, &mut breakpoints).1,
+        ""
+    );
+    assert_eq!(
+        handle_str("qSupported:swbreak+", &mut frame, &mut breakpoints).1,
+        "PacketSize=400"
+    );
+}
+
+#[test_case]
+fn test_memory_packets() {
+    let mut data = [0xdeu8, 0xad, 0xbe, 0xef];
+    let mut breakpoints = Breakpoints::new();
+    let mut frame = TrapFrame::default();
+    let addr = data.as_mut_ptr() as u64;
+    let (_, reply) = handle_str(
+        &alloc::format!("m{:x},4", addr),
+        &mut frame,
+        &mut breakpoints,
+    );
+    assert_eq!(reply, "deadbeef");
+    let (_, reply) = handle_str(
+        &alloc::format!("M{:x},2:0102", addr + 1),
+        &mut frame,
+        &mut breakpoints,
+    );
+    assert_eq!(reply, "OK");
+    assert_eq!(
+        unsafe { core::ptr::addr_of!(data).read_volatile() },
+        [0xde, 1, 2, 0xef]
+    );
+    // 长度与数据不符
+    let (_, reply) = handle_str(
+        &alloc::format!("M{:x},2:01", addr),
+        &mut frame,
+        &mut breakpoints,
+    );
+    assert_eq!(reply, "E16");
+    assert_eq!(handle_str("m0,4", &mut frame, &mut breakpoints).1, "E0e");
+    assert_eq!(
+        handle_str("m800000000000,4", &mut frame, &mut breakpoints).1,
+        "E0e"
+    );
+    assert_eq!(handle_str("mzz", &mut frame, &mut breakpoints).1, "E16");
+}
+
+// 测试中插入断点的函数, 断点移除后仍能正常调用
+#[cfg(test)]
+#[inline(never)]
+fn breakpoint_target() -> u64 {
+    core::hint::black_box(42)
+}
+
+#[test_case]
+fn test_software_breakpoints() {
+    let mut breakpoints = Breakpoints::new();
+    let mut frame = TrapFrame::default();
+    let addr = breakpoint_target as fn() -> u64 as usize as u64;
+    let original = unsafe { (addr as *const u8).read_volatile() };
+    let read = alloc::format!("m{:x},1", addr);
+
+    let (_, reply) = handle_str(
+        &alloc::format!("Z0,{:x},1", addr),
+        &mut frame,
+        &mut breakpoints,
+    );
+    assert_eq!(reply, "OK");
+    assert!(breakpoints.contains(addr));
+    assert_eq!(handle_str(&read, &mut frame, &mut breakpoints).1, "cc");
+    // 重复插入不会覆盖保存的原字节
+    handle_str(
+        &alloc::format!("Z0,{:x},1", addr),
+        &mut frame,
+        &mut breakpoints,
+    );
+    let (_, reply) = handle_str(
+        &alloc::format!("z0,{:x},1", addr),
+        &mut frame,
+        &mut breakpoints,
+    );
+    assert_eq!(reply, "OK");
+    assert_eq!(unsafe { (addr as *const u8).read_volatile() }, original);
+    assert_eq!(breakpoint_target(), 42);
+
+    assert_eq!(handle_str("Z0,0,1", &mut frame, &mut breakpoints).1, "E0e");
+    // 硬件断点不支持
+    assert_eq!(
+        handle_str(
+            &alloc::format!("Z1,{:x},1", addr),
+            &mut frame,
+            &mut breakpoints
+        )
+        .1,
+        ""
+    );
+    handle_str(
+        &alloc::format!("Z0,{:x},1", addr),
+        &mut frame,
+        &mut breakpoints,
+    );
+    assert_eq!(
+        handle_str("D", &mut frame, &mut breakpoints),
+        (Action::Detach, "OK".into())
+    );
+    breakpoints.clear();
+    assert!(!breakpoints.contains(addr));
+    assert_eq!(breakpoint_target(), 42);
+}
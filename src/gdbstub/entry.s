@@ -0,0 +1,65 @@
+# 断点异常(#BP)和调试异常(#DB)的入口. 在中断帧之下压入向量号和全部通用寄存器,
+# 组成TrapFrame交给gdbstub_trap, 返回时恢复可能被调试器修改过的寄存器.
+# 来自ring 3时与int 0x80入口一样swapgs换入per-CPU数据指针
+
+.pushsection .text
+.global gdbstub_breakpoint_entry
+gdbstub_breakpoint_entry:
+    push $3
+    jmp 1f
+
+.global gdbstub_debug_entry
+gdbstub_debug_entry:
+    push $1
+1:
+    # 向量号之上是CPU压入的中断帧, 8(%rsp)是rip, 16(%rsp)是cs
+    testb $3, 16(%rsp)
+    jz 2f
+    swapgs
+2:
+    push %rax
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    cld
+    mov %rsp, %rdi
+    # CPU压入5项后栈对齐到8, 再压入16项, 调用前补齐到16
+    sub $8, %rsp
+    call gdbstub_trap
+    add $8, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rbp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rbx
+    pop %rax
+    # 向量号
+    add $8, %rsp
+    testb $3, 8(%rsp)
+    jz 3f
+    # 换回用户的GS_BASE之后到iretq之前不能被中断
+    cli
+    swapgs
+3:
+    iretq
+.popsection
@@ -0,0 +1,351 @@
+//! GDB远程串行协议的分包和十六进制编码, 不访问硬件
+//!
+//! 包的格式为`$数据#校验和`, 校验和是数据各字节之和模256的两位十六进制.
+//! 数据中的`$`、`#`、`}`和`*`以`}`开头并与0x20异或后发送
+
+use core::fmt;
+
+/// 收发的一个包最多包含的数据字节数, 通过qSupported告诉gdb
+pub const MAX_PACKET: usize = 1024;
+
+const ESCAPE: u8 = b'}';
+const ESCAPE_XOR: u8 = 0x20;
+/// gdb请求中断目标时发送的字节, 不在包内
+pub const INTERRUPT: u8 = 0x03;
+
+/// 数据各字节之和模256
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+pub fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// 把十六进制字符串解码到`out`开头, 返回解码的字节数. 长度为奇数或含非十六进制字符时为None
+pub fn decode_hex(hex: &[u8], out: &mut [u8]) -> Option<usize> {
+    if !hex.len().is_multiple_of(2) || hex.len() / 2 > out.len() {
+        return None;
+    }
+    for (byte, pair) in out.iter_mut().zip(hex.chunks(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(hex.len() / 2)
+}
+
+/// 解析不带前缀的十六进制数, 地址和长度都是这种格式
+pub fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0u64, |value, &byte| {
+        Some(value << 4 | u64::from(hex_digit(byte)?))
+    })
+}
+
+/// 收到一个字节后的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// 校验和正确的包, 内容由`Reader::packet`取得, 需要回复`+`
+    Packet,
+    /// 校验和错误或超过`MAX_PACKET`, 需要回复`-`让对方重发
+    Corrupt,
+    /// 包外的中断请求
+    Interrupt,
+    /// 对方确认收到了上一个包
+    Ack,
+    /// 对方要求重发上一个包
+    Nack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Data,
+    Escaped,
+    Checksum,
+    ChecksumLow(u8),
+}
+
+/// 逐字节组包, 数据中的转义在这里去掉
+pub struct Reader {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+    sum: u8,
+    overflow: bool,
+    state: State,
+}
+
+impl Reader {
+    pub const fn new() -> Self {
+        Reader {
+            buf: [0; MAX_PACKET],
+            len: 0,
+            sum: 0,
+            overflow: false,
+            state: State::Idle,
+        }
+    }
+
+    /// 最近一个完整的包的数据
+    pub fn packet(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<Event> {
+        match (self.state, byte) {
+            // 包内出现`$`说明之前的包不完整, 从这里重新开始
+            (_, b'$') => {
+                self.len = 0;
+                self.sum = 0;
+                self.overflow = false;
+                self.state = State::Data;
+                None
+            }
+            (State::Idle, INTERRUPT) => Some(Event::Interrupt),
+            (State::Idle, b'+') => Some(Event::Ack),
+            (State::Idle, b'-') => Some(Event::Nack),
+            (State::Idle, _) => None,
+            (State::Data, b'#') => {
+                self.state = State::Checksum;
+                None
+            }
+            (State::Data, ESCAPE) => {
+                self.sum = self.sum.wrapping_add(byte);
+                self.state = State::Escaped;
+                None
+            }
+            (State::Data | State::Escaped, _) => {
+                self.sum = self.sum.wrapping_add(byte);
+                let byte = if self.state == State::Escaped {
+                    byte ^ ESCAPE_XOR
+                } else {
+                    byte
+                };
+                self.state = State::Data;
+                self.push(byte);
+                None
+            }
+            (State::Checksum, _) => {
+                self.state = match hex_digit(byte) {
+                    Some(high) => State::ChecksumLow(high),
+                    None => State::Idle,
+                };
+                (self.state == State::Idle).then_some(Event::Corrupt)
+            }
+            (State::ChecksumLow(high), _) => {
+                self.state = State::Idle;
+                match hex_digit(byte) {
+                    Some(low) if high << 4 | low == self.sum && !self.overflow => {
+                        Some(Event::Packet)
+                    }
+                    _ => Some(Event::Corrupt),
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        match self.buf.get_mut(self.len) {
+            Some(slot) => {
+                *slot = byte;
+                self.len += 1;
+            }
+            None => self.overflow = true,
+        }
+    }
+}
+
+impl Default for Reader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 要发送的包的数据, 超过`MAX_PACKET`的部分被丢弃
+pub struct Writer {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Writer {
+    pub const fn new() -> Self {
+        Writer {
+            buf: [0; MAX_PACKET],
+            len: 0,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if let Some(slot) = self.buf.get_mut(self.len) {
+            *slot = byte;
+            self.len += 1;
+        }
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+    }
+
+    /// 按内存中的顺序写入每个字节的两位十六进制
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(HEX_DIGITS[usize::from(byte >> 4)]);
+            self.push(HEX_DIGITS[usize::from(byte & 0xF)]);
+        }
+    }
+
+    /// 加上首尾和校验和, 逐字节交给`send`
+    pub fn frame(&self, mut send: impl FnMut(u8)) {
+        send(b'$');
+        let mut sum = 0u8;
+        let mut emit = |byte: u8| {
+            sum = sum.wrapping_add(byte);
+            send(byte);
+        };
+        for &byte in self.data() {
+            if matches!(byte, b'$' | b'#' | b'*' | ESCAPE) {
+                emit(ESCAPE);
+                emit(byte ^ ESCAPE_XOR);
+            } else {
+                emit(byte);
+            }
+        }
+        send(b'#');
+        send(HEX_DIGITS[usize::from(sum >> 4)]);
+        send(HEX_DIGITS[usize::from(sum & 0xF)]);
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn feed_all(reader: &mut Reader, bytes: &[u8]) -> alloc::vec::Vec<Event> {
+    bytes.iter().filter_map(|&byte| reader.feed(byte)).collect()
+}
+
+#[test_case]
+fn test_reader_framing() {
+    let mut reader = Reader::new();
+    assert_eq!(
+        feed_all(&mut reader, b"+$g#67"),
+        [Event::Ack, Event::Packet]
+    );
+    assert_eq!(reader.packet(), b"g");
+    assert_eq!(
+        feed_all(&mut reader, b"$m ffffffff8000,4#00"),
+        [Event::Corrupt]
+    );
+    assert_eq!(feed_all(&mut reader, b"$m0,4#zz"), [Event::Corrupt]);
+    // 包外的中断请求, 包内的0x03只是数据
+    assert_eq!(
+        feed_all(&mut reader, b"\x03-"),
+        [Event::Interrupt, Event::Nack]
+    );
+    assert_eq!(feed_all(&mut reader, b"$\x03#03"), [Event::Packet]);
+    assert_eq!(reader.packet(), b"\x03");
+    // 不完整的包被之后的`$`丢弃
+    assert_eq!(feed_all(&mut reader, b"$qSupp$?#3f"), [Event::Packet]);
+    assert_eq!(reader.packet(), b"?");
+    // 转义的`#`, 校验和按转义后发送的字节计算
+    let escaped = b"X0,1:}\x03";
+    let mut framed = alloc::vec![b'$'];
+    framed.extend_from_slice(escaped);
+    framed.extend_from_slice(alloc::format!("#{:02x}", checksum(escaped)).as_bytes());
+    assert_eq!(feed_all(&mut reader, &framed), [Event::Packet]);
+    assert_eq!(reader.packet(), b"X0,1:#");
+    // 超长的包要求重发
+    let mut long = alloc::vec![b'$'];
+    long.resize(MAX_PACKET + 2, b'0');
+    long.extend_from_slice(alloc::format!("#{:02x}", checksum(&long[1..])).as_bytes());
+    assert_eq!(feed_all(&mut reader, &long), [Event::Corrupt]);
+}
+
+#[test_case]
+fn test_writer_framing() {
+    use core::fmt::Write;
+
+    let frame = |writer: &Writer| {
+        let mut out = alloc::vec::Vec::new();
+        writer.frame(|byte| out.push(byte));
+        out
+    };
+    let mut writer = Writer::new();
+    assert_eq!(frame(&writer), b"$#00");
+    writer.push_str("OK");
+    assert_eq!(frame(&writer), b"$OK#9a");
+    writer.clear();
+    write!(writer, "S{:02x}", 5).unwrap();
+    assert_eq!(frame(&writer), b"$S05#b8");
+    // 对方收到的数据与写入的相同
+    writer.clear();
+    writer.push_str("a$b#c}d*e");
+    let framed = frame(&writer);
+    assert_eq!(&framed[..15], b"$a}\x04b}\x03c}]d}\x0ae#");
+    let mut reader = Reader::new();
+    assert_eq!(feed_all(&mut reader, &framed), [Event::Packet]);
+    assert_eq!(reader.packet(), b"a$b#c}d*e");
+    // 写满之后的部分被丢弃
+    writer.clear();
+    for _ in 0..MAX_PACKET + 10 {
+        writer.push(b'x');
+    }
+    assert_eq!(writer.data().len(), MAX_PACKET);
+}
+
+#[test_case]
+fn test_hex_encoding() {
+    let mut writer = Writer::new();
+    writer.push_hex(&[0x00, 0x7f, 0xab, 0xff]);
+    assert_eq!(writer.data(), b"007fabff");
+    writer.clear();
+    writer.push_hex(&0x1234_5678_9abc_def0u64.to_le_bytes());
+    assert_eq!(writer.data(), b"f0debc9a78563412");
+
+    let mut out = [0u8; 4];
+    assert_eq!(decode_hex(b"007FabfF", &mut out), Some(4));
+    assert_eq!(out, [0x00, 0x7f, 0xab, 0xff]);
+    assert_eq!(decode_hex(b"", &mut out), Some(0));
+    assert_eq!(decode_hex(b"abc", &mut out), None);
+    assert_eq!(decode_hex(b"0g", &mut out), None);
+    assert_eq!(decode_hex(b"0011223344", &mut out), None);
+
+    assert_eq!(parse_hex(b"ffffffff8000"), Some(0xffff_ffff_8000));
+    assert_eq!(parse_hex(b"0"), Some(0));
+    assert_eq!(parse_hex(b"FFFFFFFFFFFFFFFF"), Some(u64::MAX));
+    assert_eq!(parse_hex(b"10000000000000000"), None);
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"0x10"), None);
+}
@@ -1,46 +1,140 @@
-use lazy_static::lazy_static;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+
+use conquer_once::spin::OnceCell;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STAICK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STAICK_SIZE] = [0; STAICK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STAICK_SIZE;
-            stack_end
-        };
-        tss
+// 切换线程时修改RSP0, 处理器直接从内存读取TSS
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+static TSS: OnceCell<Tss> = OnceCell::uninit();
+
+fn build_tss() -> Tss {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        const STAICK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STAICK_SIZE] = [0; STAICK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(core::ptr::addr_of!(STACK));
+        stack_start + STAICK_SIZE
     };
+    // 从ring 3进入内核时使用的栈
+    tss.privilege_stack_table[0] = {
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        VirtAddr::from_ptr(core::ptr::addr_of!(STACK)) + STACK_SIZE
+    };
+    Tss(UnsafeCell::new(tss))
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector: SegmentSelector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector: SegmentSelector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors{code_selector, tss_selector})
-    };
+// 内核数据段紧跟内核代码段, 用户代码段紧跟用户数据段, 这是syscall/sysret要求的顺序,
+// 由syscall::init检查
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector: SegmentSelector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector: SegmentSelector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors {
+        code_selector,
+        data_selector,
+        user_code_selector,
+        user_data_selector,
+        tss_selector,
+    })
 }
 
-pub fn init() {
+static GDT: OnceCell<(GlobalDescriptorTable, Selectors)> = OnceCell::uninit();
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, Segment};
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+/// 建立并加载BSP的GDT和TSS, 由`toy_os::init`调用. 重复调用会panic
+pub fn init() {
+    TSS.try_init_once(build_tss)
+        .expect("gdt::init called twice");
+    GDT.try_init_once(|| build_gdt(unsafe { &*bsp_tss().0.get() }))
+        .expect("gdt::init called twice");
+    load(gdt());
+}
+
+fn bsp_tss() -> &'static Tss {
+    TSS.try_get().expect("TSS used before gdt::init")
+}
+
+fn gdt() -> &'static (GlobalDescriptorTable, Selectors) {
+    GDT.try_get().expect("GDT used before gdt::init")
+}
+
+/// 内核代码段和数据段选择子
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (gdt().1.code_selector, gdt().1.data_selector)
+}
+
+/// 用户代码段和数据段选择子, RPL为3
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (gdt().1.user_code_selector, gdt().1.user_data_selector)
+}
+
+/// BSP从ring 3进入内核时使用的栈顶
+pub fn privilege_stack() -> VirtAddr {
+    unsafe { (*bsp_tss().0.get()).privilege_stack_table[0] }
+}
+
+/// 设置BSP从ring 3进入内核时使用的栈顶, 切换线程时在关中断的情况下调用
+pub fn set_privilege_stack(top: VirtAddr) {
+    unsafe { (*bsp_tss().0.get()).privilege_stack_table[0] = top }
+}
+
+/// 为AP加载它自己的GDT和TSS, 已被加载的TSS处于忙状态, 不能在多个处理器间共用
+pub fn init_ap(double_fault_stack: VirtAddr) {
+    use x86_64::instructions::segmentation::{Segment, DS, ES, SS};
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+    let gdt = Box::leak(Box::new(build_gdt(Box::leak(Box::new(tss)))));
+    load(gdt);
+    // 启动代码使用的数据段选择子在新的GDT中不存在
+    unsafe {
+        let null = SegmentSelector(0);
+        SS::set_reg(null);
+        DS::set_reg(null);
+        ES::set_reg(null);
+    }
+}
+#[test_case]
+fn test_user_selectors() {
+    use x86_64::PrivilegeLevel;
+
+    let (code, data) = user_selectors();
+    assert_eq!(code.rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(data.rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(code.index(), data.index() + 1);
+    let (kernel_code, kernel_data) = kernel_selectors();
+    assert_eq!(kernel_code.rpl(), PrivilegeLevel::Ring0);
+    assert_eq!(kernel_data.index(), kernel_code.index() + 1);
+}
@@ -0,0 +1,356 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::apic::{self, ioapic, RedirectionEntry};
+use crate::interrupts::HPET_VECTOR;
+use crate::{acpi, cpu, memory, time};
+
+// 寄存器偏移
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIG: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+const REGISTER_BLOCK_SIZE: usize = 0x400;
+
+const fn timer_config(index: usize) -> u64 {
+    0x100 + 0x20 * index as u64
+}
+
+const fn timer_comparator(index: usize) -> u64 {
+    0x108 + 0x20 * index as u64
+}
+
+// 通用配置寄存器
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+// 定时器配置寄存器, 高32位是可用的中断路由
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+// 规范要求计数周期不超过100ns
+const MAX_PERIOD_FS: u32 = 100_000_000;
+const FEMTOS_PER_NS: u128 = 1_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+// 校准TSC时测量的时长
+const TSC_CALIBRATION_NS: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NotPresent,
+    /// 寄存器不在内存地址空间中
+    NotMemoryMapped,
+    MapFailed,
+    BadPeriod(u32),
+    /// 只支持64位主计数器
+    Counter32Bit,
+    /// 没有能路由到IO-APIC的比较器
+    NoRoute,
+}
+
+/// 通用能力寄存器
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities {
+    /// 主计数器每次加一经过的飞秒数
+    pub fn period_fs(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    pub fn frequency_hz(self) -> u64 {
+        FEMTOS_PER_SEC / u64::from(self.period_fs().max(1))
+    }
+
+    pub fn vendor_id(self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+
+    pub fn legacy_route_capable(self) -> bool {
+        self.0 & (1 << 15) != 0
+    }
+
+    pub fn counter_64bit(self) -> bool {
+        self.0 & (1 << 13) != 0
+    }
+
+    /// 寄存器中保存的是最后一个定时器的编号
+    pub fn timer_count(self) -> usize {
+        ((self.0 >> 8) & 0x1F) as usize + 1
+    }
+
+    pub fn validate(self) -> Result<(), HpetError> {
+        let period = self.period_fs();
+        if period == 0 || period > MAX_PERIOD_FS {
+            return Err(HpetError::BadPeriod(period));
+        }
+        if !self.counter_64bit() {
+            return Err(HpetError::Counter32Bit);
+        }
+        Ok(())
+    }
+}
+
+fn ticks_to_ns(ticks: u64, period_fs: u32) -> u64 {
+    (u128::from(ticks) * u128::from(period_fs) / FEMTOS_PER_NS) as u64
+}
+
+// 向上取整, 保证至少经过`ns`纳秒
+fn ns_to_ticks(ns: u64, period_fs: u32) -> u64 {
+    let femtos = u128::from(ns) * FEMTOS_PER_NS;
+    let period = u128::from(period_fs);
+    femtos.div_ceil(period) as u64
+}
+
+struct Registers {
+    base: VirtAddr,
+}
+
+impl Registers {
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { read_volatile((self.base + offset).as_ptr()) }
+    }
+
+    fn write(&self, offset: u64, value: u64) {
+        unsafe { write_volatile((self.base + offset).as_mut_ptr(), value) }
+    }
+}
+
+// 用于一次性定时的比较器
+#[derive(Debug, Clone, Copy)]
+struct Comparator {
+    index: usize,
+    gsi: u32,
+}
+
+struct Hpet {
+    regs: Registers,
+    period_fs: u32,
+    comparator: Option<Comparator>,
+}
+
+impl Hpet {
+    fn counter(&self) -> u64 {
+        self.regs.read(MAIN_COUNTER)
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+// 一次性比较器的目标计数值, 0表示未设置
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// 通过ACPI的HPET表找到定时器块, 启用主计数器并准备一个一次性比较器
+///
+/// 需要在`acpi::init`和`apic::init`之后调用, 关闭传统替换路由以保留PIT的时钟中断
+pub fn init() -> Result<(), HpetError> {
+    let info = acpi::hpet().ok_or(HpetError::NotPresent)?;
+    if info.base_address.address_space != 0 {
+        return Err(HpetError::NotMemoryMapped);
+    }
+    let base = memory::map_mmio(PhysAddr::new(info.base_address.address), REGISTER_BLOCK_SIZE)
+        .map_err(|_| HpetError::MapFailed)?;
+    let regs = Registers { base };
+    let capabilities = Capabilities(regs.read(GENERAL_CAPABILITIES));
+    capabilities.validate()?;
+
+    // 停止计数后清零再启用
+    let config = regs.read(GENERAL_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    regs.write(GENERAL_CONFIG, config);
+    regs.write(MAIN_COUNTER, 0);
+    regs.write(GENERAL_CONFIG, config | CONFIG_ENABLE);
+
+    let comparator = setup_comparator(&regs, capabilities.timer_count());
+    let hpet = HPET.call_once(|| Hpet {
+        regs,
+        period_fs: capabilities.period_fs(),
+        comparator,
+    });
+    calibrate_tsc(hpet);
+    Ok(())
+}
+
+// 选择第一个能路由到IO-APIC的定时器, 优先使用GSI 16以上不与ISA设备共用的线路
+fn setup_comparator(regs: &Registers, timer_count: usize) -> Option<Comparator> {
+    let apic_id = apic::local_apic()?.id() as u8;
+    for index in 0..timer_count {
+        let config = regs.read(timer_config(index));
+        let routes = (config >> 32) as u32;
+        let Some(gsi) = (0..32).rev().find(|&gsi| routes & (1 << gsi) != 0 && ioapic::covers(gsi))
+        else {
+            continue;
+        };
+
+        // 比较值先设为最大, 启用后不会立即触发
+        regs.write(timer_comparator(index), u64::MAX);
+        let mode = TIMER_LEVEL_TRIGGERED | TIMER_PERIODIC | TIMER_32BIT_MODE | TIMER_FSB_ENABLE;
+        let config = (config & !(mode | TIMER_ROUTE_MASK))
+            | u64::from(gsi) << TIMER_ROUTE_SHIFT
+            | TIMER_INTERRUPT_ENABLE;
+        regs.write(timer_config(index), config);
+        // 不支持的路由写入后读回的值会不同
+        if (regs.read(timer_config(index)) & TIMER_ROUTE_MASK) >> TIMER_ROUTE_SHIFT != u64::from(gsi) {
+            continue;
+        }
+        if ioapic::route(gsi, RedirectionEntry::new(HPET_VECTOR, apic_id)).is_err() {
+            continue;
+        }
+        return Some(Comparator { index, gsi });
+    }
+    None
+}
+
+// 以HPET为基准测量TSC频率, 只有TSC不变时才值得作为Instant的时钟源
+fn calibrate_tsc(hpet: &Hpet) {
+    if !cpu::features().invariant_tsc {
+        return;
+    }
+    let ticks = ns_to_ticks(TSC_CALIBRATION_NS, hpet.period_fs);
+    let (start_tsc, start) = (cpu::rdtsc_serialized(), hpet.counter());
+    while hpet.counter() - start < ticks {
+        core::hint::spin_loop();
+    }
+    let (end_tsc, end) = (cpu::rdtsc_serialized(), hpet.counter());
+    let elapsed_ns = ticks_to_ns(end - start, hpet.period_fs).max(1);
+    let hz = u128::from(end_tsc - start_tsc) * 1_000_000_000 / u128::from(elapsed_ns);
+    time::set_tsc_frequency(hz as u64);
+}
+
+pub fn available() -> bool {
+    HPET.get().is_some()
+}
+
+/// 主计数器启用以来经过的飞秒数
+pub fn now_femtos() -> Option<u128> {
+    let hpet = HPET.get()?;
+    Some(u128::from(hpet.counter()) * u128::from(hpet.period_fs))
+}
+
+/// 主计数器启用以来经过的纳秒数
+pub fn now_ns() -> Option<u64> {
+    let hpet = HPET.get()?;
+    Some(ticks_to_ns(hpet.counter(), hpet.period_fs))
+}
+
+/// 比较器使用的全局系统中断
+pub fn oneshot_gsi() -> Option<u32> {
+    Some(HPET.get()?.comparator?.gsi)
+}
+
+/// `delay_ns`纳秒后触发一次比较器中断, 覆盖尚未到期的设置
+pub fn set_oneshot(delay_ns: u64) -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+    let comparator = hpet.comparator.ok_or(HpetError::NoRoute)?;
+    let target = hpet.counter() + ns_to_ticks(delay_ns, hpet.period_fs).max(1);
+    interrupts::without_interrupts(|| {
+        DEADLINE.store(target, Ordering::SeqCst);
+        hpet.regs.write(timer_comparator(comparator.index), target);
+    });
+    // 写入比较值之前计数器已越过目标时, 部分硬件不会再触发
+    if hpet.counter() >= target {
+        let _ = DEADLINE.compare_exchange(target, 0, Ordering::SeqCst, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+/// 是否有尚未到期的一次性定时
+pub fn oneshot_pending() -> bool {
+    DEADLINE.load(Ordering::SeqCst) != 0
+}
+
+/// 比较器到期触发的中断次数
+pub fn interrupt_count() -> u64 {
+    INTERRUPTS.load(Ordering::SeqCst)
+}
+
+/// 由中断处理函数调用
+pub(crate) fn on_interrupt() {
+    let Some(hpet) = HPET.get() else {
+        return;
+    };
+    let deadline = DEADLINE.load(Ordering::SeqCst);
+    // GSI可能与PIT共用(如i440fx上的GSI 2), 只有计数器越过目标才算比较器中断
+    if deadline != 0
+        && hpet.counter() >= deadline
+        && DEADLINE
+            .compare_exchange(deadline, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    {
+        INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn test_capabilities_decoding() {
+    // QEMU: 100MHz, 3个定时器
+    let qemu = Capabilities(0x0098_9680_8086_A201);
+    assert_eq!(qemu.period_fs(), 10_000_000);
+    assert_eq!(qemu.frequency_hz(), 100_000_000);
+    assert_eq!(qemu.timer_count(), 3);
+    assert_eq!(qemu.vendor_id(), 0x8086);
+    assert!(qemu.counter_64bit() && qemu.legacy_route_capable());
+    assert_eq!(qemu.validate(), Ok(()));
+
+    // Intel ICH: 14.318MHz, 8个定时器
+    let ich = Capabilities(0x0429_B17F_8086_A701);
+    assert_eq!(ich.period_fs(), 69_841_279);
+    assert_eq!(ich.frequency_hz(), 14_318_179);
+    assert_eq!(ich.timer_count(), 8);
+
+    assert_eq!(Capabilities(0x8086_A201).validate(), Err(HpetError::BadPeriod(0)));
+    assert_eq!(
+        Capabilities(0x05F5_E101_8086_A201).validate(),
+        Err(HpetError::BadPeriod(100_000_001))
+    );
+    assert_eq!(Capabilities(0x0098_9680_8086_8201).validate(), Err(HpetError::Counter32Bit));
+}
+
+#[test_case]
+fn test_tick_conversion() {
+    assert_eq!(ticks_to_ns(100, 10_000_000), 1_000);
+    assert_eq!(ns_to_ticks(5_000_000, 10_000_000), 500_000);
+    // 不足一个计数周期时向上取整
+    assert_eq!(ns_to_ticks(1, 69_841_279), 1);
+    assert_eq!(ns_to_ticks(0, 69_841_279), 0);
+}
+
+#[test_case]
+fn test_now_ns_monotonic() {
+    let Some(mut last) = now_ns() else {
+        return;
+    };
+    for _ in 0..1000 {
+        let now = now_ns().unwrap();
+        assert!(now >= last);
+        last = now;
+    }
+    assert!(now_femtos().unwrap() >= u128::from(last) * FEMTOS_PER_NS);
+}
+
+#[test_case]
+fn test_oneshot_interrupt() {
+    if oneshot_gsi().is_none() {
+        return;
+    }
+    let before = interrupt_count();
+    let start_ns = now_ns().unwrap();
+    let start_ticks = time::pit_ticks();
+
+    set_oneshot(5_000_000).unwrap();
+    time::wait_until(500, || interrupt_count() > before).expect("comparator interrupt not delivered");
+
+    assert!(now_ns().unwrap() - start_ns >= 5_000_000);
+    // PIT每个tick约55ms, 5ms的定时最多跨过一次时钟中断
+    assert!(time::pit_ticks() - start_ticks <= time::ms_to_ticks(5) + 1);
+    assert!(!oneshot_pending());
+}
@@ -1,16 +1,72 @@
-use lazy_static::lazy_static;
-use pic8259::ChainedPics;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use conquer_once::spin::OnceCell;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
-use crate::{hlt_loop, print, println};
+use crate::{earlycon, early_println, fault, hlt_loop, print, println, profile};
 use crate::gdt::DOUBLE_FAULT_IST_INDEX;
+use crate::io::HardwareBus;
+use crate::pic::ChainedPics;
+use crate::shell::{self, CmdError};
+use crate::trace::EventId;
+use crate::usermode::{self, KernelGs};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
-    ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
-});
+pub static PICS: spin::Mutex<ChainedPics<HardwareBus>> = spin::Mutex::new(ChainedPics::new(
+    unsafe { HardwareBus::new() },
+    PIC_1_OFFSET,
+    PIC_2_OFFSET,
+));
+
+// 测试用: 置位时时钟中断每次输出一整行标记而不是"."
+#[cfg(test)]
+static TIMER_PRINT_MARKER: AtomicBool = AtomicBool::new(false);
+#[cfg(test)]
+pub(crate) const TIMER_MARKER: &str = "<timer tick>";
+
+#[cfg(test)]
+pub(crate) fn set_timer_print_marker(enabled: bool) {
+    TIMER_PRINT_MARKER.store(enabled, Ordering::SeqCst);
+}
+
+// 非测试模式下时钟中断是否输出"."
+static PRINT_TICKS: AtomicBool = AtomicBool::new(true);
+
+// 各IRQ收到的中断次数
+static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+/// 各IRQ收到的中断次数, 下标为IRQ号
+pub fn irq_counts() -> [u64; 16] {
+    core::array::from_fn(|irq| IRQ_COUNTS[irq].load(Ordering::Relaxed))
+}
+
+fn count_irq(irq: u8) {
+    IRQ_COUNTS[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// 注册`ints`命令
+pub fn register_commands() {
+    shell::register_command("ints", "interrupt counts per IRQ", ints_command)
+        .expect("duplicate interrupt command");
+}
+
+fn ints_command(_args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    for (irq, count) in irq_counts().iter().enumerate() {
+        if *count != 0 {
+            writeln!(out, "IRQ{:<3}{}", irq, count)?;
+        }
+    }
+    Ok(())
+}
+
+/// 打开或关闭时钟中断打印的"., 外壳运行时关闭以免打乱输入行
+pub fn set_print_ticks(enabled: bool) {
+    PRINT_TICKS.store(enabled, Ordering::Relaxed);
+}
 
 // 中断
 #[repr(u8)]
@@ -18,8 +74,13 @@ pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // IRQ12
+    Mouse = PIC_2_OFFSET + 4,
 }
 
+// 经IO-APIC投递的中断使用PIC之后的向量
+pub const HPET_VECTOR: u8 = PIC_2_OFFSET + 8;
+
 impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
@@ -30,93 +91,379 @@ impl InterruptIndex {
     }
 }
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
-        }
-        // 时钟中断
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        // 键盘中断
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
-        // 缺页中断
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt
+macro_rules! set_irq_handlers {
+    ($idt:ident, $($irq:literal),*) => {
+        $(
+            $idt[usize::from(PIC_1_OFFSET + $irq)].set_handler_fn(irq_handler::<$irq>);
+        )*
     };
 }
 
+static IDT: OnceCell<InterruptDescriptorTable> = OnceCell::uninit();
+
+fn build_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    // 断点和调试异常经gdbstub的入口保存全部寄存器, 用户代码可以用int3回到内核
+    unsafe {
+        idt.breakpoint
+            .set_handler_addr(crate::gdbstub::breakpoint_entry_addr())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        idt.debug.set_handler_addr(crate::gdbstub::debug_entry_addr());
+    }
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    }
+    // 时钟中断
+    idt[InterruptIndex::Timer.as_usize()]
+        .set_handler_fn(timer_interrupt_handler);
+    // 键盘中断
+    idt[InterruptIndex::Keyboard.as_usize()]
+        .set_handler_fn(keyboard_interrupt_handler);
+    // 鼠标中断
+    idt[InterruptIndex::Mouse.as_usize()]
+        .set_handler_fn(mouse_interrupt_handler);
+    // 其余IRQ分发给驱动注册的处理函数, IRQ2用于级联
+    set_irq_handlers!(idt, 3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 14, 15);
+    // HPET比较器中断和local APIC的伪中断
+    idt[usize::from(HPET_VECTOR)].set_handler_fn(hpet_interrupt_handler);
+    idt[usize::from(crate::apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+    // 系统调用
+    unsafe {
+        idt[usize::from(crate::syscall::SYSCALL_VECTOR)]
+            .set_handler_addr(crate::syscall::int80_handler_addr())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+    // 用户程序可能触发的其他异常
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    // 缺页中断
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt
+}
+
+/// 建立并加载IDT, 由`toy_os::init`调用. 重复调用会panic
 pub fn init_idt() {
-    IDT.load();
+    IDT.try_init_once(build_idt)
+        .expect("interrupts::init_idt called twice");
+    load_idt();
+}
+
+/// 在当前处理器上加载已经建立的IDT, AP启动时调用
+pub fn load_idt() {
+    IDT.try_get()
+        .expect("interrupts::load_idt called before init_idt")
+        .load();
+}
+
+// 驱动通过register_irq注册的处理函数, 在中断上下文中调用. PCI设备可能共用一条中断线
+const MAX_SHARED_HANDLERS: usize = 4;
+type IrqHandlers = [[Option<fn()>; MAX_SHARED_HANDLERS]; 16];
+static IRQ_HANDLERS: spin::Mutex<IrqHandlers> =
+    spin::Mutex::new([[None; MAX_SHARED_HANDLERS]; 16]);
+
+/// IRQ不允许注册或共享的处理函数已满
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqUnavailable;
+
+/// 为IRQ注册处理函数, 处理函数结束后自动发送EOI. 时钟、键盘、鼠标和级联IRQ不能注册
+///
+/// 共用同一IRQ的处理函数每次都会全部调用, 各自检查自己的设备是否发出了中断
+pub fn register_irq(irq: u8, handler: fn()) -> Result<(), IrqUnavailable> {
+    if matches!(irq, 0 | 1 | 2 | 12) || irq >= 16 {
+        return Err(IrqUnavailable);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = handlers[usize::from(irq)]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqUnavailable)?;
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+extern "x86-interrupt" fn irq_handler<const IRQ: u8>(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    crate::trace!(EventId::IrqEntry, PIC_1_OFFSET + IRQ);
+    let start = profile::now();
+    count_irq(IRQ);
+    let handlers = IRQ_HANDLERS.lock()[usize::from(IRQ)];
+    for handler in handlers.into_iter().flatten() {
+        handler();
+    }
+
+    PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + IRQ);
+    profile::handler_exit(PIC_1_OFFSET + IRQ, start);
+    crate::trace!(EventId::IrqExit, PIC_1_OFFSET + IRQ);
+    // 设备完成的请求可能唤醒了任务
+    crate::thread::on_wake_interrupt();
+}
+
+extern "x86-interrupt" fn hpet_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    crate::trace!(EventId::IrqEntry, HPET_VECTOR);
+    let start = profile::now();
+    crate::hpet::on_interrupt();
+    crate::apic::eoi();
+    profile::handler_exit(HPET_VECTOR, start);
+    crate::trace!(EventId::IrqExit, HPET_VECTOR);
+}
+
+// 伪中断不需要EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// 取消屏蔽指定的IRQ, 从片上的IRQ同时取消屏蔽级联用的IRQ2
+pub fn unmask_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| PICS.lock().unmask(irq));
+}
+
+/// 屏蔽指定的IRQ, 从片上的IRQ不屏蔽级联用的IRQ2
+pub fn mask_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| PICS.lock().mask(irq));
+}
+
+/// 指定的IRQ是否在PIC上被屏蔽
+pub fn irq_masked(irq: u8) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| PICS.lock().is_masked(irq))
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    usermode::exit_on_user_fault(&stack_frame, fault::DIVIDE_ERROR_VECTOR, 0, None);
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    usermode::exit_on_user_fault(&stack_frame, fault::INVALID_OPCODE_VECTOR, 0, None);
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    let privilege = usermode::privilege_level(&stack_frame);
+    if privilege == PrivilegeLevel::Ring0
+        && fault::recover(&mut stack_frame, fault::GENERAL_PROTECTION_VECTOR, error_code, None)
+    {
+        return;
+    }
+    if earlycon::active() {
+        early_println!(
+            "EXCEPTION: GENERAL PROTECTION FAULT during early boot (error code {:#x}) at {:?}",
+            error_code,
+            stack_frame.instruction_pointer
+        );
+        hlt_loop();
+    }
+    usermode::exit_on_user_fault(
+        &stack_frame,
+        fault::GENERAL_PROTECTION_VECTOR,
+        error_code,
+        None,
+    );
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT in {:?} (error code {:#x})\n{:#?}",
+        privilege, error_code, stack_frame
+    );
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+extern "x86-interrupt" fn page_fault_handler(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;
 
+    let _gs = KernelGs::enter(&stack_frame);
+    // 写入写时复制的页, 包括内核通过copy_to_user写入, 复制帧后重新执行
+    let write = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(write) && crate::memory::handle_cow_fault(Cr2::read()) == Ok(true) {
+        return;
+    }
+    // 第一次访问进程用mmap保留的页, 映射清零的页后重新执行
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && crate::process::handle_page_fault(
+            Cr2::read(),
+            error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+        )
+    {
+        return;
+    }
+    let privilege = usermode::privilege_level(&stack_frame);
+    if privilege == PrivilegeLevel::Ring0
+        && fault::recover(
+            &mut stack_frame,
+            fault::PAGE_FAULT_VECTOR,
+            error_code.bits(),
+            Some(Cr2::read()),
+        )
+    {
+        return;
+    }
+    if earlycon::active() {
+        // 只输出一行, earlycon的每一行都覆盖屏幕首行
+        early_println!(
+            "EXCEPTION: PAGE FAULT during early boot accessing {:?} ({:?}) at {:?}",
+            Cr2::read(),
+            error_code,
+            stack_frame.instruction_pointer
+        );
+        hlt_loop();
+    }
+    // 用户程序的错误只结束用户程序, 由进程退出时输出一行记录
+    usermode::exit_on_user_fault(
+        &stack_frame,
+        fault::PAGE_FAULT_VECTOR,
+        error_code.bits(),
+        Some(Cr2::read()),
+    );
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
+    println!("Fault Kind: {}", crate::layout::region_of(Cr2::read()).fault_kind());
     println!("Error Code: {:?}", error_code);
+    println!("Privilege Level: {:?}", privilege);
     println!("{:#?}", stack_frame);
+    let _ = crate::backtrace::print_exception(&mut crate::vga_buffer::Console, &stack_frame);
     hlt_loop();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame)
-{
-    use spin::{Mutex, MutexGuard};
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
-    }
-
-    let mut keybord: MutexGuard<Keyboard<layouts::Us104Key, ScancodeSet1>> = KEYBOARD.lock();
+    let _gs = KernelGs::enter(&stack_frame);
+    crate::trace!(EventId::IrqEntry, InterruptIndex::Keyboard.as_u8());
+    let start = profile::now();
+    count_irq(1);
     let mut port = Port::new(0x60);
-
     let scancode: u8 = unsafe { port.read() };
-    // 解构
-    if let Ok(Some(key_event)) = keybord.add_byte(scancode) {
-        // 解构
-        if let Some(key) = keybord.process_keyevent(key_event) {
-            // 模式匹配
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    // 键盘对命令的应答(0xFA/0xFE)不是扫描码
+    if !crate::ps2::handle_keyboard_response(scancode) {
+        crate::keyboard::handle_scancode(scancode);
     }
 
+    PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    profile::handler_exit(InterruptIndex::Keyboard.as_u8(), start);
+    crate::trace!(EventId::IrqExit, InterruptIndex::Keyboard.as_u8());
+    // 按键唤醒的任务在高优先级的启动线程中执行
+    crate::thread::on_wake_interrupt();
+}
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+extern "x86-interrupt" fn mouse_interrupt_handler(stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let _gs = KernelGs::enter(&stack_frame);
+    crate::trace!(EventId::IrqEntry, InterruptIndex::Mouse.as_u8());
+    let start = profile::now();
+    count_irq(12);
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    crate::mouse::handle_byte(byte);
+
+    PICS.lock().notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    profile::handler_exit(InterruptIndex::Mouse.as_u8(), start);
+    crate::trace!(EventId::IrqExit, InterruptIndex::Mouse.as_u8());
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    crate::trace!(EventId::IrqEntry, InterruptIndex::Timer.as_u8());
+    let start = profile::now();
+    count_irq(0);
+    let now = crate::time::on_timer_interrupt();
+    crate::percpu::on_timer_interrupt();
+    crate::sched::on_timer_interrupt(now);
+    // 先检查测试是否超时, 避免测试持有WRITER锁时在print!处死锁
+    crate::check_test_deadline(now);
+
+    print_tick();
 
     // pic中断应该显示结束
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
+    PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    // 在可能的线程切换之前结束计时, 不把其他线程运行的时间算进来
+    profile::handler_exit(InterruptIndex::Timer.as_u8(), start);
+    crate::trace!(EventId::IrqExit, InterruptIndex::Timer.as_u8());
+    // 可能切换到别的线程, 之后的时钟中断由那个线程处理
+    crate::thread::on_timer_interrupt();
+}
+
+#[cfg(not(test))]
+fn print_tick() {
+    if PRINT_TICKS.load(Ordering::Relaxed) {
+        print!(".");
     }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+#[cfg(test)]
+fn print_tick() {
+    if TIMER_PRINT_MARKER.load(Ordering::SeqCst) {
+        println!("{}", TIMER_MARKER);
+    } else if PRINT_TICKS.load(Ordering::Relaxed) {
+        print!(".");
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    let _gs = KernelGs::enter(&stack_frame);
+    if earlycon::active() {
+        early_println!(
+            "EXCEPTION: DOUBLE FAULT during early boot at {:?}",
+            stack_frame.instruction_pointer
+        );
+        hlt_loop();
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 #[test_case]
 fn test_breakpoint_interrupt() {
     x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn test_timer_irq_count() {
+    let before = irq_counts()[0];
+    crate::time::wait_until(1000, || irq_counts()[0] > before).expect("timer IRQ not counted");
+}
+
+// 中断处理函数与主流程同时打印, 每次write_fmt的输出都必须完整连续,
+// _print去掉without_interrupts时本测试会死锁
+#[test_case]
+fn test_print_from_interrupt_does_not_interleave() {
+    use crate::time::pit_ticks;
+    use crate::vga_buffer::{start_recording, stop_recording};
+
+    const BODY_LINE: &str = "body line: the quick brown fox jumps over the lazy dog";
+    const MIN_ITERATIONS: usize = 200;
+    const MIN_TICKS: u64 = 3;
+
+    TIMER_PRINT_MARKER.store(true, Ordering::SeqCst);
+    start_recording(32 * 1024);
+
+    let start = pit_ticks();
+    let mut i = 0;
+    while i < MIN_ITERATIONS || pit_ticks() < start + MIN_TICKS {
+        println!("{} {:04}", BODY_LINE, i);
+        i += 1;
+    }
+
+    let transcript = stop_recording();
+    TIMER_PRINT_MARKER.store(false, Ordering::SeqCst);
+
+    let mut markers = 0;
+    let mut bodies = 0;
+    // 最后一段可能因记录写满而不完整, 只检查以换行结束的行
+    let complete = &transcript[..transcript.rfind('\n').map_or(0, |end| end + 1)];
+    for line in complete.lines() {
+        if line == TIMER_MARKER {
+            markers += 1;
+        } else {
+            assert!(line.starts_with(BODY_LINE), "interleaved output: {:?}", line);
+            assert_eq!(line.len(), BODY_LINE.len() + 5, "interleaved output: {:?}", line);
+            bodies += 1;
+        }
+    }
+    assert!(bodies > 0);
+    assert!(markers > 0, "timer handler never printed during the test");
 }
\ No newline at end of file
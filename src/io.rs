@@ -0,0 +1,53 @@
+use x86_64::instructions::port::Port;
+
+#[cfg(test)]
+pub mod mock;
+
+/// I/O端口的访问方式. 驱动通过它读写端口, 测试时替换为`mock::MockBus`
+pub trait PortBus {
+    fn read_u8(&mut self, port: u16) -> u8;
+    fn read_u16(&mut self, port: u16) -> u16;
+    fn read_u32(&mut self, port: u16) -> u32;
+    fn write_u8(&mut self, port: u16, value: u8);
+    fn write_u16(&mut self, port: u16, value: u16);
+    fn write_u32(&mut self, port: u16, value: u32);
+}
+
+/// 通过in/out指令访问真实的端口
+#[derive(Debug, Clone, Copy)]
+pub struct HardwareBus(());
+
+impl HardwareBus {
+    /// # Safety
+    ///
+    /// 调用者需保证通过它访问的端口不会破坏内存安全, 比如不会让设备DMA到任意地址
+    pub const unsafe fn new() -> Self {
+        HardwareBus(())
+    }
+}
+
+impl PortBus for HardwareBus {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        unsafe { Port::new(port).write(value) }
+    }
+}
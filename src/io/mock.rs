@@ -0,0 +1,151 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use super::PortBus;
+
+/// 一次端口写入, 记录宽度以便检查驱动使用了正确的指令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortWrite {
+    U8(u16, u8),
+    U16(u16, u16),
+    U32(u16, u32),
+}
+
+// 写入某个值之后才出现在端口上的数据, 模拟设备对命令的应答
+struct Reply {
+    write: PortWrite,
+    data_port: u16,
+    data: Vec<u32>,
+}
+
+/// 记录所有写入并按端口依次返回脚本中的值的测试总线
+///
+/// 读取没有待读数据的端口会panic, 除非它是用`ready_flag`声明的状态端口
+#[derive(Default)]
+pub struct MockBus {
+    writes: Vec<PortWrite>,
+    reads: BTreeMap<u16, VecDeque<u32>>,
+    replies: VecDeque<Reply>,
+    // (状态端口, 数据端口, 标志): 数据端口有待读数据时读状态端口得到标志, 否则为0
+    ready: Option<(u16, u16, u32)>,
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        MockBus::default()
+    }
+
+    /// 之后依次读取`port`时返回`values`
+    pub fn respond(&mut self, port: u16, values: &[u32]) -> &mut Self {
+        self.reads.entry(port).or_default().extend(values);
+        self
+    }
+
+    /// 发生`write`后, `data_port`上出现`data`. 应答按声明的顺序依次等待匹配的写入
+    pub fn reply_after(&mut self, write: PortWrite, data_port: u16, data: &[u32]) -> &mut Self {
+        self.replies.push_back(Reply {
+            write,
+            data_port,
+            data: data.to_vec(),
+        });
+        self
+    }
+
+    /// 读`status_port`时, `data_port`有待读数据则返回`flag`, 否则返回0
+    pub fn ready_flag(&mut self, status_port: u16, data_port: u16, flag: u32) -> &mut Self {
+        self.ready = Some((status_port, data_port, flag));
+        self
+    }
+
+    /// 到目前为止的所有写入
+    pub fn writes(&self) -> &[PortWrite] {
+        &self.writes
+    }
+
+    /// 脚本中的数据和应答是否都已被使用
+    pub fn finished(&self) -> bool {
+        self.replies.is_empty() && self.reads.values().all(VecDeque::is_empty)
+    }
+
+    fn pending(&self, port: u16) -> bool {
+        self.reads
+            .get(&port)
+            .is_some_and(|values| !values.is_empty())
+    }
+
+    fn read(&mut self, port: u16) -> u32 {
+        if let Some((status_port, data_port, flag)) = self.ready {
+            if port == status_port && !self.pending(status_port) {
+                return if self.pending(data_port) { flag } else { 0 };
+            }
+        }
+        self.reads
+            .get_mut(&port)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or_else(|| panic!("unexpected read of port {:#x}", port))
+    }
+
+    fn write(&mut self, write: PortWrite) {
+        self.writes.push(write);
+        if let Some(reply) = self.replies.pop_front_if(|reply| reply.write == write) {
+            self.respond(reply.data_port, &reply.data);
+        }
+    }
+}
+
+impl PortBus for MockBus {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        self.read(port) as u8
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        self.read(port) as u16
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        self.read(port)
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.write(PortWrite::U8(port, value));
+    }
+
+    fn write_u16(&mut self, port: u16, value: u16) {
+        self.write(PortWrite::U16(port, value));
+    }
+
+    fn write_u32(&mut self, port: u16, value: u32) {
+        self.write(PortWrite::U32(port, value));
+    }
+}
+
+#[test_case]
+fn test_mock_bus() {
+    use PortWrite::{U16, U8};
+
+    let mut bus = MockBus::new();
+    bus.respond(0x10, &[1, 2])
+        .reply_after(U8(0x20, 0xAA), 0x21, &[0x55])
+        .ready_flag(0x22, 0x21, 0x01);
+    assert_eq!(bus.read_u8(0x10), 1);
+    assert_eq!(bus.read_u16(0x10), 2);
+    assert_eq!(bus.read_u8(0x22), 0);
+    // 值或宽度不同的写入不触发应答
+    bus.write_u8(0x20, 0xAB);
+    bus.write_u16(0x20, 0xAA);
+    assert_eq!(bus.read_u8(0x22), 0);
+    bus.write_u8(0x20, 0xAA);
+    assert_eq!(bus.read_u8(0x22), 0x01);
+    assert_eq!(bus.read_u8(0x21), 0x55);
+    bus.write_u16(0x30, 0x1234);
+    assert_eq!(
+        bus.writes(),
+        [
+            U8(0x20, 0xAB),
+            U16(0x20, 0xAA),
+            U8(0x20, 0xAA),
+            U16(0x30, 0x1234)
+        ]
+    );
+    assert!(bus.finished());
+}
@@ -0,0 +1,356 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use conquer_once::spin::OnceCell;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::FutureExt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyState, Keyboard, ScancodeSet1};
+use spin::Mutex;
+
+use crate::profile;
+use crate::shell::{self, CmdError, CommandFuture};
+use crate::sync::IrqMutex;
+use crate::task::channel::Channel;
+use crate::trace::{self, EventId};
+use crate::vfs;
+use crate::vga_buffer::console;
+use crate::{print, println};
+
+pub mod hotkey;
+pub mod keymap;
+
+pub use hotkey::{Hotkey, HotkeyFilter};
+pub use keymap::{Keymap, KeymapError};
+
+use keymap::{LayoutState, Mapped};
+
+const KEY_QUEUE_SIZE: usize = 100;
+
+/// 布局文件所在的目录, 其中的`<名称>.kmap`可以用名称选择
+pub const KEYMAP_DIR: &str = "/etc/keymaps";
+/// 内置布局的名称
+pub const BUILTIN_LAYOUT: &str = "us";
+
+type UsDecoder = Keyboard<layouts::Us104Key, ScancodeSet1>;
+
+// 内置的US布局解码器和载入的布局所需的状态, 两者看到同样的扫描码
+struct Decoder {
+    keyboard: UsDecoder,
+    layout: LayoutState,
+}
+
+static KEYBOARD: OnceCell<Mutex<Decoder>> = OnceCell::uninit();
+// 只由键盘中断访问
+static HOTKEYS: Mutex<HotkeyFilter> = Mutex::new(HotkeyFilter::new());
+// 载入的布局, None时只用内置的US布局. 键盘中断加锁读取, 替换时旧的布局在锁外释放
+static KEYMAP: IrqMutex<Option<Box<Keymap>>> = IrqMutex::new("keyboard::KEYMAP", None);
+
+// 每个按键带着送出的时刻, 取走时计入`profile`的输入延迟
+static KEYS: Channel<(DecodedKey, profile::Stamp)> = Channel::new(KEY_QUEUE_SIZE);
+// 有了接收端之后按键不再直接回显
+static HAS_CONSUMER: AtomicBool = AtomicBool::new(false);
+
+/// 创建扫描码解码器, 由`toy_os::init`在开中断之前调用. 重复调用会panic
+pub fn init() {
+    KEYBOARD
+        .try_init_once(|| {
+            Mutex::new(Decoder {
+                keyboard: us_decoder(),
+                layout: LayoutState::new(),
+            })
+        })
+        .expect("keyboard::init called twice");
+}
+
+fn us_decoder() -> UsDecoder {
+    Keyboard::new(
+        layouts::Us104Key,
+        ScancodeSet1,
+        HandleControl::MapLettersToUnicode,
+    )
+}
+
+// 一个扫描码的解码结果
+#[derive(Debug, PartialEq, Eq)]
+enum Input {
+    Hotkey(Hotkey),
+    Key(DecodedKey),
+}
+
+// 快捷键在解码之前被取出, 不会改变解码器的状态. 载入的布局中有的键按布局解码, 其他键按US布局
+fn decode(
+    decoder: &mut Decoder,
+    keymap: Option<&Keymap>,
+    hotkeys: &mut HotkeyFilter,
+    scancode: u8,
+) -> Option<Input> {
+    let code = decoder.layout.scancode(scancode);
+    let Ok(Some(key_event)) = decoder.keyboard.add_byte(scancode) else {
+        return None;
+    };
+    // 锁定键切换时同步指示灯
+    crate::ps2::update_lock_leds(&key_event);
+    if let Some(hotkey) = hotkeys.filter(&key_event) {
+        return Some(Input::Hotkey(hotkey));
+    }
+    let modifier = decoder.layout.modifier(&key_event);
+    let pressed = key_event.state == KeyState::Down;
+    let us = decoder.keyboard.process_keyevent(key_event);
+    if let (Some(keymap), Some(code), true, false) = (keymap, code, pressed, modifier) {
+        match decoder.layout.map(keymap, code) {
+            Mapped::Char(c) => return Some(Input::Key(DecodedKey::Unicode(c))),
+            Mapped::Consumed => return None,
+            Mapped::Unmapped => {}
+        }
+    }
+    us.map(Input::Key)
+}
+
+/// 由键盘中断调用, 解码扫描码并发送给接收端. 初始化之前的扫描码被丢弃
+pub(crate) fn handle_scancode(scancode: u8) {
+    crate::trace!(EventId::Scancode, scancode);
+    let Ok(keyboard) = KEYBOARD.try_get() else {
+        return;
+    };
+    let input = decode(
+        &mut keyboard.lock(),
+        KEYMAP.lock().as_deref(),
+        &mut HOTKEYS.lock(),
+        scancode,
+    );
+    let key = match input {
+        None => return,
+        // 快捷键不送给接收端
+        Some(Input::Hotkey(hotkey)) => {
+            console::handle_hotkey(hotkey);
+            return;
+        }
+        Some(Input::Key(key)) => key,
+    };
+    crate::trace!(EventId::KeyDecoded, trace::key_arg(&key));
+    // 其他按键回到回滚历史的底部
+    console::scroll_to_bottom();
+    if HAS_CONSUMER.load(Ordering::Relaxed) {
+        KEYS.push((key, profile::now()));
+    } else {
+        match key {
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        }
+    }
+}
+
+/// 按键流, 同一时刻只能有一个消费者
+pub fn keys() -> impl Stream<Item = DecodedKey> {
+    let stream = KEYS.stream();
+    HAS_CONSUMER.store(true, Ordering::Relaxed);
+    stream.map(|(key, sent)| {
+        profile::input_handled(sent);
+        key
+    })
+}
+
+/// 载入`path`处的布局文件并替换当前的布局, 出错时当前的布局不变
+pub async fn load_layout(path: &str) -> Result<(), KeymapError> {
+    let mut file = vfs::open(path).await?;
+    let mut data = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        match file.read(&mut buf).await? {
+            0 => break,
+            len => data.extend_from_slice(&buf[..len]),
+        }
+    }
+    let text = String::from_utf8(data).map_err(|_| KeymapError::NotUtf8)?;
+    let keymap = Box::new(Keymap::parse(&text)?);
+    let old = KEYMAP.lock().replace(keymap);
+    drop(old);
+    Ok(())
+}
+
+/// 按名称选择布局, `BUILTIN_LAYOUT`恢复内置的US布局, 其他名称从`KEYMAP_DIR`载入
+pub async fn select_layout(name: &str) -> Result<(), KeymapError> {
+    if name == BUILTIN_LAYOUT {
+        let old = KEYMAP.lock().take();
+        drop(old);
+        return Ok(());
+    }
+    load_layout(&alloc::format!("{}/{}.kmap", KEYMAP_DIR, name)).await
+}
+
+/// 当前布局的名称
+pub fn layout_name() -> String {
+    match KEYMAP.lock().as_deref() {
+        Some(keymap) => keymap.name.clone(),
+        None => BUILTIN_LAYOUT.into(),
+    }
+}
+
+/// 注册`keymap`命令
+pub fn register_commands() {
+    shell::register_async_command(
+        "keymap",
+        "keymap [name]: list keyboard layouts or switch to one",
+        keymap_command,
+    )
+    .expect("duplicate keyboard command");
+}
+
+// 不带参数时列出内置布局和布局目录中的文件, 当前的布局标有`*`
+fn keymap_command<'a>(
+    args: &'a [&'a str],
+    out: &'a mut (dyn fmt::Write + Send),
+) -> CommandFuture<'a> {
+    Box::pin(async move {
+        match args {
+            [] => {
+                let active = layout_name();
+                let mut names = alloc::vec![String::from(BUILTIN_LAYOUT)];
+                // 没有布局目录时只有内置布局
+                if let Ok(entries) = vfs::readdir(KEYMAP_DIR).await {
+                    names.extend(entries.into_iter().filter_map(|entry| {
+                        let name = entry.name.strip_suffix(".kmap")?;
+                        (!entry.metadata.is_dir()).then(|| name.into())
+                    }));
+                }
+                for name in names {
+                    let marker = if name == active { '*' } else { ' ' };
+                    writeln!(out, "{} {}", marker, name)?;
+                }
+                Ok(())
+            }
+            [name] => {
+                select_layout(name).await?;
+                writeln!(out, "keymap: {}", layout_name())?;
+                Ok(())
+            }
+            _ => Err(CmdError::Usage("keymap [name]")),
+        }
+    })
+}
+
+/// 启动时按命令行的`keymap=<名称>`选择布局
+pub(crate) fn apply_cmdline(name: &str) {
+    // 布局目录在启动时挂载的initrd中, 载入不会等待
+    if let Some(Err(err)) = select_layout(name).now_or_never() {
+        println!("cmdline: keymap={}: {}", name, err);
+    }
+}
+
+#[cfg(test)]
+fn test_decoder() -> Decoder {
+    Decoder {
+        keyboard: us_decoder(),
+        layout: LayoutState::new(),
+    }
+}
+
+// 按扫描码模拟按键, 返回解码的结果
+#[cfg(test)]
+fn type_scancodes(
+    decoder: &mut Decoder,
+    keymap: Option<&Keymap>,
+    hotkeys: &mut HotkeyFilter,
+    scancodes: &[u8],
+) -> alloc::vec::Vec<Input> {
+    scancodes
+        .iter()
+        .filter_map(|&scancode| decode(decoder, keymap, hotkeys, scancode))
+        .collect()
+}
+
+#[test_case]
+fn test_hotkeys_switch_consoles() {
+    use crate::vga_buffer::ConsoleId;
+    use pc_keyboard::KeyCode;
+
+    // Alt按下, F2按下, F2松开, Alt松开
+    const ALT_F2: [u8; 4] = [0x38, 0x3C, 0xBC, 0xB8];
+    const ALT_F1: [u8; 4] = [0x38, 0x3B, 0xBB, 0xB8];
+    // 右Shift, PageUp和PageDown是E0前缀的扩展键
+    const SHIFT_PAGE_UP: [u8; 6] = [0x36, 0xE0, 0x49, 0xE0, 0xC9, 0xB6];
+    const SHIFT_PAGE_DOWN: [u8; 6] = [0x36, 0xE0, 0x51, 0xE0, 0xD1, 0xB6];
+    const CTRL_L: [u8; 4] = [0x1D, 0x26, 0xA6, 0x9D];
+
+    let mut keyboard = test_decoder();
+    let mut hotkeys = HotkeyFilter::new();
+    let mut consoles = console::test_consoles();
+    let mut press = |scancodes: &[u8]| type_scancodes(&mut keyboard, None, &mut hotkeys, scancodes);
+
+    // 修饰键本身可能被解码为RawKey, 只看快捷键
+    let hotkey = |input: &[Input]| {
+        let mut hotkeys = input.iter().filter_map(|input| match input {
+            Input::Hotkey(hotkey) => Some(*hotkey),
+            Input::Key(_) => None,
+        });
+        let hotkey = hotkeys.next().expect("no hotkey decoded");
+        assert_eq!(hotkeys.next(), None);
+        hotkey
+    };
+    let switch = hotkey(&press(&ALT_F1));
+    assert_eq!(switch, Hotkey::Switch(ConsoleId::Shell));
+    consoles.handle(switch);
+    assert_eq!(consoles.active(), ConsoleId::Shell);
+    consoles.handle(hotkey(&press(&ALT_F2)));
+    assert_eq!(consoles.active(), ConsoleId::Log);
+    // 再按一次不变
+    consoles.handle(hotkey(&press(&ALT_F2)));
+    assert_eq!(consoles.active(), ConsoleId::Log);
+
+    for i in 0..40 {
+        consoles.writer(ConsoleId::Log).write_byte(b'0' + i % 10);
+        consoles.writer(ConsoleId::Log).write_byte(b'\n');
+    }
+    consoles.handle(hotkey(&press(&SHIFT_PAGE_UP)));
+    assert_eq!(consoles.view(), console::SCROLL_STEP);
+    consoles.handle(hotkey(&press(&SHIFT_PAGE_DOWN)));
+    assert_eq!(consoles.view(), 0);
+    assert_eq!(hotkey(&press(&CTRL_L)), Hotkey::Clear);
+
+    // 其他按键照常解码, 包括不带Alt的F1和不带Shift的PageUp
+    assert_eq!(
+        press(&[0x1E, 0x9E, 0x3B, 0xBB, 0xE0, 0x49, 0xE0, 0xC9]),
+        [
+            Input::Key(DecodedKey::Unicode('a')),
+            Input::Key(DecodedKey::RawKey(KeyCode::F1)),
+            Input::Key(DecodedKey::RawKey(KeyCode::PageUp)),
+        ]
+    );
+    // 快捷键没有影响解码器的修饰键状态
+    assert_eq!(
+        press(&[0x2A, 0x1E, 0x9E, 0xAA]),
+        [Input::Key(DecodedKey::Unicode('A'))]
+    );
+    assert_eq!(consoles.active(), ConsoleId::Log);
+}
+
+#[test_case]
+fn test_keymap_decoding() {
+    let keymap = Keymap::parse(keymap::FIXTURE).unwrap();
+    let mut decoder = test_decoder();
+    let mut hotkeys = HotkeyFilter::new();
+    let mut press = |scancodes: &[u8]| {
+        type_scancodes(&mut decoder, Some(&keymap), &mut hotkeys, scancodes)
+            .into_iter()
+            .filter_map(|input| match input {
+                Input::Key(DecodedKey::Unicode(c)) => Some(c),
+                _ => None,
+            })
+            .collect::<String>()
+    };
+    // ´ + e, Shift + ´ (即`) + e, ´ + Shift + e
+    assert_eq!(press(&[0x0D, 0x8D, 0x12, 0x92]), "é");
+    assert_eq!(press(&[0x2A, 0x0D, 0x8D, 0xAA, 0x12, 0x92]), "è");
+    assert_eq!(press(&[0x0D, 0x8D, 0x2A, 0x12, 0x92, 0xAA]), "É");
+    // 不能组合时丢弃死键, 连按两次得到死键本身
+    assert_eq!(press(&[0x0D, 0x8D, 0x1F, 0x9F]), "s");
+    assert_eq!(press(&[0x0D, 0x8D, 0x0D, 0x8D]), "´");
+    // Z和Y互换, AltGr是E0前缀的右Alt
+    assert_eq!(press(&[0x15, 0x95, 0x2C, 0xAC]), "zy");
+    assert_eq!(press(&[0xE0, 0x38, 0x10, 0x90, 0xE0, 0xB8]), "@");
+    // 布局中没有的键按US布局解码
+    assert_eq!(press(&[0x02, 0x82, 0x2A, 0x02, 0x82, 0xAA]), "1!");
+}
@@ -0,0 +1,103 @@
+//! 全局快捷键, 在按键解码和送给接收端之前处理
+//!
+//! 解码器自己记录的修饰键状态不对外公开, 这里根据按键事件另外记录一份
+
+use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+
+use crate::vga_buffer::ConsoleId;
+
+/// 改为全局处理的按键组合
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    /// Alt+F1和Alt+F2, 切换虚拟控制台
+    Switch(ConsoleId),
+    /// Shift+PageUp
+    ScrollUp,
+    /// Shift+PageDown
+    ScrollDown,
+    /// Ctrl+L
+    Clear,
+}
+
+const SHIFT: u8 = 0b0000_0011;
+const CONTROL: u8 = 0b0000_1100;
+const ALT: u8 = 0b0011_0000;
+
+// 左右两个修饰键分别记录, 松开其中一个时另一个仍然有效
+fn modifier(code: KeyCode) -> Option<u8> {
+    match code {
+        KeyCode::ShiftLeft => Some(0b0000_0001),
+        KeyCode::ShiftRight => Some(0b0000_0010),
+        KeyCode::ControlLeft => Some(0b0000_0100),
+        KeyCode::ControlRight => Some(0b0000_1000),
+        KeyCode::AltLeft => Some(0b0001_0000),
+        KeyCode::AltRight => Some(0b0010_0000),
+        _ => None,
+    }
+}
+
+/// 从按键事件中识别快捷键
+pub struct HotkeyFilter {
+    modifiers: u8,
+}
+
+impl HotkeyFilter {
+    pub const fn new() -> Self {
+        HotkeyFilter { modifiers: 0 }
+    }
+
+    /// 按下快捷键时返回它, 这个事件不应再交给解码器. 修饰键和松开按键的事件总是返回None
+    pub fn filter(&mut self, event: &KeyEvent) -> Option<Hotkey> {
+        if let Some(bit) = modifier(event.code) {
+            if event.state == KeyState::Down {
+                self.modifiers |= bit;
+            } else {
+                self.modifiers &= !bit;
+            }
+            return None;
+        }
+        if event.state != KeyState::Down {
+            return None;
+        }
+        let held = |mask: u8| self.modifiers & mask != 0;
+        match event.code {
+            KeyCode::F1 if held(ALT) => Some(Hotkey::Switch(ConsoleId::Shell)),
+            KeyCode::F2 if held(ALT) => Some(Hotkey::Switch(ConsoleId::Log)),
+            KeyCode::PageUp if held(SHIFT) => Some(Hotkey::ScrollUp),
+            KeyCode::PageDown if held(SHIFT) => Some(Hotkey::ScrollDown),
+            KeyCode::L if held(CONTROL) => Some(Hotkey::Clear),
+            _ => None,
+        }
+    }
+}
+
+impl Default for HotkeyFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_modifiers() {
+    let event = |code, state| KeyEvent { code, state };
+    let mut filter = HotkeyFilter::new();
+    assert_eq!(filter.filter(&event(KeyCode::F1, KeyState::Down)), None);
+    filter.filter(&event(KeyCode::AltLeft, KeyState::Down));
+    filter.filter(&event(KeyCode::AltRight, KeyState::Down));
+    filter.filter(&event(KeyCode::AltLeft, KeyState::Up));
+    // 右Alt还按着
+    assert_eq!(
+        filter.filter(&event(KeyCode::F2, KeyState::Down)),
+        Some(Hotkey::Switch(ConsoleId::Log))
+    );
+    assert_eq!(filter.filter(&event(KeyCode::F2, KeyState::Up)), None);
+    filter.filter(&event(KeyCode::AltRight, KeyState::Up));
+    assert_eq!(filter.filter(&event(KeyCode::F2, KeyState::Down)), None);
+    // Alt不影响Shift的组合
+    filter.filter(&event(KeyCode::ShiftRight, KeyState::Down));
+    assert_eq!(
+        filter.filter(&event(KeyCode::PageDown, KeyState::Down)),
+        Some(Hotkey::ScrollDown)
+    );
+    assert_eq!(filter.filter(&event(KeyCode::L, KeyState::Down)), None);
+}
@@ -0,0 +1,599 @@
+//! 从文本文件载入的键盘布局
+//!
+//! 每行一条指令, `#`开头的行和空行被忽略:
+//!
+//! ```text
+//! name de
+//! # key <扫描码> <不按Shift> [<Shift> [<AltGr>]]
+//! key 0x10 q Q @
+//! key 0x0D dead:acute dead:grave
+//! # dead <名称> <单独输入时的字符> <基础字符>=<组合结果>...
+//! dead acute ´ e=é E=É
+//! ```
+//!
+//! 扫描码是第1套的按下码, E0前缀的扩展键写作0xE0xx. 字符写作字符本身、`U+xxxx`,
+//! 或者`dead:<名称>`表示死键, `-`表示这一列没有字符. 表中没有的键按内置的US布局解码
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+
+use crate::error::KernelError;
+
+// 布局必须定义的键: 三排字母键和空格
+const REQUIRED: [core::ops::RangeInclusive<u16>; 4] =
+    [0x10..=0x19, 0x1E..=0x26, 0x2C..=0x32, 0x39..=0x39];
+// 扩展键的前缀
+const EXTENDED: u16 = 0xE000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapError {
+    Vfs(KernelError),
+    NotUtf8,
+    /// 不认识的指令, 或者参数的个数、格式不对
+    Syntax {
+        line: usize,
+    },
+    MissingName,
+    /// 第1套的按下码在0x01到0x58之间, 扩展键在0xE001到0xE07F之间
+    ScancodeOutOfRange {
+        line: usize,
+        scancode: u32,
+    },
+    DuplicateScancode {
+        line: usize,
+        scancode: u16,
+        first: usize,
+    },
+    /// 不是单个字符、`U+xxxx`、`dead:<名称>`或`-`
+    BadSymbol {
+        line: usize,
+    },
+    /// 超出Unicode范围或者是代理码点
+    CodepointOutOfRange {
+        line: usize,
+        codepoint: u32,
+    },
+    /// `dead:<名称>`引用的死键没有定义
+    UnknownDeadKey {
+        line: usize,
+    },
+    DuplicateDeadKey {
+        line: usize,
+    },
+    MissingKey {
+        scancode: u16,
+    },
+}
+
+impl From<KernelError> for KeymapError {
+    fn from(err: KernelError) -> Self {
+        KeymapError::Vfs(err)
+    }
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeymapError::Vfs(err) => write!(f, "cannot read keymap: {}", err),
+            KeymapError::NotUtf8 => write!(f, "keymap is not valid UTF-8"),
+            KeymapError::Syntax { line } => write!(f, "line {}: syntax error", line),
+            KeymapError::MissingName => write!(f, "keymap has no name line"),
+            KeymapError::ScancodeOutOfRange { line, scancode } => {
+                write!(f, "line {}: scancode {:#x} out of range", line, scancode)
+            }
+            KeymapError::DuplicateScancode {
+                line,
+                scancode,
+                first,
+            } => write!(
+                f,
+                "line {}: scancode {:#x} already defined on line {}",
+                line, scancode, first
+            ),
+            KeymapError::BadSymbol { line } => write!(f, "line {}: bad character", line),
+            KeymapError::CodepointOutOfRange { line, codepoint } => {
+                write!(f, "line {}: U+{:04X} is not a character", line, codepoint)
+            }
+            KeymapError::UnknownDeadKey { line } => {
+                write!(f, "line {}: undefined dead key", line)
+            }
+            KeymapError::DuplicateDeadKey { line } => {
+                write!(f, "line {}: dead key already defined", line)
+            }
+            KeymapError::MissingKey { scancode } => {
+                write!(f, "required scancode {:#x} not defined", scancode)
+            }
+        }
+    }
+}
+
+/// 键的一列
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbol {
+    Char(char),
+    /// `Keymap::dead_keys`中的下标
+    Dead(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadKey {
+    pub name: String,
+    /// 死键之后输入空格或再按一次死键时得到的字符
+    pub spacing: char,
+    /// 基础字符和组合结果
+    pub compose: Vec<(char, char)>,
+}
+
+impl DeadKey {
+    fn compose(&self, base: char) -> Option<char> {
+        self.compose
+            .iter()
+            .find(|&&(from, _)| from == base)
+            .map(|&(_, composed)| composed)
+    }
+}
+
+// 列的下标
+const PLAIN: usize = 0;
+const SHIFT: usize = 1;
+const ALTGR: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    pub name: String,
+    keys: BTreeMap<u16, [Option<Symbol>; 3]>,
+    dead_keys: Vec<DeadKey>,
+}
+
+impl Keymap {
+    /// 解析并检查布局文件的内容
+    pub fn parse(text: &str) -> Result<Keymap, KeymapError> {
+        let lines = || {
+            text.lines()
+                .enumerate()
+                .map(|(index, line)| (index + 1, line.trim()))
+                .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        };
+
+        // 先收集死键, 键的定义可以引用后面定义的死键
+        let mut dead_keys = Vec::new();
+        for (line, text) in lines() {
+            let mut words = text.split_whitespace();
+            if words.next() != Some("dead") {
+                continue;
+            }
+            let name = words.next().ok_or(KeymapError::Syntax { line })?;
+            if dead_keys.iter().any(|dead: &DeadKey| dead.name == name) {
+                return Err(KeymapError::DuplicateDeadKey { line });
+            }
+            let spacing = match words.next().map(|word| parse_symbol(word, line, &[])) {
+                Some(Ok(Some(Symbol::Char(c)))) => c,
+                Some(Err(err)) => return Err(err),
+                _ => return Err(KeymapError::Syntax { line }),
+            };
+            let mut compose = Vec::new();
+            for pair in words {
+                let (base, composed) = pair.split_once('=').ok_or(KeymapError::Syntax { line })?;
+                match (
+                    parse_symbol(base, line, &[])?,
+                    parse_symbol(composed, line, &[])?,
+                ) {
+                    (Some(Symbol::Char(base)), Some(Symbol::Char(composed))) => {
+                        compose.push((base, composed))
+                    }
+                    _ => return Err(KeymapError::BadSymbol { line }),
+                }
+            }
+            dead_keys.push(DeadKey {
+                name: name.into(),
+                spacing,
+                compose,
+            });
+        }
+
+        let mut name = None;
+        let mut keys = BTreeMap::new();
+        // 各扫描码第一次定义的行号
+        let mut defined = BTreeMap::new();
+        for (line, text) in lines() {
+            let mut words = text.split_whitespace();
+            match words.next() {
+                Some("name") => match (words.next(), words.next(), &name) {
+                    (Some(value), None, None) => name = Some(String::from(value)),
+                    _ => return Err(KeymapError::Syntax { line }),
+                },
+                Some("key") => {
+                    let scancode = parse_scancode(words.next(), line)?;
+                    let mut columns = [None; 3];
+                    let mut count = 0;
+                    for word in words {
+                        if count == columns.len() {
+                            return Err(KeymapError::Syntax { line });
+                        }
+                        columns[count] = parse_symbol(word, line, &dead_keys)?;
+                        count += 1;
+                    }
+                    if count == 0 {
+                        return Err(KeymapError::Syntax { line });
+                    }
+                    if let Some(&first) = defined.get(&scancode) {
+                        return Err(KeymapError::DuplicateScancode {
+                            line,
+                            scancode,
+                            first,
+                        });
+                    }
+                    defined.insert(scancode, line);
+                    keys.insert(scancode, columns);
+                }
+                Some("dead") => {}
+                _ => return Err(KeymapError::Syntax { line }),
+            }
+        }
+
+        let name = name.ok_or(KeymapError::MissingName)?;
+        if let Some(scancode) = REQUIRED
+            .iter()
+            .flat_map(|range| range.clone())
+            .find(|scancode| !keys.contains_key(scancode))
+        {
+            return Err(KeymapError::MissingKey { scancode });
+        }
+        Ok(Keymap {
+            name,
+            keys,
+            dead_keys,
+        })
+    }
+
+    pub fn dead_keys(&self) -> &[DeadKey] {
+        &self.dead_keys
+    }
+
+    /// `scancode`在`column`列的字符, 没有定义时返回None
+    pub fn symbol(&self, scancode: u16, column: usize) -> Option<Symbol> {
+        *self.keys.get(&scancode)?.get(column)?
+    }
+}
+
+// "0x1E"或"0xE035"
+fn parse_scancode(word: Option<&str>, line: usize) -> Result<u16, KeymapError> {
+    let digits = word
+        .and_then(|word| word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")))
+        .ok_or(KeymapError::Syntax { line })?;
+    let scancode = u32::from_str_radix(digits, 16).map_err(|_| KeymapError::Syntax { line })?;
+    if !matches!(scancode, 0x01..=0x58 | 0xE001..=0xE07F) {
+        return Err(KeymapError::ScancodeOutOfRange { line, scancode });
+    }
+    Ok(scancode as u16)
+}
+
+// 一列的字符, "-"返回None. 解析死键本身时`dead_keys`为空, 不允许引用死键
+fn parse_symbol(
+    word: &str,
+    line: usize,
+    dead_keys: &[DeadKey],
+) -> Result<Option<Symbol>, KeymapError> {
+    if word == "-" {
+        return Ok(None);
+    }
+    if let Some(name) = word.strip_prefix("dead:") {
+        return dead_keys
+            .iter()
+            .position(|dead| dead.name == name)
+            .map(|index| Some(Symbol::Dead(index)))
+            .ok_or(KeymapError::UnknownDeadKey { line });
+    }
+    if let Some(digits) = word.strip_prefix("U+") {
+        let codepoint =
+            u32::from_str_radix(digits, 16).map_err(|_| KeymapError::BadSymbol { line })?;
+        return char::from_u32(codepoint)
+            .map(|c| Some(Symbol::Char(c)))
+            .ok_or(KeymapError::CodepointOutOfRange { line, codepoint });
+    }
+    let mut chars = word.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Some(Symbol::Char(c))),
+        _ => Err(KeymapError::BadSymbol { line }),
+    }
+}
+
+/// 按载入的布局解码时需要的状态. 修饰键由pc_keyboard另外记录, 这里记录一份用于选择列
+#[derive(Debug, Default)]
+pub struct LayoutState {
+    // 上一个字节是E0前缀
+    extended: bool,
+    shift: u8,
+    control: u8,
+    altgr: bool,
+    caps_lock: bool,
+    // 等待组合的死键
+    pending: Option<usize>,
+}
+
+/// 一次按键在载入的布局中的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapped {
+    Char(char),
+    /// 死键, 或者这一列没有字符
+    Consumed,
+    /// 布局中没有这个键, 按US布局解码
+    Unmapped,
+}
+
+impl LayoutState {
+    pub const fn new() -> Self {
+        LayoutState {
+            extended: false,
+            shift: 0,
+            control: 0,
+            altgr: false,
+            caps_lock: false,
+            pending: None,
+        }
+    }
+
+    /// 记录交给pc_keyboard的每个字节, 返回不含松开位的扫描码, 前缀字节返回None
+    pub fn scancode(&mut self, byte: u8) -> Option<u16> {
+        if byte == 0xE0 {
+            self.extended = true;
+            return None;
+        }
+        let code = u16::from(byte & 0x7F);
+        Some(if core::mem::take(&mut self.extended) {
+            EXTENDED | code
+        } else {
+            code
+        })
+    }
+
+    /// 更新修饰键的状态, 事件是修饰键时返回true
+    pub fn modifier(&mut self, event: &KeyEvent) -> bool {
+        let down = event.state == KeyState::Down;
+        let set = |bits: &mut u8, bit: u8| {
+            if down {
+                *bits |= bit;
+            } else {
+                *bits &= !bit;
+            }
+        };
+        match event.code {
+            KeyCode::ShiftLeft => set(&mut self.shift, 1),
+            KeyCode::ShiftRight => set(&mut self.shift, 2),
+            KeyCode::ControlLeft => set(&mut self.control, 1),
+            KeyCode::ControlRight => set(&mut self.control, 2),
+            KeyCode::AltRight => self.altgr = down,
+            KeyCode::CapsLock => self.caps_lock ^= down,
+            KeyCode::AltLeft => {}
+            _ => return false,
+        }
+        true
+    }
+
+    /// 按下`scancode`时在`keymap`中的结果. 按住Ctrl时按US布局解码, 以便输入控制字符
+    pub fn map(&mut self, keymap: &Keymap, scancode: u16) -> Mapped {
+        if self.control != 0 || !keymap.keys.contains_key(&scancode) {
+            self.pending = None;
+            return Mapped::Unmapped;
+        }
+        let column = if self.altgr {
+            ALTGR
+        } else {
+            // Caps Lock只影响字母
+            let letter = matches!(keymap.symbol(scancode, PLAIN), Some(Symbol::Char(c)) if c.is_alphabetic());
+            if (self.shift != 0) != (self.caps_lock && letter) {
+                SHIFT
+            } else {
+                PLAIN
+            }
+        };
+        let pending = self.pending.take();
+        let dead = pending.and_then(|index| keymap.dead_keys.get(index));
+        match (keymap.symbol(scancode, column), dead) {
+            (None, _) => Mapped::Consumed,
+            // 连按两次同一个死键得到它本身的字符, 按下另一个死键时改为等待后者
+            (Some(Symbol::Dead(index)), Some(dead)) if pending == Some(index) => {
+                Mapped::Char(dead.spacing)
+            }
+            (Some(Symbol::Dead(index)), _) => {
+                self.pending = Some(index);
+                Mapped::Consumed
+            }
+            (Some(Symbol::Char(' ')), Some(dead)) => Mapped::Char(dead.spacing),
+            // 不能组合时丢弃死键
+            (Some(Symbol::Char(c)), Some(dead)) => Mapped::Char(dead.compose(c).unwrap_or(c)),
+            (Some(Symbol::Char(c)), None) => Mapped::Char(c),
+        }
+    }
+}
+
+// 德语布局的片段, 字母键按QWERTZ排列
+#[cfg(test)]
+pub(super) const FIXTURE: &str = "\
+# test layout
+name test
+key 0x10 q Q @
+key 0x11 w W
+key 0x12 e E €
+key 0x13 r R
+key 0x14 t T
+key 0x15 z Z
+key 0x16 u U
+key 0x17 i I
+key 0x18 o O
+key 0x19 p P
+key 0x1E a A
+key 0x1F s S
+key 0x20 d D
+key 0x21 f F
+key 0x22 g G
+key 0x23 h H
+key 0x24 j J
+key 0x25 k K
+key 0x26 l L
+key 0x2C y Y
+key 0x2D x X
+key 0x2E c C
+key 0x2F v V
+key 0x30 b B
+key 0x31 n N
+key 0x32 m M
+key 0x39 U+0020
+key 0x0D dead:acute dead:grave
+key 0x0C ß ? \\
+key 0x1A ü Ü
+key 0xE035 / -
+dead acute ´ e=é E=É a=á u=ú
+dead grave ` e=è a=à
+";
+
+#[cfg(test)]
+fn fixture_with(line: &str) -> String {
+    let mut text = String::from(FIXTURE);
+    text.push_str(line);
+    text.push('\n');
+    text
+}
+
+#[test_case]
+fn test_parse_keymap() {
+    let keymap = Keymap::parse(FIXTURE).unwrap();
+    assert_eq!(keymap.name, "test");
+    assert_eq!(keymap.symbol(0x15, PLAIN), Some(Symbol::Char('z')));
+    assert_eq!(keymap.symbol(0x10, ALTGR), Some(Symbol::Char('@')));
+    assert_eq!(keymap.symbol(0x39, PLAIN), Some(Symbol::Char(' ')));
+    assert_eq!(keymap.symbol(0x0C, ALTGR), Some(Symbol::Char('\\')));
+    assert_eq!(keymap.symbol(0x11, ALTGR), None);
+    assert_eq!(keymap.symbol(0xE035, SHIFT), None);
+    assert_eq!(keymap.symbol(0x0D, SHIFT), Some(Symbol::Dead(1)));
+    assert_eq!(keymap.dead_keys()[0].name, "acute");
+    assert_eq!(keymap.dead_keys()[0].compose('E'), Some('É'));
+    assert_eq!(keymap.dead_keys()[1].compose('u'), None);
+}
+
+#[test_case]
+fn test_broken_keymaps() {
+    // FIXTURE有35行, 追加的行是第36行
+    let cases = [
+        (
+            "key 0x10 x",
+            KeymapError::DuplicateScancode {
+                line: 36,
+                scancode: 0x10,
+                first: 3,
+            },
+        ),
+        (
+            "key 0x80 x",
+            KeymapError::ScancodeOutOfRange {
+                line: 36,
+                scancode: 0x80,
+            },
+        ),
+        (
+            "key 0xE0100 x",
+            KeymapError::ScancodeOutOfRange {
+                line: 36,
+                scancode: 0xE0100,
+            },
+        ),
+        (
+            "key 0x00 x",
+            KeymapError::ScancodeOutOfRange {
+                line: 36,
+                scancode: 0,
+            },
+        ),
+        (
+            "key 0x02 U+D800",
+            KeymapError::CodepointOutOfRange {
+                line: 36,
+                codepoint: 0xD800,
+            },
+        ),
+        (
+            "key 0x02 U+110000",
+            KeymapError::CodepointOutOfRange {
+                line: 36,
+                codepoint: 0x110000,
+            },
+        ),
+        ("key 0x02 ab", KeymapError::BadSymbol { line: 36 }),
+        (
+            "key 0x02 dead:tilde",
+            KeymapError::UnknownDeadKey { line: 36 },
+        ),
+        ("key 0x02 1 ! ¹ ¡", KeymapError::Syntax { line: 36 }),
+        ("key 0x02", KeymapError::Syntax { line: 36 }),
+        ("key 2 x", KeymapError::Syntax { line: 36 }),
+        ("name again", KeymapError::Syntax { line: 36 }),
+        ("dead acute ´", KeymapError::DuplicateDeadKey { line: 36 }),
+        ("dead tilde ~ n", KeymapError::Syntax { line: 36 }),
+        (
+            "dead tilde dead:acute",
+            KeymapError::UnknownDeadKey { line: 36 },
+        ),
+        ("keys 0x02 1", KeymapError::Syntax { line: 36 }),
+    ];
+    for (line, expected) in cases {
+        assert_eq!(
+            Keymap::parse(&fixture_with(line)),
+            Err(expected),
+            "{}",
+            line
+        );
+    }
+    let errors = [
+        (FIXTURE.replace("name test\n", ""), KeymapError::MissingName),
+        (
+            FIXTURE.replace("key 0x2E c C\n", ""),
+            KeymapError::MissingKey { scancode: 0x2E },
+        ),
+        (
+            FIXTURE.replace("key 0x39 U+0020\n", ""),
+            KeymapError::MissingKey { scancode: 0x39 },
+        ),
+    ];
+    for (text, expected) in errors {
+        assert_eq!(Keymap::parse(&text), Err(expected));
+    }
+    // 错误信息说明了位置
+    let err = Keymap::parse(&fixture_with("key 0x1E x")).unwrap_err();
+    assert_eq!(
+        alloc::format!("{}", err),
+        "line 36: scancode 0x1e already defined on line 13"
+    );
+}
+
+#[test_case]
+fn test_dead_keys() {
+    let keymap = Keymap::parse(FIXTURE).unwrap();
+    let mut state = LayoutState::new();
+    let mut press = |byte: u8| {
+        let scancode = state.scancode(byte).unwrap();
+        state.map(&keymap, scancode)
+    };
+    // ´ e
+    assert_eq!(press(0x0D), Mapped::Consumed);
+    assert_eq!(press(0x12), Mapped::Char('é'));
+    // ´ ´, ´ 空格
+    assert_eq!(press(0x0D), Mapped::Consumed);
+    assert_eq!(press(0x0D), Mapped::Char('´'));
+    assert_eq!(press(0x0D), Mapped::Consumed);
+    assert_eq!(press(0x39), Mapped::Char('´'));
+    // 不能组合时只输出基础字符
+    assert_eq!(press(0x0D), Mapped::Consumed);
+    assert_eq!(press(0x15), Mapped::Char('z'));
+    // 表中没有的键取消等待的死键
+    assert_eq!(press(0x0D), Mapped::Consumed);
+    assert_eq!(press(0x1C), Mapped::Unmapped);
+    assert_eq!(press(0x12), Mapped::Char('e'));
+    // 扩展键
+    assert_eq!(state.scancode(0xE0), None);
+    assert_eq!(state.scancode(0x35), Some(0xE035));
+    assert_eq!(state.scancode(0x35), Some(0x35));
+}
@@ -0,0 +1,164 @@
+//! 内核日志环形缓冲区
+//!
+//! 控制台的所有输出同时写入定长的环形缓冲区, 写满后覆盖最旧的字节. 字节按写入的总量编号,
+//! `read_from`按编号读取, 持久化据此只写出新增的部分, 见`persist`
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::shell::{self, CmdError};
+
+pub mod persist;
+pub mod record;
+
+pub use persist::recover;
+
+/// 缓冲区大小
+pub const RING_SIZE: usize = 16 * 1024;
+
+struct Ring {
+    buf: [u8; RING_SIZE],
+    // 写入的总字节数
+    written: u64,
+}
+
+impl Ring {
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[(self.written % RING_SIZE as u64) as usize] = byte;
+            self.written += 1;
+        }
+    }
+
+    fn read_from(&self, offset: u64, buf: &mut [u8]) -> (u64, usize) {
+        let oldest = self.written.saturating_sub(RING_SIZE as u64);
+        let start = offset.clamp(oldest, self.written);
+        let len = buf.len().min((self.written - start) as usize);
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.buf[((start + i as u64) % RING_SIZE as u64) as usize];
+        }
+        (start, len)
+    }
+}
+
+// 不使用IrqMutex: lockdep的报告本身也要写入这里
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    buf: [0; RING_SIZE],
+    written: 0,
+});
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+struct RingWriter<'a>(&'a mut Ring);
+
+impl fmt::Write for RingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// 写入缓冲区, 由控制台输出调用
+pub fn write(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    if PAUSED.load(Ordering::Relaxed) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        // panic时锁可能正被持有, 不再等待
+        let ring = if PANICKING.load(Ordering::Relaxed) {
+            RING.try_lock()
+        } else {
+            Some(RING.lock())
+        };
+        if let Some(mut ring) = ring {
+            let _ = RingWriter(&mut ring).write_fmt(args);
+        }
+    });
+}
+
+/// 执行`f`期间的控制台输出不写入缓冲区
+pub fn without_capture<R>(f: impl FnOnce() -> R) -> R {
+    let paused = PAUSED.swap(true, Ordering::Relaxed);
+    let result = f();
+    PAUSED.store(paused, Ordering::Relaxed);
+    result
+}
+
+/// 进入panic, 之后写入缓冲区时不再等待锁
+pub fn on_panic() {
+    PANICKING.store(true, Ordering::Relaxed);
+}
+
+/// 已写入的总字节数
+pub fn written() -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| RING.lock().written)
+}
+
+/// 从编号`offset`开始读取到`buf`, 返回实际开始的编号和读取的字节数
+///
+/// `offset`对应的字节已被覆盖时从最旧的字节开始
+pub fn read_from(offset: u64, buf: &mut [u8]) -> (u64, usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| RING.lock().read_from(offset, buf))
+}
+
+/// 注册`dmesg`和`panic`命令
+pub fn register_commands() {
+    shell::register_command("dmesg", "show the kernel log buffer", dmesg_command)
+        .expect("duplicate klog command");
+    shell::register_command(
+        "panic",
+        "panic -y: panic the kernel, for testing the persistent log",
+        panic_command,
+    )
+    .expect("duplicate klog command");
+}
+
+fn dmesg_command(_args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let mut offset = 0;
+    let end = written();
+    // 输出的内容不再写入缓冲区
+    without_capture(|| {
+        let mut buf = [0u8; 512];
+        while offset < end {
+            let (start, len) = read_from(offset, &mut buf);
+            let len = len.min((end - start) as usize);
+            out.write_str(&alloc::string::String::from_utf8_lossy(&buf[..len]))?;
+            offset = start + len as u64;
+        }
+        Ok(())
+    })
+}
+
+fn panic_command(args: &[&str], _out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    match args {
+        ["-y"] => panic!("panic requested from the shell"),
+        _ => Err(CmdError::Usage("panic -y")),
+    }
+}
+
+#[test_case]
+fn test_ring_wraps() {
+    let mut ring = Ring {
+        buf: [0; RING_SIZE],
+        written: 0,
+    };
+    ring.push(b"hello");
+    let mut buf = [0u8; 8];
+    assert_eq!(ring.read_from(0, &mut buf), (0, 5));
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(ring.read_from(3, &mut buf), (3, 2));
+    assert_eq!(ring.read_from(9, &mut buf), (5, 0));
+
+    ring.push(&[b'x'; RING_SIZE]);
+    // 被覆盖的字节从最旧的开始读
+    assert_eq!(ring.read_from(0, &mut buf), (5, 8));
+    assert_eq!(&buf, b"xxxxxxxx");
+    ring.push(b"tail");
+    let (start, len) = ring.read_from(ring.written - 4, &mut buf);
+    assert_eq!((start, &buf[..len]), (RING_SIZE as u64 + 5, &b"tail"[..]));
+}
@@ -0,0 +1,226 @@
+//! 把内核日志写到磁盘上的日志区域, 下次启动时输出
+//!
+//! 启动时`recover`在块设备上查找区域头, 输出上一次启动留下的记录, 然后增加区域头的会话号,
+//! 旧的记录从此不再属于当前会话. 之后`run`定期把环形缓冲区中新增的字节写成记录,
+//! panic处理函数最后调用`flush_on_panic`再写一次. 没有找到区域时这些函数什么都不做
+
+use alloc::vec::Vec;
+use core::str;
+
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+use super::record::{self, Header, Record, HEADER_LBA, PAYLOAD_MAX};
+use crate::ata::{self, Position};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::error::KernelError;
+use crate::{print, println, time, virtio};
+
+/// 后台写出的间隔
+pub const FLUSH_INTERVAL_MS: u64 = 1000;
+// 恢复时最多输出的字节数
+const REPLAY_MAX: usize = super::RING_SIZE;
+// 恢复时一次读取的扇区数
+const READ_BATCH: usize = 32;
+
+enum Device {
+    Virtio(&'static virtio::VirtioBlk),
+    Ata(ata::Drive),
+}
+
+impl Device {
+    fn block(&self) -> &dyn BlockDevice {
+        match self {
+            Device::Virtio(device) => *device,
+            Device::Ata(drive) => drive,
+        }
+    }
+}
+
+struct Persist {
+    device: Device,
+    header: Header,
+    // 正在写的记录和其中已有的字节
+    seq: u64,
+    payload: [u8; PAYLOAD_MAX],
+    len: usize,
+    // 环形缓冲区中已写出的字节编号
+    flushed: u64,
+}
+
+impl Persist {
+    // 写出失败时状态不变, 下次从同一处重试
+    fn flush(&mut self) -> Result<(), KernelError> {
+        loop {
+            let (mut seq, mut len) = (self.seq, self.len);
+            let mut payload = self.payload;
+            let (start, count) = super::read_from(self.flushed, &mut payload[len..]);
+            if count == 0 {
+                return Ok(());
+            }
+            // 中间的字节已被覆盖, 从新的记录开始
+            if start != self.flushed && len != 0 {
+                payload.copy_within(len..len + count, 0);
+                seq += 1;
+                len = 0;
+            }
+            len += count;
+            // 没写满的记录下次写出时覆盖同一个扇区
+            let sector = Record {
+                session: self.header.session,
+                seq,
+                payload: &payload[..len],
+            }
+            .encode();
+            self.device
+                .block()
+                .write_sectors(self.header.lba(seq), 1, &sector)?;
+            if len == PAYLOAD_MAX {
+                seq += 1;
+                len = 0;
+            }
+            self.seq = seq;
+            self.len = len;
+            self.payload = payload;
+            self.flushed = start + count as u64;
+        }
+    }
+}
+
+static PERSIST: OnceCell<Mutex<Persist>> = OnceCell::uninit();
+
+/// 查找日志区域, 输出上一次启动的日志并开始新的会话. 需要堆和块设备驱动. 重复调用会panic
+pub fn recover() {
+    let Some((device, header)) = find_region() else {
+        return;
+    };
+    match replay(device.block(), &header) {
+        Ok(()) => {}
+        Err(err) => println!("klog: failed to read the previous log: {}", err),
+    }
+    let header = Header {
+        session: header.session.wrapping_add(1),
+        ..header
+    };
+    if let Err(err) = device
+        .block()
+        .write_sectors(HEADER_LBA, 1, &header.encode())
+    {
+        println!("klog: failed to start a new session: {}", err);
+        return;
+    }
+    let persist = Persist {
+        device,
+        header,
+        seq: 0,
+        payload: [0; PAYLOAD_MAX],
+        len: 0,
+        flushed: 0,
+    };
+    PERSIST
+        .try_init_once(|| Mutex::new(persist))
+        .expect("klog::recover called twice");
+}
+
+// 依次检查可写的virtio-blk设备和主通道上的驱动器
+fn find_region() -> Option<(Device, Header)> {
+    let mut candidates = Vec::new();
+    if let Some(device) = virtio::device().filter(|device| !device.read_only()) {
+        candidates.push(Device::Virtio(device));
+    }
+    for position in [Position::Master, Position::Slave] {
+        if let Some(drive) = ata::drive(position) {
+            candidates.push(Device::Ata(drive));
+        }
+    }
+    candidates.into_iter().find_map(|device| {
+        let header = read_header(device.block())?;
+        Some((device, header))
+    })
+}
+
+// 区域头无效或区域超出设备时返回None
+fn read_header(device: &dyn BlockDevice) -> Option<Header> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    device.read_sectors(HEADER_LBA, 1, &mut sector).ok()?;
+    let header = Header::parse(&sector).ok()?;
+    let end = header.start.checked_add(header.sectors)?;
+    (end <= device.sector_count()).then_some(header)
+}
+
+// 输出会话号与区域头相同的记录的末尾部分
+fn replay(device: &dyn BlockDevice, header: &Header) -> Result<(), KernelError> {
+    let mut records: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut buf = alloc::vec![0u8; READ_BATCH * SECTOR_SIZE];
+    let mut lba = header.start;
+    let end = header.start + header.sectors;
+    while lba < end {
+        let count = READ_BATCH.min((end - lba) as usize);
+        let buf = &mut buf[..count * SECTOR_SIZE];
+        device.read_sectors(lba, count, buf)?;
+        let sectors = buf
+            .chunks_exact(SECTOR_SIZE)
+            .map(|sector| sector.try_into().unwrap());
+        records.extend(
+            record::collect(header.session, sectors)
+                .into_iter()
+                .map(|record| (record.seq, record.payload.to_vec())),
+        );
+        lba += count as u64;
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+    records.sort_by_key(|(seq, _)| *seq);
+    let text: Vec<u8> = records
+        .into_iter()
+        .flat_map(|(_, payload)| payload)
+        .collect();
+    let mut tail = &text[text.len().saturating_sub(REPLAY_MAX)..];
+    // 截断处可能切开了一个字符
+    while !tail.is_empty() && tail[0] & 0xC0 == 0x80 {
+        tail = &tail[1..];
+    }
+    // 输出的内容不再写入新会话的日志
+    super::without_capture(|| {
+        println!("previous kernel log:");
+        print!("{}", str::from_utf8(tail).unwrap_or("(not UTF-8)\n"));
+        if !tail.ends_with(b"\n") {
+            println!();
+        }
+        println!("end of previous kernel log");
+    });
+    Ok(())
+}
+
+/// 写出环形缓冲区中新增的字节
+pub fn flush() {
+    let Ok(persist) = PERSIST.try_get() else {
+        return;
+    };
+    if let Err(err) = persist.lock().flush() {
+        // 写出失败的日志会在下一次写出时重试
+        crate::log!(crate::log::Level::Debug, "klog: flush failed: {}", err);
+    }
+}
+
+/// 定期写出的后台任务
+pub async fn run() {
+    if PERSIST.try_get().is_err() {
+        return;
+    }
+    loop {
+        time::sleep_ms(FLUSH_INTERVAL_MS).await;
+        flush();
+    }
+}
+
+/// panic处理函数的最后一次写出. 后台任务正在写出时放弃, 设备驱动的锁被持有时会卡住
+pub fn flush_on_panic() {
+    let Ok(persist) = PERSIST.try_get() else {
+        return;
+    };
+    if let Some(mut persist) = persist.try_lock() {
+        let _ = persist.flush();
+    }
+}
@@ -0,0 +1,300 @@
+//! 磁盘上日志区域的格式
+//!
+//! 区域由设备第`HEADER_LBA`个扇区上的区域头描述, 区域头给出数据扇区的位置、数量和当前的会话号.
+//! 每个数据扇区保存一条记录: 会话号、序号、长度、校验和, 以及最多`PAYLOAD_MAX`字节的日志.
+//! 序号为`seq`的记录写在第`seq % sectors`个数据扇区, 写满后覆盖最旧的记录.
+//! 校验和是CRC-32, 覆盖记录头和有效的日志字节, 写了一半的扇区因此会被识别出来
+
+use alloc::vec::Vec;
+
+use crate::block::SECTOR_SIZE;
+
+/// 区域头所在的扇区, 紧跟在引导扇区之后
+pub const HEADER_LBA: u64 = 1;
+pub const HEADER_MAGIC: &[u8; 8] = b"TOYOSKLG";
+pub const VERSION: u32 = 1;
+
+pub const RECORD_MAGIC: &[u8; 4] = b"KLGR";
+const RECORD_HEADER: usize = 24;
+/// 一条记录最多保存的日志字节数
+pub const PAYLOAD_MAX: usize = SECTOR_SIZE - RECORD_HEADER;
+
+/// 解析失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatError {
+    /// 不是区域头或记录, 例如从未写过的扇区
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// 长度超出扇区
+    BadLength,
+    /// 校验和不符, 通常是写了一半的扇区
+    Checksum,
+}
+
+/// 日志区域的区域头
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// 第一个数据扇区
+    pub start: u64,
+    /// 数据扇区的数量
+    pub sectors: u64,
+    /// 当前会话号, 只有会话号相同的记录属于上一次启动
+    pub session: u32,
+}
+
+impl Header {
+    pub fn parse(sector: &[u8; SECTOR_SIZE]) -> Result<Header, FormatError> {
+        if &sector[..8] != HEADER_MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        if read_u32(sector, 32) != crc32(&[&sector[..32]]) {
+            return Err(FormatError::Checksum);
+        }
+        let version = read_u32(sector, 8);
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let header = Header {
+            start: read_u64(sector, 12),
+            sectors: read_u64(sector, 20),
+            session: read_u32(sector, 28),
+        };
+        // 区域必须在区域头之后
+        if header.sectors == 0 || header.start <= HEADER_LBA {
+            return Err(FormatError::BadLength);
+        }
+        Ok(header)
+    }
+
+    pub fn encode(&self) -> [u8; SECTOR_SIZE] {
+        let mut sector = [0u8; SECTOR_SIZE];
+        sector[..8].copy_from_slice(HEADER_MAGIC);
+        sector[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        sector[12..20].copy_from_slice(&self.start.to_le_bytes());
+        sector[20..28].copy_from_slice(&self.sectors.to_le_bytes());
+        sector[28..32].copy_from_slice(&self.session.to_le_bytes());
+        let crc = crc32(&[&sector[..32]]);
+        sector[32..36].copy_from_slice(&crc.to_le_bytes());
+        sector
+    }
+
+    /// 序号为`seq`的记录所在的扇区
+    pub fn lba(&self, seq: u64) -> u64 {
+        self.start + seq % self.sectors
+    }
+}
+
+/// 一条日志记录
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub session: u32,
+    pub seq: u64,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    pub fn parse(sector: &'a [u8; SECTOR_SIZE]) -> Result<Record<'a>, FormatError> {
+        if &sector[..4] != RECORD_MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        let len = u16::from_le_bytes([sector[16], sector[17]]) as usize;
+        if len > PAYLOAD_MAX {
+            return Err(FormatError::BadLength);
+        }
+        let payload = &sector[RECORD_HEADER..RECORD_HEADER + len];
+        if read_u32(sector, 20) != crc32(&[&sector[..20], payload]) {
+            return Err(FormatError::Checksum);
+        }
+        Ok(Record {
+            session: read_u32(sector, 4),
+            seq: read_u64(sector, 8),
+            payload,
+        })
+    }
+
+    /// `payload`超过`PAYLOAD_MAX`时panic
+    pub fn encode(&self) -> [u8; SECTOR_SIZE] {
+        assert!(self.payload.len() <= PAYLOAD_MAX, "klog record too long");
+        let mut sector = [0u8; SECTOR_SIZE];
+        sector[..4].copy_from_slice(RECORD_MAGIC);
+        sector[4..8].copy_from_slice(&self.session.to_le_bytes());
+        sector[8..16].copy_from_slice(&self.seq.to_le_bytes());
+        sector[16..18].copy_from_slice(&(self.payload.len() as u16).to_le_bytes());
+        sector[RECORD_HEADER..RECORD_HEADER + self.payload.len()].copy_from_slice(self.payload);
+        let crc = crc32(&[&sector[..20], self.payload]);
+        sector[20..24].copy_from_slice(&crc.to_le_bytes());
+        sector
+    }
+}
+
+/// 从一组数据扇区中收集属于`session`的记录, 按序号排列
+///
+/// 不完整或校验失败的扇区和其他会话的记录被跳过. 返回的记录的序号可能不连续
+pub fn collect<'a>(
+    session: u32,
+    sectors: impl IntoIterator<Item = &'a [u8; SECTOR_SIZE]>,
+) -> Vec<Record<'a>> {
+    let mut records: Vec<Record> = sectors
+        .into_iter()
+        .filter_map(|sector| Record::parse(sector).ok())
+        .filter(|record| record.session == session)
+        .collect();
+    records.sort_by_key(|record| record.seq);
+    records
+}
+
+/// 按顺序计算几段数据合起来的CRC-32(IEEE)
+pub fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_u32(sector: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(sector: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(sector[offset..offset + 8].try_into().unwrap())
+}
+
+#[test_case]
+fn test_crc32() {
+    assert_eq!(crc32(&[b"123456789"]), 0xCBF4_3926);
+    assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+    assert_eq!(crc32(&[]), 0);
+}
+
+#[test_case]
+fn test_header_round_trip() {
+    let header = Header {
+        start: 1024,
+        sectors: 1024,
+        session: 7,
+    };
+    let sector = header.encode();
+    assert_eq!(Header::parse(&sector), Ok(header));
+    assert_eq!(header.lba(1030), 1030);
+    assert_eq!(header.lba(2050), 1026);
+
+    let mut corrupted = sector;
+    corrupted[28] ^= 1;
+    assert_eq!(Header::parse(&corrupted), Err(FormatError::Checksum));
+    assert_eq!(Header::parse(&[0; SECTOR_SIZE]), Err(FormatError::BadMagic));
+    let empty = Header {
+        sectors: 0,
+        ..header
+    };
+    assert_eq!(Header::parse(&empty.encode()), Err(FormatError::BadLength));
+}
+
+#[test_case]
+fn test_record_round_trip() {
+    let record = Record {
+        session: 3,
+        seq: 42,
+        payload: b"kernel panicked\n",
+    };
+    let sector = record.encode();
+    assert_eq!(Record::parse(&sector), Ok(record));
+    let full = [b'x'; PAYLOAD_MAX];
+    let sector = Record {
+        payload: &full,
+        ..record
+    }
+    .encode();
+    assert_eq!(Record::parse(&sector).unwrap().payload, &full[..]);
+}
+
+#[test_case]
+fn test_corrupted_records() {
+    let sector = Record {
+        session: 3,
+        seq: 42,
+        payload: b"boot ok\n",
+    }
+    .encode();
+
+    // 日志字节被改动
+    let mut flipped = sector;
+    flipped[RECORD_HEADER + 1] ^= 0x20;
+    assert_eq!(Record::parse(&flipped), Err(FormatError::Checksum));
+    // 序号被改动
+    let mut flipped = sector;
+    flipped[8] += 1;
+    assert_eq!(Record::parse(&flipped), Err(FormatError::Checksum));
+    // 长度超出扇区
+    let mut long = sector;
+    long[16..18].copy_from_slice(&(PAYLOAD_MAX as u16 + 1).to_le_bytes());
+    assert_eq!(Record::parse(&long), Err(FormatError::BadLength));
+    // 长度变短时校验和覆盖的字节也变了
+    let mut short = sector;
+    short[16] -= 1;
+    assert_eq!(Record::parse(&short), Err(FormatError::Checksum));
+    assert_eq!(Record::parse(&[0; SECTOR_SIZE]), Err(FormatError::BadMagic));
+    assert_eq!(
+        Record::parse(&[0xFF; SECTOR_SIZE]),
+        Err(FormatError::BadMagic)
+    );
+}
+
+#[test_case]
+fn test_torn_write() {
+    // 扇区写了一半: 前半是新记录, 后半还是旧记录
+    let old = Record {
+        session: 3,
+        seq: 2,
+        payload: &[b'o'; PAYLOAD_MAX],
+    }
+    .encode();
+    let new = Record {
+        session: 3,
+        seq: 2,
+        payload: &[b'n'; PAYLOAD_MAX],
+    }
+    .encode();
+    let mut torn = old;
+    torn[..SECTOR_SIZE / 2].copy_from_slice(&new[..SECTOR_SIZE / 2]);
+    assert_eq!(Record::parse(&torn), Err(FormatError::Checksum));
+}
+
+#[test_case]
+fn test_collect_skips_bad_records() {
+    let record = |session, seq, payload: &'static [u8]| {
+        Record {
+            session,
+            seq,
+            payload,
+        }
+        .encode()
+    };
+    let mut torn = record(5, 11, b"lost line\n");
+    torn[RECORD_HEADER] = b'?';
+    // 区域已经绕了一圈, 最旧的记录在中间
+    let sectors = [
+        record(5, 12, b"third\n"),
+        torn,
+        [0; SECTOR_SIZE],
+        record(4, 13, b"older session\n"),
+        record(5, 9, b"first\n"),
+        record(5, 10, b"second\n"),
+    ];
+    let records = collect(5, &sectors);
+    let seqs: Vec<u64> = records.iter().map(|record| record.seq).collect();
+    assert_eq!(seqs, [9, 10, 12]);
+    let text: Vec<u8> = records
+        .iter()
+        .flat_map(|record| record.payload.iter().copied())
+        .collect();
+    assert_eq!(text, b"first\nsecond\nthird\n");
+    assert!(collect(6, &sectors).is_empty());
+}
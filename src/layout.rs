@@ -0,0 +1,229 @@
+//! 内核虚拟地址空间的布局. 各区域不重叠, 由编译时的检查保证
+//!
+//! 物理内存映射和启动栈的位置由Cargo.toml中`package.metadata.bootloader`指定, 必须与这里一致.
+//! 内核镜像由链接器放在低地址, bootloader自身、VGA缓冲区和`memory::identity_map`的映射也在那里
+
+use x86_64::VirtAddr;
+
+/// 半开区间`[start, end)`, 两端按页对齐
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Range {
+    pub const fn new(start: u64, end: u64) -> Self {
+        assert!(start < end, "empty layout range");
+        assert!(
+            start.is_multiple_of(4096) && end.is_multiple_of(4096),
+            "layout range not page aligned"
+        );
+        Range { start, end }
+    }
+
+    pub const fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// `[start, start + len)`完全在区间内, 长度为0时只要求`start`不越界
+    pub const fn contains_range(&self, start: u64, len: u64) -> bool {
+        match start.checked_add(len) {
+            Some(end) => self.start <= start && end <= self.end,
+            None => false,
+        }
+    }
+
+    pub const fn overlaps(&self, other: &Range) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    pub const fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// 第一页不映射, 空指针访问总是页错误
+const NULL_GUARD: u64 = 0x1000;
+
+/// 内核镜像、bootloader和恒等映射的低地址区域, 占前两个4级页表项
+pub const KERNEL_IMAGE: Range = Range::new(NULL_GUARD, 0x_0100_0000_0000);
+/// 全部物理内存的映射, 起点即`bootinfo::physical_memory_offset()`
+pub const PHYSICAL_MEMORY: Range = Range::new(0x_1000_0000_0000, 0x_2000_0000_0000);
+/// 内核堆保留的区域, 只有开头的`allocator::HEAP_SIZE`字节已映射
+pub const HEAP: Range = Range::new(0x_4444_4444_0000, 0x_4444_8444_0000);
+/// 设备寄存器的映射, 由`memory::map_mmio`依次分配
+pub const MMIO: Range = Range::new(0x_5555_0000_0000, 0x_5655_0000_0000);
+/// bootloader建立的启动栈和BootInfo
+pub const BOOT: Range = Range::new(0x_6000_0000_0000, 0x_6001_0000_0000);
+/// `memory::alloc_stack`分配的内核栈, 每个栈下方有一个保护页
+pub const STACKS: Range = Range::new(0x_6666_0000_0000, 0x_6766_0000_0000);
+/// 用户空间, 正好是一个4级页表项, 各进程的地址空间只在这里不同
+pub const USER: Range = Range::new(0x_7000_0000_0000, 0x_7080_0000_0000);
+
+/// 地址所在的区域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    KernelImage,
+    PhysicalMemory,
+    Heap,
+    Mmio,
+    Boot,
+    Stacks,
+    User,
+    /// 不属于任何区域
+    Unassigned,
+}
+
+impl Region {
+    /// 页错误地址在这个区域时的说明
+    pub fn fault_kind(self) -> &'static str {
+        match self {
+            Region::KernelImage => "fault in kernel image or identity mapping",
+            Region::PhysicalMemory => "fault in physical memory mapping, beyond installed memory",
+            Region::Heap => "fault in heap demand range, beyond the mapped heap",
+            Region::Mmio => "fault in MMIO window",
+            Region::Boot | Region::Stacks => "fault in kernel stack area, likely a stack overflow",
+            Region::User => "user fault",
+            Region::Unassigned => "wild kernel pointer",
+        }
+    }
+}
+
+/// 所有区域, 按地址排列
+pub const REGIONS: [(Region, Range); 7] = [
+    (Region::KernelImage, KERNEL_IMAGE),
+    (Region::PhysicalMemory, PHYSICAL_MEMORY),
+    (Region::Heap, HEAP),
+    (Region::Mmio, MMIO),
+    (Region::Boot, BOOT),
+    (Region::Stacks, STACKS),
+    (Region::User, USER),
+];
+
+/// 第一对重叠区域的下标
+pub const fn first_overlap(regions: &[(Region, Range)]) -> Option<(usize, usize)> {
+    let mut i = 0;
+    while i < regions.len() {
+        let mut j = i + 1;
+        while j < regions.len() {
+            if regions[i].1.overlaps(&regions[j].1) {
+                return Some((i, j));
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    None
+}
+
+// 所有区域都在低半部分的规范地址内
+const fn all_lower_half(regions: &[(Region, Range)]) -> bool {
+    let mut i = 0;
+    while i < regions.len() {
+        if regions[i].1.end > 0x_8000_0000_0000 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(
+    first_overlap(&REGIONS).is_none(),
+    "kernel layout regions overlap"
+);
+const _: () = assert!(
+    all_lower_half(&REGIONS),
+    "kernel layout region is not canonical"
+);
+// 用户空间独占一个4级页表项, 创建地址空间时只复制其他项
+const _: () = assert!(USER.size() == 1 << 39 && USER.start.is_multiple_of(1 << 39));
+
+// 只用于确认上面的检查会失败: 启用后编译必须报错
+#[cfg(feature = "layout-overlap-test")]
+const _: () = assert!(
+    first_overlap(&[
+        (Region::Heap, HEAP),
+        (Region::Mmio, Range::new(HEAP.end - 0x1000, MMIO.end)),
+    ])
+    .is_none(),
+    "kernel layout regions overlap"
+);
+
+pub fn region_of(addr: VirtAddr) -> Region {
+    let addr = addr.as_u64();
+    REGIONS
+        .iter()
+        .find(|(_, range)| range.contains(addr))
+        .map_or(Region::Unassigned, |&(region, _)| region)
+}
+
+pub fn is_user(addr: VirtAddr) -> bool {
+    USER.contains(addr.as_u64())
+}
+
+pub fn is_kernel_heap(addr: VirtAddr) -> bool {
+    HEAP.contains(addr.as_u64())
+}
+
+#[test_case]
+fn test_region_of() {
+    let region = |addr| region_of(VirtAddr::new(addr));
+    assert_eq!(region(0), Region::Unassigned);
+    assert_eq!(region(0xb8000), Region::KernelImage);
+    assert_eq!(
+        region(region_of as fn(VirtAddr) -> Region as usize as u64),
+        Region::KernelImage
+    );
+    assert_eq!(
+        region(crate::bootinfo::physical_memory_offset().as_u64()),
+        Region::PhysicalMemory
+    );
+    assert_eq!(region(HEAP.start), Region::Heap);
+    assert_eq!(region(HEAP.end - 1), Region::Heap);
+    assert_eq!(region(HEAP.end), Region::Unassigned);
+    assert_eq!(region(MMIO.start + 0x2000), Region::Mmio);
+    assert_eq!(region(STACKS.start), Region::Stacks);
+    assert_eq!(region(USER.start), Region::User);
+    assert_eq!(region(USER.end - 1), Region::User);
+    assert_eq!(region(USER.end), Region::Unassigned);
+    assert_eq!(region(0xffff_8000_0000_0000), Region::Unassigned);
+
+    // 运行中的实际地址
+    let boxed = alloc::boxed::Box::new(0u64);
+    assert!(is_kernel_heap(VirtAddr::from_ptr(&*boxed)));
+    let local = 0u64;
+    let stack = region_of(VirtAddr::from_ptr(&local));
+    assert!(
+        stack == Region::Boot || stack == Region::Stacks,
+        "{:?}",
+        stack
+    );
+    assert!(is_user(VirtAddr::new(USER.start + 0x1234)));
+    assert!(!is_user(VirtAddr::new(HEAP.start)));
+    assert_eq!(Region::Unassigned.fault_kind(), "wild kernel pointer");
+}
+
+#[test_case]
+fn test_overlap_check() {
+    assert_eq!(first_overlap(&REGIONS), None);
+    let overlapping = [
+        (Region::Heap, HEAP),
+        (Region::Mmio, MMIO),
+        (
+            Region::Stacks,
+            Range::new(MMIO.end - 0x1000, MMIO.end + 0x1000),
+        ),
+    ];
+    assert_eq!(first_overlap(&overlapping), Some((1, 2)));
+    // 相邻的区域不算重叠
+    let adjacent = [
+        (Region::Heap, Range::new(0x1000, 0x2000)),
+        (Region::Mmio, Range::new(0x2000, 0x3000)),
+    ];
+    assert_eq!(first_overlap(&adjacent), None);
+    assert!(HEAP.contains_range(HEAP.start, HEAP.size()));
+    assert!(!HEAP.contains_range(HEAP.start, HEAP.size() + 1));
+    assert!(!HEAP.contains_range(u64::MAX, 2));
+}
@@ -5,52 +5,421 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use bootloader::BootInfo;
+
+use crate::log::Level;
+use crate::testing::{Status, StatusLine};
 
 pub mod interrupts;
+pub mod io;
+pub mod pic;
+pub mod sync;
+pub mod error;
+pub mod profile;
+pub mod bootprof;
+pub mod trace;
+pub mod earlycon;
 pub mod vga_buffer;
+pub mod vga_font;
+pub mod console;
+pub mod bootinfo;
+pub mod framebuffer;
 pub mod serial;
 pub mod gdt;
+pub mod layout;
+pub mod memory;
+pub mod memdebug;
+pub mod bootmem;
+pub mod backtrace;
+pub mod symbols;
+pub mod fault;
+pub mod gdbstub;
+pub mod allocator;
+#[cfg(feature = "alloc-track")]
+pub mod alloc_track;
+pub mod cpu;
+pub mod bench;
+pub mod time;
+pub mod ps2;
+pub mod mouse;
+pub mod keyboard;
+pub mod task;
+pub mod pci;
+pub mod ata;
+pub mod block;
+pub mod virtio;
+pub mod acpi;
+pub mod power;
+pub mod speaker;
+pub mod rng;
+pub mod apic;
+pub mod percpu;
+pub mod smp;
+pub mod hpet;
+pub mod net;
+pub mod ramfs;
+pub mod fat;
+pub mod vfs;
+pub mod tty;
+pub mod shell;
+pub mod debugcon;
+pub mod usermode;
+pub mod syscall;
+pub mod elf;
+pub mod thread;
+pub mod sched;
+pub mod workqueue;
+pub mod process;
+pub mod cmdline;
+pub mod log;
+pub mod klog;
+pub mod version;
+pub mod testing;
+pub mod batch;
+pub mod selftest;
 
+pub use power::{reboot, shutdown};
+pub use version::print_banner;
+
+/// 按顺序初始化段描述符、中断和控制台, 每个阶段只能初始化一次.
+/// 在这之前`print!`直接写屏幕首行, `serial_print!`直接写端口, 异常通过earlycon报告
 pub fn init() {
-    interrupts::init_idt();
+    bootprof::mark("init");
+    // 先加载TSS, 双重错误使用的IST栈在IDT加载后立即可用
     gdt::init();
-    unsafe {
-        interrupts::PICS.lock().initialize();
-    }
+    interrupts::init_idt();
+    bootprof::mark("serial");
+    serial::init();
+    bootprof::mark("serial");
+    vga_buffer::init();
+    keyboard::init();
+    interrupts::PICS.lock().initialize();
+    earlycon::end_early_boot();
     // 启用中断
     x86_64::instructions::interrupts::enable();
+
+    // PS/2控制器的超时依赖时钟中断
+    bootprof::mark("ps2");
+    match ps2::init() {
+        Ok(()) if ps2::mouse_enabled() => interrupts::unmask_irq(12),
+        Ok(()) => {}
+        Err(err) => println!("PS/2 controller initialization failed: {:?}", err),
+    }
+    bootprof::mark("ps2");
+    bootprof::mark("ata");
+    ata::init();
+    bootprof::mark("ata");
+    bootprof::mark("init");
+}
+
+/// 保存启动信息并选择控制台, 然后初始化页表、物理帧分配器和内核堆, 以及依赖它们的驱动
+pub fn init_memory(boot_info: &'static BootInfo) {
+    bootprof::mark("memory");
+    bootinfo::init(boot_info);
+    vga_buffer::init_console();
+
+    bootprof::mark("paging");
+    let phys_mem_offset = bootinfo::physical_memory_offset();
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        memory::BootInfoFrameAllocator::init(bootinfo::memory_map(), phys_mem_offset)
+    };
+    smp::reserve_trampoline(&mut frame_allocator);
+    bootprof::mark("paging");
+
+    // 以下两项从bootmem分配, 必须在堆初始化封存它之前
+    bootprof::mark("acpi");
+    if let Err(err) = acpi::init() {
+        log!(Level::Warn, "ACPI table discovery failed: {:?}", err);
+    }
+    percpu::reserve();
+    bootprof::mark("acpi");
+
+    bootprof::mark("heap");
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(phys_mem_offset, mapper, frame_allocator);
+    bootprof::mark("heap");
+    workqueue::init();
+
+    bootprof::mark("vfs");
+    if let Err(err) = ramfs::init() {
+        println!("initrd parsing failed: {:?}", err);
+    }
+    if let Err(err) = vfs::init() {
+        println!("VFS initialization failed: {}", err);
+    }
+    bootprof::mark("vfs");
+    cmdline::init();
+    apply_cmdline();
+    register_commands();
+    register_selftests();
+    batch::init();
+    if let Err(err) = gdbstub::init() {
+        log!(Level::Warn, "gdbstub initialization failed: {:?}", err);
+    }
+
+    bootprof::mark("apic");
+    if let Err(err) = apic::init() {
+        log!(Level::Warn, "APIC initialization failed: {:?}", err);
+    }
+    percpu::init_bsp();
+    bootprof::mark("apic");
+    syscall::init();
+    thread::init();
+    // 包括以HPET为基准校准TSC
+    bootprof::mark("hpet");
+    if let Err(err) = hpet::init() {
+        log!(Level::Warn, "HPET initialization failed: {:?}", err);
+    }
+    bootprof::mark("hpet");
+    // 以下驱动需要堆和DMA内存
+    bootprof::mark("virtio");
+    if let Err(err) = virtio::init() {
+        log!(Level::Warn, "virtio-blk initialization failed: {:?}", err);
+    }
+    bootprof::mark("virtio");
+    bootprof::mark("e1000");
+    if let Err(err) = net::e1000::init() {
+        log!(Level::Warn, "e1000 initialization failed: {:?}", err);
+    }
+    bootprof::mark("e1000");
+    // 所有启动时的映射都已建立. 没有单独的后期初始化阶段, 在这里检查
+    #[cfg(debug_assertions)]
+    memory::audit::log_violations();
+    bootprof::mark("memory");
+}
+
+// 按命令行设置日志级别和控制台, 无法识别的值保留默认设置
+fn apply_cmdline() {
+    if let Some(level) = cmdline::get("loglevel") {
+        match Level::parse(level) {
+            Some(level) => log::set_max_level(level),
+            None => println!("cmdline: unknown loglevel={}", level),
+        }
+    }
+    if let Some(console) = cmdline::get("console") {
+        match vga_buffer::ConsoleTarget::parse(console) {
+            Some(target) => vga_buffer::set_console_target(target),
+            None => println!("cmdline: unknown console={}", console),
+        }
+    }
+    if cmdline::flag("cpu_prefix") {
+        vga_buffer::set_cpu_prefix(true);
+    }
+    if let Some(name) = cmdline::get("keymap") {
+        keyboard::apply_cmdline(name);
+    }
+}
+
+// 外壳命令由各自的模块实现并注册
+fn register_commands() {
+    shell::init();
+    memory::register_commands();
+    memdebug::register_commands();
+    interrupts::register_commands();
+    profile::register_commands();
+    trace::register_commands();
+    task::register_commands();
+    sched::register_commands();
+    time::register_commands();
+    vga_buffer::register_commands();
+    vga_font::register_commands();
+    power::register_commands();
+    process::register_commands();
+    debugcon::register_commands();
+    klog::register_commands();
+    vfs::register_commands();
+    keyboard::register_commands();
+    bootprof::register_commands();
+}
+
+// 启动自检由各自的模块实现并登记, 按这里的顺序运行, 被依赖的检查在前
+fn register_selftests() {
+    vga_buffer::register_selftests();
+    serial::register_selftests();
+    gdbstub::register_selftests();
+    time::register_selftests();
+    ps2::register_selftests();
+    memory::register_selftests();
+    allocator::register_selftests();
+    rng::register_selftests();
+    pci::register_selftests();
+    ata::register_selftests();
 }
 
 pub trait Testable {
-    fn run(&self) -> ();
+    fn run(&self);
+    fn name(&self) -> &'static str;
 }
 
 impl<T> Testable for T
     where T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
+        run_test(self.name(), DEFAULT_TEST_TIMEOUT_SECS, self);
     }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// 单个测试默认的超时时间(秒)
+pub const DEFAULT_TEST_TIMEOUT_SECS: u64 = 5;
+
+/// 为已知较慢的测试放宽超时时间, 从调用时刻起给`test`留出`secs`秒
+///
+/// ```ignore
+/// #[test_case]
+/// fn test_slow() {
+///     toy_os::with_timeout(30, || { /* ... */ });
+/// }
+/// ```
+pub fn with_timeout<R>(secs: u64, test: impl FnOnce() -> R) -> R {
+    // 不在测试中(截止时间为0)时不设置
+    if TEST_DEADLINE.load(Ordering::SeqCst) != 0 {
+        let deadline = time::pit_ticks() + time::secs_to_ticks(secs);
+        TEST_DEADLINE.store(deadline, Ordering::SeqCst);
+    }
+    test()
+}
+
+// 当前测试的截止tick, 0表示没有正在运行的测试
+static TEST_DEADLINE: AtomicU64 = AtomicU64::new(0);
+// 当前测试名(type_name返回的&'static str拆成指针和长度存储, 供中断处理函数读取)
+static TEST_NAME_PTR: AtomicUsize = AtomicUsize::new(0);
+static TEST_NAME_LEN: AtomicUsize = AtomicUsize::new(0);
+
+fn run_test(name: &'static str, timeout_secs: u64, test: &dyn Fn()) {
+    serial_print!("{}...\t", name);
+
+    TEST_NAME_PTR.store(name.as_ptr() as usize, Ordering::SeqCst);
+    TEST_NAME_LEN.store(name.len(), Ordering::SeqCst);
+    // 命令行中的nowatchdog关闭超时检查, 便于挂上调试器
+    if !cmdline::flag("nowatchdog") {
+        let deadline = time::pit_ticks() + time::secs_to_ticks(timeout_secs);
+        TEST_DEADLINE.store(deadline, Ordering::SeqCst);
+    }
+
+    let start = time::Instant::now();
+    test();
+    let elapsed = start.elapsed();
+
+    TEST_DEADLINE.store(0, Ordering::SeqCst);
+    serial_println!("{}", StatusLine::new(Status::Ok, Some(elapsed)));
+    testing::record_pass(name, elapsed);
+}
+
+// 正在运行的测试名, 不在测试中时为None
+fn current_test_name() -> Option<&'static str> {
+    let ptr = TEST_NAME_PTR.load(Ordering::SeqCst) as *const u8;
+    if ptr.is_null() {
+        return None;
+    }
+    let len = TEST_NAME_LEN.load(Ordering::SeqCst);
+    Some(unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len)) })
+}
+
+/// 由时钟中断调用, 当前测试超过截止时间时打印测试名并以失败退出qemu
+pub fn check_test_deadline(now: u64) {
+    let deadline = TEST_DEADLINE.load(Ordering::SeqCst);
+    if deadline == 0 || now < deadline {
+        return;
+    }
+
+    // 超时的测试可能正停在串口输出中途, 不释放SERIAL1的话这里会死锁
+    serial::unlock_for_panic();
+    let name = current_test_name().unwrap_or("<unknown>");
+    serial_println!("{}\n", StatusLine::new(Status::TimedOut, None));
+    serial_println!("Error: test {} exceeded its deadline\n", name);
+    testing::record_failure(name, format_args!("exceeded its deadline"));
+    let _ = testing::print_summary(&mut serial::Serial);
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
+    vga_buffer::unlock_for_panic();
+    serial_println!("{}\n", StatusLine::new(Status::Failed, None));
     serial_println!("Error: {}\n", info);
+    let _ = backtrace::print(&mut serial::Serial);
+    if let Some(name) = current_test_name() {
+        testing::record_failure(name, format_args!("{}", info));
+    }
+    let _ = testing::print_summary(&mut serial::Serial);
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
 
+// 编译时通过环境变量筛选测试, 如`TEST_FILTER=vga cargo test --lib`.
+// 命令行中的test_filter=和test_skip=优先
+const TEST_FILTER: Option<&str> = option_env!("TEST_FILTER");
+const TEST_SKIP: Option<&str> = option_env!("TEST_SKIP");
+
+/// 名称包含TEST_FILTER且不包含TEST_SKIP的测试才运行, 空字符串视为未设置
+fn test_selected(name: &str, filter: Option<&str>, skip: Option<&str>) -> bool {
+    let included = match filter {
+        Some(filter) if !filter.is_empty() => name.contains(filter),
+        _ => true,
+    };
+    let excluded = match skip {
+        Some(skip) if !skip.is_empty() => name.contains(skip),
+        _ => false,
+    };
+    included && !excluded
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
+
+    let filter = cmdline::get("test_filter").or(TEST_FILTER);
+    let skip = cmdline::get("test_skip").or(TEST_SKIP);
+    let mut run = 0;
+    let mut skipped = 0;
+    testing::begin();
     for test in tests {
-        test.run();
+        if test_selected(test.name(), filter, skip) {
+            test.run();
+            run += 1;
+        } else {
+            serial_println!("{}...\t{}", test.name(), StatusLine::new(Status::Skipped, None));
+            skipped += 1;
+        }
+    }
+
+    let _ = testing::print_summary(&mut serial::Serial);
+    serial_println!("test result: {} run, {} skipped, {} total", run, skipped, tests.len());
+
+    // 筛选条件一个测试都没匹配上, 多半是拼写错误
+    if run == 0 && !tests.is_empty() {
+        serial_println!(
+            "Error: no tests matched TEST_FILTER={:?} TEST_SKIP={:?}\n",
+            filter.unwrap_or(""),
+            skip.unwrap_or("")
+        );
+        exit_qemu(QemuExitCode::Failed);
     }
+    speaker::success_beep();
     exit_qemu(QemuExitCode::Success);
 }
 
+#[test_case]
+fn test_filter_selection() {
+    let name = "toy_os::vga_buffer::test_print_many_characters";
+    assert!(test_selected(name, None, None));
+    assert!(test_selected(name, Some(""), Some("")));
+    assert!(test_selected(name, Some("vga"), None));
+    assert!(!test_selected(name, Some("serial"), None));
+    assert!(!test_selected(name, None, Some("print")));
+    assert!(!test_selected(name, Some("vga"), Some("many")));
+}
+
 #[repr(u32)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum QemuExitCode {
@@ -68,11 +437,14 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 }
 
 
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
 /// 测试入口
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    init_memory(boot_info);
     test_main();
     hlt_loop();
 }
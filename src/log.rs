@@ -0,0 +1,69 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::vga_buffer::Role;
+
+/// 日志级别, 越靠前越重要
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// 解析级别名称, 或者1(error)到4(debug)的数字
+    pub fn parse(name: &str) -> Option<Level> {
+        match name {
+            "error" | "1" => Some(Level::Error),
+            "warn" | "2" => Some(Level::Warn),
+            "info" | "3" => Some(Level::Info),
+            "debug" | "4" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    /// 这一级别的消息在控制台上使用的颜色
+    pub fn role(self) -> Role {
+        match self {
+            Level::Error => Role::Error,
+            Level::Warn => Role::Warn,
+            Level::Info => Role::Info,
+            Level::Debug => Role::Normal,
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// 高于`level`的消息不再输出
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// `level`的消息是否输出
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// 按级别过滤后以级别对应的颜色输出到控制台
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            $crate::println_role!($crate::log::Level::role($level), $($arg)*);
+        }
+    };
+}
+
+#[test_case]
+fn test_levels() {
+    assert_eq!(Level::parse("warn"), Some(Level::Warn));
+    assert_eq!(Level::parse("4"), Some(Level::Debug));
+    assert_eq!(Level::parse("verbose"), None);
+    assert!(enabled(Level::Error));
+    set_max_level(Level::Warn);
+    assert!(enabled(Level::Warn) && !enabled(Level::Info));
+    set_max_level(Level::Info);
+}
@@ -4,15 +4,32 @@
 #![test_runner(toy_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-#[cfg(not(test))]
-use toy_os::println;
+use toy_os::error::KernelError;
+use toy_os::{batch, bootprof, debugcon, klog, mouse, net, ramfs, selftest, shell, vfs};
+use toy_os::{print, println};
+use toy_os::task::executor::Executor;
+use toy_os::task::Task;
+use x86_64::registers::control::Cr3;
+
+entry_point!(kernel_main);
 
 /// 正常panic handler
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    klog::on_panic();
+    toy_os::vga_buffer::unlock_for_panic();
+    toy_os::vga_buffer::console::on_panic();
+    toy_os::println_role!(toy_os::vga_buffer::Role::Panic, "{}", info);
+    toy_os::gdbstub::on_panic();
+    if batch::running() {
+        batch::on_panic(info);
+    }
+    let _ = toy_os::backtrace::print(&mut toy_os::vga_buffer::Console);
+    klog::persist::flush_on_panic();
+    toy_os::speaker::panic_beep();
     toy_os::hlt_loop();
 }
 
@@ -22,17 +39,84 @@ fn panic(info: &PanicInfo) -> ! {
     toy_os::test_panic_handler(info)
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     toy_os::init();
+    toy_os::init_memory(boot_info);
+    // 在启动横幅之前输出上一次启动的日志
+    klog::recover();
+    toy_os::print_banner();
+    toy_os::bootinfo::print_summary();
+    if let Some(motd) = ramfs::open("/etc/motd") {
+        print!("{}", core::str::from_utf8(motd.as_bytes()).unwrap_or("(motd is not UTF-8)\n"));
+    }
+    toy_os::pci::print_devices();
+    toy_os::cpu::msr::report();
+    bootprof::mark("smp");
+    match toy_os::smp::boot_aps() {
+        Ok(_) => println!("{} CPUs online", toy_os::smp::online_cpus()),
+        Err(err) => println!("SMP startup failed: {:?}", err),
+    }
+    bootprof::mark("smp");
 
     #[cfg(test)]
     test_main();
 
-    #[cfg(not(test))]
-    use x86_64::registers::control::Cr3;
+    // 自检在启动任务之前同步运行, 结束后按命令行退出或继续启动外壳
+    if selftest::requested() {
+        selftest::run_and_report();
+    }
+
     let (level_4_page_table, _) = Cr3::read();
     println!("Level 4 page table at: {:?}", level_4_page_table.start_address());
 
-    toy_os::hlt_loop();
+    let mut executor = Executor::new();
+    executor.spawn(Task::named("klog", klog::persist::run()));
+    // 接上了带FAT卷的从盘时记录这次启动, 等待磁盘期间其他任务照常运行
+    executor.spawn(Task::named("disk", async {
+        bootprof::mark("disk");
+        let result = match vfs::disk::mount().await {
+            Ok(()) => vfs::disk::append_boot_log().await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(boot) => println!("boot {} recorded in {}", boot, vfs::disk::BOOT_LOG),
+            Err(KernelError::NotFound(_)) => {}
+            Err(err) => println!("{}: {}", vfs::disk::MOUNT_POINT, err),
+        }
+        bootprof::mark("disk");
+    }));
+    #[cfg(feature = "heap-canaries")]
+    executor.spawn(Task::named("heap-scrub", toy_os::allocator::canary::scrub()));
+    // 通过DHCP获取地址, 失败时使用QEMU user模式网络的默认地址, 之后运行命令行指定的HTTP请求
+    if let Some(mac) = net::mac_address() {
+        executor.spawn(Task::named("net", net::stack::run()));
+        executor.spawn(Task::named("dhcp", async move {
+            match net::dhcp::acquire().await {
+                Ok(lease) => println!("dhcp: {} via {}", lease.ip, lease.server),
+                Err(err) => {
+                    println!("dhcp failed: {:?}, using a static address", err);
+                    net::configure(
+                        net::Ipv4Addr::new(10, 0, 2, 15),
+                        net::Ipv4Addr::new(255, 255, 255, 0),
+                        net::Ipv4Addr::new(10, 0, 2, 2),
+                        mac,
+                    );
+                }
+            }
+            net::http::demo().await;
+        }));
+    }
+    // 批处理模式下依次运行命令行指定的工作负载, 结束后退出, 不启动外壳
+    if let Some(names) = batch::requested() {
+        executor.spawn(Task::named("batch", batch::run(names)));
+        executor.run();
+    }
+    // 没有鼠标时演示无法结束, 跳过
+    if toy_os::ps2::mouse_enabled() {
+        executor.spawn(Task::named("cursor", mouse::cursor_demo()));
+    }
+    bootprof::log_report();
+    executor.spawn(Task::named("shell", shell::run()));
+    executor.spawn(Task::named("debugcon", debugcon::run()));
+    executor.run();
 }
\ No newline at end of file
@@ -0,0 +1,481 @@
+use alloc::string::String;
+use core::fmt;
+use core::ops::Range;
+
+use spin::Once;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+use crate::shell::{self, args, ArgError, CmdError};
+
+/// 调试命令访问内存失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemAccessError {
+    /// 地址不在规范地址范围内
+    NonCanonical(u64),
+    /// 第一个无法访问的地址所在页没有映射
+    Unmapped(VirtAddr),
+    /// 页没有WRITABLE
+    ReadOnly(VirtAddr),
+    /// 地址在内核代码中, 见[`kernel_text`]
+    KernelText(VirtAddr),
+}
+
+impl fmt::Display for MemAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemAccessError::NonCanonical(addr) => {
+                write!(f, "{:#x} is not a canonical address", addr)
+            }
+            MemAccessError::Unmapped(addr) => write!(f, "{:#x} is not mapped", addr.as_u64()),
+            MemAccessError::ReadOnly(addr) => write!(f, "{:#x} is read-only", addr.as_u64()),
+            MemAccessError::KernelText(addr) => {
+                write!(f, "{:#x} is kernel code", addr.as_u64())
+            }
+        }
+    }
+}
+
+/// 从`addr`开始读满`buf`, 访问前逐页检查映射. 返回读到的字节数, 遇到未映射的页时停在页首;
+/// 第一个字节就无法读取时返回错误
+pub fn safe_read_bytes(addr: VirtAddr, buf: &mut [u8]) -> Result<usize, MemAccessError> {
+    let start = addr.as_u64();
+    let mut read = 0;
+    while read < buf.len() {
+        let Some(current) = start.checked_add(read as u64) else {
+            break;
+        };
+        let current = match VirtAddr::try_new(current) {
+            Ok(current) if memory::is_mapped(current) => current,
+            Ok(current) if read == 0 => return Err(MemAccessError::Unmapped(current)),
+            Err(_) if read == 0 => return Err(MemAccessError::NonCanonical(current)),
+            _ => break,
+        };
+        // 读到页尾或者`buf`结束
+        let count = (4096 - u64::from(current.page_offset()) as usize).min(buf.len() - read);
+        for (i, byte) in buf[read..read + count].iter_mut().enumerate() {
+            *byte = unsafe { (current + i as u64).as_ptr::<u8>().read_volatile() };
+        }
+        read += count;
+    }
+    Ok(read)
+}
+
+/// 向`addr`写入一个字节. 拒绝未映射、只读和内核代码中的地址
+pub fn safe_write_byte(addr: VirtAddr, value: u8) -> Result<(), MemAccessError> {
+    check_writable(addr)?;
+    unsafe { addr.as_mut_ptr::<u8>().write_volatile(value) };
+    Ok(())
+}
+
+fn check_writable(addr: VirtAddr) -> Result<(), MemAccessError> {
+    let flags = memory::page_flags(addr).map_err(|_| MemAccessError::Unmapped(addr))?;
+    if kernel_text().contains(&addr.as_u64()) {
+        return Err(MemAccessError::KernelText(addr));
+    }
+    if !flags.contains(PageTableFlags::WRITABLE) {
+        return Err(MemAccessError::ReadOnly(addr));
+    }
+    Ok(())
+}
+
+/// 内核代码所在的虚拟地址范围: 包含这个函数的、连续的只读可执行内核页
+///
+/// 没有链接脚本提供的符号, 因此按bootloader映射内核段时使用的页表标志推断.
+/// 没有启用NX时范围可能包括相邻的只读数据
+pub fn kernel_text() -> Range<u64> {
+    static TEXT: Once<Range<u64>> = Once::new();
+    TEXT.call_once(|| {
+        let is_text = |page: u64| {
+            VirtAddr::try_new(page)
+                .ok()
+                .and_then(|page| memory::page_flags(page).ok())
+                .is_some_and(|flags| {
+                    !flags.intersects(
+                        PageTableFlags::WRITABLE
+                            | PageTableFlags::NO_EXECUTE
+                            | PageTableFlags::USER_ACCESSIBLE,
+                    )
+                })
+        };
+        let here = kernel_text as fn() -> Range<u64> as usize as u64 & !0xfff;
+        let mut start = here;
+        while start >= 4096 && is_text(start - 4096) {
+            start -= 4096;
+        }
+        let mut end = here + 4096;
+        while is_text(end) {
+            end += 4096;
+        }
+        start..end
+    })
+    .clone()
+}
+
+/// 注册`hexdump`、`physdump`、`peek`和`poke`命令
+pub fn register_commands() {
+    shell::register_command(
+        "hexdump",
+        "hexdump <addr> <len>: dump memory",
+        hexdump_command,
+    )
+    .expect("duplicate memdebug command");
+    shell::register_command(
+        "physdump",
+        "physdump <addr> <len>: dump physical memory",
+        physdump_command,
+    )
+    .expect("duplicate memdebug command");
+    shell::register_command("peek", "peek <addr>: read one byte", peek_command)
+        .expect("duplicate memdebug command");
+    shell::register_command(
+        "poke",
+        "poke <addr> <byte> [-y]: write one byte, -y to confirm",
+        poke_command,
+    )
+    .expect("duplicate memdebug command");
+}
+
+// hexdump一次最多输出的字节数
+const MAX_DUMP_LEN: u64 = 4096;
+const HEXDUMP_USAGE: &str = "hexdump <addr> <len>";
+const PHYSDUMP_USAGE: &str = "physdump <addr> <len>";
+const PEEK_USAGE: &str = "peek <addr>";
+const POKE_USAGE: &str = "poke <addr> <byte> [-y]";
+
+fn parse_addr(arg: &str) -> Result<VirtAddr, CmdError> {
+    let addr = args::parse_number(arg)?;
+    VirtAddr::try_new(addr).map_err(|_| CmdError::Memory(MemAccessError::NonCanonical(addr)))
+}
+
+fn hexdump_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let [addr, len] = args else {
+        return Err(CmdError::Usage(HEXDUMP_USAGE));
+    };
+    let start = args::parse_number(addr)?;
+    let len = args::parse_number(len)?;
+    if len > MAX_DUMP_LEN {
+        return Err(CmdError::Usage(HEXDUMP_USAGE));
+    }
+    if len == 0 {
+        return Ok(());
+    }
+    if start.checked_add(len - 1).is_none() {
+        return Err(CmdError::Usage(HEXDUMP_USAGE));
+    }
+    let start = parse_addr(addr)?;
+    let mut bytes = alloc::vec![0u8; len as usize];
+    let read = safe_read_bytes(start, &mut bytes).map_err(CmdError::Memory)?;
+    for (i, line) in bytes[..read].chunks(16).enumerate() {
+        writeln!(
+            out,
+            "{}",
+            hexdump_line(start.as_u64() + i as u64 * 16, line)
+        )?;
+    }
+    if read < bytes.len() {
+        // 输出已读到的部分后报告停下的位置
+        let next = start.as_u64() + read as u64;
+        return Err(CmdError::Memory(match VirtAddr::try_new(next) {
+            Ok(next) => MemAccessError::Unmapped(next),
+            Err(_) => MemAccessError::NonCanonical(next),
+        }));
+    }
+    Ok(())
+}
+
+// 物理内存通过`memory::read_phys`整段读取, 范围超出内存映射时什么都不输出
+fn physdump_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let [addr, len] = args else {
+        return Err(CmdError::Usage(PHYSDUMP_USAGE));
+    };
+    let start = args::parse_number(addr)?;
+    let len = args::parse_number(len)?;
+    if len > MAX_DUMP_LEN {
+        return Err(CmdError::Usage(PHYSDUMP_USAGE));
+    }
+    // 超出物理地址宽度的地址不截断
+    let start = PhysAddr::try_new(start).map_err(|_| CmdError::Usage(PHYSDUMP_USAGE))?;
+    let mut bytes = alloc::vec![0u8; len as usize];
+    memory::read_phys(start, &mut bytes)?;
+    for (i, line) in bytes.chunks(16).enumerate() {
+        writeln!(
+            out,
+            "{}",
+            hexdump_line(start.as_u64() + i as u64 * 16, line)
+        )?;
+    }
+    Ok(())
+}
+
+// 一行最多16字节: 地址、按4字节分组的十六进制和可打印字符
+fn hexdump_line(addr: u64, bytes: &[u8]) -> String {
+    use core::fmt::Write;
+
+    let mut line = String::new();
+    let _ = write!(line, "{:016x}:", addr);
+    for i in 0..16 {
+        if i % 4 == 0 {
+            line.push(' ');
+        }
+        match bytes.get(i) {
+            Some(byte) => {
+                let _ = write!(line, "{:02x}", byte);
+            }
+            None => line.push_str("  "),
+        }
+    }
+    line.push_str("  ");
+    for &byte in bytes {
+        if byte == b' ' || byte.is_ascii_graphic() {
+            line.push(char::from(byte));
+        } else {
+            line.push('.');
+        }
+    }
+    line
+}
+
+fn peek_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let [addr] = args else {
+        return Err(CmdError::Usage(PEEK_USAGE));
+    };
+    let addr = parse_addr(addr)?;
+    let mut byte = [0u8];
+    safe_read_bytes(addr, &mut byte).map_err(CmdError::Memory)?;
+    writeln!(out, "{:016x}: {:02x}", addr.as_u64(), byte[0])?;
+    Ok(())
+}
+
+fn poke_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let (addr, value, confirmed) = match args {
+        [addr, value] => (addr, value, false),
+        [addr, value, "-y"] => (addr, value, true),
+        _ => return Err(CmdError::Usage(POKE_USAGE)),
+    };
+    let addr = parse_addr(addr)?;
+    let value = u8::try_from(args::parse_number(value)?)
+        .map_err(|_| CmdError::BadArgument(ArgError::InvalidNumber))?;
+    check_writable(addr).map_err(CmdError::Memory)?;
+    let mut old = [0u8];
+    safe_read_bytes(addr, &mut old).map_err(CmdError::Memory)?;
+    if !confirmed {
+        writeln!(
+            out,
+            "{:016x}: {:02x} -> {:02x}, add -y to write",
+            addr.as_u64(),
+            old[0],
+            value
+        )?;
+        return Ok(());
+    }
+    safe_write_byte(addr, value).map_err(CmdError::Memory)?;
+    writeln!(
+        out,
+        "{:016x}: {:02x} -> {:02x}",
+        addr.as_u64(),
+        old[0],
+        value
+    )?;
+    Ok(())
+}
+
+#[test_case]
+fn test_hexdump_line() {
+    assert_eq!(
+        hexdump_line(0xb8000, b"0123456789abcdef"),
+        "00000000000b8000: 30313233 34353637 38396162 63646566  0123456789abcdef"
+    );
+    assert_eq!(
+        hexdump_line(0x10, &[0, b'a', 0xff]),
+        "0000000000000010: 0061ff                               .a."
+    );
+}
+
+#[test_case]
+fn test_hexdump_command() {
+    use alloc::format;
+
+    static DATA: [u8; 20] = *b"hexdump test data!\0\n";
+    let addr = DATA.as_ptr() as u64;
+    let mut out = String::new();
+    let start = format!("{:#x}", addr);
+    hexdump_command(&[start.as_str(), "20"], &mut out).unwrap();
+    assert_eq!(
+        out,
+        format!(
+            "{}\n{}\n",
+            hexdump_line(addr, &DATA[..16]),
+            hexdump_line(addr + 16, &DATA[16..])
+        )
+    );
+    assert!(out.contains("hexdump test dat"));
+
+    let mut out = String::new();
+    let usage = Err(CmdError::Usage(HEXDUMP_USAGE));
+    assert_eq!(hexdump_command(&["0x1000"], &mut out), usage);
+    assert_eq!(
+        hexdump_command(&["0x1000", "zz"], &mut out),
+        Err(CmdError::BadArgument(ArgError::InvalidNumber))
+    );
+    assert_eq!(hexdump_command(&["0", "0x2000"], &mut out), usage);
+    assert_eq!(
+        hexdump_command(&["0xffffffffffffffff", "2"], &mut out),
+        usage
+    );
+    // 非规范地址
+    assert_eq!(
+        hexdump_command(&["0x800000000000", "1"], &mut out),
+        Err(CmdError::Memory(MemAccessError::NonCanonical(
+            0x8000_0000_0000
+        )))
+    );
+    // 内核栈保护页
+    let stack = memory::alloc_stack(1).unwrap();
+    let guard = stack - 2 * 4096u64;
+    let start = format!("{:#x}", guard.as_u64() + 16);
+    assert_eq!(
+        hexdump_command(&[start.as_str(), "16"], &mut out),
+        Err(CmdError::Memory(MemAccessError::Unmapped(guard + 16u64)))
+    );
+    assert!(out.is_empty());
+}
+
+#[test_case]
+fn test_physdump_command() {
+    use alloc::format;
+    use crate::memory::MemError;
+
+    static DATA: [u8; 16] = *b"physdump order!\n";
+    let virt = VirtAddr::from_ptr(DATA.as_ptr());
+    let phys = memory::translate(virt).unwrap();
+    // 数据不跨页时物理地址连续
+    if u64::from(virt.page_offset()) <= 4096 - 16 {
+        let mut out = String::new();
+        let start = format!("{:#x}", phys.as_u64());
+        physdump_command(&[start.as_str(), "16"], &mut out).unwrap();
+        assert_eq!(out, format!("{}\n", hexdump_line(phys.as_u64(), &DATA)));
+    }
+
+    let last = crate::bootinfo::memory_map()
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap();
+    let mut out = String::new();
+    let start = format!("{:#x}", last - 8);
+    let err = physdump_command(&[start.as_str(), "16"], &mut out).unwrap_err();
+    assert_eq!(
+        err,
+        CmdError::Mem(MemError::OutOfPhysicalMemory(Some(PhysAddr::new(last))))
+    );
+    assert_eq!(
+        format!("{}", err),
+        format!("physical address {:#x} does not exist", last)
+    );
+    assert!(out.is_empty());
+    assert_eq!(
+        physdump_command(&["0xfff0000000000000", "1"], &mut out),
+        Err(CmdError::Usage(PHYSDUMP_USAGE))
+    );
+}
+
+#[test_case]
+fn test_read_across_page_edge() {
+    use alloc::format;
+
+    // 栈顶之上是下一个栈的保护页
+    let top = memory::alloc_stack(1).unwrap();
+    let data = top - 32u64;
+    for i in 0..32u8 {
+        unsafe { (data + i as u64).as_mut_ptr::<u8>().write(i) };
+    }
+    let mut buf = [0xffu8; 64];
+    assert_eq!(safe_read_bytes(data, &mut buf), Ok(32));
+    assert!(buf[..32].iter().copied().eq(0..32));
+    assert!(buf[32..].iter().all(|&byte| byte == 0xff));
+    assert_eq!(
+        safe_read_bytes(top, &mut buf),
+        Err(MemAccessError::Unmapped(top))
+    );
+    assert_eq!(safe_read_bytes(top, &mut []), Ok(0));
+
+    // 输出停在页边界, 然后报告第一个未映射的地址
+    let mut out = String::new();
+    let start = format!("{:#x}", data.as_u64());
+    assert_eq!(
+        hexdump_command(&[start.as_str(), "64"], &mut out),
+        Err(CmdError::Memory(MemAccessError::Unmapped(top)))
+    );
+    let bytes: [u8; 32] = core::array::from_fn(|i| i as u8);
+    assert_eq!(
+        out,
+        format!(
+            "{}\n{}\n",
+            hexdump_line(data.as_u64(), &bytes[..16]),
+            hexdump_line(data.as_u64() + 16, &bytes[16..])
+        )
+    );
+}
+
+#[test_case]
+fn test_peek_and_poke() {
+    use alloc::boxed::Box;
+    use alloc::format;
+
+    let text = kernel_text();
+    let code = peek_command as shell::Handler as usize as u64;
+    let here = kernel_text as fn() -> Range<u64> as usize as u64;
+    assert!(text.contains(&code) && text.contains(&here));
+
+    let cell = Box::new(0x5au8);
+    let addr = format!("{:#x}", &*cell as *const u8 as u64);
+    let mut out = String::new();
+    peek_command(&[addr.as_str()], &mut out).unwrap();
+    assert!(out.trim_end().ends_with(": 5a"), "{}", out);
+
+    // 不带-y时只显示将要写入的值
+    let mut out = String::new();
+    poke_command(&[addr.as_str(), "0xa5"], &mut out).unwrap();
+    assert!(out.contains("add -y"));
+    assert_eq!(unsafe { core::ptr::read_volatile(&*cell) }, 0x5a);
+    poke_command(&[addr.as_str(), "0xa5", "-y"], &mut out).unwrap();
+    assert_eq!(unsafe { core::ptr::read_volatile(&*cell) }, 0xa5);
+    assert_eq!(
+        poke_command(&[addr.as_str(), "256", "-y"], &mut out),
+        Err(CmdError::BadArgument(ArgError::InvalidNumber))
+    );
+    assert_eq!(
+        poke_command(&[addr.as_str()], &mut out),
+        Err(CmdError::Usage(POKE_USAGE))
+    );
+
+    // 内核代码, 带不带-y都拒绝
+    let code_arg = format!("{:#x}", code);
+    for confirm in [&[][..], &["-y"][..]] {
+        let mut args = alloc::vec![code_arg.as_str(), "0xcc"];
+        args.extend_from_slice(confirm);
+        assert_eq!(
+            poke_command(&args, &mut out),
+            Err(CmdError::Memory(MemAccessError::KernelText(VirtAddr::new(
+                code
+            ))))
+        );
+    }
+    let first = format!("{:#x}", text.start);
+    assert!(matches!(
+        poke_command(&[first.as_str(), "0", "-y"], &mut out),
+        Err(CmdError::Memory(MemAccessError::KernelText(_)))
+    ));
+    assert_eq!(
+        safe_write_byte(VirtAddr::new(code), 0xcc),
+        Err(MemAccessError::KernelText(VirtAddr::new(code)))
+    );
+    assert_eq!(
+        peek_command(&["0x800000000000"], &mut out),
+        Err(CmdError::Memory(MemAccessError::NonCanonical(
+            0x8000_0000_0000
+        )))
+    );
+}
@@ -0,0 +1,939 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::{Mutex, Once};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{allocator, layout, vga_buffer};
+use crate::selftest::{self, Check, Outcome};
+use crate::shell::{self, CmdError};
+use crate::sync::IrqMutex;
+
+pub mod addr;
+mod address_space;
+pub mod audit;
+pub mod frames;
+pub mod refcount;
+
+pub use addr::MemError;
+pub(crate) use address_space::{handle_cow_fault, map_active_user_page, unmap_active_user_pages};
+pub use address_space::{AddressSpace, COW};
+pub use audit::{audit_mappings, AuditReport};
+pub use frames::FrameUsage;
+pub use refcount::FrameRefs;
+
+// 初始化完成后供驱动使用的页表、物理帧分配器和物理内存映射
+static MAPPER: IrqMutex<Option<OffsetPageTable<'static>>> = IrqMutex::new("memory::MAPPER", None);
+static FRAME_ALLOCATOR: IrqMutex<Option<BootInfoFrameAllocator>> =
+    IrqMutex::new("memory::FRAME_ALLOCATOR", None);
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+// bootloader提供的内存映射, 用于检查物理地址是否存在
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+// 内核页表的4级页表所在的帧
+static KERNEL_P4: Once<PhysFrame> = Once::new();
+
+// 设备寄存器在`layout::MMIO`中依次分配, 不回收
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(layout::MMIO.start);
+
+// 内核栈在`layout::STACKS`中分配. 释放的栈记录栈顶和页数, 地址留给相同大小的栈
+static STACK_NEXT: AtomicU64 = AtomicU64::new(layout::STACKS.start);
+static FREE_STACKS: Mutex<Vec<(VirtAddr, u64)>> = Mutex::new(Vec::new());
+
+/// 初始化OffsetPageTable, 同时记录物理内存偏移, 之后`phys_to_virt`可用
+///
+/// # Safety
+///
+/// 调用者需保证整个物理内存已映射到`physical_memory_offset`处, 且只能调用一次
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+// 通过物理内存映射访问当前的4级页表
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+/// 从bootloader提供的内存映射中分配可用的物理帧. 释放的帧串成链表, 优先再次分配
+///
+/// 每个帧的用途记录在最高的可用区域末尾的标记表中, 每帧一字节. 标记表占用的帧不再可用.
+/// 被多个页表项共享的用户页另外记录引用计数, 最后一个映射去掉时才释放
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+    // 空闲链表头, 每个空闲帧的前8字节存放下一帧的物理地址
+    free_list: Option<PhysFrame>,
+    // 按物理帧号索引的用途标记, 以及它占用的物理地址范围
+    tags: &'static mut [u8],
+    reserved: Range<u64>,
+    by_usage: [usize; FrameUsage::ALL.len()],
+    refs: FrameRefs,
+}
+
+// 空闲链表的结尾
+const FREE_LIST_END: u64 = u64::MAX;
+
+impl BootInfoFrameAllocator {
+    /// 没有区域放得下标记表时panic
+    ///
+    /// # Safety
+    ///
+    /// 调用者需保证内存映射中标记为USABLE的帧确实未被使用, 且整个物理内存已映射到
+    /// `physical_memory_offset`处
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let end = usable().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let len = (end / 4096) as usize;
+        let size = len.next_multiple_of(4096) as u64;
+        let region = usable()
+            .filter(|r| r.range.end_addr() - r.range.start_addr() >= size)
+            .max_by_key(|r| r.range.start_addr())
+            .expect("no usable region can hold the frame usage table");
+        let start = region.range.end_addr() - size;
+        let tags = core::slice::from_raw_parts_mut(
+            (physical_memory_offset + start).as_mut_ptr::<u8>(),
+            len,
+        );
+        tags.fill(0);
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+            free_list: None,
+            tags,
+            reserved: start..start + size,
+            by_usage: [0; FrameUsage::ALL.len()],
+            refs: FrameRefs::new(),
+        }
+    }
+
+    // 所有可用的物理帧, 不包括标记表
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let reserved = self.reserved.clone();
+        let regions = self.memory_map.iter();
+        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        // 按4KiB对齐切分
+        let frame_addresses = addr_ranges
+            .flat_map(|r| r.step_by(4096))
+            .filter(move |addr| !reserved.contains(addr));
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// 分配一个用于`usage`的帧, 优先使用释放过的帧
+    pub fn allocate(&mut self, usage: FrameUsage) -> Option<PhysFrame> {
+        let frame = match self.free_list {
+            Some(frame) => {
+                let next = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+                self.free_list = (next != FREE_LIST_END)
+                    .then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+                frame
+            }
+            None => {
+                let frame = self.usable_frames().nth(self.next)?;
+                self.next += 1;
+                frame
+            }
+        };
+        self.mark(frame, usage);
+        Some(frame)
+    }
+
+    /// 分配`count`个物理地址连续的帧, 返回第一帧. 为凑齐连续区域而跳过的帧不再使用, 计为`Other`
+    pub fn allocate_contiguous(&mut self, count: usize, usage: FrameUsage) -> Option<PhysFrame> {
+        let mut start = None;
+        let mut run = 0;
+        let mut expected = 0;
+        let mut found = None;
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            let addr = frame.start_address().as_u64();
+            if run > 0 && addr == expected {
+                run += 1;
+            } else {
+                start = Some((index, frame));
+                run = 1;
+            }
+            expected = addr + 4096;
+            if run == count {
+                found = start;
+                break;
+            }
+        }
+        let (first, frame) = found?;
+        self.skip_to(first);
+        for frame in PhysFrame::range(frame, frame + count as u64) {
+            self.mark(frame, usage);
+        }
+        self.next = first + count;
+        Some(frame)
+    }
+
+    /// 分配一个物理地址低于`limit`的帧, 跳过第0帧. 跳过的帧不再使用, 应在其他分配之前调用
+    pub fn allocate_below(&mut self, limit: PhysAddr, usage: FrameUsage) -> Option<PhysFrame> {
+        let (index, frame) = self
+            .usable_frames()
+            .enumerate()
+            .skip(self.next)
+            .take_while(|(_, frame)| frame.start_address() < limit)
+            .find(|(_, frame)| frame.start_address().as_u64() != 0)?;
+        self.skip_to(index);
+        self.mark(frame, usage);
+        self.next = index + 1;
+        Some(frame)
+    }
+
+    // 把`next`到`index`之前的帧计为跳过. 可能在堆初始化之前调用
+    fn skip_to(&mut self, index: usize) {
+        let skipped = self.usable_frames().skip(self.next).take(index - self.next);
+        for frame in skipped {
+            self.mark(frame, FrameUsage::Other);
+        }
+    }
+
+    fn mark(&mut self, frame: PhysFrame, usage: FrameUsage) {
+        self.tags[frame.start_address().as_u64() as usize / 4096] = usage.tag();
+        self.by_usage[usage.index()] += 1;
+    }
+
+    /// 已分配的帧的用途, 空闲或不可用的帧为None
+    pub fn usage(&self, frame: PhysFrame) -> Option<FrameUsage> {
+        let tag = self
+            .tags
+            .get(frame.start_address().as_u64() as usize / 4096)?;
+        FrameUsage::from_tag(*tag)
+    }
+
+    /// 按地址顺序排列的所有可用帧的用途
+    pub fn usage_map(&self) -> impl Iterator<Item = (PhysFrame, Option<FrameUsage>)> + '_ {
+        self.usable_frames().map(|frame| (frame, self.usage(frame)))
+    }
+
+    /// 又一个页表项映射了`frame`
+    pub fn share(&mut self, frame: PhysFrame) {
+        self.refs.increment(frame);
+    }
+
+    /// 映射`frame`的页表项数
+    pub fn ref_count(&self, frame: PhysFrame) -> u32 {
+        self.refs.count(frame)
+    }
+
+    /// 一个映射`frame`的页表项不再使用它, 是最后一个映射时释放帧
+    ///
+    /// # Safety
+    ///
+    /// 调用者需保证这个映射已经撤销
+    pub unsafe fn release(&mut self, frame: PhysFrame) {
+        if self.refs.decrement(frame) {
+            self.deallocate_frame(frame);
+        }
+    }
+}
+
+/// 物理帧的使用情况
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// 已分配或被跳过且没有释放的帧数
+    pub allocated: usize,
+    /// 内存映射中可用的帧数, 不包括标记表
+    pub total: usize,
+    /// 各用途的帧数, 按`FrameUsage::index`排列, 总和等于`allocated`
+    pub by_usage: [usize; FrameUsage::ALL.len()],
+    /// 被多个页表项共享的帧数, 它们也计入`allocated`
+    pub shared: usize,
+}
+
+impl FrameStats {
+    /// 用于`usage`的帧数
+    pub fn usage(&self, usage: FrameUsage) -> usize {
+        self.by_usage[usage.index()]
+    }
+}
+
+impl BootInfoFrameAllocator {
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            allocated: self.by_usage.iter().sum(),
+            total: self.usable_frames().count(),
+            by_usage: self.by_usage,
+            shared: self.refs.shared(),
+        }
+    }
+}
+
+/// 页表映射时通过这个接口分配下级页表, 分配的帧计为`PageTable`
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(FrameUsage::PageTable)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// 按分配时的用途减少计数. 释放的帧内容不清零, 需要时由下一个使用者清零
+    ///
+    /// 释放空闲的帧会panic
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame.start_address().as_u64() as usize / 4096;
+        let usage = self
+            .tags
+            .get(index)
+            .and_then(|&tag| FrameUsage::from_tag(tag))
+            .expect("deallocating a free frame");
+        self.tags[index] = 0;
+        self.by_usage[usage.index()] -= 1;
+        let next = self
+            .free_list
+            .map_or(FREE_LIST_END, |next| next.start_address().as_u64());
+        phys_to_virt(frame.start_address()).as_mut_ptr::<u64>().write(next);
+        self.free_list = Some(frame);
+    }
+}
+
+/// 保存页表、帧分配器和物理内存偏移, 之后驱动可以分配DMA内存和映射寄存器
+pub fn install(
+    physical_memory_offset: VirtAddr,
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    MEMORY_MAP.call_once(|| frame_allocator.memory_map);
+    KERNEL_P4.call_once(|| x86_64::registers::control::Cr3::read().0);
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// 通过物理内存映射访问物理地址
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("physical memory offset not installed");
+    *offset + phys.as_u64()
+}
+
+/// 把物理地址`phys`起的内容读满`buf`, 范围必须都在内存映射中
+pub fn read_phys(phys: PhysAddr, buf: &mut [u8]) -> Result<(), MemError> {
+    addr::check_phys_exists(phys, buf.len() as u64)?;
+    let src: *const u8 = phys_to_virt(phys).as_ptr();
+    unsafe {
+        core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+    }
+    Ok(())
+}
+
+/// 已安装的帧分配器的使用情况, `install`之前为None
+pub fn frame_stats() -> Option<FrameStats> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map(BootInfoFrameAllocator::stats)
+}
+
+/// `addr`所在的页在当前地址空间中是否已映射, `install`之前总是false
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    translate(addr).is_ok()
+}
+
+/// `addr`在当前地址空间中对应的物理地址, `install`之前总是`NotMapped`
+pub fn translate(addr: VirtAddr) -> Result<PhysAddr, MemError> {
+    with_active_table(|table| table.translate_addr(addr))
+        .flatten()
+        .ok_or(MemError::NotMapped(addr))
+}
+
+/// `addr`所在页在当前地址空间中的页表项标志, `install`之前总是`NotMapped`
+pub fn page_flags(addr: VirtAddr) -> Result<PageTableFlags, MemError> {
+    with_active_table(|table| match table.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    })
+    .flatten()
+    .ok_or(MemError::NotMapped(addr))
+}
+
+/// 与`translate`相同但不加锁, 供panic和异常处理使用, 那时页表锁可能被打断的代码持有
+///
+/// # Safety
+///
+/// 调用者需保证没有其他CPU同时修改页表
+pub unsafe fn translate_unlocked(addr: VirtAddr) -> Result<PhysAddr, MemError> {
+    let offset = *PHYSICAL_MEMORY_OFFSET.get().ok_or(MemError::NotMapped(addr))?;
+    OffsetPageTable::new(active_level_4_table(offset), offset)
+        .translate_addr(addr)
+        .ok_or(MemError::NotMapped(addr))
+}
+
+// 在CR3指向的页表上查询. 进程运行时CR3是进程自己的页表
+fn with_active_table<R>(f: impl FnOnce(&OffsetPageTable) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // 持有锁, 避免与页表修改同时进行
+        let _mapper = MAPPER.lock();
+        let offset = *PHYSICAL_MEMORY_OFFSET.get()?;
+        let table = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+        Some(f(&table))
+    })
+}
+
+/// 分配物理地址连续并清零的帧, 用于设备DMA
+pub fn allocate_dma_frames(count: usize) -> Result<PhysFrame, MemError> {
+    let frame = x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_mut()?
+            .allocate_contiguous(count, FrameUsage::Mmio)
+    })
+    .ok_or(MemError::OutOfPhysicalMemory(None))?;
+    let ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe {
+        core::ptr::write_bytes(ptr, 0, count * 4096);
+    }
+    Ok(frame)
+}
+
+/// 把`phys`起的`size`字节设备寄存器以不可缓存方式映射到内核地址空间, 返回对应的虚拟地址
+///
+/// 设备寄存器不在内存映射中, 不检查物理地址是否存在. MMIO窗口用完时返回`Overlap`
+pub fn map_mmio(phys: PhysAddr, size: usize) -> Result<VirtAddr, MemError> {
+    let end = phys
+        .as_u64()
+        .checked_add(size.max(1) as u64 - 1)
+        .and_then(|end| PhysAddr::try_new(end).ok())
+        .ok_or(MemError::OutOfPhysicalMemory(Some(phys)))?;
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::containing_address(end);
+    let count = last - first + 1;
+    let virt_start = reserve(&MMIO_NEXT, layout::MMIO, count * 4096)?;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE;
+    with_page_tables(|mapper, allocator| {
+        for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+            let page = Page::containing_address(VirtAddr::new(virt_start + i as u64 * 4096));
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, allocator)
+                    .map_err(|err| MemError::from_map(err, page.start_address()))?
+                    .flush();
+            }
+        }
+        Ok::<(), MemError>(())
+    })?;
+    Ok(VirtAddr::new(virt_start + phys.as_u64() % 4096))
+}
+
+// 在`range`中依次分配`len`字节的虚拟地址, 不回收. 用完时返回`Overlap`, 不移动`next`
+fn reserve(next: &AtomicU64, range: layout::Range, len: u64) -> Result<u64, MemError> {
+    next.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |start| {
+        range.contains_range(start, len).then(|| start + len)
+    })
+    .map_err(|_| MemError::Overlap(VirtAddr::new(range.end)))
+}
+
+/// 分配`pages`页的内核栈, 返回栈顶. 栈底下方留一个不映射的保护页, 溢出时触发页错误
+pub fn alloc_stack(pages: u64) -> Result<VirtAddr, MemError> {
+    let reused = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut free = FREE_STACKS.lock();
+        let index = free.iter().position(|&(_, size)| size == pages)?;
+        Some(free.swap_remove(index).0)
+    });
+    let bottom = match reused {
+        Some(top) => top - pages * 4096,
+        None => {
+            let guard = reserve(&STACK_NEXT, layout::STACKS, (pages + 1) * 4096)?;
+            VirtAddr::new(guard + 4096)
+        }
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_page_tables(|mapper, allocator| {
+        for i in 0..pages {
+            let page = Page::containing_address(bottom + i * 4096);
+            let frame = allocator
+                .allocate(FrameUsage::Stack)
+                .ok_or(MemError::OutOfPhysicalMemory(None))?;
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, allocator)
+                    .map_err(|err| MemError::from_map(err, page.start_address()))?
+                    .flush();
+            }
+        }
+        Ok::<(), MemError>(())
+    })?;
+    Ok(bottom + pages * 4096)
+}
+
+/// 释放`alloc_stack`分配的栈. 地址范围留给之后页数相同的栈, 不再需要新的页表
+///
+/// 先检查所有页都已映射, 出错时不释放任何页
+///
+/// # Safety
+///
+/// 调用者需保证栈已不再使用
+pub unsafe fn free_stack(top: VirtAddr, pages: u64) -> Result<(), MemError> {
+    addr::check_aligned(top.as_u64(), 4096)?;
+    let bottom = top
+        .as_u64()
+        .checked_sub(pages * 4096)
+        .filter(|&bottom| layout::STACKS.contains_range(bottom, pages * 4096))
+        .ok_or(MemError::Overlap(top))?;
+    let bottom = VirtAddr::new(bottom);
+    with_page_tables(|mapper, allocator| {
+        for i in 0..pages {
+            let page = Page::<Size4KiB>::containing_address(bottom + i * 4096);
+            if mapper.translate_page(page).is_err() {
+                return Err(MemError::NotMapped(page.start_address()));
+            }
+        }
+        for i in 0..pages {
+            let page = Page::<Size4KiB>::containing_address(bottom + i * 4096);
+            let (frame, flush) = mapper.unmap(page).expect("stack page not mapped");
+            flush.flush();
+            allocator.deallocate_frame(frame);
+        }
+        Ok(())
+    })?;
+    x86_64::instructions::interrupts::without_interrupts(|| FREE_STACKS.lock().push((top, pages)));
+    Ok(())
+}
+
+/// 在内核页表的用户空间分配并映射从`start`开始的`pages`页, 见[`AddressSpace::map_user_pages`]
+pub fn map_user_pages(
+    start: VirtAddr,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<(), MemError> {
+    AddressSpace::kernel().map_user_pages(start, pages, flags)
+}
+
+/// 把`frame`映射到与其物理地址相同的虚拟地址, 已经这样映射时直接返回.
+/// 那个地址已映射到其他帧时返回`Overlap`
+pub fn identity_map(frame: PhysFrame) -> Result<(), MemError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_page_tables(|mapper, allocator| {
+        match unsafe { mapper.identity_map(frame, flags, allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => Ok(()),
+            Err(err) => {
+                let page = VirtAddr::new(frame.start_address().as_u64());
+                Err(MemError::from_map(err, page))
+            }
+        }
+    })
+}
+
+/// 输出各用途的物理帧数
+pub fn print_frame_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    let Some(stats) = frame_stats() else {
+        return writeln!(out, "frames: allocator not installed");
+    };
+    writeln!(
+        out,
+        "frames: {} of {} allocated, {} KiB free",
+        stats.allocated,
+        stats.total,
+        (stats.total - stats.allocated) * 4
+    )?;
+    for usage in FrameUsage::ALL {
+        let count = stats.usage(usage);
+        writeln!(
+            out,
+            "  {:<12}{:>8} {:>8} KiB",
+            usage.name(),
+            count,
+            count * 4
+        )?;
+    }
+    writeln!(out, "  {:<12}{:>8}", "shared", stats.shared)
+}
+
+// 屏幕上一行的格数和最多的行数, 加上行首的地址不超过80列
+const MAP_COLUMNS: usize = 64;
+const MAP_ROWS: usize = 16;
+
+/// 已安装的帧分配器的使用情况图, 每格`frames_per_cell`帧, 返回每格的首帧和用途.
+/// `install`之前为None
+pub fn frame_map(frames_per_cell: usize) -> Option<Vec<(PhysFrame, Option<FrameUsage>)>> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let guard = FRAME_ALLOCATOR.lock();
+        let allocator = guard.as_ref()?;
+        let starts = allocator
+            .usage_map()
+            .step_by(frames_per_cell)
+            .map(|(frame, _)| frame);
+        Some(
+            starts
+                .zip(frames::cells(
+                    allocator.usage_map().map(|(_, usage)| usage),
+                    frames_per_cell,
+                ))
+                .collect(),
+        )
+    })
+}
+
+/// 注册`mem`、`frames`、`framemap`和`wxaudit`命令
+pub fn register_commands() {
+    shell::register_command("mem", "heap and physical frame usage", mem_command)
+        .expect("duplicate memory command");
+    shell::register_command("frames", "physical frames by usage", |_, out| {
+        print_frame_stats(out).map_err(CmdError::from)
+    })
+    .expect("duplicate memory command");
+    shell::register_command("framemap", FRAMEMAP_USAGE, framemap_command)
+        .expect("duplicate memory command");
+    shell::register_command(
+        "wxaudit",
+        "wxaudit: check page table flags",
+        wxaudit_command,
+    )
+    .expect("duplicate memory command");
+}
+
+const FRAMEMAP_USAGE: &str = "framemap [frames-per-char]: map of physical frame usage";
+
+fn framemap_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let Some(stats) = frame_stats() else {
+        writeln!(out, "frames: allocator not installed")?;
+        return Ok(());
+    };
+    let per_cell = match args {
+        [] => frames::frames_per_cell(stats.total, MAP_COLUMNS * MAP_ROWS),
+        [count] => match shell::args::parse_number(count)? {
+            0 => return Err(CmdError::Usage(FRAMEMAP_USAGE)),
+            count => count as usize,
+        },
+        _ => return Err(CmdError::Usage(FRAMEMAP_USAGE)),
+    };
+    let map = frame_map(per_cell).unwrap_or_default();
+    write!(out, "1 char = {} frames:", per_cell)?;
+    for usage in FrameUsage::ALL {
+        write!(out, " {} {}", usage.symbol(), usage.name())?;
+    }
+    writeln!(out, " {} free", frames::FREE_SYMBOL)?;
+    // 在屏幕上按用途着色, 输出不是控制台时只有字符
+    let theme = vga_buffer::theme();
+    let result = (|| -> Result<(), CmdError> {
+        for row in map.chunks(MAP_COLUMNS) {
+            vga_buffer::set_color(theme.normal, theme.background);
+            write!(out, "{:#012x} ", row[0].0.start_address().as_u64())?;
+            for &(_, usage) in row {
+                let color = usage.map_or(theme.normal, FrameUsage::color);
+                vga_buffer::set_color(color, theme.background);
+                write!(out, "{}", frames::cell_symbol(usage))?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    })();
+    vga_buffer::set_color(theme.normal, theme.background);
+    result
+}
+
+fn wxaudit_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage("wxaudit"));
+    }
+    audit_mappings(out);
+    Ok(())
+}
+
+fn mem_command(_args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let heap = allocator::heap_stats();
+    writeln!(
+        out,
+        "heap: {} of {} bytes used in {} allocations ({})",
+        heap.used,
+        heap.size,
+        heap.allocations,
+        allocator::allocator_name()
+    )?;
+    match frame_stats() {
+        Some(frames) => writeln!(out, "frames: {} of {} allocated", frames.allocated, frames.total)?,
+        None => writeln!(out, "frames: allocator not installed")?,
+    }
+    Ok(())
+}
+
+/// 登记页表查询和帧分配器的自检
+pub fn register_selftests() {
+    static TRANSLATE: Check = Check::new("translate", translate_selftest);
+    static FRAMES: Check = Check::new("frames", frames_selftest);
+    selftest::register(&TRANSLATE).expect("duplicate memory selftest");
+    selftest::register(&FRAMES).expect("duplicate memory selftest");
+}
+
+// 静态数据和堆上的值经过translate和物理内存映射读出的应与原值相同
+fn translate_selftest() -> Outcome {
+    static MARKER: u64 = 0x5e1f_7e57_c0de_cafe;
+    let boxed = Box::new(!MARKER);
+    let values = [
+        ("static", &MARKER as *const u64),
+        ("heap", &*boxed as *const u64),
+    ];
+    for (what, ptr) in values {
+        let virt = VirtAddr::from_ptr(ptr);
+        let phys = match translate(virt) {
+            Ok(phys) => phys,
+            Err(err) => return Outcome::Fail(format!("{} data: {}", what, err)),
+        };
+        let expected = unsafe { ptr.read_volatile() };
+        let read = unsafe { phys_to_virt(phys).as_ptr::<u64>().read_volatile() };
+        if read != expected {
+            return Outcome::Fail(format!(
+                "{} data at {:#x} translated to {:#x}, which holds {:#x} instead of {:#x}",
+                what,
+                virt.as_u64(),
+                phys.as_u64(),
+                read,
+                expected
+            ));
+        }
+    }
+    Outcome::Pass
+}
+
+// 分配一帧写满图案再读回, 释放后使用情况应与之前相同
+fn frames_selftest() -> Outcome {
+    const WORDS: usize = 4096 / 8;
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let Some(allocator) = guard.as_mut() else {
+        return Outcome::Skipped("frame allocator not installed".into());
+    };
+    let before = allocator.stats();
+    let Some(frame) = allocator.allocate(FrameUsage::Other) else {
+        return Outcome::Fail("no free frame".into());
+    };
+    let words = phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+    let seed = frame.start_address().as_u64() ^ 0xa5a5_a5a5;
+    let pattern = |index: usize| seed.rotate_left(index as u32);
+    let intact = unsafe {
+        for index in 0..WORDS {
+            words.add(index).write_volatile(pattern(index));
+        }
+        (0..WORDS).all(|index| words.add(index).read_volatile() == pattern(index))
+    };
+    unsafe { allocator.deallocate_frame(frame) };
+    let after = allocator.stats();
+    if !intact {
+        Outcome::Fail(format!(
+            "frame {:#x} did not hold the written pattern",
+            frame.start_address().as_u64()
+        ))
+    } else if after != before {
+        Outcome::Fail(format!(
+            "frame usage changed from {:?} to {:?}",
+            before, after
+        ))
+    } else {
+        Outcome::Pass
+    }
+}
+
+// 关中断后锁住已安装的页表和帧分配器
+fn with_page_tables<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+            panic!("page table not installed");
+        };
+        f(mapper, allocator)
+    })
+}
+
+#[test_case]
+fn test_alloc_stack() {
+    let first = alloc_stack(2).unwrap();
+    let second = alloc_stack(2).unwrap();
+    // 两个栈之间隔着一个保护页
+    assert_eq!(second - first, 3 * 4096);
+    let bottom: *mut u64 = (second - 2 * 4096u64).as_mut_ptr();
+    let top: *mut u64 = (second - 8u64).as_mut_ptr();
+    unsafe {
+        bottom.write_volatile(1);
+        top.write_volatile(2);
+        assert_eq!(bottom.read_volatile() + top.read_volatile(), 3);
+    }
+}
+
+#[test_case]
+fn test_frame_stats() {
+    let before = frame_stats().unwrap();
+    assert!(before.allocated > 0 && before.allocated <= before.total);
+    let stack = alloc_stack(1).unwrap();
+    let after = frame_stats().unwrap();
+    assert!(after.allocated > before.allocated);
+    assert_eq!(after.total, before.total);
+
+    assert!(is_mapped(stack - 8u64));
+    // 栈底下方的保护页
+    assert!(!is_mapped(stack - 2 * 4096u64));
+}
+
+
+#[test_case]
+fn test_frame_usage() {
+    let counts = [
+        (FrameUsage::PageTable, 2),
+        (FrameUsage::Heap, 3),
+        (FrameUsage::Stack, 1),
+        (FrameUsage::Mmio, 4),
+        (FrameUsage::User, 5),
+        (FrameUsage::Other, 2),
+    ];
+    let before = frame_stats().unwrap();
+    let frames: Vec<(FrameUsage, PhysFrame)> = with_page_tables(|_, allocator| {
+        counts
+            .iter()
+            .flat_map(|&(usage, count)| core::iter::repeat_n(usage, count))
+            .map(|usage| (usage, allocator.allocate(usage).unwrap()))
+            .collect()
+    });
+    let during = frame_stats().unwrap();
+    assert_eq!(during.allocated, before.allocated + 17);
+    for (usage, count) in counts {
+        assert_eq!(during.usage(usage), before.usage(usage) + count);
+    }
+    // 每格一帧时各字符的个数就是各用途的帧数
+    let map = frame_map(1).unwrap();
+    assert_eq!(map.len(), during.total);
+    let cells = |usage| map.iter().filter(|&&(_, cell)| cell == usage).count();
+    for usage in FrameUsage::ALL {
+        assert_eq!(cells(Some(usage)), during.usage(usage));
+    }
+    assert_eq!(cells(None), during.total - during.allocated);
+
+    // 释放用户页和两个DMA帧, 只减少它们的计数
+    let (freed, kept): (Vec<_>, Vec<_>) =
+        frames.iter().enumerate().partition(|(index, (usage, _))| {
+            *usage == FrameUsage::User || (*usage == FrameUsage::Mmio && index % 2 == 0)
+        });
+    with_page_tables(|_, allocator| {
+        for (_, &(usage, frame)) in &freed {
+            assert_eq!(allocator.usage(frame), Some(usage));
+            unsafe { allocator.deallocate_frame(frame) };
+            assert_eq!(allocator.usage(frame), None);
+        }
+    });
+    let after = frame_stats().unwrap();
+    assert_eq!(after.allocated, before.allocated + 10);
+    assert_eq!(
+        after.usage(FrameUsage::User),
+        before.usage(FrameUsage::User)
+    );
+    assert_eq!(
+        after.usage(FrameUsage::Mmio),
+        before.usage(FrameUsage::Mmio) + 2
+    );
+    assert_eq!(
+        after.usage(FrameUsage::Heap),
+        before.usage(FrameUsage::Heap) + 3
+    );
+    let map = frame_map(1).unwrap();
+    let cells = |usage| map.iter().filter(|&&(_, cell)| cell == usage).count();
+    assert_eq!(cells(Some(FrameUsage::User)), after.usage(FrameUsage::User));
+    assert_eq!(cells(None), after.total - after.allocated);
+
+    with_page_tables(|_, allocator| {
+        for (_, &(_, frame)) in &kept {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+    });
+    assert_eq!(frame_stats().unwrap(), before);
+}
+
+
+#[test_case]
+fn test_mem_errors() {
+    let user = VirtAddr::new(layout::USER.start + 0x80_0000);
+    assert_eq!(
+        translate(VirtAddr::zero()),
+        Err(MemError::NotMapped(VirtAddr::zero()))
+    );
+    assert_eq!(page_flags(user), Err(MemError::NotMapped(user)));
+    assert_eq!(
+        addr::check_canonical(0x0000_8000_0000_0000),
+        Err(MemError::NonCanonical(0x0000_8000_0000_0000))
+    );
+
+    let mut space = AddressSpace::new().unwrap();
+    assert_eq!(
+        space.map_user_pages(user + 0x10u64, 1, PageTableFlags::WRITABLE),
+        Err(MemError::Misaligned {
+            addr: user.as_u64() + 0x10,
+            align: 4096
+        })
+    );
+    let heap = VirtAddr::new(layout::HEAP.start);
+    assert_eq!(
+        space.map_user_pages(heap, 1, PageTableFlags::WRITABLE),
+        Err(MemError::Overlap(heap))
+    );
+    // 第二页已映射, 失败时第一页保留
+    space
+        .map_user_pages(user + 4096u64, 1, PageTableFlags::WRITABLE)
+        .unwrap();
+    assert_eq!(
+        space.map_user_pages(user, 2, PageTableFlags::WRITABLE),
+        Err(MemError::Overlap(user + 4096u64))
+    );
+    assert!(space.translate(user).is_ok());
+    drop(space);
+
+    let last = crate::bootinfo::memory_map()
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(
+        read_phys(PhysAddr::new(last), &mut buf),
+        Err(MemError::OutOfPhysicalMemory(Some(PhysAddr::new(last))))
+    );
+    let top = layout::STACKS.start + 8;
+    assert_eq!(
+        unsafe { free_stack(VirtAddr::new(top), 1) },
+        Err(MemError::Misaligned {
+            addr: top,
+            align: 4096
+        })
+    );
+}
+
+#[test_case]
+fn test_read_phys() {
+    let value = alloc::boxed::Box::new(0x1122_3344_5566_7788u64);
+    let phys = translate(VirtAddr::from_ptr(&*value)).unwrap();
+    let mut buf = [0u8; 8];
+    read_phys(phys, &mut buf).unwrap();
+    assert_eq!(u64::from_le_bytes(buf), *value);
+}
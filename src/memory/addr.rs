@@ -0,0 +1,170 @@
+//! 内存接口的地址检查和错误类型
+//!
+//! 公开的内存接口先用这里的函数检查参数, 不合法的地址返回`MemError`, 不会在页表操作中途panic,
+//! 也不会悄悄截掉地址的高位
+
+use core::fmt;
+
+use bootloader::bootinfo::MemoryMap;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::layout;
+
+/// 内存接口拒绝请求的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemError {
+    /// 地址不在规范地址范围内
+    NonCanonical(u64),
+    /// 地址没有按`align`对齐
+    Misaligned { addr: u64, align: u64 },
+    /// 地址所在的页没有映射
+    NotMapped(VirtAddr),
+    /// 没有空闲的物理帧, 或物理地址超出内存映射, 此时附带第一个不存在的地址
+    OutOfPhysicalMemory(Option<PhysAddr>),
+    /// 与已有的映射重叠, 或超出了所在的区域, 附带第一个冲突的地址
+    Overlap(VirtAddr),
+}
+
+impl MemError {
+    /// 映射`page`时页表返回的错误
+    pub fn from_map(err: MapToError<Size4KiB>, page: VirtAddr) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => MemError::OutOfPhysicalMemory(None),
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                MemError::Overlap(page)
+            }
+        }
+    }
+}
+
+impl From<MapToError<Size4KiB>> for MemError {
+    /// 不知道是哪一页时, 冲突的地址记为0
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        MemError::from_map(err, VirtAddr::zero())
+    }
+}
+
+impl fmt::Display for MemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemError::NonCanonical(addr) => write!(f, "{:#x} is not a canonical address", addr),
+            MemError::Misaligned { addr, align } => {
+                write!(f, "{:#x} is not aligned to {:#x}", addr, align)
+            }
+            MemError::NotMapped(addr) => write!(f, "{:#x} is not mapped", addr.as_u64()),
+            MemError::OutOfPhysicalMemory(None) => write!(f, "out of physical memory"),
+            MemError::OutOfPhysicalMemory(Some(addr)) => {
+                write!(f, "physical address {:#x} does not exist", addr.as_u64())
+            }
+            MemError::Overlap(addr) => {
+                write!(f, "{:#x} overlaps an existing mapping", addr.as_u64())
+            }
+        }
+    }
+}
+
+/// 检查`addr`是规范地址, 返回对应的`VirtAddr`
+pub fn check_canonical(addr: u64) -> Result<VirtAddr, MemError> {
+    VirtAddr::try_new(addr).map_err(|_| MemError::NonCanonical(addr))
+}
+
+/// 检查`addr`按`align`对齐, `align`必须是2的幂
+pub fn check_aligned(addr: u64, align: u64) -> Result<(), MemError> {
+    assert!(align.is_power_of_two(), "alignment is not a power of two");
+    if addr.is_multiple_of(align) {
+        Ok(())
+    } else {
+        Err(MemError::Misaligned { addr, align })
+    }
+}
+
+/// 检查`[start, start + len)`完全在用户空间中
+pub fn check_user_range(start: VirtAddr, len: u64) -> Result<(), MemError> {
+    if layout::USER.contains_range(start.as_u64(), len) {
+        Ok(())
+    } else if layout::USER.contains(start.as_u64()) {
+        Err(MemError::Overlap(VirtAddr::new(layout::USER.end)))
+    } else {
+        Err(MemError::Overlap(start))
+    }
+}
+
+/// 检查`[addr, addr + len)`都在内存映射的区域内, 区域类型不限. `install`之前总是失败
+pub fn check_phys_exists(addr: PhysAddr, len: u64) -> Result<(), MemError> {
+    let map = super::MEMORY_MAP.get().copied();
+    covered(map, addr.as_u64(), len)
+        .map_err(|missing| MemError::OutOfPhysicalMemory(Some(PhysAddr::new_truncate(missing))))
+}
+
+// 区域之间可能有空洞, 也不保证按地址排列. 失败时返回第一个不在任何区域中的地址
+fn covered(map: Option<&MemoryMap>, start: u64, len: u64) -> Result<(), u64> {
+    // 长度为0时只检查`start`
+    let end = start.checked_add(len.max(1)).ok_or(start)?;
+    let mut current = start;
+    while current < end {
+        let region = map
+            .into_iter()
+            .flat_map(|map| map.iter())
+            .find(|region| {
+                region.range.start_addr() <= current && current < region.range.end_addr()
+            })
+            .ok_or(current)?;
+        current = region.range.end_addr();
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_canonical_and_aligned() {
+    assert_eq!(
+        check_canonical(0x0000_8000_0000_0000),
+        Err(MemError::NonCanonical(0x0000_8000_0000_0000))
+    );
+    assert_eq!(
+        check_canonical(0x0000_7fff_ffff_ffff),
+        Ok(VirtAddr::new(0x0000_7fff_ffff_ffff))
+    );
+    assert!(check_canonical(0xffff_8000_0000_0000).is_ok());
+    assert_eq!(check_aligned(0x2000, 4096), Ok(()));
+    assert_eq!(
+        check_aligned(0x2010, 4096),
+        Err(MemError::Misaligned {
+            addr: 0x2010,
+            align: 4096
+        })
+    );
+}
+
+#[test_case]
+fn test_user_range() {
+    let start = VirtAddr::new(layout::USER.start);
+    assert_eq!(check_user_range(start, layout::USER.size()), Ok(()));
+    assert_eq!(
+        check_user_range(start + 4096u64, layout::USER.size()),
+        Err(MemError::Overlap(VirtAddr::new(layout::USER.end)))
+    );
+    let heap = VirtAddr::new(layout::HEAP.start);
+    assert_eq!(check_user_range(heap, 4096), Err(MemError::Overlap(heap)));
+}
+
+#[test_case]
+fn test_phys_exists() {
+    let map = crate::bootinfo::memory_map();
+    let last = map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap();
+    assert_eq!(check_phys_exists(PhysAddr::new(0), 4096), Ok(()));
+    assert_eq!(
+        check_phys_exists(PhysAddr::new(last), 1),
+        Err(MemError::OutOfPhysicalMemory(Some(PhysAddr::new(last))))
+    );
+    // 跨过最后一个区域的末尾时报告超出的第一个地址
+    assert_eq!(
+        check_phys_exists(PhysAddr::new(last - 4096), 8192),
+        Err(MemError::OutOfPhysicalMemory(Some(PhysAddr::new(last))))
+    );
+}
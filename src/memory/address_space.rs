@@ -0,0 +1,394 @@
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{
+    addr, phys_to_virt, with_page_tables, BootInfoFrameAllocator, FrameUsage, MemError,
+    FRAME_ALLOCATOR, KERNEL_P4, MAPPER, PHYSICAL_MEMORY_OFFSET,
+};
+use crate::layout;
+
+// 用户空间占用的4级页表项
+const USER_P4_INDEX: usize = (layout::USER.start >> 39) as usize & 0x1ff;
+
+/// 写时复制的页: 页表项不可写, 写入时复制帧后恢复可写. 使用留给操作系统的第9位
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// 一个4级页表. 内核部分与内核页表共享, 用户空间各自独立
+///
+/// 新地址空间只复制创建时内核页表中的4级页表项, 之后内核新增的4级页表项不会出现在其中
+pub struct AddressSpace {
+    p4: PhysFrame,
+    // 内核页表不属于任何进程, 不能释放
+    owned: bool,
+}
+
+impl AddressSpace {
+    /// 创建用户空间为空的地址空间
+    pub fn new() -> Result<AddressSpace, MemError> {
+        interrupts::without_interrupts(|| {
+            let mut mapper = MAPPER.lock();
+            let kernel = mapper.as_mut().expect("page table not installed");
+            let p4 = FRAME_ALLOCATOR
+                .lock()
+                .as_mut()
+                .and_then(|allocator| allocator.allocate(FrameUsage::PageTable))
+                .ok_or(MemError::OutOfPhysicalMemory(None))?;
+            let table = unsafe { table_mut(p4) };
+            for (i, entry) in kernel.level_4_table().iter().enumerate() {
+                table[i] = if i == USER_P4_INDEX {
+                    PageTableEntry::new()
+                } else {
+                    entry.clone()
+                };
+            }
+            Ok(AddressSpace { p4, owned: true })
+        })
+    }
+
+    /// 内核页表. 它的用户空间供不属于进程的用户程序使用
+    pub fn kernel() -> AddressSpace {
+        AddressSpace {
+            p4: *KERNEL_P4.get().expect("page table not installed"),
+            owned: false,
+        }
+    }
+
+    /// 4级页表所在的帧, 切换地址空间时写入CR3
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4
+    }
+
+    /// 复制当前地址空间的用户空间, 用于fork. 内核部分与新建的地址空间相同
+    ///
+    /// 所有用户页的帧由两边共享并增加引用计数, 可写的页在两边都改为只读并标记为`COW`.
+    /// 失败时已复制的部分随新地址空间释放, 当前地址空间中已标记的页在写入时恢复可写
+    pub fn fork_active() -> Result<AddressSpace, MemError> {
+        let child = AddressSpace::new()?;
+        let parent = Cr3::read().0;
+        with_table(parent, |_, allocator| unsafe {
+            let entry = &table_mut(parent)[USER_P4_INDEX];
+            if entry.is_unused() {
+                return Ok(());
+            }
+            let child_entry = &mut table_mut(child.p4)[USER_P4_INDEX];
+            fork_table(
+                allocator,
+                entry.frame().unwrap(),
+                child_entry,
+                entry.flags(),
+                3,
+            )
+        })?;
+        // 当前地址空间中的页改为只读了
+        tlb::flush_all();
+        Ok(child)
+    }
+
+    /// 在用户空间分配并映射从`start`开始的`pages`页, 内容清零. `flags`之外总是带PRESENT和USER_ACCESSIBLE
+    ///
+    /// 范围超出用户空间或遇到已映射的页时返回`Overlap`, 之前已映射的页不会撤销
+    pub fn map_user_pages(
+        &mut self,
+        start: VirtAddr,
+        pages: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MemError> {
+        addr::check_aligned(start.as_u64(), 4096)?;
+        let len = pages.checked_mul(4096).ok_or(MemError::Overlap(start))?;
+        addr::check_user_range(start, len)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        self.with_mapper(|mapper, allocator| {
+            for i in 0..pages {
+                let page = Page::<Size4KiB>::containing_address(start + i * 4096);
+                // 不是当前地址空间时这次刷新是多余的, 但无害
+                map_zeroed(mapper, allocator, page, flags)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// `addr`在这个地址空间中对应的物理地址
+    pub fn translate(&mut self, addr: VirtAddr) -> Result<PhysAddr, MemError> {
+        self.with_mapper(|mapper, _| mapper.translate_addr(addr))
+            .ok_or(MemError::NotMapped(addr))
+    }
+
+    /// `addr`所在页在这个地址空间中的页表项标志
+    pub fn page_flags(&mut self, addr: VirtAddr) -> Result<PageTableFlags, MemError> {
+        self.with_mapper(|mapper, _| match mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        })
+        .ok_or(MemError::NotMapped(addr))
+    }
+
+    /// 用户空间中已映射的页数, 与其他地址空间共享的页也计算在内
+    pub fn user_pages(&mut self) -> u64 {
+        let p4 = self.p4;
+        self.with_mapper(|_, _| unsafe {
+            let entry = &table_mut(p4)[USER_P4_INDEX];
+            if entry.is_unused() {
+                return 0;
+            }
+            count_pages(entry.frame().unwrap(), 3)
+        })
+    }
+
+    // 关中断后锁住这个地址空间的页表和帧分配器
+    fn with_mapper<R>(
+        &mut self,
+        f: impl FnOnce(&mut OffsetPageTable, &mut BootInfoFrameAllocator) -> R,
+    ) -> R {
+        if !self.owned {
+            return with_page_tables(|mapper, allocator| f(mapper, allocator));
+        }
+        with_table(self.p4, f)
+    }
+}
+
+// 关中断后锁住以`p4`为4级页表的页表和帧分配器
+fn with_table<R>(
+    p4: PhysFrame,
+    f: impl FnOnce(&mut OffsetPageTable, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    interrupts::without_interrupts(|| {
+        // 与内核页表使用同样的加锁顺序
+        let _kernel = MAPPER.lock();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().expect("frame allocator not installed");
+        let offset = *PHYSICAL_MEMORY_OFFSET.get().unwrap();
+        let mut mapper = unsafe { OffsetPageTable::new(table_mut(p4), offset) };
+        f(&mut mapper, allocator)
+    })
+}
+
+/// 处理当前地址空间中对`addr`的写入引起的页错误, 返回是否是写时复制的页
+///
+/// 帧仍被其他页表项共享时复制到新的帧并映射为可写, 否则直接恢复可写
+pub(crate) fn handle_cow_fault(addr: VirtAddr) -> Result<bool, MemError> {
+    if PHYSICAL_MEMORY_OFFSET.get().is_none() || !layout::USER.contains(addr.as_u64()) {
+        return Ok(false);
+    }
+    let page = Page::<Size4KiB>::containing_address(addr);
+    with_table(Cr3::read().0, |mapper, allocator| {
+        let TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } = mapper.translate(addr)
+        else {
+            return Ok(false);
+        };
+        if !flags.contains(COW) {
+            return Ok(false);
+        }
+        let flags = (flags - COW) | PageTableFlags::WRITABLE;
+        unsafe {
+            if allocator.ref_count(frame) == 1 {
+                mapper
+                    .update_flags(page, flags)
+                    .map_err(|_| MemError::NotMapped(addr))?
+                    .flush();
+                return Ok(true);
+            }
+            let copy = allocator
+                .allocate(FrameUsage::User)
+                .ok_or(MemError::OutOfPhysicalMemory(None))?;
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                4096,
+            );
+            let (_, flush) = mapper.unmap(page).map_err(|_| MemError::NotMapped(addr))?;
+            flush.ignore();
+            mapper
+                .map_to(page, copy, flags, allocator)
+                .map_err(|err| MemError::from_map(err, page.start_address()))?
+                .flush();
+            allocator.release(frame);
+        }
+        Ok(true)
+    })
+}
+
+/// 在当前地址空间中映射`addr`所在的一页, 内容清零, 用于按需映射的区域. `flags`之外总是带PRESENT和USER_ACCESSIBLE
+///
+/// 页已映射时返回`Overlap`
+pub(crate) fn map_active_user_page(addr: VirtAddr, flags: PageTableFlags) -> Result<(), MemError> {
+    addr::check_user_range(addr, 1)?;
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    with_table(Cr3::read().0, |mapper, allocator| {
+        map_zeroed(mapper, allocator, page, flags)
+    })
+}
+
+/// 取消当前地址空间中从`start`开始的`pages`页的映射, 跳过没有映射的页. 帧只在没有其他页表项共享时释放,
+/// 返回取消映射的页数
+pub(crate) fn unmap_active_user_pages(start: VirtAddr, pages: u64) -> Result<u64, MemError> {
+    addr::check_aligned(start.as_u64(), 4096)?;
+    let len = pages.checked_mul(4096).ok_or(MemError::Overlap(start))?;
+    addr::check_user_range(start, len)?;
+    with_table(Cr3::read().0, |mapper, allocator| {
+        let mut unmapped = 0;
+        for i in 0..pages {
+            let page = Page::<Size4KiB>::containing_address(start + i * 4096);
+            let Ok((frame, flush)) = mapper.unmap(page) else {
+                continue;
+            };
+            flush.flush();
+            unsafe { allocator.release(frame) };
+            unmapped += 1;
+        }
+        Ok(unmapped)
+    })
+}
+
+// 分配清零的帧并映射到`page`, 映射失败时释放帧
+fn map_zeroed(
+    mapper: &mut OffsetPageTable,
+    allocator: &mut BootInfoFrameAllocator,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), MemError> {
+    let frame = allocator
+        .allocate(FrameUsage::User)
+        .ok_or(MemError::OutOfPhysicalMemory(None))?;
+    unsafe {
+        let virt: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        core::ptr::write_bytes(virt, 0, 4096);
+        match mapper.map_to(page, frame, flags, allocator) {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                allocator.deallocate_frame(frame);
+                return Err(MemError::from_map(err, page.start_address()));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Drop for AddressSpace {
+    /// 释放用户空间映射的帧、用户空间的各级页表和4级页表
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        assert_ne!(Cr3::read().0, self.p4, "dropping the active address space");
+        interrupts::without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let allocator = allocator.as_mut().expect("frame allocator not installed");
+            unsafe {
+                let entry = &table_mut(self.p4)[USER_P4_INDEX];
+                if !entry.is_unused() {
+                    free_table(allocator, entry.frame().unwrap(), 3);
+                }
+                allocator.deallocate_frame(self.p4);
+            }
+        })
+    }
+}
+
+// 通过物理内存映射访问页表
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+// 释放`level`级页表及其下的所有帧. 1级页表映射的是用户页, 与其他地址空间共享的页只减少引用计数
+unsafe fn free_table(allocator: &mut BootInfoFrameAllocator, frame: PhysFrame, level: u8) {
+    for entry in table_mut(frame).iter().filter(|entry| !entry.is_unused()) {
+        // 用户空间只使用4KiB的页
+        let child = entry.frame().expect("huge page in user space");
+        if level > 1 {
+            free_table(allocator, child, level - 1);
+        } else {
+            allocator.release(child);
+        }
+    }
+    allocator.deallocate_frame(frame);
+}
+
+// `level`级页表及其下映射的用户页数
+unsafe fn count_pages(frame: PhysFrame, level: u8) -> u64 {
+    table_mut(frame)
+        .iter()
+        .filter(|entry| !entry.is_unused())
+        .map(|entry| {
+            let child = entry.frame().expect("huge page in user space");
+            if level > 1 {
+                count_pages(child, level - 1)
+            } else {
+                1
+            }
+        })
+        .sum()
+}
+
+// 为`level`级页表`parent`建立副本并写入`child_entry`, 用户页改为共享. 副本先写入上级页表再填充,
+// 中途失败时已复制的部分由地址空间释放时回收
+unsafe fn fork_table(
+    allocator: &mut BootInfoFrameAllocator,
+    parent: PhysFrame,
+    child_entry: &mut PageTableEntry,
+    flags: PageTableFlags,
+    level: u8,
+) -> Result<(), MemError> {
+    let frame = allocator
+        .allocate(FrameUsage::PageTable)
+        .ok_or(MemError::OutOfPhysicalMemory(None))?;
+    let table = table_mut(frame);
+    table.zero();
+    child_entry.set_frame(frame, flags);
+    for (i, entry) in table_mut(parent).iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let page = entry.frame().expect("huge page in user space");
+        if level > 1 {
+            fork_table(allocator, page, &mut table[i], entry.flags(), level - 1)?;
+            continue;
+        }
+        let mut flags = entry.flags();
+        if flags.intersects(PageTableFlags::WRITABLE | COW) {
+            flags = (flags - PageTableFlags::WRITABLE) | COW;
+            entry.set_flags(flags);
+        }
+        allocator.share(page);
+        table[i].set_frame(page, flags);
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_address_space_reclaim() {
+    use super::frame_stats;
+
+    let user = VirtAddr::new(layout::USER.start + 0x40_0000);
+    let before = frame_stats().unwrap().allocated;
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map_user_pages(user, 3, PageTableFlags::WRITABLE)
+        .unwrap();
+    assert!(space.translate(user + 4096u64).is_ok());
+    assert_eq!(space.user_pages(), 3);
+    // 内核部分共享, 用户空间不出现在内核页表中
+    assert_eq!(
+        space.translate(VirtAddr::new(crate::allocator::HEAP_START as u64)),
+        AddressSpace::kernel().translate(VirtAddr::new(crate::allocator::HEAP_START as u64))
+    );
+    assert_eq!(
+        AddressSpace::kernel().translate(user),
+        Err(MemError::NotMapped(user))
+    );
+    // 3页加上4级页表和3张下级页表
+    assert_eq!(frame_stats().unwrap().allocated, before + 7);
+    drop(space);
+    assert_eq!(frame_stats().unwrap().allocated, before);
+}
@@ -0,0 +1,453 @@
+//! 检查当前页表中的映射: 可写又可执行的页、内核区域中的用户页和可写的内核代码
+//!
+//! 经过物理内存映射遍历CR3指向的整个页表, 每个页的有效权限由各级页表项共同决定:
+//! 每一级都可写、都允许用户访问时才可写、可被用户访问, 任何一级带NO_EXECUTE就不可执行
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use super::{phys_to_virt, MAPPER, PHYSICAL_MEMORY_OFFSET};
+use crate::cpu::msr::Efer;
+use crate::layout::{self, Range, Region};
+use crate::log;
+use crate::log::Level;
+
+// 链接器(lld)自动定义的符号. 默认布局中ELF头和.rodata在.text之前, 两者之间没有可写的段
+extern "C" {
+    static __ehdr_start: u8;
+    static etext: u8;
+}
+
+/// 内核.rodata和.text所在的虚拟地址范围, 从ELF头到.text末尾
+pub fn kernel_text() -> Range {
+    let start = core::ptr::addr_of!(__ehdr_start) as u64;
+    let end = core::ptr::addr_of!(etext) as u64;
+    Range::new(start & !0xfff, (end + 0xfff) & !0xfff)
+}
+
+// 参与统计的页表项标志, 其余的位不影响权限
+const COUNTED: PageTableFlags = PageTableFlags::WRITABLE
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE)
+    .union(PageTableFlags::GLOBAL)
+    .union(PageTableFlags::NO_CACHE);
+
+/// 违反的规则
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// 可写又可执行
+    WriteExecute,
+    /// 用户可访问的页在内核的区域中
+    UserInKernel,
+    /// 内核代码或只读数据可写
+    WritableText,
+}
+
+impl ViolationKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ViolationKind::WriteExecute => "W+X",
+            ViolationKind::UserInKernel => "user page in kernel range",
+            ViolationKind::WritableText => "writable kernel text",
+        }
+    }
+}
+
+/// 一段连续的、违反同一规则的映射
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub start: VirtAddr,
+    /// 字节数, 总是页大小的整数倍
+    pub size: u64,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:#x}..{:#x} ({} KiB)",
+            self.kind.name(),
+            self.start.as_u64(),
+            self.start.as_u64() + self.size,
+            self.size / 1024
+        )
+    }
+}
+
+/// 某种页大小和标志组合的页数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagCount {
+    /// 有效的标志, 只包括`WRITABLE`、`USER_ACCESSIBLE`、`NO_EXECUTE`、`GLOBAL`和`NO_CACHE`
+    pub flags: PageTableFlags,
+    pub page_size: u64,
+    pub pages: u64,
+}
+
+/// `audit_mappings`的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    violations: Vec<Violation>,
+    counts: Vec<FlagCount>,
+}
+
+impl AuditReport {
+    /// 按地址排列, 相邻的同类违规合并成一段
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// 按页大小和标志排列
+    pub fn counts(&self) -> &[FlagCount] {
+        &self.counts
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// 已映射的页数, 不论页大小
+    pub fn mapped_pages(&self) -> u64 {
+        self.counts.iter().map(|count| count.pages).sum()
+    }
+
+    fn add(&mut self, kind: ViolationKind, start: u64, size: u64) {
+        // 遍历按地址进行, 只需与同类的最后一段比较
+        if let Some(last) = self.violations.iter_mut().rev().find(|v| v.kind == kind) {
+            if last.start.as_u64() + last.size == start {
+                last.size += size;
+                return;
+            }
+        }
+        self.violations.push(Violation {
+            kind,
+            start: VirtAddr::new(start),
+            size,
+        });
+    }
+
+    fn count(&mut self, flags: PageTableFlags, page_size: u64) {
+        let flags = flags & COUNTED;
+        let key = |count: &FlagCount| (count.page_size, count.flags.bits());
+        match self
+            .counts
+            .binary_search_by_key(&(page_size, flags.bits()), key)
+        {
+            Ok(index) => self.counts[index].pages += 1,
+            Err(index) => self.counts.insert(
+                index,
+                FlagCount {
+                    flags,
+                    page_size,
+                    pages: 1,
+                },
+            ),
+        }
+    }
+
+    pub fn print(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "{} pages mapped", self.mapped_pages())?;
+        writeln!(out, "  {:<6}{:<24}{:>10}", "SIZE", "FLAGS", "PAGES")?;
+        for count in &self.counts {
+            writeln!(
+                out,
+                "  {:<6}{:<24}{:>10}",
+                size_name(count.page_size),
+                FlagNames(count.flags),
+                count.pages
+            )?;
+        }
+        if self.is_clean() {
+            return writeln!(out, "no violations");
+        }
+        writeln!(out, "{} violations:", self.violations.len())?;
+        for violation in &self.violations {
+            writeln!(out, "  {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+fn size_name(page_size: u64) -> &'static str {
+    match page_size {
+        PAGE_4K => "4K",
+        PAGE_2M => "2M",
+        _ => "1G",
+    }
+}
+
+const PAGE_4K: u64 = 4096;
+const PAGE_2M: u64 = 512 * PAGE_4K;
+const PAGE_1G: u64 = 512 * PAGE_2M;
+
+// 像"rw- kernel global"这样的标志说明
+struct FlagNames(PageTableFlags);
+
+impl fmt::Display for FlagNames {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = self.0;
+        let mut text = alloc::string::String::from("r");
+        text.push(if flags.contains(PageTableFlags::WRITABLE) {
+            'w'
+        } else {
+            '-'
+        });
+        text.push(if flags.contains(PageTableFlags::NO_EXECUTE) {
+            '-'
+        } else {
+            'x'
+        });
+        text.push_str(if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            " user"
+        } else {
+            " kernel"
+        });
+        if flags.contains(PageTableFlags::GLOBAL) {
+            text.push_str(" global");
+        }
+        if flags.contains(PageTableFlags::NO_CACHE) {
+            text.push_str(" uncached");
+        }
+        f.pad(&text)
+    }
+}
+
+// 遍历时的规则和结果
+struct Audit {
+    // 只检查与这个范围重叠的页
+    window: Range,
+    text: Range,
+    // 没有启用NX时NO_EXECUTE是保留位, 所有页都可执行
+    nx: bool,
+    report: AuditReport,
+}
+
+impl Audit {
+    // 检查一个叶子页表项映射的页, `flags`是各级合并后的有效标志
+    fn visit(&mut self, start: u64, page_size: u64, flags: PageTableFlags) {
+        let page = Range {
+            start,
+            end: start.saturating_add(page_size),
+        };
+        let writable = flags.contains(PageTableFlags::WRITABLE);
+        if writable && !(self.nx && flags.contains(PageTableFlags::NO_EXECUTE)) {
+            self.report
+                .add(ViolationKind::WriteExecute, start, page_size);
+        }
+        let in_kernel = layout::REGIONS
+            .iter()
+            .any(|&(region, range)| region != Region::User && range.overlaps(&page));
+        if in_kernel && flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            self.report
+                .add(ViolationKind::UserInKernel, start, page_size);
+        }
+        if writable && self.text.overlaps(&page) {
+            self.report
+                .add(ViolationKind::WritableText, start, page_size);
+        }
+        self.report.count(flags, page_size);
+    }
+
+    // 遍历`table`, 它的每一项覆盖`entry_size`字节, 第一项从`base`开始. `inherited`是上级页表项的有效标志
+    fn walk(
+        &mut self,
+        table: &PageTable,
+        base: u64,
+        entry_size: u64,
+        inherited: PageTableFlags,
+        table_at: &dyn Fn(PhysFrame) -> &'static PageTable,
+    ) {
+        for (index, entry) in table.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let start = canonical(base + index as u64 * entry_size);
+            let covered = Range {
+                start,
+                end: start.saturating_add(entry_size),
+            };
+            if !covered.overlaps(&self.window) {
+                continue;
+            }
+            // 可写和用户访问取各级的交集, 不可执行取并集
+            let permissions = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+            let effective = (flags - permissions)
+                | (flags & inherited & permissions)
+                | (inherited & PageTableFlags::NO_EXECUTE);
+            let leaf = entry_size == PAGE_4K || flags.contains(PageTableFlags::HUGE_PAGE);
+            if leaf {
+                self.visit(start, entry_size, effective);
+            } else {
+                let next = table_at(PhysFrame::containing_address(entry.addr()));
+                self.walk(next, start, entry_size / 512, effective, table_at);
+            }
+        }
+    }
+}
+
+// 高半部分的地址要把第47位扩展到高16位
+fn canonical(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
+
+// 检查当前页表中与`window`重叠的映射, `install`之前为None
+fn audit(window: Range) -> Option<AuditReport> {
+    let mut audit = Audit {
+        window,
+        text: kernel_text(),
+        nx: Efer::read().nx_enabled(),
+        report: AuditReport::default(),
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // 持有锁, 避免与页表修改同时进行
+        let _mapper = MAPPER.lock();
+        PHYSICAL_MEMORY_OFFSET.get()?;
+        let table_at = |frame: PhysFrame| -> &'static PageTable {
+            unsafe { &*phys_to_virt(frame.start_address()).as_ptr() }
+        };
+        let p4 = table_at(Cr3::read().0);
+        // 从所有权限开始
+        let all = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        audit.walk(p4, 0, 512 * PAGE_1G, all, &table_at);
+        Some(())
+    })?;
+    Some(audit.report)
+}
+
+// 整个地址空间, 只有`start`和`end`被使用
+const EVERYTHING: Range = Range {
+    start: 0,
+    end: u64::MAX,
+};
+
+/// 检查当前页表中的所有映射, 把统计和违规输出到`out`. 输出失败不影响返回的结果
+pub fn audit_mappings(out: &mut dyn fmt::Write) -> AuditReport {
+    let Some(report) = audit(EVERYTHING) else {
+        let _ = writeln!(out, "wxaudit: page table not installed");
+        return AuditReport::default();
+    };
+    let _ = report.print(out);
+    report
+}
+
+/// 以error级别记录当前页表中的违规
+pub fn log_violations() {
+    let report = audit(EVERYTHING).unwrap_or_default();
+    for violation in report.violations() {
+        log!(Level::Error, "wxaudit: {}", violation);
+    }
+}
+
+#[test_case]
+fn test_write_execute_mapping() {
+    use super::{reserve, with_page_tables, FrameUsage, STACK_NEXT};
+    use x86_64::structures::paging::{FrameDeallocator, Mapper, Page, Size4KiB};
+
+    let start = reserve(&STACK_NEXT, layout::STACKS, PAGE_4K).unwrap();
+    let window = Range::new(start, start + PAGE_4K);
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    with_page_tables(|mapper, allocator| {
+        let frame = allocator.allocate(FrameUsage::Stack).unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, allocator)
+                .unwrap()
+                .flush()
+        };
+    });
+    let report = audit(window).unwrap();
+    assert_eq!(
+        report.violations(),
+        [Violation {
+            kind: ViolationKind::WriteExecute,
+            start: VirtAddr::new(start),
+            size: PAGE_4K,
+        }]
+    );
+    assert_eq!(report.mapped_pages(), 1);
+
+    with_page_tables(|mapper, allocator| {
+        let (frame, flush) = mapper.unmap(page).unwrap();
+        flush.flush();
+        unsafe { allocator.deallocate_frame(frame) };
+    });
+    let report = audit(window).unwrap();
+    assert!(report.is_clean(), "{:?}", report.violations());
+    assert_eq!(report.mapped_pages(), 0);
+}
+
+#[test_case]
+fn test_kernel_mappings() {
+    let mut out = alloc::string::String::new();
+    let report = audit_mappings(&mut out);
+    assert!(out.contains("pages mapped"));
+    // 内核代码由bootloader按段的标志映射为只读, 用户页只在用户空间
+    assert!(report
+        .violations()
+        .iter()
+        .all(|v| v.kind == ViolationKind::WriteExecute));
+    let text = kernel_text();
+    assert!(text.contains(kernel_text as fn() -> Range as usize as u64));
+    let name: fn(ViolationKind) -> &'static str = ViolationKind::name;
+    assert!(text.contains(name as usize as u64));
+    // 物理内存映射用的是大页
+    assert!(report
+        .counts()
+        .iter()
+        .any(|count| count.page_size > PAGE_4K));
+    assert!(report.mapped_pages() > 0);
+}
+
+#[test_case]
+fn test_merge_violations() {
+    let mut audit = Audit {
+        window: EVERYTHING,
+        text: Range::new(0x20_0000, 0x20_2000),
+        nx: true,
+        report: AuditReport::default(),
+    };
+    let rw = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let rwx = PageTableFlags::WRITABLE;
+    let user = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    audit.visit(0x20_0000, PAGE_4K, PageTableFlags::empty());
+    audit.visit(0x20_1000, PAGE_4K, rw);
+    audit.visit(0x20_2000, PAGE_4K, rwx);
+    audit.visit(0x20_3000, PAGE_4K, rwx);
+    audit.visit(0x40_0000, PAGE_2M, rwx | PageTableFlags::GLOBAL);
+    audit.visit(layout::HEAP.start, PAGE_4K, user);
+    audit.visit(layout::USER.start, PAGE_4K, user);
+    let report = audit.report;
+    let found: Vec<_> = report
+        .violations()
+        .iter()
+        .map(|v| (v.kind, v.start.as_u64(), v.size))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (ViolationKind::WritableText, 0x20_1000, PAGE_4K),
+            (ViolationKind::WriteExecute, 0x20_2000, 2 * PAGE_4K),
+            (ViolationKind::WriteExecute, 0x40_0000, PAGE_2M),
+            (ViolationKind::UserInKernel, layout::HEAP.start, PAGE_4K),
+        ]
+    );
+    assert_eq!(report.mapped_pages(), 7);
+    assert_eq!(report.counts().len(), 5);
+    assert_eq!(report.counts()[1].flags, rwx);
+    assert_eq!(report.counts()[1].pages, 2);
+    assert_eq!(report.counts()[4].page_size, PAGE_2M);
+    let mut out = alloc::string::String::new();
+    report.print(&mut out).unwrap();
+    assert!(
+        out.contains("  2M    rwx kernel global                1"),
+        "{}",
+        out
+    );
+    assert!(out.contains("W+X: 0x202000..0x204000 (8 KiB)"), "{}", out);
+}
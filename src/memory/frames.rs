@@ -0,0 +1,183 @@
+//! 物理帧的用途和使用情况图
+//!
+//! 帧分配器为每个帧记录一字节的用途标记, 释放时按标记减少对应用途的计数.
+//! 使用情况图把可用的帧按顺序分成若干格, 每格显示其中最多的用途
+
+use alloc::vec::Vec;
+
+use crate::vga_buffer::Color;
+
+/// 分配物理帧的用途, 由调用者指定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameUsage {
+    /// 各级页表, 页表映射时通过`FrameAllocator`分配的帧都属于这一类
+    PageTable = 1,
+    Heap,
+    /// 内核栈
+    Stack,
+    /// 设备使用的内存, 例如DMA缓冲区
+    Mmio,
+    /// 用户空间的页
+    User,
+    /// 其他用途, 以及为凑齐连续区域而跳过的帧
+    Other,
+}
+
+impl FrameUsage {
+    pub const ALL: [FrameUsage; 6] = [
+        FrameUsage::PageTable,
+        FrameUsage::Heap,
+        FrameUsage::Stack,
+        FrameUsage::Mmio,
+        FrameUsage::User,
+        FrameUsage::Other,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameUsage::PageTable => "page table",
+            FrameUsage::Heap => "heap",
+            FrameUsage::Stack => "stack",
+            FrameUsage::Mmio => "mmio",
+            FrameUsage::User => "user",
+            FrameUsage::Other => "other",
+        }
+    }
+
+    /// 在使用情况图上的字符
+    pub fn symbol(self) -> char {
+        match self {
+            FrameUsage::PageTable => 'P',
+            FrameUsage::Heap => 'H',
+            FrameUsage::Stack => 'S',
+            FrameUsage::Mmio => 'M',
+            FrameUsage::User => 'U',
+            FrameUsage::Other => 'O',
+        }
+    }
+
+    /// 在使用情况图上的颜色
+    pub fn color(self) -> Color {
+        match self {
+            FrameUsage::PageTable => Color::LightCyan,
+            FrameUsage::Heap => Color::Yellow,
+            FrameUsage::Stack => Color::LightGreen,
+            FrameUsage::Mmio => Color::Pink,
+            FrameUsage::User => Color::LightBlue,
+            FrameUsage::Other => Color::LightRed,
+        }
+    }
+
+    /// 在计数数组中的位置
+    pub fn index(self) -> usize {
+        self as usize - 1
+    }
+
+    // 用途标记, 0表示空闲
+    pub(super) fn tag(self) -> u8 {
+        self as u8
+    }
+
+    pub(super) fn from_tag(tag: u8) -> Option<FrameUsage> {
+        FrameUsage::ALL.get((tag as usize).checked_sub(1)?).copied()
+    }
+}
+
+/// 空闲的帧在使用情况图上的字符
+pub const FREE_SYMBOL: char = '.';
+
+/// 一格在图上的字符
+pub fn cell_symbol(cell: Option<FrameUsage>) -> char {
+    cell.map_or(FREE_SYMBOL, FrameUsage::symbol)
+}
+
+/// 让`total`个帧不超过`max_cells`格时每格的帧数
+pub fn frames_per_cell(total: usize, max_cells: usize) -> usize {
+    total.div_ceil(max_cells).max(1)
+}
+
+/// 把按顺序排列的帧的用途每`frames_per_cell`个合成一格, 最后一格可能不满
+///
+/// 每格取其中最多的用途, 分配的帧和空闲的帧一样多时取分配的用途, 几种用途一样多时取靠前的
+pub fn cells(
+    frames: impl Iterator<Item = Option<FrameUsage>>,
+    frames_per_cell: usize,
+) -> Vec<Option<FrameUsage>> {
+    assert!(frames_per_cell > 0, "empty frame map cell");
+    let mut cells = Vec::new();
+    // 最后一项是空闲帧
+    let mut counts = [0usize; FrameUsage::ALL.len() + 1];
+    let mut filled = 0;
+    for usage in frames {
+        counts[usage.map_or(FrameUsage::ALL.len(), FrameUsage::index)] += 1;
+        filled += 1;
+        if filled == frames_per_cell {
+            cells.push(dominant(&counts));
+            counts = [0; FrameUsage::ALL.len() + 1];
+            filled = 0;
+        }
+    }
+    if filled > 0 {
+        cells.push(dominant(&counts));
+    }
+    cells
+}
+
+fn dominant(counts: &[usize; FrameUsage::ALL.len() + 1]) -> Option<FrameUsage> {
+    let (index, &count) = counts[..FrameUsage::ALL.len()]
+        .iter()
+        .enumerate()
+        .rev()
+        .max_by_key(|&(_, count)| count)?;
+    (count > 0 && count >= counts[FrameUsage::ALL.len()]).then(|| FrameUsage::ALL[index])
+}
+
+#[test_case]
+fn test_tags() {
+    for usage in FrameUsage::ALL {
+        assert_eq!(FrameUsage::from_tag(usage.tag()), Some(usage));
+        assert_eq!(FrameUsage::ALL[usage.index()], usage);
+    }
+    assert_eq!(FrameUsage::from_tag(0), None);
+    assert_eq!(FrameUsage::from_tag(7), None);
+}
+
+#[test_case]
+fn test_cells() {
+    use FrameUsage::*;
+
+    let frames = [
+        // 2个页表和2个空闲帧, 一样多时取分配的用途
+        Some(PageTable),
+        None,
+        Some(PageTable),
+        None,
+        // 堆和栈一样多时取靠前的
+        Some(Stack),
+        Some(Heap),
+        Some(Heap),
+        Some(Stack),
+        // 空闲帧最多
+        None,
+        None,
+        Some(User),
+        None,
+        // 不满的最后一格
+        Some(Mmio),
+    ];
+    let map: alloc::string::String = cells(frames.into_iter(), 4)
+        .into_iter()
+        .map(cell_symbol)
+        .collect();
+    assert_eq!(map, "PH.M");
+
+    let map = cells(frames.into_iter(), 1);
+    assert_eq!(map.len(), frames.len());
+    let count = |usage| map.iter().filter(|&&cell| cell == usage).count();
+    assert_eq!(count(None), 5);
+    assert_eq!(count(Some(Heap)), 2);
+    assert_eq!(count(Some(Other)), 0);
+    assert_eq!(frames_per_cell(13, 4), 4);
+    assert_eq!(frames_per_cell(3, 1024), 1);
+}
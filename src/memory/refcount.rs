@@ -0,0 +1,112 @@
+//! 共享物理帧的引用计数
+//!
+//! fork之后父子进程的用户页映射到同一个帧, 每个帧记录映射它的页表项数. 只有一个映射的帧不占用表项,
+//! 绝大多数帧因此不需要额外的内存
+
+use alloc::collections::BTreeMap;
+
+use x86_64::structures::paging::PhysFrame;
+
+/// 按帧号记录被多个页表项映射的帧
+pub struct FrameRefs {
+    // 映射数, 总是大于1
+    shared: BTreeMap<u64, u32>,
+}
+
+impl FrameRefs {
+    pub const fn new() -> Self {
+        FrameRefs {
+            shared: BTreeMap::new(),
+        }
+    }
+
+    /// 映射`frame`的页表项数, 没有记录的帧为1
+    pub fn count(&self, frame: PhysFrame) -> u32 {
+        self.shared.get(&number(frame)).copied().unwrap_or(1)
+    }
+
+    /// 增加一个映射, 返回新的映射数
+    pub fn increment(&mut self, frame: PhysFrame) -> u32 {
+        let count = self.shared.entry(number(frame)).or_insert(1);
+        *count += 1;
+        *count
+    }
+
+    /// 去掉一个映射, 返回是否是最后一个. 返回true时帧应当释放
+    pub fn decrement(&mut self, frame: PhysFrame) -> bool {
+        let number = number(frame);
+        match self.shared.get_mut(&number) {
+            None => true,
+            Some(2) => {
+                self.shared.remove(&number);
+                false
+            }
+            Some(count) => {
+                *count -= 1;
+                false
+            }
+        }
+    }
+
+    /// 被多个页表项映射的帧数
+    pub fn shared(&self) -> usize {
+        self.shared.len()
+    }
+}
+
+impl Default for FrameRefs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn number(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / 4096
+}
+
+#[cfg(test)]
+fn test_frame(number: u64) -> PhysFrame {
+    PhysFrame::containing_address(x86_64::PhysAddr::new(number * 4096))
+}
+
+#[test_case]
+fn test_unshared_frame() {
+    let mut refs = FrameRefs::new();
+    let frame = test_frame(7);
+    assert_eq!(refs.count(frame), 1);
+    // 唯一的映射去掉后释放, 不留下记录
+    assert!(refs.decrement(frame));
+    assert_eq!(refs.shared(), 0);
+}
+
+#[test_case]
+fn test_shared_frame() {
+    let mut refs = FrameRefs::new();
+    let (frame, other) = (test_frame(7), test_frame(8));
+    assert_eq!(refs.increment(frame), 2);
+    assert_eq!(refs.increment(frame), 3);
+    assert_eq!(refs.count(other), 1);
+    assert_eq!(refs.shared(), 1);
+    assert!(!refs.decrement(frame));
+    assert_eq!(refs.count(frame), 2);
+    // 剩下的一个映射不再记录
+    assert!(!refs.decrement(frame));
+    assert_eq!(refs.count(frame), 1);
+    assert_eq!(refs.shared(), 0);
+    assert!(refs.decrement(frame));
+}
+
+#[test_case]
+fn test_share_again_after_copy() {
+    let mut refs = FrameRefs::new();
+    let frame = test_frame(3);
+    // 父子进程共享后子进程写入时复制, 父进程再次fork
+    refs.increment(frame);
+    assert!(!refs.decrement(frame));
+    assert_eq!(refs.increment(frame), 2);
+    assert_eq!(refs.increment(test_frame(4)), 2);
+    assert_eq!(refs.shared(), 2);
+    assert!(!refs.decrement(frame));
+    assert!(refs.decrement(frame));
+    assert_eq!(refs.count(test_frame(4)), 2);
+}
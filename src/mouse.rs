@@ -0,0 +1,186 @@
+use futures_util::stream::{Stream, StreamExt};
+use spin::Mutex;
+
+use crate::task::channel::Channel;
+
+// 第一个字节
+const BUTTON_LEFT: u8 = 1 << 0;
+const BUTTON_RIGHT: u8 = 1 << 1;
+const BUTTON_MIDDLE: u8 = 1 << 2;
+// 第一个字节的bit3恒为1, 用于重新同步
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// 一个鼠标数据包, dy向上为正
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// 将IRQ12收到的字节拼成3字节的数据包
+pub struct PacketAssembler {
+    bytes: [u8; 3],
+    index: usize,
+}
+
+impl PacketAssembler {
+    pub const fn new() -> Self {
+        PacketAssembler {
+            bytes: [0; 3],
+            index: 0,
+        }
+    }
+
+    /// 收到完整且有效的数据包时返回事件
+    pub fn push(&mut self, byte: u8) -> Option<MouseEvent> {
+        // 第一个字节的bit3不为1说明与数据流失去同步, 丢弃直到找到下一个包头
+        if self.index == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+
+        self.bytes[self.index] = byte;
+        self.index += 1;
+        if self.index < self.bytes.len() {
+            return None;
+        }
+        self.index = 0;
+
+        let flags = self.bytes[0];
+        // 溢出时位移量不可信
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return None;
+        }
+        // 9位补码, 符号位在第一个字节中
+        let extend = |value: u8, sign: u8| {
+            let value = i16::from(value);
+            if flags & sign != 0 {
+                value - 0x100
+            } else {
+                value
+            }
+        };
+        let dx = extend(self.bytes[1], X_SIGN);
+        let dy = extend(self.bytes[2], Y_SIGN);
+        Some(MouseEvent {
+            dx,
+            dy,
+            left: flags & BUTTON_LEFT != 0,
+            right: flags & BUTTON_RIGHT != 0,
+            middle: flags & BUTTON_MIDDLE != 0,
+        })
+    }
+}
+
+impl Default for PacketAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const EVENT_QUEUE_SIZE: usize = 100;
+
+static ASSEMBLER: Mutex<PacketAssembler> = Mutex::new(PacketAssembler::new());
+static EVENTS: Channel<MouseEvent> = Channel::new(EVENT_QUEUE_SIZE);
+
+/// 由鼠标中断调用
+pub(crate) fn handle_byte(byte: u8) {
+    if let Some(event) = ASSEMBLER.lock().push(byte) {
+        EVENTS.push(event);
+    }
+}
+
+/// 鼠标事件流, 同一时刻只能有一个消费者
+pub fn events() -> impl Stream<Item = MouseEvent> {
+    EVENTS.stream()
+}
+
+// 每个字符格对应的鼠标位移
+const COUNTS_PER_COL: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+/// 用反色的字符格作为光标跟随鼠标移动, 同时按下左右键时结束
+pub async fn cursor_demo() {
+    use crate::vga_buffer::{invert_cell, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+    let max_x = BUFFER_WIDTH as i32 * COUNTS_PER_COL - 1;
+    let max_y = BUFFER_HEIGHT as i32 * COUNTS_PER_ROW - 1;
+    let mut x = max_x / 2;
+    let mut y = max_y / 2;
+    let cell = |x: i32, y: i32| ((y / COUNTS_PER_ROW) as usize, (x / COUNTS_PER_COL) as usize);
+
+    let mut cursor = cell(x, y);
+    invert_cell(cursor.0, cursor.1);
+
+    let mut events = events();
+    while let Some(event) = events.next().await {
+        if event.left && event.right {
+            break;
+        }
+        x = (x + i32::from(event.dx)).clamp(0, max_x);
+        // 屏幕坐标向下为正
+        y = (y - i32::from(event.dy)).clamp(0, max_y);
+
+        let next = cell(x, y);
+        if next != cursor {
+            invert_cell(cursor.0, cursor.1);
+            invert_cell(next.0, next.1);
+            cursor = next;
+        }
+    }
+    invert_cell(cursor.0, cursor.1);
+}
+
+#[cfg(test)]
+fn feed(assembler: &mut PacketAssembler, bytes: &[u8]) -> alloc::vec::Vec<MouseEvent> {
+    bytes.iter().filter_map(|&b| assembler.push(b)).collect()
+}
+
+#[test_case]
+fn test_packet_decoding() {
+    let mut assembler = PacketAssembler::new();
+    let events = feed(
+        &mut assembler,
+        &[
+            ALWAYS_ONE | BUTTON_LEFT, 5, 3,
+            ALWAYS_ONE | X_SIGN | Y_SIGN | BUTTON_MIDDLE, 0xFB, 0xFE,
+        ],
+    );
+    assert_eq!(
+        events,
+        [
+            MouseEvent { dx: 5, dy: 3, left: true, right: false, middle: false },
+            MouseEvent { dx: -5, dy: -2, left: false, right: false, middle: true },
+        ]
+    );
+}
+
+#[test_case]
+fn test_packet_resync() {
+    let mut assembler = PacketAssembler::new();
+    // 从数据包中间开始接收, bit3为0的字节被丢弃
+    let events = feed(&mut assembler, &[0x05, 0x03, ALWAYS_ONE | BUTTON_RIGHT, 1, 2]);
+    assert_eq!(
+        events,
+        [MouseEvent { dx: 1, dy: 2, left: false, right: true, middle: false }]
+    );
+}
+
+#[test_case]
+fn test_packet_overflow_discarded() {
+    let mut assembler = PacketAssembler::new();
+    let events = feed(
+        &mut assembler,
+        &[ALWAYS_ONE | X_OVERFLOW, 0xFF, 0, ALWAYS_ONE, 0, 7],
+    );
+    assert_eq!(
+        events,
+        [MouseEvent { dx: 0, dy: 7, left: false, right: false, middle: false }]
+    );
+}
@@ -0,0 +1,229 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Deref;
+
+use futures_util::stream::Stream;
+use spin::Mutex;
+
+pub mod arp;
+pub mod dhcp;
+pub mod e1000;
+pub mod icmp;
+pub mod ipv4;
+pub mod http;
+pub mod stack;
+pub mod tcp;
+pub mod udp;
+
+pub use core::net::Ipv4Addr;
+pub use e1000::TxError;
+
+/// 以太网帧的最大长度, 不含FCS
+pub const MAX_FRAME_SIZE: usize = 1514;
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// 解析收到的数据时发现的问题
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    Truncated,
+    /// 长度等字段自相矛盾
+    Malformed,
+    BadChecksum,
+    /// 格式正确但不支持, 如其他硬件类型的ARP
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// 借用接收缓冲区的以太网帧视图
+#[derive(Debug, Clone, Copy)]
+pub struct Ethernet<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Ethernet<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Ethernet<'a>, ParseError> {
+        if bytes.len() < ETHERNET_HEADER_SIZE {
+            return Err(ParseError::Truncated);
+        }
+        Ok(Ethernet { bytes })
+    }
+
+    pub fn destination(&self) -> MacAddress {
+        MacAddress(self.bytes[0..6].try_into().unwrap())
+    }
+
+    pub fn source(&self) -> MacAddress {
+        MacAddress(self.bytes[6..12].try_into().unwrap())
+    }
+
+    pub fn ethertype(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[12], self.bytes[13]])
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[ETHERNET_HEADER_SIZE..]
+    }
+}
+
+/// 在`buf`开头写入以太网头, 返回头部长度
+pub fn write_ethernet_header(
+    buf: &mut [u8],
+    destination: MacAddress,
+    source: MacAddress,
+    ethertype: u16,
+) -> usize {
+    buf[0..6].copy_from_slice(&destination.0);
+    buf[6..12].copy_from_slice(&source.0);
+    buf[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    ETHERNET_HEADER_SIZE
+}
+
+/// 静态的IPv4配置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mac: MacAddress,
+}
+
+impl Config {
+    /// `ip`与本机是否在同一子网
+    pub fn is_local(&self, ip: Ipv4Addr) -> bool {
+        (u32::from(ip) ^ u32::from(self.ip)) & u32::from(self.netmask) == 0
+    }
+
+    /// 子网的广播地址
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.ip) | !u32::from(self.netmask))
+    }
+}
+
+static CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+/// 设置本机地址, 协议栈立即使用新的配置. `ip`为0.0.0.0表示还没有地址
+pub fn configure(ip: Ipv4Addr, netmask: Ipv4Addr, gateway: Ipv4Addr, mac: MacAddress) {
+    let config = Config {
+        ip,
+        netmask,
+        gateway,
+        mac,
+    };
+    *CONFIG.lock() = Some(config);
+    stack::reconfigure(config);
+}
+
+pub fn config() -> Option<Config> {
+    *CONFIG.lock()
+}
+
+/// 收到的一个以太网帧, 不含FCS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(data: Vec<u8>) -> Frame {
+        Frame { data }
+    }
+
+    pub fn destination(&self) -> Option<MacAddress> {
+        Some(MacAddress(self.data.get(0..6)?.try_into().unwrap()))
+    }
+
+    pub fn source(&self) -> Option<MacAddress> {
+        Some(MacAddress(self.data.get(6..12)?.try_into().unwrap()))
+    }
+
+    pub fn ethertype(&self) -> Option<u16> {
+        let bytes = self.data.get(12..ETHERNET_HEADER_SIZE)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// 以太网头之后的内容
+    pub fn payload(&self) -> &[u8] {
+        self.data.get(ETHERNET_HEADER_SIZE..).unwrap_or(&[])
+    }
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// 网卡的MAC地址, 没有网卡时返回None
+pub fn mac_address() -> Option<MacAddress> {
+    Some(e1000::device()?.mac_address())
+}
+
+/// 发送一个完整的以太网帧, 网卡负责补齐最小长度和追加FCS
+pub fn send(frame: &[u8]) -> Result<(), TxError> {
+    e1000::device().ok_or(TxError::NoDevice)?.send(frame)
+}
+
+/// 收到的帧, 同一时刻只能有一个接收端. 没有网卡时流立即结束
+pub fn rx_frames() -> impl Stream<Item = Frame> {
+    e1000::rx_frames()
+}
+
+#[test_case]
+fn test_frame_header() {
+    let mut data = Vec::from([0xFFu8; 6]);
+    data.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x08, 0x06, 0xAA]);
+    let frame = Frame::new(data);
+    assert_eq!(frame.destination(), Some(MacAddress::BROADCAST));
+    assert_eq!(frame.source(), Some(MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])));
+    assert_eq!(frame.ethertype(), Some(0x0806));
+    assert_eq!(frame.payload(), &[0xAA]);
+
+    let short = Frame::new(Vec::from([0u8; 10]));
+    assert_eq!(short.source(), None);
+    assert_eq!(short.payload(), &[] as &[u8]);
+}
+
+#[test_case]
+fn test_ethernet_parse() {
+    assert_eq!(Ethernet::parse(&[0; 13]).unwrap_err(), ParseError::Truncated);
+
+    let mut buf = [0u8; 16];
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    let len = write_ethernet_header(&mut buf, MacAddress::BROADCAST, mac, ETHERTYPE_ARP);
+    assert_eq!(len, ETHERNET_HEADER_SIZE);
+    let eth = Ethernet::parse(&buf).unwrap();
+    assert_eq!((eth.destination(), eth.source()), (MacAddress::BROADCAST, mac));
+    assert_eq!(eth.ethertype(), ETHERTYPE_ARP);
+    assert_eq!(eth.payload().len(), 2);
+}
+
+#[test_case]
+fn test_config_subnet() {
+    let config = Config {
+        ip: Ipv4Addr::new(10, 0, 2, 15),
+        netmask: Ipv4Addr::new(255, 255, 255, 0),
+        gateway: Ipv4Addr::new(10, 0, 2, 2),
+        mac: MacAddress([0; 6]),
+    };
+    assert!(config.is_local(Ipv4Addr::new(10, 0, 2, 200)));
+    assert!(!config.is_local(Ipv4Addr::new(10, 0, 3, 1)));
+    assert_eq!(config.broadcast(), Ipv4Addr::new(10, 0, 2, 255));
+}
@@ -0,0 +1,190 @@
+use super::{Ipv4Addr, MacAddress, ParseError, ETHERTYPE_IPV4};
+
+pub const PACKET_LENGTH: usize = 28;
+pub const OP_REQUEST: u16 = 1;
+pub const OP_REPLY: u16 = 2;
+
+const HARDWARE_ETHERNET: u16 = 1;
+
+/// 以太网上IPv4的ARP报文
+#[derive(Debug, Clone, Copy)]
+pub struct ArpPacket<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ArpPacket<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<ArpPacket<'a>, ParseError> {
+        if bytes.len() < PACKET_LENGTH {
+            return Err(ParseError::Truncated);
+        }
+        let hardware = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let protocol = u16::from_be_bytes([bytes[2], bytes[3]]);
+        if hardware != HARDWARE_ETHERNET || protocol != ETHERTYPE_IPV4 {
+            return Err(ParseError::Unsupported);
+        }
+        if bytes[4] != 6 || bytes[5] != 4 {
+            return Err(ParseError::Malformed);
+        }
+        Ok(ArpPacket {
+            bytes: &bytes[..PACKET_LENGTH],
+        })
+    }
+
+    pub fn operation(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[6], self.bytes[7]])
+    }
+
+    pub fn sender_mac(&self) -> MacAddress {
+        MacAddress(self.bytes[8..14].try_into().unwrap())
+    }
+
+    pub fn sender_ip(&self) -> Ipv4Addr {
+        ip_at(self.bytes, 14)
+    }
+
+    pub fn target_mac(&self) -> MacAddress {
+        MacAddress(self.bytes[18..24].try_into().unwrap())
+    }
+
+    pub fn target_ip(&self) -> Ipv4Addr {
+        ip_at(self.bytes, 24)
+    }
+}
+
+fn ip_at(bytes: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3])
+}
+
+/// 写入一个ARP报文, 返回报文长度
+pub fn write(
+    buf: &mut [u8],
+    operation: u16,
+    sender: (MacAddress, Ipv4Addr),
+    target: (MacAddress, Ipv4Addr),
+) -> usize {
+    let packet = &mut buf[..PACKET_LENGTH];
+    packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&operation.to_be_bytes());
+    packet[8..14].copy_from_slice(&sender.0 .0);
+    packet[14..18].copy_from_slice(&sender.1.octets());
+    packet[18..24].copy_from_slice(&target.0 .0);
+    packet[24..28].copy_from_slice(&target.1.octets());
+    PACKET_LENGTH
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    ip: Ipv4Addr,
+    mac: MacAddress,
+    last_used: u64,
+}
+
+/// 固定容量的ARP缓存, 满了以后淘汰最久未使用的一项
+pub struct ArpCache<const N: usize> {
+    entries: [Option<Entry>; N],
+    clock: u64,
+}
+
+impl<const N: usize> ArpCache<N> {
+    pub const fn new() -> Self {
+        ArpCache {
+            entries: [None; N],
+            clock: 0,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// 记录或更新`ip`对应的地址
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddress) {
+        let last_used = self.tick();
+        let entry = Entry { ip, mac, last_used };
+        if let Some(slot) = self.entries.iter_mut().flatten().find(|e| e.ip == ip) {
+            *slot = entry;
+            return;
+        }
+        let slot = match self.entries.iter_mut().find(|e| e.is_none()) {
+            Some(slot) => slot,
+            None => self
+                .entries
+                .iter_mut()
+                .min_by_key(|e| e.map_or(0, |e| e.last_used))
+                .unwrap(),
+        };
+        *slot = Some(entry);
+    }
+
+    pub fn lookup(&mut self, ip: Ipv4Addr) -> Option<MacAddress> {
+        let now = self.tick();
+        let entry = self.entries.iter_mut().flatten().find(|e| e.ip == ip)?;
+        entry.last_used = now;
+        Some(entry.mac)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<const N: usize> Default for ArpCache<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+fn mac(last: u8) -> MacAddress {
+    MacAddress([0x52, 0x54, 0, 0, 0, last])
+}
+
+#[test_case]
+fn test_arp_packet() {
+    let sender = (mac(1), Ipv4Addr::new(10, 0, 2, 15));
+    let target = (MacAddress([0; 6]), Ipv4Addr::new(10, 0, 2, 2));
+    // 以太网帧的最小长度会带来填充
+    let mut buf = [0u8; 46];
+    assert_eq!(write(&mut buf, OP_REQUEST, sender, target), PACKET_LENGTH);
+
+    let arp = ArpPacket::parse(&buf).unwrap();
+    assert_eq!(arp.operation(), OP_REQUEST);
+    assert_eq!((arp.sender_mac(), arp.sender_ip()), sender);
+    assert_eq!((arp.target_mac(), arp.target_ip()), target);
+
+    assert_eq!(ArpPacket::parse(&buf[..27]).unwrap_err(), ParseError::Truncated);
+    let mut other = buf;
+    other[1] = 6;
+    assert_eq!(ArpPacket::parse(&other).unwrap_err(), ParseError::Unsupported);
+    let mut bad_length = buf;
+    bad_length[4] = 8;
+    assert_eq!(ArpPacket::parse(&bad_length).unwrap_err(), ParseError::Malformed);
+}
+
+#[test_case]
+fn test_arp_cache_lru() {
+    let ip = |last| Ipv4Addr::new(10, 0, 2, last);
+    let mut cache = ArpCache::<2>::new();
+    cache.insert(ip(1), mac(1));
+    cache.insert(ip(2), mac(2));
+    // 访问1以后, 2成为最久未使用的一项
+    assert_eq!(cache.lookup(ip(1)), Some(mac(1)));
+    cache.insert(ip(3), mac(3));
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.lookup(ip(2)), None);
+    assert_eq!(cache.lookup(ip(3)), Some(mac(3)));
+
+    // 更新已有的项不会淘汰其他项
+    cache.insert(ip(1), mac(9));
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.lookup(ip(1)), Some(mac(9)));
+    assert_eq!(cache.lookup(ip(3)), Some(mac(3)));
+}
@@ -0,0 +1,486 @@
+use alloc::vec::Vec;
+
+use super::udp::{UdpError, UdpSocket};
+use super::{Ipv4Addr, MacAddress, ParseError};
+use crate::{println, rng, time};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// 还没有地址, 请服务器以广播回复
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// op到file字段的固定部分, 之后是magic cookie和选项
+const FIXED_LENGTH: usize = 236;
+const OPTIONS_OFFSET: usize = FIXED_LENGTH + 4;
+// BOOTP报文的最小长度, 部分服务器会丢弃更短的报文
+const MIN_MESSAGE_LENGTH: usize = 300;
+const MAX_MESSAGE_LENGTH: usize = 576;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+// 每轮的等待时间从1秒开始加倍
+const ATTEMPTS: u32 = 4;
+const INITIAL_TIMEOUT_SECS: u64 = 1;
+// 服务器没有给出子网掩码时按/24处理
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<MessageType> {
+        use MessageType::*;
+        [Discover, Offer, Request, Decline, Ack, Nak, Release, Inform]
+            .into_iter()
+            .find(|kind| *kind as u8 == value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpError {
+    NoDevice,
+    Udp(UdpError),
+    /// 所有重试都没有得到ACK
+    Timeout,
+}
+
+impl From<UdpError> for DhcpError {
+    fn from(err: UdpError) -> DhcpError {
+        DhcpError::Udp(err)
+    }
+}
+
+/// 借用接收缓冲区的DHCP报文
+#[derive(Debug, Clone, Copy)]
+pub struct DhcpMessage<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> DhcpMessage<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<DhcpMessage<'a>, ParseError> {
+        if bytes.len() < OPTIONS_OFFSET {
+            return Err(ParseError::Truncated);
+        }
+        // 没有magic cookie的是BOOTP报文
+        if bytes[FIXED_LENGTH..OPTIONS_OFFSET] != MAGIC_COOKIE {
+            return Err(ParseError::Unsupported);
+        }
+        if bytes[1] != HTYPE_ETHERNET || bytes[2] != 6 {
+            return Err(ParseError::Unsupported);
+        }
+        Ok(DhcpMessage { bytes })
+    }
+
+    pub fn op(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn xid(&self) -> u32 {
+        u32::from_be_bytes(self.bytes[4..8].try_into().unwrap())
+    }
+
+    /// 服务器分配的地址
+    pub fn yiaddr(&self) -> Ipv4Addr {
+        address(&self.bytes[16..20])
+    }
+
+    pub fn client_mac(&self) -> MacAddress {
+        MacAddress(self.bytes[28..34].try_into().unwrap())
+    }
+
+    pub fn raw_options(&self) -> RawOptions<'a> {
+        RawOptions {
+            bytes: &self.bytes[OPTIONS_OFFSET..],
+        }
+    }
+
+    pub fn options(&self) -> Result<Options, ParseError> {
+        Options::parse(self.raw_options())
+    }
+}
+
+fn address(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+/// 依次产生`(代码, 值)`, 跳过填充, 遇到END或出错后结束
+#[derive(Debug, Clone)]
+pub struct RawOptions<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for RawOptions<'a> {
+    type Item = Result<(u8, &'a [u8]), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut bytes = self.bytes;
+        // 缺少END时把报文结尾当作选项结束
+        while let Some((&OPTION_PAD, rest)) = bytes.split_first() {
+            bytes = rest;
+        }
+        self.bytes = &[];
+        let (&code, rest) = bytes.split_first()?;
+        if code == OPTION_END {
+            return None;
+        }
+        match rest.split_first() {
+            Some((&length, rest)) if rest.len() >= usize::from(length) => {
+                let (value, rest) = rest.split_at(usize::from(length));
+                self.bytes = rest;
+                Some(Ok((code, value)))
+            }
+            _ => Some(Err(ParseError::Truncated)),
+        }
+    }
+}
+
+/// 客户端关心的选项
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    pub message_type: Option<MessageType>,
+    pub subnet_mask: Option<Ipv4Addr>,
+    pub router: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+    pub lease_time: Option<u32>,
+    pub server_id: Option<Ipv4Addr>,
+}
+
+impl Options {
+    /// 不认识的选项被跳过, 已知选项的长度不对时返回Malformed
+    pub fn parse(raw: RawOptions) -> Result<Options, ParseError> {
+        let mut options = Options::default();
+        for option in raw {
+            let (code, value) = option?;
+            match code {
+                OPTION_MESSAGE_TYPE => {
+                    let [kind] = value else {
+                        return Err(ParseError::Malformed);
+                    };
+                    options.message_type =
+                        Some(MessageType::from_u8(*kind).ok_or(ParseError::Malformed)?);
+                }
+                OPTION_SUBNET_MASK => options.subnet_mask = Some(single_address(value)?),
+                OPTION_SERVER_ID => options.server_id = Some(single_address(value)?),
+                // 路由器和DNS服务器是地址列表, 只使用第一个
+                OPTION_ROUTER => options.router = Some(first_address(value)?),
+                OPTION_DNS => options.dns = Some(first_address(value)?),
+                OPTION_LEASE_TIME => {
+                    let bytes: [u8; 4] = value.try_into().map_err(|_| ParseError::Malformed)?;
+                    options.lease_time = Some(u32::from_be_bytes(bytes));
+                }
+                _ => {}
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn single_address(value: &[u8]) -> Result<Ipv4Addr, ParseError> {
+    match value.len() {
+        4 => Ok(address(value)),
+        _ => Err(ParseError::Malformed),
+    }
+}
+
+fn first_address(value: &[u8]) -> Result<Ipv4Addr, ParseError> {
+    if value.is_empty() || !value.len().is_multiple_of(4) {
+        return Err(ParseError::Malformed);
+    }
+    Ok(address(value))
+}
+
+fn build(xid: u32, mac: MacAddress, kind: MessageType, options: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut message = Vec::from([0u8; OPTIONS_OFFSET]);
+    message[0] = OP_REQUEST;
+    message[1] = HTYPE_ETHERNET;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message[28..34].copy_from_slice(&mac.0);
+    message[FIXED_LENGTH..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind as u8]);
+    for (code, value) in options {
+        message.extend_from_slice(&[*code, value.len() as u8]);
+        message.extend_from_slice(value);
+    }
+    message.extend_from_slice(&[
+        OPTION_PARAMETER_REQUEST,
+        4,
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+        OPTION_LEASE_TIME,
+        OPTION_END,
+    ]);
+    if message.len() < MIN_MESSAGE_LENGTH {
+        message.resize(MIN_MESSAGE_LENGTH, OPTION_PAD);
+    }
+    message
+}
+
+pub fn discover(xid: u32, mac: MacAddress) -> Vec<u8> {
+    build(xid, mac, MessageType::Discover, &[])
+}
+
+/// 请求`offer`中提供的地址
+pub fn request(xid: u32, mac: MacAddress, offer: &Lease) -> Vec<u8> {
+    let requested = offer.ip.octets();
+    let server = offer.server.octets();
+    let options: [(u8, &[u8]); 2] = [
+        (OPTION_REQUESTED_IP, &requested),
+        (OPTION_SERVER_ID, &server),
+    ];
+    build(xid, mac, MessageType::Request, &options)
+}
+
+/// 服务器提供或确认的地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    /// 租期秒数, None表示服务器没有给出
+    pub lease_time: Option<u32>,
+}
+
+impl Lease {
+    /// `source`是回复的来源地址, 没有服务器标识选项时使用
+    fn new(message: &DhcpMessage, options: &Options, source: Ipv4Addr) -> Lease {
+        Lease {
+            ip: message.yiaddr(),
+            netmask: options.subnet_mask.unwrap_or(DEFAULT_NETMASK),
+            gateway: options.router,
+            dns: options.dns,
+            server: options.server_id.unwrap_or(source),
+            lease_time: options.lease_time,
+        }
+    }
+}
+
+/// 等待本次交互中类型为`expected`之一的回复
+async fn receive(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    xid: u32,
+    mac: MacAddress,
+    expected: &[MessageType],
+) -> (MessageType, Lease) {
+    loop {
+        let (length, source, _) = socket.recv_from(buf).await;
+        let Ok(message) = DhcpMessage::parse(&buf[..length]) else {
+            continue;
+        };
+        if message.op() != OP_REPLY || message.xid() != xid || message.client_mac() != mac {
+            continue;
+        }
+        let Ok(options) = message.options() else {
+            continue;
+        };
+        match options.message_type {
+            Some(kind) if expected.contains(&kind) => {
+                return (kind, Lease::new(&message, &options, source))
+            }
+            _ => continue,
+        }
+    }
+}
+
+/// 通过DISCOVER/OFFER/REQUEST/ACK获取地址, 成功后调用`net::configure`
+///
+/// 需要协议栈任务在运行. 开始时清除原有的地址
+pub async fn acquire() -> Result<Lease, DhcpError> {
+    let mac = super::mac_address().ok_or(DhcpError::NoDevice)?;
+    let unspecified = Ipv4Addr::UNSPECIFIED;
+    super::configure(unspecified, unspecified, unspecified, mac);
+
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let xid = rng::u64() as u32;
+    let mut buf = Vec::from([0u8; MAX_MESSAGE_LENGTH]);
+    for attempt in 0..ATTEMPTS {
+        let wait = time::secs_to_ticks(INITIAL_TIMEOUT_SECS << attempt);
+
+        socket
+            .send_to(&discover(xid, mac), Ipv4Addr::BROADCAST, SERVER_PORT)
+            .await?;
+        let offer = time::timeout(
+            wait,
+            receive(&socket, &mut buf, xid, mac, &[MessageType::Offer]),
+        );
+        let Ok((_, offer)) = offer.await else {
+            continue;
+        };
+
+        socket
+            .send_to(&request(xid, mac, &offer), Ipv4Addr::BROADCAST, SERVER_PORT)
+            .await?;
+        let expected = [MessageType::Ack, MessageType::Nak];
+        // NAK或超时时从DISCOVER重新开始
+        match time::timeout(wait, receive(&socket, &mut buf, xid, mac, &expected)).await {
+            Ok((MessageType::Ack, lease)) => {
+                let gateway = lease.gateway.unwrap_or(unspecified);
+                super::configure(lease.ip, lease.netmask, gateway, mac);
+                return Ok(lease);
+            }
+            Ok((_, _)) => println!("dhcp: {} declined by {}", offer.ip, offer.server),
+            Err(_) => {}
+        }
+    }
+    Err(DhcpError::Timeout)
+}
+
+// 按QEMU user模式网络回复的OFFER整理, sname和file字段全为0
+#[cfg(test)]
+const OFFER_HEADER: [u8; 34] = [
+    0x02, 0x01, 0x06, 0x00, // op, htype, hlen, hops
+    0x3c, 0x4d, 0x5e, 0x6f, // xid
+    0x00, 0x00, 0x80, 0x00, // secs, flags
+    0, 0, 0, 0, // ciaddr
+    10, 0, 2, 15, // yiaddr
+    10, 0, 2, 2, // siaddr
+    0, 0, 0, 0, // giaddr
+    0x52, 0x54, 0x00, 0x12, 0x34, 0x56, // chaddr
+];
+
+#[cfg(test)]
+const OFFER_OPTIONS: [u8; 38] = [
+    99, 130, 83, 99, // magic cookie
+    53, 1, 2, // OFFER
+    54, 4, 10, 0, 2, 2, // 服务器标识
+    1, 4, 255, 255, 255, 0, // 子网掩码
+    3, 4, 10, 0, 2, 2, // 路由器
+    6, 4, 10, 0, 2, 3, // DNS
+    51, 4, 0x00, 0x01, 0x51, 0x80, // 租期86400秒
+    255,
+];
+
+#[cfg(test)]
+fn captured(options: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::from([0u8; FIXED_LENGTH]);
+    packet[..OFFER_HEADER.len()].copy_from_slice(&OFFER_HEADER);
+    packet.extend_from_slice(options);
+    packet.resize(MIN_MESSAGE_LENGTH, 0);
+    packet
+}
+
+#[test_case]
+fn test_parse_offer() {
+    let packet = captured(&OFFER_OPTIONS);
+    let message = DhcpMessage::parse(&packet).unwrap();
+    assert_eq!((message.op(), message.xid()), (OP_REPLY, 0x3c4d_5e6f));
+    assert_eq!(message.yiaddr(), Ipv4Addr::new(10, 0, 2, 15));
+    assert_eq!(
+        message.client_mac(),
+        MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+    );
+
+    let options = message.options().unwrap();
+    assert_eq!(options.message_type, Some(MessageType::Offer));
+    assert_eq!(options.subnet_mask, Some(Ipv4Addr::new(255, 255, 255, 0)));
+    assert_eq!(options.router, Some(Ipv4Addr::new(10, 0, 2, 2)));
+    assert_eq!(options.dns, Some(Ipv4Addr::new(10, 0, 2, 3)));
+    assert_eq!(options.lease_time, Some(86400));
+    assert_eq!(options.server_id, Some(Ipv4Addr::new(10, 0, 2, 2)));
+
+    let lease = Lease::new(&message, &options, Ipv4Addr::new(10, 0, 2, 2));
+    assert_eq!(
+        (lease.ip, lease.gateway),
+        (Ipv4Addr::new(10, 0, 2, 15), options.router)
+    );
+}
+
+#[test_case]
+fn test_parse_bad_options() {
+    // 截断在选项中间
+    let packet = captured(&OFFER_OPTIONS[..10]);
+    let message = DhcpMessage::parse(&packet[..OPTIONS_OFFSET + 6]).unwrap();
+    assert_eq!(message.options().unwrap_err(), ParseError::Truncated);
+
+    // 子网掩码只有3字节
+    let packet = captured(&[99, 130, 83, 99, 53, 1, 5, 1, 3, 255, 255, 255, 255]);
+    assert_eq!(
+        DhcpMessage::parse(&packet).unwrap().options().unwrap_err(),
+        ParseError::Malformed
+    );
+
+    // 未知的消息类型
+    let packet = captured(&[99, 130, 83, 99, 53, 1, 42, 255]);
+    assert_eq!(
+        DhcpMessage::parse(&packet).unwrap().options().unwrap_err(),
+        ParseError::Malformed
+    );
+
+    // 填充和未知选项被跳过, 多个路由器只取第一个
+    let packet = captured(&[
+        99, 130, 83, 99, 0, 0, 53, 1, 5, 12, 2, b'q', b'e', 3, 8, 10, 0, 2, 2, 10, 0, 2, 1, 255,
+    ]);
+    let options = DhcpMessage::parse(&packet).unwrap().options().unwrap();
+    assert_eq!(options.message_type, Some(MessageType::Ack));
+    assert_eq!(options.router, Some(Ipv4Addr::new(10, 0, 2, 2)));
+    assert_eq!(options.lease_time, None);
+
+    // 没有magic cookie
+    let packet = captured(&[0, 0, 0, 0]);
+    assert_eq!(
+        DhcpMessage::parse(&packet).unwrap_err(),
+        ParseError::Unsupported
+    );
+    assert_eq!(
+        DhcpMessage::parse(&packet[..200]).unwrap_err(),
+        ParseError::Truncated
+    );
+}
+
+#[test_case]
+fn test_build_request() {
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    let packet = discover(7, mac);
+    assert!(packet.len() >= MIN_MESSAGE_LENGTH);
+    let message = DhcpMessage::parse(&packet).unwrap();
+    assert_eq!(
+        (message.op(), message.xid(), message.client_mac()),
+        (OP_REQUEST, 7, mac)
+    );
+    assert_eq!(
+        message.options().unwrap().message_type,
+        Some(MessageType::Discover)
+    );
+
+    let offer = captured(&OFFER_OPTIONS);
+    let offer = DhcpMessage::parse(&offer).unwrap();
+    let lease = Lease::new(&offer, &offer.options().unwrap(), Ipv4Addr::UNSPECIFIED);
+    let packet = request(7, mac, &lease);
+    let message = DhcpMessage::parse(&packet).unwrap();
+    let requested = message.raw_options().find_map(|option| match option {
+        Ok((OPTION_REQUESTED_IP, value)) => Some(address(value)),
+        _ => None,
+    });
+    assert_eq!(requested, Some(Ipv4Addr::new(10, 0, 2, 15)));
+    let options = message.options().unwrap();
+    assert_eq!(options.message_type, Some(MessageType::Request));
+    assert_eq!(options.server_id, Some(Ipv4Addr::new(10, 0, 2, 2)));
+}
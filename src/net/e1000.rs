@@ -0,0 +1,574 @@
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{Frame, MacAddress, MAX_FRAME_SIZE};
+use crate::pci::{self, Bar};
+use crate::{interrupts, memory, time};
+
+const VENDOR_ID: u16 = 0x8086;
+// QEMU默认的82540EM
+const DEVICE_ID_82540EM: u16 = 0x100E;
+
+// 寄存器偏移
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00C0;
+const REG_IMS: u64 = 0x00D0;
+const REG_IMC: u64 = 0x00D8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+const REG_MTA: u64 = 0x5200;
+const REG_RAL: u64 = 0x5400;
+const REG_RAH: u64 = 0x5404;
+
+const MTA_ENTRIES: u64 = 128;
+
+const CTRL_LRST: u32 = 1 << 3;
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_PHY_RST: u32 = 1 << 31;
+
+const STATUS_LU: u32 = 1 << 1;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+const RAH_AV: u32 = 1 << 31;
+
+// 中断原因, ICR和IMS使用相同的位
+const INT_LSC: u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+const INT_RX: u32 = INT_RXDMT0 | INT_RXO | INT_RXT0;
+
+// BSIZE为0表示2048字节的接收缓冲区
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+// 铜缆接口推荐的包间隔
+const TIPG_VALUE: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RX_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const TX_STATUS_DD: u8 = 1 << 0;
+
+// 描述符环长度必须是128字节(8个描述符)的倍数
+const RX_RING_SIZE: u16 = 32;
+const TX_RING_SIZE: u16 = 32;
+const BUFFER_SIZE: usize = 2048;
+const FRAME_SIZE: usize = 4096;
+// 两个描述符环共用一页
+const TX_RING_OFFSET: usize = FRAME_SIZE / 2;
+
+const RESET_TIMEOUT_MS: u64 = 100;
+const EEPROM_TIMEOUT_MS: u64 = 10;
+const LINK_TIMEOUT_MS: u64 = 1000;
+const TX_TIMEOUT_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum E1000Error {
+    NoMemoryBar,
+    MapFailed,
+    ResetTimeout,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxError {
+    NoDevice,
+    /// 空帧或超过MAX_FRAME_SIZE
+    InvalidLength,
+    /// 发送环一直是满的
+    Timeout,
+}
+
+/// 描述符环的索引运算, 保留一项以区分空和满
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ring {
+    size: u16,
+}
+
+impl Ring {
+    pub const fn new(size: u16) -> Ring {
+        Ring { size }
+    }
+
+    pub fn next(self, index: u16) -> u16 {
+        (index + 1) % self.size
+    }
+
+    /// 从`from`前进到`to`需要的步数
+    pub fn distance(self, from: u16, to: u16) -> u16 {
+        (to + self.size - from) % self.size
+    }
+
+    /// `head`到`tail`之间的项已交给网卡, 返回还能提交的项数
+    pub fn free(self, head: u16, tail: u16) -> u16 {
+        self.size - 1 - self.distance(head, tail)
+    }
+}
+
+// 硬件定义的布局, 驱动不读取校验和等字段
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(dead_code)]
+struct RxDescriptor {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(dead_code)]
+struct TxDescriptor {
+    addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+struct Registers {
+    base: VirtAddr,
+}
+
+impl Registers {
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { read_volatile((self.base + reg).as_ptr()) }
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+        unsafe { write_volatile((self.base + reg).as_mut_ptr(), value) }
+    }
+}
+
+struct RxRing {
+    descriptors: *mut RxDescriptor,
+    buffers: PhysAddr,
+    // 下一个要检查的描述符
+    next: u16,
+    // 正在丢弃一个跨越多个描述符的帧
+    discarding: bool,
+}
+
+struct TxRing {
+    descriptors: *mut TxDescriptor,
+    buffers: PhysAddr,
+    // 下一个要填写的描述符, 与TDT相同
+    tail: u16,
+    // 最早一个尚未确认发送完成的描述符
+    clean: u16,
+}
+
+// 描述符和缓冲区只通过持有锁的一方访问
+unsafe impl Send for RxRing {}
+unsafe impl Send for TxRing {}
+
+fn buffer(base: PhysAddr, index: u16) -> PhysAddr {
+    base + (usize::from(index) * BUFFER_SIZE) as u64
+}
+
+impl RxRing {
+    fn descriptor(&self, index: u16) -> RxDescriptor {
+        unsafe { read_volatile(self.descriptors.add(usize::from(index))) }
+    }
+
+    // 清除状态后把描述符交还给网卡
+    fn recycle(&mut self, index: u16) {
+        let desc = RxDescriptor {
+            addr: buffer(self.buffers, index).as_u64(),
+            ..RxDescriptor::default()
+        };
+        unsafe { write_volatile(self.descriptors.add(usize::from(index)), desc) }
+    }
+}
+
+impl TxRing {
+    // 回收网卡已经写回DD的描述符
+    fn reclaim(&mut self) {
+        let ring = Ring::new(TX_RING_SIZE);
+        while self.clean != self.tail {
+            let desc = unsafe { read_volatile(self.descriptors.add(usize::from(self.clean))) };
+            if desc.status & TX_STATUS_DD == 0 {
+                break;
+            }
+            self.clean = ring.next(self.clean);
+        }
+    }
+}
+
+/// Intel 82540EM网卡, 使用传统描述符格式
+pub struct E1000 {
+    regs: Registers,
+    mac: MacAddress,
+    rx: Mutex<RxRing>,
+    tx: Mutex<TxRing>,
+    irq: Option<u8>,
+}
+
+static DEVICE: Once<E1000> = Once::new();
+
+// 中断处理函数只读取ICR并唤醒接收端, 不获取设备锁
+static ICR_ADDRESS: AtomicU64 = AtomicU64::new(0);
+static IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+static RX_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// 查找并初始化网卡, 没有设备时什么也不做. 需要堆和DMA内存
+pub fn init() -> Result<(), E1000Error> {
+    let Some(pci_device) = pci::devices()
+        .find(|d| d.vendor_id == VENDOR_ID && d.device_id == DEVICE_ID_82540EM)
+    else {
+        return Ok(());
+    };
+    let (address, size) = match pci_device.bars[0] {
+        Bar::Memory { address, size, .. } => (address, size as usize),
+        _ => return Err(E1000Error::NoMemoryBar),
+    };
+    pci::enable_bus_master(pci_device);
+    let base = memory::map_mmio(PhysAddr::new(address), size).map_err(|_| E1000Error::MapFailed)?;
+    let regs = Registers { base };
+
+    // 复位期间和复位后都屏蔽所有中断, 并清除残留的中断原因
+    regs.write(REG_IMC, u32::MAX);
+    regs.write(REG_CTRL, regs.read(REG_CTRL) | CTRL_RST);
+    time::wait_until(RESET_TIMEOUT_MS, || regs.read(REG_CTRL) & CTRL_RST == 0)
+        .map_err(|_| E1000Error::ResetTimeout)?;
+    regs.write(REG_IMC, u32::MAX);
+    regs.read(REG_ICR);
+
+    let ctrl = regs.read(REG_CTRL) & !(CTRL_LRST | CTRL_PHY_RST);
+    regs.write(REG_CTRL, ctrl | CTRL_SLU | CTRL_ASDE);
+
+    let mac = read_mac(&regs);
+    let [m0, m1, m2, m3, m4, m5] = mac.0;
+    regs.write(REG_RAL, u32::from_le_bytes([m0, m1, m2, m3]));
+    regs.write(REG_RAH, u32::from(u16::from_le_bytes([m4, m5])) | RAH_AV);
+    // 不接收任何组播
+    for i in 0..MTA_ENTRIES {
+        regs.write(REG_MTA + i * 4, 0);
+    }
+
+    let (rx, tx) = setup_rings(&regs)?;
+
+    // 0和0xFF表示没有分配中断线, 接收端退回轮询
+    let interrupt_line = pci_device.interrupt_line;
+    let irq = if (1..16).contains(&interrupt_line)
+        && interrupts::register_irq(interrupt_line, on_interrupt).is_ok()
+    {
+        ICR_ADDRESS.store((base + REG_ICR).as_u64(), Ordering::SeqCst);
+        IRQ_ENABLED.store(true, Ordering::SeqCst);
+        regs.write(REG_IMS, INT_RX | INT_LSC);
+        interrupts::unmask_irq(interrupt_line);
+        Some(interrupt_line)
+    } else {
+        None
+    };
+
+    // 链路协商需要一点时间, 超时不影响之后使用
+    let _ = time::wait_until(LINK_TIMEOUT_MS, || regs.read(REG_STATUS) & STATUS_LU != 0);
+
+    DEVICE.call_once(|| E1000 {
+        regs,
+        mac,
+        rx: Mutex::new(rx),
+        tx: Mutex::new(tx),
+        irq,
+    });
+    Ok(())
+}
+
+// 优先从EEPROM读取, 失败时使用固件写入接收地址寄存器的值
+fn read_mac(regs: &Registers) -> MacAddress {
+    let mut words = [0u16; 3];
+    for (word, value) in words.iter_mut().enumerate() {
+        regs.write(REG_EERD, (word as u32) << 8 | EERD_START);
+        let mut result = 0;
+        let done = time::wait_until(EEPROM_TIMEOUT_MS, || {
+            result = regs.read(REG_EERD);
+            result & EERD_DONE != 0
+        });
+        if done.is_err() {
+            let low = regs.read(REG_RAL).to_le_bytes();
+            let high = regs.read(REG_RAH).to_le_bytes();
+            return MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]]);
+        }
+        *value = (result >> 16) as u16;
+    }
+    let [a, b] = words[0].to_le_bytes();
+    let [c, d] = words[1].to_le_bytes();
+    let [e, f] = words[2].to_le_bytes();
+    MacAddress([a, b, c, d, e, f])
+}
+
+fn setup_rings(regs: &Registers) -> Result<(RxRing, TxRing), E1000Error> {
+    let buffer_frames = |count: u16| (usize::from(count) * BUFFER_SIZE).div_ceil(FRAME_SIZE);
+    let rings = memory::allocate_dma_frames(1).map_err(|_| E1000Error::OutOfMemory)?;
+    let rx_buffers = memory::allocate_dma_frames(buffer_frames(RX_RING_SIZE))
+        .map_err(|_| E1000Error::OutOfMemory)?
+        .start_address();
+    let tx_buffers = memory::allocate_dma_frames(buffer_frames(TX_RING_SIZE))
+        .map_err(|_| E1000Error::OutOfMemory)?
+        .start_address();
+
+    let rx_ring = rings.start_address();
+    let tx_ring = rx_ring + TX_RING_OFFSET as u64;
+    let mut rx = RxRing {
+        descriptors: memory::phys_to_virt(rx_ring).as_mut_ptr(),
+        buffers: rx_buffers,
+        next: 0,
+        discarding: false,
+    };
+    for index in 0..RX_RING_SIZE {
+        rx.recycle(index);
+    }
+    // 发送描述符已由allocate_dma_frames清零
+    let tx = TxRing {
+        descriptors: memory::phys_to_virt(tx_ring).as_mut_ptr(),
+        buffers: tx_buffers,
+        tail: 0,
+        clean: 0,
+    };
+
+    let descriptor_size = core::mem::size_of::<RxDescriptor>() as u32;
+    regs.write(REG_RDBAL, rx_ring.as_u64() as u32);
+    regs.write(REG_RDBAH, (rx_ring.as_u64() >> 32) as u32);
+    regs.write(REG_RDLEN, u32::from(RX_RING_SIZE) * descriptor_size);
+    // 除最后一个外全部交给网卡, RDT追上RDH时网卡停止接收
+    regs.write(REG_RDH, 0);
+    regs.write(REG_RDT, u32::from(RX_RING_SIZE - 1));
+    regs.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+    regs.write(REG_TDBAL, tx_ring.as_u64() as u32);
+    regs.write(REG_TDBAH, (tx_ring.as_u64() >> 32) as u32);
+    regs.write(REG_TDLEN, u32::from(TX_RING_SIZE) * descriptor_size);
+    regs.write(REG_TDH, 0);
+    regs.write(REG_TDT, 0);
+    regs.write(REG_TIPG, TIPG_VALUE);
+    regs.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+    Ok((rx, tx))
+}
+
+// 读取ICR会清除中断原因, 接收的数据由任务在中断之外取走
+fn on_interrupt() {
+    let address = ICR_ADDRESS.load(Ordering::SeqCst);
+    if address == 0 {
+        return;
+    }
+    let cause = unsafe { read_volatile(address as *const u32) };
+    if cause != 0 {
+        INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+    }
+    if cause & INT_RX != 0 {
+        RX_WAKER.wake();
+    }
+}
+
+/// 已初始化的网卡
+pub fn device() -> Option<&'static E1000> {
+    DEVICE.get()
+}
+
+impl E1000 {
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    /// 网卡使用的中断线, 为None时接收端通过轮询获取数据
+    pub fn irq(&self) -> Option<u8> {
+        self.irq
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.regs.read(REG_STATUS) & STATUS_LU != 0
+    }
+
+    /// 收到的网卡中断次数
+    pub fn interrupt_count(&self) -> usize {
+        INTERRUPTS.load(Ordering::SeqCst)
+    }
+
+    /// 出错或跨越多个描述符而被丢弃的帧数
+    pub fn rx_dropped(&self) -> usize {
+        RX_DROPPED.load(Ordering::SeqCst)
+    }
+
+    /// 把帧复制到发送缓冲区并提交, 不等待发送完成
+    pub fn send(&self, frame: &[u8]) -> Result<(), TxError> {
+        if frame.is_empty() || frame.len() > MAX_FRAME_SIZE {
+            return Err(TxError::InvalidLength);
+        }
+        let ring = Ring::new(TX_RING_SIZE);
+        let mut tx = self.tx.lock();
+        time::wait_until(TX_TIMEOUT_MS, || {
+            tx.reclaim();
+            ring.free(tx.clean, tx.tail) > 0
+        })
+        .map_err(|_| TxError::Timeout)?;
+
+        let index = tx.tail;
+        let address = buffer(tx.buffers, index);
+        let desc = TxDescriptor {
+            addr: address.as_u64(),
+            length: frame.len() as u16,
+            cmd: TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS,
+            ..TxDescriptor::default()
+        };
+        unsafe {
+            let data: *mut u8 = memory::phys_to_virt(address).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), data, frame.len());
+            write_volatile(tx.descriptors.add(usize::from(index)), desc);
+        }
+        // 网卡必须在看到新的TDT之前看到描述符和数据
+        fence(Ordering::SeqCst);
+        tx.tail = ring.next(index);
+        self.regs.write(REG_TDT, u32::from(tx.tail));
+        Ok(())
+    }
+
+    // 取出下一个完整的帧并把描述符交还给网卡
+    fn receive(&self) -> Option<Frame> {
+        let ring = Ring::new(RX_RING_SIZE);
+        let mut rx = self.rx.lock();
+        loop {
+            let index = rx.next;
+            let desc = rx.descriptor(index);
+            if desc.status & RX_STATUS_DD == 0 {
+                return None;
+            }
+            // 看到DD之后才能读取长度和数据
+            fence(Ordering::SeqCst);
+
+            let end_of_packet = desc.status & RX_STATUS_EOP != 0;
+            let length = usize::from(desc.length);
+            let frame = if rx.discarding || !end_of_packet || desc.errors != 0 || length > BUFFER_SIZE {
+                if end_of_packet {
+                    RX_DROPPED.fetch_add(1, Ordering::SeqCst);
+                }
+                None
+            } else {
+                let data: *const u8 = memory::phys_to_virt(buffer(rx.buffers, index)).as_ptr();
+                let data = unsafe { core::slice::from_raw_parts(data, length) };
+                Some(Frame::new(Vec::from(data)))
+            };
+            rx.discarding = !end_of_packet;
+
+            rx.recycle(index);
+            fence(Ordering::SeqCst);
+            self.regs.write(REG_RDT, u32::from(index));
+            rx.next = ring.next(index);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+/// 接收端, 由网卡中断唤醒
+pub struct RxFrames {
+    device: Option<&'static E1000>,
+}
+
+pub fn rx_frames() -> RxFrames {
+    RxFrames { device: device() }
+}
+
+impl Stream for RxFrames {
+    type Item = Frame;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Frame>> {
+        let Some(device) = self.device else {
+            return Poll::Ready(None);
+        };
+        if let Some(frame) = device.receive() {
+            return Poll::Ready(Some(frame));
+        }
+
+        if IRQ_ENABLED.load(Ordering::SeqCst) {
+            // 注册waker后再检查一次, 防止错过注册前到达的帧
+            RX_WAKER.register(cx.waker());
+        } else {
+            cx.waker().wake_by_ref();
+        }
+        match device.receive() {
+            Some(frame) => Poll::Ready(Some(frame)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_descriptor_layout() {
+    assert_eq!(core::mem::size_of::<RxDescriptor>(), 16);
+    assert_eq!(core::mem::size_of::<TxDescriptor>(), 16);
+    assert!(TX_RING_OFFSET >= usize::from(RX_RING_SIZE) * 16);
+    assert_eq!(usize::from(TX_RING_SIZE) * 16 % 128, 0);
+}
+
+#[test_case]
+fn test_ring_wraparound() {
+    let ring = Ring::new(8);
+    assert_eq!(ring.next(6), 7);
+    assert_eq!(ring.next(7), 0);
+    assert_eq!(ring.distance(6, 2), 4);
+    assert_eq!(ring.distance(3, 3), 0);
+    // 空环最多提交size-1项
+    assert_eq!(ring.free(5, 5), 7);
+    // 尾指针回绕到头指针之前一项时环已满
+    assert_eq!(ring.free(5, 4), 0);
+    assert_eq!(ring.free(2, 7), 2);
+
+    // 反复填满再回收一部分, 让索引多次回绕
+    let (mut head, mut tail) = (0, 0);
+    for round in 0..50 {
+        for _ in 0..ring.free(head, tail) {
+            tail = ring.next(tail);
+        }
+        assert_eq!(ring.distance(head, tail), 7);
+        let done = round % 7 + 1;
+        for _ in 0..done {
+            head = ring.next(head);
+        }
+        assert_eq!(ring.free(head, tail), done);
+    }
+}
@@ -0,0 +1,144 @@
+//! 基于TCP的HTTP/1.0 GET, 读到连接关闭为止
+//!
+//! 命令行给出`http_get=ip:port/path`时, 配置好地址后由`demo`请求一次并输出结果
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use super::tcp::{TcpError, TcpSocket};
+use super::Ipv4Addr;
+use crate::{cmdline, println};
+
+// 响应的最大长度, 包括头部
+const MAX_RESPONSE: usize = 16 * 1024;
+const CHUNK: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpError {
+    Tcp(TcpError),
+    /// 状态行或头部格式不对
+    Malformed,
+    /// 超过`MAX_RESPONSE`
+    TooLarge,
+}
+
+impl From<TcpError> for HttpError {
+    fn from(err: TcpError) -> HttpError {
+        HttpError::Tcp(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// 请求`http://ip:port/path`, 服务器关闭连接后关闭己方并返回响应
+pub async fn get(ip: Ipv4Addr, port: u16, path: &str) -> Result<Response, HttpError> {
+    let socket = TcpSocket::connect(ip, port).await?;
+    let mut request = String::new();
+    let _ = write!(
+        request,
+        "GET {} HTTP/1.0\r\nHost: {}:{}\r\nConnection: close\r\n\r\n",
+        path, ip, port
+    );
+    socket.send(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut chunk = [0u8; CHUNK];
+    loop {
+        match socket.recv(&mut chunk).await? {
+            0 => break,
+            len if response.len() + len > MAX_RESPONSE => return Err(HttpError::TooLarge),
+            len => response.extend_from_slice(&chunk[..len]),
+        }
+    }
+    socket.close().await?;
+    parse_response(&response)
+}
+
+/// 解析响应, 忽略头部
+pub fn parse_response(response: &[u8]) -> Result<Response, HttpError> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(HttpError::Malformed)?;
+    let head = core::str::from_utf8(&response[..end]).map_err(|_| HttpError::Malformed)?;
+    let status_line = head.split("\r\n").next().unwrap_or("");
+    let mut parts = status_line.split(' ');
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::Malformed);
+    }
+    let status = parts
+        .next()
+        .and_then(|code| code.parse().ok())
+        .ok_or(HttpError::Malformed)?;
+    Ok(Response {
+        status,
+        body: Vec::from(&response[end + 4..]),
+    })
+}
+
+// "10.0.2.2:8000/index.html"
+fn parse_target(target: &str) -> Option<(Ipv4Addr, u16, &str)> {
+    let (address, path) = match target.find('/') {
+        Some(slash) => target.split_at(slash),
+        None => (target, "/"),
+    };
+    let (ip, port) = match address.split_once(':') {
+        Some((ip, port)) => (ip, port.parse().ok()?),
+        None => (address, 80),
+    };
+    Some((ip.parse().ok()?, port, path))
+}
+
+/// 命令行给出`http_get`时请求一次并输出状态码和响应体
+pub async fn demo() {
+    let Some(target) = cmdline::get("http_get") else {
+        return;
+    };
+    let Some((ip, port, path)) = parse_target(target) else {
+        println!("http: bad target {:?}", target);
+        return;
+    };
+    match get(ip, port, path).await {
+        Ok(response) => {
+            println!("http: {} {} bytes", response.status, response.body.len());
+            println!("{}", String::from_utf8_lossy(&response.body));
+        }
+        Err(err) => println!("http: {:?}", err),
+    }
+}
+
+#[test_case]
+fn test_parse_response() {
+    let response = parse_response(b"HTTP/1.0 200 OK\r\nServer: test\r\n\r\nhello\r\n").unwrap();
+    assert_eq!(
+        (response.status, &response.body[..]),
+        (200, &b"hello\r\n"[..])
+    );
+    let response = parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap();
+    assert_eq!((response.status, response.body.len()), (404, 0));
+    assert_eq!(
+        parse_response(b"HTTP/1.0 200 OK\r\n"),
+        Err(HttpError::Malformed)
+    );
+    assert_eq!(
+        parse_response(b"SSH-2.0\r\n\r\n"),
+        Err(HttpError::Malformed)
+    );
+}
+
+#[test_case]
+fn test_parse_target() {
+    let host = Ipv4Addr::new(10, 0, 2, 2);
+    assert_eq!(
+        parse_target("10.0.2.2:8000/index.html"),
+        Some((host, 8000, "/index.html"))
+    );
+    assert_eq!(parse_target("10.0.2.2"), Some((host, 80, "/")));
+    assert_eq!(parse_target("example.com/"), None);
+}
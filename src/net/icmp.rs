@@ -0,0 +1,99 @@
+use super::ipv4::checksum;
+use super::ParseError;
+
+pub const HEADER_LENGTH: usize = 8;
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+/// 借用接收缓冲区的ICMP报文, 解析时已校验整个报文的校验和
+#[derive(Debug, Clone, Copy)]
+pub struct IcmpPacket<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> IcmpPacket<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<IcmpPacket<'a>, ParseError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(ParseError::Truncated);
+        }
+        if checksum(bytes) != 0 {
+            return Err(ParseError::BadChecksum);
+        }
+        Ok(IcmpPacket { bytes })
+    }
+
+    pub fn kind(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn code(&self) -> u8 {
+        self.bytes[1]
+    }
+
+    /// 回显报文的标识符和序号
+    pub fn identifier(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[4], self.bytes[5]])
+    }
+
+    pub fn sequence(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[6], self.bytes[7]])
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[HEADER_LENGTH..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// 写入一个ICMP报文并计算校验和, `rest`是类型相关的第4..8字节, 返回报文长度
+pub fn write(buf: &mut [u8], kind: u8, code: u8, rest: [u8; 4], payload: &[u8]) -> usize {
+    let length = HEADER_LENGTH + payload.len();
+    let message = &mut buf[..length];
+    message[0] = kind;
+    message[1] = code;
+    message[2..4].fill(0);
+    message[4..8].copy_from_slice(&rest);
+    message[HEADER_LENGTH..].copy_from_slice(payload);
+    let sum = checksum(message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    length
+}
+
+/// 对回显请求的回复, 原样带回标识符、序号和数据
+pub fn write_echo_reply(buf: &mut [u8], request: &IcmpPacket) -> usize {
+    let rest = request.bytes[4..8].try_into().unwrap();
+    write(buf, TYPE_ECHO_REPLY, 0, rest, request.payload())
+}
+
+#[test_case]
+fn test_echo_reply() {
+    let mut request = [0u8; 12];
+    assert_eq!(write(&mut request, TYPE_ECHO_REQUEST, 0, [0x12, 0x34, 0x00, 0x05], b"ping"), 12);
+    let packet = IcmpPacket::parse(&request).unwrap();
+    assert_eq!((packet.kind(), packet.identifier(), packet.sequence()), (TYPE_ECHO_REQUEST, 0x1234, 5));
+
+    let mut reply = [0u8; 12];
+    assert_eq!(write_echo_reply(&mut reply, &packet), 12);
+    let reply = IcmpPacket::parse(&reply).unwrap();
+    assert_eq!((reply.kind(), reply.code()), (TYPE_ECHO_REPLY, 0));
+    assert_eq!((reply.identifier(), reply.sequence()), (0x1234, 5));
+    assert_eq!(reply.payload(), b"ping");
+}
+
+#[test_case]
+fn test_parse_errors() {
+    assert_eq!(IcmpPacket::parse(&[8, 0, 0, 0]).unwrap_err(), ParseError::Truncated);
+
+    let mut request = [0u8; 9];
+    write(&mut request, TYPE_ECHO_REQUEST, 0, [0; 4], &[0xAB]);
+    request[8] ^= 0xFF;
+    assert_eq!(IcmpPacket::parse(&request).unwrap_err(), ParseError::BadChecksum);
+}
@@ -0,0 +1,262 @@
+use super::{Ipv4Addr, ParseError};
+
+pub const HEADER_LENGTH: usize = 20;
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+pub const DEFAULT_TTL: u8 = 64;
+
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+/// 互联网校验和(RFC 1071): 按16位大端累加的反码和再取反, 奇数长度时末尾补0
+///
+/// 对包含正确校验和字段的数据再计算一次结果为0
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(data))
+}
+
+/// UDP等协议的校验和, 覆盖伪首部(源地址、目的地址、协议和长度)和整个报文
+pub fn pseudo_header_checksum(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    data: &[u8],
+) -> u16 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&source.octets());
+    pseudo[4..8].copy_from_slice(&destination.octets());
+    pseudo[9] = protocol;
+    pseudo[10..12].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    fold(sum(&pseudo) + sum(data))
+}
+
+// 未折叠的16位累加和, 以太网帧长度内不会溢出
+fn sum(data: &[u8]) -> u32 {
+    let mut sum = 0u32;
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// 借用接收缓冲区的IPv4数据报, 解析时已校验头部
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Packet<'a> {
+    header: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// 以太网帧可能带有填充字节, 按总长度截断
+    pub fn parse(bytes: &'a [u8]) -> Result<Ipv4Packet<'a>, ParseError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(ParseError::Truncated);
+        }
+        if bytes[0] >> 4 != 4 {
+            return Err(ParseError::Unsupported);
+        }
+        let header_length = usize::from(bytes[0] & 0x0F) * 4;
+        let total_length = usize::from(u16::from_be_bytes([bytes[2], bytes[3]]));
+        if header_length < HEADER_LENGTH || total_length < header_length {
+            return Err(ParseError::Malformed);
+        }
+        if bytes.len() < total_length {
+            return Err(ParseError::Truncated);
+        }
+        let header = &bytes[..header_length];
+        if checksum(header) != 0 {
+            return Err(ParseError::BadChecksum);
+        }
+        Ok(Ipv4Packet {
+            header,
+            payload: &bytes[header_length..total_length],
+        })
+    }
+
+    /// 包括选项的头部, ICMP差错报文需要带回
+    pub fn header(&self) -> &'a [u8] {
+        self.header
+    }
+
+    pub fn identification(&self) -> u16 {
+        u16::from_be_bytes([self.header[4], self.header[5]])
+    }
+
+    fn flags_and_offset(&self) -> u16 {
+        u16::from_be_bytes([self.header[6], self.header[7]])
+    }
+
+    /// 分片中的一片, 包括第一片
+    pub fn is_fragment(&self) -> bool {
+        self.flags_and_offset() & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.header[8]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.header[9]
+    }
+
+    pub fn source(&self) -> Ipv4Addr {
+        Ipv4Addr::new(
+            self.header[12],
+            self.header[13],
+            self.header[14],
+            self.header[15],
+        )
+    }
+
+    pub fn destination(&self) -> Ipv4Addr {
+        Ipv4Addr::new(
+            self.header[16],
+            self.header[17],
+            self.header[18],
+            self.header[19],
+        )
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+}
+
+/// 要发送的IPv4头部字段
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub identification: u16,
+}
+
+impl Header {
+    /// 在`buf`开头写入不带选项的头部并计算校验和, 返回头部长度. 不分片
+    pub fn write(&self, buf: &mut [u8], payload_length: usize) -> usize {
+        let total_length = (HEADER_LENGTH + payload_length) as u16;
+        let header = &mut buf[..HEADER_LENGTH];
+        header[0] = 0x45;
+        header[1] = 0;
+        header[2..4].copy_from_slice(&total_length.to_be_bytes());
+        header[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        header[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+        header[8] = self.ttl;
+        header[9] = self.protocol;
+        header[10..12].fill(0);
+        header[12..16].copy_from_slice(&self.source.octets());
+        header[16..20].copy_from_slice(&self.destination.octets());
+        let sum = checksum(header);
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+        HEADER_LENGTH
+    }
+}
+
+// 维基百科IPv4头部校验和一节中的例子
+#[cfg(test)]
+const SAMPLE_HEADER: [u8; 20] = [
+    0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00, 0x01,
+    0xc0, 0xa8, 0x00, 0xc7,
+];
+
+#[test_case]
+fn test_checksum() {
+    // RFC 1071中的例子
+    assert_eq!(
+        checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]),
+        0x220d
+    );
+    // 奇数长度在末尾补0
+    assert_eq!(checksum(&[0x01]), checksum(&[0x01, 0x00]));
+    assert_eq!(checksum(&[]), 0xFFFF);
+
+    assert_eq!(checksum(&SAMPLE_HEADER), 0);
+    let mut header = SAMPLE_HEADER;
+    header[10..12].fill(0);
+    assert_eq!(checksum(&header), 0xb861);
+}
+
+#[test_case]
+fn test_parse_header() {
+    let mut packet = [0u8; 0x73];
+    packet[..20].copy_from_slice(&SAMPLE_HEADER);
+    let ip = Ipv4Packet::parse(&packet).unwrap();
+    assert_eq!(ip.source(), Ipv4Addr::new(192, 168, 0, 1));
+    assert_eq!(ip.destination(), Ipv4Addr::new(192, 168, 0, 199));
+    assert_eq!((ip.protocol(), ip.ttl()), (PROTOCOL_UDP, 64));
+    assert_eq!(ip.payload().len(), 0x73 - 20);
+    assert!(!ip.is_fragment());
+
+    // 总长度超出收到的数据
+    assert_eq!(
+        Ipv4Packet::parse(&packet[..0x72]).unwrap_err(),
+        ParseError::Truncated
+    );
+    assert_eq!(
+        Ipv4Packet::parse(&packet[..19]).unwrap_err(),
+        ParseError::Truncated
+    );
+
+    let mut corrupted = packet;
+    corrupted[15] ^= 1;
+    assert_eq!(
+        Ipv4Packet::parse(&corrupted).unwrap_err(),
+        ParseError::BadChecksum
+    );
+
+    // 头部长度小于20字节
+    let mut short_ihl = packet;
+    short_ihl[0] = 0x44;
+    assert_eq!(
+        Ipv4Packet::parse(&short_ihl).unwrap_err(),
+        ParseError::Malformed
+    );
+
+    let mut ipv6 = packet;
+    ipv6[0] = 0x60;
+    assert_eq!(
+        Ipv4Packet::parse(&ipv6).unwrap_err(),
+        ParseError::Unsupported
+    );
+}
+
+#[test_case]
+fn test_write_header() {
+    let header = Header {
+        source: Ipv4Addr::new(10, 0, 2, 15),
+        destination: Ipv4Addr::new(10, 0, 2, 2),
+        protocol: PROTOCOL_ICMP,
+        ttl: DEFAULT_TTL,
+        identification: 7,
+    };
+    let mut buf = [0u8; 28];
+    assert_eq!(header.write(&mut buf, 8), HEADER_LENGTH);
+    let ip = Ipv4Packet::parse(&buf).unwrap();
+    assert_eq!(
+        (ip.source(), ip.destination()),
+        (header.source, header.destination)
+    );
+    assert_eq!((ip.identification(), ip.payload().len()), (7, 8));
+    assert!(!ip.is_fragment());
+
+    // 设置了MF或片偏移的都是分片
+    buf[6] |= 0x20;
+    buf[10..12].fill(0);
+    let sum = checksum(&buf[..20]);
+    buf[10..12].copy_from_slice(&sum.to_be_bytes());
+    assert!(Ipv4Packet::parse(&buf).unwrap().is_fragment());
+}
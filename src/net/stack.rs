@@ -0,0 +1,760 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use futures_util::stream::StreamExt;
+use spin::Mutex;
+
+use super::arp::{self, ArpCache, ArpPacket};
+use super::icmp::{self, IcmpPacket};
+use super::ipv4::{self, Ipv4Packet};
+use super::tcp::{self, TcpSegment};
+use super::udp::{self, UdpPacket};
+use super::{
+    write_ethernet_header, Config, Ethernet, Ipv4Addr, MacAddress, ParseError, TxError,
+    ETHERNET_HEADER_SIZE, ETHERTYPE_ARP, ETHERTYPE_IPV4, MAX_FRAME_SIZE,
+};
+use crate::time;
+
+const ARP_CACHE_SIZE: usize = 16;
+// 等待ARP回复的间隔和重试次数
+const ARP_RETRY_MS: u64 = 200;
+const ARP_ATTEMPTS: usize = 5;
+// ICMP差错报文带回的原数据报内容
+const ICMP_ERROR_DATA: usize = 8;
+const CODE_PORT_UNREACHABLE: u8 = 3;
+
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+static MALFORMED: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static ARP_REPLIES: AtomicUsize = AtomicUsize::new(0);
+static ECHO_REPLIES: AtomicUsize = AtomicUsize::new(0);
+static PORT_UNREACHABLE: AtomicUsize = AtomicUsize::new(0);
+static TX_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// 协议栈的计数器
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub received: usize,
+    /// 解析失败的帧
+    pub malformed: usize,
+    /// 格式正确但不处理的帧, 如分片或发给其他主机的
+    pub dropped: usize,
+    pub arp_replies: usize,
+    pub echo_replies: usize,
+    pub port_unreachable: usize,
+    pub tx_errors: usize,
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        received: RECEIVED.load(Ordering::Relaxed),
+        malformed: MALFORMED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        arp_replies: ARP_REPLIES.load(Ordering::Relaxed),
+        echo_replies: ECHO_REPLIES.load(Ordering::Relaxed),
+        port_unreachable: PORT_UNREACHABLE.load(Ordering::Relaxed),
+        tx_errors: TX_ERRORS.load(Ordering::Relaxed),
+    }
+}
+
+// 不处理一个帧的原因
+enum Discard {
+    Malformed,
+    Ignored,
+}
+
+impl From<ParseError> for Discard {
+    fn from(err: ParseError) -> Discard {
+        match err {
+            ParseError::Unsupported => Discard::Ignored,
+            _ => Discard::Malformed,
+        }
+    }
+}
+
+/// 发送IPv4数据报失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    NotConfigured,
+    TooLarge,
+    /// ARP解析下一跳超时
+    Unreachable,
+    Tx(TxError),
+}
+
+/// 构造出的待发送帧
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outgoing {
+    Ready(Vec<u8>),
+    /// 下一跳还没有解析, 需要先发送这个ARP请求
+    Resolving(Vec<u8>),
+}
+
+/// 以太网/ARP/IPv4/ICMP/UDP协议栈的状态
+pub struct Stack {
+    config: Config,
+    arp: ArpCache<ARP_CACHE_SIZE>,
+    identification: u16,
+}
+
+impl Stack {
+    pub fn new(config: Config) -> Stack {
+        Stack {
+            config,
+            arp: ArpCache::new(),
+            identification: 0,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// 地址变化时保留ARP缓存
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    fn next_hop(&self, ip: Ipv4Addr) -> Ipv4Addr {
+        if self.config.is_local(ip) {
+            ip
+        } else {
+            self.config.gateway
+        }
+    }
+
+    /// 发往`ip`要使用的下一跳MAC地址, 子网外的地址经过网关
+    pub fn resolve(&mut self, ip: Ipv4Addr) -> Option<MacAddress> {
+        if self.is_broadcast(ip) {
+            return Some(MacAddress::BROADCAST);
+        }
+        self.arp.lookup(self.next_hop(ip))
+    }
+
+    fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        ip == Ipv4Addr::BROADCAST || ip == self.config.broadcast()
+    }
+
+    /// 构造发往`destination`的IPv4帧, 下一跳未知时改为构造ARP请求
+    pub fn build_ipv4(&mut self, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Outgoing {
+        let Some(mac) = self.resolve(destination) else {
+            let mut frame = Vec::from([0u8; ETHERNET_HEADER_SIZE + arp::PACKET_LENGTH]);
+            let offset = write_ethernet_header(
+                &mut frame,
+                MacAddress::BROADCAST,
+                self.config.mac,
+                ETHERTYPE_ARP,
+            );
+            let ours = (self.config.mac, self.config.ip);
+            let target = (MacAddress([0; 6]), self.next_hop(destination));
+            arp::write(&mut frame[offset..], arp::OP_REQUEST, ours, target);
+            return Outgoing::Resolving(frame);
+        };
+
+        let mut frame = Vec::from([0u8; ETHERNET_HEADER_SIZE + ipv4::HEADER_LENGTH]);
+        let offset = write_ethernet_header(&mut frame, mac, self.config.mac, ETHERTYPE_IPV4);
+        let header = ipv4::Header {
+            source: self.config.ip,
+            destination,
+            protocol,
+            ttl: ipv4::DEFAULT_TTL,
+            identification: self.next_identification(),
+        };
+        header.write(&mut frame[offset..], payload.len());
+        frame.extend_from_slice(payload);
+        Outgoing::Ready(frame)
+    }
+
+    /// 处理收到的一个帧, 需要回复时写入`out`并返回回复的长度
+    pub fn handle(&mut self, frame: &[u8], out: &mut [u8]) -> Option<usize> {
+        RECEIVED.fetch_add(1, Ordering::Relaxed);
+        match self.dispatch(frame, out) {
+            Ok(reply) => reply,
+            Err(Discard::Malformed) => {
+                MALFORMED.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Err(Discard::Ignored) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn dispatch(&mut self, frame: &[u8], out: &mut [u8]) -> Result<Option<usize>, Discard> {
+        let eth = Ethernet::parse(frame)?;
+        let destination = eth.destination();
+        if destination != self.config.mac && destination != MacAddress::BROADCAST {
+            return Err(Discard::Ignored);
+        }
+        match eth.ethertype() {
+            ETHERTYPE_ARP => self.handle_arp(&eth, out),
+            ETHERTYPE_IPV4 => self.handle_ipv4(&eth, out),
+            _ => Err(Discard::Ignored),
+        }
+    }
+
+    fn handle_arp(&mut self, eth: &Ethernet, out: &mut [u8]) -> Result<Option<usize>, Discard> {
+        let packet = ArpPacket::parse(eth.payload())?;
+        let sender = (packet.sender_mac(), packet.sender_ip());
+        // 未指定的发送方地址用于地址冲突检测, 不记录
+        if !sender.1.is_unspecified() {
+            self.arp.insert(sender.1, sender.0);
+        }
+        if packet.operation() != arp::OP_REQUEST || packet.target_ip() != self.config.ip {
+            return Ok(None);
+        }
+
+        let length = ETHERNET_HEADER_SIZE + arp::PACKET_LENGTH;
+        if out.len() < length {
+            return Err(Discard::Ignored);
+        }
+        let offset = write_ethernet_header(out, sender.0, self.config.mac, ETHERTYPE_ARP);
+        let ours = (self.config.mac, self.config.ip);
+        arp::write(&mut out[offset..], arp::OP_REPLY, ours, sender);
+        ARP_REPLIES.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(length))
+    }
+
+    fn handle_ipv4(&mut self, eth: &Ethernet, out: &mut [u8]) -> Result<Option<usize>, Discard> {
+        let packet = Ipv4Packet::parse(eth.payload())?;
+        // 没有地址时(如DHCP期间)接收所有发给本机MAC的数据报
+        let unicast = packet.destination() == self.config.ip;
+        if !unicast && !self.is_broadcast(packet.destination()) && !self.config.ip.is_unspecified()
+        {
+            return Err(Discard::Ignored);
+        }
+        // 不重组分片, 收到TTL为0的数据报说明发送方有误
+        if packet.is_fragment() || packet.ttl() == 0 {
+            return Err(Discard::Ignored);
+        }
+        if self.config.is_local(packet.source()) {
+            self.arp.insert(packet.source(), eth.source());
+        }
+
+        match packet.protocol() {
+            ipv4::PROTOCOL_ICMP if unicast => self.handle_icmp(eth, &packet, out),
+            ipv4::PROTOCOL_UDP => self.handle_udp(eth, &packet, unicast, out),
+            ipv4::PROTOCOL_TCP if unicast => self.handle_tcp(eth, &packet, out),
+            _ => Err(Discard::Ignored),
+        }
+    }
+
+    fn handle_udp(
+        &mut self,
+        eth: &Ethernet,
+        packet: &Ipv4Packet,
+        unicast: bool,
+        out: &mut [u8],
+    ) -> Result<Option<usize>, Discard> {
+        let datagram = UdpPacket::parse(packet.payload(), packet.source(), packet.destination())?;
+        if udp::deliver(packet.source(), &datagram) {
+            return Ok(None);
+        }
+        // 广播或还没有地址时不回复差错报文
+        if !unicast || self.config.ip.is_unspecified() {
+            return Err(Discard::Ignored);
+        }
+
+        // 带回原数据报的IP头和前8字节
+        let data = packet.payload().len().min(ICMP_ERROR_DATA);
+        let icmp_length = icmp::HEADER_LENGTH + packet.header().len() + data;
+        let length = ETHERNET_HEADER_SIZE + ipv4::HEADER_LENGTH + icmp_length;
+        if out.len() < length {
+            return Err(Discard::Ignored);
+        }
+        let mut quoted = [0u8; 60 + ICMP_ERROR_DATA];
+        let quoted_length = packet.header().len() + data;
+        quoted[..packet.header().len()].copy_from_slice(packet.header());
+        quoted[packet.header().len()..quoted_length].copy_from_slice(&packet.payload()[..data]);
+
+        let mut offset = write_ethernet_header(out, eth.source(), self.config.mac, ETHERTYPE_IPV4);
+        let header = ipv4::Header {
+            source: self.config.ip,
+            destination: packet.source(),
+            protocol: ipv4::PROTOCOL_ICMP,
+            ttl: ipv4::DEFAULT_TTL,
+            identification: self.next_identification(),
+        };
+        offset += header.write(&mut out[offset..], icmp_length);
+        icmp::write(
+            &mut out[offset..],
+            icmp::TYPE_DESTINATION_UNREACHABLE,
+            CODE_PORT_UNREACHABLE,
+            [0; 4],
+            &quoted[..quoted_length],
+        );
+        PORT_UNREACHABLE.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(length))
+    }
+
+    // 连接需要的ACK或RST直接回复给发送方的MAC地址
+    fn handle_tcp(
+        &mut self,
+        eth: &Ethernet,
+        packet: &Ipv4Packet,
+        out: &mut [u8],
+    ) -> Result<Option<usize>, Discard> {
+        let segment = TcpSegment::parse(packet.payload(), packet.source(), packet.destination())?;
+        let Some(reply) = tcp::deliver(packet.source(), packet.destination(), &segment) else {
+            return Ok(None);
+        };
+        let length = ETHERNET_HEADER_SIZE + ipv4::HEADER_LENGTH + reply.len();
+        if out.len() < length || length > MAX_FRAME_SIZE {
+            return Err(Discard::Ignored);
+        }
+        let mut offset = write_ethernet_header(out, eth.source(), self.config.mac, ETHERTYPE_IPV4);
+        let header = ipv4::Header {
+            source: self.config.ip,
+            destination: packet.source(),
+            protocol: ipv4::PROTOCOL_TCP,
+            ttl: ipv4::DEFAULT_TTL,
+            identification: self.next_identification(),
+        };
+        offset += header.write(&mut out[offset..], reply.len());
+        out[offset..length].copy_from_slice(&reply);
+        Ok(Some(length))
+    }
+
+    fn handle_icmp(
+        &mut self,
+        eth: &Ethernet,
+        packet: &Ipv4Packet,
+        out: &mut [u8],
+    ) -> Result<Option<usize>, Discard> {
+        let request = IcmpPacket::parse(packet.payload())?;
+        if request.kind() != icmp::TYPE_ECHO_REQUEST {
+            return Err(Discard::Ignored);
+        }
+
+        let length = ETHERNET_HEADER_SIZE + ipv4::HEADER_LENGTH + request.len();
+        if out.len() < length || length > MAX_FRAME_SIZE {
+            return Err(Discard::Ignored);
+        }
+        // 直接回复给发送请求的MAC地址, 不需要查询ARP缓存
+        let mut offset = write_ethernet_header(out, eth.source(), self.config.mac, ETHERTYPE_IPV4);
+        let header = ipv4::Header {
+            source: self.config.ip,
+            destination: packet.source(),
+            protocol: ipv4::PROTOCOL_ICMP,
+            ttl: ipv4::DEFAULT_TTL,
+            identification: self.next_identification(),
+        };
+        offset += header.write(&mut out[offset..], request.len());
+        icmp::write_echo_reply(&mut out[offset..], &request);
+        ECHO_REPLIES.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(length))
+    }
+
+    fn next_identification(&mut self) -> u16 {
+        self.identification = self.identification.wrapping_add(1);
+        self.identification
+    }
+}
+
+// 协议栈任务和发送数据报的任务共享, 中断处理函数不访问
+static STACK: Mutex<Option<Stack>> = Mutex::new(None);
+
+/// 由`net::configure`调用, 第一次配置时创建协议栈
+pub(super) fn reconfigure(config: Config) {
+    let mut stack = STACK.lock();
+    match stack.as_mut() {
+        Some(stack) => stack.set_config(config),
+        None => *stack = Some(Stack::new(config)),
+    }
+}
+
+/// 发送一个IPv4数据报, 下一跳未知时先通过ARP解析
+pub async fn send_ipv4(
+    destination: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), SendError> {
+    if ETHERNET_HEADER_SIZE + ipv4::HEADER_LENGTH + payload.len() > MAX_FRAME_SIZE {
+        return Err(SendError::TooLarge);
+    }
+    for _ in 0..ARP_ATTEMPTS {
+        // 不能在持有锁时等待
+        let outgoing = STACK
+            .lock()
+            .as_mut()
+            .ok_or(SendError::NotConfigured)?
+            .build_ipv4(destination, protocol, payload);
+        match outgoing {
+            Outgoing::Ready(frame) => return transmit(&frame),
+            Outgoing::Resolving(request) => {
+                transmit(&request)?;
+                time::sleep_ms(ARP_RETRY_MS).await;
+            }
+        }
+    }
+    Err(SendError::Unreachable)
+}
+
+fn transmit(frame: &[u8]) -> Result<(), SendError> {
+    super::send(frame).map_err(|err| {
+        TX_ERRORS.fetch_add(1, Ordering::Relaxed);
+        SendError::Tx(err)
+    })
+}
+
+/// 协议栈任务, 处理收到的帧. 调用`net::configure`之前收到的帧被丢弃
+pub async fn run() {
+    let mut frames = super::rx_frames();
+    let mut reply = [0u8; MAX_FRAME_SIZE];
+    while let Some(frame) = frames.next().await {
+        let length = match STACK.lock().as_mut() {
+            Some(stack) => stack.handle(&frame, &mut reply),
+            None => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        };
+        if let Some(length) = length {
+            let _ = transmit(&reply[..length]);
+        }
+    }
+}
+
+#[cfg(test)]
+const HOST_MAC: MacAddress = MacAddress([0x52, 0x55, 0x0A, 0x00, 0x02, 0x02]);
+#[cfg(test)]
+const HOST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+#[cfg(test)]
+fn test_stack() -> Stack {
+    Stack::new(Config {
+        ip: Ipv4Addr::new(10, 0, 2, 15),
+        netmask: Ipv4Addr::new(255, 255, 255, 0),
+        gateway: HOST_IP,
+        mac: MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+    })
+}
+
+// 主机发给本机的ping
+#[cfg(test)]
+fn ping_frame(stack: &Stack, buf: &mut [u8], sequence: u16) -> usize {
+    let config = stack.config();
+    let payload = b"abcdefghijklmnopqrstuvwxyz";
+    let mut offset = write_ethernet_header(buf, config.mac, HOST_MAC, ETHERTYPE_IPV4);
+    let header = ipv4::Header {
+        source: HOST_IP,
+        destination: config.ip,
+        protocol: ipv4::PROTOCOL_ICMP,
+        ttl: 64,
+        identification: 1,
+    };
+    offset += header.write(&mut buf[offset..], icmp::HEADER_LENGTH + payload.len());
+    let rest = [0x00, 0x2A, (sequence >> 8) as u8, sequence as u8];
+    offset
+        + icmp::write(
+            &mut buf[offset..],
+            icmp::TYPE_ECHO_REQUEST,
+            0,
+            rest,
+            payload,
+        )
+}
+
+#[test_case]
+fn test_ping_reply() {
+    let mut stack = test_stack();
+    let mut request = [0u8; 128];
+    let length = ping_frame(&stack, &mut request, 3);
+    let before = stats();
+
+    let mut out = [0u8; MAX_FRAME_SIZE];
+    let reply_length = stack.handle(&request[..length], &mut out).unwrap();
+    assert_eq!(reply_length, length);
+    assert_eq!(stats().echo_replies, before.echo_replies + 1);
+
+    let eth = Ethernet::parse(&out[..reply_length]).unwrap();
+    assert_eq!(
+        (eth.destination(), eth.source()),
+        (HOST_MAC, stack.config().mac)
+    );
+    // 解析时会校验IP和ICMP的校验和
+    let ip = Ipv4Packet::parse(eth.payload()).unwrap();
+    assert_eq!(
+        (ip.source(), ip.destination()),
+        (stack.config().ip, HOST_IP)
+    );
+    assert_eq!(ip.ttl(), ipv4::DEFAULT_TTL);
+    let reply = IcmpPacket::parse(ip.payload()).unwrap();
+    assert_eq!(reply.kind(), icmp::TYPE_ECHO_REPLY);
+    assert_eq!((reply.identifier(), reply.sequence()), (0x2A, 3));
+    assert_eq!(reply.payload(), b"abcdefghijklmnopqrstuvwxyz");
+
+    // 学到了主机的地址
+    assert_eq!(stack.resolve(HOST_IP), Some(HOST_MAC));
+    assert_eq!(stack.resolve(Ipv4Addr::new(8, 8, 8, 8)), Some(HOST_MAC));
+}
+
+#[test_case]
+fn test_truncated_frames() {
+    let mut stack = test_stack();
+    let mut request = [0u8; 128];
+    let length = ping_frame(&stack, &mut request, 1);
+    let mut out = [0u8; MAX_FRAME_SIZE];
+
+    let before = stats().malformed;
+    for cut in 0..length {
+        assert_eq!(stack.handle(&request[..cut], &mut out), None);
+    }
+    assert_eq!(stats().malformed, before + length);
+
+    // 损坏的ICMP校验和
+    let mut corrupted = request;
+    corrupted[length - 1] ^= 0xFF;
+    assert_eq!(stack.handle(&corrupted[..length], &mut out), None);
+    assert_eq!(stats().malformed, before + length + 1);
+}
+
+#[test_case]
+fn test_garbage_frames() {
+    let mut stack = test_stack();
+    let mac = stack.config().mac;
+    let mut rng = crate::rng::Xorshift64::new(0x1234_5678);
+    let mut frame = [0u8; 128];
+    let mut out = [0u8; MAX_FRAME_SIZE];
+    for round in 0..500 {
+        for byte in frame.iter_mut() {
+            *byte = rng.next_u64() as u8;
+        }
+        // 让大部分帧通过以太网层, 进入ARP和IPv4的解析
+        frame[0..6].copy_from_slice(&mac.0);
+        let ethertype = if round % 2 == 0 {
+            ETHERTYPE_IPV4
+        } else {
+            ETHERTYPE_ARP
+        };
+        frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+        if round % 4 == 0 {
+            frame[14] = 0x45;
+        }
+        let length = (rng.next_u64() % frame.len() as u64) as usize;
+        let _ = stack.handle(&frame[..length], &mut out);
+    }
+}
+
+#[test_case]
+fn test_arp_request() {
+    let mut stack = test_stack();
+    let config = *stack.config();
+    let mut request = [0u8; 60];
+    let offset =
+        write_ethernet_header(&mut request, MacAddress::BROADCAST, HOST_MAC, ETHERTYPE_ARP);
+    let target = (MacAddress([0; 6]), config.ip);
+    arp::write(
+        &mut request[offset..],
+        arp::OP_REQUEST,
+        (HOST_MAC, HOST_IP),
+        target,
+    );
+
+    let mut out = [0u8; MAX_FRAME_SIZE];
+    let length = stack.handle(&request, &mut out).unwrap();
+    let eth = Ethernet::parse(&out[..length]).unwrap();
+    assert_eq!(
+        (eth.destination(), eth.ethertype()),
+        (HOST_MAC, ETHERTYPE_ARP)
+    );
+    let reply = ArpPacket::parse(eth.payload()).unwrap();
+    assert_eq!(reply.operation(), arp::OP_REPLY);
+    assert_eq!(
+        (reply.sender_mac(), reply.sender_ip()),
+        (config.mac, config.ip)
+    );
+    assert_eq!((reply.target_mac(), reply.target_ip()), (HOST_MAC, HOST_IP));
+
+    // 询问其他主机的请求只学习地址, 不回复
+    let other = Ipv4Addr::new(10, 0, 2, 3);
+    let mac = MacAddress([0x52, 0x54, 0, 0, 0, 3]);
+    arp::write(
+        &mut request[offset..],
+        arp::OP_REQUEST,
+        (mac, other),
+        (MacAddress([0; 6]), HOST_IP),
+    );
+    assert_eq!(stack.handle(&request, &mut out), None);
+    assert_eq!(stack.resolve(other), Some(mac));
+}
+
+#[test_case]
+fn test_fragments_dropped() {
+    let mut stack = test_stack();
+    let mut request = [0u8; 128];
+    let length = ping_frame(&stack, &mut request, 1);
+    // 设置MF位并重新计算头部校验和
+    let header = &mut request[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + ipv4::HEADER_LENGTH];
+    header[6] |= 0x20;
+    header[10..12].fill(0);
+    let sum = ipv4::checksum(header);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    let before = stats();
+    let mut out = [0u8; MAX_FRAME_SIZE];
+    assert_eq!(stack.handle(&request[..length], &mut out), None);
+    let after = stats();
+    assert_eq!(
+        (after.dropped, after.malformed),
+        (before.dropped + 1, before.malformed)
+    );
+}
+
+// 主机发给`destination`的UDP数据报
+#[cfg(test)]
+fn udp_frame(stack: &Stack, buf: &mut [u8], destination: Ipv4Addr, port: u16) -> usize {
+    let datagram = udp::build((HOST_IP, 5555), (destination, port), b"hello");
+    let mut offset = write_ethernet_header(buf, stack.config().mac, HOST_MAC, ETHERTYPE_IPV4);
+    let header = ipv4::Header {
+        source: HOST_IP,
+        destination,
+        protocol: ipv4::PROTOCOL_UDP,
+        ttl: 64,
+        identification: 2,
+    };
+    offset += header.write(&mut buf[offset..], datagram.len());
+    buf[offset..offset + datagram.len()].copy_from_slice(&datagram);
+    offset + datagram.len()
+}
+
+#[test_case]
+fn test_udp_demux() {
+    let mut stack = test_stack();
+    let ip = stack.config().ip;
+    let mut request = [0u8; 128];
+    let mut out = [0u8; MAX_FRAME_SIZE];
+
+    let socket = udp::UdpSocket::bind(4100).unwrap();
+    let length = udp_frame(&stack, &mut request, ip, 4100);
+    assert_eq!(stack.handle(&request[..length], &mut out), None);
+    let mut buf = [0u8; 16];
+    assert_eq!(socket.try_recv_from(&mut buf), Some((5, HOST_IP, 5555)));
+    assert_eq!(&buf[..5], b"hello");
+
+    // 发往子网广播地址的数据报也交给套接字
+    let length = udp_frame(&stack, &mut request, Ipv4Addr::new(10, 0, 2, 255), 4100);
+    assert_eq!(stack.handle(&request[..length], &mut out), None);
+    assert!(socket.try_recv_from(&mut buf).is_some());
+}
+
+#[test_case]
+fn test_port_unreachable() {
+    let mut stack = test_stack();
+    let ip = stack.config().ip;
+    let mut request = [0u8; 128];
+    let length = udp_frame(&stack, &mut request, ip, 4200);
+    let mut out = [0u8; MAX_FRAME_SIZE];
+
+    let before = stats().port_unreachable;
+    let reply_length = stack.handle(&request[..length], &mut out).unwrap();
+    assert_eq!(stats().port_unreachable, before + 1);
+    let eth = Ethernet::parse(&out[..reply_length]).unwrap();
+    assert_eq!(eth.destination(), HOST_MAC);
+    let packet = Ipv4Packet::parse(eth.payload()).unwrap();
+    assert_eq!(
+        (packet.destination(), packet.protocol()),
+        (HOST_IP, ipv4::PROTOCOL_ICMP)
+    );
+    let error = IcmpPacket::parse(packet.payload()).unwrap();
+    assert_eq!(
+        (error.kind(), error.code()),
+        (icmp::TYPE_DESTINATION_UNREACHABLE, CODE_PORT_UNREACHABLE)
+    );
+    // 带回原IP头和UDP头
+    let quoted = &request[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + ipv4::HEADER_LENGTH + 8];
+    assert_eq!(error.payload(), quoted);
+
+    // 广播的数据报不回复差错报文
+    let length = udp_frame(&stack, &mut request, Ipv4Addr::BROADCAST, 4200);
+    assert_eq!(stack.handle(&request[..length], &mut out), None);
+}
+
+#[test_case]
+fn test_tcp_reset() {
+    use super::tcp::connection::{Segment, FLAG_ACK, FLAG_RST, FLAG_SYN};
+
+    let mut stack = test_stack();
+    let ip = stack.config().ip;
+    let syn = Segment {
+        seq: 100,
+        flags: FLAG_SYN,
+        ..Segment::default()
+    };
+    let segment = tcp::build((HOST_IP, 5555), (ip, 4300), &syn);
+    let mut request = [0u8; 128];
+    let mut offset =
+        write_ethernet_header(&mut request, stack.config().mac, HOST_MAC, ETHERTYPE_IPV4);
+    let header = ipv4::Header {
+        source: HOST_IP,
+        destination: ip,
+        protocol: ipv4::PROTOCOL_TCP,
+        ttl: 64,
+        identification: 3,
+    };
+    offset += header.write(&mut request[offset..], segment.len());
+    request[offset..offset + segment.len()].copy_from_slice(&segment);
+    let length = offset + segment.len();
+
+    // 没有连接的端口回复RST
+    let mut out = [0u8; MAX_FRAME_SIZE];
+    let reply_length = stack.handle(&request[..length], &mut out).unwrap();
+    let eth = Ethernet::parse(&out[..reply_length]).unwrap();
+    assert_eq!(eth.destination(), HOST_MAC);
+    let packet = Ipv4Packet::parse(eth.payload()).unwrap();
+    assert_eq!(packet.protocol(), ipv4::PROTOCOL_TCP);
+    let reset = TcpSegment::parse(packet.payload(), ip, HOST_IP).unwrap();
+    assert_eq!(reset.destination_port(), 5555);
+    let reset = reset.to_segment();
+    assert_eq!((reset.flags, reset.ack), (FLAG_RST | FLAG_ACK, 101));
+}
+
+#[test_case]
+fn test_build_ipv4_resolves() {
+    let mut stack = test_stack();
+    let config = *stack.config();
+    let other = Ipv4Addr::new(10, 0, 2, 7);
+
+    // 下一跳未知时先发送ARP请求
+    let Outgoing::Resolving(request) = stack.build_ipv4(other, ipv4::PROTOCOL_UDP, b"x") else {
+        panic!("expected an ARP request");
+    };
+    let eth = Ethernet::parse(&request).unwrap();
+    assert_eq!(
+        (eth.destination(), eth.ethertype()),
+        (MacAddress::BROADCAST, ETHERTYPE_ARP)
+    );
+    let arp = ArpPacket::parse(eth.payload()).unwrap();
+    assert_eq!(
+        (arp.operation(), arp.sender_ip(), arp.target_ip()),
+        (arp::OP_REQUEST, config.ip, other)
+    );
+
+    // 收到回复后构造IPv4帧
+    let mut reply = [0u8; 60];
+    let offset = write_ethernet_header(&mut reply, config.mac, HOST_MAC, ETHERTYPE_ARP);
+    arp::write(
+        &mut reply[offset..],
+        arp::OP_REPLY,
+        (HOST_MAC, other),
+        (config.mac, config.ip),
+    );
+    stack.handle(&reply, &mut [0u8; 64]);
+    let Outgoing::Ready(frame) = stack.build_ipv4(other, ipv4::PROTOCOL_UDP, b"x") else {
+        panic!("expected a resolved frame");
+    };
+    let eth = Ethernet::parse(&frame).unwrap();
+    assert_eq!(eth.destination(), HOST_MAC);
+    let packet = Ipv4Packet::parse(eth.payload()).unwrap();
+    assert_eq!(
+        (packet.source(), packet.destination(), packet.payload()),
+        (config.ip, other, &b"x"[..])
+    );
+
+    // 广播不需要解析
+    assert!(matches!(
+        stack.build_ipv4(Ipv4Addr::BROADCAST, ipv4::PROTOCOL_UDP, b"x"),
+        Outgoing::Ready(_)
+    ));
+}
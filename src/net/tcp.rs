@@ -0,0 +1,447 @@
+//! TCP客户端: 主动打开连接, 按顺序接收, 超时重传, 双向FIN关闭
+//!
+//! 每个连接的状态机在`connection`中. 协议栈任务收到段后调用`deliver`, 需要回复的ACK或RST
+//! 立即作为回复帧发出; 数据、SYN和FIN的发送和重传由等待在套接字上的任务完成
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Poll;
+
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::ipv4::{self, pseudo_header_checksum};
+use super::stack::{self, SendError};
+use super::{Ipv4Addr, ParseError, ETHERNET_HEADER_SIZE, MAX_FRAME_SIZE};
+use crate::{rng, time};
+
+pub mod connection;
+
+pub use connection::TcpState;
+use connection::{Connection, Event, Segment};
+
+/// 不带选项的头部长度
+pub const HEADER_LENGTH: usize = 20;
+/// 一个段最多能携带的数据, 作为MSS通告给对方
+pub const MSS: usize = MAX_FRAME_SIZE - ETHERNET_HEADER_SIZE - ipv4::HEADER_LENGTH - HEADER_LENGTH;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpError {
+    /// 当前状态不允许这个事件
+    InvalidTransition(TcpState, Event),
+    /// 对方用RST回复了SYN
+    ConnectionRefused,
+    ConnectionReset,
+    /// 重传次数用完仍没有得到确认
+    TimedOut,
+    /// 连接尚未建立或发送方向已经关闭
+    NotConnected,
+    /// 没有空闲的临时端口
+    NoFreePorts,
+    Send(SendError),
+}
+
+impl From<SendError> for TcpError {
+    fn from(err: SendError) -> TcpError {
+        TcpError::Send(err)
+    }
+}
+
+/// 借用接收缓冲区的TCP段, 解析时已校验长度和校验和
+#[derive(Debug, Clone, Copy)]
+pub struct TcpSegment<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    /// 校验和需要IP头中的地址
+    pub fn parse(
+        bytes: &'a [u8],
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> Result<TcpSegment<'a>, ParseError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(ParseError::Truncated);
+        }
+        let header_length = usize::from(bytes[12] >> 4) * 4;
+        if header_length < HEADER_LENGTH || header_length > bytes.len() {
+            return Err(ParseError::Malformed);
+        }
+        if pseudo_header_checksum(source, destination, ipv4::PROTOCOL_TCP, bytes) != 0 {
+            return Err(ParseError::BadChecksum);
+        }
+        Ok(TcpSegment { bytes })
+    }
+
+    pub fn source_port(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[0], self.bytes[1]])
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[2], self.bytes[3]])
+    }
+
+    fn header_length(&self) -> usize {
+        usize::from(self.bytes[12] >> 4) * 4
+    }
+
+    // 选项中的MSS, 其他选项忽略
+    fn mss(&self) -> Option<u16> {
+        let mut options = &self.bytes[HEADER_LENGTH..self.header_length()];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                OPTION_END => break,
+                OPTION_NOP => options = rest,
+                _ => {
+                    let length = usize::from(*rest.first()?);
+                    if length < 2 || length > options.len() {
+                        return None;
+                    }
+                    if *kind == OPTION_MSS && length == 4 {
+                        return Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[length..];
+                }
+            }
+        }
+        None
+    }
+
+    /// 转换为状态机使用的段
+    pub fn to_segment(&self) -> Segment {
+        let u32_at =
+            |offset: usize| u32::from_be_bytes(self.bytes[offset..offset + 4].try_into().unwrap());
+        Segment {
+            seq: u32_at(4),
+            ack: u32_at(8),
+            flags: self.bytes[13] & 0x3F,
+            window: u16::from_be_bytes([self.bytes[14], self.bytes[15]]),
+            mss: self.mss(),
+            payload: Vec::from(&self.bytes[self.header_length()..]),
+        }
+    }
+}
+
+/// 构造完整的TCP段, 包括校验和. 带有MSS时加上MSS选项
+pub fn build(source: (Ipv4Addr, u16), destination: (Ipv4Addr, u16), segment: &Segment) -> Vec<u8> {
+    let header_length = HEADER_LENGTH + if segment.mss.is_some() { 4 } else { 0 };
+    let mut bytes = Vec::with_capacity(header_length + segment.payload.len());
+    bytes.extend_from_slice(&source.1.to_be_bytes());
+    bytes.extend_from_slice(&destination.1.to_be_bytes());
+    bytes.extend_from_slice(&segment.seq.to_be_bytes());
+    bytes.extend_from_slice(&segment.ack.to_be_bytes());
+    bytes.extend_from_slice(&[(header_length as u8 / 4) << 4, segment.flags]);
+    bytes.extend_from_slice(&segment.window.to_be_bytes());
+    // 校验和与紧急指针
+    bytes.extend_from_slice(&[0; 4]);
+    if let Some(mss) = segment.mss {
+        bytes.extend_from_slice(&[OPTION_MSS, 4]);
+        bytes.extend_from_slice(&mss.to_be_bytes());
+    }
+    bytes.extend_from_slice(&segment.payload);
+    let checksum = pseudo_header_checksum(source.0, destination.0, ipv4::PROTOCOL_TCP, &bytes);
+    bytes[16..18].copy_from_slice(&checksum.to_be_bytes());
+    bytes
+}
+
+// 本地端口、对方地址和对方端口
+type Key = (u16, Ipv4Addr, u16);
+
+struct Shared {
+    connection: Mutex<Connection>,
+    waker: AtomicWaker,
+    // 每处理一个收到的段加1, 等待的任务据此判断状态是否变化
+    events: AtomicUsize,
+}
+
+static CONNECTIONS: Mutex<BTreeMap<Key, Arc<Shared>>> = Mutex::new(BTreeMap::new());
+static NEXT_EPHEMERAL: AtomicUsize = AtomicUsize::new(0);
+
+/// 一个TCP连接. drop时还在TIME-WAIT的连接留在连接表中回复重传的FIN, 其余的直接移除,
+/// 之后对方发来的段得到RST
+pub struct TcpSocket {
+    key: Key,
+    local_ip: Ipv4Addr,
+    shared: Arc<Shared>,
+}
+
+impl TcpSocket {
+    /// 通过三次握手连接到`ip:port`, 初始序号是随机数
+    pub async fn connect(ip: Ipv4Addr, port: u16) -> Result<TcpSocket, TcpError> {
+        let local_ip = super::config().ok_or(SendError::NotConfigured)?.ip;
+        let shared = Arc::new(Shared {
+            connection: Mutex::new(Connection::connect(rng::u64() as u32, MSS)),
+            waker: AtomicWaker::new(),
+            events: AtomicUsize::new(0),
+        });
+        let key = {
+            let mut connections = CONNECTIONS.lock();
+            prune(&mut connections);
+            let key = (ephemeral_port(&connections)?, ip, port);
+            connections.insert(key, shared.clone());
+            key
+        };
+        let socket = TcpSocket {
+            key,
+            local_ip,
+            shared,
+        };
+        socket
+            .drive(|connection| match connection.state() {
+                TcpState::SynSent => None,
+                TcpState::Closed => Some(Err(connection
+                    .error()
+                    .unwrap_or(TcpError::ConnectionRefused))),
+                _ => Some(Ok(())),
+            })
+            .await?;
+        Ok(socket)
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.key.0
+    }
+
+    pub fn remote(&self) -> (Ipv4Addr, u16) {
+        (self.key.1, self.key.2)
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.shared.connection.lock().state()
+    }
+
+    /// 发送`buf`中的全部数据, 等到对方确认后返回
+    pub async fn send(&self, buf: &[u8]) -> Result<usize, TcpError> {
+        let mut written = 0;
+        self.drive(|connection| {
+            match connection.send(&buf[written..]) {
+                Ok(len) => written += len,
+                Err(err) => return Some(Err(err)),
+            }
+            (written == buf.len() && connection.unacked() == 0).then_some(Ok(written))
+        })
+        .await
+    }
+
+    /// 等待收到的数据, 对方关闭连接后返回0
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        self.drive(|connection| connection.recv(buf)).await
+    }
+
+    /// 发送FIN, 等到对方确认后返回. 对方还没有关闭时连接进入FIN-WAIT-2, 仍可以接收
+    pub async fn close(&self) -> Result<(), TcpError> {
+        self.shared.connection.lock().close()?;
+        self.drive(|connection| match connection.state() {
+            TcpState::FinWait2 | TcpState::TimeWait => Some(Ok(())),
+            TcpState::Closed => Some(connection.error().map_or(Ok(()), Err)),
+            _ => None,
+        })
+        .await
+    }
+
+    // 反复发出状态机要发送的段, 等待收到的段或定时器, 直到`ready`给出结果
+    async fn drive<T>(
+        &self,
+        mut ready: impl FnMut(&mut Connection) -> Option<Result<T, TcpError>>,
+    ) -> Result<T, TcpError> {
+        loop {
+            let events = self.shared.events.load(Ordering::Acquire);
+            let now = time::uptime().ms;
+            let mut outgoing = Vec::new();
+            let (result, deadline) = {
+                let mut connection = self.shared.connection.lock();
+                let result = ready(&mut connection);
+                while let Some(segment) = connection.poll_transmit(now) {
+                    outgoing.push(segment);
+                }
+                (result, connection.next_deadline())
+            };
+            for segment in &outgoing {
+                let bytes = build(
+                    (self.local_ip, self.key.0),
+                    (self.key.1, self.key.2),
+                    segment,
+                );
+                stack::send_ipv4(self.key.1, ipv4::PROTOCOL_TCP, &bytes).await?;
+            }
+            if let Some(result) = result {
+                return result;
+            }
+            // 注册waker后再检查一次, 防止错过注册前处理的段
+            let changed = poll_fn(|cx| {
+                if self.shared.events.load(Ordering::Acquire) != events {
+                    return Poll::Ready(());
+                }
+                self.shared.waker.register(cx.waker());
+                if self.shared.events.load(Ordering::Acquire) != events {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            });
+            match deadline {
+                Some(at) => {
+                    let _ = time::timeout(time::ms_to_ticks(at.saturating_sub(now)), changed).await;
+                }
+                None => changed.await,
+            }
+        }
+    }
+}
+
+impl Drop for TcpSocket {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock();
+        if self.shared.connection.lock().state() != TcpState::TimeWait {
+            connections.remove(&self.key);
+        }
+    }
+}
+
+// 移除已经关闭的连接, 包括TIME-WAIT已经结束的
+fn prune(connections: &mut BTreeMap<Key, Arc<Shared>>) {
+    let now = time::uptime().ms;
+    connections.retain(|_, shared| {
+        let mut connection = shared.connection.lock();
+        if connection.state() == TcpState::TimeWait {
+            connection.poll_transmit(now);
+        }
+        connection.state() != TcpState::Closed
+    });
+}
+
+fn ephemeral_port(connections: &BTreeMap<Key, Arc<Shared>>) -> Result<u16, TcpError> {
+    let count = EPHEMERAL_PORTS.len();
+    let start = NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed);
+    (0..count)
+        .map(|i| EPHEMERAL_PORTS.start() + ((start + i) % count) as u16)
+        .find(|port| !connections.keys().any(|key| key.0 == *port))
+        .ok_or(TcpError::NoFreePorts)
+}
+
+/// 把收到的段交给对应的连接, 返回需要立即回复的段. 没有对应的连接时回复RST
+pub(super) fn deliver(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    packet: &TcpSegment,
+) -> Option<Vec<u8>> {
+    let key = (packet.destination_port(), source, packet.source_port());
+    let segment = packet.to_segment();
+    let shared = CONNECTIONS.lock().get(&key).cloned();
+    let reply = match shared {
+        Some(shared) => {
+            let now = time::uptime().ms;
+            let reply = {
+                let mut connection = shared.connection.lock();
+                connection.on_segment(&segment, now);
+                connection.poll_transmit(now)
+            };
+            shared.events.fetch_add(1, Ordering::Release);
+            shared.waker.wake();
+            reply
+        }
+        None => connection::reset_for(&segment),
+    }?;
+    Some(build((destination, key.0), (source, key.2), &reply))
+}
+
+#[cfg(test)]
+const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+#[cfg(test)]
+const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+
+#[test_case]
+fn test_build_and_parse() {
+    let syn = Segment {
+        seq: 1000,
+        flags: connection::FLAG_SYN,
+        window: 4096,
+        mss: Some(MSS as u16),
+        ..Segment::default()
+    };
+    let bytes = build((GUEST, 50000), (HOST, 80), &syn);
+    assert_eq!(bytes.len(), HEADER_LENGTH + 4);
+    let packet = TcpSegment::parse(&bytes, GUEST, HOST).unwrap();
+    assert_eq!(
+        (packet.source_port(), packet.destination_port()),
+        (50000, 80)
+    );
+    assert_eq!(packet.to_segment(), syn);
+
+    let data = Segment {
+        seq: 7,
+        ack: 9,
+        flags: connection::FLAG_ACK | connection::FLAG_PSH,
+        window: 512,
+        mss: None,
+        payload: Vec::from(&b"GET / HTTP/1.0\r\n"[..]),
+    };
+    let bytes = build((HOST, 80), (GUEST, 50000), &data);
+    assert_eq!(
+        TcpSegment::parse(&bytes, HOST, GUEST).unwrap().to_segment(),
+        data
+    );
+    assert_eq!(
+        TcpSegment::parse(&bytes, HOST, Ipv4Addr::new(10, 0, 2, 16)).unwrap_err(),
+        ParseError::BadChecksum
+    );
+    assert_eq!(
+        TcpSegment::parse(&bytes[..19], HOST, GUEST).unwrap_err(),
+        ParseError::Truncated
+    );
+    // 头部长度超出段的长度
+    let mut bad_offset = bytes.clone();
+    bad_offset[12] = 0xF0;
+    assert_eq!(
+        TcpSegment::parse(&bad_offset, HOST, GUEST).unwrap_err(),
+        ParseError::Malformed
+    );
+}
+
+#[test_case]
+fn test_mss_option() {
+    // NOP填充后的MSS选项, 之后是窗口扩大选项
+    let mut bytes = build((HOST, 80), (GUEST, 50000), &Segment::default());
+    bytes[12] = 8 << 4;
+    bytes.splice(
+        HEADER_LENGTH..HEADER_LENGTH,
+        [
+            OPTION_NOP, OPTION_MSS, 4, 0x05, 0xB4, 3, 3, 7, OPTION_END, 0, 0, 0,
+        ],
+    );
+    bytes[16..18].fill(0);
+    let checksum = pseudo_header_checksum(HOST, GUEST, ipv4::PROTOCOL_TCP, &bytes);
+    bytes[16..18].copy_from_slice(&checksum.to_be_bytes());
+    let segment = TcpSegment::parse(&bytes, HOST, GUEST).unwrap().to_segment();
+    assert_eq!((segment.mss, segment.payload.len()), (Some(1460), 0));
+}
+
+#[test_case]
+fn test_reset_unknown_connection() {
+    let syn = Segment {
+        seq: 42,
+        flags: connection::FLAG_SYN,
+        ..Segment::default()
+    };
+    let bytes = build((HOST, 5555), (GUEST, 4321), &syn);
+    let packet = TcpSegment::parse(&bytes, HOST, GUEST).unwrap();
+    let reply = deliver(HOST, GUEST, &packet).unwrap();
+    let reset = TcpSegment::parse(&reply, GUEST, HOST).unwrap();
+    assert_eq!(
+        (reset.source_port(), reset.destination_port()),
+        (4321, 5555)
+    );
+    let reset = reset.to_segment();
+    assert_eq!(
+        (reset.flags, reset.ack),
+        (connection::FLAG_RST | connection::FLAG_ACK, 43)
+    );
+}
@@ -0,0 +1,774 @@
+//! 一个TCP连接的状态机, 不做I/O
+//!
+//! 收到的段交给`on_segment`, 要发送的段由`poll_transmit`逐个取出, 时间以毫秒为单位由调用者传入,
+//! 单元测试可以按脚本驱动. 只实现主动打开: 不支持LISTEN和同时打开
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::TcpError;
+
+pub const FLAG_FIN: u8 = 0x01;
+pub const FLAG_SYN: u8 = 0x02;
+pub const FLAG_RST: u8 = 0x04;
+pub const FLAG_PSH: u8 = 0x08;
+pub const FLAG_ACK: u8 = 0x10;
+
+/// 固定大小的接收窗口
+pub const RECV_WINDOW: usize = 4096;
+// 发送缓冲区, 包括已发送未确认的数据
+const SEND_BUFFER: usize = 8192;
+// 对方没有给出MSS选项时的默认值
+const DEFAULT_MSS: usize = 536;
+/// 依次使用的重传超时, 用完后放弃连接
+pub const RTO_MS: [u64; 5] = [300, 600, 1200, 2400, 4800];
+/// TIME-WAIT的时长, 比2MSL短得多
+pub const TIME_WAIT_MS: u64 = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    Closed,
+    SynSent,
+    Established,
+    /// 已发送FIN, 等待确认
+    FinWait1,
+    /// 己方的FIN已被确认, 等待对方的FIN
+    FinWait2,
+    /// 双方同时关闭, 收到了对方的FIN, 己方的FIN还没有被确认
+    Closing,
+    TimeWait,
+    /// 对方已关闭, 仍可以发送
+    CloseWait,
+    LastAck,
+}
+
+/// 引起状态变化的事件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// 发送SYN
+    Open,
+    /// 收到确认了SYN的SYN-ACK
+    SynAck,
+    /// 应用关闭发送方向
+    Close,
+    /// 收到对方的FIN
+    Fin,
+    /// 己方的FIN被确认
+    FinAcked,
+    /// 收到RST
+    Reset,
+    /// 重传次数用完
+    TimedOut,
+    TimeWaitExpired,
+}
+
+/// 状态转移表, 表中没有的组合返回`InvalidTransition`
+pub fn transition(state: TcpState, event: Event) -> Result<TcpState, TcpError> {
+    use TcpState::*;
+    let next = match (state, event) {
+        (Closed, Event::Open) => SynSent,
+        (SynSent, Event::SynAck) => Established,
+        (SynSent, Event::Close) => Closed,
+        (Established, Event::Close) => FinWait1,
+        (CloseWait, Event::Close) => LastAck,
+        (Established, Event::Fin) => CloseWait,
+        (FinWait1, Event::Fin) => Closing,
+        (FinWait2, Event::Fin) => TimeWait,
+        (FinWait1, Event::FinAcked) => FinWait2,
+        (Closing, Event::FinAcked) => TimeWait,
+        (LastAck, Event::FinAcked) => Closed,
+        (TimeWait, Event::TimeWaitExpired) => Closed,
+        (TimeWait | Closed, Event::TimedOut) => {
+            return Err(TcpError::InvalidTransition(state, event))
+        }
+        (Closed, Event::Reset) => return Err(TcpError::InvalidTransition(state, event)),
+        (_, Event::Reset | Event::TimedOut) => Closed,
+        _ => return Err(TcpError::InvalidTransition(state, event)),
+    };
+    Ok(next)
+}
+
+/// 收到或要发送的一个段, 不含端口和校验和
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Segment {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// SYN中的MSS选项
+    pub mss: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// 占用的序号数, SYN和FIN各占一个
+    pub fn len(&self) -> u32 {
+        self.payload.len() as u32 + u32::from(self.has(FLAG_SYN)) + u32::from(self.has(FLAG_FIN))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 回复发往不存在的连接的段, 收到的是RST时不回复
+pub fn reset_for(segment: &Segment) -> Option<Segment> {
+    if segment.has(FLAG_RST) {
+        return None;
+    }
+    Some(if segment.has(FLAG_ACK) {
+        Segment {
+            seq: segment.ack,
+            flags: FLAG_RST,
+            ..Segment::default()
+        }
+    } else {
+        Segment {
+            ack: segment.seq.wrapping_add(segment.len()),
+            flags: FLAG_RST | FLAG_ACK,
+            ..Segment::default()
+        }
+    })
+}
+
+// 按模2^32比较序号
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub struct Connection {
+    state: TcpState,
+    iss: u32,
+    // 最早的未确认序号和下一个要发送的序号
+    snd_una: u32,
+    snd_nxt: u32,
+    // 对方通告的窗口
+    snd_wnd: usize,
+    mss: usize,
+    rcv_nxt: u32,
+    // 从snd_una开始的数据, 包括已发送未确认的和还没有发送的
+    send_buf: VecDeque<u8>,
+    recv_buf: VecDeque<u8>,
+    // 应用已经关闭发送方向, 数据发完后发送FIN
+    fin_queued: bool,
+    // 收到了对方的FIN
+    peer_fin: bool,
+    ack_pending: bool,
+    // 当前是第几次重传, 决定使用的超时
+    retries: usize,
+    retransmit_at: Option<u64>,
+    time_wait_until: Option<u64>,
+    error: Option<TcpError>,
+}
+
+impl Connection {
+    /// 以初始序号`iss`主动打开连接, `mss`是己方能接收的最大段
+    pub fn connect(iss: u32, mss: usize) -> Connection {
+        Connection {
+            state: transition(TcpState::Closed, Event::Open).unwrap(),
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            mss,
+            rcv_nxt: 0,
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            fin_queued: false,
+            peer_fin: false,
+            ack_pending: false,
+            retries: 0,
+            retransmit_at: None,
+            time_wait_until: None,
+            error: None,
+        }
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.state
+    }
+
+    /// 连接被重置或超时的原因
+    pub fn error(&self) -> Option<TcpError> {
+        self.error
+    }
+
+    /// 发送缓冲区中还没有被确认的字节数
+    pub fn unacked(&self) -> usize {
+        self.send_buf.len()
+    }
+
+    /// 下一个定时器到期的时间
+    pub fn next_deadline(&self) -> Option<u64> {
+        match (self.retransmit_at, self.time_wait_until) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn advance(&mut self, event: Event) -> Result<(), TcpError> {
+        self.state = transition(self.state, event)?;
+        if matches!(self.state, TcpState::Closed | TcpState::TimeWait) {
+            self.retransmit_at = None;
+        }
+        Ok(())
+    }
+
+    fn fail(&mut self, err: TcpError, event: Event) {
+        self.error = Some(err);
+        // Reset和TimedOut在Closed以外的状态都有定义
+        let _ = self.advance(event);
+        self.send_buf.clear();
+    }
+
+    fn window(&self) -> u16 {
+        (RECV_WINDOW - self.recv_buf.len()) as u16
+    }
+
+    // 己方还可以发送数据或FIN的状态
+    fn sending(&self) -> bool {
+        use TcpState::*;
+        matches!(
+            self.state,
+            Established | CloseWait | FinWait1 | Closing | LastAck
+        )
+    }
+
+    // 还可以接收数据的状态
+    fn receiving(&self) -> bool {
+        use TcpState::*;
+        matches!(self.state, Established | FinWait1 | FinWait2)
+    }
+
+    fn fin_seq(&self) -> u32 {
+        self.snd_una.wrapping_add(self.send_buf.len() as u32)
+    }
+
+    fn fin_sent(&self) -> bool {
+        self.fin_queued && self.snd_nxt == self.fin_seq().wrapping_add(1)
+    }
+
+    // 发送占用序号的段时启动重传定时器
+    fn arm(&mut self, now: u64) {
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + RTO_MS[self.retries]);
+        }
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        if self.state == TcpState::TimeWait {
+            self.time_wait_until = Some(now + TIME_WAIT_MS);
+        }
+    }
+
+    /// 把数据放入发送缓冲区, 返回放入的字节数
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, TcpError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if !matches!(self.state, TcpState::Established | TcpState::CloseWait) || self.fin_queued {
+            return Err(TcpError::NotConnected);
+        }
+        let len = data.len().min(SEND_BUFFER - self.send_buf.len());
+        self.send_buf.extend(&data[..len]);
+        Ok(len)
+    }
+
+    /// 读取收到的数据. 没有数据时返回None, 对方已关闭时返回0
+    pub fn recv(&mut self, buf: &mut [u8]) -> Option<Result<usize, TcpError>> {
+        if self.recv_buf.is_empty() {
+            return match (self.error, self.state) {
+                (Some(err), _) => Some(Err(err)),
+                (None, TcpState::SynSent) => None,
+                _ if self.peer_fin || self.state == TcpState::Closed => Some(Ok(0)),
+                _ => None,
+            };
+        }
+        let old_window = usize::from(self.window());
+        let len = buf.len().min(self.recv_buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.recv_buf.drain(..len)) {
+            *dst = src;
+        }
+        // 窗口从不足一个段变为可用时通知对方
+        if old_window < self.mss && usize::from(self.window()) >= self.mss {
+            self.ack_pending = true;
+        }
+        Some(Ok(len))
+    }
+
+    /// 关闭发送方向, 缓冲区中的数据发完后发送FIN
+    pub fn close(&mut self) -> Result<(), TcpError> {
+        self.advance(Event::Close)?;
+        self.fin_queued = self.state != TcpState::Closed;
+        Ok(())
+    }
+
+    /// 处理收到的段
+    pub fn on_segment(&mut self, segment: &Segment, now: u64) {
+        match self.state {
+            TcpState::Closed => {}
+            TcpState::SynSent => self.on_syn_sent(segment),
+            _ => self.on_synchronized(segment, now),
+        }
+    }
+
+    fn on_syn_sent(&mut self, segment: &Segment) {
+        let expected = self.iss.wrapping_add(1);
+        let ack_ok = segment.has(FLAG_ACK) && segment.ack == expected;
+        if segment.has(FLAG_RST) {
+            if ack_ok {
+                self.fail(TcpError::ConnectionRefused, Event::Reset);
+            }
+            return;
+        }
+        // 不支持同时打开, 没有确认SYN的段都丢弃
+        if !segment.has(FLAG_SYN) || !ack_ok {
+            return;
+        }
+        self.rcv_nxt = segment.seq.wrapping_add(1);
+        self.snd_una = expected;
+        self.snd_wnd = usize::from(segment.window);
+        self.mss = self.mss.min(segment.mss.map_or(DEFAULT_MSS, usize::from));
+        self.retries = 0;
+        self.retransmit_at = None;
+        self.ack_pending = true;
+        self.state = transition(self.state, Event::SynAck).unwrap();
+    }
+
+    fn on_synchronized(&mut self, segment: &Segment, now: u64) {
+        if segment.has(FLAG_RST) {
+            // 只接受序号正好是期望值的RST, 防止伪造
+            if segment.seq == self.rcv_nxt {
+                if self.state == TcpState::TimeWait {
+                    let _ = self.advance(Event::Reset);
+                } else {
+                    self.fail(TcpError::ConnectionReset, Event::Reset);
+                }
+            }
+            return;
+        }
+        // 重传的SYN-ACK说明己方的ACK丢失了
+        if segment.has(FLAG_SYN) {
+            self.ack_pending = true;
+            return;
+        }
+        // 只接受按顺序到达的段, 乱序的丢弃并重复确认, 迫使对方重传
+        if before(self.rcv_nxt, segment.seq) {
+            self.ack_pending = true;
+            return;
+        }
+        if segment.has(FLAG_ACK) {
+            self.on_ack(segment.ack, segment.window, now);
+        }
+        // 去掉已经收到过的部分
+        let old = self.rcv_nxt.wrapping_sub(segment.seq) as usize;
+        let data = segment.payload.get(old..).unwrap_or(&[]);
+        let fin = segment.has(FLAG_FIN) && old <= segment.payload.len();
+        if old > 0 && data.is_empty() && !fin {
+            self.ack_pending = true;
+            if self.state == TcpState::TimeWait {
+                self.enter_time_wait(now);
+            }
+            return;
+        }
+        if !self.receiving() {
+            return;
+        }
+        if !data.is_empty() {
+            let len = data.len().min(RECV_WINDOW - self.recv_buf.len());
+            self.recv_buf.extend(&data[..len]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+            self.ack_pending = true;
+            // 超出窗口的部分和之后的FIN由对方重传
+            if len < data.len() {
+                return;
+            }
+        }
+        if fin {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.peer_fin = true;
+            self.ack_pending = true;
+            if self.advance(Event::Fin).is_ok() {
+                self.enter_time_wait(now);
+            }
+        }
+    }
+
+    fn on_ack(&mut self, ack: u32, window: u16, now: u64) {
+        // 确认了还没有发送的序号
+        if before(self.snd_nxt, ack) {
+            self.ack_pending = true;
+            return;
+        }
+        if before(self.snd_una, ack) {
+            let fin_acked = self.fin_sent() && ack == self.snd_nxt;
+            let acked = ack.wrapping_sub(self.snd_una) as usize;
+            let data = acked.min(self.send_buf.len());
+            self.send_buf.drain(..data);
+            self.snd_una = ack;
+            self.retries = 0;
+            self.retransmit_at = (self.snd_una != self.snd_nxt).then_some(now + RTO_MS[0]);
+            if fin_acked && self.advance(Event::FinAcked).is_ok() {
+                self.enter_time_wait(now);
+            }
+        }
+        if ack == self.snd_una {
+            self.snd_wnd = usize::from(window);
+        }
+    }
+
+    // 处理到期的定时器: 重传超时时回到最早的未确认序号重新发送
+    fn expire(&mut self, now: u64) {
+        if self.retransmit_at.is_some_and(|at| now >= at) {
+            self.retransmit_at = None;
+            if self.retries + 1 == RTO_MS.len() {
+                self.fail(TcpError::TimedOut, Event::TimedOut);
+                return;
+            }
+            self.retries += 1;
+            self.snd_nxt = self.snd_una;
+        }
+        if self.time_wait_until.is_some_and(|at| now >= at) {
+            self.time_wait_until = None;
+            let _ = self.advance(Event::TimeWaitExpired);
+        }
+    }
+
+    /// 取出下一个要发送的段, 没有时返回None. 先处理到期的定时器
+    pub fn poll_transmit(&mut self, now: u64) -> Option<Segment> {
+        self.expire(now);
+        let mut segment = match self.state {
+            TcpState::Closed => return None,
+            TcpState::SynSent if self.snd_nxt == self.iss => {
+                self.snd_nxt = self.iss.wrapping_add(1);
+                self.arm(now);
+                return Some(Segment {
+                    seq: self.iss,
+                    flags: FLAG_SYN,
+                    window: self.window(),
+                    mss: Some(self.mss as u16),
+                    ..Segment::default()
+                });
+            }
+            TcpState::SynSent => return None,
+            _ if self.sending() => self.next_data(),
+            _ => None,
+        };
+        if segment.is_some() {
+            self.arm(now);
+        } else if self.ack_pending {
+            segment = Some(Segment {
+                seq: self.snd_nxt,
+                ..Segment::default()
+            });
+        }
+        let mut segment = segment?;
+        segment.ack = self.rcv_nxt;
+        segment.flags |= FLAG_ACK;
+        segment.window = self.window();
+        self.ack_pending = false;
+        Some(segment)
+    }
+
+    // 窗口允许时发送新的数据, 数据发完后发送FIN
+    fn next_data(&mut self) -> Option<Segment> {
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        let sent = in_flight.min(self.send_buf.len());
+        let unsent = self.send_buf.len() - sent;
+        if unsent > 0 {
+            // 对方窗口为0时也发送一个字节探测
+            let window = if in_flight == 0 {
+                self.snd_wnd.max(1)
+            } else {
+                self.snd_wnd.saturating_sub(in_flight)
+            };
+            let len = unsent.min(window).min(self.mss);
+            if len == 0 {
+                return None;
+            }
+            let seq = self.snd_nxt;
+            self.snd_nxt = seq.wrapping_add(len as u32);
+            return Some(Segment {
+                seq,
+                flags: FLAG_PSH,
+                payload: self.send_buf.range(sent..sent + len).copied().collect(),
+                ..Segment::default()
+            });
+        }
+        if self.fin_queued && !self.fin_sent() {
+            let seq = self.fin_seq();
+            self.snd_nxt = seq.wrapping_add(1);
+            return Some(Segment {
+                seq,
+                flags: FLAG_FIN,
+                ..Segment::default()
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+const ISS: u32 = 0xFFFF_F000;
+#[cfg(test)]
+const PEER_ISS: u32 = 5000;
+
+// 对方发来的段
+#[cfg(test)]
+fn peer(seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Segment {
+    Segment {
+        seq,
+        ack,
+        flags,
+        window: 2048,
+        mss: None,
+        payload: Vec::from(payload),
+    }
+}
+
+// 完成三次握手的连接, 对方的下一个序号为PEER_ISS + 1
+#[cfg(test)]
+fn established() -> Connection {
+    let mut connection = Connection::connect(ISS, 1460);
+    let syn = connection.poll_transmit(0).unwrap();
+    assert_eq!((syn.seq, syn.flags, syn.mss), (ISS, FLAG_SYN, Some(1460)));
+    assert_eq!(connection.poll_transmit(0), None);
+    let mut syn_ack = peer(PEER_ISS, ISS + 1, FLAG_SYN | FLAG_ACK, b"");
+    syn_ack.mss = Some(1000);
+    connection.on_segment(&syn_ack, 10);
+    assert_eq!(connection.state(), TcpState::Established);
+    let ack = connection.poll_transmit(10).unwrap();
+    assert_eq!(
+        (ack.seq, ack.ack, ack.flags),
+        (ISS + 1, PEER_ISS + 1, FLAG_ACK)
+    );
+    assert_eq!(connection.next_deadline(), None);
+    connection
+}
+
+#[test_case]
+fn test_transitions() {
+    use TcpState::*;
+    assert_eq!(transition(Closed, Event::Open), Ok(SynSent));
+    assert_eq!(transition(Established, Event::Close), Ok(FinWait1));
+    assert_eq!(transition(FinWait1, Event::Fin), Ok(Closing));
+    assert_eq!(transition(Closing, Event::FinAcked), Ok(TimeWait));
+    assert_eq!(transition(CloseWait, Event::Reset), Ok(Closed));
+    for (state, event) in [
+        (Closed, Event::Close),
+        (SynSent, Event::Fin),
+        (FinWait2, Event::Close),
+        (TimeWait, Event::TimedOut),
+        (Closed, Event::Reset),
+        (Established, Event::SynAck),
+    ] {
+        assert_eq!(
+            transition(state, event),
+            Err(TcpError::InvalidTransition(state, event))
+        );
+    }
+    let mut connection = established();
+    connection.close().unwrap();
+    assert_eq!(
+        connection.close(),
+        Err(TcpError::InvalidTransition(FinWait1, Event::Close))
+    );
+}
+
+#[test_case]
+fn test_data_in_order_only() {
+    let mut connection = established();
+    let next = PEER_ISS + 1;
+    connection.on_segment(&peer(next, ISS + 1, FLAG_ACK, b"hello "), 20);
+    // 乱序到达的段被丢弃, 重复确认期望的序号
+    connection.on_segment(&peer(next + 12, ISS + 1, FLAG_ACK, b"!"), 21);
+    let ack = connection.poll_transmit(21).unwrap();
+    assert_eq!((ack.ack, ack.window), (next + 6, (RECV_WINDOW - 6) as u16));
+    // 部分重复的段只接受新的部分
+    connection.on_segment(&peer(next + 3, ISS + 1, FLAG_ACK, b"lo world"), 22);
+    let mut buf = [0u8; 32];
+    assert_eq!(connection.recv(&mut buf), Some(Ok(11)));
+    assert_eq!(&buf[..11], b"hello world");
+    assert_eq!(connection.recv(&mut buf), None);
+
+    // 发送的数据按对方的MSS分段
+    assert_eq!(connection.send(&[7; 1500]), Ok(1500));
+    let first = connection.poll_transmit(30).unwrap();
+    let second = connection.poll_transmit(30).unwrap();
+    assert_eq!((first.seq, first.payload.len()), (ISS + 1, 1000));
+    assert_eq!((second.seq, second.payload.len()), (ISS + 1001, 500));
+    assert_eq!(first.ack, next + 11);
+    assert_eq!(connection.poll_transmit(30), None);
+    connection.on_segment(&peer(next + 11, ISS + 1501, FLAG_ACK, b""), 40);
+    assert_eq!(connection.unacked(), 0);
+    assert_eq!(connection.next_deadline(), None);
+}
+
+#[test_case]
+fn test_retransmit_after_dropped_ack() {
+    let mut connection = established();
+    connection.send(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let segment = connection.poll_transmit(100).unwrap();
+    assert_eq!(connection.next_deadline(), Some(100 + RTO_MS[0]));
+    assert_eq!(connection.poll_transmit(100 + RTO_MS[0] - 1), None);
+    // 确认丢失, 超时后重传同样的数据, 下一次超时加倍
+    let again = connection.poll_transmit(100 + RTO_MS[0]).unwrap();
+    assert_eq!((again.seq, again.payload), (segment.seq, segment.payload));
+    let now = 100 + RTO_MS[0];
+    assert_eq!(connection.next_deadline(), Some(now + RTO_MS[1]));
+    connection.on_segment(&peer(PEER_ISS + 1, ISS + 19, FLAG_ACK, b""), now + 5);
+    assert_eq!(
+        (connection.unacked(), connection.next_deadline()),
+        (0, None)
+    );
+
+    // 所有超时用完后放弃连接
+    connection.send(b"x").unwrap();
+    let mut now = 1000;
+    let mut sent = 0;
+    while connection.state() == TcpState::Established {
+        if connection.poll_transmit(now).is_some() {
+            sent += 1;
+        }
+        now = connection.next_deadline().unwrap_or(now);
+    }
+    assert_eq!(sent, RTO_MS.len());
+    assert_eq!(connection.error(), Some(TcpError::TimedOut));
+    assert_eq!(connection.send(b"y"), Err(TcpError::TimedOut));
+    assert_eq!(connection.recv(&mut [0; 4]), Some(Err(TcpError::TimedOut)));
+}
+
+#[test_case]
+fn test_lost_syn_ack() {
+    let mut connection = Connection::connect(ISS, 1460);
+    let syn = connection.poll_transmit(0).unwrap();
+    // 超时前重传SYN
+    assert_eq!(connection.poll_transmit(RTO_MS[0]), Some(syn));
+    // 对方重传SYN-ACK说明ACK丢失, 再确认一次
+    let syn_ack = peer(PEER_ISS, ISS + 1, FLAG_SYN | FLAG_ACK, b"");
+    connection.on_segment(&syn_ack, 400);
+    connection.poll_transmit(400).unwrap();
+    connection.on_segment(&syn_ack, 500);
+    let ack = connection.poll_transmit(500).unwrap();
+    assert_eq!((ack.flags, ack.ack), (FLAG_ACK, PEER_ISS + 1));
+    assert_eq!(connection.state(), TcpState::Established);
+}
+
+#[test_case]
+fn test_simultaneous_close() {
+    let mut connection = established();
+    let next = PEER_ISS + 1;
+    connection.close().unwrap();
+    let fin = connection.poll_transmit(50).unwrap();
+    assert_eq!((fin.seq, fin.flags), (ISS + 1, FLAG_FIN | FLAG_ACK));
+    assert_eq!(connection.state(), TcpState::FinWait1);
+    assert_eq!(connection.send(b"late"), Err(TcpError::NotConnected));
+
+    // 对方的FIN没有确认己方的FIN
+    connection.on_segment(&peer(next, ISS + 1, FLAG_FIN | FLAG_ACK, b""), 60);
+    assert_eq!(connection.state(), TcpState::Closing);
+    let ack = connection.poll_transmit(60).unwrap();
+    assert_eq!((ack.seq, ack.ack, ack.flags), (ISS + 2, next + 1, FLAG_ACK));
+    assert_eq!(connection.recv(&mut [0; 4]), Some(Ok(0)));
+
+    connection.on_segment(&peer(next + 1, ISS + 2, FLAG_ACK, b""), 70);
+    assert_eq!(connection.state(), TcpState::TimeWait);
+    // TIME-WAIT中再次确认重传的FIN
+    connection.on_segment(&peer(next, ISS + 2, FLAG_FIN | FLAG_ACK, b""), 80);
+    assert_eq!(
+        connection.poll_transmit(80).map(|ack| ack.ack),
+        Some(next + 1)
+    );
+    assert_eq!(connection.next_deadline(), Some(80 + TIME_WAIT_MS));
+    assert_eq!(connection.poll_transmit(80 + TIME_WAIT_MS), None);
+    assert_eq!(connection.state(), TcpState::Closed);
+    assert_eq!(connection.error(), None);
+}
+
+#[test_case]
+fn test_passive_close() {
+    let mut connection = established();
+    let next = PEER_ISS + 1;
+    // 数据和FIN在同一个段中
+    connection.on_segment(&peer(next, ISS + 1, FLAG_FIN | FLAG_ACK, b"bye"), 20);
+    assert_eq!(connection.state(), TcpState::CloseWait);
+    assert_eq!(
+        connection.poll_transmit(20).map(|ack| ack.ack),
+        Some(next + 4)
+    );
+    let mut buf = [0u8; 8];
+    assert_eq!(connection.recv(&mut buf), Some(Ok(3)));
+    assert_eq!(connection.recv(&mut buf), Some(Ok(0)));
+
+    // 对方关闭后仍可以发送
+    connection.send(b"last").unwrap();
+    connection.close().unwrap();
+    assert_eq!(connection.state(), TcpState::LastAck);
+    let data = connection.poll_transmit(30).unwrap();
+    let fin = connection.poll_transmit(30).unwrap();
+    assert_eq!((data.payload.as_slice(), fin.seq), (&b"last"[..], ISS + 5));
+    connection.on_segment(&peer(next + 4, ISS + 6, FLAG_ACK, b""), 40);
+    assert_eq!(connection.state(), TcpState::Closed);
+    assert_eq!(connection.poll_transmit(40), None);
+}
+
+#[test_case]
+fn test_reset() {
+    // SYN-SENT中确认了SYN的RST表示连接被拒绝
+    let mut connection = Connection::connect(ISS, 1460);
+    connection.poll_transmit(0);
+    connection.on_segment(&peer(0, ISS + 7, FLAG_RST | FLAG_ACK, b""), 1);
+    assert_eq!(connection.state(), TcpState::SynSent);
+    connection.on_segment(&peer(0, ISS + 1, FLAG_RST | FLAG_ACK, b""), 1);
+    assert_eq!(connection.state(), TcpState::Closed);
+    assert_eq!(connection.error(), Some(TcpError::ConnectionRefused));
+
+    // 序号不对的RST被忽略
+    let mut connection = established();
+    connection.send(b"data").unwrap();
+    connection.poll_transmit(20);
+    connection.on_segment(&peer(PEER_ISS + 100, 0, FLAG_RST, b""), 30);
+    assert_eq!(connection.state(), TcpState::Established);
+    connection.on_segment(&peer(PEER_ISS + 1, 0, FLAG_RST, b""), 30);
+    assert_eq!(connection.state(), TcpState::Closed);
+    assert_eq!(
+        connection.recv(&mut [0; 4]),
+        Some(Err(TcpError::ConnectionReset))
+    );
+    assert_eq!(connection.poll_transmit(10_000), None);
+    assert_eq!(connection.next_deadline(), None);
+
+    // 回复发往不存在的连接的段
+    let syn = peer(9, 0, FLAG_SYN, b"");
+    let reset = reset_for(&syn).unwrap();
+    assert_eq!(
+        (reset.seq, reset.ack, reset.flags),
+        (0, 10, FLAG_RST | FLAG_ACK)
+    );
+    let data = peer(9, 77, FLAG_ACK, b"abc");
+    let reset = reset_for(&data).unwrap();
+    assert_eq!((reset.seq, reset.flags), (77, FLAG_RST));
+    assert_eq!(reset_for(&reset), None);
+}
+
+#[test_case]
+fn test_window_limits_receive() {
+    let mut connection = established();
+    let next = PEER_ISS + 1;
+    let data = [1u8; 1000];
+    for i in 0..5 {
+        connection.on_segment(&peer(next + i * 1000, ISS + 1, FLAG_ACK, &data), 20);
+    }
+    // 窗口满后多出的部分被丢弃
+    let ack = connection.poll_transmit(20).unwrap();
+    assert_eq!((ack.ack, ack.window), (next + RECV_WINDOW as u32, 0));
+    let mut buf = [0u8; RECV_WINDOW];
+    assert_eq!(connection.recv(&mut buf), Some(Ok(RECV_WINDOW)));
+    // 读取后通告新的窗口
+    let update = connection.poll_transmit(30).unwrap();
+    assert_eq!(update.window, RECV_WINDOW as u16);
+}
@@ -0,0 +1,296 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Poll;
+
+use crossbeam_queue::ArrayQueue;
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::ipv4::{self, pseudo_header_checksum};
+use super::stack::{self, SendError};
+use super::{Ipv4Addr, ParseError, ETHERNET_HEADER_SIZE, MAX_FRAME_SIZE};
+
+pub const HEADER_LENGTH: usize = 8;
+/// 一个不分片的数据报最多能携带的数据
+pub const MAX_PAYLOAD: usize =
+    MAX_FRAME_SIZE - ETHERNET_HEADER_SIZE - ipv4::HEADER_LENGTH - HEADER_LENGTH;
+
+// 每个套接字最多缓存的数据报, 超出时丢弃
+const QUEUE_CAPACITY: usize = 16;
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpError {
+    AddressInUse,
+    /// 没有空闲的临时端口
+    NoFreePorts,
+    TooLarge,
+    Send(SendError),
+}
+
+impl From<SendError> for UdpError {
+    fn from(err: SendError) -> UdpError {
+        UdpError::Send(err)
+    }
+}
+
+/// 借用接收缓冲区的UDP数据报, 解析时已校验长度和校验和
+#[derive(Debug, Clone, Copy)]
+pub struct UdpPacket<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> UdpPacket<'a> {
+    /// 校验和需要IP头中的地址, 为0表示发送方没有计算
+    pub fn parse(
+        bytes: &'a [u8],
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> Result<UdpPacket<'a>, ParseError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(ParseError::Truncated);
+        }
+        let length = usize::from(u16::from_be_bytes([bytes[4], bytes[5]]));
+        if length < HEADER_LENGTH {
+            return Err(ParseError::Malformed);
+        }
+        if bytes.len() < length {
+            return Err(ParseError::Truncated);
+        }
+        let bytes = &bytes[..length];
+        let checksum = u16::from_be_bytes([bytes[6], bytes[7]]);
+        if checksum != 0
+            && pseudo_header_checksum(source, destination, ipv4::PROTOCOL_UDP, bytes) != 0
+        {
+            return Err(ParseError::BadChecksum);
+        }
+        Ok(UdpPacket { bytes })
+    }
+
+    pub fn source_port(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[0], self.bytes[1]])
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[2], self.bytes[3]])
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[HEADER_LENGTH..]
+    }
+}
+
+/// 构造完整的UDP数据报, 包括校验和
+pub fn build(source: (Ipv4Addr, u16), destination: (Ipv4Addr, u16), payload: &[u8]) -> Vec<u8> {
+    let length = HEADER_LENGTH + payload.len();
+    let mut datagram = Vec::with_capacity(length);
+    datagram.extend_from_slice(&source.1.to_be_bytes());
+    datagram.extend_from_slice(&destination.1.to_be_bytes());
+    datagram.extend_from_slice(&(length as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let checksum =
+        match pseudo_header_checksum(source.0, destination.0, ipv4::PROTOCOL_UDP, &datagram) {
+            // 计算结果为0时发送全1, 0表示没有校验和
+            0 => 0xFFFF,
+            sum => sum,
+        };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
+
+#[derive(Debug)]
+struct Datagram {
+    source: Ipv4Addr,
+    port: u16,
+    data: Vec<u8>,
+}
+
+struct Socket {
+    queue: ArrayQueue<Datagram>,
+    waker: AtomicWaker,
+    dropped: AtomicUsize,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Arc<Socket>>> = Mutex::new(BTreeMap::new());
+static NEXT_EPHEMERAL: AtomicUsize = AtomicUsize::new(0);
+
+/// 绑定到一个本地端口的UDP套接字, drop时解除绑定
+pub struct UdpSocket {
+    port: u16,
+    socket: Arc<Socket>,
+}
+
+impl UdpSocket {
+    /// 绑定`port`, 为0时分配一个临时端口
+    pub fn bind(port: u16) -> Result<UdpSocket, UdpError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => ephemeral_port(&sockets)?,
+            port if sockets.contains_key(&port) => return Err(UdpError::AddressInUse),
+            port => port,
+        };
+        let socket = Arc::new(Socket {
+            queue: ArrayQueue::new(QUEUE_CAPACITY),
+            waker: AtomicWaker::new(),
+            dropped: AtomicUsize::new(0),
+        });
+        sockets.insert(port, socket.clone());
+        Ok(UdpSocket { port, socket })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// 发送一个数据报, 目的地址需要ARP解析时会等待
+    pub async fn send_to(&self, buf: &[u8], ip: Ipv4Addr, port: u16) -> Result<usize, UdpError> {
+        if buf.len() > MAX_PAYLOAD {
+            return Err(UdpError::TooLarge);
+        }
+        let source = super::config().ok_or(SendError::NotConfigured)?.ip;
+        let datagram = build((source, self.port), (ip, port), buf);
+        stack::send_ipv4(ip, ipv4::PROTOCOL_UDP, &datagram).await?;
+        Ok(buf.len())
+    }
+
+    /// 等待下一个数据报, 返回长度和发送方的地址端口. 超出`buf`的部分被截断
+    pub async fn recv_from(&self, buf: &mut [u8]) -> (usize, Ipv4Addr, u16) {
+        poll_fn(|cx| {
+            if let Some(result) = self.try_recv_from(buf) {
+                return Poll::Ready(result);
+            }
+            // 注册waker后再检查一次, 防止错过注册前到达的数据报
+            self.socket.waker.register(cx.waker());
+            match self.try_recv_from(buf) {
+                Some(result) => Poll::Ready(result),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, Ipv4Addr, u16)> {
+        let datagram = self.socket.queue.pop()?;
+        let length = datagram.data.len().min(buf.len());
+        buf[..length].copy_from_slice(&datagram.data[..length]);
+        Some((length, datagram.source, datagram.port))
+    }
+
+    /// 队列已满而被丢弃的数据报个数
+    pub fn dropped(&self) -> usize {
+        self.socket.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+fn ephemeral_port(sockets: &BTreeMap<u16, Arc<Socket>>) -> Result<u16, UdpError> {
+    let count = EPHEMERAL_PORTS.len();
+    let start = NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed);
+    (0..count)
+        .map(|i| EPHEMERAL_PORTS.start() + ((start + i) % count) as u16)
+        .find(|port| !sockets.contains_key(port))
+        .ok_or(UdpError::NoFreePorts)
+}
+
+/// 把收到的数据报交给绑定的套接字, 端口没有绑定时返回false
+pub(super) fn deliver(source: Ipv4Addr, packet: &UdpPacket) -> bool {
+    let Some(socket) = SOCKETS.lock().get(&packet.destination_port()).cloned() else {
+        return false;
+    };
+    let datagram = Datagram {
+        source,
+        port: packet.source_port(),
+        data: Vec::from(packet.payload()),
+    };
+    if socket.queue.push(datagram).is_err() {
+        socket.dropped.fetch_add(1, Ordering::Relaxed);
+    } else {
+        socket.waker.wake();
+    }
+    true
+}
+
+#[cfg(test)]
+const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+#[cfg(test)]
+const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+
+#[test_case]
+fn test_build_and_parse() {
+    let datagram = build((GUEST, 1234), (HOST, 53), b"hello");
+    assert_eq!(datagram.len(), HEADER_LENGTH + 5);
+    assert_ne!(&datagram[6..8], &[0, 0]);
+
+    let packet = UdpPacket::parse(&datagram, GUEST, HOST).unwrap();
+    assert_eq!(
+        (packet.source_port(), packet.destination_port()),
+        (1234, 53)
+    );
+    assert_eq!(packet.payload(), b"hello");
+
+    // 伪首部的地址不同时校验和不匹配
+    let other = Ipv4Addr::new(10, 0, 2, 3);
+    assert_eq!(
+        UdpPacket::parse(&datagram, GUEST, other).unwrap_err(),
+        ParseError::BadChecksum
+    );
+    assert_eq!(
+        UdpPacket::parse(&datagram[..12], GUEST, HOST).unwrap_err(),
+        ParseError::Truncated
+    );
+
+    // 没有校验和的数据报不做校验
+    let mut unchecked = datagram.clone();
+    unchecked[6..8].fill(0);
+    assert!(UdpPacket::parse(&unchecked, GUEST, other).is_ok());
+
+    let mut bad_length = datagram;
+    bad_length[4..6].copy_from_slice(&7u16.to_be_bytes());
+    assert_eq!(
+        UdpPacket::parse(&bad_length, GUEST, HOST).unwrap_err(),
+        ParseError::Malformed
+    );
+}
+
+#[test_case]
+fn test_bind_and_deliver() {
+    let socket = UdpSocket::bind(4000).unwrap();
+    assert_eq!(UdpSocket::bind(4000).err(), Some(UdpError::AddressInUse));
+
+    let datagram = build((HOST, 53), (GUEST, 4000), b"reply");
+    let packet = UdpPacket::parse(&datagram, HOST, GUEST).unwrap();
+    assert!(deliver(HOST, &packet));
+    let mut buf = [0u8; 3];
+    assert_eq!(socket.try_recv_from(&mut buf), Some((3, HOST, 53)));
+    assert_eq!(&buf, b"rep");
+    assert_eq!(socket.try_recv_from(&mut buf), None);
+
+    // 队列满了以后丢弃并计数
+    for _ in 0..QUEUE_CAPACITY + 2 {
+        assert!(deliver(HOST, &packet));
+    }
+    assert_eq!(socket.dropped(), 2);
+
+    // 解除绑定后端口可以再次使用
+    drop(socket);
+    assert!(!deliver(HOST, &packet));
+    assert!(UdpSocket::bind(4000).is_ok());
+}
+
+#[test_case]
+fn test_ephemeral_ports() {
+    let a = UdpSocket::bind(0).unwrap();
+    let b = UdpSocket::bind(0).unwrap();
+    assert_ne!(a.local_port(), b.local_port());
+    assert!(EPHEMERAL_PORTS.contains(&a.local_port()));
+    assert!(EPHEMERAL_PORTS.contains(&b.local_port()));
+}
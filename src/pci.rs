@@ -0,0 +1,581 @@
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::selftest::{self, Check, Outcome};
+
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// 配置空间偏移
+const OFFSET_ID: u8 = 0x00;
+const OFFSET_COMMAND: u8 = 0x04;
+const OFFSET_CLASS: u8 = 0x08;
+const OFFSET_HEADER_TYPE: u8 = 0x0C;
+const OFFSET_BAR0: u8 = 0x10;
+const OFFSET_BUS_NUMBERS: u8 = 0x18;
+const OFFSET_INTERRUPT: u8 = 0x3C;
+
+// 命令寄存器中的地址译码位
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_GENERAL: u8 = 0x00;
+const HEADER_PCI_BRIDGE: u8 = 0x01;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const NO_VENDOR: u16 = 0xFFFF;
+
+// BAR低位
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_TYPE_MASK: u32 = 0x6;
+const BAR_MEMORY_64BIT: u32 = 0x4;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// 配置空间访问方式, 便于在测试中替换为录制的配置空间
+pub trait ConfigAccess {
+    fn read(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u32;
+    fn write(&mut self, bus: u8, device: u8, function: u8, offset: u8, value: u32);
+}
+
+/// 通过0xCF8/0xCFC端口访问配置空间
+pub struct PortAccess {
+    address: Port<u32>,
+    data: Port<u32>,
+}
+
+impl PortAccess {
+    pub const fn new() -> Self {
+        PortAccess {
+            address: Port::new(CONFIG_ADDRESS_PORT),
+            data: Port::new(CONFIG_DATA_PORT),
+        }
+    }
+
+    fn select(&mut self, bus: u8, device: u8, function: u8, offset: u8) {
+        let address = CONFIG_ENABLE
+            | u32::from(bus) << 16
+            | u32::from(device) << 11
+            | u32::from(function) << 8
+            | u32::from(offset & 0xFC);
+        unsafe { self.address.write(address) }
+    }
+}
+
+impl Default for PortAccess {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigAccess for PortAccess {
+    fn read(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+        self.select(bus, device, function, offset);
+        unsafe { self.data.read() }
+    }
+
+    fn write(&mut self, bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+        self.select(bus, device, function, offset);
+        unsafe { self.data.write(value) }
+    }
+}
+
+/// 解码后的基址寄存器
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// 未实现, 或是前一个64位BAR的高32位
+    None,
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub bars: [Bar; 6],
+    pub interrupt_line: u8,
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} irq {}",
+            self.bus,
+            self.device,
+            self.function,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.prog_if,
+            self.interrupt_line,
+        )
+    }
+}
+
+/// 从总线0开始递归扫描所有设备, 会短暂关闭设备的地址译码以探测BAR大小
+pub fn scan<A: ConfigAccess>(access: &mut A) -> Vec<PciDevice> {
+    let mut scanner = Scanner {
+        access,
+        devices: Vec::new(),
+        visited: [false; 256],
+    };
+
+    // 主桥是多功能设备时, 每个功能对应一个主机控制器和一条总线
+    let header_type = scanner.header_type(0, 0, 0);
+    if header_type & HEADER_MULTI_FUNCTION == 0 {
+        scanner.scan_bus(0);
+    } else {
+        for function in 0..8 {
+            if scanner.vendor_id(0, 0, function) != NO_VENDOR {
+                scanner.scan_bus(function);
+            }
+        }
+    }
+    scanner.devices
+}
+
+struct Scanner<'a, A> {
+    access: &'a mut A,
+    devices: Vec<PciDevice>,
+    // 防止错误配置的桥导致重复扫描
+    visited: [bool; 256],
+}
+
+impl<A: ConfigAccess> Scanner<'_, A> {
+    fn read(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+        self.access.read(bus, device, function, offset)
+    }
+
+    fn write(&mut self, bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+        self.access.write(bus, device, function, offset, value)
+    }
+
+    fn vendor_id(&mut self, bus: u8, device: u8, function: u8) -> u16 {
+        self.read(bus, device, function, OFFSET_ID) as u16
+    }
+
+    fn header_type(&mut self, bus: u8, device: u8, function: u8) -> u8 {
+        (self.read(bus, device, function, OFFSET_HEADER_TYPE) >> 16) as u8
+    }
+
+    fn scan_bus(&mut self, bus: u8) {
+        if self.visited[usize::from(bus)] {
+            return;
+        }
+        self.visited[usize::from(bus)] = true;
+
+        for device in 0..32 {
+            self.scan_device(bus, device);
+        }
+    }
+
+    fn scan_device(&mut self, bus: u8, device: u8) {
+        if self.vendor_id(bus, device, 0) == NO_VENDOR {
+            return;
+        }
+        self.scan_function(bus, device, 0);
+
+        if self.header_type(bus, device, 0) & HEADER_MULTI_FUNCTION != 0 {
+            for function in 1..8 {
+                if self.vendor_id(bus, device, function) != NO_VENDOR {
+                    self.scan_function(bus, device, function);
+                }
+            }
+        }
+    }
+
+    fn scan_function(&mut self, bus: u8, device: u8, function: u8) {
+        let id = self.read(bus, device, function, OFFSET_ID);
+        let class = self.read(bus, device, function, OFFSET_CLASS);
+        let header_type = self.header_type(bus, device, function) & HEADER_TYPE_MASK;
+        let interrupt = self.read(bus, device, function, OFFSET_INTERRUPT);
+
+        // 桥只有两个BAR, 其他头部类型不解析BAR
+        let bar_count = match header_type {
+            HEADER_GENERAL => 6,
+            HEADER_PCI_BRIDGE => 2,
+            _ => 0,
+        };
+        let bars = self.probe_bars(bus, device, function, bar_count);
+
+        let found = PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            bars,
+            interrupt_line: interrupt as u8,
+        };
+        self.devices.push(found);
+
+        if found.class == CLASS_BRIDGE
+            && found.subclass == SUBCLASS_PCI_BRIDGE
+            && header_type == HEADER_PCI_BRIDGE
+        {
+            let secondary = (self.read(bus, device, function, OFFSET_BUS_NUMBERS) >> 8) as u8;
+            self.scan_bus(secondary);
+        }
+    }
+
+    fn probe_bars(&mut self, bus: u8, device: u8, function: u8, count: usize) -> [Bar; 6] {
+        let mut bars = [Bar::None; 6];
+        if count == 0 {
+            return bars;
+        }
+
+        // 探测期间关闭译码, 否则写入全1会让设备短暂占用错误的地址
+        let command = self.read(bus, device, function, OFFSET_COMMAND);
+        let decode = COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE;
+        self.write(bus, device, function, OFFSET_COMMAND, command & 0xFFFF & !decode);
+
+        let mut index = 0;
+        while index < count {
+            let offset = OFFSET_BAR0 + 4 * index as u8;
+            let (low, low_mask) = self.probe(bus, device, function, offset);
+
+            if low & BAR_IO != 0 {
+                let size = (!(low_mask & !0x3)).wrapping_add(1) & 0xFFFF;
+                if low_mask != 0 {
+                    bars[index] = Bar::Io {
+                        port: low & !0x3,
+                        size,
+                    };
+                }
+                index += 1;
+                continue;
+            }
+
+            let is_64bit = low & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_64BIT && index + 1 < count;
+            let (address, mask) = if is_64bit {
+                let (high, high_mask) = self.probe(bus, device, function, offset + 4);
+                (
+                    u64::from(high) << 32 | u64::from(low & !0xF),
+                    u64::from(high_mask) << 32 | u64::from(low_mask & !0xF),
+                )
+            } else {
+                // 32位BAR的高位视为全1
+                (u64::from(low & !0xF), 0xFFFF_FFFF_0000_0000 | u64::from(low_mask & !0xF))
+            };
+            if mask & 0xFFFF_FFFF != 0 || (is_64bit && mask != 0) {
+                bars[index] = Bar::Memory {
+                    address,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: low & BAR_PREFETCHABLE != 0,
+                    is_64bit,
+                };
+            }
+            index += if is_64bit { 2 } else { 1 };
+        }
+
+        self.write(bus, device, function, OFFSET_COMMAND, command & 0xFFFF);
+        bars
+    }
+
+    // 写入全1后读回大小掩码, 然后恢复原值
+    fn probe(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> (u32, u32) {
+        let original = self.read(bus, device, function, offset);
+        self.write(bus, device, function, offset, 0xFFFF_FFFF);
+        let mask = self.read(bus, device, function, offset);
+        self.write(bus, device, function, offset, original);
+        (original, mask)
+    }
+}
+
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+/// 系统中的所有PCI设备, 第一次调用时扫描, 需要在堆初始化之后调用
+pub fn devices() -> impl Iterator<Item = &'static PciDevice> {
+    DEVICES
+        .call_once(|| interrupts::without_interrupts(|| scan(&mut PortAccess::new())))
+        .iter()
+}
+
+/// 查找第一个具有指定类别和子类别的设备
+pub fn find(class: u8, subclass: u8) -> Option<&'static PciDevice> {
+    devices().find(|device| device.class == class && device.subclass == subclass)
+}
+
+/// 允许设备发起DMA
+pub fn enable_bus_master(device: &PciDevice) {
+    let mut access = PortAccess::new();
+    let (bus, dev, function) = (device.bus, device.device, device.function);
+    interrupts::without_interrupts(|| {
+        let command = access.read(bus, dev, function, OFFSET_COMMAND) & 0xFFFF;
+        access.write(bus, dev, function, OFFSET_COMMAND, command | COMMAND_BUS_MASTER);
+    });
+}
+
+/// 登记PCI的自检
+pub fn register_selftests() {
+    static DEVICES_TEST: Check = Check::new("pci", devices_selftest);
+    selftest::register(&DEVICES_TEST).expect("duplicate PCI selftest");
+}
+
+// 重新读取扫描到的每个设备的ID. 不重新扫描, 探测BAR大小会暂时改写正在使用的BAR
+fn devices_selftest() -> Outcome {
+    let mut access = PortAccess::new();
+    let mut count = 0;
+    for device in devices() {
+        count += 1;
+        let (bus, dev, function) = (device.bus, device.device, device.function);
+        let id = interrupts::without_interrupts(|| access.read(bus, dev, function, OFFSET_ID));
+        let expected = u32::from(device.device_id) << 16 | u32::from(device.vendor_id);
+        if id != expected {
+            return Outcome::Fail(format!(
+                "{:02x}:{:02x}.{} now reads id {:#010x}",
+                bus, dev, function, id
+            ));
+        }
+    }
+    if count == 0 {
+        Outcome::Skipped("no PCI devices found".into())
+    } else {
+        Outcome::Pass
+    }
+}
+
+/// 打印设备列表
+pub fn print_devices() {
+    crate::println!("PCI devices:");
+    for device in devices() {
+        crate::println!("  {}", device);
+    }
+}
+
+#[test_case]
+fn test_scan_qemu_fixture() {
+    let mut access = fixture::FixtureAccess::new(&fixture::qemu_pc());
+    let devices = scan(&mut access);
+
+    let locations: Vec<_> = devices.iter().map(|d| (d.bus, d.device, d.function)).collect();
+    assert_eq!(locations, [(0, 0, 0), (0, 1, 0), (0, 1, 1), (0, 1, 3), (0, 2, 0), (0, 3, 0)]);
+
+    let ide = &devices[2];
+    assert_eq!((ide.vendor_id, ide.device_id), (0x8086, 0x7010));
+    assert_eq!((ide.class, ide.subclass, ide.prog_if), (0x01, 0x01, 0x80));
+    assert_eq!(ide.bars[4], Bar::Io { port: 0xC040, size: 16 });
+    assert_eq!(ide.bars[0], Bar::None);
+
+    let vga = &devices[4];
+    assert_eq!(
+        vga.bars[0],
+        Bar::Memory { address: 0xFD00_0000, size: 16 << 20, prefetchable: true, is_64bit: false }
+    );
+    assert_eq!(
+        vga.bars[2],
+        Bar::Memory { address: 0xFEBF_0000, size: 4096, prefetchable: false, is_64bit: false }
+    );
+
+    let e1000 = &devices[5];
+    assert_eq!((e1000.class, e1000.subclass), (0x02, 0x00));
+    assert_eq!(e1000.interrupt_line, 11);
+    assert_eq!(
+        e1000.bars[0],
+        Bar::Memory { address: 0xFEBC_0000, size: 128 << 10, prefetchable: false, is_64bit: false }
+    );
+    assert_eq!(e1000.bars[1], Bar::Io { port: 0xC000, size: 64 });
+}
+
+#[test_case]
+fn test_bar_probe_restores_config() {
+    let mut access = fixture::FixtureAccess::new(&fixture::qemu_pc());
+    scan(&mut access);
+
+    assert!(!access.probed_while_decoding);
+    assert_eq!(access.dword((0, 3, 0), OFFSET_COMMAND), 0x0000_0107);
+    assert_eq!(access.dword((0, 3, 0), OFFSET_BAR0), 0xFEBC_0000);
+    assert_eq!(access.dword((0, 3, 0), OFFSET_BAR0 + 4), 0x0000_C001);
+}
+
+#[test_case]
+fn test_scan_behind_bridge() {
+    let mut functions = fixture::qemu_pc();
+    // 用PCI桥替换e1000, 其后的总线1上有一个带64位BAR的设备
+    functions[5] = fixture::Function {
+        location: (0, 3, 0),
+        config: [
+            0x0001_1B36, 0x0000_0107, 0x0604_0000, 0x0001_0000,
+            0, 0, 0x0001_0100, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ],
+        bar_masks: [0; 6],
+    };
+    let mut functions: Vec<_> = functions.into_iter().collect();
+    functions.push(fixture::Function {
+        location: (1, 0, 0),
+        config: [
+            0x1000_1AF4, 0x0000_0106, 0x0100_0000, 0x0000_0000,
+            0xFE00_000C, 0x0000_0001, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ],
+        bar_masks: [0xFFFF_C00C, 0xFFFF_FFFF, 0, 0, 0, 0],
+    });
+    let mut access = fixture::FixtureAccess::new(&functions);
+    let devices = scan(&mut access);
+
+    let device = devices.last().unwrap();
+    assert_eq!((device.bus, device.vendor_id, device.device_id), (1, 0x1AF4, 0x1000));
+    assert_eq!(
+        device.bars[0],
+        Bar::Memory { address: 0x1_FE00_0000, size: 16 << 10, prefetchable: true, is_64bit: true }
+    );
+    assert_eq!(device.bars[1], Bar::None);
+}
+
+#[test_case]
+fn test_finds_host_bridge_and_vga() {
+    assert!(devices().any(|d| d.class == CLASS_BRIDGE && d.subclass == 0x00));
+    assert!(find(0x03, 0x00).is_some());
+}
+
+#[cfg(test)]
+mod fixture {
+    use super::*;
+    use alloc::collections::BTreeMap;
+
+    // 一个功能的配置空间前64字节, 以及BAR写入全1后读回的值
+    pub struct Function {
+        pub location: (u8, u8, u8),
+        pub config: [u32; 16],
+        pub bar_masks: [u32; 6],
+    }
+
+    // 配置空间和BAR掩码
+    type Recorded = ([u32; 16], [u32; 6]);
+
+    /// 回放录制的配置空间, 并模拟BAR的大小探测
+    pub struct FixtureAccess {
+        functions: BTreeMap<(u8, u8, u8), Recorded>,
+        pub probed_while_decoding: bool,
+    }
+
+    impl FixtureAccess {
+        pub fn new(functions: &[Function]) -> Self {
+            FixtureAccess {
+                functions: functions
+                    .iter()
+                    .map(|f| (f.location, (f.config, f.bar_masks)))
+                    .collect(),
+                probed_while_decoding: false,
+            }
+        }
+
+        pub fn dword(&self, location: (u8, u8, u8), offset: u8) -> u32 {
+            self.functions[&location].0[usize::from(offset / 4)]
+        }
+    }
+
+    impl ConfigAccess for FixtureAccess {
+        fn read(&mut self, bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+            match self.functions.get(&(bus, device, function)) {
+                Some((config, _)) => config[usize::from(offset / 4)],
+                None => 0xFFFF_FFFF,
+            }
+        }
+
+        fn write(&mut self, bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+            let Some((config, masks)) = self.functions.get_mut(&(bus, device, function)) else {
+                return;
+            };
+            let index = usize::from(offset / 4);
+            let bar = usize::from(offset.wrapping_sub(OFFSET_BAR0) / 4);
+            if offset >= OFFSET_BAR0 && bar < 6 && value == 0xFFFF_FFFF {
+                if config[1] & (COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE) != 0 {
+                    self.probed_while_decoding = true;
+                }
+                config[index] = masks[bar];
+            } else {
+                config[index] = value;
+            }
+        }
+    }
+
+    /// QEMU默认i440fx机器上录制的设备
+    pub fn qemu_pc() -> [Function; 6] {
+        [
+            // 主桥 8086:1237
+            Function {
+                location: (0, 0, 0),
+                config: [
+                    0x1237_8086, 0x0000_0106, 0x0600_0002, 0x0000_0000,
+                    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                ],
+                bar_masks: [0; 6],
+            },
+            // ISA桥 8086:7000, 多功能设备
+            Function {
+                location: (0, 1, 0),
+                config: [
+                    0x7000_8086, 0x0200_0007, 0x0601_0000, 0x0080_0000,
+                    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                ],
+                bar_masks: [0; 6],
+            },
+            // IDE 8086:7010, BAR4为总线主控I/O端口
+            Function {
+                location: (0, 1, 1),
+                config: [
+                    0x7010_8086, 0x0280_0005, 0x0101_8000, 0x0000_0000,
+                    0, 0, 0, 0, 0x0000_C041, 0, 0, 0, 0, 0, 0, 0x0000_0000,
+                ],
+                bar_masks: [0, 0, 0, 0, 0xFFFF_FFF1, 0],
+            },
+            // ACPI 8086:7113
+            Function {
+                location: (0, 1, 3),
+                config: [
+                    0x7113_8086, 0x0280_0003, 0x0680_0003, 0x0000_0000,
+                    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0000_0109,
+                ],
+                bar_masks: [0; 6],
+            },
+            // VGA 1234:1111, 16M可预取帧缓冲和4K的MMIO
+            Function {
+                location: (0, 2, 0),
+                config: [
+                    0x1111_1234, 0x0000_0103, 0x0300_0002, 0x0000_0000,
+                    0xFD00_0008, 0, 0xFEBF_0000, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                ],
+                bar_masks: [0xFF00_0008, 0, 0xFFFF_F000, 0, 0, 0],
+            },
+            // e1000 8086:100E
+            Function {
+                location: (0, 3, 0),
+                config: [
+                    0x100E_8086, 0x0000_0107, 0x0200_0003, 0x0000_0000,
+                    0xFEBC_0000, 0x0000_C001, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0000_010B,
+                ],
+                bar_masks: [0xFFFE_0000, 0xFFFF_FFC1, 0, 0, 0, 0],
+            },
+        ]
+    }
+}
@@ -0,0 +1,191 @@
+use core::arch::asm;
+use core::mem::{offset_of, MaybeUninit};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use spin::Once;
+
+use crate::cpu::msr::{self, IA32_GS_BASE, IA32_KERNEL_GS_BASE};
+use crate::{acpi, apic, bootmem};
+
+/// 每个处理器独有的数据, 内核通过GS_BASE寻址.
+/// 用户代码运行时指针保存在KERNEL_GS_BASE, 进入内核时由swapgs换回
+#[repr(C)]
+pub struct PerCpu {
+    // 必须是第一个字段, get()通过gs:[0]取得本结构的地址
+    self_ptr: *const PerCpu,
+    /// 内核分配的逻辑编号, BSP为0
+    pub cpu_id: u32,
+    pub apic_id: u32,
+    ticks: AtomicU64,
+    // 调度器当前运行的线程, 尚未运行线程时为空
+    current_thread: AtomicPtr<()>,
+    // syscall入口通过gs访问: 进入时暂存的用户栈指针和要切换到的内核栈顶
+    syscall_user_rsp: AtomicU64,
+    syscall_stack: AtomicU64,
+    // fault::try_with_recovery最内层的恢复区域, 没有时为空
+    recovery_slot: AtomicPtr<()>,
+    #[cfg(feature = "lockdep")]
+    held_locks: crate::sync::lockdep::HeldLocks,
+}
+
+/// `syscall_user_rsp`相对GS_BASE的偏移, 供syscall入口使用
+pub(crate) const SYSCALL_USER_RSP_OFFSET: usize = offset_of!(PerCpu, syscall_user_rsp);
+/// `syscall_stack`相对GS_BASE的偏移
+pub(crate) const SYSCALL_STACK_OFFSET: usize = offset_of!(PerCpu, syscall_stack);
+
+// 除了只读字段和只由本处理器访问的held_locks都是原子类型, 其他处理器也可以读取
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    /// 本处理器处理过的时钟中断次数
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    pub fn current_thread(&self) -> *mut () {
+        self.current_thread.load(Ordering::Acquire)
+    }
+
+    pub fn set_current_thread(&self, thread: *mut ()) {
+        self.current_thread.store(thread, Ordering::Release);
+    }
+
+    /// syscall进入内核时使用的栈
+    pub(crate) fn set_syscall_stack(&self, top: u64) {
+        self.syscall_stack.store(top, Ordering::Relaxed);
+    }
+
+    pub(crate) fn recovery_slot(&self) -> &AtomicPtr<()> {
+        &self.recovery_slot
+    }
+
+    #[cfg(feature = "lockdep")]
+    pub(crate) fn held_locks(&self) -> &crate::sync::lockdep::HeldLocks {
+        &self.held_locks
+    }
+}
+
+// `reserve`预留的数据块, 下标是cpu_id
+struct Blocks {
+    base: *mut MaybeUninit<PerCpu>,
+    len: usize,
+}
+
+// 每个处理器只写入自己的数据块
+unsafe impl Send for Blocks {}
+unsafe impl Sync for Blocks {}
+
+static BLOCKS: Once<Blocks> = Once::new();
+
+/// 从bootmem为BSP和MADT中每个启用的AP预留数据块, 需要在`acpi::init`之后、堆初始化之前调用
+pub fn reserve() {
+    let bsp = initial_apic_id();
+    let aps = acpi::madt().map_or(0, |madt| {
+        madt.processors
+            .iter()
+            .filter(|p| p.enabled && p.apic_id != bsp)
+            .count()
+    });
+    BLOCKS.call_once(|| {
+        let blocks = bootmem::alloc_slice::<PerCpu>(aps + 1);
+        Blocks {
+            base: blocks.as_mut_ptr(),
+            len: blocks.len(),
+        }
+    });
+}
+
+/// 在预留的数据块中为当前处理器建立数据并写入GS_BASE, 本处理器的local APIC需要已经启用
+///
+/// 每个处理器只能调用一次, 数据块不会释放
+pub fn init(cpu_id: u32) -> &'static PerCpu {
+    let blocks = BLOCKS.get().expect("per-CPU blocks not reserved");
+    assert!(
+        (cpu_id as usize) < blocks.len,
+        "no per-CPU block reserved for CPU {}",
+        cpu_id
+    );
+    let apic_id = apic::local_apic().map_or_else(initial_apic_id, |lapic| lapic.id());
+    // 下标不同的数据块互不重叠
+    let slot: &'static mut MaybeUninit<PerCpu> = unsafe { &mut *blocks.base.add(cpu_id as usize) };
+    let percpu = slot.write(PerCpu {
+        self_ptr: ptr::null(),
+        cpu_id,
+        apic_id,
+        ticks: AtomicU64::new(0),
+        current_thread: AtomicPtr::new(ptr::null_mut()),
+        syscall_user_rsp: AtomicU64::new(0),
+        syscall_stack: AtomicU64::new(0),
+        recovery_slot: AtomicPtr::new(ptr::null_mut()),
+        #[cfg(feature = "lockdep")]
+        held_locks: crate::sync::lockdep::HeldLocks::new(),
+    });
+    percpu.self_ptr = ptr::addr_of!(*percpu);
+    unsafe {
+        msr::write(IA32_GS_BASE, percpu.self_ptr as u64);
+        // 第一次进入ring 3时换给用户代码的GS_BASE
+        msr::write(IA32_KERNEL_GS_BASE, 0);
+    }
+    percpu
+}
+
+/// 为BSP建立数据块, 在`apic::init`之后调用以取得APIC ID
+pub fn init_bsp() -> &'static PerCpu {
+    init(0)
+}
+
+// 没有可用的local APIC时使用CPUID报告的初始APIC ID
+fn initial_apic_id() -> u32 {
+    #[allow(unused_unsafe)]
+    let leaf1 = unsafe { core::arch::x86_64::__cpuid(1) };
+    leaf1.ebx >> 24
+}
+
+/// 当前处理器的数据块, 必须已经调用过`init`
+pub fn get() -> &'static PerCpu {
+    try_get().expect("per-CPU data not initialized")
+}
+
+/// 当前处理器的数据块, 尚未初始化时返回None, 可以在中断处理函数中调用
+pub fn try_get() -> Option<&'static PerCpu> {
+    // GS_BASE为0时gs:[0]指向未映射的0地址, 先检查MSR
+    if unsafe { msr::read(IA32_GS_BASE) } == 0 {
+        return None;
+    }
+    let percpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) percpu, options(nostack, preserves_flags, readonly));
+        Some(&*percpu)
+    }
+}
+
+/// 当前处理器的逻辑编号. 每处理器数据建立之前只有BSP在运行, 返回0
+pub fn cpu_id() -> u32 {
+    try_get().map_or(0, |percpu| percpu.cpu_id)
+}
+
+/// 由时钟中断处理函数调用
+pub(crate) fn on_timer_interrupt() {
+    if let Some(percpu) = try_get() {
+        percpu.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_bsp_percpu() {
+    let percpu = get();
+    assert_eq!(percpu.cpu_id, 0);
+    assert!(ptr::eq(percpu, percpu.self_ptr));
+    if let Some(lapic) = apic::local_apic() {
+        assert_eq!(percpu.apic_id, lapic.id());
+    }
+    // 调度器初始化时登记了启动线程
+    assert!(!percpu.current_thread().is_null());
+}
+
+#[test_case]
+fn test_percpu_ticks() {
+    let start = get().ticks();
+    crate::time::wait_until(200, || get().ticks() > start).expect("per-CPU tick counter stalled");
+}
@@ -0,0 +1,210 @@
+use crate::io::PortBus;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+// 写入这个未使用的端口大约需要1微秒, 给老式PIC处理初始化字的时间
+const WAIT_PORT: u16 = 0x80;
+
+// ICW1: 需要ICW4, 级联模式, 边沿触发
+const ICW1_INIT: u8 = 0x11;
+// ICW3: 从片接在主片的IRQ2上
+const ICW3_MASTER_HAS_SLAVE_ON_IRQ2: u8 = 1 << 2;
+const ICW3_SLAVE_CASCADE_ID: u8 = 2;
+const ICW4_8086_MODE: u8 = 0x01;
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+
+const CASCADE_IRQ: u8 = 2;
+
+/// 级联的两片8259 PIC, 主片处理IRQ0-7, 从片处理IRQ8-15
+pub struct ChainedPics<B> {
+    bus: B,
+    master_offset: u8,
+    slave_offset: u8,
+}
+
+impl<B: PortBus> ChainedPics<B> {
+    /// IRQ0-7映射到`master_offset`开始的向量, IRQ8-15映射到`slave_offset`开始的向量
+    pub const fn new(bus: B, master_offset: u8, slave_offset: u8) -> Self {
+        ChainedPics {
+            bus,
+            master_offset,
+            slave_offset,
+        }
+    }
+
+    fn wait(&mut self) {
+        self.bus.write_u8(WAIT_PORT, 0);
+    }
+
+    /// 按ICW1-ICW4重新映射中断向量, 保留原来的屏蔽字
+    pub fn initialize(&mut self) {
+        let (master_mask, slave_mask) = self.read_masks();
+        let steps = [
+            (MASTER_COMMAND, ICW1_INIT),
+            (SLAVE_COMMAND, ICW1_INIT),
+            (MASTER_DATA, self.master_offset),
+            (SLAVE_DATA, self.slave_offset),
+            (MASTER_DATA, ICW3_MASTER_HAS_SLAVE_ON_IRQ2),
+            (SLAVE_DATA, ICW3_SLAVE_CASCADE_ID),
+            (MASTER_DATA, ICW4_8086_MODE),
+            (SLAVE_DATA, ICW4_8086_MODE),
+        ];
+        for (port, value) in steps {
+            self.bus.write_u8(port, value);
+            self.wait();
+        }
+        self.write_masks(master_mask, slave_mask);
+    }
+
+    /// 主片和从片的屏蔽字, 置位的IRQ被屏蔽
+    pub fn read_masks(&mut self) -> (u8, u8) {
+        (self.bus.read_u8(MASTER_DATA), self.bus.read_u8(SLAVE_DATA))
+    }
+
+    pub fn write_masks(&mut self, master: u8, slave: u8) {
+        self.bus.write_u8(MASTER_DATA, master);
+        self.bus.write_u8(SLAVE_DATA, slave);
+    }
+
+    /// 取消屏蔽指定的IRQ, 从片上的IRQ同时取消屏蔽级联用的IRQ2
+    pub fn unmask(&mut self, irq: u8) {
+        if irq < 8 {
+            let mask = self.bus.read_u8(MASTER_DATA);
+            self.bus.write_u8(MASTER_DATA, mask & !(1 << irq));
+        } else {
+            let mask = self.bus.read_u8(SLAVE_DATA);
+            self.bus.write_u8(SLAVE_DATA, mask & !(1 << (irq - 8)));
+            let mask = self.bus.read_u8(MASTER_DATA);
+            self.bus.write_u8(MASTER_DATA, mask & !(1 << CASCADE_IRQ));
+        }
+    }
+
+    /// 向量是否来自这两片PIC
+    pub fn handles_interrupt(&self, vector: u8) -> bool {
+        self.is_master(vector) || self.is_slave(vector)
+    }
+
+    fn is_master(&self, vector: u8) -> bool {
+        (self.master_offset..self.master_offset.saturating_add(8)).contains(&vector)
+    }
+
+    fn is_slave(&self, vector: u8) -> bool {
+        (self.slave_offset..self.slave_offset.saturating_add(8)).contains(&vector)
+    }
+
+    /// 屏蔽指定的IRQ, 级联用的IRQ2保持不变
+    pub fn mask(&mut self, irq: u8) {
+        let (port, bit) = Self::mask_bit(irq);
+        let mask = self.bus.read_u8(port);
+        self.bus.write_u8(port, mask | bit);
+    }
+
+    /// 指定的IRQ是否被屏蔽, 不检查从片上的IRQ所需的IRQ2
+    pub fn is_masked(&mut self, irq: u8) -> bool {
+        let (port, bit) = Self::mask_bit(irq);
+        self.bus.read_u8(port) & bit != 0
+    }
+
+    fn mask_bit(irq: u8) -> (u16, u8) {
+        if irq < 8 {
+            (MASTER_DATA, 1 << irq)
+        } else {
+            (SLAVE_DATA, 1 << (irq - 8))
+        }
+    }
+
+    /// 中断处理结束, 来自从片的中断需要两片都发送EOI. 其他向量被忽略
+    pub fn notify_end_of_interrupt(&mut self, vector: u8) {
+        if self.is_slave(vector) {
+            self.bus.write_u8(SLAVE_COMMAND, CMD_END_OF_INTERRUPT);
+        }
+        if self.handles_interrupt(vector) {
+            self.bus.write_u8(MASTER_COMMAND, CMD_END_OF_INTERRUPT);
+        }
+    }
+}
+
+#[test_case]
+fn test_remap_sequence() {
+    use crate::io::mock::{MockBus, PortWrite::U8};
+
+    let mut bus = MockBus::new();
+    bus.respond(MASTER_DATA, &[0xB8])
+        .respond(SLAVE_DATA, &[0x8E]);
+    let mut pics = ChainedPics::new(bus, 32, 40);
+    pics.initialize();
+    assert_eq!(
+        pics.bus.writes(),
+        [
+            U8(0x20, 0x11),
+            U8(0x80, 0),
+            U8(0xA0, 0x11),
+            U8(0x80, 0),
+            U8(0x21, 32),
+            U8(0x80, 0),
+            U8(0xA1, 40),
+            U8(0x80, 0),
+            U8(0x21, 0x04),
+            U8(0x80, 0),
+            U8(0xA1, 0x02),
+            U8(0x80, 0),
+            U8(0x21, 0x01),
+            U8(0x80, 0),
+            U8(0xA1, 0x01),
+            U8(0x80, 0),
+            // 恢复原来的屏蔽字
+            U8(0x21, 0xB8),
+            U8(0xA1, 0x8E),
+        ]
+    );
+    assert!(pics.bus.finished());
+}
+
+#[test_case]
+fn test_end_of_interrupt_and_unmask() {
+    use crate::io::mock::{MockBus, PortWrite::U8};
+
+    let mut bus = MockBus::new();
+    bus.respond(MASTER_DATA, &[0xFF, 0xFF])
+        .respond(SLAVE_DATA, &[0xFF]);
+    let mut pics = ChainedPics::new(bus, 32, 40);
+    pics.notify_end_of_interrupt(33);
+    pics.notify_end_of_interrupt(44);
+    // 不属于PIC的向量
+    pics.notify_end_of_interrupt(0x80);
+    assert!(!pics.handles_interrupt(48));
+    pics.unmask(1);
+    pics.unmask(12);
+    assert_eq!(
+        pics.bus.writes(),
+        [
+            U8(0x20, 0x20),
+            U8(0xA0, 0x20),
+            U8(0x20, 0x20),
+            U8(0x21, 0xFD),
+            U8(0xA1, 0xEF),
+            U8(0x21, 0xFB),
+        ]
+    );
+    assert!(pics.bus.finished());
+}
+
+#[test_case]
+fn test_mask() {
+    use crate::io::mock::{MockBus, PortWrite::U8};
+
+    let mut bus = MockBus::new();
+    bus.respond(MASTER_DATA, &[0x00, 0x01, 0x01])
+        .respond(SLAVE_DATA, &[0x00, 0x10]);
+    let mut pics = ChainedPics::new(bus, 32, 40);
+    pics.mask(0);
+    assert!(pics.is_masked(0));
+    assert!(!pics.is_masked(1));
+    pics.mask(12);
+    assert!(pics.is_masked(12));
+    // 屏蔽从片上的IRQ不影响主片的屏蔽字
+    assert_eq!(pics.bus.writes(), [U8(0x21, 0x01), U8(0xA1, 0x10)]);
+    assert!(pics.bus.finished());
+}
@@ -0,0 +1,98 @@
+use x86_64::instructions::port::Port;
+
+use crate::shell;
+use crate::{acpi, println, time};
+
+// PM1控制寄存器
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_EN: u16 = 1 << 13;
+
+// 切换到ACPI模式最多等待的时间
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 300;
+
+// QEMU(新版本和Bochs/旧版本)的关机端口和写入值
+const QEMU_SHUTDOWN_PORTS: [u16; 2] = [0x604, 0xB004];
+const QEMU_SHUTDOWN_VALUE: u16 = 0x2000;
+
+/// 注册`shutdown`和`reboot`命令
+pub fn register_commands() {
+    shell::register_command("shutdown", "power off", |_, _| shutdown())
+        .expect("duplicate power command");
+    shell::register_command("reboot", "restart the machine", |_, _| reboot())
+        .expect("duplicate power command");
+}
+
+/// 关闭电源, 依次尝试ACPI S5、QEMU专用端口, 都失败时停机
+pub fn shutdown() -> ! {
+    acpi_poweroff();
+
+    for port in QEMU_SHUTDOWN_PORTS {
+        unsafe {
+            Port::<u16>::new(port).write(QEMU_SHUTDOWN_VALUE);
+        }
+    }
+
+    println!("It is now safe to turn off your computer");
+    crate::hlt_loop();
+}
+
+// 成功时不会返回
+fn acpi_poweroff() {
+    let (Some(fadt), Some(dsdt)) = (acpi::fadt(), acpi::dsdt()) else {
+        return;
+    };
+    let Some((slp_typ_a, slp_typ_b)) = acpi::parse_s5(dsdt) else {
+        return;
+    };
+    if fadt.pm1a_control_block == 0 {
+        return;
+    }
+
+    let mut pm1a = Port::<u16>::new(fadt.pm1a_control_block as u16);
+    // 固件仍处于传统模式时需要先通过SMI切换到ACPI模式
+    if unsafe { pm1a.read() } & PM1_SCI_EN == 0 && fadt.smi_command_port != 0 {
+        unsafe {
+            Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+        }
+        let enabled = time::wait_until(ACPI_ENABLE_TIMEOUT_MS, || {
+            (unsafe { pm1a.read() } & PM1_SCI_EN) != 0
+        });
+        if enabled.is_err() {
+            return;
+        }
+    }
+
+    unsafe {
+        pm1a.write(u16::from(slp_typ_a) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+        if fadt.pm1b_control_block != 0 {
+            Port::<u16>::new(fadt.pm1b_control_block as u16)
+                .write(u16::from(slp_typ_b) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+        }
+    }
+    // 给硬件一点时间断电
+    let _ = time::wait_until(ACPI_ENABLE_TIMEOUT_MS, || false);
+}
+
+/// 重启, 先通过8042控制器复位CPU, 失败时触发三重错误
+pub fn reboot() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    x86_64::instructions::interrupts::disable();
+    crate::ps2::pulse_cpu_reset();
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+
+    // 空的IDT使任何异常都升级为三重错误
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&idt);
+    }
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
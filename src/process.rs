@@ -0,0 +1,503 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::elf::{self, ElfError, Program};
+use crate::error::{Context as ErrorContext, KernelError};
+use crate::layout::{self, Range};
+use crate::memory::{self, AddressSpace};
+use crate::shell::{self, CmdError, CommandFuture};
+use crate::syscall::{SyscallError, SyscallFrame};
+use crate::thread::{self, ThreadId};
+use crate::usermode::{self, UserExit};
+use crate::vfs::File;
+use crate::{println, task};
+
+mod limits;
+mod regions;
+
+use limits::Accounting;
+pub use limits::{
+    Resource, Resources, DEFAULT_LIMITS, RLIMIT_CHILDREN, RLIMIT_FILES, RLIMIT_PAGES,
+};
+pub use regions::{
+    Protection, Region, RegionError, RegionKind, Regions, PROT_EXEC, PROT_READ, PROT_WRITE,
+};
+
+// 0到2留给标准输入、输出和错误, 打开的文件从3开始编号
+const FIRST_FD: u64 = 3;
+
+/// 进程编号, 从1开始
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+// 第一个创建的进程, 目前只有它可以调整资源上限
+const INIT: Pid = Pid(1);
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    Load(ElfError),
+    /// 没有空闲帧创建页表或内核栈, 或程序的页数超过默认上限, 都是`KernelError::OutOfMemory`
+    Kernel(KernelError),
+}
+
+impl From<ElfError> for SpawnError {
+    fn from(err: ElfError) -> Self {
+        SpawnError::Load(err)
+    }
+}
+
+impl From<KernelError> for SpawnError {
+    fn from(err: KernelError) -> Self {
+        SpawnError::Kernel(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkError {
+    /// 当前线程没有在运行进程
+    NotAProcess,
+    /// 子进程数达到上限
+    TooManyChildren,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// 当前线程没有在运行进程
+    NotAProcess,
+    /// `prot`参数不合法, 或同时要求可写和可执行
+    BadProtection,
+    Region(RegionError),
+}
+
+impl From<RegionError> for MapError {
+    fn from(err: RegionError) -> Self {
+        MapError::Region(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// 进程不存在或已被等待过
+    NoSuchProcess,
+}
+
+/// 进程列表中的一项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub name: String,
+    /// 已结束但尚未被等待时为退出原因
+    pub exit: Option<UserExit>,
+    pub usage: Resources,
+    pub limits: Resources,
+}
+
+struct Process {
+    name: String,
+    // 运行用户程序的线程, 它拥有进程的地址空间和内核栈
+    thread: ThreadId,
+    exit: Option<UserExit>,
+    waker: AtomicWaker,
+    // 用户空间中已占用的区域, mmap从空隙中分配
+    regions: Regions,
+    // 打开的文件, 下标加`FIRST_FD`是文件描述符
+    files: Vec<Option<File>>,
+    accounting: Accounting,
+    // fork出它的进程, 结束时归还父进程的子进程计数
+    parent: Option<Pid>,
+}
+
+// 尚未被等待的进程, 进程在中断关闭的系统调用中退出, 因此总是在关中断时加锁
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+
+/// 在新的地址空间中加载`path`处的ELF程序, 创建进入ring 3运行它的线程
+pub async fn spawn(path: &str) -> Result<Pid, SpawnError> {
+    let mut space = AddressSpace::new().map_err(KernelError::from)?;
+    let program = elf::load_path(&mut space, path).await?;
+    let regions = initial_regions(&program);
+    let accounting = Accounting::new(DEFAULT_LIMITS);
+    if !accounting.charge(Resource::Pages, space.user_pages()) {
+        return Err(KernelError::OutOfMemory(ErrorContext::None).into());
+    }
+    let pid = Pid::new();
+    let name = String::from(path.rsplit('/').next().unwrap_or(path));
+    // 登记之前线程不能运行, 否则它可能在登记前就退出
+    interrupts::without_interrupts(|| {
+        let thread = thread::spawn_with_address_space(&name, space, move || unsafe {
+            usermode::enter(program.entry, program.stack_top)
+        })
+        .map_err(KernelError::from)?;
+        let process = Process {
+            name,
+            thread,
+            exit: None,
+            waker: AtomicWaker::new(),
+            regions,
+            files: Vec::new(),
+            accounting,
+            parent: None,
+        };
+        PROCESSES.lock().insert(pid, process);
+        Ok(pid)
+    })
+}
+
+/// 复制当前进程, 由fork系统调用调用. 子进程共享父进程的用户页直到写入,
+/// 它从`frame`保存的用户寄存器继续运行, 看到系统调用返回0. 子进程不继承打开的文件
+///
+/// 子进程继承上限, 共享的页也计入它的页数, 因此写时复制不会使它越过上限
+pub(crate) fn fork_current(frame: &SyscallFrame) -> Result<Pid, KernelError> {
+    let thread = thread::current_id().ok_or(ForkError::NotAProcess)?;
+    interrupts::without_interrupts(|| {
+        let (parent, name, regions, limits) = {
+            let processes = PROCESSES.lock();
+            let (&pid, process) = processes
+                .iter()
+                .find(|(_, process)| process.thread == thread && process.exit.is_none())
+                .ok_or(ForkError::NotAProcess)?;
+            if !process.accounting.charge(Resource::Children, 1) {
+                return Err(ForkError::TooManyChildren.into());
+            }
+            let limits = process.accounting.limits();
+            (pid, process.name.clone(), process.regions.clone(), limits)
+        };
+        let (child, accounting) = fork_child(&name, frame, limits).inspect_err(|_| {
+            if let Some(process) = PROCESSES.lock().get(&parent) {
+                process.accounting.uncharge(Resource::Children, 1);
+            }
+        })?;
+        let pid = Pid::new();
+        let process = Process {
+            name,
+            thread: child,
+            exit: None,
+            waker: AtomicWaker::new(),
+            regions,
+            files: Vec::new(),
+            accounting,
+            parent: Some(parent),
+        };
+        PROCESSES.lock().insert(pid, process);
+        Ok(pid)
+    })
+}
+
+// 复制地址空间并创建子进程的线程, 返回线程和子进程的计数
+fn fork_child(
+    name: &str,
+    frame: &SyscallFrame,
+    limits: Resources,
+) -> Result<(ThreadId, Accounting), KernelError> {
+    let mut space = AddressSpace::fork_active()?;
+    let accounting = Accounting::new(limits);
+    if !accounting.charge(Resource::Pages, space.user_pages()) {
+        return Err(KernelError::OutOfMemory(ErrorContext::None));
+    }
+    let frame = *frame;
+    let thread =
+        thread::spawn_with_address_space(name, space, move || unsafe { usermode::resume(&frame) })?;
+    Ok((thread, accounting))
+}
+
+// 加载后的程序占用的区域: 各段和用户栈
+fn initial_regions(program: &Program) -> Regions {
+    let mut regions = Regions::new(layout::USER);
+    let stack = Range::new(elf::STACK_TOP - elf::STACK_PAGES * 4096, layout::USER.end);
+    regions
+        .insert(program.image, RegionKind::Image)
+        .and_then(|()| regions.insert(stack, RegionKind::Stack))
+        .expect("program overlaps its stack");
+    regions
+}
+
+// 在关中断时访问当前进程, 当前线程不是进程时返回None
+fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    with_current_pid(|_, process| f(process))
+}
+
+fn with_current_pid<R>(f: impl FnOnce(Pid, &mut Process) -> R) -> Option<R> {
+    let thread = thread::current_id()?;
+    interrupts::without_interrupts(|| {
+        PROCESSES
+            .lock()
+            .iter_mut()
+            .find(|(_, process)| process.thread == thread && process.exit.is_none())
+            .map(|(&pid, process)| f(pid, process))
+    })
+}
+
+/// 在当前进程的用户空间中保留`len`字节的匿名区域, 返回起始地址. 由mmap系统调用调用
+///
+/// 区域中的页在第一次访问时才映射, 内容为0. 区域的页数超过剩余的额度时失败, 页在映射时才计数
+pub(crate) fn mmap_current(len: u64, prot: u64) -> Result<VirtAddr, KernelError> {
+    let protection = Protection::from_bits(prot).ok_or(MapError::BadProtection)?;
+    let start = with_current(|process| {
+        if len.div_ceil(4096) > process.accounting.available(Resource::Pages) {
+            return Err(KernelError::OutOfMemory(ErrorContext::None));
+        }
+        let start = process
+            .regions
+            .allocate(len, protection)
+            .map_err(MapError::from)?;
+        Ok(start)
+    })
+    .ok_or(MapError::NotAProcess)??;
+    Ok(VirtAddr::new(start))
+}
+
+/// 取消当前进程中`[start, start + len)`的映射并释放其中已映射的页, 范围必须是整页且在同一个匿名区域内.
+/// 由munmap系统调用调用
+pub(crate) fn munmap_current(start: u64, len: u64) -> Result<(), MapError> {
+    with_current(|process| -> Result<(), MapError> {
+        let range = process.regions.remove(start, len)?;
+        let pages = range.size() / 4096;
+        let unmapped = memory::unmap_active_user_pages(VirtAddr::new(range.start), pages)
+            .expect("anonymous region outside user space");
+        process.accounting.uncharge(Resource::Pages, unmapped);
+        Ok(())
+    })
+    .ok_or(MapError::NotAProcess)?
+}
+
+/// 把`file`加入当前进程的文件表, 返回文件描述符. 由open系统调用调用
+pub(crate) fn open_current(file: File) -> Result<u64, KernelError> {
+    with_current(|process| -> Result<u64, KernelError> {
+        if !process.accounting.charge(Resource::Files, 1) {
+            return Err(KernelError::TooManyOpenFiles(ErrorContext::None));
+        }
+        let index = match process.files.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                process.files.push(None);
+                process.files.len() - 1
+            }
+        };
+        process.files[index] = Some(file);
+        Ok(FIRST_FD + index as u64)
+    })
+    .ok_or(SyscallError::NotAProcess)?
+}
+
+// 从文件表中取出`fd`, 读写文件时不持有进程表的锁
+fn take_file(fd: u64) -> Result<File, KernelError> {
+    with_current(|process| {
+        let index = usize::try_from(fd.checked_sub(FIRST_FD)?).ok()?;
+        process.files.get_mut(index)?.take()
+    })
+    .ok_or(SyscallError::NotAProcess)?
+    .ok_or(SyscallError::BadFd.into())
+}
+
+/// 从当前进程打开的文件`fd`读取到`buf`, 返回读取的字节数. 由read系统调用调用
+pub(crate) fn read_current(fd: u64, buf: &mut [u8]) -> Result<usize, KernelError> {
+    let mut file = take_file(fd)?;
+    let result = thread::block_on(file.read(buf));
+    // 文件表只由进程自己的线程访问, 读取时park也不会被改动
+    with_current(|process| process.files[(fd - FIRST_FD) as usize] = Some(file));
+    result
+}
+
+/// 关闭当前进程打开的文件`fd`. 由close系统调用调用
+pub(crate) fn close_current(fd: u64) -> Result<(), KernelError> {
+    drop(take_file(fd)?);
+    with_current(|process| process.accounting.uncharge(Resource::Files, 1));
+    Ok(())
+}
+
+/// 把当前进程的`resource`的上限改为`limit`, 由setrlimit系统调用调用. 只有初始进程可以调用
+pub(crate) fn set_limit_current(resource: u64, limit: u64) -> Result<(), KernelError> {
+    let resource =
+        Resource::from_raw(resource).ok_or(KernelError::InvalidInput(ErrorContext::None))?;
+    with_current_pid(|pid, process| {
+        if pid != INIT {
+            return Err(KernelError::PermissionDenied(ErrorContext::None));
+        }
+        process.accounting.set_limit(resource, limit);
+        Ok(())
+    })
+    .ok_or(SyscallError::NotAProcess)?
+}
+
+// `addr`所在的匿名区域在第一次访问时能否以这种方式映射
+fn lazy_protection(addr: VirtAddr, write: bool) -> Option<Protection> {
+    if !layout::USER.contains(addr.as_u64()) {
+        return None;
+    }
+    let protection = with_current(|process| match process.regions.find(addr.as_u64())?.kind {
+        RegionKind::Anonymous(protection) => Some(protection),
+        _ => None,
+    })??;
+    (protection.writable || !write).then_some(protection)
+}
+
+/// `addr`是否在当前进程的匿名区域中且允许这种访问, 这样的页没有映射时访问它会由页错误映射
+pub(crate) fn can_fault_in(addr: VirtAddr, write: bool) -> bool {
+    lazy_protection(addr, write).is_some()
+}
+
+/// 处理当前进程对`addr`的访问引起的缺页, 返回是否已映射. 由页错误处理函数调用
+///
+/// 只映射匿名区域中的页, 权限不允许、页数达到上限或没有空闲帧时返回false, 由调用者报告页错误
+pub(crate) fn handle_page_fault(addr: VirtAddr, write: bool) -> bool {
+    let Some(protection) = lazy_protection(addr, write) else {
+        return false;
+    };
+    if with_current(|process| process.accounting.charge(Resource::Pages, 1)) != Some(true) {
+        return false;
+    }
+    let mut flags = PageTableFlags::empty();
+    if protection.writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !protection.executable {
+        flags |= elf::no_execute();
+    }
+    let mapped = memory::map_active_user_page(addr, flags).is_ok();
+    if !mapped {
+        with_current(|process| process.accounting.uncharge(Resource::Pages, 1));
+    }
+    mapped
+}
+
+/// 等待进程结束并返回退出原因, 之后进程从列表中删除
+pub fn wait(pid: Pid) -> impl Future<Output = Result<UserExit, WaitError>> {
+    Wait { pid }
+}
+
+struct Wait {
+    pid: Pid,
+}
+
+impl Future for Wait {
+    type Output = Result<UserExit, WaitError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        interrupts::without_interrupts(|| {
+            let mut processes = PROCESSES.lock();
+            let Some(process) = processes.get(&self.pid) else {
+                return Poll::Ready(Err(WaitError::NoSuchProcess));
+            };
+            process.waker.register(cx.waker());
+            let exit = process.exit;
+            match exit {
+                Some(exit) => {
+                    processes.remove(&self.pid);
+                    Poll::Ready(Ok(exit))
+                }
+                None => Poll::Pending,
+            }
+        })
+    }
+}
+
+/// 尚未被等待的进程, 按pid排序
+pub fn list() -> Vec<ProcessInfo> {
+    interrupts::without_interrupts(|| {
+        PROCESSES
+            .lock()
+            .iter()
+            .map(|(&pid, process)| ProcessInfo {
+                pid,
+                name: process.name.clone(),
+                exit: process.exit,
+                usage: process.accounting.usage(),
+                limits: process.accounting.limits(),
+            })
+            .collect()
+    })
+}
+
+/// 当前线程是否在运行某个进程
+pub(crate) fn is_current() -> bool {
+    let Some(thread) = thread::current_id_unlocked() else {
+        return false;
+    };
+    interrupts::without_interrupts(|| {
+        PROCESSES
+            .lock()
+            .values()
+            .any(|process| process.thread == thread && process.exit.is_none())
+    })
+}
+
+/// 由`usermode::exit`调用, 记录退出原因并唤醒等待者, 然后结束线程. 地址空间和内核栈在切换走之后释放
+///
+/// 因异常结束时输出一行记录. 当前线程从每处理器数据中取得, 异常处理函数中不需要调度器锁
+pub(crate) fn exit_current(reason: UserExit) -> ! {
+    interrupts::disable();
+    let thread = thread::current_id_unlocked();
+    let mut processes = PROCESSES.lock();
+    let (&pid, process) = processes
+        .iter_mut()
+        .find(|(_, process)| Some(process.thread) == thread)
+        .expect("current thread is not a process");
+    process.exit = Some(reason);
+    process.waker.wake();
+    // 地址空间随线程释放, 打开的文件在释放锁之后关闭
+    let files = core::mem::take(&mut process.files);
+    process.accounting.reset(Resource::Pages);
+    process.accounting.reset(Resource::Files);
+    if let Some(parent) = process.parent.and_then(|parent| processes.get(&parent)) {
+        parent.accounting.uncharge(Resource::Children, 1);
+    }
+    drop(processes);
+    drop(files);
+    if let UserExit::Fault(fault) = reason {
+        println!("process {} killed: {}", pid, fault);
+    }
+    thread::exit()
+}
+
+/// 注册`run`命令
+pub fn register_commands() {
+    shell::register_async_command("run", "run <path>: start a user program", run_command)
+        .expect("duplicate process command");
+}
+
+fn run_command<'a>(args: &'a [&'a str], out: &'a mut (dyn fmt::Write + Send)) -> CommandFuture<'a> {
+    Box::pin(async move {
+        let [path] = args else {
+            return Err(CmdError::Usage("run <path>"));
+        };
+        let pid = spawn(path).await.map_err(CmdError::Spawn)?;
+        writeln!(out, "started process {}", pid)?;
+        // 在后台等待, 外壳不必等程序结束, 也不等这个任务本身
+        drop(task::spawn("wait", async move {
+            match wait(pid).await {
+                Ok(exit) => println!("process {} {}", pid, exit),
+                Err(err) => println!("process {}: {:?}", pid, err),
+            }
+        }));
+        Ok(())
+    })
+}
@@ -0,0 +1,219 @@
+//! 进程的资源上限和使用量
+//!
+//! 每种资源是一对原子变量. 计数在检查上限的同时增加, 超过上限的请求不改变计数,
+//! 所以同时发生的缺页也不会越过上限. spawn创建的进程使用默认上限, fork的子进程继承父进程的上限
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// setrlimit系统调用的`resource`参数
+pub const RLIMIT_PAGES: u64 = 0;
+pub const RLIMIT_FILES: u64 = 1;
+pub const RLIMIT_CHILDREN: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// 用户空间中已映射的页, 包括与其他进程写时复制共享的页
+    Pages,
+    /// 打开的文件
+    Files,
+    /// 还没有结束的子进程
+    Children,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 3] = [Resource::Pages, Resource::Files, Resource::Children];
+
+    /// 解析`RLIMIT_*`
+    pub fn from_raw(resource: u64) -> Option<Resource> {
+        match resource {
+            RLIMIT_PAGES => Some(Resource::Pages),
+            RLIMIT_FILES => Some(Resource::Files),
+            RLIMIT_CHILDREN => Some(Resource::Children),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Resource::Pages => "Pages",
+            Resource::Files => "Files",
+            Resource::Children => "Children",
+        }
+    }
+}
+
+/// 每种资源一个数值, 表示上限或使用量
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resources {
+    pub pages: u64,
+    pub files: u64,
+    pub children: u64,
+}
+
+impl Resources {
+    pub fn get(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Pages => self.pages,
+            Resource::Files => self.files,
+            Resource::Children => self.children,
+        }
+    }
+}
+
+/// spawn创建的进程的上限
+pub const DEFAULT_LIMITS: Resources = Resources {
+    pages: 4096,
+    files: 16,
+    children: 16,
+};
+
+struct Counter {
+    used: AtomicU64,
+    limit: AtomicU64,
+}
+
+impl Counter {
+    const fn new(limit: u64) -> Counter {
+        Counter {
+            used: AtomicU64::new(0),
+            limit: AtomicU64::new(limit),
+        }
+    }
+
+    fn charge(&self, count: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(count)
+                    .filter(|&used| used <= self.limit.load(Ordering::Acquire))
+            })
+            .is_ok()
+    }
+
+    fn uncharge(&self, count: u64) {
+        // 先检查再减, 归还的过多时计数保持不变
+        let result = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_sub(count)
+            });
+        assert!(result.is_ok(), "resource usage underflow");
+    }
+}
+
+/// 一个进程的资源计数
+pub(crate) struct Accounting {
+    pages: Counter,
+    files: Counter,
+    children: Counter,
+}
+
+impl Accounting {
+    pub fn new(limits: Resources) -> Accounting {
+        Accounting {
+            pages: Counter::new(limits.pages),
+            files: Counter::new(limits.files),
+            children: Counter::new(limits.children),
+        }
+    }
+
+    fn counter(&self, resource: Resource) -> &Counter {
+        match resource {
+            Resource::Pages => &self.pages,
+            Resource::Files => &self.files,
+            Resource::Children => &self.children,
+        }
+    }
+
+    /// 使用量增加`count`, 会超过上限时不变并返回false
+    pub fn charge(&self, resource: Resource, count: u64) -> bool {
+        self.counter(resource).charge(count)
+    }
+
+    /// 归还`charge`计入的`count`. 归还的比计入的多时panic
+    pub fn uncharge(&self, resource: Resource, count: u64) {
+        self.counter(resource).uncharge(count)
+    }
+
+    /// 使用量归零, 用于进程结束时释放了全部这种资源
+    pub fn reset(&self, resource: Resource) {
+        self.counter(resource).used.store(0, Ordering::Release);
+    }
+
+    /// 还能计入的数量
+    pub fn available(&self, resource: Resource) -> u64 {
+        let counter = self.counter(resource);
+        let limit = counter.limit.load(Ordering::Acquire);
+        limit.saturating_sub(counter.used.load(Ordering::Acquire))
+    }
+
+    /// 可以低于当前的使用量, 之后的`charge`都会失败, 直到使用量降到上限以下
+    pub fn set_limit(&self, resource: Resource, limit: u64) {
+        self.counter(resource).limit.store(limit, Ordering::Release);
+    }
+
+    pub fn usage(&self) -> Resources {
+        let used = |counter: &Counter| counter.used.load(Ordering::Acquire);
+        Resources {
+            pages: used(&self.pages),
+            files: used(&self.files),
+            children: used(&self.children),
+        }
+    }
+
+    pub fn limits(&self) -> Resources {
+        let limit = |counter: &Counter| counter.limit.load(Ordering::Acquire);
+        Resources {
+            pages: limit(&self.pages),
+            files: limit(&self.files),
+            children: limit(&self.children),
+        }
+    }
+}
+
+#[test_case]
+fn test_charge_up_to_limit() {
+    let accounting = Accounting::new(Resources {
+        pages: 10,
+        files: 2,
+        children: 0,
+    });
+    assert!(accounting.charge(Resource::Pages, 8));
+    // 超过上限的请求整个被拒绝, 不会部分计入
+    assert!(!accounting.charge(Resource::Pages, 3));
+    assert_eq!(accounting.available(Resource::Pages), 2);
+    assert!(accounting.charge(Resource::Pages, 2));
+    assert!(!accounting.charge(Resource::Pages, 1));
+    accounting.uncharge(Resource::Pages, 4);
+    assert!(accounting.charge(Resource::Files, 2));
+    assert!(!accounting.charge(Resource::Children, 1));
+    assert!(!accounting.charge(Resource::Pages, u64::MAX));
+    assert_eq!(
+        accounting.usage(),
+        Resources {
+            pages: 6,
+            files: 2,
+            children: 0
+        }
+    );
+}
+
+#[test_case]
+fn test_lowered_limit() {
+    let accounting = Accounting::new(DEFAULT_LIMITS);
+    assert!(accounting.charge(Resource::Files, 5));
+    // 降到使用量以下后不再能打开文件, 关闭到上限以下才可以
+    accounting.set_limit(Resource::Files, 3);
+    assert_eq!(accounting.available(Resource::Files), 0);
+    assert!(!accounting.charge(Resource::Files, 1));
+    accounting.uncharge(Resource::Files, 3);
+    assert!(accounting.charge(Resource::Files, 1));
+    assert_eq!(accounting.limits().files, 3);
+    assert_eq!(accounting.limits().pages, DEFAULT_LIMITS.pages);
+    accounting.reset(Resource::Files);
+    assert_eq!(accounting.usage().files, 0);
+    assert_eq!(
+        Resource::from_raw(RLIMIT_CHILDREN),
+        Some(Resource::Children)
+    );
+    assert_eq!(Resource::from_raw(3), None);
+}
@@ -0,0 +1,314 @@
+//! 进程用户空间中已占用的区域
+//!
+//! 程序的各段和用户栈在加载时登记, mmap在它们之间的空隙中按首次适应分配匿名区域.
+//! 匿名区域只登记不映射, 第一次访问时由页错误映射清零的页
+
+use alloc::vec::Vec;
+
+use crate::layout::Range;
+
+/// mmap的`prot`参数中的位
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// 匿名区域的访问权限. 页表不能表示不可读的页, 因此总是可读
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Protection {
+    /// 解析`prot`参数. 没有`PROT_READ`、有未知的位或同时可写和可执行时返回None
+    pub fn from_bits(prot: u64) -> Option<Protection> {
+        if prot & PROT_READ == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return None;
+        }
+        let protection = Protection {
+            writable: prot & PROT_WRITE != 0,
+            executable: prot & PROT_EXEC != 0,
+        };
+        (!(protection.writable && protection.executable)).then_some(protection)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// 程序的各段, 包括段之间的空隙
+    Image,
+    /// 用户栈和它上方的保护页
+    Stack,
+    /// mmap分配的区域
+    Anonymous(Protection),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub range: Range,
+    pub kind: RegionKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// 地址或长度不是整页
+    Misaligned,
+    /// 长度为0或超出用户空间
+    BadLength,
+    /// 与已有的区域重叠
+    Overlap,
+    /// 没有足够大的空隙
+    NoSpace,
+    /// 范围不完全在同一个匿名区域内
+    NotReserved,
+}
+
+/// 按地址排序、互不重叠的区域
+#[derive(Debug, Clone)]
+pub struct Regions {
+    bounds: Range,
+    regions: Vec<Region>,
+}
+
+impl Regions {
+    /// 可以在`bounds`内登记区域
+    pub fn new(bounds: Range) -> Self {
+        Regions {
+            bounds,
+            regions: Vec::new(),
+        }
+    }
+
+    /// 登记`range`, 它必须在范围内且不与已有的区域重叠
+    pub fn insert(&mut self, range: Range, kind: RegionKind) -> Result<(), RegionError> {
+        if !self.bounds.contains_range(range.start, range.size()) {
+            return Err(RegionError::BadLength);
+        }
+        let index = self
+            .regions
+            .partition_point(|region| region.range.start < range.start);
+        let before = index.checked_sub(1).map(|i| &self.regions[i]);
+        if before
+            .into_iter()
+            .chain(self.regions.get(index))
+            .any(|region| region.range.overlaps(&range))
+        {
+            return Err(RegionError::Overlap);
+        }
+        self.regions.insert(index, Region { range, kind });
+        Ok(())
+    }
+
+    /// 在第一个足够大的空隙中登记`len`字节的匿名区域, 长度向上取整到页, 返回起始地址
+    pub fn allocate(&mut self, len: u64, protection: Protection) -> Result<u64, RegionError> {
+        let len = match len.checked_next_multiple_of(PAGE_SIZE) {
+            Some(len) if len > 0 && len <= self.bounds.size() => len,
+            _ => return Err(RegionError::BadLength),
+        };
+        let mut start = self.bounds.start;
+        for region in &self.regions {
+            if region.range.start - start >= len {
+                break;
+            }
+            start = region.range.end;
+        }
+        if self.bounds.end - start < len {
+            return Err(RegionError::NoSpace);
+        }
+        let range = Range::new(start, start + len);
+        self.insert(range, RegionKind::Anonymous(protection))?;
+        Ok(start)
+    }
+
+    /// 去掉匿名区域中`[start, start + len)`的部分, 中间的一段被去掉时区域分成两个.
+    /// 返回去掉部分的范围
+    pub fn remove(&mut self, start: u64, len: u64) -> Result<Range, RegionError> {
+        if !start.is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE) {
+            return Err(RegionError::Misaligned);
+        }
+        let end = match start.checked_add(len) {
+            Some(end) if len > 0 => end,
+            _ => return Err(RegionError::BadLength),
+        };
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.range.start <= start && end <= region.range.end)
+            .filter(|&i| matches!(self.regions[i].kind, RegionKind::Anonymous(_)))
+            .ok_or(RegionError::NotReserved)?;
+        let region = self.regions.remove(index);
+        // 剩下的部分插回原来的位置, 倒序插入以保持顺序
+        let parts = [(region.range.start, start), (end, region.range.end)];
+        for (start, end) in parts.into_iter().rev().filter(|(start, end)| start < end) {
+            let range = Range::new(start, end);
+            let kind = region.kind;
+            self.regions.insert(index, Region { range, kind });
+        }
+        Ok(Range::new(start, end))
+    }
+
+    /// `addr`所在的区域
+    pub fn find(&self, addr: u64) -> Option<&Region> {
+        let index = self
+            .regions
+            .partition_point(|region| region.range.start <= addr);
+        let region = &self.regions[index.checked_sub(1)?];
+        region.range.contains(addr).then_some(region)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+}
+
+#[cfg(test)]
+const TEST_BOUNDS: Range = Range::new(0x10_0000, 0x20_0000);
+
+#[cfg(test)]
+const READ_WRITE: Protection = Protection {
+    writable: true,
+    executable: false,
+};
+
+#[test_case]
+fn test_protection_bits() {
+    let read_only = Protection {
+        writable: false,
+        executable: false,
+    };
+    assert_eq!(Protection::from_bits(PROT_READ), Some(read_only));
+    assert_eq!(
+        Protection::from_bits(PROT_READ | PROT_WRITE),
+        Some(READ_WRITE)
+    );
+    assert!(Protection::from_bits(PROT_READ | PROT_EXEC).is_some_and(|p| p.executable));
+    // 可写又可执行的页违反W^X
+    assert_eq!(
+        Protection::from_bits(PROT_READ | PROT_WRITE | PROT_EXEC),
+        None
+    );
+    assert_eq!(Protection::from_bits(PROT_WRITE), None);
+    assert_eq!(Protection::from_bits(0), None);
+    assert_eq!(Protection::from_bits(PROT_READ | 8), None);
+}
+
+#[test_case]
+fn test_insert_overlaps() {
+    let mut regions = Regions::new(TEST_BOUNDS);
+    let range = |start, end| Range::new(start, end);
+    regions
+        .insert(range(0x14_0000, 0x15_0000), RegionKind::Image)
+        .unwrap();
+    regions
+        .insert(range(0x1f_0000, 0x20_0000), RegionKind::Stack)
+        .unwrap();
+    assert_eq!(
+        regions.insert(range(0x14_f000, 0x16_0000), RegionKind::Image),
+        Err(RegionError::Overlap)
+    );
+    assert_eq!(
+        regions.insert(range(0x13_0000, 0x14_1000), RegionKind::Image),
+        Err(RegionError::Overlap)
+    );
+    assert_eq!(
+        regions.insert(range(0x10_0000, 0x1f_1000), RegionKind::Image),
+        Err(RegionError::Overlap)
+    );
+    assert_eq!(
+        regions.insert(range(0x1f_0000, 0x21_0000), RegionKind::Image),
+        Err(RegionError::BadLength)
+    );
+    // 首尾相接不算重叠
+    regions
+        .insert(range(0x15_0000, 0x15_1000), RegionKind::Image)
+        .unwrap();
+    regions
+        .insert(range(0x13_f000, 0x14_0000), RegionKind::Image)
+        .unwrap();
+    let starts: Vec<u64> = regions.iter().map(|region| region.range.start).collect();
+    assert_eq!(starts, [0x13_f000, 0x14_0000, 0x15_0000, 0x1f_0000]);
+    assert_eq!(regions.find(0x14_0fff).unwrap().range.start, 0x14_0000);
+    assert_eq!(regions.find(0x15_1000), None);
+    assert_eq!(regions.find(0xf_ffff), None);
+}
+
+#[test_case]
+fn test_allocate_first_fit() {
+    let mut regions = Regions::new(TEST_BOUNDS);
+    regions
+        .insert(Range::new(0x10_2000, 0x10_3000), RegionKind::Image)
+        .unwrap();
+    regions
+        .insert(Range::new(0x1f_0000, 0x20_0000), RegionKind::Stack)
+        .unwrap();
+    // 长度向上取整到页, 正好填满开头的空隙
+    assert_eq!(regions.allocate(0x1001, READ_WRITE), Ok(0x10_0000));
+    assert_eq!(regions.allocate(1, READ_WRITE), Ok(0x10_3000));
+    assert_eq!(regions.allocate(0x2000, READ_WRITE), Ok(0x10_4000));
+    assert_eq!(regions.allocate(0, READ_WRITE), Err(RegionError::BadLength));
+    assert_eq!(
+        regions.allocate(u64::MAX, READ_WRITE),
+        Err(RegionError::BadLength)
+    );
+    assert_eq!(
+        regions.allocate(0xe_b000, READ_WRITE),
+        Err(RegionError::NoSpace)
+    );
+    assert_eq!(regions.allocate(0xe_a000, READ_WRITE), Ok(0x10_6000));
+    assert_eq!(
+        regions.allocate(0x1000, READ_WRITE),
+        Err(RegionError::NoSpace)
+    );
+    let region = regions.find(0x10_6000).unwrap();
+    assert_eq!(region.range, Range::new(0x10_6000, 0x1f_0000));
+    assert_eq!(region.kind, RegionKind::Anonymous(READ_WRITE));
+}
+
+#[test_case]
+fn test_remove_splits_regions() {
+    let mut regions = Regions::new(TEST_BOUNDS);
+    regions
+        .insert(Range::new(0x10_0000, 0x10_1000), RegionKind::Image)
+        .unwrap();
+    let start = regions.allocate(0x4000, READ_WRITE).unwrap();
+    assert_eq!(start, 0x10_1000);
+    assert_eq!(
+        regions.remove(start + 1, 0x1000),
+        Err(RegionError::Misaligned)
+    );
+    assert_eq!(regions.remove(start, 0x800), Err(RegionError::Misaligned));
+    assert_eq!(regions.remove(start, 0), Err(RegionError::BadLength));
+    // 程序的段和没有登记的地址不能取消映射
+    assert_eq!(
+        regions.remove(0x10_0000, 0x1000),
+        Err(RegionError::NotReserved)
+    );
+    assert_eq!(regions.remove(start, 0x5000), Err(RegionError::NotReserved));
+    assert_eq!(
+        regions.remove(start + 0x1000, 0x2000),
+        Ok(Range::new(0x10_2000, 0x10_4000))
+    );
+    let ranges: Vec<Range> = regions.iter().map(|region| region.range).collect();
+    assert_eq!(
+        ranges,
+        [
+            Range::new(0x10_0000, 0x10_1000),
+            Range::new(0x10_1000, 0x10_2000),
+            Range::new(0x10_4000, 0x10_5000),
+        ]
+    );
+    // 已去掉的部分不能再去掉, 跨两个区域也不行
+    assert_eq!(
+        regions.remove(0x10_1000, 0x2000),
+        Err(RegionError::NotReserved)
+    );
+    // 空隙被再次分配
+    assert_eq!(regions.allocate(0x2000, READ_WRITE), Ok(0x10_2000));
+    assert_eq!(
+        regions.remove(0x10_4000, 0x1000),
+        Ok(Range::new(0x10_4000, 0x10_5000))
+    );
+    assert_eq!(regions.find(0x10_4000), None);
+}
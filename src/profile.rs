@@ -0,0 +1,328 @@
+//! 中断处理函数和`IrqMutex`临界区的耗时统计, 以及按键从键盘中断到被任务取走的延迟分布, 单位为TSC周期
+//!
+//! 只在启用`profile` feature时记录, 关闭时`now`和各记录函数都是空函数
+
+use core::fmt;
+#[cfg(feature = "profile")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "profile")]
+use spin::Mutex;
+
+use crate::interrupts::PIC_1_OFFSET;
+use crate::shell::{self, CmdError};
+
+/// 开始计时的时刻, 未启用feature时不占空间
+#[derive(Debug, Clone, Copy)]
+pub struct Stamp {
+    #[cfg(feature = "profile")]
+    tsc: u64,
+}
+
+impl Stamp {
+    #[cfg(feature = "profile")]
+    fn elapsed(self) -> u64 {
+        crate::cpu::rdtsc().saturating_sub(self.tsc)
+    }
+}
+
+#[inline(always)]
+pub fn now() -> Stamp {
+    Stamp {
+        #[cfg(feature = "profile")]
+        tsc: crate::cpu::rdtsc(),
+    }
+}
+
+/// 一个中断向量的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VectorStats {
+    pub calls: u64,
+    pub total_cycles: u64,
+    pub max_cycles: u64,
+}
+
+#[cfg(feature = "profile")]
+struct VectorCounters {
+    calls: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+}
+
+#[cfg(feature = "profile")]
+static VECTORS: [VectorCounters; 256] = [const {
+    VectorCounters {
+        calls: AtomicU64::new(0),
+        total: AtomicU64::new(0),
+        max: AtomicU64::new(0),
+    }
+}; 256];
+
+/// 延迟直方图的桶数. 第`i`个桶是`[2^i, 2^(i+1))`个周期, 第0个桶包括0, 最后一个桶没有上界
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// 延迟的分布, 按2的幂分桶
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; LATENCY_BUCKETS],
+    pub max_cycles: u64,
+}
+
+impl LatencyHistogram {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// 至少`percent`%的样本不超过的周期数, 取所在桶的上界, 不超过最大值. 没有样本时为0
+    pub fn percentile(&self, percent: u64) -> u64 {
+        let target = (self.count() * percent).div_ceil(100);
+        let mut seen = 0;
+        for (i, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target && seen > 0 {
+                let upper = 1u64
+                    .checked_shl(i as u32 + 1)
+                    .map_or(u64::MAX, |bound| bound - 1);
+                return upper.min(self.max_cycles);
+            }
+        }
+        self.max_cycles
+    }
+}
+
+#[cfg(feature = "profile")]
+fn latency_bucket(cycles: u64) -> usize {
+    (cycles.checked_ilog2().unwrap_or(0) as usize).min(LATENCY_BUCKETS - 1)
+}
+
+#[cfg(feature = "profile")]
+static INPUT_LATENCY: [AtomicU64; LATENCY_BUCKETS] = [const { AtomicU64::new(0) }; LATENCY_BUCKETS];
+#[cfg(feature = "profile")]
+static INPUT_LATENCY_MAX: AtomicU64 = AtomicU64::new(0);
+
+// 最长的临界区: (周期数, 锁名). 先用原子变量过滤, 只有刷新纪录时才加锁
+#[cfg(feature = "profile")]
+static LONGEST_CYCLES: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "profile")]
+static LONGEST: Mutex<(u64, &'static str)> = Mutex::new((0, ""));
+
+/// 中断处理函数返回前调用, `start`是进入时的`now()`
+#[inline(always)]
+pub fn handler_exit(vector: u8, start: Stamp) {
+    #[cfg(feature = "profile")]
+    {
+        let cycles = start.elapsed();
+        let counters = &VECTORS[usize::from(vector)];
+        counters.calls.fetch_add(1, Ordering::Relaxed);
+        counters.total.fetch_add(cycles, Ordering::Relaxed);
+        counters.max.fetch_max(cycles, Ordering::Relaxed);
+    }
+    #[cfg(not(feature = "profile"))]
+    let _ = (vector, start);
+}
+
+/// 由`IrqMutexGuard`在恢复中断前调用, `start`是加锁前关中断的时刻
+#[cfg(feature = "profile")]
+#[inline(always)]
+pub(crate) fn critical_section_end(name: &'static str, start: Stamp) {
+    let cycles = start.elapsed();
+    if cycles > LONGEST_CYCLES.load(Ordering::Relaxed) {
+        // 调用者已关中断, 不会被本处理器上的中断打断
+        let mut longest = LONGEST.lock();
+        if cycles > longest.0 {
+            *longest = (cycles, name);
+            LONGEST_CYCLES.store(cycles, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 按键被任务取走时调用, `start`是键盘中断送出它时的`now()`
+#[inline(always)]
+pub fn input_handled(start: Stamp) {
+    #[cfg(feature = "profile")]
+    {
+        let cycles = start.elapsed();
+        INPUT_LATENCY[latency_bucket(cycles)].fetch_add(1, Ordering::Relaxed);
+        INPUT_LATENCY_MAX.fetch_max(cycles, Ordering::Relaxed);
+    }
+    #[cfg(not(feature = "profile"))]
+    let _ = start;
+}
+
+/// 是否启用了`profile` feature
+pub const fn enabled() -> bool {
+    cfg!(feature = "profile")
+}
+
+/// 指定向量的统计, 未启用feature时全为0
+pub fn vector_stats(vector: u8) -> VectorStats {
+    #[cfg(feature = "profile")]
+    {
+        let counters = &VECTORS[usize::from(vector)];
+        VectorStats {
+            calls: counters.calls.load(Ordering::Relaxed),
+            total_cycles: counters.total.load(Ordering::Relaxed),
+            max_cycles: counters.max.load(Ordering::Relaxed),
+        }
+    }
+    #[cfg(not(feature = "profile"))]
+    {
+        let _ = vector;
+        VectorStats::default()
+    }
+}
+
+/// 关中断时间最长的临界区所属的锁和周期数
+pub fn longest_critical_section() -> Option<(&'static str, u64)> {
+    #[cfg(feature = "profile")]
+    {
+        let (cycles, name) =
+            x86_64::instructions::interrupts::without_interrupts(|| *LONGEST.lock());
+        (cycles != 0).then_some((name, cycles))
+    }
+    #[cfg(not(feature = "profile"))]
+    None
+}
+
+/// 按键从键盘中断到被任务取走的延迟, 未启用feature时为空
+pub fn input_latency() -> LatencyHistogram {
+    #[cfg(feature = "profile")]
+    {
+        LatencyHistogram {
+            buckets: core::array::from_fn(|i| INPUT_LATENCY[i].load(Ordering::Relaxed)),
+            max_cycles: INPUT_LATENCY_MAX.load(Ordering::Relaxed),
+        }
+    }
+    #[cfg(not(feature = "profile"))]
+    LatencyHistogram::default()
+}
+
+/// 清空所有统计
+pub fn reset() {
+    #[cfg(feature = "profile")]
+    {
+        for counters in &VECTORS {
+            counters.calls.store(0, Ordering::Relaxed);
+            counters.total.store(0, Ordering::Relaxed);
+            counters.max.store(0, Ordering::Relaxed);
+        }
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            *LONGEST.lock() = (0, "");
+            LONGEST_CYCLES.store(0, Ordering::Relaxed);
+        });
+        for bucket in &INPUT_LATENCY {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        INPUT_LATENCY_MAX.store(0, Ordering::Relaxed);
+    }
+}
+
+/// 输出处理过中断的向量和最长的临界区
+pub fn report(out: &mut dyn fmt::Write) -> fmt::Result {
+    if !enabled() {
+        return writeln!(out, "profiling disabled, build with --features profile");
+    }
+    writeln!(
+        out,
+        "{:<10}{:<12}{:<12}max cycles",
+        "vector", "calls", "avg cycles"
+    )?;
+    for vector in 0..=u8::MAX {
+        let stats = vector_stats(vector);
+        if stats.calls == 0 {
+            continue;
+        }
+        writeln!(
+            out,
+            "{:<3} {:<6}{:<12}{:<12}{}",
+            vector,
+            vector_label(vector),
+            stats.calls,
+            stats.total_cycles / stats.calls,
+            stats.max_cycles
+        )?;
+    }
+    match longest_critical_section() {
+        Some((name, cycles)) => writeln!(
+            out,
+            "longest critical section: {} ({} cycles)",
+            name, cycles
+        )?,
+        None => writeln!(out, "longest critical section: none")?,
+    }
+    let latency = input_latency();
+    match latency.count() {
+        0 => writeln!(out, "input latency: none"),
+        keys => writeln!(
+            out,
+            "input latency: {} keys, p50 {} p99 {} max {} cycles",
+            keys,
+            latency.percentile(50),
+            latency.percentile(99),
+            latency.max_cycles
+        ),
+    }
+}
+
+// PIC的向量显示IRQ号
+fn vector_label(vector: u8) -> &'static str {
+    match vector.checked_sub(PIC_1_OFFSET) {
+        Some(irq) if irq < 16 => IRQ_LABELS[usize::from(irq)],
+        _ => "",
+    }
+}
+
+const IRQ_LABELS: [&str; 16] = [
+    "IRQ0", "IRQ1", "IRQ2", "IRQ3", "IRQ4", "IRQ5", "IRQ6", "IRQ7", "IRQ8", "IRQ9", "IRQ10",
+    "IRQ11", "IRQ12", "IRQ13", "IRQ14", "IRQ15",
+];
+
+/// 注册`prof`命令
+pub fn register_commands() {
+    shell::register_command(
+        "prof",
+        "prof [reset]: interrupt, lock and input latency timing",
+        prof_command,
+    )
+    .expect("duplicate profile command");
+}
+
+fn prof_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    match args {
+        [] => report(out)?,
+        ["reset"] => reset(),
+        _ => return Err(CmdError::Usage("prof [reset]")),
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_profile_report() {
+    let mut out = alloc::string::String::new();
+    report(&mut out).unwrap();
+    if !enabled() {
+        assert!(out.contains("disabled"));
+        return;
+    }
+    // 测试运行时时钟中断一直在发生
+    let timer = vector_stats(PIC_1_OFFSET);
+    assert!(timer.calls > 0 && timer.max_cycles > 0);
+    assert!(timer.total_cycles >= timer.max_cycles);
+    assert!(out.contains("IRQ0"));
+    assert!(longest_critical_section().is_some());
+}
+
+#[test_case]
+fn test_latency_percentiles() {
+    let mut histogram = LatencyHistogram::default();
+    assert_eq!(histogram.percentile(99), 0);
+    // 90个样本在[16, 32), 10个在[1024, 2048)
+    histogram.buckets[4] = 90;
+    histogram.buckets[10] = 10;
+    histogram.max_cycles = 1500;
+    assert_eq!(histogram.count(), 100);
+    assert_eq!(histogram.percentile(50), 31);
+    assert_eq!(histogram.percentile(90), 31);
+    assert_eq!(histogram.percentile(91), 1500);
+    assert_eq!(histogram.percentile(100), 1500);
+}
@@ -0,0 +1,689 @@
+use alloc::format;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::io::{HardwareBus, PortBus};
+use crate::selftest::{self, Check, Outcome};
+use crate::time::{self, Elapsed};
+
+const DATA_PORT: u16 = 0x60;
+// 读为状态寄存器, 写为命令寄存器
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+// 状态寄存器
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// 配置字节
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT1_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+
+// 控制器命令
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_ENABLE_PORT2: u8 = 0xA8;
+const CMD_TEST_PORT2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+// 下一个写入数据端口的字节放入输出缓冲区, 如同第一个端口上的设备发送
+const CMD_WRITE_PORT1_OUTPUT: u8 = 0xD2;
+// 下一个写入数据端口的字节发往第二个端口
+const CMD_WRITE_PORT2: u8 = 0xD4;
+// 拉低CPU复位线
+const CMD_PULSE_RESET: u8 = 0xFE;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const INTERFACE_TEST_PASSED: u8 = 0x00;
+
+// 设备命令和应答
+const DEVICE_RESET: u8 = 0xFF;
+const DEVICE_ACK: u8 = 0xFA;
+const DEVICE_RESEND: u8 = 0xFE;
+const DEVICE_SELF_TEST_PASSED: u8 = 0xAA;
+
+const KEYBOARD_SET_LEDS: u8 = 0xED;
+const KEYBOARD_SET_TYPEMATIC: u8 = 0xF3;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+
+const TIMEOUT_MS: u64 = 100;
+// 设备复位自检较慢
+const RESET_TIMEOUT_MS: u64 = 1000;
+// 收到0xFE后最多重发的次数
+const MAX_RESENDS: u8 = 3;
+// 等待输入缓冲区为空的最大轮询次数, 键盘命令可能在中断中发送, 不能依赖时钟
+const INPUT_SPIN_LIMIT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    Timeout,
+    SelfTestFailed(u8),
+    InterfaceTestFailed(u8),
+    UnexpectedResponse(u8),
+    TooManyResends,
+    QueueFull,
+    InvalidArgument,
+    NotInitialized,
+}
+
+impl From<Elapsed> for Ps2Error {
+    fn from(_: Elapsed) -> Self {
+        Ps2Error::Timeout
+    }
+}
+
+// 发往键盘的命令, 命令字节和参数字节都要等待0xFA应答
+#[derive(Debug, Clone, Copy)]
+struct DeviceCommand {
+    code: u8,
+    arg: Option<u8>,
+}
+
+const QUEUE_LEN: usize = 4;
+
+/// 8042控制器, 通过`B`访问它的两个端口
+pub struct Controller<B> {
+    bus: B,
+    dual_channel: bool,
+    mouse_enabled: bool,
+    // 待发送的键盘命令, 由键盘中断收到的应答驱动
+    queue: [DeviceCommand; QUEUE_LEN],
+    head: usize,
+    len: usize,
+    arg_sent: bool,
+    resends: u8,
+    failed_commands: usize,
+}
+
+impl<B: PortBus> Controller<B> {
+    pub const fn new(bus: B) -> Self {
+        const EMPTY: DeviceCommand = DeviceCommand { code: 0, arg: None };
+        Controller {
+            bus,
+            dual_channel: false,
+            mouse_enabled: false,
+            queue: [EMPTY; QUEUE_LEN],
+            head: 0,
+            len: 0,
+            arg_sent: false,
+            resends: 0,
+            failed_commands: 0,
+        }
+    }
+
+    /// 是否存在第二个端口(鼠标)
+    pub fn dual_channel(&self) -> bool {
+        self.dual_channel
+    }
+
+    /// 鼠标是否已启用数据上报
+    pub fn mouse_enabled(&self) -> bool {
+        self.mouse_enabled
+    }
+
+    /// 标准初始化流程, 结束后第一个端口(以及存在时的鼠标)的中断被打开
+    pub fn initialize(&mut self) -> Result<(), Ps2Error> {
+        // 禁用设备, 防止初始化过程中设备发送数据
+        self.command(CMD_DISABLE_PORT1)?;
+        self.command(CMD_DISABLE_PORT2)?;
+        self.flush_output();
+
+        // 关闭中断, 保留扫描码转换(解码器使用扫描码集1)
+        let mut config = self.read_config()?;
+        self.dual_channel = config & CONFIG_PORT2_CLOCK_DISABLED != 0;
+        config &= !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ);
+        self.write_config(config)?;
+
+        let result = self.command_with_response(CMD_SELF_TEST)?;
+        if result != SELF_TEST_PASSED {
+            return Err(Ps2Error::SelfTestFailed(result));
+        }
+        // 部分控制器自检后会复位配置
+        self.write_config(config)?;
+
+        // 启用第二个端口后时钟位被清除才说明存在第二个端口
+        if self.dual_channel {
+            self.command(CMD_ENABLE_PORT2)?;
+            self.dual_channel = self.read_config()? & CONFIG_PORT2_CLOCK_DISABLED == 0;
+            if self.dual_channel {
+                self.command(CMD_DISABLE_PORT2)?;
+            }
+        }
+
+        let result = self.command_with_response(CMD_TEST_PORT1)?;
+        if result != INTERFACE_TEST_PASSED {
+            return Err(Ps2Error::InterfaceTestFailed(result));
+        }
+        if self.dual_channel {
+            let result = self.command_with_response(CMD_TEST_PORT2)?;
+            self.dual_channel = result == INTERFACE_TEST_PASSED;
+        }
+
+        // 中断打开前复位键盘, 应答由这里轮询读取
+        self.command(CMD_ENABLE_PORT1)?;
+        self.reset_keyboard()?;
+        config |= CONFIG_PORT1_IRQ;
+        config &= !CONFIG_PORT1_CLOCK_DISABLED;
+
+        // 鼠标启用失败不影响键盘
+        if self.dual_channel {
+            self.mouse_enabled = self.enable_mouse().is_ok();
+            if self.mouse_enabled {
+                config |= CONFIG_PORT2_IRQ;
+                config &= !CONFIG_PORT2_CLOCK_DISABLED;
+            } else {
+                self.command(CMD_DISABLE_PORT2)?;
+            }
+        }
+
+        self.write_config(config)
+    }
+
+    /// 运行控制器自检, 无论结果如何都写回原来的配置. 调用者需要先屏蔽键盘和鼠标的中断,
+    /// 否则设备的数据可能被当作应答读走
+    pub fn self_test(&mut self) -> Result<(), Ps2Error> {
+        self.flush_output();
+        let config = self.read_config()?;
+        self.command(CMD_DISABLE_PORT1)?;
+        self.command(CMD_DISABLE_PORT2)?;
+        self.flush_output();
+        let result = self.command_with_response(CMD_SELF_TEST);
+        // 配置中的时钟位同时恢复两个端口
+        self.write_config(config)?;
+        match result? {
+            SELF_TEST_PASSED => Ok(()),
+            result => Err(Ps2Error::SelfTestFailed(result)),
+        }
+    }
+
+    fn enable_mouse(&mut self) -> Result<(), Ps2Error> {
+        self.command(CMD_ENABLE_PORT2)?;
+        self.send_mouse_sync(MOUSE_ENABLE_REPORTING)
+    }
+
+    fn read_status(&mut self) -> u8 {
+        self.bus.read_u8(STATUS_COMMAND_PORT)
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.bus.read_u8(DATA_PORT)
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.bus.write_u8(DATA_PORT, value);
+    }
+
+    fn write_command(&mut self, value: u8) {
+        self.bus.write_u8(STATUS_COMMAND_PORT, value);
+    }
+
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        time::wait_until(TIMEOUT_MS, || self.read_status() & STATUS_INPUT_FULL == 0)?;
+        Ok(())
+    }
+
+    fn read_response(&mut self, timeout_ms: u64) -> Result<u8, Ps2Error> {
+        time::wait_until(timeout_ms, || self.read_status() & STATUS_OUTPUT_FULL != 0)?;
+        Ok(self.read_data())
+    }
+
+    fn flush_output(&mut self) {
+        for _ in 0..16 {
+            if self.read_status() & STATUS_OUTPUT_FULL == 0 {
+                break;
+            }
+            self.read_data();
+        }
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        self.write_command(command);
+        Ok(())
+    }
+
+    fn command_with_response(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.command(command)?;
+        self.read_response(TIMEOUT_MS)
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.command_with_response(CMD_READ_CONFIG)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.command(CMD_WRITE_CONFIG)?;
+        self.wait_input_empty()?;
+        self.write_data(config);
+        Ok(())
+    }
+
+    // 同步发送一个字节给键盘并等待应答, 收到0xFE时重发
+    fn send_device_sync(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..=MAX_RESENDS {
+            self.wait_input_empty()?;
+            self.write_data(byte);
+            match self.read_response(TIMEOUT_MS)? {
+                DEVICE_ACK => return Ok(()),
+                DEVICE_RESEND => continue,
+                other => return Err(Ps2Error::UnexpectedResponse(other)),
+            }
+        }
+        Err(Ps2Error::TooManyResends)
+    }
+
+    // 通过0xD4前缀同步发送一个字节给鼠标并等待应答
+    fn send_mouse_sync(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..=MAX_RESENDS {
+            self.command(CMD_WRITE_PORT2)?;
+            self.wait_input_empty()?;
+            self.write_data(byte);
+            match self.read_response(TIMEOUT_MS)? {
+                DEVICE_ACK => return Ok(()),
+                DEVICE_RESEND => continue,
+                other => return Err(Ps2Error::UnexpectedResponse(other)),
+            }
+        }
+        Err(Ps2Error::TooManyResends)
+    }
+
+    /// 让控制器把`byte`当作键盘发来的字节放入输出缓冲区, 会产生键盘中断
+    pub fn inject_keyboard_byte(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.command(CMD_WRITE_PORT1_OUTPUT)?;
+        self.wait_input_empty()?;
+        self.write_data(byte);
+        Ok(())
+    }
+
+    fn reset_keyboard(&mut self) -> Result<(), Ps2Error> {
+        self.send_device_sync(DEVICE_RESET)?;
+        match self.read_response(RESET_TIMEOUT_MS)? {
+            DEVICE_SELF_TEST_PASSED => Ok(()),
+            other => Err(Ps2Error::UnexpectedResponse(other)),
+        }
+    }
+
+    /// 将键盘命令加入队列, 队列为空时立即发送
+    fn enqueue(&mut self, command: DeviceCommand) -> Result<(), Ps2Error> {
+        if self.len == QUEUE_LEN {
+            return Err(Ps2Error::QueueFull);
+        }
+        self.queue[(self.head + self.len) % QUEUE_LEN] = command;
+        self.len += 1;
+        if self.len == 1 {
+            self.send_current();
+        }
+        Ok(())
+    }
+
+    fn current_byte(&self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let command = self.queue[self.head];
+        if self.arg_sent {
+            command.arg
+        } else {
+            Some(command.code)
+        }
+    }
+
+    fn send_current(&mut self) {
+        if let Some(byte) = self.current_byte() {
+            for _ in 0..INPUT_SPIN_LIMIT {
+                if self.read_status() & STATUS_INPUT_FULL == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            self.write_data(byte);
+        }
+    }
+
+    fn pop_command(&mut self) {
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.len -= 1;
+        self.arg_sent = false;
+        self.resends = 0;
+    }
+
+    /// 处理键盘发来的字节, 是命令应答时返回true, 否则应交给扫描码解码器
+    pub fn handle_response(&mut self, byte: u8) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+
+        match byte {
+            DEVICE_ACK => {
+                let command = self.queue[self.head];
+                if !self.arg_sent && command.arg.is_some() {
+                    self.arg_sent = true;
+                    self.resends = 0;
+                } else {
+                    self.pop_command();
+                }
+                self.send_current();
+                true
+            }
+            DEVICE_RESEND => {
+                self.resends += 1;
+                if self.resends > MAX_RESENDS {
+                    // 放弃这条命令
+                    self.failed_commands += 1;
+                    self.pop_command();
+                }
+                self.send_current();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 是否没有待应答的键盘命令
+    pub fn is_idle(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn set_leds(&mut self, caps: bool, num: bool, scroll: bool) -> Result<(), Ps2Error> {
+        let mask = (scroll as u8) | (num as u8) << 1 | (caps as u8) << 2;
+        self.enqueue(DeviceCommand {
+            code: KEYBOARD_SET_LEDS,
+            arg: Some(mask),
+        })
+    }
+
+    /// `rate`为0(30次/秒)~0x1F(2次/秒), `delay`为0(250ms)~3(1000ms)
+    pub fn set_typematic(&mut self, rate: u8, delay: u8) -> Result<(), Ps2Error> {
+        if rate > 0x1F || delay > 3 {
+            return Err(Ps2Error::InvalidArgument);
+        }
+        self.enqueue(DeviceCommand {
+            code: KEYBOARD_SET_TYPEMATIC,
+            arg: Some(delay << 5 | rate),
+        })
+    }
+}
+
+static CONTROLLER: Mutex<Controller<HardwareBus>> =
+    Mutex::new(Controller::new(unsafe { HardwareBus::new() }));
+// 初始化完成前中断处理函数不访问CONTROLLER, 避免与持有锁的初始化流程死锁
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Caps/Num/Scroll Lock的当前状态
+const LOCK_SCROLL: u8 = 1 << 0;
+const LOCK_NUM: u8 = 1 << 1;
+const LOCK_CAPS: u8 = 1 << 2;
+static LOCK_KEYS: AtomicU8 = AtomicU8::new(0);
+
+/// 通过控制器复位CPU, 不获取控制器的锁, 也不依赖时钟中断
+pub(crate) fn pulse_cpu_reset() {
+    let mut bus = unsafe { HardwareBus::new() };
+    for _ in 0..INPUT_SPIN_LIMIT {
+        if bus.read_u8(STATUS_COMMAND_PORT) & STATUS_INPUT_FULL == 0 {
+            break;
+        }
+    }
+    bus.write_u8(STATUS_COMMAND_PORT, CMD_PULSE_RESET);
+}
+
+/// 初始化PS/2控制器, 依赖时钟中断实现超时, 需在开启中断后调用
+pub fn init() -> Result<(), Ps2Error> {
+    CONTROLLER.lock().initialize()?;
+    INITIALIZED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// 鼠标是否可用
+pub fn mouse_enabled() -> bool {
+    with_controller(|controller| controller.mouse_enabled()).unwrap_or(false)
+}
+
+fn with_controller<R>(f: impl FnOnce(&mut Controller<HardwareBus>) -> R) -> Result<R, Ps2Error> {
+    if !INITIALIZED.load(Ordering::SeqCst) {
+        return Err(Ps2Error::NotInitialized);
+    }
+    Ok(interrupts::without_interrupts(|| f(&mut CONTROLLER.lock())))
+}
+
+/// 设置键盘指示灯
+pub fn keyboard_set_leds(caps: bool, num: bool, scroll: bool) -> Result<(), Ps2Error> {
+    with_controller(|controller| controller.set_leds(caps, num, scroll))?
+}
+
+/// 设置键盘重复速率和延迟
+pub fn set_typematic(rate: u8, delay: u8) -> Result<(), Ps2Error> {
+    with_controller(|controller| controller.set_typematic(rate, delay))?
+}
+
+/// 模拟键盘发送一个扫描码, 由正常的键盘中断处理, 用于测试
+pub fn inject_scancode(scancode: u8) -> Result<(), Ps2Error> {
+    with_controller(|controller| controller.inject_keyboard_byte(scancode))?
+}
+
+/// 登记控制器的自检, 超时依赖时钟中断
+pub fn register_selftests() {
+    static CONTROLLER_TEST: Check = Check::new("ps2", controller_selftest).requires(&["timer"]);
+    selftest::register(&CONTROLLER_TEST).expect("duplicate PS/2 selftest");
+}
+
+fn controller_selftest() -> Outcome {
+    if !INITIALIZED.load(Ordering::SeqCst) {
+        return Outcome::Skipped("controller not initialized".into());
+    }
+    if !keyboard_idle() {
+        return Outcome::Skipped("a keyboard command is pending".into());
+    }
+    // 屏蔽键盘和鼠标中断而不是关中断, 等待应答的超时需要时钟中断
+    let unmasked = [1, 12].map(|irq| (irq, !crate::interrupts::irq_masked(irq)));
+    for (irq, _) in unmasked {
+        crate::interrupts::mask_irq(irq);
+    }
+    let result = CONTROLLER.lock().self_test();
+    for (irq, unmasked) in unmasked {
+        if unmasked {
+            crate::interrupts::unmask_irq(irq);
+        }
+    }
+    match result {
+        Ok(()) => Outcome::Pass,
+        Err(err) => Outcome::Fail(format!("controller self-test failed: {:?}", err)),
+    }
+}
+
+/// 是否没有待应答的键盘命令
+pub fn keyboard_idle() -> bool {
+    with_controller(|controller| controller.is_idle()).unwrap_or(true)
+}
+
+/// 由键盘中断调用, 字节是命令应答时返回true
+pub fn handle_keyboard_response(byte: u8) -> bool {
+    with_controller(|controller| controller.handle_response(byte)).unwrap_or(false)
+}
+
+/// 由键盘中断调用, 锁定键按下时切换状态并更新指示灯
+pub fn update_lock_leds(event: &KeyEvent) {
+    if event.state != KeyState::Down {
+        return;
+    }
+    let bit = match event.code {
+        KeyCode::CapsLock => LOCK_CAPS,
+        KeyCode::NumpadLock => LOCK_NUM,
+        KeyCode::ScrollLock => LOCK_SCROLL,
+        _ => return,
+    };
+    let state = LOCK_KEYS.fetch_xor(bit, Ordering::SeqCst) ^ bit;
+    // 队列满或控制器未初始化时只是指示灯不同步, 不影响输入
+    let _ = keyboard_set_leds(
+        state & LOCK_CAPS != 0,
+        state & LOCK_NUM != 0,
+        state & LOCK_SCROLL != 0,
+    );
+}
+
+// 测试用的总线: 数据端口有待读数据时状态寄存器的输出缓冲区满, 输入缓冲区总是空的
+#[cfg(test)]
+fn mock_bus(stale: &[u32]) -> crate::io::mock::MockBus {
+    let mut bus = crate::io::mock::MockBus::new();
+    let output_full = u32::from(STATUS_OUTPUT_FULL);
+    bus.respond(DATA_PORT, stale)
+        .ready_flag(STATUS_COMMAND_PORT, DATA_PORT, output_full);
+    bus
+}
+
+#[cfg(test)]
+fn command(value: u8) -> crate::io::mock::PortWrite {
+    crate::io::mock::PortWrite::U8(STATUS_COMMAND_PORT, value)
+}
+
+#[cfg(test)]
+fn data(value: u8) -> crate::io::mock::PortWrite {
+    crate::io::mock::PortWrite::U8(DATA_PORT, value)
+}
+
+#[test_case]
+fn test_init_handshake() {
+    let mut bus = mock_bus(&[0x1C]);
+    bus.reply_after(command(CMD_READ_CONFIG), DATA_PORT, &[0x61])
+        .reply_after(command(CMD_SELF_TEST), DATA_PORT, &[SELF_TEST_PASSED.into()])
+        .reply_after(command(CMD_READ_CONFIG), DATA_PORT, &[0x40])
+        .reply_after(command(CMD_TEST_PORT1), DATA_PORT, &[0])
+        .reply_after(command(CMD_TEST_PORT2), DATA_PORT, &[0])
+        .reply_after(
+            data(DEVICE_RESET),
+            DATA_PORT,
+            &[DEVICE_ACK.into(), DEVICE_SELF_TEST_PASSED.into()],
+        )
+        .reply_after(data(MOUSE_ENABLE_REPORTING), DATA_PORT, &[DEVICE_ACK.into()]);
+    let mut controller = Controller::new(bus);
+    assert_eq!(controller.initialize(), Ok(()));
+    assert!(controller.dual_channel());
+    assert!(controller.mouse_enabled());
+    // 最先读出残留的扫描码, 之后的写入顺序与标准流程一致
+    assert_eq!(
+        controller.bus.writes(),
+        [
+            command(CMD_DISABLE_PORT1),
+            command(CMD_DISABLE_PORT2),
+            command(CMD_READ_CONFIG),
+            command(CMD_WRITE_CONFIG),
+            data(0x60),
+            command(CMD_SELF_TEST),
+            command(CMD_WRITE_CONFIG),
+            data(0x60),
+            command(CMD_ENABLE_PORT2),
+            command(CMD_READ_CONFIG),
+            command(CMD_DISABLE_PORT2),
+            command(CMD_TEST_PORT1),
+            command(CMD_TEST_PORT2),
+            command(CMD_ENABLE_PORT1),
+            data(DEVICE_RESET),
+            command(CMD_ENABLE_PORT2),
+            command(CMD_WRITE_PORT2),
+            data(MOUSE_ENABLE_REPORTING),
+            command(CMD_WRITE_CONFIG),
+            data(0x43),
+        ]
+    );
+    assert!(controller.bus.finished());
+}
+
+#[test_case]
+fn test_self_test_restores_config() {
+    let mut bus = mock_bus(&[0x1C]);
+    bus.reply_after(command(CMD_READ_CONFIG), DATA_PORT, &[0x47])
+        .reply_after(command(CMD_SELF_TEST), DATA_PORT, &[0xFC]);
+    let mut controller = Controller::new(bus);
+    assert_eq!(controller.self_test(), Err(Ps2Error::SelfTestFailed(0xFC)));
+    // 先读走残留的扫描码, 自检失败也写回了原来的配置
+    assert_eq!(
+        controller.bus.writes(),
+        [
+            command(CMD_READ_CONFIG),
+            command(CMD_DISABLE_PORT1),
+            command(CMD_DISABLE_PORT2),
+            command(CMD_SELF_TEST),
+            command(CMD_WRITE_CONFIG),
+            data(0x47),
+        ]
+    );
+    assert!(controller.bus.finished());
+}
+
+#[test_case]
+fn test_inject_keyboard_byte() {
+    let mut controller = Controller::new(mock_bus(&[]));
+    controller.inject_keyboard_byte(0x1E).unwrap();
+    assert_eq!(
+        controller.bus.writes(),
+        [command(CMD_WRITE_PORT1_OUTPUT), data(0x1E)]
+    );
+}
+
+#[test_case]
+fn test_init_self_test_failure() {
+    let mut bus = mock_bus(&[]);
+    bus.reply_after(command(CMD_READ_CONFIG), DATA_PORT, &[0x01])
+        .reply_after(command(CMD_SELF_TEST), DATA_PORT, &[0xFC]);
+    let mut controller = Controller::new(bus);
+    assert_eq!(controller.initialize(), Err(Ps2Error::SelfTestFailed(0xFC)));
+    assert!(!controller.dual_channel());
+    assert_eq!(
+        controller.bus.writes(),
+        [
+            command(CMD_DISABLE_PORT1),
+            command(CMD_DISABLE_PORT2),
+            command(CMD_READ_CONFIG),
+            command(CMD_WRITE_CONFIG),
+            data(0x00),
+            command(CMD_SELF_TEST),
+        ]
+    );
+}
+
+#[test_case]
+fn test_keyboard_command_resend() {
+    let mut controller = Controller::new(mock_bus(&[]));
+    // 空闲时的普通扫描码不被当作应答
+    assert!(!controller.handle_response(DEVICE_ACK));
+
+    controller.set_leds(true, false, false).unwrap();
+    assert!(controller.handle_response(DEVICE_RESEND));
+    assert!(controller.handle_response(DEVICE_ACK));
+    assert!(!controller.is_idle());
+    assert!(controller.handle_response(DEVICE_ACK));
+    assert!(controller.is_idle());
+    assert!(!controller.handle_response(0x1C));
+    assert_eq!(
+        controller.bus.writes(),
+        [data(KEYBOARD_SET_LEDS), data(KEYBOARD_SET_LEDS), data(LOCK_CAPS)]
+    );
+}
+
+#[test_case]
+fn test_keyboard_command_gives_up() {
+    let mut controller = Controller::new(mock_bus(&[]));
+    assert_eq!(controller.set_typematic(0x20, 0), Err(Ps2Error::InvalidArgument));
+
+    controller.set_typematic(0x0B, 1).unwrap();
+    for _ in 0..=MAX_RESENDS {
+        assert!(controller.handle_response(DEVICE_RESEND));
+    }
+    assert!(controller.is_idle());
+    assert_eq!(controller.failed_commands, 1);
+    assert_eq!(controller.bus.writes(), [data(KEYBOARD_SET_TYPEMATIC); 4]);
+}
+
+#[test_case]
+fn test_caps_lock_leds_do_not_hang() {
+    keyboard_set_leds(true, false, false).unwrap();
+    time::wait_until(500, keyboard_idle).expect("keyboard did not acknowledge set_leds");
+    keyboard_set_leds(false, false, false).unwrap();
+    time::wait_until(500, keyboard_idle).expect("keyboard did not acknowledge set_leds");
+}
@@ -0,0 +1,296 @@
+use alloc::vec::Vec;
+
+use spin::Once;
+
+pub mod tar;
+
+pub use tar::TarError;
+
+// 构建时由build.rs把initrd目录打包成ustar归档
+#[cfg(feature = "initrd")]
+static INITRD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+#[cfg(not(feature = "initrd"))]
+static INITRD: &[u8] = &[];
+
+enum NodeKind<'a> {
+    File(&'a [u8]),
+    Directory(Vec<Node<'a>>),
+}
+
+struct Node<'a> {
+    name: &'a str,
+    kind: NodeKind<'a>,
+}
+
+impl<'a> Node<'a> {
+    fn directory(name: &'a str) -> Node<'a> {
+        Node {
+            name,
+            kind: NodeKind::Directory(Vec::new()),
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&Node<'a>> {
+        match &self.kind {
+            NodeKind::Directory(children) => children.iter().find(|child| child.name == name),
+            NodeKind::File(_) => None,
+        }
+    }
+
+    // 取得名为`name`的子目录, 不存在时创建; 同名的文件被目录替换
+    fn child_directory(&mut self, name: &'a str) -> &mut Node<'a> {
+        let NodeKind::Directory(children) = &mut self.kind else {
+            unreachable!("parent is always a directory");
+        };
+        let index = match children.iter().position(|child| child.name == name) {
+            Some(index) => {
+                if let NodeKind::File(_) = children[index].kind {
+                    children[index] = Node::directory(name);
+                }
+                index
+            }
+            None => {
+                children.push(Node::directory(name));
+                children.len() - 1
+            }
+        };
+        &mut children[index]
+    }
+
+    fn insert_file(&mut self, name: &'a str, data: &'a [u8]) {
+        let NodeKind::Directory(children) = &mut self.kind else {
+            unreachable!("parent is always a directory");
+        };
+        let file = Node {
+            name,
+            kind: NodeKind::File(data),
+        };
+        // 归档中后出现的同名项覆盖前面的
+        match children.iter_mut().find(|child| child.name == name) {
+            Some(existing) => *existing = file,
+            None => children.push(file),
+        }
+    }
+}
+
+/// 从ustar归档构建的只读文件系统, 文件内容直接引用归档中的数据
+pub struct Ramfs<'a> {
+    root: Node<'a>,
+}
+
+impl<'a> Ramfs<'a> {
+    /// 解析归档, 自动创建没有单独目录项的中间目录, 跳过链接等不支持的类型
+    pub fn parse(archive: &'a [u8]) -> Result<Ramfs<'a>, TarError> {
+        let mut root = Node::directory("");
+        for entry in tar::entries(archive) {
+            let entry = entry?;
+            let components: Vec<&str> = entry.components().collect();
+            let Some((&last, parents)) = components.split_last() else {
+                continue;
+            };
+            let parent = parents
+                .iter()
+                .fold(&mut root, |dir, &name| dir.child_directory(name));
+            match entry.kind {
+                tar::EntryKind::File => parent.insert_file(last, entry.data),
+                tar::EntryKind::Directory => {
+                    parent.child_directory(last);
+                }
+                tar::EntryKind::Other(_) => {}
+            }
+        }
+        Ok(Ramfs { root })
+    }
+
+    /// 打开文件或目录, 忽略开头的"/"和路径中的"./", 拒绝".."
+    pub fn open(&self, path: &str) -> Option<File<'_>> {
+        let mut node = &self.root;
+        for part in path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            if part == ".." {
+                return None;
+            }
+            node = node.child(part)?;
+        }
+        Some(File { node })
+    }
+}
+
+/// 打开的文件或目录
+#[derive(Clone, Copy)]
+pub struct File<'a> {
+    node: &'a Node<'a>,
+}
+
+impl<'a> File<'a> {
+    pub fn name(&self) -> &'a str {
+        self.node.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self.node.kind, NodeKind::Directory(_))
+    }
+
+    /// 文件的字节数, 目录为0
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 文件的全部内容, 目录为空
+    pub fn as_bytes(&self) -> &'a [u8] {
+        match self.node.kind {
+            NodeKind::File(data) => data,
+            NodeKind::Directory(_) => &[],
+        }
+    }
+
+    /// 从`offset`开始读取到`buf`, 返回读取的字节数, 到达末尾时返回0
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let data = self.as_bytes();
+        let Some(rest) = data.get(offset..) else {
+            return 0;
+        };
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        len
+    }
+
+    /// 目录中的各项, 按归档中出现的顺序; 文件没有目录项
+    pub fn entries(&self) -> impl Iterator<Item = DirEntry<'a>> {
+        let node: &'a Node<'a> = self.node;
+        let children: &'a [Node<'a>] = match &node.kind {
+            NodeKind::Directory(children) => children,
+            NodeKind::File(_) => &[],
+        };
+        children.iter().map(|node| DirEntry {
+            name: node.name,
+            is_dir: matches!(node.kind, NodeKind::Directory(_)),
+            len: File { node }.len(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirEntry<'a> {
+    pub name: &'a str,
+    pub is_dir: bool,
+    pub len: usize,
+}
+
+static RAMFS: Once<Ramfs<'static>> = Once::new();
+
+/// 解析内嵌的initrd, 需要堆. 没有启用`initrd` feature时文件系统为空
+pub fn init() -> Result<(), TarError> {
+    let ramfs = Ramfs::parse(INITRD)?;
+    RAMFS.call_once(|| ramfs);
+    Ok(())
+}
+
+/// 内嵌的initrd, `init`之前为None
+pub fn get() -> Option<&'static Ramfs<'static>> {
+    RAMFS.get()
+}
+
+/// 在initrd中打开`path`, `init`之前或找不到时返回None
+pub fn open(path: &str) -> Option<File<'static>> {
+    RAMFS.get()?.open(path)
+}
+
+#[cfg(test)]
+pub(crate) static FIXTURE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ramfs-fixture.tar"));
+
+#[test_case]
+fn test_fixture_tree() {
+    let fs = Ramfs::parse(FIXTURE).unwrap();
+    let root = fs.open("/").unwrap();
+    assert!(root.is_dir());
+    let names: Vec<_> = root.entries().map(|entry| entry.name).collect();
+    assert_eq!(names, ["docs", "empty", "data", "long"]);
+
+    let readme = fs.open("docs/readme.txt").unwrap();
+    assert!(!readme.is_dir());
+    assert_eq!(readme.as_bytes(), b"ramfs fixture\n");
+    // 中间目录没有单独的目录项
+    let note = fs.open("/docs/nested/deep/note.txt").unwrap();
+    assert_eq!(note.as_bytes(), b"deep\n");
+    let nested: Vec<_> = fs.open("docs/nested").unwrap().entries().collect();
+    assert_eq!(
+        nested,
+        [DirEntry {
+            name: "deep",
+            is_dir: true,
+            len: 0
+        }]
+    );
+    // prefix字段中的路径
+    let long = fs.open("long").unwrap().entries().next().unwrap();
+    assert_eq!(long.name.len(), 120);
+    // 链接被跳过
+    assert!(fs.open("docs/link").is_none());
+}
+
+#[test_case]
+fn test_path_lookup() {
+    let fs = Ramfs::parse(FIXTURE).unwrap();
+    for path in [
+        "docs/readme.txt",
+        "./docs/readme.txt",
+        "/./docs//readme.txt",
+        "docs/./readme.txt",
+    ] {
+        assert_eq!(fs.open(path).map(|file| file.len()), Some(14), "{}", path);
+    }
+    assert!(fs.open("docs/../empty").is_none());
+    assert!(fs.open("..").is_none());
+    assert!(fs.open("missing").is_none());
+    assert!(fs.open("docs/readme.txt/more").is_none());
+}
+
+#[test_case]
+fn test_read_at() {
+    let fs = Ramfs::parse(FIXTURE).unwrap();
+    let empty = fs.open("empty").unwrap();
+    assert!(empty.is_empty());
+    assert_eq!(empty.read_at(0, &mut [0; 16]), 0);
+
+    let big = fs.open("data/big.bin").unwrap();
+    assert_eq!(big.len(), 1300);
+    // 跨过第一个512字节块的边界
+    let mut buf = [0u8; 100];
+    assert_eq!(big.read_at(500, &mut buf), 100);
+    assert!(buf
+        .iter()
+        .enumerate()
+        .all(|(i, &b)| b == ((500 + i) % 251) as u8));
+    assert_eq!(big.read_at(1250, &mut buf), 50);
+    assert_eq!(buf[49], (1299 % 251) as u8);
+    assert_eq!(big.read_at(1300, &mut buf), 0);
+    assert_eq!(big.read_at(5000, &mut buf), 0);
+}
+
+#[test_case]
+fn test_malformed_archive() {
+    let mut archive = FIXTURE.to_vec();
+    // 第一个头部校验和错误
+    archive[0] ^= 1;
+    assert_eq!(Ramfs::parse(&archive).err(), Some(TarError::BadChecksum));
+    archive[0] ^= 1;
+    // readme的内容被截断
+    assert_eq!(
+        Ramfs::parse(&archive[..1024 + 10]).err(),
+        Some(TarError::Truncated)
+    );
+    assert!(Ramfs::parse(&[]).is_ok());
+}
+
+#[test_case]
+fn test_initrd_motd() {
+    let motd = open("/etc/motd");
+    assert_eq!(motd.is_some(), cfg!(feature = "initrd"));
+}
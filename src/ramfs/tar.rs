@@ -0,0 +1,162 @@
+use core::str;
+
+const BLOCK_SIZE: usize = 512;
+
+// ustar头部各字段的范围
+const NAME: (usize, usize) = (0, 100);
+const SIZE: (usize, usize) = (124, 136);
+const CHECKSUM: (usize, usize) = (148, 156);
+const TYPEFLAG: usize = 156;
+const MAGIC: (usize, usize) = (257, 262);
+const PREFIX: (usize, usize) = (345, 500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    /// 头部或文件内容超出归档末尾
+    Truncated,
+    BadMagic,
+    BadChecksum,
+    /// 数字字段不是八进制
+    BadNumber,
+    /// 路径不是UTF-8或包含".."
+    BadPath,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// 链接、设备等不支持的类型
+    Other(u8),
+}
+
+/// 归档中的一项, 路径由prefix和name两个字段组成
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    prefix: &'a str,
+    name: &'a str,
+    pub kind: EntryKind,
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    /// 路径中的各级名称, 跳过空的部分和"."
+    pub fn components(&self) -> impl Iterator<Item = &'a str> {
+        self.prefix
+            .split('/')
+            .chain(self.name.split('/'))
+            .filter(|part| !part.is_empty() && *part != ".")
+    }
+}
+
+/// 依次解析归档中的各项, 遇到全零的块时结束
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries { archive, offset: 0 }
+}
+
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let archive = self.archive;
+        let header = archive.get(self.offset..self.offset + BLOCK_SIZE)?;
+        if header.iter().all(|&b| b == 0) {
+            return None;
+        }
+        let entry = parse_header(header).and_then(|(prefix, name, kind, size)| {
+            let start = self.offset + BLOCK_SIZE;
+            let data = archive
+                .get(start..start + size)
+                .ok_or(TarError::Truncated)?;
+            self.offset = start + size.next_multiple_of(BLOCK_SIZE);
+            Ok(Entry {
+                prefix,
+                name,
+                kind,
+                data,
+            })
+        });
+        // 出错后不再继续解析
+        if entry.is_err() {
+            self.offset = archive.len();
+        }
+        Some(entry)
+    }
+}
+
+fn parse_header(header: &[u8]) -> Result<(&str, &str, EntryKind, usize), TarError> {
+    // GNU tar写入的是"ustar  "
+    if &header[MAGIC.0..MAGIC.1] != b"ustar" {
+        return Err(TarError::BadMagic);
+    }
+    let checksum = octal(&header[CHECKSUM.0..CHECKSUM.1])?;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (CHECKSUM.0..CHECKSUM.1).contains(&i) {
+                u64::from(b' ')
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum();
+    if sum != checksum {
+        return Err(TarError::BadChecksum);
+    }
+
+    let kind = match header[TYPEFLAG] {
+        b'0' | 0 => EntryKind::File,
+        b'5' => EntryKind::Directory,
+        other => EntryKind::Other(other),
+    };
+    // 只有普通文件的size是内容长度
+    let size = octal(&header[SIZE.0..SIZE.1])? as usize;
+    let size = if kind == EntryKind::File { size } else { 0 };
+    let prefix = text(&header[PREFIX.0..PREFIX.1])?;
+    let name = text(&header[NAME.0..NAME.1])?;
+    if prefix
+        .split('/')
+        .chain(name.split('/'))
+        .any(|part| part == "..")
+    {
+        return Err(TarError::BadPath);
+    }
+    Ok((prefix, name, kind, size))
+}
+
+// 以NUL结尾或占满整个字段的字符串
+fn text(field: &[u8]) -> Result<&str, TarError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| TarError::BadPath)
+}
+
+// 以NUL或空格结尾的八进制数, 前面可能有空格
+fn octal(field: &[u8]) -> Result<u64, TarError> {
+    let digits = field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ');
+    let mut value: u64 = 0;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return Err(TarError::BadNumber);
+        }
+        value = value.checked_mul(8).ok_or(TarError::BadNumber)? + u64::from(digit - b'0');
+    }
+    Ok(value)
+}
+
+#[test_case]
+fn test_octal() {
+    assert_eq!(octal(b"0000644\0"), Ok(0o644));
+    assert_eq!(octal(b"00000002424\0"), Ok(1300));
+    assert_eq!(octal(b"  1234 \0"), Ok(0o1234));
+    assert_eq!(octal(b"\0\0\0"), Ok(0));
+    assert_eq!(octal(b"0000089\0"), Err(TarError::BadNumber));
+}
@@ -0,0 +1,277 @@
+use alloc::format;
+use alloc::string::String;
+use core::arch::asm;
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::cpu;
+use crate::selftest::{self, Check, Outcome};
+
+// Intel建议rdrand连续失败10次后放弃
+const RDRAND_RETRIES: usize = 10;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// 秒、分、时、日、月、年寄存器
+const CMOS_TIME_REGISTERS: [u8; 6] = [0x00, 0x02, 0x04, 0x07, 0x08, 0x09];
+const CMOS_STATUS_A: u8 = 0x0A;
+const CMOS_STATUS_B: u8 = 0x0B;
+// 状态寄存器A: RTC正在更新时间寄存器
+const STATUS_A_UPDATING: u8 = 1 << 7;
+// 状态寄存器B: 时间寄存器为二进制而不是BCD, 小时为24小时制
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+// 12小时制时小时寄存器的最高位表示下午
+const HOUR_PM: u8 = 1 << 7;
+// 更新大约需要2ms, 有限次等待保证没有RTC时不会卡住
+const UPDATE_SPIN_LIMIT: usize = 100_000;
+
+/// 用rdrand获取一个硬件随机数, CF为0表示暂时无可用的熵, 重试10次后返回None
+pub fn rdrand() -> Option<u64> {
+    if !cpu::features().rdrand {
+        return None;
+    }
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {0}", "setc {1}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// xorshift64*伪随机数生成器, 不能用于密码学用途
+#[derive(Debug, Clone)]
+pub struct Xorshift64 {
+    state: u64,
+}
+
+impl Xorshift64 {
+    /// 种子为0时状态会一直为0, 替换为固定的非零值
+    pub const fn new(seed: u64) -> Self {
+        Xorshift64 {
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+fn read_cmos(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+// 读取RTC的原始时间寄存器作为种子的一部分, 不需要解码BCD
+fn rtc_seed() -> u64 {
+    CMOS_TIME_REGISTERS.iter().fold(0, |seed, &register| {
+        seed << 8 | u64::from(read_cmos(register))
+    })
+}
+
+/// 登记RTC的自检
+pub fn register_selftests() {
+    static RTC: Check = Check::new("rtc", rtc_selftest);
+    selftest::register(&RTC).expect("duplicate rtc selftest");
+}
+
+// 等更新结束后读取时间寄存器, 连续两次相同说明没有读到更新中途的值
+fn rtc_selftest() -> Outcome {
+    let read = || {
+        interrupts::without_interrupts(|| {
+            for _ in 0..UPDATE_SPIN_LIMIT {
+                if read_cmos(CMOS_STATUS_A) & STATUS_A_UPDATING == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            CMOS_TIME_REGISTERS.map(read_cmos)
+        })
+    };
+    let mut time = read();
+    for _ in 0..3 {
+        let again = read();
+        if again == time {
+            break;
+        }
+        time = again;
+    }
+    match check_rtc_time(time, read_cmos(CMOS_STATUS_B)) {
+        Ok(()) => Outcome::Pass,
+        Err(reason) => Outcome::Fail(reason),
+    }
+}
+
+// 按状态寄存器B解码BCD和12小时制, 检查时间寄存器的值都在范围内
+fn check_rtc_time(raw: [u8; 6], status_b: u8) -> Result<(), String> {
+    if raw.iter().all(|&value| value == 0xFF) {
+        return Err("no RTC, CMOS registers read 0xff".into());
+    }
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8| {
+        let (high, low) = (value >> 4, value & 0x0F);
+        if binary {
+            Some(value)
+        } else {
+            (high < 10 && low < 10).then_some(high * 10 + low)
+        }
+    };
+    let (hour, hours) = if status_b & STATUS_B_24_HOUR != 0 {
+        (raw[2], 0..=23)
+    } else {
+        (raw[2] & !HOUR_PM, 1..=12)
+    };
+    let fields = [
+        ("second", raw[0], 0..=59),
+        ("minute", raw[1], 0..=59),
+        ("hour", hour, hours),
+        ("day", raw[3], 1..=31),
+        ("month", raw[4], 1..=12),
+        ("year", raw[5], 0..=99),
+    ];
+    for (name, value, range) in fields {
+        match decode(value) {
+            Some(decoded) if range.contains(&decoded) => {}
+            _ => return Err(format!("{} register holds {:#04x}", name, value)),
+        }
+    }
+    Ok(())
+}
+
+// 第一次使用时才播种, 可能在中断中被调用, 因此访问时关中断
+static PRNG: Mutex<Option<Xorshift64>> = Mutex::new(None);
+
+fn prng_u64() -> u64 {
+    interrupts::without_interrupts(|| {
+        PRNG.lock()
+            .get_or_insert_with(|| {
+                let seed = cpu::rdtsc() ^ rtc_seed().rotate_left(32);
+                Xorshift64::new(seed)
+            })
+            .next_u64()
+    })
+}
+
+/// 一个随机数, 优先使用rdrand
+pub fn u64() -> u64 {
+    rdrand().unwrap_or_else(prng_u64)
+}
+
+/// 用随机字节填满`buf`
+pub fn fill(buf: &mut [u8]) {
+    fill_with(buf, u64)
+}
+
+/// `range`内均匀分布的随机数, 范围不能为空
+pub fn range(range: Range<u64>) -> u64 {
+    range_with(range, u64)
+}
+
+fn fill_with(buf: &mut [u8], mut next: impl FnMut() -> u64) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = next().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+// 直接取模会偏向较小的值, 拒绝落在最后一个不完整区间内的样本
+fn range_with(range: Range<u64>, mut next: impl FnMut() -> u64) -> u64 {
+    assert!(range.start < range.end, "empty range");
+    let span = range.end - range.start;
+    // 2^64 mod span
+    let threshold = span.wrapping_neg() % span;
+    loop {
+        let value = next();
+        if value >= threshold {
+            return range.start + value % span;
+        }
+    }
+}
+
+#[test_case]
+fn test_xorshift_reproducible() {
+    let mut rng = Xorshift64::new(1);
+    assert_eq!(rng.next_u64(), 0x47E4_CE4B_896C_DD1D);
+    assert_eq!(rng.next_u64(), 0xABCF_A6A8_E079_651D);
+
+    let mut a = Xorshift64::new(0xDEAD_BEEF);
+    let mut b = Xorshift64::new(0xDEAD_BEEF);
+    for _ in 0..100 {
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+}
+
+#[test_case]
+fn test_range_bounds() {
+    let mut rng = Xorshift64::new(42);
+    let mut seen = [false; 7];
+    for _ in 0..10_000 {
+        let value = range_with(10..17, || rng.next_u64());
+        assert!((10..17).contains(&value));
+        seen[(value - 10) as usize] = true;
+    }
+    assert!(seen.iter().all(|&s| s));
+    assert_eq!(range_with(5..6, || rng.next_u64()), 5);
+}
+
+#[test_case]
+fn test_range_rejects_biased_samples() {
+    // span为3时2^64 mod 3 = 1, 样本0必须被拒绝
+    let mut samples = [0, 0, 4].into_iter();
+    assert_eq!(range_with(0..3, || samples.next().unwrap()), 1);
+}
+
+#[test_case]
+fn test_fill_covers_buffer() {
+    // 长度不是8的倍数, 覆盖最后不完整的块
+    let mut buf = [0u8; 37];
+    fill_with(&mut buf, || u64::MAX);
+    assert!(buf.iter().all(|&b| b == 0xFF));
+}
+
+#[test_case]
+fn test_successive_values_differ() {
+    assert_ne!(u64(), u64());
+}
+
+#[test_case]
+fn test_rtc_time_ranges() {
+    // BCD, 24小时制: 12月31日23:59:30
+    assert_eq!(
+        check_rtc_time([0x30, 0x59, 0x23, 0x31, 0x12, 0x24], 0x02),
+        Ok(())
+    );
+    // 二进制
+    assert_eq!(check_rtc_time([59, 0, 23, 1, 1, 99], 0x06), Ok(()));
+    // 12小时制的下午12点, 以及不存在的0点
+    assert_eq!(check_rtc_time([0, 0, 0x92, 1, 1, 0], 0x00), Ok(()));
+    assert_eq!(
+        check_rtc_time([0, 0, 0x00, 1, 1, 0], 0x00),
+        Err("hour register holds 0x00".into())
+    );
+    assert_eq!(
+        check_rtc_time([0x5A, 0, 0, 1, 1, 0], 0x02),
+        Err("second register holds 0x5a".into())
+    );
+    assert_eq!(
+        check_rtc_time([0x10, 0, 0, 1, 0x13, 0], 0x02),
+        Err("month register holds 0x13".into())
+    );
+    assert!(check_rtc_time([0xFF; 6], 0xFF).is_err());
+}
@@ -0,0 +1,421 @@
+//! CPU时间的统计, 单位为TSC周期
+//!
+//! 线程切换、任务poll前后和hlt前后各读一次TSC, 把上一次切换以来的时间记到之前运行的对象上.
+//! 时钟中断每秒把各对象的累计值记入样本环, `cpu_usage`按最近几秒的样本计算占用比例.
+//! 调度器只在BSP上运行, 因此只统计BSP
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::shell::{self, CmdError};
+use crate::sync::IrqMutex;
+use crate::{cpu, task, thread, time};
+
+/// 同时统计的对象数, 超出时新对象的时间不计入
+pub const MAX_ENTITIES: usize = 64;
+/// 样本环覆盖的秒数
+pub const WINDOW_SECS: usize = 5;
+
+/// 占用CPU的对象
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Entity {
+    /// 空闲线程和hlt等待中断的时间
+    Idle,
+    Thread(u64),
+    Task(u64),
+}
+
+impl Entity {
+    // 低两位区分种类, 0表示空位
+    const fn key(self) -> u64 {
+        match self {
+            Entity::Idle => 1,
+            Entity::Thread(id) => id << 2 | 2,
+            Entity::Task(id) => id << 2 | 3,
+        }
+    }
+
+    fn from_key(key: u64) -> Option<Entity> {
+        match key & 3 {
+            1 => Some(Entity::Idle),
+            2 => Some(Entity::Thread(key >> 2)),
+            3 => Some(Entity::Task(key >> 2)),
+            _ => None,
+        }
+    }
+}
+
+struct Slot {
+    key: AtomicU64,
+    cycles: AtomicU64,
+}
+
+static SLOTS: [Slot; MAX_ENTITIES] = [const {
+    Slot {
+        key: AtomicU64::new(0),
+        cycles: AtomicU64::new(0),
+    }
+}; MAX_ENTITIES];
+
+// 上一次切换的TSC, 0表示还没有切换过
+static SINCE: AtomicU64 = AtomicU64::new(0);
+// 正在运行的对象, 开始时是启动线程
+static CURRENT: AtomicU64 = AtomicU64::new(Entity::Thread(0).key());
+
+/// 开始运行`next`, 上一次切换以来的时间记到之前的对象上, 返回之前的对象
+pub fn switch_to(next: Entity) -> Entity {
+    interrupts::without_interrupts(|| {
+        let now = cpu::rdtsc();
+        let since = SINCE.swap(now, Ordering::Relaxed);
+        let prev = CURRENT.swap(next.key(), Ordering::Relaxed);
+        if since != 0 {
+            charge(prev, now.wrapping_sub(since));
+        }
+        Entity::from_key(prev).unwrap_or(Entity::Idle)
+    })
+}
+
+fn charge(key: u64, cycles: u64) {
+    if let Some(slot) = SLOTS
+        .iter()
+        .find(|slot| slot.key.load(Ordering::Relaxed) == key)
+    {
+        slot.cycles.fetch_add(cycles, Ordering::Relaxed);
+        return;
+    }
+    for slot in &SLOTS {
+        if slot
+            .key
+            .compare_exchange(0, key, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            slot.cycles.store(cycles, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+/// 对象已经结束, 释放它的统计位置
+pub fn forget(entity: Entity) {
+    interrupts::without_interrupts(|| {
+        let key = entity.key();
+        if let Some(slot) = SLOTS
+            .iter()
+            .find(|slot| slot.key.load(Ordering::Relaxed) == key)
+        {
+            slot.key.store(0, Ordering::Relaxed);
+            slot.cycles.store(0, Ordering::Relaxed);
+        }
+    });
+}
+
+/// 开中断并hlt等待下一个中断, 等待的时间记为空闲
+///
+/// 调用时中断应已关闭, 以便在检查等待条件之后、hlt之前到达的中断不会丢失
+pub fn halt() {
+    let prev = switch_to(Entity::Idle);
+    interrupts::enable_and_hlt();
+    switch_to(prev);
+}
+
+// 某一时刻各位置的对象和累计周期数
+#[derive(Clone, Copy)]
+struct Sample {
+    tsc: u64,
+    slots: [(u64, u64); MAX_ENTITIES],
+}
+
+impl Sample {
+    const EMPTY: Sample = Sample {
+        tsc: 0,
+        slots: [(0, 0); MAX_ENTITIES],
+    };
+}
+
+// 先把正在进行的一段记到当前对象上
+fn snapshot() -> Sample {
+    interrupts::without_interrupts(|| {
+        let current = Entity::from_key(CURRENT.load(Ordering::Relaxed)).unwrap_or(Entity::Idle);
+        switch_to(current);
+        Sample {
+            tsc: SINCE.load(Ordering::Relaxed),
+            slots: core::array::from_fn(|i| {
+                let slot = &SLOTS[i];
+                (
+                    slot.key.load(Ordering::Relaxed),
+                    slot.cycles.load(Ordering::Relaxed),
+                )
+            }),
+        }
+    })
+}
+
+// 最近WINDOW_SECS秒的样本, 多保存一个作为窗口的起点
+struct History {
+    samples: [Sample; WINDOW_SECS + 1],
+    next: usize,
+    len: usize,
+}
+
+impl History {
+    fn push(&mut self, sample: Sample) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % self.samples.len();
+        self.len = (self.len + 1).min(self.samples.len());
+    }
+
+    // 最早和最新的样本
+    fn window(&self) -> Option<(Sample, Sample)> {
+        if self.len < 2 {
+            return None;
+        }
+        let size = self.samples.len();
+        let newest = (self.next + size - 1) % size;
+        let oldest = (self.next + size - self.len) % size;
+        Some((self.samples[oldest], self.samples[newest]))
+    }
+}
+
+static HISTORY: IrqMutex<History> = IrqMutex::new(
+    "sched::HISTORY",
+    History {
+        samples: [Sample::EMPTY; WINDOW_SECS + 1],
+        next: 0,
+        len: 0,
+    },
+);
+
+/// 由时钟中断处理函数调用, 每秒记录一个样本
+pub(crate) fn on_timer_interrupt(ticks: u64) {
+    if ticks.is_multiple_of(time::secs_to_ticks(1)) {
+        let sample = snapshot();
+        HISTORY.lock().push(sample);
+    }
+}
+
+/// 一个对象在统计窗口内的占用
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntityUsage {
+    pub entity: Entity,
+    pub cycles: u64,
+    pub percent: u32,
+}
+
+/// 一段时间内的CPU占用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuUsage {
+    /// 窗口的长度
+    pub window_cycles: u64,
+    pub idle_percent: u32,
+    /// 窗口内运行过的线程和任务, 按占用从高到低排列
+    pub entities: Vec<EntityUsage>,
+}
+
+/// 最近几秒的CPU占用, 启动后的第一秒内还没有足够的样本
+pub fn cpu_usage() -> Option<CpuUsage> {
+    let (oldest, newest) = HISTORY.lock().window()?;
+    Some(usage(&oldest, &newest))
+}
+
+// 位置上的对象变了时, 新对象的时间全部算在窗口内
+fn usage(old: &Sample, new: &Sample) -> CpuUsage {
+    let window = new.tsc.wrapping_sub(old.tsc).max(1);
+    let percent = |cycles: u64| {
+        ((u128::from(cycles) * 100 + u128::from(window) / 2) / u128::from(window)) as u32
+    };
+    let mut idle_percent = 0;
+    let mut entities = Vec::new();
+    for (&(key, total), &(old_key, old_total)) in new.slots.iter().zip(&old.slots) {
+        let Some(entity) = Entity::from_key(key) else {
+            continue;
+        };
+        let cycles = if key == old_key {
+            total.saturating_sub(old_total)
+        } else {
+            total
+        };
+        if entity == Entity::Idle {
+            idle_percent = percent(cycles);
+        } else if cycles > 0 {
+            entities.push(EntityUsage {
+                entity,
+                cycles,
+                percent: percent(cycles),
+            });
+        }
+    }
+    entities.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.entity.cmp(&b.entity)));
+    CpuUsage {
+        window_cycles: window,
+        idle_percent,
+        entities,
+    }
+}
+
+/// 注册`top`命令
+pub fn register_commands() {
+    shell::register_command(
+        "top",
+        "top: threads and tasks by recent CPU usage",
+        top_command,
+    )
+    .expect("duplicate sched command");
+}
+
+fn top_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    if !args.is_empty() {
+        return Err(CmdError::Usage("top"));
+    }
+    let Some(usage) = cpu_usage() else {
+        writeln!(out, "no samples yet, try again in a second")?;
+        return Ok(());
+    };
+    let threads: BTreeMap<u64, _> = thread::threads()
+        .into_iter()
+        .map(|info| (info.id.as_u64(), info.name))
+        .collect();
+    let tasks: BTreeMap<u64, _> = task::tasks()
+        .into_iter()
+        .map(|info| (info.id, info.name))
+        .collect();
+    write_usage(out, &usage, |entity| match entity {
+        Entity::Idle => Some("idle"),
+        Entity::Thread(id) => threads.get(&id).map(|name| name.as_str()),
+        Entity::Task(id) => tasks.get(&id).copied(),
+    })
+}
+
+fn write_usage<'a>(
+    out: &mut dyn fmt::Write,
+    usage: &CpuUsage,
+    name: impl Fn(Entity) -> Option<&'a str>,
+) -> Result<(), CmdError> {
+    writeln!(out, "idle: {}%", usage.idle_percent)?;
+    writeln!(out, "{:>4}  {:<7}{:>4}  NAME", "CPU%", "KIND", "ID")?;
+    for entity in &usage.entities {
+        let (kind, id) = match entity.entity {
+            Entity::Thread(id) => ("thread", id),
+            Entity::Task(id) => ("task", id),
+            Entity::Idle => continue,
+        };
+        // 窗口内已经结束的对象没有名字
+        let name = name(entity.entity).unwrap_or("-");
+        writeln!(
+            out,
+            "{:>3}%  {:<7}{:>4}  {}",
+            entity.percent, kind, id, name
+        )?;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_usage_window() {
+    let mut old = Sample::EMPTY;
+    let mut new = Sample::EMPTY;
+    old.tsc = 1000;
+    new.tsc = 2000;
+    old.slots[0] = (Entity::Idle.key(), 100);
+    new.slots[0] = (Entity::Idle.key(), 350);
+    old.slots[1] = (Entity::Thread(1).key(), 500);
+    new.slots[1] = (Entity::Thread(1).key(), 1100);
+    // 位置被新任务占用, 之前的值不算
+    old.slots[2] = (Entity::Task(7).key(), 900);
+    new.slots[2] = (Entity::Task(8).key(), 150);
+    // 窗口内没有运行的对象不列出
+    old.slots[3] = (Entity::Task(9).key(), 40);
+    new.slots[3] = (Entity::Task(9).key(), 40);
+    let usage = usage(&old, &new);
+    assert_eq!(usage.window_cycles, 1000);
+    assert_eq!(usage.idle_percent, 25);
+    assert_eq!(
+        usage.entities,
+        [
+            EntityUsage {
+                entity: Entity::Thread(1),
+                cycles: 600,
+                percent: 60
+            },
+            EntityUsage {
+                entity: Entity::Task(8),
+                cycles: 150,
+                percent: 15
+            },
+        ]
+    );
+
+    let mut out = alloc::string::String::new();
+    write_usage(&mut out, &usage, |entity| {
+        (entity == Entity::Thread(1)).then_some("worker")
+    })
+    .unwrap();
+    assert_eq!(
+        out,
+        "idle: 25%\nCPU%  KIND     ID  NAME\n 60%  thread    1  worker\n 15%  task      8  -\n"
+    );
+}
+
+// 测量时长
+#[cfg(test)]
+const MEASURE_MS: u64 = 500;
+
+// 运行`sleepers`个反复睡眠的线程, 以及可选的一个忙等线程, 返回测量期间的占用和忙等线程
+#[cfg(test)]
+fn measure(busy: bool, sleepers: usize) -> (CpuUsage, Option<thread::ThreadId>) {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let busy = busy.then(|| {
+        let stop = stop.clone();
+        thread::spawn("busy", thread::Priority::Normal, move || {
+            while !stop.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        })
+        .unwrap()
+    });
+    for _ in 0..sleepers {
+        let stop = stop.clone();
+        thread::spawn("sleeper", thread::Priority::Normal, move || {
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep_ms(10);
+            }
+        })
+        .unwrap();
+    }
+    // 测试线程也睡眠, 不占用测量期间的时间
+    thread::sleep_ms(20);
+    let start = snapshot();
+    thread::sleep_ms(MEASURE_MS);
+    let end = snapshot();
+    stop.store(true, Ordering::Relaxed);
+    while Arc::strong_count(&stop) > 1 {
+        thread::sleep_ms(10);
+    }
+    (usage(&start, &end), busy)
+}
+
+#[test_case]
+fn test_busy_thread_dominates() {
+    let (usage, busy) = measure(true, 1);
+    let busy = Entity::Thread(busy.unwrap().as_u64());
+    let top = usage.entities.first().expect("no usage recorded");
+    assert_eq!(top.entity, busy);
+    assert!(top.percent >= 80, "busy thread only {}%", top.percent);
+    assert!(usage.idle_percent <= 5, "idle {}%", usage.idle_percent);
+}
+
+#[test_case]
+fn test_sleepers_leave_cpu_idle() {
+    let (usage, _) = measure(false, 2);
+    assert!(
+        usage.idle_percent >= 90,
+        "idle only {}%",
+        usage.idle_percent
+    );
+}
@@ -0,0 +1,320 @@
+//! 启动自检: 命令行带`selftest`时, 在启动外壳之前依次运行各子系统登记的检查,
+//! 在屏幕和串口上输出记分卡. 同时带`selftest_shell`时之后照常启动外壳, 否则退出或关机
+//!
+//! 检查由所属的模块实现并用`register`登记, 不能改变系统状态. 检查可以声明依赖的检查,
+//! 依赖没有通过时直接跳过. 例如时钟中断不工作时, 依赖超时的检查会被跳过而不是卡住
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use spin::Mutex;
+
+use crate::testing::{Status, StatusLine};
+use crate::vga_buffer::{self, Role};
+use crate::{cmdline, exit_qemu, power, serial, QemuExitCode};
+
+/// 一个检查的结果, 附带失败或跳过的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    Skipped(String),
+}
+
+impl Outcome {
+    fn status(&self) -> Status {
+        match self {
+            Outcome::Pass => Status::Ok,
+            Outcome::Fail(_) => Status::Failed,
+            Outcome::Skipped(_) => Status::Skipped,
+        }
+    }
+
+    fn reason(&self) -> Option<&str> {
+        match self {
+            Outcome::Pass => None,
+            Outcome::Fail(reason) | Outcome::Skipped(reason) => Some(reason),
+        }
+    }
+}
+
+/// 一项自检
+pub trait SelfTest: Sync {
+    fn name(&self) -> &'static str;
+
+    /// 依赖的检查名, 其中任何一个没有通过时跳过这个检查. 依赖必须先登记
+    fn requires(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn run(&self) -> Outcome;
+}
+
+/// 由一个函数实现的检查
+pub struct Check {
+    name: &'static str,
+    requires: &'static [&'static str],
+    run: fn() -> Outcome,
+}
+
+impl Check {
+    pub const fn new(name: &'static str, run: fn() -> Outcome) -> Self {
+        Check {
+            name,
+            requires: &[],
+            run,
+        }
+    }
+
+    /// 声明依赖的检查
+    pub const fn requires(self, requires: &'static [&'static str]) -> Self {
+        Check { requires, ..self }
+    }
+}
+
+impl SelfTest for Check {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn requires(&self) -> &'static [&'static str] {
+        self.requires
+    }
+
+    fn run(&self) -> Outcome {
+        (self.run)()
+    }
+}
+
+/// 同名的检查已经登记
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplicateSelfTest;
+
+static TESTS: Mutex<Vec<&'static dyn SelfTest>> = Mutex::new(Vec::new());
+
+/// 登记检查, 按登记的顺序运行. 需要堆
+pub fn register(test: &'static dyn SelfTest) -> Result<(), DuplicateSelfTest> {
+    let mut tests = TESTS.lock();
+    if tests.iter().any(|other| other.name() == test.name()) {
+        return Err(DuplicateSelfTest);
+    }
+    tests.push(test);
+    Ok(())
+}
+
+/// 命令行是否要求自检
+pub fn requested() -> bool {
+    cmdline::flag("selftest")
+}
+
+/// 运行所有登记的检查, 返回每个检查的名称和结果
+pub fn run_all() -> Vec<(&'static str, Outcome)> {
+    // 检查本身可能需要登记表以外的锁, 先复制一份
+    let tests = TESTS.lock().clone();
+    run_tests(&tests)
+}
+
+fn run_tests(tests: &[&dyn SelfTest]) -> Vec<(&'static str, Outcome)> {
+    let mut results: Vec<(&'static str, Outcome)> = Vec::new();
+    for test in tests {
+        let unmet = test.requires().iter().find(|&&required| {
+            !results
+                .iter()
+                .any(|(name, outcome)| *name == required && *outcome == Outcome::Pass)
+        });
+        let outcome = match unmet {
+            Some(required) => Outcome::Skipped(format!("requires {}", required)),
+            None => test.run(),
+        };
+        results.push((test.name(), outcome));
+    }
+    results
+}
+
+/// 运行自检并在屏幕和串口上输出记分卡. 命令行带`selftest_shell`时返回, 否则以自检的结果
+/// 退出qemu, 没有isa-debug-exit设备时关机
+pub fn run_and_report() {
+    let results = run_all();
+    // 屏幕上按主题着色, 串口上按`testing::color_enabled`使用ANSI颜色
+    let color = crate::testing::color_enabled();
+    let _ = write_scorecard(&results, &mut |piece| match piece {
+        Piece::Text(args) => {
+            vga_buffer::print_screen(Role::Normal, args);
+            Ok(())
+        }
+        Piece::Status(status) => {
+            let label = StatusLine {
+                status,
+                elapsed: None,
+                color: false,
+            };
+            vga_buffer::print_screen(status_role(status), format_args!("{}", label));
+            Ok(())
+        }
+    });
+    let _ = write_scorecard(&results, &mut |piece| match piece {
+        Piece::Text(args) => serial::Serial.write_fmt(args),
+        Piece::Status(status) => {
+            let label = StatusLine {
+                status,
+                elapsed: None,
+                color,
+            };
+            write!(serial::Serial, "{}", label)
+        }
+    });
+    if cmdline::flag("selftest_shell") {
+        return;
+    }
+    let failed = results
+        .iter()
+        .any(|(_, outcome)| matches!(outcome, Outcome::Fail(_)));
+    exit_qemu(if failed {
+        QemuExitCode::Failed
+    } else {
+        QemuExitCode::Success
+    });
+    power::shutdown();
+}
+
+fn status_role(status: Status) -> Role {
+    match status {
+        Status::Ok => Role::Normal,
+        Status::Skipped => Role::Warn,
+        Status::Failed | Status::TimedOut => Role::Error,
+    }
+}
+
+// 记分卡的一段输出, 状态标签单独一段, 由输出端决定颜色
+enum Piece<'a> {
+    Text(fmt::Arguments<'a>),
+    Status(Status),
+}
+
+fn write_scorecard(
+    results: &[(&'static str, Outcome)],
+    emit: &mut dyn FnMut(Piece) -> fmt::Result,
+) -> fmt::Result {
+    emit(Piece::Text(format_args!("selftest scorecard:\n")))?;
+    let mut counts = [0; 3];
+    for (name, outcome) in results {
+        let index = match outcome {
+            Outcome::Pass => 0,
+            Outcome::Fail(_) => 1,
+            Outcome::Skipped(_) => 2,
+        };
+        counts[index] += 1;
+        emit(Piece::Text(format_args!("  {:<12}", name)))?;
+        emit(Piece::Status(outcome.status()))?;
+        match outcome.reason() {
+            Some(reason) => emit(Piece::Text(format_args!(" {}\n", reason)))?,
+            None => emit(Piece::Text(format_args!("\n")))?,
+        }
+    }
+    emit(Piece::Text(format_args!(
+        "selftest result: {} checks, {} passed, {} failed, {} skipped\n",
+        results.len(),
+        counts[0],
+        counts[1],
+        counts[2]
+    )))
+}
+
+#[cfg(test)]
+fn scorecard_text(results: &[(&'static str, Outcome)]) -> String {
+    let mut out = String::new();
+    write_scorecard(results, &mut |piece| match piece {
+        Piece::Text(args) => out.write_fmt(args),
+        Piece::Status(status) => write!(
+            out,
+            "{}",
+            StatusLine {
+                status,
+                elapsed: None,
+                color: false,
+            }
+        ),
+    })
+    .unwrap();
+    out
+}
+
+#[test_case]
+fn test_scorecard() {
+    let results = [
+        ("vga", Outcome::Pass),
+        ("timer", Outcome::Fail("no timer interrupt".into())),
+        ("ps2", Outcome::Skipped("requires timer".into())),
+    ];
+    assert_eq!(
+        scorecard_text(&results),
+        "selftest scorecard:\n  \
+         vga         [ok]\n  \
+         timer       [failed] no timer interrupt\n  \
+         ps2         [skipped] requires timer\n\
+         selftest result: 3 checks, 1 passed, 1 failed, 1 skipped\n"
+    );
+}
+
+#[test_case]
+fn test_requires_skips_dependents() {
+    static FIRST: Check = Check::new("first", || Outcome::Fail("broken".into()));
+    static DEPENDENT: Check =
+        Check::new("dependent", || panic!("dependent ran")).requires(&["first"]);
+    static OTHER: Check = Check::new("other", || Outcome::Pass);
+    static MISSING: Check = Check::new("missing", || Outcome::Pass).requires(&["other", "nope"]);
+
+    assert_eq!(
+        run_tests(&[&FIRST, &DEPENDENT, &OTHER, &MISSING]),
+        [
+            ("first", Outcome::Fail("broken".into())),
+            ("dependent", Outcome::Skipped("requires first".into())),
+            ("other", Outcome::Pass),
+            ("missing", Outcome::Skipped("requires nope".into())),
+        ]
+    );
+}
+
+#[test_case]
+fn test_register_rejects_duplicates() {
+    static CHECK: Check = Check::new("selftest-duplicate", || Outcome::Pass);
+    assert_eq!(register(&CHECK), Ok(()));
+    assert_eq!(register(&CHECK), Err(DuplicateSelfTest));
+    TESTS
+        .lock()
+        .retain(|test| test.name() != "selftest-duplicate");
+}
+
+// 屏蔽时钟中断后时钟检查失败, 依赖时钟的检查被跳过, 其他检查照常运行
+#[test_case]
+fn test_masked_timer() {
+    use x86_64::instructions::interrupts;
+
+    let masked = crate::interrupts::irq_masked(0);
+    crate::interrupts::mask_irq(0);
+    let results = run_all();
+    if !masked {
+        crate::interrupts::unmask_irq(0);
+    }
+    assert!(interrupts::are_enabled());
+    let outcome = |name: &str| {
+        results
+            .iter()
+            .find(|(other, _)| *other == name)
+            .map(|(_, outcome)| outcome.clone())
+    };
+    assert!(matches!(outcome("timer"), Some(Outcome::Fail(_))));
+    assert_eq!(
+        outcome("ps2"),
+        Some(Outcome::Skipped("requires timer".into()))
+    );
+    assert_eq!(
+        outcome("ata"),
+        Some(Outcome::Skipped("requires timer".into()))
+    );
+    assert_eq!(outcome("heap"), Some(Outcome::Pass));
+    assert_eq!(outcome("frames"), Some(Outcome::Pass));
+    assert_eq!(outcome("translate"), Some(Outcome::Pass));
+}
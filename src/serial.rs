@@ -1,26 +1,291 @@
-use uart_16550::SerialPort;
-use spin::Mutex;
-use lazy_static::lazy_static;
-
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use conquer_once::spin::OnceCell;
+use futures_util::stream::Stream;
+
+use alloc::format;
+
+use crate::bench_case;
+use crate::interrupts::{self, IrqUnavailable};
+use crate::io::{HardwareBus, PortBus};
+use crate::selftest::{self, Check, Outcome};
+use crate::sync::{PanicRelease, TrackedMutex};
+use crate::task::channel::Channel;
+use crate::vga_buffer::PrefixWriter;
+
+const COM1: u16 = 0x3F8;
+const COM1_IRQ: u8 = 4;
+// 接收队列的长度, 满了之后的字节被丢弃
+const RX_QUEUE_SIZE: usize = 256;
+
+// 16550寄存器相对基址的偏移. DLAB置位时前两个寄存器是波特率除数的低、高字节
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_DLAB: u8 = 1 << 7;
+// 8个数据位, 无校验, 1个停止位
+const LINE_8N1: u8 = 0x03;
+// 启用并清空FIFO, 14字节触发
+const FIFO_ENABLE_CLEAR_14: u8 = 0xC7;
+// DTR、RTS和OUT2, OUT2打开中断输出
+const MODEM_DTR_RTS_OUT2: u8 = 0x0B;
+// 回环模式, 发送的字节直接进入接收缓冲, 不出现在线路上
+const MODEM_LOOPBACK: u8 = 1 << 4;
+const INTERRUPT_RECEIVED: u8 = 1 << 0;
+// 接收缓冲寄存器中有数据
+const LINE_DATA_READY: u8 = 1 << 0;
+// 发送保持寄存器为空, 可以写入下一个字节
+const LINE_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// 没有UART时端口读到全1
+const LINE_STATUS_FLOATING: u8 = 0xFF;
+
+// 除数为1时的波特率
+const BASE_BAUD: u32 = 115_200;
+const DEFAULT_BAUD: u32 = 38_400;
+// 没有串口时读到0xff, 有限次等待也保证不会卡住
+const TRANSMIT_SPIN_LIMIT: usize = 100_000;
+
+/// 回环测试失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopbackError {
+    NoDevice,
+    /// 发送的字节没有读回, 或读回了别的字节
+    Mismatch {
+        sent: u8,
+        received: Option<u8>,
+    },
+}
+
+/// 16550兼容的UART
+pub struct SerialPort<B> {
+    bus: B,
+    base: u16,
+}
+
+impl<B: PortBus> SerialPort<B> {
+    /// `base`为第一个寄存器的端口, 不访问硬件
+    pub const fn new(bus: B, base: u16) -> Self {
+        SerialPort { bus, base }
+    }
+
+    /// 设置为默认波特率和8N1并启用FIFO和接收中断
+    pub fn init(&mut self) {
+        self.bus.write_u8(self.base + INTERRUPT_ENABLE, 0);
+        self.set_baud_rate(DEFAULT_BAUD);
+        self.bus.write_u8(self.base + FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+        self.bus.write_u8(self.base + MODEM_CONTROL, MODEM_DTR_RTS_OUT2);
+        self.bus.write_u8(self.base + INTERRUPT_ENABLE, INTERRUPT_RECEIVED);
+    }
+
+    /// 通过DLAB写入波特率除数, 之后线路控制寄存器为8N1. `baud`应能整除115200
+    pub fn set_baud_rate(&mut self, baud: u32) {
+        let divisor = (BASE_BAUD / baud.clamp(1, BASE_BAUD)) as u16;
+        let [low, high] = divisor.to_le_bytes();
+        self.bus.write_u8(self.base + LINE_CONTROL, LINE_DLAB);
+        self.bus.write_u8(self.base + DATA, low);
+        self.bus.write_u8(self.base + INTERRUPT_ENABLE, high);
+        self.bus.write_u8(self.base + LINE_CONTROL, LINE_8N1);
+    }
+
+    /// 发送一个字节, 不做转换
+    pub fn send_raw(&mut self, byte: u8) {
+        for _ in 0..TRANSMIT_SPIN_LIMIT {
+            if self.bus.read_u8(self.base + LINE_STATUS) & LINE_TRANSMIT_EMPTY != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.bus.write_u8(self.base + DATA, byte);
+    }
+
+    /// 读取一个收到的字节, 没有数据时返回None
+    pub fn receive(&mut self) -> Option<u8> {
+        if self.bus.read_u8(self.base + LINE_STATUS) & LINE_DATA_READY == 0 {
+            return None;
+        }
+        Some(self.bus.read_u8(self.base + DATA))
+    }
+
+    /// 在回环模式下逐个发送`pattern`并读回, 之后恢复调制解调器控制和中断使能寄存器.
+    /// 开始前接收缓冲中已有的字节交给`pending`, 不会丢失
+    pub fn loopback_test(
+        &mut self,
+        pattern: &[u8],
+        mut pending: impl FnMut(u8),
+    ) -> Result<(), LoopbackError> {
+        if self.bus.read_u8(self.base + LINE_STATUS) == LINE_STATUS_FLOATING {
+            return Err(LoopbackError::NoDevice);
+        }
+        while let Some(byte) = self.receive() {
+            pending(byte);
+        }
+        let interrupts = self.bus.read_u8(self.base + INTERRUPT_ENABLE);
+        let modem = self.bus.read_u8(self.base + MODEM_CONTROL);
+        self.bus.write_u8(self.base + INTERRUPT_ENABLE, 0);
+        self.bus
+            .write_u8(self.base + MODEM_CONTROL, modem | MODEM_LOOPBACK);
+        let result = pattern.iter().try_for_each(|&sent| {
+            self.send_raw(sent);
+            let received = (0..TRANSMIT_SPIN_LIMIT).find_map(|_| self.receive());
+            if received == Some(sent) {
+                Ok(())
+            } else {
+                Err(LoopbackError::Mismatch { sent, received })
+            }
+        });
+        self.bus.write_u8(self.base + MODEM_CONTROL, modem);
+        self.bus.write_u8(self.base + INTERRUPT_ENABLE, interrupts);
+        result
+    }
+
+    /// 发送一个字节, 退格在终端上擦除前一个字符
+    pub fn send(&mut self, byte: u8) {
+        match byte {
+            8 | 0x7F => {
+                self.send_raw(8);
+                self.send_raw(b' ');
+                self.send_raw(8);
+            }
+            byte => self.send_raw(byte),
+        }
+    }
+}
+
+impl<B: PortBus> fmt::Write for SerialPort<B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+static SERIAL1: OnceCell<TrackedMutex<SerialPort<HardwareBus>>> = OnceCell::uninit();
+// COM1的输出是否停在行首, 只在持有SERIAL1时读写
+static AT_LINE_START: AtomicBool = AtomicBool::new(true);
+
+/// 初始化COM1, 由`toy_os::init`最先调用. 重复调用会panic
+pub fn init() {
+    SERIAL1
+        .try_init_once(|| {
+            let mut serial_port = SerialPort::new(unsafe { HardwareBus::new() }, COM1);
+            serial_port.init();
+            TrackedMutex::new("serial::SERIAL1", serial_port)
+        })
+        .expect("serial::init called twice");
+}
+
+static RX: Channel<u8> = Channel::new(RX_QUEUE_SIZE);
+
+/// 注册COM1的接收中断, 之后收到的字节通过`received`读取. 需要PIC已经初始化
+pub fn enable_receive() -> Result<(), IrqUnavailable> {
+    interrupts::register_irq(COM1_IRQ, on_interrupt)?;
+    interrupts::unmask_irq(COM1_IRQ);
+    Ok(())
+}
+
+// 读空FIFO, 读取接收缓冲寄存器会清除中断
+fn on_interrupt() {
+    let Ok(serial) = SERIAL1.try_get() else {
+        return;
+    };
+    let mut serial = serial.lock();
+    while let Some(byte) = serial.receive() {
+        RX.push(byte);
+    }
+}
+
+/// COM1收到的字节流, 同一时刻只能有一个消费者. 第一次调用时分配队列, 不能在中断中调用
+pub fn received() -> impl Stream<Item = u8> {
+    RX.stream()
+}
+
+/// 登记COM1的回环自检
+pub fn register_selftests() {
+    static LOOPBACK: Check = Check::new("serial", loopback_selftest);
+    selftest::register(&LOOPBACK).expect("duplicate serial selftest");
+}
+
+fn loopback_selftest() -> Outcome {
+    let Ok(serial) = SERIAL1.try_get() else {
+        return Outcome::Skipped("serial port not initialized".into());
     };
+    // 持有锁时其他输出等待, 不会在回环模式下发送. 先收到的输入照常送入接收队列
+    let result = serial
+        .lock()
+        .loopback_test(b"\x55\xAAtoy_os", |byte| RX.push(byte));
+    match result {
+        Ok(()) => Outcome::Pass,
+        Err(LoopbackError::NoDevice) => Outcome::Skipped("no UART at COM1".into()),
+        Err(LoopbackError::Mismatch { sent, received }) => Outcome::Fail(format!(
+            "sent {:#04x} in loopback mode, received {:x?}",
+            sent, received
+        )),
+    }
+}
+
+/// `init`是否已经完成
+pub fn is_initialized() -> bool {
+    SERIAL1.is_initialized()
+}
+
+/// 不经过锁直接写COM1, 用于持有者可能就是当前处理器的场合, 输出可能与其他处理器交错
+pub(crate) fn unlocked_writer() -> SerialPort<HardwareBus> {
+    SerialPort::new(unsafe { HardwareBus::new() }, COM1)
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
+    print_prefixed(None, args);
+}
+
+/// 同`_print`, `cpu`不为None时在每行开头加上`[cpuN] `
+pub(crate) fn print_prefixed(cpu: Option<u32>, args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // 在闭包执行时禁用中断
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock()
+    match SERIAL1.try_get() {
+        Ok(serial) => {
+            let mut port = serial.lock();
+            let mut at_line_start = AT_LINE_START.load(Ordering::Relaxed);
+            PrefixWriter {
+                out: &mut *port,
+                cpu,
+                at_line_start: &mut at_line_start,
+            }
             .write_fmt(args)
             .expect("Printing to serial failed");
-    });
+            AT_LINE_START.store(at_line_start, Ordering::Relaxed);
+        }
+        // 初始化之前以及初始化过程中panic时不加锁, 直接写端口.
+        // QEMU的UART复位后就能发送, 不需要先设置波特率
+        Err(_) => {
+            let _ = unlocked_writer().write_fmt(args);
+        }
+    }
+}
+
+/// 使panic路径可以获取COM1的锁, 见`TrackedMutex::release_for_panic`
+pub fn unlock_for_panic() -> PanicRelease {
+    match SERIAL1.try_get() {
+        Ok(serial) => unsafe { serial.release_for_panic() },
+        Err(_) => PanicRelease::Free,
+    }
+}
+
+/// 与`serial_print!`相同的输出, 用于需要`fmt::Write`的地方
+pub struct Serial;
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        _print(format_args!("{}", s));
+        Ok(())
+    }
 }
 
 /// Prints to the host through the serial interface.
@@ -38,4 +303,123 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+// 通过串口输出1KiB数据的耗时
+bench_case!(bench_serial_print_1k, 10, 200_000_000, || {
+    const LINE: &str = unsafe { core::str::from_utf8_unchecked(&[b'.'; 1023]) };
+    serial_print!("{}\n", LINE);
+});
+#[test_case]
+fn test_divisor_programming() {
+    use crate::io::mock::{MockBus, PortWrite::U8};
+
+    let mut port = SerialPort::new(MockBus::new(), 0x2F8);
+    port.init();
+    assert_eq!(
+        port.bus.writes(),
+        [
+            U8(0x2F9, 0x00),
+            // DLAB, 除数3即38400波特
+            U8(0x2FB, 0x80),
+            U8(0x2F8, 0x03),
+            U8(0x2F9, 0x00),
+            U8(0x2FB, 0x03),
+            U8(0x2FA, 0xC7),
+            U8(0x2FC, 0x0B),
+            U8(0x2F9, 0x01),
+        ]
+    );
+
+    let mut port = SerialPort::new(MockBus::new(), COM1);
+    port.set_baud_rate(9600);
+    port.set_baud_rate(115_200);
+    assert_eq!(
+        port.bus.writes(),
+        [
+            U8(0x3FB, 0x80),
+            U8(0x3F8, 12),
+            U8(0x3F9, 0),
+            U8(0x3FB, 0x03),
+            U8(0x3FB, 0x80),
+            U8(0x3F8, 1),
+            U8(0x3F9, 0),
+            U8(0x3FB, 0x03),
+        ]
+    );
+}
+
+#[test_case]
+fn test_send_waits_for_transmitter() {
+    use crate::io::mock::{MockBus, PortWrite::U8};
+
+    let mut bus = MockBus::new();
+    // 第一次查询时发送保持寄存器还未空
+    bus.respond(COM1 + LINE_STATUS, &[0x00, 0x20, 0x20, 0x20, 0x20]);
+    let mut port = SerialPort::new(bus, COM1);
+    port.send(b'a');
+    port.send(0x7F);
+    assert_eq!(
+        port.bus.writes(),
+        [U8(0x3F8, b'a'), U8(0x3F8, 8), U8(0x3F8, b' '), U8(0x3F8, 8)]
+    );
+    assert!(port.bus.finished());
+}
+
+#[test_case]
+fn test_receive_drains_fifo() {
+    use crate::io::mock::MockBus;
+
+    let mut bus = MockBus::new();
+    bus.respond(COM1 + LINE_STATUS, &[0x61, 0x61, 0x60])
+        .respond(COM1 + DATA, &[u32::from(b'h'), u32::from(b'i')]);
+    let mut port = SerialPort::new(bus, COM1);
+    assert_eq!(port.receive(), Some(b'h'));
+    assert_eq!(port.receive(), Some(b'i'));
+    assert_eq!(port.receive(), None);
+    assert!(port.bus.finished());
+}
+
+#[test_case]
+fn test_loopback_restores_registers() {
+    use crate::io::mock::{MockBus, PortWrite::U8};
+
+    let mut bus = MockBus::new();
+    // 第一次读状态判断是否存在, 第二次发现已有一个字节待读
+    let status = [0x60, 0x61, 0x60, 0x20, 0x61, 0x20, 0x61];
+    bus.respond(COM1 + LINE_STATUS, &status)
+        .respond(COM1 + DATA, &[u32::from(b'q'), 0x55, 0x13])
+        .respond(COM1 + INTERRUPT_ENABLE, &[0x01])
+        .respond(COM1 + MODEM_CONTROL, &[0x0B]);
+    let mut port = SerialPort::new(bus, COM1);
+    let mut pending = alloc::vec::Vec::new();
+    assert_eq!(
+        port.loopback_test(&[0x55, 0xAA], |byte| pending.push(byte)),
+        Err(LoopbackError::Mismatch {
+            sent: 0xAA,
+            received: Some(0x13)
+        })
+    );
+    assert_eq!(pending, [b'q']);
+    assert_eq!(
+        port.bus.writes(),
+        [
+            U8(0x3F9, 0x00),
+            U8(0x3FC, 0x1B),
+            U8(0x3F8, 0x55),
+            U8(0x3F8, 0xAA),
+            U8(0x3FC, 0x0B),
+            U8(0x3F9, 0x01),
+        ]
+    );
+    assert!(port.bus.finished());
+
+    let mut bus = MockBus::new();
+    bus.respond(COM1 + LINE_STATUS, &[0xFF]);
+    let mut port = SerialPort::new(bus, COM1);
+    assert_eq!(
+        port.loopback_test(b"x", |_| {}),
+        Err(LoopbackError::NoDevice)
+    );
+    assert!(port.bus.writes().is_empty());
+}
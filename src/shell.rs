@@ -0,0 +1,368 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+
+use futures_util::future;
+use futures_util::stream::StreamExt;
+use spin::Mutex;
+
+use crate::tty::{self, LineEditor, Tty};
+use crate::vga_buffer::console::{self, Output};
+use crate::vga_buffer::{self, ConsoleId, Role, BUFFER_WIDTH};
+use crate::{interrupts, keyboard, task};
+
+pub mod args;
+
+pub use args::ArgError;
+
+const PROMPT: &str = "> ";
+// 一行最多的字符数, 超出屏幕宽度的部分滚动显示
+const MAX_LINE_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdError {
+    UnknownCommand,
+    /// 参数个数不对, 附带用法
+    Usage(&'static str),
+    BadArgument(ArgError),
+    UnknownColor,
+    UnknownTheme,
+    /// 调试命令无法访问内存
+    Memory(crate::memdebug::MemAccessError),
+    /// 内存接口拒绝了地址
+    Mem(crate::memory::MemError),
+    /// 无法启动用户程序
+    Spawn(crate::process::SpawnError),
+    /// 内核接口返回的错误, 如无法打开或读取文件
+    Kernel(crate::error::KernelError),
+    /// 无法载入键盘布局
+    Keymap(crate::keyboard::KeymapError),
+    /// 无法载入控制台字体
+    Font(crate::vga_font::FontError),
+    /// 写入输出失败
+    Output,
+}
+
+impl From<ArgError> for CmdError {
+    fn from(err: ArgError) -> Self {
+        CmdError::BadArgument(err)
+    }
+}
+
+impl From<fmt::Error> for CmdError {
+    fn from(_: fmt::Error) -> Self {
+        CmdError::Output
+    }
+}
+
+impl From<crate::memory::MemError> for CmdError {
+    fn from(err: crate::memory::MemError) -> Self {
+        CmdError::Mem(err)
+    }
+}
+
+impl From<crate::error::KernelError> for CmdError {
+    fn from(err: crate::error::KernelError) -> Self {
+        CmdError::Kernel(err)
+    }
+}
+
+impl From<crate::keyboard::KeymapError> for CmdError {
+    fn from(err: crate::keyboard::KeymapError) -> Self {
+        CmdError::Keymap(err)
+    }
+}
+
+impl From<crate::vga_font::FontError> for CmdError {
+    fn from(err: crate::vga_font::FontError) -> Self {
+        CmdError::Font(err)
+    }
+}
+
+impl fmt::Display for CmdError {
+    /// 内存错误说明地址的问题, 内核错误说明类别, 布局和字体错误说明原因, 其他错误按Debug格式输出
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CmdError::Memory(err) => write!(f, "{}", err),
+            CmdError::Mem(err) => write!(f, "{}", err),
+            CmdError::Kernel(err) => write!(f, "{}", err),
+            CmdError::Keymap(err) => write!(f, "{}", err),
+            CmdError::Font(err) => write!(f, "{}", err),
+            err => write!(f, "{:?}", err),
+        }
+    }
+}
+
+/// 命令的处理函数, `args`不包括命令名, 输出写入`out`
+pub type Handler = fn(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError>;
+
+/// 异步命令的执行过程, 在命令的任务中等待
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = Result<(), CmdError>> + Send + 'a>>;
+
+/// 需要等待文件系统或设备的命令的处理函数, 等待期间执行器继续运行其他任务
+pub type AsyncHandler =
+    for<'a> fn(args: &'a [&'a str], out: &'a mut (dyn fmt::Write + Send)) -> CommandFuture<'a>;
+
+#[derive(Clone, Copy)]
+pub enum CommandHandler {
+    Sync(Handler),
+    Async(AsyncHandler),
+}
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub handler: CommandHandler,
+}
+
+/// 同名的命令已经注册
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplicateCommand;
+
+// 只在任务上下文中访问
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// 注册外壳命令, 需要堆
+pub fn register_command(
+    name: &'static str,
+    help: &'static str,
+    handler: Handler,
+) -> Result<(), DuplicateCommand> {
+    register(name, help, CommandHandler::Sync(handler))
+}
+
+/// 注册异步的外壳命令
+pub fn register_async_command(
+    name: &'static str,
+    help: &'static str,
+    handler: AsyncHandler,
+) -> Result<(), DuplicateCommand> {
+    register(name, help, CommandHandler::Async(handler))
+}
+
+fn register(
+    name: &'static str,
+    help: &'static str,
+    handler: CommandHandler,
+) -> Result<(), DuplicateCommand> {
+    let mut commands = COMMANDS.lock();
+    if commands.iter().any(|command| command.name == name) {
+        return Err(DuplicateCommand);
+    }
+    commands.push(Command {
+        name,
+        help,
+        handler,
+    });
+    Ok(())
+}
+
+/// 已注册的命令, 按名称排序
+pub fn commands() -> Vec<Command> {
+    let mut commands = COMMANDS.lock().clone();
+    commands.sort_unstable_by_key(|command| command.name);
+    commands
+}
+
+// 复制出来再执行, 处理函数可能再次访问命令表
+fn find(name: &str) -> Option<Command> {
+    COMMANDS
+        .lock()
+        .iter()
+        .find(|command| command.name == name)
+        .copied()
+}
+
+/// 注册外壳自身的命令
+pub fn init() {
+    register_command("help", "list commands", help).expect("duplicate shell command");
+    register_command("echo", "print the arguments", echo).expect("duplicate shell command");
+}
+
+// 解析一行, 返回命令和参数, 空行返回None
+fn parse(line: &str) -> Result<Option<(Command, Vec<String>)>, CmdError> {
+    let mut args = args::split(line)?;
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let command = find(&args[0]).ok_or(CmdError::UnknownCommand)?;
+    args.remove(0);
+    Ok(Some((command, args)))
+}
+
+async fn run_command(
+    command: Command,
+    args: &[String],
+    out: &mut (dyn fmt::Write + Send),
+) -> Result<(), CmdError> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match command.handler {
+        CommandHandler::Sync(handler) => handler(&args, out),
+        CommandHandler::Async(handler) => handler(&args, out).await,
+    }
+}
+
+/// 解析并在当前任务中执行一行命令, 空行不做任何事
+pub async fn dispatch(line: &str, out: &mut (dyn fmt::Write + Send)) -> Result<(), CmdError> {
+    match parse(line)? {
+        Some((command, args)) => run_command(command, &args, out).await,
+        None => Ok(()),
+    }
+}
+
+// 测试中执行同步命令, 它们不会等待
+#[cfg(test)]
+fn dispatch_now(line: &str, out: &mut String) -> Result<(), CmdError> {
+    task::complete_now(dispatch(line, out))
+}
+
+/// 键盘和屏幕上的终端
+pub static CONSOLE: Tty<ConsoleEcho> = Tty::new(MAX_LINE_LEN, ConsoleEcho);
+
+// 外壳自己的输出和命令的输出都写到外壳的控制台
+fn print_error(args: fmt::Arguments) {
+    vga_buffer::print_role_on(ConsoleId::Shell, Role::Error, format_args!("{}\n", args));
+}
+
+/// 切换到外壳的控制台, 读取键盘输入并执行命令, 不会结束
+pub async fn run() {
+    use core::fmt::Write;
+
+    interrupts::set_print_ticks(false);
+    console::switch(ConsoleId::Shell);
+    let _ = writeln!(
+        Output(ConsoleId::Shell),
+        "Type `help` for a list of commands"
+    );
+    let mut lines = CONSOLE.lines();
+    let keys = keyboard::keys().filter_map(|key| future::ready(tty::key_from_decoded(key)));
+    CONSOLE.redraw();
+    let commands = async {
+        while let Some(line) = lines.next().await {
+            execute(&line).await;
+            CONSOLE.redraw();
+        }
+    };
+    future::join(CONSOLE.run(keys), commands).await;
+}
+
+/// 在外壳控制台的当前行显示提示符和输入, 超出屏幕宽度时滚动显示
+pub struct ConsoleEcho;
+
+impl tty::Echo for ConsoleEcho {
+    fn redraw(&mut self, editor: &LineEditor) {
+        let (text, cursor) = editor.visible(BUFFER_WIDTH - PROMPT.len());
+        vga_buffer::rewrite_prompt_line(PROMPT, &text, Some(PROMPT.len() + cursor));
+    }
+
+    fn finish(&mut self, line: &str, interrupted: bool) {
+        use core::fmt::Write;
+
+        // 提交的行从头显示, 去掉光标
+        if interrupted {
+            vga_buffer::rewrite_prompt_line(PROMPT, &alloc::format!("{}^C", line), None);
+        } else {
+            vga_buffer::rewrite_prompt_line(PROMPT, line, None);
+        }
+        let _ = writeln!(Output(ConsoleId::Shell));
+    }
+}
+
+// 命令在单独的任务中运行, 返回错误时只打印错误, 外壳继续等待输入
+async fn execute(line: &str) {
+    let (command, args) = match parse(line) {
+        Ok(Some(parsed)) => parsed,
+        Ok(None) => return,
+        Err(CmdError::UnknownCommand) => {
+            let name = line.split_whitespace().next().unwrap_or("");
+            print_error(format_args!("unknown command: {} (try `help`)", name));
+            return;
+        }
+        Err(err) => {
+            print_error(format_args!("parse error: {:?}", err));
+            return;
+        }
+    };
+    task::spawn(command.name, async move {
+        if let Err(err) = run_command(command, &args, &mut Output(ConsoleId::Shell)).await {
+            print_error(format_args!("{}: {}", command.name, err));
+        }
+    })
+    .await;
+}
+
+fn help(_args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    for command in commands() {
+        writeln!(out, "{:<10}{}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn echo(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    writeln!(out, "{}", args.join(" "))?;
+    Ok(())
+}
+
+#[test_case]
+fn test_register_and_dispatch() {
+    use alloc::string::ToString;
+
+    static LAST_ARGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    fn record(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+        *LAST_ARGS.lock() = args.iter().map(|arg| arg.to_string()).collect();
+        write!(out, "recorded {}", args.len())?;
+        Ok(())
+    }
+
+    register_command("test-record", "test command", record).unwrap();
+    assert_eq!(
+        register_command("test-record", "again", record),
+        Err(DuplicateCommand)
+    );
+
+    let mut out = String::new();
+    dispatch_now("test-record 0x10 \"two words\"", &mut out).unwrap();
+    assert_eq!(out, "recorded 2");
+    assert_eq!(*LAST_ARGS.lock(), ["0x10", "two words"]);
+
+    let mut out = String::new();
+    assert_eq!(dispatch_now("", &mut out), Ok(()));
+    assert_eq!(
+        dispatch_now("no-such-command", &mut out),
+        Err(CmdError::UnknownCommand)
+    );
+    assert_eq!(
+        dispatch_now("echo 'open", &mut out),
+        Err(CmdError::BadArgument(ArgError::UnterminatedQuote))
+    );
+    assert!(out.is_empty());
+}
+
+#[test_case]
+fn test_help_sorted() {
+    let mut out = String::new();
+    dispatch_now("help", &mut out).unwrap();
+    let names: Vec<&str> = out
+        .lines()
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect();
+    assert!(
+        names.windows(2).all(|pair| pair[0] < pair[1]),
+        "{:?}",
+        names
+    );
+    for name in [
+        "help", "echo", "mem", "ints", "ps", "uptime", "clear", "shutdown",
+    ] {
+        assert!(names.contains(&name), "{} not registered", name);
+    }
+
+    let mut out = String::new();
+    dispatch_now("echo a  \"b c\"", &mut out).unwrap();
+    assert_eq!(out, "a b c\n");
+}
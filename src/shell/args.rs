@@ -0,0 +1,106 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgError {
+    UnterminatedQuote,
+    InvalidNumber,
+}
+
+/// 按空白切分命令行. 单引号内原样保留, 双引号和引号外可以用反斜杠转义下一个字符
+pub fn split(line: &str) -> Result<Vec<String>, ArgError> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    // 引号可以产生空参数, 因此单独记录是否在参数中
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => current.push(c),
+            (_, '\\') => {
+                in_arg = true;
+                // 行尾的反斜杠按普通字符处理
+                current.push(chars.next().unwrap_or('\\'));
+            }
+            (Some(_), c) => current.push(c),
+            (None, '\'' | '"') => {
+                in_arg = true;
+                quote = Some(c);
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(core::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                in_arg = true;
+                current.push(c);
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(ArgError::UnterminatedQuote);
+    }
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// 解析十进制数或以`0x`开头的十六进制数
+pub fn parse_number(arg: &str) -> Result<u64, ArgError> {
+    let (digits, radix) = match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None => (arg, 10),
+    };
+    // from_str_radix接受开头的"+"
+    if digits.is_empty() || digits.starts_with('+') {
+        return Err(ArgError::InvalidNumber);
+    }
+    u64::from_str_radix(digits, radix).map_err(|_| ArgError::InvalidNumber)
+}
+
+#[test_case]
+fn test_split() {
+    assert_eq!(split("").unwrap(), Vec::<String>::new());
+    assert_eq!(split("  echo   a  b ").unwrap(), ["echo", "a", "b"]);
+    assert_eq!(
+        split("echo \"hello world\" x").unwrap(),
+        ["echo", "hello world", "x"]
+    );
+    assert_eq!(split("a'b c'd").unwrap(), ["ab cd"]);
+    assert_eq!(split("'' \"\"").unwrap(), ["", ""]);
+    // 单引号内不转义
+    assert_eq!(
+        split(r#"'a\"b' "a\"b" a\ b"#).unwrap(),
+        [r#"a\"b"#, "a\"b", "a b"]
+    );
+    assert_eq!(split("\"it's\"").unwrap(), ["it's"]);
+    assert_eq!(split("end\\").unwrap(), ["end\\"]);
+    assert_eq!(split("echo \"open"), Err(ArgError::UnterminatedQuote));
+    assert_eq!(split("'"), Err(ArgError::UnterminatedQuote));
+}
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number("0"), Ok(0));
+    assert_eq!(parse_number("4096"), Ok(4096));
+    assert_eq!(parse_number("0xb8000"), Ok(0xb8000));
+    assert_eq!(parse_number("0XFF"), Ok(255));
+    assert_eq!(parse_number("0xffffffffffffffff"), Ok(u64::MAX));
+    for bad in [
+        "",
+        "0x",
+        "-1",
+        "+1",
+        "0x+1",
+        "12a",
+        "0x1g",
+        "0x10000000000000000",
+    ] {
+        assert_eq!(parse_number(bad), Err(ArgError::InvalidNumber), "{}", bad);
+    }
+}
@@ -0,0 +1,215 @@
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::ptr::{self, addr_of};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr3};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use crate::cpu::msr::Efer;
+use crate::log::Level;
+use crate::memory::{self, BootInfoFrameAllocator, FrameUsage};
+use crate::percpu::{self, PerCpu};
+use crate::{acpi, apic, gdt, interrupts, log, time};
+
+// AP内核栈和双重错误栈的页数
+const AP_STACK_PAGES: u64 = 4;
+const AP_DOUBLE_FAULT_STACK_PAGES: u64 = 2;
+
+// SIPI只能指定1MiB以下的页
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+
+// INIT之后和每次SIPI之后的等待时间
+const INIT_DELAY_US: u64 = 10_000;
+const STARTUP_DELAY_US: u64 = 200;
+// 等待AP完成初始化的时间
+const AP_ONLINE_TIMEOUT_MS: u64 = 500;
+
+global_asm!(include_str!("smp/trampoline.s"), options(att_syntax));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_long_mode: u8;
+    static ap_gdt: u8;
+    static ap_trampoline_data: u8;
+}
+
+// 启动代码末尾的数据区, 布局与trampoline.s一致
+#[repr(C, packed)]
+struct TrampolineData {
+    gdt_limit: u16,
+    gdt_base: u32,
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    cr3: u32,
+    efer: u32,
+    cr0: u32,
+    stack_top: u64,
+    entry: u64,
+    cpu_id: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    AlreadyStarted,
+    NoApic,
+    NoMadt,
+    /// 没有预留1MiB以下的页
+    NoTrampoline,
+    /// 实模式只能写入32位的CR3
+    PageTableAbove4GiB,
+    MapFailed,
+}
+
+/// `boot_aps`的结果, 列出的都是APIC ID
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootReport {
+    pub online: Vec<u32>,
+    pub timed_out: Vec<u32>,
+}
+
+static TRAMPOLINE: Once<PhysFrame> = Once::new();
+static AP_HOOK: Once<fn(&'static PerCpu)> = Once::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+// 包括BSP在内已完成初始化的处理器数
+static ONLINE: AtomicU32 = AtomicU32::new(1);
+
+/// 为AP启动代码预留1MiB以下的一页, 需要在其他分配之前调用
+pub fn reserve_trampoline(frame_allocator: &mut BootInfoFrameAllocator) {
+    if let Some(frame) =
+        frame_allocator.allocate_below(PhysAddr::new(TRAMPOLINE_LIMIT), FrameUsage::Other)
+    {
+        TRAMPOLINE.call_once(|| frame);
+    }
+}
+
+/// 已完成初始化的处理器数, 包括BSP
+pub fn online_cpus() -> u32 {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// 依次启动MADT中的其他处理器, 每个AP打印"CPU n online"后停机
+pub fn boot_aps() -> Result<BootReport, SmpError> {
+    boot_aps_with(|_| {})
+}
+
+/// 同`boot_aps`, 每个AP在停机前调用一次`hook`
+pub fn boot_aps_with(hook: fn(&'static PerCpu)) -> Result<BootReport, SmpError> {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return Err(SmpError::AlreadyStarted);
+    }
+    let lapic = apic::local_apic().ok_or(SmpError::NoApic)?;
+    let madt = acpi::madt().ok_or(SmpError::NoMadt)?;
+    let frame = *TRAMPOLINE.get().ok_or(SmpError::NoTrampoline)?;
+    let (level_4_table, _) = Cr3::read();
+    let cr3 = u32::try_from(level_4_table.start_address().as_u64())
+        .map_err(|_| SmpError::PageTableAbove4GiB)?;
+    // 打开分页后AP仍在这一页上执行, 需要恒等映射
+    memory::identity_map(frame).map_err(|_| SmpError::MapFailed)?;
+    let vector = (frame.start_address().as_u64() / 4096) as u8;
+    let data = install_trampoline(frame, cr3);
+    AP_HOOK.call_once(|| hook);
+
+    let bsp = lapic.id();
+    let mut report = BootReport::default();
+    for processor in madt
+        .processors
+        .iter()
+        .filter(|p| p.enabled && p.apic_id != bsp)
+    {
+        let stack_top = memory::alloc_stack(AP_STACK_PAGES).map_err(|_| SmpError::MapFailed)?;
+        let cpu_id = online_cpus();
+        unsafe {
+            ptr::addr_of_mut!((*data).stack_top).write_unaligned(stack_top.as_u64());
+            ptr::addr_of_mut!((*data).cpu_id).write_unaligned(u64::from(cpu_id));
+        }
+
+        lapic.send_init(processor.apic_id);
+        time::delay_us(INIT_DELAY_US);
+        // 第一次SIPI之后已启动的AP会忽略第二次
+        for _ in 0..2 {
+            lapic.send_startup(processor.apic_id, vector);
+            time::delay_us(STARTUP_DELAY_US);
+        }
+
+        // 一次只启动一个AP, 它完成初始化之后启动代码的数据区才能复用
+        if time::wait_until(AP_ONLINE_TIMEOUT_MS, || online_cpus() > cpu_id).is_ok() {
+            report.online.push(processor.apic_id);
+        } else {
+            // 使其回到等待SIPI的状态, 以免迟到的AP使用下一个AP的栈
+            lapic.send_init(processor.apic_id);
+            log!(
+                Level::Warn,
+                "CPU with APIC ID {} did not come online",
+                processor.apic_id
+            );
+            report.timed_out.push(processor.apic_id);
+        }
+    }
+    Ok(report)
+}
+
+// 把启动代码复制到预留的页, 返回其中数据区的指针
+fn install_trampoline(frame: PhysFrame, cr3: u32) -> *mut TrampolineData {
+    let base = frame.start_address().as_u64();
+    let page: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let offset = |symbol: *const u8| symbol as usize - start as usize;
+        let len = offset(addr_of!(ap_trampoline_end));
+        assert!(len <= 4096, "AP trampoline does not fit in one page");
+        ptr::copy_nonoverlapping(start, page, len);
+
+        let data = page.add(offset(addr_of!(ap_trampoline_data))) as *mut TrampolineData;
+        // LMA是只读位
+        let efer = Efer::read().0 & !(1 << 10);
+        data.write_unaligned(TrampolineData {
+            gdt_limit: 3 * 8 - 1,
+            gdt_base: (base + offset(addr_of!(ap_gdt)) as u64) as u32,
+            long_mode_offset: (base + offset(addr_of!(ap_long_mode)) as u64) as u32,
+            long_mode_selector: 0x08,
+            cr3,
+            efer: efer as u32,
+            cr0: Cr0::read_raw() as u32,
+            stack_top: 0,
+            entry: ap_main as extern "C" fn(u64) -> ! as usize as u64,
+            cpu_id: 0,
+        });
+        data
+    }
+}
+
+// AP从启动代码进入的第一个Rust函数, 已处于长模式并使用自己的栈
+extern "C" fn ap_main(cpu_id: u64) -> ! {
+    let cpu_id = cpu_id as u32;
+    let double_fault_stack = memory::alloc_stack(AP_DOUBLE_FAULT_STACK_PAGES)
+        .expect("failed to allocate AP double fault stack");
+    gdt::init_ap(double_fault_stack);
+    interrupts::load_idt();
+    apic::init_ap();
+    let percpu = percpu::init(cpu_id);
+
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    log!(Level::Info, "CPU {} online", cpu_id);
+    if let Some(hook) = AP_HOOK.get() {
+        hook(percpu);
+    }
+    // 中断保持关闭, AP停在hlt直到下一次INIT
+    crate::hlt_loop();
+}
+
+#[test_case]
+fn test_trampoline_layout() {
+    assert_eq!(core::mem::size_of::<TrampolineData>(), 48);
+    let len = addr_of!(ap_trampoline_end) as usize - addr_of!(ap_trampoline_start) as usize;
+    assert!(len <= 4096);
+    let data = addr_of!(ap_trampoline_end) as usize - addr_of!(ap_trampoline_data) as usize;
+    assert_eq!(data, core::mem::size_of::<TrampolineData>());
+    if let Some(frame) = TRAMPOLINE.get() {
+        let base = frame.start_address().as_u64();
+        assert!(base != 0 && base < TRAMPOLINE_LIMIT);
+    }
+}
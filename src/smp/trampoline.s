@@ -0,0 +1,80 @@
+# AP启动代码, 由BSP复制到1MiB以下的一页并通过SIPI跳转到页首执行.
+# 实模式下CS指向这一页, 所有数据按相对页首的偏移访问; 数据区由BSP在发送SIPI前填写,
+# 布局与smp.rs中的TrampolineData一致
+
+.pushsection .text.ap_trampoline, "ax"
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+
+    lgdtl (.Lgdt_pointer - ap_trampoline_start)
+
+    # CR4.PAE | CR4.PGE
+    mov $0xA0, %eax
+    mov %eax, %cr4
+    mov (.Lcr3 - ap_trampoline_start), %eax
+    mov %eax, %cr3
+    # 写入BSP的EFER, 其中包含LME和NXE
+    mov $0xC0000080, %ecx
+    mov (.Lefer - ap_trampoline_start), %eax
+    xor %edx, %edx
+    wrmsr
+    # 同时打开保护模式和分页, 直接进入长模式的兼容子模式
+    mov (.Lcr0 - ap_trampoline_start), %eax
+    mov %eax, %cr0
+    ljmpl *(.Llong_mode_jump - ap_trampoline_start)
+
+.code64
+.global ap_long_mode
+ap_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    xor %ax, %ax
+    mov %ax, %fs
+    mov %ax, %gs
+
+    mov .Lstack_top(%rip), %rsp
+    mov .Lcpu_id(%rip), %rdi
+    mov .Lentry(%rip), %rax
+    call *%rax
+    ud2
+
+.balign 8
+.global ap_gdt
+ap_gdt:
+    .quad 0
+    # 64位代码段
+    .quad 0x00AF9A000000FFFF
+    # 数据段
+    .quad 0x00CF92000000FFFF
+
+.balign 8
+.global ap_trampoline_data
+ap_trampoline_data:
+.Lgdt_pointer:
+    .word 23
+    .long 0
+.Llong_mode_jump:
+    .long 0
+    .word 0x08
+.Lcr3:
+    .long 0
+.Lefer:
+    .long 0
+.Lcr0:
+    .long 0
+.Lstack_top:
+    .quad 0
+.Lentry:
+    .quad 0
+.Lcpu_id:
+    .quad 0
+.global ap_trampoline_end
+ap_trampoline_end:
+.popsection
@@ -0,0 +1,168 @@
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::time::{self, PIT_BASE_FREQUENCY};
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const SPEAKER_CONTROL: u16 = 0x61;
+// 常用于短延时的未使用端口, 每次写入约1µs
+const IO_DELAY_PORT: u16 = 0x80;
+
+// 通道2, 先低字节后高字节, 模式3方波
+const PIT_CHANNEL2_SQUARE_WAVE: u8 = 0b1011_0110;
+
+// 0x61的bit0连接通道2的门控, bit1连接扬声器
+const SPEAKER_GATE: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+
+// 提示音的(频率, 毫秒)
+const PANIC_BEEP: (u16, u16) = (220, 150);
+const SUCCESS_BEEP: (u16, u16) = (880, 100);
+
+/// 产生`freq_hz`的方波需要的PIT分频值
+pub fn divisor(freq_hz: u16) -> u16 {
+    let freq = u64::from(freq_hz.max(1));
+    (PIT_BASE_FREQUENCY / freq).clamp(1, u64::from(u16::MAX)) as u16
+}
+
+// 0x61端口的访问, 便于在测试中替换
+trait ControlPort {
+    fn read(&mut self) -> u8;
+    fn write(&mut self, value: u8);
+}
+
+impl ControlPort for Port<u8> {
+    fn read(&mut self) -> u8 {
+        unsafe { Port::read(self) }
+    }
+
+    fn write(&mut self, value: u8) {
+        unsafe { Port::write(self, value) }
+    }
+}
+
+// 只清除扬声器的两位, 其他位原样写回
+fn gate_off(port: &mut impl ControlPort) {
+    let value = port.read();
+    port.write(value & !(SPEAKER_GATE | SPEAKER_DATA));
+}
+
+fn gate_on(port: &mut impl ControlPort) {
+    let value = port.read();
+    port.write(value | SPEAKER_GATE | SPEAKER_DATA);
+}
+
+/// 开始以`freq_hz`发声, 直到调用`off`
+pub fn on(freq_hz: u16) {
+    let [low, high] = divisor(freq_hz).to_le_bytes();
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL2_SQUARE_WAVE);
+        let mut channel = Port::<u8>::new(PIT_CHANNEL2);
+        channel.write(low);
+        channel.write(high);
+        gate_on(&mut Port::<u8>::new(SPEAKER_CONTROL));
+    });
+}
+
+/// 关闭扬声器, 读改写期间关中断, 不会被中断中的其他访问打断
+pub fn off() {
+    interrupts::without_interrupts(|| gate_off(&mut Port::<u8>::new(SPEAKER_CONTROL)));
+}
+
+// 发声期间持有, future被取消时也会关闭扬声器
+struct Tone;
+
+impl Tone {
+    fn start(freq_hz: u16) -> Tone {
+        on(freq_hz);
+        Tone
+    }
+}
+
+impl Drop for Tone {
+    fn drop(&mut self) {
+        off();
+    }
+}
+
+/// 以`freq_hz`发声`duration_ms`毫秒
+pub async fn beep(freq_hz: u16, duration_ms: u16) {
+    let _tone = Tone::start(freq_hz);
+    time::sleep_ms(u64::from(duration_ms)).await;
+}
+
+/// 依次播放`(频率, 毫秒)`, 频率为0表示休止
+pub async fn play(notes: &[(u16, u16)]) {
+    for &(freq_hz, duration_ms) in notes {
+        if freq_hz == 0 {
+            time::sleep_ms(u64::from(duration_ms)).await;
+        } else {
+            beep(freq_hz, duration_ms).await;
+        }
+    }
+}
+
+/// 不依赖执行器的同步版本, 中断关闭时(如在中断中panic)改用端口延时
+pub fn beep_blocking(freq_hz: u16, duration_ms: u16) {
+    let _tone = Tone::start(freq_hz);
+    if interrupts::are_enabled() {
+        let _ = time::wait_until(u64::from(duration_ms), || false);
+    } else {
+        let mut delay = Port::<u8>::new(IO_DELAY_PORT);
+        for _ in 0..u32::from(duration_ms) * 1000 {
+            unsafe { delay.write(0) };
+        }
+    }
+}
+
+/// panic时的短促低音, 需启用`beep` feature
+pub fn panic_beep() {
+    if cfg!(feature = "beep") {
+        beep_blocking(PANIC_BEEP.0, PANIC_BEEP.1);
+    }
+}
+
+/// 测试全部通过时的短促高音, 需启用`beep` feature
+pub fn success_beep() {
+    if cfg!(feature = "beep") {
+        beep_blocking(SUCCESS_BEEP.0, SUCCESS_BEEP.1);
+    }
+}
+
+#[cfg(test)]
+struct MockControl(u8);
+
+#[cfg(test)]
+impl ControlPort for MockControl {
+    fn read(&mut self) -> u8 {
+        self.0
+    }
+
+    fn write(&mut self, value: u8) {
+        self.0 = value;
+    }
+}
+
+#[test_case]
+fn test_divisor() {
+    assert_eq!(divisor(440), 2711);
+    assert_eq!(divisor(1000), 1193);
+    // 超出16位的分频值被截断到最低频率
+    assert_eq!(divisor(1), u16::MAX);
+    assert_eq!(divisor(0), u16::MAX);
+    assert_eq!(divisor(u16::MAX), 18);
+}
+
+#[test_case]
+fn test_off_preserves_other_bits() {
+    let mut port = MockControl(0b1010_1100);
+    gate_on(&mut port);
+    assert_eq!(port.0, 0b1010_1111);
+    gate_off(&mut port);
+    assert_eq!(port.0, 0b1010_1100);
+
+    let mut port = MockControl(0xFF);
+    gate_off(&mut port);
+    assert_eq!(port.0, 0xFC);
+}
@@ -0,0 +1,321 @@
+use core::fmt::{self, Write};
+use core::ptr::addr_of;
+
+use spin::Once;
+
+/// 为符号表预留的字节数, 需与build/ksyms.rs保持一致
+pub const KSYMS_SIZE: usize = 1 << 20;
+const MAGIC: &[u8; 4] = b"KSYM";
+// magic、符号数、字符串表长度、保留、写入时`ksyms_anchor`的地址
+const HEADER_SIZE: usize = 24;
+// 地址、名称在字符串表中的偏移和长度
+const ENTRY_SIZE: usize = 16;
+
+// 链接后由build/runner.sh调用build/ksyms.rs写入按地址排序的函数符号, 没有写入时全为0
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+/// 写入符号表时记录这个函数的地址, 与运行时的地址不同说明符号表来自另一次构建
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn ksyms_anchor() {}
+
+/// 按地址排序的符号表
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    strings: &'a [u8],
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> SymbolTable<'a> {
+    /// 解析build/ksyms.rs生成的符号表. 格式不对、没有排序或记录的`anchor`不同时为None
+    pub fn parse(data: &'a [u8], anchor: u64) -> Option<SymbolTable<'a>> {
+        if data.len() < HEADER_SIZE || &data[..4] != MAGIC || u64_at(data, 16) != anchor {
+            return None;
+        }
+        let count = u32_at(data, 4) as usize;
+        let strings_len = u32_at(data, 8) as usize;
+        let entries_end = HEADER_SIZE.checked_add(count.checked_mul(ENTRY_SIZE)?)?;
+        let strings_end = entries_end.checked_add(strings_len)?;
+        if strings_end > data.len() {
+            return None;
+        }
+        let table = SymbolTable {
+            entries: &data[HEADER_SIZE..entries_end],
+            strings: &data[entries_end..strings_end],
+        };
+        let sorted = (1..count).all(|i| table.address(i - 1) <= table.address(i));
+        let names = (0..count).all(|i| table.name(i).is_some());
+        (sorted && names).then_some(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn address(&self, i: usize) -> u64 {
+        u64_at(self.entries, i * ENTRY_SIZE)
+    }
+
+    fn name(&self, i: usize) -> Option<&'a str> {
+        let offset = u32_at(self.entries, i * ENTRY_SIZE + 8) as usize;
+        let len = u32_at(self.entries, i * ENTRY_SIZE + 12) as usize;
+        let name = self.strings.get(offset..offset.checked_add(len)?)?;
+        core::str::from_utf8(name).ok()
+    }
+
+    /// 不大于`addr`的最后一个符号和`addr`相对它的偏移
+    pub fn resolve(&self, addr: u64) -> Option<(&'a str, u64)> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.address(mid) <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let i = low.checked_sub(1)?;
+        Some((self.name(i)?, addr - self.address(i)))
+    }
+}
+
+// 内容在链接后才写入, 不能让编译器按全0的初值优化
+fn ksyms() -> &'static [u8] {
+    let data = core::hint::black_box(addr_of!(KSYMS).cast::<u8>());
+    unsafe { core::slice::from_raw_parts(data, KSYMS_SIZE) }
+}
+
+// 运行时`ksyms_anchor`的地址, 与写入时记录的不同说明符号表已过期
+fn anchor_address() -> u64 {
+    ksyms_anchor as extern "C" fn() as usize as u64
+}
+
+/// 内核自己的符号表, 没有写入或者已过期时为None
+pub fn kernel_symbols() -> Option<SymbolTable<'static>> {
+    static TABLE: Once<Option<SymbolTable<'static>>> = Once::new();
+    *TABLE.call_once(|| SymbolTable::parse(ksyms(), anchor_address()))
+}
+
+/// 包含`addr`的内核函数的名称(可能是rustc修饰过的)和偏移, 没有符号表时为None
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    kernel_symbols()?.resolve(addr)
+}
+
+/// 还原旧格式(`_ZN...17h<hash>E`)的rustc修饰名并去掉哈希, 其他名称原样输出
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(path) = legacy_path(self.0) else {
+            return f.write_str(self.0);
+        };
+        let mut components = Components(path).peekable();
+        let mut first = true;
+        while let Some(component) = components.next() {
+            if components.peek().is_none() && is_hash(component) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_ident(f, component)?;
+        }
+        Ok(())
+    }
+}
+
+// `_ZN`和`E`之间的部分, 各部分的长度都正确时才返回. 忽略`E`之后的后缀, 比如LLVM加的`.llvm.1234`
+fn legacy_path(name: &str) -> Option<&str> {
+    let name = name.strip_prefix('_').unwrap_or(name);
+    let path = name
+        .strip_prefix("_ZN")
+        .or_else(|| name.strip_prefix("ZN"))?;
+    let mut components = Components(path);
+    while components.next().is_some() {}
+    let rest = components.0.strip_prefix('E')?;
+    if rest.is_empty() || rest.starts_with('.') {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+// 以长度开头的各部分, 遇到`E`或者格式错误时结束
+struct Components<'a>(&'a str);
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let digits = self.0.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = self.0[..digits].parse().ok()?;
+        let rest = &self.0[digits..];
+        let component = rest.get(..len).filter(|_| len > 0)?;
+        self.0 = &rest[len..];
+        Some(component)
+    }
+}
+
+// rustc附加的`h`加16位十六进制哈希
+fn is_hash(component: &str) -> bool {
+    component.len() == 17
+        && component.starts_with('h')
+        && component[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn write_ident(f: &mut fmt::Formatter, ident: &str) -> fmt::Result {
+    // 以`$`开头的部分前面加了`_`
+    let mut rest = ident
+        .strip_prefix('_')
+        .filter(|rest| rest.starts_with('$'))
+        .unwrap_or(ident);
+    while let Some(c) = rest.chars().next() {
+        if let Some(tail) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = tail;
+            continue;
+        }
+        if c == '$' {
+            let decoded = rest[1..]
+                .find('$')
+                .and_then(|end| Some((unescape(&rest[1..1 + end])?, end)));
+            if let Some((c, end)) = decoded {
+                f.write_char(c)?;
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+        f.write_char(c)?;
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(())
+}
+
+fn unescape(code: &str) -> Option<char> {
+    match code {
+        "SP" => Some('@'),
+        "BP" => Some('*'),
+        "RF" => Some('&'),
+        "LT" => Some('<'),
+        "GT" => Some('>'),
+        "LP" => Some('('),
+        "RP" => Some(')'),
+        "C" => Some(','),
+        _ => char::from_u32(u32::from_str_radix(code.strip_prefix('u')?, 16).ok()?),
+    }
+}
+
+#[test_case]
+fn test_demangle() {
+    use alloc::format;
+
+    let demangle = |name: &str| format!("{}", Demangle(name));
+    assert_eq!(
+        demangle("_ZN6toy_os6memory14translate_addr17h0123456789abcdefE"),
+        "toy_os::memory::translate_addr"
+    );
+    assert_eq!(
+        demangle(
+            "_ZN4core3ptr46drop_in_place$LT$alloc..vec..Vec$LT$u8$GT$$GT$17h00000000000000ffE"
+        ),
+        "core::ptr::drop_in_place<alloc::vec::Vec<u8>>"
+    );
+    assert_eq!(
+        demangle(
+            "_ZN48_$LT$toy_os..Foo$u20$as$u20$core..fmt..Debug$GT$3fmt17h1111111111111111E.llvm.42"
+        ),
+        "<toy_os::Foo as core::fmt::Debug>::fmt"
+    );
+    // 没有哈希时也可以还原
+    assert_eq!(demangle("_ZN3foo3barE"), "foo::bar");
+    // 其他名称原样输出
+    assert_eq!(demangle("ksyms_anchor"), "ksyms_anchor");
+    assert_eq!(demangle("_ZN3fooE3bar"), "_ZN3fooE3bar");
+    assert_eq!(demangle("_ZN9tooshortE"), "_ZN9tooshortE");
+    assert_eq!(demangle("_RNvC6toy_os4main"), "_RNvC6toy_os4main");
+}
+
+#[cfg(test)]
+fn build_table(anchor: u64, symbols: &[(u64, &str)]) -> alloc::vec::Vec<u8> {
+    let mut data = alloc::vec::Vec::from(&MAGIC[..]);
+    let strings_len: usize = symbols.iter().map(|(_, name)| name.len()).sum();
+    data.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    data.extend_from_slice(&(strings_len as u32).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&anchor.to_le_bytes());
+    let mut offset = 0u32;
+    for (addr, name) in symbols {
+        data.extend_from_slice(&addr.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        offset += name.len() as u32;
+    }
+    for (_, name) in symbols {
+        data.extend_from_slice(name.as_bytes());
+    }
+    // 符号表之后是预留空间中剩余的0
+    data.resize(data.len() + 64, 0);
+    data
+}
+
+#[test_case]
+fn test_symbol_table() {
+    let data = build_table(
+        0x1234,
+        &[(0x1000, "first"), (0x1010, "second"), (0x2000, "third")],
+    );
+    let table = SymbolTable::parse(&data, 0x1234).unwrap();
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.resolve(0xfff), None);
+    assert_eq!(table.resolve(0x1000), Some(("first", 0)));
+    // 两个符号之间的地址属于前一个
+    assert_eq!(table.resolve(0x100f), Some(("first", 0xf)));
+    assert_eq!(table.resolve(0x1010), Some(("second", 0)));
+    assert_eq!(table.resolve(0x1fff), Some(("second", 0xfef)));
+    assert_eq!(table.resolve(0x2024), Some(("third", 0x24)));
+    assert!(SymbolTable::parse(&build_table(0, &[]), 0)
+        .unwrap()
+        .is_empty());
+
+    // 其他构建的符号表、全0的预留空间、没有排序和越界的表
+    assert!(SymbolTable::parse(&data, 0x5678).is_none());
+    assert!(SymbolTable::parse(&[0; 64], 0).is_none());
+    let unsorted = build_table(0, &[(0x2000, "b"), (0x1000, "a")]);
+    assert!(SymbolTable::parse(&unsorted, 0).is_none());
+    let mut truncated = build_table(0, &[(0x1000, "a")]);
+    truncated[8..12].copy_from_slice(&1000u32.to_le_bytes());
+    assert!(SymbolTable::parse(&truncated, 0).is_none());
+}
+
+#[test_case]
+fn test_kernel_symbols() {
+    // 不经过build/runner.sh启动时没有符号表, 只能输出地址
+    let Some(table) = kernel_symbols() else {
+        return;
+    };
+    assert!(!table.is_empty());
+    let anchor = anchor_address();
+    assert_eq!(resolve(anchor), Some(("ksyms_anchor", 0)));
+    assert_eq!(resolve(anchor + 1), Some(("ksyms_anchor", 1)));
+    let (name, offset) = resolve(anchor_address as fn() -> u64 as usize as u64 + 4).unwrap();
+    assert_eq!(offset, 4);
+    assert_eq!(
+        alloc::format!("{}", Demangle(name)),
+        "toy_os::symbols::anchor_address"
+    );
+}
@@ -0,0 +1,175 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use x86_64::instructions::interrupts;
+
+#[cfg(feature = "profile")]
+use crate::profile;
+#[cfg(feature = "tracing")]
+use crate::trace::{self, EventId};
+
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod tracked;
+
+pub use tracked::{PanicRelease, TrackedMutex, TrackedMutexGuard};
+
+/// 持有期间关闭本处理器中断的自旋锁, 守卫释放时恢复加锁前的中断状态
+///
+/// 中断处理函数也会获取的锁应使用它, 否则持有锁时到来的中断会死锁.
+/// 启用`lockdep` feature时检查加锁顺序, 启用`profile` feature时记录关中断的时长,
+/// 启用`tracing` feature时记录加锁和释放事件, 都关闭时没有额外的字段和开销
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+    #[cfg(feature = "lockdep")]
+    class: lockdep::LockClass,
+    #[cfg(any(feature = "profile", feature = "tracing"))]
+    name: &'static str,
+}
+
+impl<T> IrqMutex<T> {
+    /// `name`出现在lockdep的报告、`profile::report`和`trace::dump`中
+    pub const fn new(name: &'static str, value: T) -> Self {
+        #[cfg(not(any(feature = "lockdep", feature = "profile", feature = "tracing")))]
+        let _ = name;
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+            #[cfg(feature = "lockdep")]
+            class: lockdep::LockClass::new(name),
+            #[cfg(any(feature = "profile", feature = "tracing"))]
+            name,
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        #[cfg(feature = "profile")]
+        let start = profile::now();
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class);
+        let guard = self.inner.lock();
+        #[cfg(feature = "tracing")]
+        crate::trace!(EventId::LockAcquire, trace::lock_arg(self.name));
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            enabled,
+            #[cfg(feature = "profile")]
+            start,
+            #[cfg(feature = "lockdep")]
+            class: &self.class,
+            #[cfg(any(feature = "profile", feature = "tracing"))]
+            name: self.name,
+        }
+    }
+
+    /// 锁已被持有时立即返回None. 不会等待, 因此lockdep不检查它的加锁顺序
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        #[cfg(feature = "profile")]
+        let start = profile::now();
+        let Some(guard) = self.inner.try_lock() else {
+            if enabled {
+                interrupts::enable();
+            }
+            return None;
+        };
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire_nonblocking(&self.class);
+        #[cfg(feature = "tracing")]
+        crate::trace!(EventId::LockAcquire, trace::lock_arg(self.name));
+        Some(IrqMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            enabled,
+            #[cfg(feature = "profile")]
+            start,
+            #[cfg(feature = "lockdep")]
+            class: &self.class,
+            #[cfg(any(feature = "profile", feature = "tracing"))]
+            name: self.name,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// 强制释放锁, 持有者的守卫不会再恢复中断状态
+    ///
+    /// # Safety
+    ///
+    /// 调用者需保证原来的持有者不会再访问受保护的数据
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() };
+        #[cfg(feature = "lockdep")]
+        lockdep::release(&self.class);
+    }
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    // 加锁前中断是否开启
+    enabled: bool,
+    // 关中断的时刻
+    #[cfg(feature = "profile")]
+    start: profile::Stamp,
+    #[cfg(feature = "lockdep")]
+    class: &'a lockdep::LockClass,
+    #[cfg(any(feature = "profile", feature = "tracing"))]
+    name: &'static str,
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // 先释放锁再开中断
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.class);
+        #[cfg(feature = "profile")]
+        profile::critical_section_end(self.name, self.start);
+        #[cfg(feature = "tracing")]
+        crate::trace!(EventId::LockRelease, trace::lock_arg(self.name));
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_irq_mutex_restores_interrupts() {
+    static LOCK: IrqMutex<u32> = IrqMutex::new("test_irq_mutex", 0);
+
+    assert!(interrupts::are_enabled());
+    {
+        let mut outer = LOCK.lock();
+        *outer += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(LOCK.try_lock().is_none());
+        // 释放失败的try_lock不会打开中断
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+
+    // 关中断时加锁, 释放后仍然关闭
+    interrupts::without_interrupts(|| {
+        *LOCK.try_lock().unwrap() += 1;
+        assert!(!interrupts::are_enabled());
+    });
+    assert_eq!(*LOCK.lock(), 2);
+    assert!(!LOCK.is_locked());
+}
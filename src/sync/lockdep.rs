@@ -0,0 +1,416 @@
+//! 加锁顺序检查, 只在启用`lockdep` feature时编译
+//!
+//! 每个`IrqMutex`第一次加锁时分配一个编号. 每个处理器记录自己持有的锁, 获取新锁时
+//! 为每个已持有的锁记录一条"持有 -> 获取"的边. 新边使依赖图成环时, 说明存在另一处
+//! 以相反顺序加锁的代码, 两者并发时会死锁, 此时输出双方的调用栈并panic
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::{backtrace, percpu, serial};
+
+/// 最多跟踪的锁数量, 之后第一次加锁的锁不再检查
+pub const MAX_LOCKS: usize = 32;
+// 每个处理器最多同时持有的锁
+const MAX_HELD: usize = 16;
+// 每次加锁记录的返回地址数
+const TRACE_DEPTH: usize = 6;
+
+type Trace = [u64; TRACE_DEPTH];
+
+/// `IrqMutex`在依赖图中的节点
+pub struct LockClass {
+    name: &'static str,
+    // 编号加1, 0表示还没有分配
+    id: AtomicUsize,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        LockClass {
+            name,
+            id: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // 编号用完时返回None
+    fn id(&self) -> Option<usize> {
+        match self.id.load(Ordering::Acquire) {
+            0 => GRAPH.lock().register(self),
+            id => Some(id - 1),
+        }
+    }
+}
+
+/// 锁之间的依赖图, 边`a -> b`表示曾在持有a时获取b
+pub struct LockGraph {
+    names: [&'static str; MAX_LOCKS],
+    count: usize,
+    // edges[a]的第b位表示边a -> b
+    edges: [u32; MAX_LOCKS],
+    // 每条边第一次出现时获取b的调用栈
+    traces: [[Trace; MAX_LOCKS]; MAX_LOCKS],
+}
+
+/// 新的边`from -> to`与已有的路径`to -> ... -> from`构成环
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cycle {
+    path: [usize; MAX_LOCKS],
+    len: usize,
+}
+
+impl Cycle {
+    /// 已有路径上的锁, 从`to`开始到`from`结束
+    pub fn path(&self) -> &[usize] {
+        &self.path[..self.len]
+    }
+}
+
+impl LockGraph {
+    pub const fn new() -> Self {
+        LockGraph {
+            names: [""; MAX_LOCKS],
+            count: 0,
+            edges: [0; MAX_LOCKS],
+            traces: [[[0; TRACE_DEPTH]; MAX_LOCKS]; MAX_LOCKS],
+        }
+    }
+
+    // 持有图的锁时分配, 避免两个处理器为同一个锁分配不同的编号
+    fn register(&mut self, class: &LockClass) -> Option<usize> {
+        if let Some(id) = class.id.load(Ordering::Acquire).checked_sub(1) {
+            return Some(id);
+        }
+        let id = self.add_lock(class.name)?;
+        class.id.store(id + 1, Ordering::Release);
+        Some(id)
+    }
+
+    /// 加入一个锁, 编号用完时返回None
+    pub fn add_lock(&mut self, name: &'static str) -> Option<usize> {
+        if self.count == MAX_LOCKS {
+            return None;
+        }
+        self.names[self.count] = name;
+        self.count += 1;
+        Some(self.count - 1)
+    }
+
+    pub fn name(&self, id: usize) -> &'static str {
+        self.names[id]
+    }
+
+    /// 记录边`from -> to`. 构成环时不加入这条边, 返回已有的反向路径
+    // 加锁路径上不能分配堆, Cycle只能按值返回
+    #[allow(clippy::result_large_err)]
+    pub fn add_edge(&mut self, from: usize, to: usize, trace: &Trace) -> Result<(), Cycle> {
+        if self.edges[from] & (1 << to) != 0 {
+            return Ok(());
+        }
+        if let Some(cycle) = self.find_path(to, from) {
+            return Err(cycle);
+        }
+        self.edges[from] |= 1 << to;
+        self.traces[from][to] = *trace;
+        Ok(())
+    }
+
+    // 广度优先搜索`from`到`to`的路径
+    fn find_path(&self, from: usize, to: usize) -> Option<Cycle> {
+        let mut parent = [usize::MAX; MAX_LOCKS];
+        let mut queue = [0; MAX_LOCKS];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        parent[from] = from;
+        while head < tail {
+            let node = queue[head];
+            head += 1;
+            if node == to {
+                let mut cycle = Cycle {
+                    path: [0; MAX_LOCKS],
+                    len: 0,
+                };
+                let mut node = to;
+                loop {
+                    cycle.path[cycle.len] = node;
+                    cycle.len += 1;
+                    if node == from {
+                        break;
+                    }
+                    node = parent[node];
+                }
+                cycle.path[..cycle.len].reverse();
+                return Some(cycle);
+            }
+            for (next, slot) in parent.iter_mut().enumerate().take(self.count) {
+                if self.edges[node] & (1 << next) != 0 && *slot == usize::MAX {
+                    *slot = node;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Default for LockGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    id: usize,
+    trace: Trace,
+}
+
+/// 一个处理器持有的锁, 位于每处理器数据中. 只由所属处理器在关中断时访问
+pub struct HeldLocks {
+    locks: UnsafeCell<[Held; MAX_HELD]>,
+    len: AtomicUsize,
+}
+
+unsafe impl Sync for HeldLocks {}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        const EMPTY: Held = Held {
+            id: 0,
+            trace: [0; TRACE_DEPTH],
+        };
+        HeldLocks {
+            locks: UnsafeCell::new([EMPTY; MAX_HELD]),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn held(&self) -> &[Held] {
+        let len = self.len.load(Ordering::Relaxed);
+        let locks = unsafe { &*self.locks.get() };
+        &locks[..len]
+    }
+
+    // 超出容量时返回false, 这个锁不再被跟踪
+    fn push(&self, held: Held) -> bool {
+        let len = self.len.load(Ordering::Relaxed);
+        if len == MAX_HELD {
+            return false;
+        }
+        unsafe { (*self.locks.get())[len] = held };
+        self.len.store(len + 1, Ordering::Relaxed);
+        true
+    }
+
+    // 锁不一定按获取的相反顺序释放
+    fn remove(&self, id: usize) -> bool {
+        let len = self.len.load(Ordering::Relaxed);
+        let locks = unsafe { &mut *self.locks.get() };
+        let Some(index) = locks[..len].iter().rposition(|held| held.id == id) else {
+            return false;
+        };
+        locks.copy_within(index + 1..len, index);
+        self.len.store(len - 1, Ordering::Relaxed);
+        true
+    }
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static GRAPH: Mutex<LockGraph> = Mutex::new(LockGraph::new());
+// 启动早期还没有每处理器数据时使用, 那时只有BSP在运行
+static EARLY_HELD: HeldLocks = HeldLocks::new();
+// 报告过一次错误后停止检查, 让panic的输出路径可以继续加锁
+static DISABLED: AtomicBool = AtomicBool::new(false);
+static OVERFLOW_REPORTED: AtomicBool = AtomicBool::new(false);
+
+fn held_locks() -> &'static HeldLocks {
+    match percpu::try_get() {
+        Some(percpu) => percpu.held_locks(),
+        None => &EARLY_HELD,
+    }
+}
+
+#[inline(never)]
+fn capture() -> Trace {
+    let mut trace = [0; TRACE_DEPTH];
+    for (slot, ret) in trace.iter_mut().zip(backtrace::frames()) {
+        *slot = ret.as_u64();
+    }
+    trace
+}
+
+/// 由`IrqMutex::lock`在关中断后、获取锁之前调用
+pub(crate) fn acquire(class: &LockClass) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(id) = class.id() else {
+        return;
+    };
+    let held = held_locks();
+    let trace = capture();
+    if let Some(previous) = held.held().iter().find(|held| held.id == id) {
+        report(|out| {
+            writeln!(out, "lockdep: recursive locking of {}", class.name)?;
+            writeln!(out, "first acquired at:")?;
+            print_trace(out, &previous.trace)?;
+            writeln!(out, "acquired again at:")?;
+            print_trace(out, &trace)
+        });
+        panic!("lockdep: recursive locking of {}", class.name);
+    }
+
+    let mut graph = GRAPH.lock();
+    for holding in held.held() {
+        if let Err(cycle) = graph.add_edge(holding.id, id, &trace) {
+            let holding_name = graph.name(holding.id);
+            report(|out| report_cycle(out, &graph, holding, id, &trace, &cycle));
+            drop(graph);
+            panic!(
+                "lockdep: lock order violation: acquiring {} while holding {}",
+                class.name, holding_name
+            );
+        }
+    }
+    drop(graph);
+    push(held, Held { id, trace });
+}
+
+/// 由`IrqMutex::try_lock`在成功获取后调用: 只登记为已持有, 不加入边
+pub(crate) fn acquire_nonblocking(class: &LockClass) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(id) = class.id() {
+        push(
+            held_locks(),
+            Held {
+                id,
+                trace: capture(),
+            },
+        );
+    }
+}
+
+fn push(held: &HeldLocks, lock: Held) {
+    if !held.push(lock) && !OVERFLOW_REPORTED.swap(true, Ordering::Relaxed) {
+        report(|out| {
+            writeln!(
+                out,
+                "lockdep: more than {} locks held, checking disabled",
+                MAX_HELD
+            )
+        });
+    }
+}
+
+/// 由`IrqMutexGuard`在释放锁后调用
+pub(crate) fn release(class: &LockClass) {
+    let id = class.id.load(Ordering::Acquire);
+    if id == 0 {
+        return;
+    }
+    // 每处理器数据初始化之前获取的锁记录在EARLY_HELD中
+    if !held_locks().remove(id - 1) {
+        EARLY_HELD.remove(id - 1);
+    }
+}
+
+// 通过不加锁的串口输出, 出错的锁可能就是控制台的锁
+fn report(f: impl FnOnce(&mut dyn Write) -> fmt::Result) {
+    DISABLED.store(true, Ordering::Relaxed);
+    let _ = f(&mut serial::unlocked_writer());
+}
+
+fn report_cycle(
+    out: &mut dyn Write,
+    graph: &LockGraph,
+    holding: &Held,
+    acquiring: usize,
+    trace: &Trace,
+    cycle: &Cycle,
+) -> fmt::Result {
+    writeln!(
+        out,
+        "lockdep: lock order violation: acquiring {} while holding {}",
+        graph.name(acquiring),
+        graph.name(holding.id)
+    )?;
+    writeln!(out, "{} was acquired at:", graph.name(holding.id))?;
+    print_trace(out, &holding.trace)?;
+    writeln!(out, "{} is being acquired at:", graph.name(acquiring))?;
+    print_trace(out, trace)?;
+    writeln!(out, "the opposite order was recorded earlier:")?;
+    for pair in cycle.path().windows(2) {
+        let (from, to) = (pair[0], pair[1]);
+        writeln!(
+            out,
+            "{} acquired while holding {} at:",
+            graph.name(to),
+            graph.name(from)
+        )?;
+        print_trace(out, &graph.traces[from][to])?;
+    }
+    Ok(())
+}
+
+fn print_trace(out: &mut dyn Write, trace: &Trace) -> fmt::Result {
+    let frames = trace.iter().take_while(|&&addr| addr != 0);
+    for (index, &addr) in frames.enumerate() {
+        backtrace::print_frame(out, index, VirtAddr::new_truncate(addr))?;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_lock_graph_cycles() {
+    let mut graph = LockGraph::new();
+    let trace = [0; TRACE_DEPTH];
+    let a = graph.add_lock("a").unwrap();
+    let b = graph.add_lock("b").unwrap();
+    let c = graph.add_lock("c").unwrap();
+    // AB之后的BA在真正死锁之前就被发现
+    assert_eq!(graph.add_edge(a, b, &trace), Ok(()));
+    assert_eq!(graph.add_edge(a, b, &trace), Ok(()));
+    assert_eq!(graph.add_edge(b, a, &trace).unwrap_err().path(), [a, b]);
+    // 经过多个锁的环
+    assert_eq!(graph.add_edge(b, c, &trace), Ok(()));
+    assert_eq!(graph.add_edge(c, a, &trace).unwrap_err().path(), [a, b, c]);
+    // 被拒绝的边没有加入图中
+    assert_eq!(graph.edges[b] & (1 << a), 0);
+    assert_eq!(graph.edges[c] & (1 << a), 0);
+
+    while graph.add_lock("filler").is_some() {}
+    assert_eq!(graph.count, MAX_LOCKS);
+}
+
+#[test_case]
+fn test_held_locks() {
+    let held = HeldLocks::new();
+    let lock = |id| Held {
+        id,
+        trace: [0; TRACE_DEPTH],
+    };
+    assert!(held.push(lock(1)) && held.push(lock(2)) && held.push(lock(3)));
+    // 不按顺序释放
+    assert!(held.remove(2));
+    assert!(!held.remove(2));
+    let ids: alloc::vec::Vec<usize> = held.held().iter().map(|held| held.id).collect();
+    assert_eq!(ids, [1, 3]);
+    while held.push(lock(4)) {}
+    assert_eq!(held.held().len(), MAX_HELD);
+}
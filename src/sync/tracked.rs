@@ -0,0 +1,161 @@
+//! 记录持有者所在处理器的`IrqMutex`, 用于panic时仍要输出的控制台和串口
+//!
+//! 关中断只能防止本处理器上的中断处理函数重入, 有多个处理器时锁可能正被其他处理器合法地持有.
+//! panic路径通过`release_for_panic`取得锁: 锁被本处理器持有时说明panic发生在输出的中途, 直接释放;
+//! 被其他处理器持有时先等待它输出完, 超时后强行取走
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{IrqMutex, IrqMutexGuard};
+use crate::percpu;
+
+// 没有持有者, 或持有者刚刚获取锁而还没有记录
+const NO_OWNER: u32 = u32::MAX;
+// 等待其他处理器释放锁的自旋次数. panic时时钟中断可能已经关闭, 不能按时间计
+const PANIC_WAIT_SPINS: u32 = 1 << 24;
+
+/// `release_for_panic`的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicRelease {
+    /// 锁没有被持有, 或持有者在等待期间释放了它
+    Free,
+    /// 本处理器在持有锁时panic, 锁已释放
+    Own,
+    /// 等待超时后从其他处理器取走, 附带它的编号. 它之后的输出可能与panic信息交错
+    Stolen(Option<u32>),
+}
+
+pub struct TrackedMutex<T> {
+    inner: IrqMutex<T>,
+    owner: AtomicU32,
+}
+
+impl<T> TrackedMutex<T> {
+    /// `name`的用途与`IrqMutex::new`相同
+    pub const fn new(name: &'static str, value: T) -> Self {
+        TrackedMutex {
+            inner: IrqMutex::new(name, value),
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> TrackedMutexGuard<'_, T> {
+        self.track(self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> Option<TrackedMutexGuard<'_, T>> {
+        self.inner.try_lock().map(|guard| self.track(guard))
+    }
+
+    fn track<'a>(&'a self, guard: IrqMutexGuard<'a, T>) -> TrackedMutexGuard<'a, T> {
+        self.owner.store(percpu::cpu_id(), Ordering::Release);
+        TrackedMutexGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// 持有锁的处理器, 没有被持有时为None
+    pub fn owner(&self) -> Option<u32> {
+        match self.owner.load(Ordering::Acquire) {
+            NO_OWNER => None,
+            cpu => Some(cpu),
+        }
+    }
+
+    /// 使panic路径可以获取锁. 锁被本处理器持有时立即释放, 被其他处理器持有时自旋等待,
+    /// 到达上限仍未释放就强行取走
+    ///
+    /// # Safety
+    /// 只能在不会返回的panic路径上调用. 本处理器上原来的守卫不会再运行; 被取走的处理器如果继续运行,
+    /// 它的守卫释放时会连同panic路径持有的锁一起释放, 这是为了不死锁而接受的代价
+    pub unsafe fn release_for_panic(&self) -> PanicRelease {
+        if !self.is_locked() {
+            return PanicRelease::Free;
+        }
+        if self.owner() == Some(percpu::cpu_id()) {
+            self.force_unlock();
+            return PanicRelease::Own;
+        }
+        for _ in 0..PANIC_WAIT_SPINS {
+            if !self.is_locked() {
+                return PanicRelease::Free;
+            }
+            core::hint::spin_loop();
+        }
+        let owner = self.owner();
+        self.force_unlock();
+        PanicRelease::Stolen(owner)
+    }
+
+    unsafe fn force_unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Release);
+        self.inner.force_unlock();
+    }
+}
+
+pub struct TrackedMutexGuard<'a, T> {
+    guard: IrqMutexGuard<'a, T>,
+    owner: &'a AtomicU32,
+}
+
+impl<T> Deref for TrackedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for TrackedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for TrackedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // 之后guard字段被drop时才释放锁
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+#[test_case]
+fn test_tracked_mutex_owner() {
+    use x86_64::instructions::interrupts;
+
+    static LOCK: TrackedMutex<u32> = TrackedMutex::new("test_tracked_mutex", 0);
+
+    assert_eq!(LOCK.owner(), None);
+    {
+        let mut guard = LOCK.lock();
+        *guard += 1;
+        assert_eq!(LOCK.owner(), Some(percpu::cpu_id()));
+        assert!(LOCK.try_lock().is_none());
+    }
+    assert_eq!(LOCK.owner(), None);
+    assert_eq!(unsafe { LOCK.release_for_panic() }, PanicRelease::Free);
+
+    // 模拟在输出中途panic: 本处理器持有的锁立即释放, 守卫不再运行
+    let guard = LOCK.lock();
+    assert_eq!(unsafe { LOCK.release_for_panic() }, PanicRelease::Own);
+    core::mem::forget(guard);
+    assert!(!LOCK.is_locked());
+    interrupts::enable();
+
+    // 记录的持有者是另一个处理器时等待后取走
+    let guard = LOCK.lock();
+    LOCK.owner.store(7, Ordering::Release);
+    assert_eq!(
+        unsafe { LOCK.release_for_panic() },
+        PanicRelease::Stolen(Some(7))
+    );
+    core::mem::forget(guard);
+    interrupts::enable();
+    assert_eq!(*LOCK.lock(), 1);
+}
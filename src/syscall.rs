@@ -0,0 +1,362 @@
+use alloc::string::String;
+use alloc::vec;
+use core::arch::global_asm;
+
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::VirtAddr;
+
+use crate::cpu::msr::{Efer, Fmask, Lstar, Star};
+use crate::error::{Context, KernelError};
+use crate::process;
+use crate::usermode::{self, UserExit};
+use crate::{gdt, percpu, print, thread, time, vfs};
+
+pub mod user;
+
+pub use user::{copy_from_user, copy_to_user, strncpy_from_user, UserAccessError};
+
+global_asm!(
+    include_str!("syscall/entry.s"),
+    user_rsp = const percpu::SYSCALL_USER_RSP_OFFSET,
+    kernel_stack = const percpu::SYSCALL_STACK_OFFSET,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn syscall_int80_entry();
+    fn syscall_fast_entry();
+}
+
+/// 系统调用使用的中断向量
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// 结束用户程序, rdi = 退出码
+pub const SYS_EXIT: u64 = 0;
+/// 写入文件描述符, rdi = fd, rsi = 缓冲区, rdx = 长度. 返回写入的字节数
+pub const SYS_WRITE: u64 = 1;
+/// 返回运行的毫秒数
+pub const SYS_UPTIME_MS: u64 = 2;
+/// 复制当前进程, 父进程返回子进程的pid, 子进程返回0. 需要进入时保存的用户寄存器, 由入口直接处理
+pub const SYS_FORK: u64 = 3;
+/// 保留匿名内存, rdi = 长度, rsi = `PROT_*`的组合. 返回起始地址, 页在第一次访问时映射并清零
+pub const SYS_MMAP: u64 = 4;
+/// 取消映射, rdi = 地址, rsi = 长度, 都必须是整页且在同一次mmap的区域内
+pub const SYS_MUNMAP: u64 = 5;
+/// 打开文件, rdi = 以NUL结尾的路径. 返回文件描述符
+pub const SYS_OPEN: u64 = 6;
+/// 从打开的文件读取, rdi = fd, rsi = 缓冲区, rdx = 长度. 返回读取的字节数, 到达末尾时返回0
+pub const SYS_READ: u64 = 7;
+/// 关闭文件描述符, rdi = fd
+pub const SYS_CLOSE: u64 = 8;
+/// 调整当前进程的资源上限, rdi = `process::RLIMIT_*`, rsi = 新的上限. 只有初始进程可以调用, 子进程继承上限
+pub const SYS_SETRLIMIT: u64 = 9;
+
+/// 标准输出, 目前唯一可写的文件描述符
+pub const STDOUT: u64 = 1;
+/// 一次write最多写入的字节数, 多余的部分由调用者再次写入
+pub const MAX_WRITE_LEN: usize = 4096;
+/// 一次read最多读取的字节数
+pub const MAX_READ_LEN: usize = 4096;
+
+/// 系统调用层特有的错误, 作为`KernelError`的上下文. 系统调用在rax中返回错误码的负值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// 文件描述符没有打开
+    BadFd,
+    /// 不存在的系统调用号
+    NoSys,
+    /// 当前线程没有在运行进程, 不能使用需要进程的系统调用
+    NotAProcess,
+}
+
+/// 系统调用入口在内核栈上保存的用户寄存器, 布局与entry.s一致. 从`rip`开始与int 0x80时CPU压入的顺序相同,
+/// syscall入口的`cs`和`ss`为0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+type Handler = fn(args: [u64; 3]) -> Result<u64, KernelError>;
+
+// 下标为系统调用号, fork由入口直接处理
+static SYSCALLS: [Option<Handler>; 10] = [
+    Some(sys_exit),
+    Some(sys_write),
+    Some(sys_uptime_ms),
+    None,
+    Some(sys_mmap),
+    Some(sys_munmap),
+    Some(sys_open),
+    Some(sys_read),
+    Some(sys_close),
+    Some(sys_setrlimit),
+];
+
+/// int 0x80入口. 门的DPL为3, 用户代码可以直接调用
+pub fn int80_handler_addr() -> VirtAddr {
+    VirtAddr::new(syscall_int80_entry as unsafe extern "C" fn() as usize as u64)
+}
+
+/// 为BSP启用syscall/sysret入口, 与int 0x80共用同一张分发表. 需要per-CPU数据
+///
+/// 入口使用TSS中ring 3进入内核时的栈, 切换线程时两者一起更新
+pub fn init() {
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    let star = star(kernel_code, kernel_data, user_code, user_data)
+        .expect("GDT layout does not match syscall/sysret");
+    percpu::get().set_syscall_stack(gdt::privilege_stack().as_u64());
+    unsafe {
+        star.write();
+        Lstar::write(VirtAddr::new(syscall_fast_entry as unsafe extern "C" fn() as usize as u64));
+        // 入口在切换到内核栈之前不能被中断
+        Fmask::write(
+            RFlags::INTERRUPT_FLAG
+                | RFlags::DIRECTION_FLAG
+                | RFlags::TRAP_FLAG
+                | RFlags::ALIGNMENT_CHECK,
+        );
+        Efer::read().with_syscall(true).write();
+    }
+}
+
+/// 检查GDT顺序后计算IA32_STAR. syscall从STAR取内核CS, SS为下一项;
+/// sysretq的用户SS为STAR中基准的下一项, 64位CS再下一项.
+/// 因此内核数据段必须紧跟内核代码段, 用户代码段必须紧跟用户数据段
+fn star(
+    kernel_code: SegmentSelector,
+    kernel_data: SegmentSelector,
+    user_code: SegmentSelector,
+    user_data: SegmentSelector,
+) -> Result<Star, &'static str> {
+    if kernel_data.index() != kernel_code.index() + 1 {
+        return Err("kernel data segment must follow kernel code segment");
+    }
+    if user_code.index() != user_data.index() + 1 {
+        return Err("user code segment must follow user data segment");
+    }
+    // sysret把RPL置为3, 基准选择子的RPL不影响结果
+    let sysret_base = ((user_data.index() - 1) << 3) | 3;
+    Ok(Star::new(kernel_code.index() << 3, sysret_base))
+}
+
+/// 执行系统调用, 返回放入rax的值: 非负为结果, 负数为错误码的负值. `SYS_FORK`只能从用户态调用, 这里返回`-ENOSYS`
+pub fn dispatch(number: u64, args: [u64; 3]) -> i64 {
+    let handler = usize::try_from(number)
+        .ok()
+        .and_then(|number| *SYSCALLS.get(number)?);
+    let Some(handler) = handler else {
+        return result(Err(SyscallError::NoSys.into()));
+    };
+    result(handler(args))
+}
+
+fn result(result: Result<u64, KernelError>) -> i64 {
+    match result {
+        Ok(value) => value as i64,
+        Err(err) => -err.to_errno(),
+    }
+}
+
+// 由entry.s调用, 中断已关闭. `frame`指向入口保存的用户寄存器
+#[no_mangle]
+extern "C" fn syscall_dispatch(
+    number: u64,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    frame: *const SyscallFrame,
+) -> i64 {
+    if number == SYS_FORK {
+        return result(sys_fork(unsafe { &*frame }));
+    }
+    dispatch(number, [arg0, arg1, arg2])
+}
+
+fn sys_exit(args: [u64; 3]) -> Result<u64, KernelError> {
+    usermode::exit(UserExit::Exit {
+        code: args[0] as i64,
+    })
+}
+
+// 非规范地址不在用户空间内
+fn user_addr(addr: u64) -> Result<VirtAddr, KernelError> {
+    VirtAddr::try_new(addr).map_err(|_| UserAccessError::OutOfRange.into())
+}
+
+fn sys_write(args: [u64; 3]) -> Result<u64, KernelError> {
+    let [fd, ptr, len] = args;
+    if fd != STDOUT {
+        return Err(SyscallError::BadFd.into());
+    }
+    let ptr = user_addr(ptr)?;
+    let len = (len as usize).min(MAX_WRITE_LEN);
+    let mut buf = vec![0; len];
+    copy_from_user(&mut buf, ptr)?;
+    print!("{}", String::from_utf8_lossy(&buf));
+    Ok(len as u64)
+}
+
+fn sys_uptime_ms(_args: [u64; 3]) -> Result<u64, KernelError> {
+    Ok(time::uptime().ms)
+}
+
+fn sys_mmap(args: [u64; 3]) -> Result<u64, KernelError> {
+    let [len, prot, _] = args;
+    Ok(process::mmap_current(len, prot)?.as_u64())
+}
+
+fn sys_munmap(args: [u64; 3]) -> Result<u64, KernelError> {
+    let [addr, len, _] = args;
+    process::munmap_current(addr, len)?;
+    Ok(0)
+}
+
+fn sys_open(args: [u64; 3]) -> Result<u64, KernelError> {
+    let mut path = vec![0; user::MAX_STRING_LEN];
+    let len = strncpy_from_user(&mut path, user_addr(args[0])?)?;
+    let path =
+        core::str::from_utf8(&path[..len]).map_err(|_| KernelError::InvalidInput(Context::None))?;
+    let file = thread::block_on(vfs::open(path))?;
+    process::open_current(file)
+}
+
+fn sys_read(args: [u64; 3]) -> Result<u64, KernelError> {
+    let [fd, ptr, len] = args;
+    let ptr = user_addr(ptr)?;
+    let mut buf = vec![0; (len as usize).min(MAX_READ_LEN)];
+    let len = process::read_current(fd, &mut buf)?;
+    copy_to_user(ptr, &buf[..len])?;
+    Ok(len as u64)
+}
+
+fn sys_close(args: [u64; 3]) -> Result<u64, KernelError> {
+    process::close_current(args[0])?;
+    Ok(0)
+}
+
+fn sys_setrlimit(args: [u64; 3]) -> Result<u64, KernelError> {
+    let [resource, limit, _] = args;
+    process::set_limit_current(resource, limit)?;
+    Ok(0)
+}
+
+fn sys_fork(frame: &SyscallFrame) -> Result<u64, KernelError> {
+    Ok(process::fork_current(frame)?.as_u64())
+}
+
+#[test_case]
+fn test_star_layout() {
+    use x86_64::PrivilegeLevel;
+
+    let selector = |index| SegmentSelector::new(index, PrivilegeLevel::Ring0);
+    let user = |index| SegmentSelector::new(index, PrivilegeLevel::Ring3);
+    let layout = star(selector(1), selector(2), user(4), user(3)).unwrap();
+    assert_eq!(layout.syscall_cs(), 0x08);
+    // sysretq: SS = 0x13 + 8, CS = 0x13 + 16
+    assert_eq!(layout.sysret_cs(), 0x13);
+    assert!(star(selector(1), selector(3), user(4), user(3)).is_err());
+    // 用户代码段在数据段之前的布局不能用于sysret
+    assert!(star(selector(1), selector(2), user(3), user(4)).is_err());
+
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    assert_eq!(
+        Star::read(),
+        star(kernel_code, kernel_data, user_code, user_data).unwrap()
+    );
+    assert!(Efer::read().syscall_enabled());
+}
+
+#[test_case]
+fn test_dispatch_errors() {
+    use crate::allocator::HEAP_START;
+    use crate::error::{EBADF, EFAULT, EINVAL, ENOSYS, EOPNOTSUPP};
+    use crate::layout;
+
+    assert_eq!(dispatch(999, [0; 3]), -ENOSYS);
+    assert_eq!(dispatch(u64::MAX, [0; 3]), -ENOSYS);
+    assert_eq!(dispatch(SYS_WRITE, [2, layout::USER.start, 1]), -EBADF);
+    assert_eq!(dispatch(SYS_WRITE, [STDOUT, HEAP_START as u64, 4]), -EFAULT);
+    // 非规范地址
+    assert_eq!(dispatch(SYS_WRITE, [STDOUT, 0x8000_0000_0000, 4]), -EFAULT);
+    assert_eq!(dispatch(SYS_WRITE, [STDOUT, 0, 0]), 0);
+    assert!(dispatch(SYS_UPTIME_MS, [0; 3]) >= 0);
+    assert_eq!(dispatch(SYS_FORK, [0; 3]), -ENOSYS);
+    // 参数先于调用者检查, 内核线程不能使用mmap
+    let prot = process::PROT_READ | process::PROT_WRITE;
+    assert_eq!(
+        dispatch(SYS_MMAP, [4096, prot | process::PROT_EXEC, 0]),
+        -EINVAL
+    );
+    assert_eq!(dispatch(SYS_MMAP, [4096, prot, 0]), -EOPNOTSUPP);
+    assert_eq!(
+        dispatch(SYS_MUNMAP, [layout::USER.start, 4096, 0]),
+        -EOPNOTSUPP
+    );
+    assert_eq!(dispatch(SYS_SETRLIMIT, [99, 0, 0]), -EINVAL);
+    assert_eq!(
+        dispatch(SYS_SETRLIMIT, [process::RLIMIT_PAGES, 1, 0]),
+        -EOPNOTSUPP
+    );
+}
+
+#[test_case]
+fn test_open_errors() {
+    use crate::error::{EFAULT, ENAMETOOLONG, ENOENT, EOPNOTSUPP};
+
+    let base = user::test_pages();
+    let path = base + 512u64;
+    copy_to_user(path, b"/etc/no-such-file\0").unwrap();
+    // 路径先于调用者检查, 不存在的文件在用户空间中是ENOENT
+    assert_eq!(dispatch(SYS_OPEN, [path.as_u64(), 0, 0]), -ENOENT);
+    if cfg!(feature = "initrd") {
+        // 文件存在, 但内核线程没有文件表
+        copy_to_user(path, b"/etc/motd\0").unwrap();
+        assert_eq!(dispatch(SYS_OPEN, [path.as_u64(), 0, 0]), -EOPNOTSUPP);
+    }
+    assert_eq!(dispatch(SYS_OPEN, [0, 0, 0]), -EFAULT);
+    // 第三页未映射, 路径在它之前没有结尾
+    let unterminated = base + (2 * 4096 - 4) as u64;
+    copy_to_user(unterminated, b"/etc").unwrap();
+    assert_eq!(dispatch(SYS_OPEN, [unterminated.as_u64(), 0, 0]), -EFAULT);
+    let mut long = vec![b'a'; user::MAX_STRING_LEN];
+    long[0] = b'/';
+    copy_to_user(base, &long).unwrap();
+    assert_eq!(dispatch(SYS_OPEN, [base.as_u64(), 0, 0]), -ENAMETOOLONG);
+    assert_eq!(dispatch(SYS_READ, [3, path.as_u64(), 16]), -EOPNOTSUPP);
+    assert_eq!(dispatch(SYS_CLOSE, [3, 0, 0]), -EOPNOTSUPP);
+}
+
+#[test_case]
+fn test_frame_layout() {
+    use core::mem::{offset_of, size_of};
+
+    // 与entry.s和usermode_resume中的偏移一致
+    assert_eq!(size_of::<SyscallFrame>(), 19 * 8);
+    assert_eq!(offset_of!(SyscallFrame, rdi), 0x50);
+    assert_eq!(offset_of!(SyscallFrame, rcx), 0x68);
+    assert_eq!(offset_of!(SyscallFrame, rip), 0x70);
+    assert_eq!(offset_of!(SyscallFrame, rflags), 0x80);
+    assert_eq!(offset_of!(SyscallFrame, rsp), 0x88);
+}
@@ -0,0 +1,117 @@
+# 系统调用入口. rax = 系统调用号, rdi/rsi/rdx = 参数, 结果写回rax.
+# int 0x80保存调用者保存的寄存器, 除rax外用户代码看到的寄存器不变;
+# syscall指令本身覆盖rcx和r11, 其余寄存器同样不变.
+# 两个入口在栈上保存相同布局的SyscallFrame, fork从中复制用户寄存器
+
+.pushsection .text
+.global syscall_int80_entry
+syscall_int80_entry:
+    # 来自ring 3时换入KERNEL_GS_BASE中的per-CPU数据指针
+    testb $3, 8(%rsp)
+    jz 1f
+    swapgs
+1:
+    cld
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %rbx
+    push %rbp
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    # syscall_dispatch(number, arg0, arg1, arg2, frame)
+    mov %rsp, %r8
+    mov %rdx, %rcx
+    mov %rsi, %rdx
+    mov %rdi, %rsi
+    mov %rax, %rdi
+    # CPU压入5项后栈对齐到8, 再压入14项, 调用前补齐到16
+    sub $8, %rsp
+    call syscall_dispatch
+    add $8, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbp
+    pop %rbx
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    testb $3, 8(%rsp)
+    jz 2f
+    # 换回用户的GS_BASE之后到iretq之前不能被中断
+    cli
+    swapgs
+2:
+    iretq
+
+# syscall入口. rcx = 用户rip, r11 = 用户rflags, FMASK已清除IF和DF.
+# syscall只能来自ring 3, 进入时swapgs换入per-CPU数据指针, sysret前换回.
+# 先按int 0x80时CPU压入的顺序保存rip、rflags和用户栈, 段选择子的位置填0
+.global syscall_fast_entry
+syscall_fast_entry:
+    swapgs
+    mov %rsp, %gs:{user_rsp}
+    mov %gs:{kernel_stack}, %rsp
+    push $0
+    push %gs:{user_rsp}
+    push %r11
+    push $0
+    push %rcx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %rbx
+    push %rbp
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, %r8
+    mov %rdx, %rcx
+    mov %rsi, %rdx
+    mov %rdi, %rsi
+    mov %rax, %rdi
+    # 栈顶按16字节对齐, 压入19项后补齐
+    sub $8, %rsp
+    call syscall_dispatch
+    add $8, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbp
+    pop %rbx
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    # 处理函数可能打开了中断, 换回GS_BASE前先关闭.
+    # 跳过rip、cs和rflags, 它们已在rcx和r11中
+    cli
+    mov 24(%rsp), %rsp
+    swapgs
+    sysretq
+.popsection
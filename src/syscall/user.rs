@@ -0,0 +1,281 @@
+//! 系统调用访问用户内存: 检查范围后复制, 复制时的异常由`try_with_recovery`恢复
+//!
+//! 检查使用CR3指向的页表, 即当前进程的地址空间. 检查之后页仍可能被取消映射,
+//! 这时复制中的页错误返回`Fault`而不是导致内核崩溃
+
+use core::ptr;
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::memory;
+use crate::{fault, layout, process};
+
+/// `copy_from_user`和`copy_to_user`一次最多复制的字节数, 内核堆只有100KiB
+pub const MAX_COPY_LEN: usize = 16 * 1024;
+/// `strncpy_from_user`最多读取的字节数(含NUL), 与路径的长度上限相同
+pub const MAX_STRING_LEN: usize = 4096;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// 访问用户内存失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAccessError {
+    /// 范围回绕地址空间或不完全在用户空间内
+    OutOfRange,
+    /// 所在页未映射、没有用户访问权限, 或写入时不可写
+    Inaccessible(VirtAddr),
+    /// 检查之后页被取消映射, 复制时发生异常
+    Fault(VirtAddr),
+    /// 超过一次复制的上限
+    TooLarge,
+    /// 上限之内没有NUL
+    Unterminated,
+}
+
+/// 访问方式, 写入还要求页可写
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// 检查`[start, start + len)`完全在用户空间内, 且经过的每一页都能以`access`方式访问
+pub fn check_range(start: VirtAddr, len: usize, access: Access) -> Result<(), UserAccessError> {
+    if len == 0 {
+        return Ok(());
+    }
+    if !layout::USER.contains_range(start.as_u64(), len as u64) {
+        return Err(UserAccessError::OutOfRange);
+    }
+    let end = start.as_u64() + len as u64;
+    let first = start.align_down(PAGE_SIZE).as_u64();
+    for page in (first..end).step_by(PAGE_SIZE as usize) {
+        check_page(VirtAddr::new(page), access)?;
+    }
+    Ok(())
+}
+
+// 用户空间各级页表项都带USER_ACCESSIBLE, 只需检查最后一级.
+// 写时复制的页算作可写, 复制时的页错误会为它复制帧. mmap的区域中尚未映射的页同样由页错误映射
+fn check_page(page: VirtAddr, access: Access) -> Result<(), UserAccessError> {
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let Ok(flags) = memory::page_flags(page) else {
+        if process::can_fault_in(page, access == Access::Write) {
+            return Ok(());
+        }
+        return Err(UserAccessError::Inaccessible(page));
+    };
+    let writable = flags.intersects(PageTableFlags::WRITABLE | memory::COW);
+    if flags.contains(required) && (access == Access::Read || writable) {
+        Ok(())
+    } else {
+        Err(UserAccessError::Inaccessible(page))
+    }
+}
+
+/// 把用户地址`src`起的`dst.len()`字节复制到内核缓冲区
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserAccessError> {
+    if dst.len() > MAX_COPY_LEN {
+        return Err(UserAccessError::TooLarge);
+    }
+    check_range(src, dst.len(), Access::Read)?;
+    unsafe { copy_with_recovery(src.as_ptr(), dst.as_mut_ptr(), dst.len(), src) }
+}
+
+/// 把`src`复制到用户地址`dst`
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserAccessError> {
+    if src.len() > MAX_COPY_LEN {
+        return Err(UserAccessError::TooLarge);
+    }
+    check_range(dst, src.len(), Access::Write)?;
+    unsafe { copy_with_recovery(src.as_ptr(), dst.as_mut_ptr(), src.len(), dst) }
+}
+
+/// 从用户地址`src`读取以NUL结尾的字符串到`dst`, 返回不含NUL的长度
+///
+/// 逐页检查和复制, 字符串之后的页不需要可访问. `dst`的长度是上限, 最多`MAX_STRING_LEN`
+pub fn strncpy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<usize, UserAccessError> {
+    if dst.len() > MAX_STRING_LEN {
+        return Err(UserAccessError::TooLarge);
+    }
+    let mut copied = 0;
+    while copied < dst.len() {
+        let addr = src.as_u64().checked_add(copied as u64);
+        let Some(addr) = addr.filter(|&addr| layout::USER.contains(addr)) else {
+            return Err(UserAccessError::OutOfRange);
+        };
+        let addr = VirtAddr::new(addr);
+        let page_end = addr.align_down(PAGE_SIZE).as_u64() + PAGE_SIZE;
+        let chunk = ((page_end - addr.as_u64()) as usize).min(dst.len() - copied);
+        check_page(addr.align_down(PAGE_SIZE), Access::Read)?;
+        let chunk = &mut dst[copied..copied + chunk];
+        unsafe { copy_with_recovery(addr.as_ptr(), chunk.as_mut_ptr(), chunk.len(), addr)? };
+        if let Some(nul) = chunk.iter().position(|&byte| byte == 0) {
+            return Ok(copied + nul);
+        }
+        copied += chunk.len();
+    }
+    Err(UserAccessError::Unterminated)
+}
+
+// 复制中的页错误和一般保护异常返回`Fault`, 一般保护异常没有CR2, 记为用户地址`user`
+//
+// 调用者需保证内核一侧的缓冲区有效
+unsafe fn copy_with_recovery(
+    src: *const u8,
+    dst: *mut u8,
+    len: usize,
+    user: VirtAddr,
+) -> Result<(), UserAccessError> {
+    let result = fault::try_with_recovery(|| unsafe { ptr::copy_nonoverlapping(src, dst, len) });
+    result.map_err(|fault| match fault.vector {
+        fault::PAGE_FAULT_VECTOR => UserAccessError::Fault(fault.cr2),
+        _ => UserAccessError::Fault(user),
+    })
+}
+
+// 测试使用内核页表中用户空间开头之后1MiB起的4页: 前两页可写, 第三页未映射, 第四页只读
+#[cfg(test)]
+pub(super) fn test_pages() -> VirtAddr {
+    use spin::Once;
+
+    static PAGES: Once<VirtAddr> = Once::new();
+    *PAGES.call_once(|| {
+        let base = VirtAddr::new(layout::USER.start + 0x10_0000);
+        memory::map_user_pages(base, 2, PageTableFlags::WRITABLE).unwrap();
+        memory::map_user_pages(base + 3 * PAGE_SIZE, 1, PageTableFlags::empty()).unwrap();
+        base
+    })
+}
+
+#[test_case]
+fn test_user_range_validation() {
+    use crate::allocator::HEAP_START;
+
+    let base = test_pages();
+    assert_eq!(check_range(base, 4096, Access::Read), Ok(()));
+    assert_eq!(check_range(base + 100u64, 10, Access::Write), Ok(()));
+    // 跨越两个已映射的页
+    assert_eq!(check_range(base + 4000u64, 200, Access::Write), Ok(()));
+    // 第三页未映射
+    assert_eq!(
+        check_range(base + 8000u64, 200, Access::Read),
+        Err(UserAccessError::Inaccessible(base + 2 * PAGE_SIZE))
+    );
+    assert_eq!(
+        check_range(base, 4 * 4096, Access::Read),
+        Err(UserAccessError::Inaccessible(base + 2 * PAGE_SIZE))
+    );
+    let read_only = base + 3 * PAGE_SIZE;
+    assert_eq!(check_range(read_only, 16, Access::Read), Ok(()));
+    assert_eq!(
+        check_range(read_only, 16, Access::Write),
+        Err(UserAccessError::Inaccessible(read_only))
+    );
+    assert_eq!(
+        check_range(VirtAddr::new(0), 1, Access::Read),
+        Err(UserAccessError::OutOfRange)
+    );
+    assert_eq!(
+        check_range(VirtAddr::new(HEAP_START as u64), 8, Access::Read),
+        Err(UserAccessError::OutOfRange)
+    );
+    assert_eq!(
+        check_range(VirtAddr::new(layout::USER.end - 8), 16, Access::Read),
+        Err(UserAccessError::OutOfRange)
+    );
+    assert_eq!(
+        check_range(VirtAddr::new(0xffff_ffff_ffff_f000), 0x2000, Access::Read),
+        Err(UserAccessError::OutOfRange)
+    );
+    // 空范围总是合法
+    assert_eq!(check_range(VirtAddr::new(0), 0, Access::Read), Ok(()));
+}
+
+#[test_case]
+fn test_copy_user() {
+    use crate::allocator::HEAP_START;
+
+    let base = test_pages();
+    // 跨页写入后读回
+    let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+    let spanning = base + (PAGE_SIZE - 32);
+    assert_eq!(copy_to_user(spanning, &data), Ok(()));
+    let mut buf = [0u8; 64];
+    assert_eq!(copy_from_user(&mut buf, spanning), Ok(()));
+    assert_eq!(buf, data);
+
+    assert_eq!(
+        copy_from_user(&mut buf, base + (2 * PAGE_SIZE - 32)),
+        Err(UserAccessError::Inaccessible(base + 2 * PAGE_SIZE))
+    );
+    assert_eq!(
+        copy_to_user(base + 3 * PAGE_SIZE, &data),
+        Err(UserAccessError::Inaccessible(base + 3 * PAGE_SIZE))
+    );
+    // 内核地址
+    assert_eq!(
+        copy_from_user(&mut buf, VirtAddr::new(HEAP_START as u64)),
+        Err(UserAccessError::OutOfRange)
+    );
+    let mut large = alloc::vec![0u8; MAX_COPY_LEN + 1];
+    assert_eq!(
+        copy_from_user(&mut large, base),
+        Err(UserAccessError::TooLarge)
+    );
+    assert_eq!(
+        crate::error::KernelError::from(UserAccessError::TooLarge).to_errno(),
+        crate::error::EINVAL
+    );
+
+    // 模拟检查之后页被取消映射: 跳过检查直接复制未映射的页
+    let unmapped = base + 2 * PAGE_SIZE + 8u64;
+    let result = unsafe { copy_with_recovery(unmapped.as_ptr(), buf.as_mut_ptr(), 8, unmapped) };
+    assert_eq!(result, Err(UserAccessError::Fault(unmapped)));
+}
+
+#[test_case]
+fn test_strncpy_from_user() {
+    let base = test_pages();
+    let mut buf = [0u8; 64];
+
+    let path = base + 256u64;
+    copy_to_user(path, b"/etc/motd\0").unwrap();
+    assert_eq!(strncpy_from_user(&mut buf, path), Ok(9));
+    assert_eq!(&buf[..10], b"/etc/motd\0");
+    // 字符串跨页
+    let spanning = base + (PAGE_SIZE - 4);
+    copy_to_user(spanning, b"/bin/sh\0").unwrap();
+    assert_eq!(strncpy_from_user(&mut buf, spanning), Ok(7));
+    assert_eq!(&buf[..7], b"/bin/sh");
+    // 字符串在未映射的页之前结束
+    let before_hole = base + (2 * PAGE_SIZE - 3);
+    copy_to_user(before_hole, b"ab\0").unwrap();
+    assert_eq!(strncpy_from_user(&mut buf, before_hole), Ok(2));
+    // 读到未映射的页还没有NUL
+    copy_to_user(before_hole, b"abc").unwrap();
+    assert_eq!(
+        strncpy_from_user(&mut buf, before_hole),
+        Err(UserAccessError::Inaccessible(base + 2 * PAGE_SIZE))
+    );
+    // 上限之内没有NUL
+    copy_to_user(path, &[b'x'; 64]).unwrap();
+    assert_eq!(
+        strncpy_from_user(&mut buf, path),
+        Err(UserAccessError::Unterminated)
+    );
+    assert_eq!(
+        strncpy_from_user(&mut buf[..0], path),
+        Err(UserAccessError::Unterminated)
+    );
+    assert_eq!(
+        strncpy_from_user(&mut buf, VirtAddr::new(0x1000)),
+        Err(UserAccessError::OutOfRange)
+    );
+    let mut large = alloc::vec![0u8; MAX_STRING_LEN + 1];
+    assert_eq!(
+        strncpy_from_user(&mut large, path),
+        Err(UserAccessError::TooLarge)
+    );
+}
@@ -0,0 +1,237 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use crate::shell::{self, CmdError};
+use crate::sync::IrqMutex;
+use crate::thread::{self, ThreadId};
+
+pub mod channel;
+pub mod executor;
+pub mod mutex;
+
+/// 由执行器调度的异步任务
+pub struct Task {
+    id: TaskId,
+    name: &'static str,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::named("task", future)
+    }
+
+    /// 带名称的任务, 名称显示在任务列表中
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            name,
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// 任务列表中的一项
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: &'static str,
+    /// 被执行器poll的次数
+    pub polls: u64,
+}
+
+// 所有执行器中尚未结束的任务, 只在任务上下文中访问
+static TASKS: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+/// 尚未结束的任务, 按ID排序
+pub fn tasks() -> Vec<TaskInfo> {
+    TASKS.lock().values().copied().collect()
+}
+
+/// 注册`ps`命令
+pub fn register_commands() {
+    shell::register_command("ps", "list tasks and processes", ps_command).expect("duplicate task command");
+}
+
+fn ps_command(_args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    writeln!(out, "{:>4}  {:<12}POLLS", "ID", "NAME")?;
+    for info in tasks() {
+        writeln!(out, "{:>4}  {:<12}{}", info.id, info.name, info.polls)?;
+    }
+    let processes = crate::process::list();
+    if !processes.is_empty() {
+        writeln!(out, "{:>4}  {:<12}STATE", "PID", "NAME")?;
+    }
+    for info in processes {
+        match info.exit {
+            Some(exit) => writeln!(out, "{:>4}  {:<12}{}", info.pid, info.name, exit)?,
+            None => writeln!(out, "{:>4}  {:<12}running", info.pid, info.name)?,
+        }
+    }
+    Ok(())
+}
+
+fn register(task: &Task) {
+    let info = TaskInfo {
+        id: task.id.0,
+        name: task.name,
+        polls: 0,
+    };
+    TASKS.lock().insert(task.id, info);
+}
+
+fn record_poll(id: TaskId, finished: bool) {
+    let mut tasks = TASKS.lock();
+    if finished {
+        tasks.remove(&id);
+        crate::sched::forget(crate::sched::Entity::Task(id.0));
+    } else if let Some(info) = tasks.get_mut(&id) {
+        info.polls += 1;
+    }
+}
+
+// 各线程正在poll的任务, 同一线程上嵌套运行的执行器依次压入
+static POLLING: IrqMutex<Vec<(Option<ThreadId>, TaskId)>> =
+    IrqMutex::new("task::POLLING", Vec::new());
+
+// 由执行器在poll前后调用
+fn enter(id: TaskId) {
+    let thread = thread::current_id_unlocked();
+    POLLING.lock().push((thread, id));
+}
+
+fn leave(id: TaskId) {
+    let mut polling = POLLING.lock();
+    if let Some(position) = polling.iter().rposition(|&(_, task)| task == id) {
+        polling.remove(position);
+    }
+}
+
+/// 当前线程正在poll的任务的编号, 不在任务中运行时返回None
+pub fn current_id() -> Option<u64> {
+    let thread = thread::current_id_unlocked();
+    POLLING
+        .lock()
+        .iter()
+        .rev()
+        .find(|&&(owner, _)| owner == thread)
+        .map(|&(_, task)| task.0)
+}
+
+// 由spawn创建、等待执行器取走的任务
+struct Spawned {
+    name: &'static str,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+static SPAWNED: Mutex<VecDeque<Spawned>> = Mutex::new(VecDeque::new());
+
+fn take_spawned() -> Option<Task> {
+    let spawned = SPAWNED.lock().pop_front()?;
+    Some(Task {
+        id: TaskId::new(),
+        name: spawned.name,
+        future: spawned.future,
+    })
+}
+
+fn has_spawned() -> bool {
+    !SPAWNED.lock().is_empty()
+}
+
+/// 在任务中创建新任务, 由正在运行的执行器取走执行. 返回的句柄在任务结束时完成
+pub fn spawn(name: &'static str, future: impl Future<Output = ()> + Send + 'static) -> JoinHandle {
+    let state = Arc::new(JoinState {
+        finished: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    let task_state = state.clone();
+    let future = Box::pin(async move {
+        future.await;
+        task_state.finished.store(true, Ordering::Release);
+        task_state.waker.wake();
+    });
+    SPAWNED.lock().push_back(Spawned { name, future });
+    JoinHandle { state }
+}
+
+struct JoinState {
+    finished: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// 等待`spawn`创建的任务结束, 丢弃句柄不影响任务运行
+pub struct JoinHandle {
+    state: Arc<JoinState>,
+}
+
+impl Future for JoinHandle {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.state.finished.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        // 注册waker后再检查一次, 防止错过注册前结束的任务
+        self.state.waker.register(cx.waker());
+        if self.state.finished.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// 让出执行器一次, 其他就绪的任务先运行
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// 测试中运行不会等待的future, 如同步设备和内存中的文件系统上的操作
+#[cfg(test)]
+pub(crate) fn complete_now<F: Future>(future: F) -> F::Output {
+    use futures_util::FutureExt;
+
+    future.now_or_never().expect("future did not complete")
+}
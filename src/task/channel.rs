@@ -0,0 +1,113 @@
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+/// 有界的单消费者通道, 发送端可以在中断中调用
+pub struct Channel<T> {
+    queue: OnceCell<ArrayQueue<T>>,
+    waker: AtomicWaker,
+    capacity: usize,
+    dropped: AtomicUsize,
+}
+
+impl<T> Channel<T> {
+    pub const fn new(capacity: usize) -> Self {
+        Channel {
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            capacity,
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// 发送一个值, 接收端尚未创建或队列已满时丢弃并计数
+    pub fn push(&self, value: T) {
+        match self.queue.try_get() {
+            Ok(queue) => {
+                if queue.push(value).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.waker.wake();
+                }
+            }
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 被丢弃的值的个数
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// 创建接收端, 第一次调用时在堆上分配队列, 因此不能在中断中调用
+    ///
+    /// 同一时刻只能有一个接收端在等待
+    pub fn stream(&'static self) -> ChannelStream<T> {
+        // 已经初始化过时直接复用队列
+        let _ = self.queue.try_init_once(|| ArrayQueue::new(self.capacity));
+        ChannelStream { channel: self }
+    }
+}
+
+/// 通道的接收端
+pub struct ChannelStream<T: 'static> {
+    channel: &'static Channel<T>,
+}
+
+impl<T> Stream for ChannelStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let queue = self
+            .channel
+            .queue
+            .try_get()
+            .expect("channel queue not initialized");
+
+        if let Some(value) = queue.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        // 注册waker后再检查一次, 防止错过注册前到达的值
+        self.channel.waker.register(cx.waker());
+        match queue.pop() {
+            Some(value) => {
+                self.channel.waker.take();
+                Poll::Ready(Some(value))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_channel_delivers_in_order() {
+    use core::task::Waker;
+    use futures_util::stream::StreamExt;
+
+    static CHANNEL: Channel<u32> = Channel::new(2);
+
+    // 接收端创建前发送的值被丢弃
+    CHANNEL.push(0);
+    assert_eq!(CHANNEL.dropped(), 1);
+
+    let mut stream = CHANNEL.stream();
+    let mut cx = Context::from_waker(Waker::noop());
+    assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Pending);
+
+    CHANNEL.push(1);
+    CHANNEL.push(2);
+    CHANNEL.push(3);
+    assert_eq!(CHANNEL.dropped(), 2);
+
+    assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Ready(Some(1)));
+    assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Ready(Some(2)));
+    assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Pending);
+}
@@ -0,0 +1,173 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::task::{Context, Waker};
+
+use crossbeam_queue::ArrayQueue;
+
+use super::{Task, TaskId};
+use crate::sched::{self, Entity};
+use crate::trace::EventId;
+use crate::workqueue;
+
+const TASK_QUEUE_SIZE: usize = 100;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    // 被唤醒的任务, waker可能在中断中调用, 因此使用无锁队列
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        super::register(&task);
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("task queue full");
+    }
+
+    /// 运行所有任务, 没有就绪任务时hlt等待中断
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// 运行直到所有任务结束
+    pub fn run_until_idle(&mut self) {
+        while !self.tasks.is_empty() || super::has_spawned() {
+            self.run_ready_tasks();
+            if !self.tasks.is_empty() {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // 取走其他任务通过task::spawn创建的任务
+        while let Some(task) = super::take_spawned() {
+            self.spawn(task);
+        }
+
+        // 解构self, 避免闭包借用整个self
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        while let Some(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                // 任务已结束
+                None => continue,
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::waker(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            crate::trace!(EventId::TaskPollStart, task_id.0);
+            let thread = sched::switch_to(Entity::Task(task_id.0));
+            super::enter(task_id);
+            let result = task.poll(&mut context);
+            super::leave(task_id);
+            sched::switch_to(thread);
+            crate::trace!(EventId::TaskPollEnd, task_id.0);
+            super::record_poll(task_id, result.is_ready());
+            if result.is_ready() {
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts;
+
+        // 先执行中断推迟的工作, 它们可能唤醒任务
+        workqueue::run_pending();
+        // 检查队列和hlt之间的中断可能唤醒任务或推迟新的工作, 因此先关中断再检查
+        interrupts::disable();
+        if self.task_queue.is_empty() && !super::has_spawned() && workqueue::is_empty() {
+            // 有其他线程可以运行时让出处理器, 回来后重新检查队列
+            if crate::thread::has_ready() {
+                crate::thread::yield_now();
+                interrupts::enable();
+            } else {
+                sched::halt();
+            }
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+        }))
+    }
+
+    fn wake_task(&self) {
+        // 队列满时任务必然已在队列中等待运行
+        let _ = self.task_queue.push(self.task_id);
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[test_case]
+fn test_spawn_and_join() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static CHILD_RAN: AtomicBool = AtomicBool::new(false);
+    static PARENT_SAW_CHILD: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::named("parent", async {
+        let child = super::spawn("child", async {
+            assert!(super::tasks().iter().any(|info| info.name == "child"));
+            CHILD_RAN.store(true, Ordering::SeqCst);
+        });
+        child.await;
+        PARENT_SAW_CHILD.store(CHILD_RAN.load(Ordering::SeqCst), Ordering::SeqCst);
+    }));
+    executor.run_until_idle();
+    assert!(PARENT_SAW_CHILD.load(Ordering::SeqCst));
+    assert!(!super::tasks()
+        .iter()
+        .any(|info| info.name == "parent" || info.name == "child"));
+}
@@ -0,0 +1,116 @@
+//! 任务之间的互斥锁, 守卫可以跨越`.await`持有
+//!
+//! 持有spin锁时`.await`会让同一执行器上等待这把锁的任务一直自旋, 持有者再也得不到poll.
+//! 这里等待的任务登记waker后返回Pending, 释放时唤醒所有等待者, 由它们重新竞争
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+
+pub struct AsyncMutex<T> {
+    locked: AtomicBool,
+    waiters: Mutex<Vec<Waker>>,
+    value: UnsafeCell<T>,
+}
+
+// 同一时刻只有持有守卫的一方访问`value`
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(value: T) -> Self {
+        AsyncMutex {
+            locked: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// 等待获得锁
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock { mutex: self }
+    }
+
+    /// 锁已被持有时返回None
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| AsyncMutexGuard { mutex: self })
+    }
+}
+
+/// `AsyncMutex::lock`返回的Future
+pub struct Lock<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(guard) = self.mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
+        // 登记waker后再试一次, 防止错过登记前的释放
+        self.mutex.waiters.lock().push(cx.waker().clone());
+        match self.mutex.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub struct AsyncMutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<T> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        let waiters = core::mem::take(&mut *self.mutex.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+#[test_case]
+fn test_async_mutex() {
+    use futures_util::FutureExt;
+
+    let mutex = AsyncMutex::new(0);
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut guard = mutex.try_lock().unwrap();
+    *guard += 1;
+    // 持有期间其他等待者不能获得锁, 释放后可以
+    let mut waiter = mutex.lock();
+    assert!(waiter.poll_unpin(&mut cx).is_pending());
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    match waiter.poll_unpin(&mut cx) {
+        Poll::Ready(guard) => assert_eq!(*guard, 1),
+        Poll::Pending => panic!("lock not released"),
+    }
+    assert!(mutex.try_lock().is_some());
+}
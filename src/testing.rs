@@ -0,0 +1,299 @@
+//! 测试运行器的输出: 每个测试的耗时、串口上的ANSI颜色, 以及结束时的汇总
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::cmdline;
+
+// 编译时设置NO_COLOR(非空)或命令行带nocolor时不输出颜色
+const NO_COLOR: Option<&str> = option_env!("NO_COLOR");
+
+const RESET: &str = "\x1b[0m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const DIM: &str = "\x1b[2m";
+
+// 汇总中列出的最慢测试数
+const SLOWEST_COUNT: usize = 5;
+// 状态标签占的宽度, 耗时在它之后对齐
+const STATUS_WIDTH: usize = 12;
+
+/// 串口输出是否使用颜色, 测试只输出到串口, 屏幕上始终没有转义序列
+pub fn color_enabled() -> bool {
+    NO_COLOR.is_none_or(str::is_empty) && !cmdline::flag("nocolor")
+}
+
+/// 一个测试的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Failed,
+    Skipped,
+    TimedOut,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => "[ok]",
+            Status::Failed => "[failed]",
+            Status::Skipped => "[skipped]",
+            Status::TimedOut => "[timed out]",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Status::Ok => GREEN,
+            Status::Failed | Status::TimedOut => RED,
+            Status::Skipped => YELLOW,
+        }
+    }
+}
+
+/// 测试名之后的部分: 状态标签和可选的耗时, 耗时按`STATUS_WIDTH`对齐
+pub struct StatusLine {
+    pub status: Status,
+    pub elapsed: Option<Duration>,
+    pub color: bool,
+}
+
+impl StatusLine {
+    /// 按`color_enabled`决定是否着色
+    pub fn new(status: Status, elapsed: Option<Duration>) -> Self {
+        StatusLine {
+            status,
+            elapsed,
+            color: color_enabled(),
+        }
+    }
+}
+
+impl fmt::Display for StatusLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = self.status.label();
+        if self.color {
+            write!(f, "{}{}{}", self.status.color(), label, RESET)?;
+        } else {
+            f.write_str(label)?;
+        }
+        let Some(elapsed) = self.elapsed else {
+            return Ok(());
+        };
+        // 转义序列不占宽度, 只按标签本身补齐
+        for _ in label.len()..STATUS_WIDTH {
+            f.write_char(' ')?;
+        }
+        if self.color {
+            write!(f, "{}{:>8}{}", DIM, FormatDuration(elapsed), RESET)
+        } else {
+            write!(f, "{:>8}", FormatDuration(elapsed))
+        }
+    }
+}
+
+/// 按量级显示耗时: `532µs`、`12.3ms`、`1.52s`, 支持宽度和对齐
+pub struct FormatDuration(pub Duration);
+
+impl fmt::Display for FormatDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = Buffer {
+            bytes: [0; 24],
+            len: 0,
+        };
+        let us = self.0.as_micros();
+        if us < 1000 {
+            write!(buf, "{}µs", us)?;
+        } else if us < 1_000_000 {
+            write!(buf, "{}.{}ms", us / 1000, us % 1000 / 100)?;
+        } else {
+            write!(buf, "{}.{:02}s", us / 1_000_000, us % 1_000_000 / 10_000)?;
+        }
+        f.pad(buf.as_str())
+    }
+}
+
+// 定长的格式化缓冲区, 写满后截断
+struct Buffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Buffer<N> {
+    fn as_str(&self) -> &str {
+        // 截断可能切开多字节字符, 只保留完整的部分
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(s) => s,
+            Err(err) => core::str::from_utf8(&self.bytes[..err.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl<const N: usize> fmt::Write for Buffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(N - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// 运行过的测试中最慢的几个, 从慢到快排列
+#[derive(Clone, Copy)]
+pub struct Slowest {
+    tests: [Option<(&'static str, Duration)>; SLOWEST_COUNT],
+}
+
+impl Slowest {
+    pub const fn new() -> Self {
+        Slowest {
+            tests: [None; SLOWEST_COUNT],
+        }
+    }
+
+    pub fn record(&mut self, name: &'static str, elapsed: Duration) {
+        let Some(index) = self
+            .tests
+            .iter()
+            .position(|test| test.is_none_or(|(_, slowest)| elapsed > slowest))
+        else {
+            return;
+        };
+        self.tests.copy_within(index..SLOWEST_COUNT - 1, index + 1);
+        self.tests[index] = Some((name, elapsed));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Duration)> + '_ {
+        self.tests.iter().flatten().copied()
+    }
+}
+
+impl Default for Slowest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 失败的测试和它的panic信息, 测试在第一次失败时就会退出, 最多只有一个
+struct Failure {
+    name: &'static str,
+    message: Buffer<256>,
+}
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+static SLOWEST: Mutex<Slowest> = Mutex::new(Slowest::new());
+static FAILURE: Mutex<Option<Failure>> = Mutex::new(None);
+
+/// 由`test_runner`在运行第一个测试前调用, 之后失败时才输出汇总
+pub fn begin() {
+    STARTED.store(true, Ordering::SeqCst);
+}
+
+/// 记录通过的测试的耗时
+pub fn record_pass(name: &'static str, elapsed: Duration) {
+    SLOWEST.lock().record(name, elapsed);
+}
+
+/// 记录失败的测试. 由panic处理函数和超时检查调用, 拿不到锁时放弃记录
+pub fn record_failure(name: &'static str, message: fmt::Arguments) {
+    let Some(mut failure) = FAILURE.try_lock() else {
+        return;
+    };
+    let mut record = Failure {
+        name,
+        message: Buffer {
+            bytes: [0; 256],
+            len: 0,
+        },
+    };
+    let _ = record.message.write_fmt(message);
+    *failure = Some(record);
+}
+
+/// 输出最慢的测试和失败的测试, `test_runner`之外(不使用测试框架的集成测试)不输出
+pub fn print_summary(out: &mut dyn fmt::Write) -> fmt::Result {
+    if !STARTED.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    if let Some(slowest) = SLOWEST.try_lock() {
+        writeln!(out, "slowest tests:")?;
+        for (name, elapsed) in slowest.iter() {
+            writeln!(out, "  {:>8}  {}", FormatDuration(elapsed), name)?;
+        }
+    }
+    if let Some(failure) = FAILURE.try_lock() {
+        if let Some(failure) = failure.as_ref() {
+            writeln!(out, "failures:")?;
+            let (color, reset) = if color_enabled() { (RED, RESET) } else { ("", "") };
+            writeln!(out, "  {}{}{}", color, failure.name, reset)?;
+            writeln!(out, "    {}", failure.message.as_str())?;
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_format_duration() {
+    use alloc::format;
+
+    let render = |us| format!("{}", FormatDuration(Duration::from_micros(us)));
+    assert_eq!(render(0), "0µs");
+    assert_eq!(render(999), "999µs");
+    assert_eq!(render(1000), "1.0ms");
+    assert_eq!(render(12_345), "12.3ms");
+    assert_eq!(render(999_999), "999.9ms");
+    assert_eq!(render(1_520_000), "1.52s");
+    assert_eq!(render(61_005_000), "61.00s");
+    // 宽度按字符计算
+    assert_eq!(format!("{:>6}", FormatDuration(Duration::from_micros(5))), "   5µs");
+}
+
+#[test_case]
+fn test_status_line_colors() {
+    use alloc::format;
+
+    let elapsed = Some(Duration::from_micros(1500));
+    let plain = StatusLine {
+        status: Status::Ok,
+        elapsed,
+        color: false,
+    };
+    assert_eq!(format!("{}", plain), "[ok]           1.5ms");
+    assert!(!format!("{}", plain).contains('\x1b'));
+
+    let colored = StatusLine {
+        status: Status::Ok,
+        elapsed,
+        color: true,
+    };
+    assert_eq!(
+        format!("{}", colored),
+        "\x1b[32m[ok]\x1b[0m        \x1b[2m   1.5ms\x1b[0m"
+    );
+    let failed = StatusLine {
+        status: Status::Failed,
+        elapsed: None,
+        color: true,
+    };
+    assert_eq!(format!("{}", failed), "\x1b[31m[failed]\x1b[0m");
+    let skipped = StatusLine {
+        status: Status::Skipped,
+        elapsed: None,
+        color: false,
+    };
+    assert_eq!(format!("{}", skipped), "[skipped]");
+}
+
+#[test_case]
+fn test_slowest_tests() {
+    let mut slowest = Slowest::new();
+    for (name, ms) in [("a", 3), ("b", 10), ("c", 1), ("d", 7), ("e", 2), ("f", 5), ("g", 4)] {
+        slowest.record(name, Duration::from_millis(ms));
+    }
+    let names: alloc::vec::Vec<&str> = slowest.iter().map(|(name, _)| name).collect();
+    assert_eq!(names, ["b", "d", "f", "g", "a"]);
+}
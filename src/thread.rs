@@ -0,0 +1,681 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use crate::memory::{self, AddressSpace, MemError};
+use crate::sched::{self, Entity};
+use crate::trace::EventId;
+use crate::{gdt, percpu, time};
+
+mod queue;
+
+pub use queue::{Priority, STARVATION_TICKS};
+
+use queue::RunQueue;
+
+global_asm!(include_str!("thread/switch.s"), options(att_syntax));
+
+extern "C" {
+    fn thread_switch(save_rsp: *mut u64, next_rsp: u64);
+    fn thread_start();
+}
+
+// 每个线程的内核栈页数
+const STACK_PAGES: u64 = 8;
+
+/// 线程编号, 启动线程为0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: String,
+    // 切换出去时保存的栈指针
+    rsp: u64,
+    // 调度器分配的内核栈顶, 启动线程使用引导时的栈
+    stack: Option<VirtAddr>,
+    // 从ring 3进入内核时使用的栈顶
+    kernel_stack: VirtAddr,
+    cr3: PhysFrame,
+    // 睡眠中修改的优先级在唤醒后排队时生效
+    priority: Priority,
+    // 只为持有线程使用的地址空间, 线程结束后随之释放, 运行时通过cr3使用
+    #[allow(dead_code)]
+    address_space: Option<AddressSpace>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Scheduler {
+    // 把到期的睡眠线程移到就绪队列
+    fn wake(&mut self, now: u64) {
+        let mut i = 0;
+        while i < self.sleeping.len() {
+            if self.sleeping[i].0 <= now {
+                let (_, thread) = self.sleeping.swap_remove(i);
+                let priority = thread.priority;
+                self.ready.push(thread, priority, now);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(top) = self.stack {
+            // 只回收已经切换走的线程, 栈不再使用
+            unsafe { memory::free_stack(top, STACK_PAGES) }.expect("freeing a thread stack");
+        }
+    }
+}
+
+struct Scheduler {
+    current: Box<Thread>,
+    ready: RunQueue<Box<Thread>>,
+    // 睡眠中的线程和唤醒的tick
+    sleeping: Vec<(u64, Box<Thread>)>,
+    // 等待`unpark`的线程. 与其他队列一样保存Box, 线程在队列之间移动时地址不变
+    #[allow(clippy::vec_box)]
+    parked: Vec<Box<Thread>>,
+    // 还没有park就被unpark的线程, 下一次park立即返回
+    unparked: Vec<ThreadId>,
+    // 没有其他线程可运行时切换到空闲线程, 它不在就绪队列中. 运行时为None
+    idle: Option<Box<Thread>>,
+    idle_id: Option<ThreadId>,
+    // 已结束但栈在切换前仍在使用的线程, 由下一个运行的线程回收.
+    // thread_switch通过指针写回rsp, 线程在切换完成前不能移动
+    #[allow(clippy::vec_box)]
+    dead: Vec<Box<Thread>>,
+}
+
+// 只在BSP上使用, 总是在关中断时加锁
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// 把当前的执行流登记为启动线程并创建空闲线程, 之后可以创建其他线程. 需要堆和per-CPU数据
+///
+/// 启动线程运行执行器, 键盘和定时器唤醒的任务都在其中执行, 因此使用高优先级
+pub fn init() {
+    let boot = Box::new(Thread {
+        id: ThreadId::new(),
+        name: String::from("boot"),
+        rsp: 0,
+        stack: None,
+        kernel_stack: gdt::privilege_stack(),
+        cr3: Cr3::read().0,
+        priority: Priority::High,
+        address_space: None,
+        entry: None,
+    });
+    // 没有空闲线程时睡眠的线程只能忙等
+    let idle = new_thread("idle", Priority::Low, None, Box::new(idle_main)).ok();
+    interrupts::without_interrupts(|| {
+        percpu::get().set_current_thread(&*boot as *const Thread as *mut ());
+        *SCHEDULER.lock() = Some(Scheduler {
+            current: boot,
+            ready: RunQueue::new(),
+            sleeping: Vec::new(),
+            parked: Vec::new(),
+            unparked: Vec::new(),
+            idle_id: idle.as_ref().map(|thread| thread.id),
+            idle,
+            dead: Vec::new(),
+        });
+    });
+}
+
+fn idle_main() {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// 以优先级`priority`创建内核线程并加入就绪队列, 线程在之后的调度中开始运行
+pub fn spawn(
+    name: &str,
+    priority: Priority,
+    entry: impl FnOnce() + Send + 'static,
+) -> Result<ThreadId, MemError> {
+    create(name, priority, None, Box::new(entry))
+}
+
+/// 同`spawn`, 但线程以普通优先级运行并使用`space`, 线程结束后释放它
+pub fn spawn_with_address_space(
+    name: &str,
+    space: AddressSpace,
+    entry: impl FnOnce() + Send + 'static,
+) -> Result<ThreadId, MemError> {
+    create(name, Priority::Normal, Some(space), Box::new(entry))
+}
+
+fn create(
+    name: &str,
+    priority: Priority,
+    address_space: Option<AddressSpace>,
+    entry: Box<dyn FnOnce() + Send>,
+) -> Result<ThreadId, MemError> {
+    let thread = new_thread(name, priority, address_space, entry)?;
+    let id = thread.id;
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .expect("scheduler not initialized")
+            .ready
+            .push(thread, priority, time::pit_ticks())
+    });
+    Ok(id)
+}
+
+fn new_thread(
+    name: &str,
+    priority: Priority,
+    address_space: Option<AddressSpace>,
+    entry: Box<dyn FnOnce() + Send>,
+) -> Result<Box<Thread>, MemError> {
+    let top = memory::alloc_stack(STACK_PAGES)?;
+    // thread_switch弹出6个寄存器后返回到thread_start
+    let rsp = top.as_u64() - 8 - 6 * 8;
+    unsafe {
+        core::ptr::write_bytes(rsp as *mut u64, 0, 6);
+        ((top.as_u64() - 8) as *mut u64)
+            .write(thread_start as unsafe extern "C" fn() as usize as u64);
+    }
+    let cr3 = address_space
+        .as_ref()
+        .map_or_else(|| AddressSpace::kernel().p4_frame(), AddressSpace::p4_frame);
+    Ok(Box::new(Thread {
+        id: ThreadId::new(),
+        name: String::from(name),
+        rsp,
+        stack: Some(top),
+        kernel_stack: top,
+        cr3,
+        priority,
+        address_space,
+        entry: Some(entry),
+    }))
+}
+
+/// 线程列表中的一项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub priority: Priority,
+    /// 是否是当前运行的线程, 否则在就绪队列中或在睡眠
+    pub running: bool,
+}
+
+/// 当前线程、就绪队列中和睡眠中的线程, 不含空闲线程
+pub fn threads() -> Vec<ThreadInfo> {
+    interrupts::without_interrupts(|| {
+        let guard = SCHEDULER.lock();
+        let Some(scheduler) = guard.as_ref() else {
+            return Vec::new();
+        };
+        let info = |thread: &Thread, running| ThreadInfo {
+            id: thread.id,
+            name: thread.name.clone(),
+            priority: thread.priority,
+            running,
+        };
+        let mut threads = alloc::vec![info(&scheduler.current, true)];
+        threads.extend(scheduler.ready.iter().map(|thread| info(thread, false)));
+        threads.extend(
+            scheduler
+                .sleeping
+                .iter()
+                .map(|(_, thread)| info(thread, false)),
+        );
+        threads.extend(scheduler.parked.iter().map(|thread| info(thread, false)));
+        threads.retain(|thread| Some(thread.id) != scheduler.idle_id);
+        threads
+    })
+}
+
+/// 修改线程的优先级, 找不到线程时返回false. 空闲线程的优先级不能修改
+///
+/// 就绪的线程立即换到新的队列, 当前线程和睡眠中的线程在下次排队时按新的优先级
+pub fn set_priority(id: ThreadId, priority: Priority) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let Some(scheduler) = guard.as_mut() else {
+            return false;
+        };
+        if Some(id) == scheduler.idle_id {
+            return false;
+        }
+        let thread = if scheduler.current.id == id {
+            Some(&mut scheduler.current)
+        } else if let Some(thread) = scheduler.ready.move_to(priority, |thread| thread.id == id) {
+            Some(thread)
+        } else {
+            scheduler
+                .sleeping
+                .iter_mut()
+                .map(|(_, thread)| thread)
+                .chain(scheduler.parked.iter_mut())
+                .find(|thread| thread.id == id)
+        };
+        match thread {
+            Some(thread) => {
+                thread.priority = priority;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// 当前线程的编号, `init`之前为None
+pub fn current_id() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| Some(SCHEDULER.lock().as_ref()?.current.id))
+}
+
+/// 同`current_id`, 但从每处理器数据读取, 不加调度器锁. 供异常处理函数使用, 那时调度器锁可能被打断的代码持有
+pub fn current_id_unlocked() -> Option<ThreadId> {
+    let thread = percpu::try_get()?.current_thread() as *const Thread;
+    // 指向的线程在切换走之前不会被释放
+    (!thread.is_null()).then(|| unsafe { (*thread).id })
+}
+
+/// 就绪队列中是否有等待运行的线程
+pub fn has_ready() -> bool {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .is_some_and(|scheduler| !scheduler.ready.is_empty())
+    })
+}
+
+/// 让出处理器, 当前线程排到同优先级的就绪队列末尾. 就绪线程的优先级都更低时也会切换
+pub fn yield_now() {
+    interrupts::without_interrupts(|| schedule(Leave::Yield));
+}
+
+/// 睡眠至少`ticks`个时钟中断, 期间不在就绪队列中. 需要开中断
+pub fn sleep_ticks(ticks: u64) {
+    let deadline = time::pit_ticks() + ticks;
+    while time::pit_ticks() < deadline {
+        interrupts::without_interrupts(|| schedule(Leave::Sleep(deadline)));
+    }
+}
+
+/// 睡眠至少`ms`毫秒
+pub fn sleep_ms(ms: u64) {
+    sleep_ticks(time::ms_to_ticks(ms));
+}
+
+/// 等待其他线程或中断处理函数对当前线程调用`unpark`, 之前已经调用过时立即返回.
+/// 也可能没有原因地返回, 调用者需在循环中检查等待的条件
+pub fn park() {
+    interrupts::without_interrupts(|| schedule(Leave::Park));
+}
+
+/// 让`park`中的线程`id`回到就绪队列; 它没有在等待时, 它的下一次`park`立即返回. 可以在中断中调用
+pub fn unpark(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let Some(scheduler) = guard.as_mut() else {
+            return;
+        };
+        match scheduler.parked.iter().position(|thread| thread.id == id) {
+            Some(i) => {
+                let thread = scheduler.parked.swap_remove(i);
+                let priority = thread.priority;
+                scheduler.ready.push(thread, priority, time::pit_ticks());
+            }
+            None if !scheduler.unparked.contains(&id) => scheduler.unparked.push(id),
+            None => {}
+        }
+    });
+}
+
+/// 在当前线程中运行`future`直到完成, 等待时park, 由waker对当前线程unpark.
+/// 供不在任务中的同步调用者使用异步接口, 如进程线程中的系统调用. 任务中应该`.await`,
+/// 在任务中park会让执行器的其他任务一起等待
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    assert!(
+        crate::task::current_id().is_none(),
+        "thread::block_on inside a task"
+    );
+    let id = current_id().expect("thread::block_on before thread::init");
+    let waker = Waker::from(Arc::new(Unparker(id)));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        park();
+    }
+}
+
+struct Unparker(ThreadId);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        unpark(self.0);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        unpark(self.0);
+    }
+}
+
+/// 结束当前线程. 内核栈和地址空间在切换到下一个线程后释放, 启动线程不能结束
+pub fn exit() -> ! {
+    interrupts::disable();
+    schedule(Leave::Exit);
+    unreachable!("exited thread was scheduled again");
+}
+
+/// 由时钟中断处理函数在发送EOI之后调用, 唤醒到期的线程, 有优先级不低于当前线程的就绪线程时轮转到它
+pub(crate) fn on_timer_interrupt() {
+    schedule(Leave::Tick);
+}
+
+/// 由唤醒任务的中断处理函数在发送EOI之后调用, 有更高优先级的就绪线程时立即切换过去,
+/// 被唤醒的任务不必等到下一个时间片
+pub(crate) fn on_wake_interrupt() {
+    schedule(Leave::Wake);
+}
+
+// 当前线程让出处理器的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Leave {
+    /// 排到就绪队列末尾, 没有其他就绪线程时继续运行
+    Yield,
+    /// 时间片用完, 只让给优先级不低于自己的线程
+    Tick,
+    /// 中断唤醒了任务, 只让给优先级更高的线程
+    Wake,
+    /// 睡眠到指定的tick
+    Sleep(u64),
+    /// 等待`unpark`
+    Park,
+    /// 不再运行
+    Exit,
+}
+
+// 切换到就绪队列中的下一个线程, 中断必须已关闭. 当前线程不能继续运行而没有就绪线程时切换到空闲线程
+fn schedule(leave: Leave) {
+    let (save, next) = {
+        let mut guard = SCHEDULER.lock();
+        let Some(scheduler) = guard.as_mut() else {
+            return;
+        };
+        assert!(
+            leave != Leave::Exit || scheduler.current.stack.is_some(),
+            "the boot thread cannot exit"
+        );
+        let now = time::pit_ticks();
+        scheduler.wake(now);
+        let current = scheduler.current.id;
+        if let Some(i) = scheduler.unparked.iter().position(|&id| id == current) {
+            match leave {
+                Leave::Park => {
+                    scheduler.unparked.swap_remove(i);
+                    return;
+                }
+                Leave::Exit => {
+                    scheduler.unparked.swap_remove(i);
+                }
+                _ => {}
+            }
+        }
+        let leave = match leave {
+            Leave::Sleep(deadline) if deadline <= now => Leave::Yield,
+            leave => leave,
+        };
+        let preempt = match (leave, scheduler.ready.top(now)) {
+            (Leave::Tick, Some(top)) => top >= scheduler.current.priority,
+            (Leave::Wake, Some(top)) => top > scheduler.current.priority,
+            (Leave::Tick | Leave::Wake, None) => false,
+            _ => true,
+        };
+        if !preempt {
+            return;
+        }
+        let next = match scheduler.ready.pop(now) {
+            Some(next) => next,
+            None if leave == Leave::Yield => return,
+            None => match scheduler.idle.take() {
+                Some(idle) => idle,
+                None => {
+                    assert!(leave != Leave::Exit, "no thread left to run");
+                    return;
+                }
+            },
+        };
+        activate(&next);
+        crate::trace!(EventId::ContextSwitch, next.id.as_u64());
+        sched::switch_to(if Some(next.id) == scheduler.idle_id {
+            Entity::Idle
+        } else {
+            Entity::Thread(next.id.as_u64())
+        });
+        let mut prev = core::mem::replace(&mut scheduler.current, next);
+        let save: *mut u64 = &mut prev.rsp;
+        match leave {
+            _ if Some(prev.id) == scheduler.idle_id => scheduler.idle = Some(prev),
+            Leave::Yield | Leave::Tick | Leave::Wake => {
+                let priority = prev.priority;
+                scheduler.ready.push(prev, priority, now);
+            }
+            Leave::Sleep(deadline) => scheduler.sleeping.push((deadline, prev)),
+            Leave::Park => scheduler.parked.push(prev),
+            Leave::Exit => scheduler.dead.push(prev),
+        }
+        (save, scheduler.current.rsp)
+    };
+    unsafe { thread_switch(save, next) };
+    reap();
+}
+
+// 加载下一个线程的页表和从ring 3进入内核时使用的栈
+fn activate(next: &Thread) {
+    let (frame, flags) = Cr3::read();
+    // 写CR3同时刷新所有非全局页的TLB, 内核部分在各地址空间中相同
+    if frame != next.cr3 {
+        unsafe { Cr3::write(next.cr3, flags) };
+    }
+    gdt::set_privilege_stack(next.kernel_stack);
+    let percpu = percpu::get();
+    percpu.set_syscall_stack(next.kernel_stack.as_u64());
+    percpu.set_current_thread(next as *const Thread as *mut ());
+}
+
+// 回收已结束的线程. 在调度器锁外释放, 释放栈和地址空间需要锁页表
+fn reap() {
+    let dead = interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .map(|scheduler| core::mem::take(&mut scheduler.dead))
+    });
+    for thread in dead.iter().flatten() {
+        sched::forget(Entity::Thread(thread.id.as_u64()));
+    }
+    drop(dead);
+}
+
+// 新线程从thread_start进入这里, 中断是关闭的
+#[no_mangle]
+extern "C" fn thread_main() -> ! {
+    reap();
+    let entry =
+        interrupts::without_interrupts(|| SCHEDULER.lock().as_mut().unwrap().current.entry.take());
+    interrupts::enable();
+    entry.expect("thread started twice")();
+    exit()
+}
+
+// 运行`count`个线程直到全部结束, 返回它们都执行到的次数
+#[cfg(test)]
+fn run_test_threads(count: usize) -> usize {
+    use core::sync::atomic::AtomicUsize;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..count {
+        let counter = counter.clone();
+        spawn("test", Priority::Normal, move || {
+            // 让出后由其他线程或时钟中断切换回来
+            yield_now();
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+    // 最后一个线程可能在退出前被抢占, 仍在就绪队列中
+    while Arc::strong_count(&counter) > 1 || has_ready() {
+        yield_now();
+    }
+    counter.load(Ordering::SeqCst)
+}
+
+#[test_case]
+fn test_threads_run_and_exit() {
+    // 第一次运行可能为新的栈地址分配页表
+    assert_eq!(run_test_threads(3), 3);
+    let before = memory::frame_stats().unwrap().allocated;
+    assert_eq!(run_test_threads(3), 3);
+    assert_eq!(current_id(), Some(ThreadId(0)));
+    assert!(!has_ready());
+    // 线程的栈已经回收
+    assert_eq!(memory::frame_stats().unwrap().allocated, before);
+}
+
+#[test_case]
+fn test_park_unpark() {
+    use core::sync::atomic::AtomicBool;
+
+    let woken = Arc::new(AtomicBool::new(false));
+    let flag = woken.clone();
+    let id = spawn("parker", Priority::Normal, move || {
+        // 没有unpark之前不会运行到这里之后
+        while !flag.load(Ordering::SeqCst) {
+            park();
+        }
+    })
+    .unwrap();
+    // 等线程运行到park
+    while has_ready() {
+        yield_now();
+    }
+    assert!(threads().iter().any(|thread| thread.id == id));
+    woken.store(true, Ordering::SeqCst);
+    unpark(id);
+    while Arc::strong_count(&woken) > 1 || has_ready() {
+        yield_now();
+    }
+    // 先unpark后park时不等待
+    unpark(current_id().unwrap());
+    park();
+}
+
+#[test_case]
+fn test_priorities_share_timeslices() {
+    use core::sync::atomic::AtomicBool;
+
+    const MEASURE_TICKS: u64 = 40;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    // 忙等线程记录运行过的时间片数和第一次运行时的tick
+    let spinner = |priority| {
+        let stats = Arc::new([AtomicU64::new(0), AtomicU64::new(u64::MAX)]);
+        let (record, stop) = (stats.clone(), stop.clone());
+        spawn("spinner", priority, move || {
+            let mut last = None;
+            while !stop.load(Ordering::Relaxed) {
+                let now = time::pit_ticks();
+                if last != Some(now) {
+                    last = Some(now);
+                    record[0].fetch_add(1, Ordering::Relaxed);
+                    record[1].fetch_min(now, Ordering::Relaxed);
+                }
+                core::hint::spin_loop();
+            }
+        })
+        .unwrap();
+        stats
+    };
+    let start = time::pit_ticks();
+    let low = spinner(Priority::Low);
+    let normal = spinner(Priority::Normal);
+    // 测试线程睡眠, 两个忙等线程竞争处理器
+    sleep_ticks(MEASURE_TICKS);
+    stop.store(true, Ordering::Relaxed);
+    while Arc::strong_count(&stop) > 1 {
+        yield_now();
+    }
+
+    let low_slices = low[0].load(Ordering::Relaxed);
+    let normal_slices = normal[0].load(Ordering::Relaxed);
+    assert!(
+        normal_slices > 2 * low_slices,
+        "normal {} vs low {} timeslices",
+        normal_slices,
+        low_slices
+    );
+    // 提升保证低优先级的线程在有限的时间片内开始运行
+    assert!(low_slices > 0, "low priority thread starved");
+    let waited = low[1].load(Ordering::Relaxed) - start;
+    assert!(
+        waited <= STARVATION_TICKS + 2,
+        "low priority thread waited {} ticks",
+        waited
+    );
+}
+
+#[test_case]
+fn test_block_on() {
+    use core::sync::atomic::AtomicBool;
+    use futures_util::task::AtomicWaker;
+
+    let state = Arc::new((AtomicBool::new(false), AtomicWaker::new()));
+    let setter = state.clone();
+    spawn("test", Priority::Normal, move || {
+        sleep_ms(10);
+        setter.0.store(true, Ordering::Release);
+        setter.1.wake();
+    })
+    .unwrap();
+    // 另一个线程唤醒之前当前线程park
+    block_on(core::future::poll_fn(|cx| {
+        state.1.register(cx.waker());
+        if state.0.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }));
+    while Arc::strong_count(&state) > 1 {
+        yield_now();
+    }
+}
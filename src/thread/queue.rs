@@ -0,0 +1,162 @@
+//! 按优先级排列的就绪队列
+//!
+//! 每个优先级一个先进先出的队列, 总是从最高的非空队列中取出. 低优先级队列最前面的线程
+//! 等待超过`STARVATION_TICKS`个时间片后临时按高优先级取出一次, 被抢占后恢复原来的优先级
+
+use alloc::collections::VecDeque;
+
+/// 线程的优先级
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// 从高到低
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    // 在`RunQueue::queues`中的位置
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+/// 低优先级的线程连续等待这么多个时间片后临时提升
+pub const STARVATION_TICKS: u64 = 4;
+
+struct Entry<T> {
+    item: T,
+    // 排入队列的tick
+    since: u64,
+}
+
+pub(super) struct RunQueue<T> {
+    queues: [VecDeque<Entry<T>>; 3],
+}
+
+impl<T> RunQueue<T> {
+    pub(super) const fn new() -> Self {
+        RunQueue {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        }
+    }
+
+    /// 在tick `now`排到`priority`的队列末尾
+    pub(super) fn push(&mut self, item: T, priority: Priority, now: u64) {
+        self.queues[priority.index()].push_back(Entry { item, since: now });
+    }
+
+    // 低优先级队列最前面的项是否已等待太久
+    fn starving(&self, now: u64) -> bool {
+        self.queues[Priority::Low.index()]
+            .front()
+            .is_some_and(|entry| now.saturating_sub(entry.since) >= STARVATION_TICKS)
+    }
+
+    /// 下一个取出的项的优先级, 临时提升的项算作高优先级
+    pub(super) fn top(&self, now: u64) -> Option<Priority> {
+        if self.starving(now) {
+            return Some(Priority::High);
+        }
+        Priority::ALL
+            .into_iter()
+            .find(|priority| !self.queues[priority.index()].is_empty())
+    }
+
+    /// 取出最高的非空队列最前面的项, 低优先级的项等待太久时先取出它
+    pub(super) fn pop(&mut self, now: u64) -> Option<T> {
+        let index = if self.starving(now) {
+            Priority::Low.index()
+        } else {
+            self.queues.iter().position(|queue| !queue.is_empty())?
+        };
+        self.queues[index].pop_front().map(|entry| entry.item)
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// 从高优先级到低优先级
+    pub(super) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.queues.iter().flatten().map(|entry| &entry.item)
+    }
+
+    /// 把第一个满足`pred`的项移到`priority`的队列末尾, 保留已等待的时间
+    pub(super) fn move_to(
+        &mut self,
+        priority: Priority,
+        mut pred: impl FnMut(&T) -> bool,
+    ) -> Option<&mut T> {
+        let (from, position) = self.queues.iter().enumerate().find_map(|(index, queue)| {
+            Some((index, queue.iter().position(|entry| pred(&entry.item))?))
+        })?;
+        let entry = self.queues[from].remove(position)?;
+        let queue = &mut self.queues[priority.index()];
+        queue.push_back(entry);
+        queue.back_mut().map(|entry| &mut entry.item)
+    }
+}
+
+#[test_case]
+fn test_highest_priority_first() {
+    let mut queue = RunQueue::new();
+    assert_eq!(queue.top(0), None);
+    queue.push("low", Priority::Low, 0);
+    queue.push("normal 1", Priority::Normal, 0);
+    queue.push("high", Priority::High, 0);
+    queue.push("normal 2", Priority::Normal, 0);
+    assert_eq!(queue.top(0), Some(Priority::High));
+    let order: alloc::vec::Vec<&str> = queue.iter().copied().collect();
+    assert_eq!(order, ["high", "normal 1", "normal 2", "low"]);
+    assert_eq!(queue.pop(1), Some("high"));
+    assert_eq!(queue.pop(1), Some("normal 1"));
+    assert_eq!(queue.top(1), Some(Priority::Normal));
+    assert_eq!(queue.pop(1), Some("normal 2"));
+    assert_eq!(queue.pop(1), Some("low"));
+    assert!(queue.is_empty());
+    assert_eq!(queue.pop(1), None);
+}
+
+#[test_case]
+fn test_starving_low_priority_is_boosted() {
+    let mut queue = RunQueue::new();
+    queue.push("low", Priority::Low, 10);
+    queue.push("normal", Priority::Normal, 10);
+    assert_eq!(queue.top(10 + STARVATION_TICKS - 1), Some(Priority::Normal));
+    assert_eq!(queue.top(10 + STARVATION_TICKS), Some(Priority::High));
+    assert_eq!(queue.pop(10 + STARVATION_TICKS), Some("low"));
+    // 重新排队后从头计算等待的时间
+    queue.push("low", Priority::Low, 20);
+    assert_eq!(queue.pop(21), Some("normal"));
+}
+
+#[test_case]
+fn test_move_keeps_waiting_time() {
+    let mut queue = RunQueue::new();
+    queue.push(1, Priority::High, 0);
+    queue.push(2, Priority::Normal, 3);
+    assert_eq!(
+        queue.move_to(Priority::Low, |&item| item == 1),
+        Some(&mut 1)
+    );
+    assert_eq!(queue.move_to(Priority::Low, |&item| item == 7), None);
+    assert_eq!(queue.pop(1), Some(2));
+    // 1从tick 0开始等待, 已经该提升了
+    queue.push(3, Priority::Normal, STARVATION_TICKS);
+    assert_eq!(queue.pop(STARVATION_TICKS), Some(1));
+}
@@ -0,0 +1,28 @@
+# 线程切换. 只保存被调用者保存的寄存器, 其余的由调用约定或中断处理函数保存
+
+.pushsection .text
+# rdi = 保存当前栈指针的位置, rsi = 要切换到的线程的栈指针
+.global thread_switch
+thread_switch:
+    push %rbx
+    push %rbp
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, (%rdi)
+    mov %rsi, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbp
+    pop %rbx
+    ret
+
+# 新线程第一次被切换到时从这里开始, 此时栈顶16字节对齐
+.global thread_start
+thread_start:
+    call thread_main
+    ud2
+.popsection
@@ -0,0 +1,587 @@
+use alloc::format;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+#[cfg(test)]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::selftest::{self, Check, Outcome};
+use crate::shell::{self, CmdError};
+use crate::workqueue::{self, Token};
+use crate::{cpu, hpet};
+
+// PIT输入频率, 未设置分频时使用默认的65536分频, 约18.2Hz
+pub const PIT_BASE_FREQUENCY: u64 = 1_193_182;
+pub const PIT_DEFAULT_DIVISOR: u64 = 65536;
+
+// 时钟中断计数, PIT只向BSP投递中断, 作为全局时钟; 各处理器自己的计数在percpu中
+static PIT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// 时钟源, 以时钟中断的tick为单位
+pub trait ClockSource: Sync {
+    fn now_ticks(&self) -> u64;
+}
+
+/// 由PIT时钟中断驱动的真实时钟
+pub struct PitClock;
+
+impl ClockSource for PitClock {
+    fn now_ticks(&self) -> u64 {
+        PIT_TICKS.load(Ordering::Relaxed)
+    }
+}
+
+static PIT_CLOCK: PitClock = PitClock;
+
+/// 当前生效的时钟源
+#[cfg(not(test))]
+pub fn clock() -> &'static dyn ClockSource {
+    &PIT_CLOCK
+}
+
+/// 当前生效的时钟源, 安装了FakeClock时返回FakeClock
+#[cfg(test)]
+pub fn clock() -> &'static dyn ClockSource {
+    if FAKE_ACTIVE.load(Ordering::SeqCst) {
+        &FAKE_CLOCK
+    } else {
+        &PIT_CLOCK
+    }
+}
+
+/// 当前时钟源的tick数
+pub fn ticks() -> u64 {
+    clock().now_ticks()
+}
+
+/// 启动以来真实发生的时钟中断次数, 不受FakeClock影响
+pub fn pit_ticks() -> u64 {
+    PIT_CLOCK.now_ticks()
+}
+
+/// 将秒数换算为tick数
+pub fn secs_to_ticks(secs: u64) -> u64 {
+    secs * PIT_BASE_FREQUENCY / PIT_DEFAULT_DIVISOR
+}
+
+/// 将毫秒数换算为tick数, 向上取整保证至少等待`ms`毫秒
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let divisor = PIT_DEFAULT_DIVISOR * 1000;
+    (ms * PIT_BASE_FREQUENCY).div_ceil(divisor)
+}
+
+/// 将tick数换算为毫秒数
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * PIT_DEFAULT_DIVISOR * 1000 / PIT_BASE_FREQUENCY
+}
+
+/// 忙等直到`condition`成立, 超过`timeout_ms`毫秒返回Err(Elapsed)
+///
+/// 超时依赖真实的时钟中断推进, 调用时中断必须已开启
+pub fn wait_until(timeout_ms: u64, mut condition: impl FnMut() -> bool) -> Result<(), Elapsed> {
+    let deadline = pit_ticks() + ms_to_ticks(timeout_ms);
+    loop {
+        if condition() {
+            return Ok(());
+        }
+        if pit_ticks() >= deadline {
+            return Err(Elapsed);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// 由时钟中断处理函数调用, 返回真实的tick数
+pub(crate) fn on_timer_interrupt() -> u64 {
+    let now = PIT_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    // 安装FakeClock时定时器只由advance_ticks驱动
+    if !fake_active() {
+        workqueue::defer_unique(&EXPIRE_TIMERS, expire_pending);
+    }
+    now
+}
+
+static EXPIRE_TIMERS: Token = Token::new();
+
+// 在中断之外扫描定时器表, 执行时可能已经过了几个tick
+fn expire_pending() {
+    if !fake_active() {
+        interrupts::without_interrupts(|| expire_timers(pit_ticks()));
+    }
+}
+
+#[cfg(not(test))]
+fn fake_active() -> bool {
+    false
+}
+
+#[cfg(test)]
+fn fake_active() -> bool {
+    FAKE_ACTIVE.load(Ordering::SeqCst)
+}
+
+// 定时器表, 扫描时只调用wake_by_ref, 不释放Waker, 因此关中断期间不会进入分配器
+const MAX_TIMERS: usize = 64;
+
+struct TimerEntry {
+    deadline: u64,
+    waker: Waker,
+    fired: bool,
+}
+
+static TIMERS: Mutex<[Option<TimerEntry>; MAX_TIMERS]> = {
+    const EMPTY: Option<TimerEntry> = None;
+    Mutex::new([EMPTY; MAX_TIMERS])
+};
+
+// 唤醒所有到期的定时器, 调用时中断必须已关闭
+fn expire_timers(now: u64) {
+    let mut timers = TIMERS.lock();
+    for entry in timers.iter_mut().flatten() {
+        if !entry.fired && entry.deadline <= now {
+            entry.fired = true;
+            entry.waker.wake_by_ref();
+        }
+    }
+}
+
+/// 等待到指定tick的future
+pub struct Sleep {
+    deadline: u64,
+    slot: Option<usize>,
+}
+
+/// 等待`ticks`个tick
+pub fn sleep_ticks(ticks: u64) -> Sleep {
+    Sleep {
+        deadline: self::ticks() + ticks,
+        slot: None,
+    }
+}
+
+/// 等待至少`ms`毫秒
+pub fn sleep_ms(ms: u64) -> Sleep {
+    sleep_ticks(ms_to_ticks(ms))
+}
+
+impl Sleep {
+    // 注册或更新waker, 定时器表已满时返回false
+    fn register(&mut self, waker: &Waker) -> bool {
+        let deadline = self.deadline;
+        interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            let slot = match self.slot {
+                Some(slot) => slot,
+                None => match timers.iter().position(|entry| entry.is_none()) {
+                    Some(slot) => slot,
+                    None => return false,
+                },
+            };
+            timers[slot] = Some(TimerEntry {
+                deadline,
+                waker: waker.clone(),
+                fired: false,
+            });
+            self.slot = Some(slot);
+            true
+        })
+    }
+
+    fn release(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            let entry = interrupts::without_interrupts(|| TIMERS.lock()[slot].take());
+            // 在关中断区域之外释放waker
+            drop(entry);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        if ticks() >= this.deadline {
+            this.release();
+            return Poll::Ready(());
+        }
+
+        if !this.register(cx.waker()) {
+            // 定时器表已满, 退化为忙轮询
+            cx.waker().wake_by_ref();
+        }
+
+        // 注册期间可能已经到期
+        if ticks() >= this.deadline {
+            this.release();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// future在限定时间内未完成
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// 限定完成时间的future
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// `future`在`ticks`个tick内未完成时返回Err(Elapsed)
+pub fn timeout<F: Future>(ticks: u64, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep_ticks(ticks),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // 只对future做结构化pin投影, sleep是Unpin的
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Instant使用的时钟源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstantSource {
+    Tsc,
+    Hpet,
+    Pit,
+}
+
+// 校准得到的TSC频率, 0表示未校准
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// 由hpet模块在校准后调用
+pub(crate) fn set_tsc_frequency(hz: u64) {
+    TSC_FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+/// 校准得到的TSC频率(Hz), 未校准时为None
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+// 只有不变的TSC才能直接换算成时间, 否则优先使用HPET
+fn select_source(invariant_tsc: bool, tsc_hz: u64, hpet: bool) -> InstantSource {
+    if invariant_tsc && tsc_hz != 0 {
+        InstantSource::Tsc
+    } else if hpet {
+        InstantSource::Hpet
+    } else {
+        InstantSource::Pit
+    }
+}
+
+/// 当前Instant使用的时钟源, HPET初始化之前退化为PIT
+pub fn instant_source() -> InstantSource {
+    let tsc_hz = TSC_FREQUENCY.load(Ordering::Relaxed);
+    select_source(cpu::features().invariant_tsc, tsc_hz, hpet::available())
+}
+
+fn monotonic_ns() -> u64 {
+    match instant_source() {
+        InstantSource::Tsc => {
+            let tsc_hz = TSC_FREQUENCY.load(Ordering::Relaxed);
+            (u128::from(cpu::rdtsc()) * 1_000_000_000 / u128::from(tsc_hz)) as u64
+        }
+        InstantSource::Hpet => hpet::now_ns().unwrap_or(0),
+        InstantSource::Pit => ticks_to_ms(pit_ticks()) * 1_000_000,
+    }
+}
+
+/// 单调的时间点, 不受FakeClock影响
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ns: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant { ns: monotonic_ns() }
+    }
+
+    /// `earlier`晚于`self`时返回0
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.ns.saturating_sub(earlier.ns))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+/// 忙等至少`us`微秒, 只有PIT可用时按时钟中断向上取整, 调用时中断必须已开启
+pub fn delay_us(us: u64) {
+    match instant_source() {
+        // 至少多等一个tick, 第一个tick可能马上到来
+        InstantSource::Pit => {
+            let _ = wait_until(us.div_ceil(1000) + ticks_to_ms(1), || false);
+        }
+        _ => {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_micros(us) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// 运行时间, 格式为`HH:MM:SS.mmm`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uptime {
+    pub ms: u64,
+}
+
+/// 当前时钟源下的运行时间
+pub fn uptime() -> Uptime {
+    Uptime {
+        ms: ticks_to_ms(ticks()),
+    }
+}
+
+/// 注册`uptime`命令
+pub fn register_commands() {
+    shell::register_command("uptime", "time since boot", uptime_command)
+        .expect("duplicate time command");
+}
+
+fn uptime_command(_args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    writeln!(out, "up {}", uptime())?;
+    Ok(())
+}
+
+/// 登记时钟中断的自检, 其他依赖超时的检查以它为前提
+pub fn register_selftests() {
+    static TIMER: Check = Check::new("timer", timer_selftest);
+    selftest::register(&TIMER).expect("duplicate time selftest");
+}
+
+// 用TSC限制等待时间, 被检查的时钟中断不工作时也不会卡住
+const SELFTEST_WAIT_MS: u64 = 500;
+/// TSC未校准时按3GHz估计
+pub(crate) const ASSUMED_TSC_HZ: u64 = 3_000_000_000;
+
+fn timer_selftest() -> Outcome {
+    if !interrupts::are_enabled() {
+        return Outcome::Fail("interrupts are disabled".into());
+    }
+    let tsc_hz = tsc_frequency().unwrap_or(ASSUMED_TSC_HZ);
+    let deadline = cpu::rdtsc() + tsc_hz / 1000 * SELFTEST_WAIT_MS;
+    let start = pit_ticks();
+    // 第一个tick可能马上到来, 等到第二个
+    while pit_ticks() < start + 2 {
+        if cpu::rdtsc() >= deadline {
+            let masked = if crate::interrupts::irq_masked(0) {
+                " (IRQ0 is masked)"
+            } else {
+                ""
+            };
+            return Outcome::Fail(format!(
+                "no timer interrupt within {}ms{}",
+                SELFTEST_WAIT_MS, masked
+            ));
+        }
+        core::hint::spin_loop();
+    }
+    Outcome::Pass
+}
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.ms / 1000;
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            self.ms % 1000
+        )
+    }
+}
+
+// 测试用的手动时钟
+#[cfg(test)]
+static FAKE_ACTIVE: AtomicBool = AtomicBool::new(false);
+#[cfg(test)]
+static FAKE_CLOCK: FakeClock = FakeClock {
+    now: AtomicU64::new(0),
+};
+
+/// 测试用时钟, 只在调用advance_ticks时前进
+#[cfg(test)]
+pub struct FakeClock {
+    now: AtomicU64,
+}
+
+#[cfg(test)]
+impl ClockSource for FakeClock {
+    fn now_ticks(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl FakeClock {
+    /// 安装FakeClock并从0开始计时, 返回的guard被drop时恢复真实时钟
+    pub fn install() -> FakeClockGuard {
+        interrupts::without_interrupts(|| {
+            assert!(!FAKE_ACTIVE.load(Ordering::SeqCst), "fake clock already installed");
+            FAKE_CLOCK.now.store(0, Ordering::SeqCst);
+            FAKE_ACTIVE.store(true, Ordering::SeqCst);
+        });
+        FakeClockGuard { clock: &FAKE_CLOCK }
+    }
+
+    /// 前进`n`个tick并同步触发到期的定时器
+    pub fn advance_ticks(&self, n: u64) {
+        interrupts::without_interrupts(|| {
+            let now = self.now.fetch_add(n, Ordering::SeqCst) + n;
+            expire_timers(now);
+        });
+    }
+}
+
+#[cfg(test)]
+pub struct FakeClockGuard {
+    clock: &'static FakeClock,
+}
+
+#[cfg(test)]
+impl core::ops::Deref for FakeClockGuard {
+    type Target = FakeClock;
+
+    fn deref(&self) -> &FakeClock {
+        self.clock
+    }
+}
+
+#[cfg(test)]
+impl Drop for FakeClockGuard {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| FAKE_ACTIVE.store(false, Ordering::SeqCst));
+    }
+}
+
+// 记录被唤醒次数的waker
+#[cfg(test)]
+static WAKE_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+fn counting_waker() -> Waker {
+    use core::task::{RawWaker, RawWakerVTable};
+
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WAKE_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+    fn drop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+    unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+}
+
+#[test_case]
+fn test_sleep_fires_on_fake_advance() {
+    let fake = FakeClock::install();
+    let waker = counting_waker();
+    let mut cx = Context::from_waker(&waker);
+    WAKE_COUNT.store(0, Ordering::SeqCst);
+
+    let mut sleep = sleep_ticks(5);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Pending);
+    fake.advance_ticks(4);
+    assert_eq!(WAKE_COUNT.load(Ordering::SeqCst), 0);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Pending);
+
+    fake.advance_ticks(1);
+    assert_eq!(WAKE_COUNT.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Ready(()));
+}
+
+#[test_case]
+fn test_timeout_with_fake_clock() {
+    let fake = FakeClock::install();
+    let waker = counting_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    let mut ready = timeout(3, core::future::ready(7));
+    assert_eq!(Pin::new(&mut ready).poll(&mut cx), Poll::Ready(Ok(7)));
+
+    let mut pending = timeout(3, core::future::pending::<()>());
+    assert_eq!(Pin::new(&mut pending).poll(&mut cx), Poll::Pending);
+    fake.advance_ticks(2);
+    assert_eq!(Pin::new(&mut pending).poll(&mut cx), Poll::Pending);
+    fake.advance_ticks(1);
+    assert_eq!(Pin::new(&mut pending).poll(&mut cx), Poll::Ready(Err(Elapsed)));
+}
+
+#[test_case]
+fn test_uptime_format() {
+    use alloc::format;
+
+    let fake = FakeClock::install();
+    assert_eq!(format!("{}", uptime()), "00:00:00.000");
+
+    fake.advance_ticks(secs_to_ticks(61) + 1);
+    assert!(format!("{}", uptime()).starts_with("00:01:01."));
+
+    assert_eq!(format!("{}", Uptime { ms: 3_723_045 }), "01:02:03.045");
+}
+
+#[test_case]
+fn test_instant_source_selection() {
+    assert_eq!(select_source(true, 2_000_000_000, true), InstantSource::Tsc);
+    // TSC会随频率变化时即使已校准也改用HPET
+    assert_eq!(select_source(false, 2_000_000_000, true), InstantSource::Hpet);
+    assert_eq!(select_source(true, 0, true), InstantSource::Hpet);
+    assert_eq!(select_source(false, 0, false), InstantSource::Pit);
+}
+
+#[test_case]
+fn test_instant_monotonic() {
+    let start = Instant::now();
+    let mut last = start;
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+    assert_eq!(start.duration_since(last), Duration::ZERO);
+    if instant_source() != InstantSource::Pit {
+        assert!(last.duration_since(start) > Duration::ZERO);
+    }
+}
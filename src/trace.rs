@@ -0,0 +1,467 @@
+//! 事件跟踪: 每个处理器一个固定大小的环形缓冲区, 记录TSC、事件编号和一个参数
+//!
+//! 只在启用`tracing` feature时记录, 关闭时`trace!`是空函数调用. 记录一个事件是一次原子加法、
+//! 一次rdtsc和四次写入, 不加锁也不关中断, 中断处理函数中同样可以使用.
+//! `dump`按时间戳合并各处理器的记录, 用于分析中断、调度和任务之间的先后顺序
+
+use core::fmt;
+#[cfg(feature = "tracing")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use pc_keyboard::DecodedKey;
+
+use crate::shell::{self, CmdError};
+
+/// 每个处理器保留的记录数, 写满后覆盖最旧的记录
+pub const RING_SIZE: usize = 1024;
+/// 有缓冲区的处理器数, 编号更大的处理器记录到最后一个缓冲区
+pub const MAX_CPUS: usize = 8;
+
+/// 预定义的事件, 参数的含义见各项
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum EventId {
+    /// 进入中断处理函数, 参数为向量号
+    IrqEntry = 1,
+    /// 中断处理函数返回, 参数为向量号
+    IrqExit,
+    /// 切换线程, 参数为下一个线程的编号
+    ContextSwitch,
+    /// 开始轮询任务, 参数为任务编号
+    TaskPollStart,
+    /// 任务轮询返回, 参数为任务编号
+    TaskPollEnd,
+    /// 获得`IrqMutex`, 参数由`lock_arg`编码锁名
+    LockAcquire,
+    /// 释放`IrqMutex`
+    LockRelease,
+    /// 键盘中断读到的扫描码
+    Scancode,
+    /// 解码后的按键, 参数由`key_arg`编码
+    KeyDecoded,
+    /// 测试用的事件, 参数任意
+    Marker,
+}
+
+const EVENTS: [EventId; 10] = [
+    EventId::IrqEntry,
+    EventId::IrqExit,
+    EventId::ContextSwitch,
+    EventId::TaskPollStart,
+    EventId::TaskPollEnd,
+    EventId::LockAcquire,
+    EventId::LockRelease,
+    EventId::Scancode,
+    EventId::KeyDecoded,
+    EventId::Marker,
+];
+
+impl EventId {
+    pub fn from_u16(id: u16) -> Option<EventId> {
+        EVENTS.iter().copied().find(|&event| event as u16 == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EventId::IrqEntry => "irq_entry",
+            EventId::IrqExit => "irq_exit",
+            EventId::ContextSwitch => "context_switch",
+            EventId::TaskPollStart => "task_poll_start",
+            EventId::TaskPollEnd => "task_poll_end",
+            EventId::LockAcquire => "lock_acquire",
+            EventId::LockRelease => "lock_release",
+            EventId::Scancode => "scancode",
+            EventId::KeyDecoded => "key_decoded",
+            EventId::Marker => "marker",
+        }
+    }
+}
+
+/// 记录一个事件: `trace!(EventId::IrqEntry, vector)`. 未启用`tracing` feature时不做任何事
+#[macro_export]
+macro_rules! trace {
+    ($event:expr, $arg:expr) => {
+        $crate::trace::record($event, $arg as u64)
+    };
+}
+
+/// 把锁名编码为一个参数: 高16位为长度, 低48位为地址. 锁名都是内核镜像中的字符串常量
+pub fn lock_arg(name: &'static str) -> u64 {
+    (name.len().min(0xFFFF) as u64) << 48 | (name.as_ptr() as u64 & 0xFFFF_FFFF_FFFF)
+}
+
+// 不对应字符的按键的参数在键码上加上这一位
+const RAW_KEY: u64 = 1 << 32;
+
+/// 把按键编码为一个参数: 字符为码点, 其他按键为`RAW_KEY`加键码
+pub fn key_arg(key: &DecodedKey) -> u64 {
+    match *key {
+        DecodedKey::Unicode(character) => u64::from(character),
+        DecodedKey::RawKey(code) => RAW_KEY | code as u64,
+    }
+}
+
+/// `lock_arg`编码的锁名
+pub fn lock_name(arg: u64) -> &'static str {
+    let len = (arg >> 48) as usize;
+    let ptr = (arg & 0xFFFF_FFFF_FFFF) as *const u8;
+    if ptr.is_null() {
+        return "?";
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+    core::str::from_utf8(bytes).unwrap_or("?")
+}
+
+/// 从缓冲区取出的一条记录
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub cpu: usize,
+    /// 在该处理器上的序号, 从0开始不断增加
+    pub seq: u64,
+    pub tsc: u64,
+    pub event_id: u16,
+    pub arg: u64,
+}
+
+// 一条记录占三个字. `stamp`为`(seq + 1) << 16 | event_id`, 最后写入;
+// 写入其他字段之前先清零, 读者前后两次读到相同的`stamp`才说明记录完整
+#[cfg(feature = "tracing")]
+struct Slot {
+    tsc: AtomicU64,
+    arg: AtomicU64,
+    stamp: AtomicU64,
+}
+
+#[cfg(feature = "tracing")]
+struct Ring {
+    next: AtomicU64,
+    // `clear`时的`next`, 更早的记录不再输出
+    start: AtomicU64,
+    slots: [Slot; RING_SIZE],
+}
+
+#[cfg(feature = "tracing")]
+static RINGS: [Ring; MAX_CPUS] = [const {
+    Ring {
+        next: AtomicU64::new(0),
+        start: AtomicU64::new(0),
+        slots: [const {
+            Slot {
+                tsc: AtomicU64::new(0),
+                arg: AtomicU64::new(0),
+                stamp: AtomicU64::new(0),
+            }
+        }; RING_SIZE],
+    }
+}; MAX_CPUS];
+
+// 还没有每处理器数据时只有BSP在运行, 使用第一个缓冲区
+#[cfg(feature = "tracing")]
+fn current_ring() -> &'static Ring {
+    let cpu = crate::percpu::try_get().map_or(0, |percpu| percpu.cpu_id as usize);
+    &RINGS[cpu.min(MAX_CPUS - 1)]
+}
+
+/// 由`trace!`调用
+#[inline(always)]
+pub fn record(event: EventId, arg: u64) {
+    #[cfg(feature = "tracing")]
+    {
+        let ring = current_ring();
+        // 原子加法分配序号, 打断这里的中断处理函数会得到下一个序号
+        let seq = ring.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &ring.slots[seq as usize % RING_SIZE];
+        slot.stamp.store(0, Ordering::Release);
+        slot.tsc.store(crate::cpu::rdtsc(), Ordering::Release);
+        slot.arg.store(arg, Ordering::Release);
+        slot.stamp
+            .store((seq + 1) << 16 | event as u64, Ordering::Release);
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (event, arg);
+}
+
+/// 是否启用了`tracing` feature
+pub const fn enabled() -> bool {
+    cfg!(feature = "tracing")
+}
+
+/// 丢弃所有处理器已有的记录
+pub fn clear() {
+    #[cfg(feature = "tracing")]
+    for ring in &RINGS {
+        ring.start
+            .store(ring.next.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+// 按序号依次读取一个处理器的记录, 跳过读取时已被覆盖的记录
+#[cfg(feature = "tracing")]
+struct Cursor {
+    cpu: usize,
+    seq: u64,
+    end: u64,
+}
+
+#[cfg(feature = "tracing")]
+impl Cursor {
+    fn new(cpu: usize) -> Cursor {
+        let ring = &RINGS[cpu];
+        let end = ring.next.load(Ordering::Acquire);
+        let oldest = end.saturating_sub(RING_SIZE as u64);
+        Cursor {
+            cpu,
+            seq: oldest.max(ring.start.load(Ordering::Relaxed)),
+            end,
+        }
+    }
+
+    fn read(&self, seq: u64) -> Option<Record> {
+        let slot = &RINGS[self.cpu].slots[seq as usize % RING_SIZE];
+        let stamp = slot.stamp.load(Ordering::Acquire);
+        let tsc = slot.tsc.load(Ordering::Acquire);
+        let arg = slot.arg.load(Ordering::Acquire);
+        if stamp >> 16 != seq + 1 || slot.stamp.load(Ordering::Acquire) != stamp {
+            return None;
+        }
+        Some(Record {
+            cpu: self.cpu,
+            seq,
+            tsc,
+            event_id: stamp as u16,
+            arg,
+        })
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Iterator for Cursor {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        while self.seq < self.end {
+            let seq = self.seq;
+            self.seq += 1;
+            if let Some(record) = self.read(seq) {
+                return Some(record);
+            }
+        }
+        None
+    }
+}
+
+/// 按TSC从小到大依次把各处理器的记录交给`f`, 只包括调用时已经写入的记录. 不分配内存
+pub fn for_each(mut f: impl FnMut(&Record)) {
+    #[cfg(feature = "tracing")]
+    {
+        let mut cursors: [core::iter::Peekable<Cursor>; MAX_CPUS] =
+            core::array::from_fn(|cpu| Cursor::new(cpu).peekable());
+        loop {
+            let earliest = (0..MAX_CPUS)
+                .filter_map(|cpu| Some((cursors[cpu].peek()?.tsc, cpu)))
+                .min();
+            let Some((_, cpu)) = earliest else {
+                break;
+            };
+            f(&cursors[cpu].next().unwrap());
+        }
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = &mut f;
+}
+
+/// 输出所有记录, 每行包括处理器、相对第一条记录的时间、与上一条记录的间隔、事件名和参数
+///
+/// TSC频率已校准时时间以微秒为单位, 否则为TSC周期
+pub fn dump(out: &mut dyn fmt::Write) -> fmt::Result {
+    if !enabled() {
+        return writeln!(out, "tracing disabled, build with --features tracing");
+    }
+    let hz = crate::time::tsc_frequency();
+    let unit = if hz.is_some() { "us" } else { "cycles" };
+    writeln!(
+        out,
+        "{:<4}{:>14}{:>12}  {:<16}arg",
+        "cpu",
+        alloc::format!("time({})", unit),
+        "delta",
+        "event"
+    )?;
+    let mut first = None;
+    let mut previous = 0;
+    let mut result = Ok(());
+    for_each(|record| {
+        if result.is_err() {
+            return;
+        }
+        let start = *first.get_or_insert(record.tsc);
+        let time = Cycles(record.tsc - start, hz);
+        let delta = Cycles(record.tsc.saturating_sub(previous.max(start)), hz);
+        previous = record.tsc;
+        result = writeln!(
+            out,
+            "{:<4}{:>14}{:>12}  {}",
+            record.cpu,
+            time,
+            alloc::format!("+{}", delta),
+            Describe(record)
+        );
+    });
+    result
+}
+
+// TSC周期数, 频率已知时换算成微秒. 支持宽度和对齐
+struct Cycles(u64, Option<u64>);
+
+impl fmt::Display for Cycles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self.1 {
+            Some(hz) => {
+                let ns = u128::from(self.0) * 1_000_000_000 / u128::from(hz);
+                alloc::format!("{}.{:03}", ns / 1000, ns % 1000)
+            }
+            None => alloc::format!("{}", self.0),
+        };
+        f.pad(&text)
+    }
+}
+
+// 事件名和按事件解释的参数
+struct Describe<'a>(&'a Record);
+
+impl fmt::Display for Describe<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arg = self.0.arg;
+        let Some(event) = EventId::from_u16(self.0.event_id) else {
+            return write!(
+                f,
+                "{:<16}{:#x}",
+                alloc::format!("#{}", self.0.event_id),
+                arg
+            );
+        };
+        write!(f, "{:<16}", event.name())?;
+        match event {
+            EventId::IrqEntry | EventId::IrqExit => write!(f, "vector {}", arg),
+            EventId::ContextSwitch => write!(f, "thread {}", arg),
+            EventId::TaskPollStart | EventId::TaskPollEnd => write!(f, "task {}", arg),
+            EventId::LockAcquire | EventId::LockRelease => f.write_str(lock_name(arg)),
+            EventId::Scancode => write!(f, "{:#04x}", arg),
+            EventId::KeyDecoded if arg & RAW_KEY != 0 => write!(f, "raw key {}", arg & 0xFF),
+            EventId::KeyDecoded => match u32::try_from(arg).ok().and_then(char::from_u32) {
+                Some(c) => write!(f, "{:?}", c),
+                None => write!(f, "{:#x}", arg),
+            },
+            EventId::Marker => write!(f, "{:#x}", arg),
+        }
+    }
+}
+
+/// 注册`trace`命令
+pub fn register_commands() {
+    shell::register_command(
+        "trace",
+        "trace [clear]: dump the event trace, then optionally clear it",
+        trace_command,
+    )
+    .expect("duplicate trace command");
+}
+
+fn trace_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    match args {
+        [] => dump(out)?,
+        ["clear"] => {
+            dump(out)?;
+            clear();
+        }
+        _ => return Err(CmdError::Usage("trace [clear]")),
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_lock_arg_round_trip() {
+    static NAME: &str = "vga_buffer::WRITER";
+    assert_eq!(lock_name(lock_arg(NAME)), NAME);
+    assert_eq!(lock_name(lock_arg("")), "");
+    for event in EVENTS {
+        assert_eq!(EventId::from_u16(event as u16), Some(event));
+    }
+    assert_eq!(EventId::from_u16(0), None);
+}
+
+#[test_case]
+fn test_dump_format() {
+    let mut out = alloc::string::String::new();
+    if !enabled() {
+        dump(&mut out).unwrap();
+        assert!(out.contains("disabled"));
+        return;
+    }
+    clear();
+    trace!(EventId::Marker, 0x5eed);
+    trace!(EventId::KeyDecoded, key_arg(&DecodedKey::Unicode('k')));
+    dump(&mut out).unwrap();
+    let marker = out
+        .lines()
+        .position(|line| line.contains("marker          0x5eed"));
+    let key = out
+        .lines()
+        .position(|line| line.contains("key_decoded     'k'"));
+    assert!(
+        matches!((marker, key), (Some(m), Some(k)) if m < k),
+        "{}",
+        out
+    );
+    // 时间从第一条记录开始
+    let first = out.lines().nth(1).unwrap();
+    assert!(
+        first
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|t| t.starts_with("0")),
+        "{}",
+        first
+    );
+}
+
+// 写入远多于缓冲区大小的记录, 期间时钟中断也在写入. 读出的每条记录都必须完整:
+// 事件编号与参数中的校验位一致, 同一处理器的序号和时间戳递增
+#[test_case]
+fn test_ring_wraps_cleanly() {
+    if !enabled() {
+        return;
+    }
+    const COUNT: u64 = RING_SIZE as u64 * 5;
+    clear();
+    for i in 0..COUNT {
+        let arg = i | (!i & 0xFFFF_FFFF) << 32;
+        trace!(EventId::Marker, arg);
+    }
+    let mut markers = 0;
+    let mut last: [Option<(u64, u64)>; MAX_CPUS] = [None; MAX_CPUS];
+    for_each(|record| {
+        if let Some((seq, tsc)) = last[record.cpu] {
+            assert!(record.seq > seq && record.tsc >= tsc, "{:?}", record);
+        }
+        last[record.cpu] = Some((record.seq, record.tsc));
+        if record.event_id == EventId::Marker as u16 {
+            let low = record.arg & 0xFFFF_FFFF;
+            assert_eq!(
+                record.arg >> 32,
+                !low & 0xFFFF_FFFF,
+                "torn record {:?}",
+                record
+            );
+            markers += 1;
+        } else {
+            assert!(EventId::from_u16(record.event_id).is_some(), "{:?}", record);
+        }
+    });
+    // 只保留最后一圈, 加上中断和锁的事件不超过缓冲区大小
+    assert!(
+        markers > RING_SIZE / 2 && markers <= RING_SIZE,
+        "{} markers",
+        markers
+    );
+}
@@ -0,0 +1,354 @@
+//! 终端的行规程, 键盘和串口的输入都经过这里
+//!
+//! 规范模式下维护编辑中的行并回显, 回车后把整行送入通道; 原始模式下每个按键原样送出, 不回显.
+//! Ctrl-C在规范模式下放弃当前行并通知登记的处理函数. 外壳和调试串口各有一个终端,
+//! 以后用户程序的read(0)也从终端的通道读取
+
+use alloc::string::String;
+use core::fmt;
+
+use futures_util::stream::{Stream, StreamExt};
+use spin::Mutex;
+
+use crate::task::channel::{Channel, ChannelStream};
+
+pub mod editor;
+pub mod input;
+
+pub use editor::{Key, LineEditor};
+pub use input::{key_from_decoded, SerialDecoder};
+
+// 通道中最多缓存的行数
+const LINE_QUEUE_SIZE: usize = 16;
+
+/// 行规程的工作方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// 编辑整行, 回车后提交
+    Cooked,
+    /// 每个按键原样送出
+    Raw,
+}
+
+/// 规范模式下的回显
+pub trait Echo {
+    /// 编辑中的行或光标改变, 重新绘制当前行
+    fn redraw(&mut self, editor: &LineEditor);
+    /// 当前行已经提交或被Ctrl-C放弃, 之后的输入从新的一行开始
+    fn finish(&mut self, line: &str, interrupted: bool);
+}
+
+/// 处理一个按键的结果
+#[derive(Debug, PartialEq, Eq)]
+pub enum Output {
+    None,
+    /// 提交的一行, 或原始模式下一个按键的内容
+    Input(String),
+    /// 规范模式下的Ctrl-C
+    Interrupt,
+}
+
+/// 行规程, 只处理按键和回显, 由`Tty`负责把结果送出
+pub struct LineDiscipline<E> {
+    editor: LineEditor,
+    echo: E,
+    mode: Mode,
+}
+
+impl<E: Echo> LineDiscipline<E> {
+    /// 以规范模式开始, 一行最多`max_len`个字符
+    pub const fn new(max_len: usize, echo: E) -> Self {
+        LineDiscipline {
+            editor: LineEditor::new(max_len),
+            echo,
+            mode: Mode::Cooked,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn editor(&self) -> &LineEditor {
+        &self.editor
+    }
+
+    pub fn feed(&mut self, key: Key) -> Output {
+        if self.mode == Mode::Raw {
+            return Output::Input(input::raw_text(key));
+        }
+        match key {
+            Key::Interrupt => {
+                let line = self.editor.line();
+                self.editor.handle(key);
+                self.echo.finish(&line, true);
+                self.echo.redraw(&self.editor);
+                Output::Interrupt
+            }
+            key => match self.editor.handle(key) {
+                Some(line) => {
+                    self.echo.finish(&line, false);
+                    Output::Input(line)
+                }
+                None => {
+                    self.echo.redraw(&self.editor);
+                    Output::None
+                }
+            },
+        }
+    }
+
+    /// 切换模式. 进入原始模式时编辑到一半的行作为输入送出, 回到规范模式时重新绘制空行
+    pub fn set_mode(&mut self, mode: Mode) -> Output {
+        if mode == self.mode {
+            return Output::None;
+        }
+        self.mode = mode;
+        match mode {
+            Mode::Raw => {
+                let line = self.editor.line();
+                self.editor.handle(Key::Interrupt);
+                self.echo.finish(&line, false);
+                if line.is_empty() {
+                    Output::None
+                } else {
+                    Output::Input(line)
+                }
+            }
+            Mode::Cooked => {
+                self.echo.redraw(&self.editor);
+                Output::None
+            }
+        }
+    }
+
+    /// 在规范模式下重新绘制当前行, 例如命令的输出覆盖了提示符之后
+    pub fn redraw(&mut self) {
+        if self.mode == Mode::Cooked {
+            self.echo.redraw(&self.editor);
+        }
+    }
+}
+
+/// 一个终端: 行规程和接收输入的通道
+pub struct Tty<E> {
+    discipline: Mutex<LineDiscipline<E>>,
+    lines: Channel<String>,
+    on_interrupt: Mutex<Option<fn()>>,
+}
+
+impl<E: Echo> Tty<E> {
+    pub const fn new(max_len: usize, echo: E) -> Self {
+        Tty {
+            discipline: Mutex::new(LineDiscipline::new(max_len, echo)),
+            lines: Channel::new(LINE_QUEUE_SIZE),
+            on_interrupt: Mutex::new(None),
+        }
+    }
+
+    /// 输入的接收端, 应在输入到达之前创建, 之前的输入被丢弃
+    pub fn lines(&'static self) -> ChannelStream<String> {
+        self.lines.stream()
+    }
+
+    /// 登记Ctrl-C的处理函数, 替换之前登记的
+    pub fn set_interrupt_handler(&self, handler: fn()) {
+        *self.on_interrupt.lock() = Some(handler);
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.discipline.lock().mode()
+    }
+
+    pub fn set_mode(&self, mode: Mode) {
+        let output = self.discipline.lock().set_mode(mode);
+        self.deliver(output);
+    }
+
+    pub fn redraw(&self) {
+        self.discipline.lock().redraw();
+    }
+
+    pub fn feed(&self, key: Key) {
+        let output = self.discipline.lock().feed(key);
+        self.deliver(output);
+    }
+
+    /// 把按键流送入行规程, 按键流结束时返回
+    pub async fn run(&self, keys: impl Stream<Item = Key>) {
+        let mut keys = core::pin::pin!(keys);
+        while let Some(key) = keys.next().await {
+            self.feed(key);
+        }
+    }
+
+    // 处理函数在释放行规程的锁之后调用, 可以再操作终端
+    fn deliver(&self, output: Output) {
+        match output {
+            Output::None => {}
+            Output::Input(text) => self.lines.push(text),
+            Output::Interrupt => {
+                let handler = *self.on_interrupt.lock();
+                if let Some(handler) = handler {
+                    handler();
+                }
+            }
+        }
+    }
+}
+
+/// 输出到行式终端的回显: 用回车回到行首重画提示符和整行, 再把光标左移到编辑位置
+pub struct Terminal<W> {
+    prompt: &'static str,
+    out: W,
+}
+
+impl<W: fmt::Write> Terminal<W> {
+    pub const fn new(prompt: &'static str, out: W) -> Self {
+        Terminal { prompt, out }
+    }
+}
+
+impl<W: fmt::Write> Echo for Terminal<W> {
+    fn redraw(&mut self, editor: &LineEditor) {
+        let line = editor.line();
+        // ESC [K清除到行尾, 删除字符后不留残余
+        let _ = write!(self.out, "\r{}{}\x1b[K", self.prompt, line);
+        let back = line.chars().count() - editor.cursor();
+        if back > 0 {
+            let _ = write!(self.out, "\x1b[{}D", back);
+        }
+    }
+
+    fn finish(&mut self, line: &str, interrupted: bool) {
+        let mark = if interrupted { "^C" } else { "" };
+        let _ = write!(self.out, "\r{}{}{}\x1b[K\n", self.prompt, line, mark);
+    }
+}
+
+#[cfg(test)]
+fn feed_str(tty: &mut LineDiscipline<Terminal<String>>, text: &str) -> alloc::vec::Vec<Output> {
+    let mut decoder = SerialDecoder::new();
+    text.bytes()
+        .filter_map(|byte| decoder.feed(byte))
+        .map(|key| tty.feed(key))
+        .filter(|output| *output != Output::None)
+        .collect()
+}
+
+#[cfg(test)]
+fn take_echo(tty: &mut LineDiscipline<Terminal<String>>) -> String {
+    core::mem::take(&mut tty.echo.out)
+}
+
+#[test_case]
+fn test_mid_line_edit() {
+    let mut tty = LineDiscipline::new(64, Terminal::new("> ", String::new()));
+    assert_eq!(feed_str(&mut tty, "mm\x1b[De"), []);
+    assert_eq!(
+        take_echo(&mut tty),
+        "\r> m\x1b[K\r> mm\x1b[K\r> mm\x1b[K\x1b[1D\r> mem\x1b[K\x1b[1D"
+    );
+    // Ctrl-W删除光标前的词, Ctrl-U删除光标前的全部
+    assert_eq!(
+        feed_str(&mut tty, "\x1b[F stats junk\x17\x1b[H\x1b[3~m"),
+        []
+    );
+    assert_eq!(tty.editor().line(), "mem stats ");
+    feed_str(&mut tty, "\x1b[Fxx\x1b[D\x15");
+    assert_eq!(tty.editor().line(), "x");
+    take_echo(&mut tty);
+    assert_eq!(
+        feed_str(&mut tty, "\x1b[F\x7fmem stats\r\n"),
+        [Output::Input("mem stats".into())]
+    );
+    assert!(take_echo(&mut tty).ends_with("\r> mem stats\x1b[K\r> mem stats\x1b[K\n"));
+    // 历史里的行可以取回再提交
+    assert_eq!(
+        feed_str(&mut tty, "\x1b[A\r"),
+        [Output::Input("mem stats".into())]
+    );
+}
+
+#[test_case]
+fn test_interrupt_during_input() {
+    let mut tty = LineDiscipline::new(64, Terminal::new("> ", String::new()));
+    feed_str(&mut tty, "ps -a");
+    take_echo(&mut tty);
+    assert_eq!(feed_str(&mut tty, "\x03"), [Output::Interrupt]);
+    // 放弃的行后面标记^C, 然后在新的一行重新显示提示符
+    assert_eq!(take_echo(&mut tty), "\r> ps -a^C\x1b[K\n\r> \x1b[K");
+    assert_eq!(tty.editor().line(), "");
+    assert_eq!(feed_str(&mut tty, "\r"), [Output::Input(String::new())]);
+    // 被放弃的行不进入历史
+    assert_eq!(
+        feed_str(&mut tty, "\x1b[A\r"),
+        [Output::Input(String::new())]
+    );
+}
+
+#[test_case]
+fn test_mode_switch() {
+    let mut tty = LineDiscipline::new(64, Terminal::new("> ", String::new()));
+    feed_str(&mut tty, "ab");
+    take_echo(&mut tty);
+    // 编辑到一半的行作为输入送出
+    assert_eq!(tty.set_mode(Mode::Raw), Output::Input("ab".into()));
+    assert_eq!(take_echo(&mut tty), "\r> ab\x1b[K\n");
+    assert_eq!(tty.set_mode(Mode::Raw), Output::None);
+    // 原始模式下按键原样送出, Ctrl-C也是普通输入, 没有回显
+    assert_eq!(
+        feed_str(&mut tty, "x\r\n\x1b[A\x03"),
+        [
+            Output::Input("x".into()),
+            Output::Input("\n".into()),
+            Output::Input("\x1b[A".into()),
+            Output::Input("\x03".into()),
+        ]
+    );
+    assert_eq!(take_echo(&mut tty), "");
+    tty.redraw();
+    assert_eq!(take_echo(&mut tty), "");
+
+    assert_eq!(tty.set_mode(Mode::Cooked), Output::None);
+    assert_eq!(take_echo(&mut tty), "\r> \x1b[K");
+    assert_eq!(feed_str(&mut tty, "ok\r"), [Output::Input("ok".into())]);
+    assert_eq!(tty.mode(), Mode::Cooked);
+}
+
+#[test_case]
+fn test_tty_channel() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{Context, Poll, Waker};
+
+    static TTY: Tty<Terminal<String>> = Tty::new(8, Terminal::new("$ ", String::new()));
+    static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut lines = TTY.lines();
+    let mut cx = Context::from_waker(Waker::noop());
+    TTY.set_interrupt_handler(|| {
+        INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    });
+    for key in [Key::Char('l'), Key::Char('s'), Key::Enter, Key::Char('x')] {
+        TTY.feed(key);
+    }
+    assert_eq!(
+        lines.poll_next_unpin(&mut cx),
+        Poll::Ready(Some("ls".into()))
+    );
+    assert_eq!(lines.poll_next_unpin(&mut cx), Poll::Pending);
+    TTY.feed(Key::Interrupt);
+    assert_eq!(INTERRUPTS.load(Ordering::Relaxed), 1);
+    TTY.set_mode(Mode::Raw);
+    TTY.feed(Key::Char('q'));
+    assert_eq!(
+        lines.poll_next_unpin(&mut cx),
+        Poll::Ready(Some("q".into()))
+    );
+    TTY.feed(Key::Interrupt);
+    assert_eq!(INTERRUPTS.load(Ordering::Relaxed), 1);
+    assert_eq!(
+        lines.poll_next_unpin(&mut cx),
+        Poll::Ready(Some("\x03".into()))
+    );
+}
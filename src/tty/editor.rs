@@ -0,0 +1,340 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// 保存的历史行数
+pub const HISTORY_CAPACITY: usize = 16;
+
+/// 行编辑器处理的按键
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    Enter,
+    /// Ctrl-U, 删除光标前的全部字符
+    KillLine,
+    /// Ctrl-W, 删除光标前的一个词
+    KillWord,
+    /// Ctrl-C, 放弃当前行
+    Interrupt,
+}
+
+/// 最近提交的行, 写满后丢弃最早的, 连续重复的行只保存一次
+#[derive(Default)]
+pub struct History {
+    entries: VecDeque<String>,
+}
+
+impl History {
+    pub fn push(&mut self, line: &str) {
+        if line.is_empty() || self.entries.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.entries.len() == HISTORY_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(String::from(line));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 第`index`行, 0是最早的
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+}
+
+/// 单行编辑器, 只维护缓冲区和光标, 由调用者负责显示
+pub struct LineEditor {
+    buffer: Vec<char>,
+    cursor: usize,
+    max_len: usize,
+    history: History,
+    // 正在查看的历史行, None表示正在编辑新行
+    browsing: Option<usize>,
+    // 开始浏览历史时正在编辑的行, 回到最新处时恢复
+    draft: Vec<char>,
+}
+
+impl LineEditor {
+    /// 一行最多`max_len`个字符, 多余的输入被忽略
+    pub const fn new(max_len: usize) -> Self {
+        LineEditor {
+            buffer: Vec::new(),
+            cursor: 0,
+            max_len,
+            history: History {
+                entries: VecDeque::new(),
+            },
+            browsing: None,
+            draft: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    /// 光标前的字符数
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// 处理一个按键, Enter时返回提交的整行并清空缓冲区
+    pub fn handle(&mut self, key: Key) -> Option<String> {
+        match key {
+            Key::Char(c) => {
+                if self.buffer.len() < self.max_len {
+                    self.buffer.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+            }
+            Key::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.buffer.remove(self.cursor);
+                }
+            }
+            Key::Delete => {
+                if self.cursor < self.buffer.len() {
+                    self.buffer.remove(self.cursor);
+                }
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.buffer.len()),
+            Key::Home => self.cursor = 0,
+            Key::KillLine => {
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::KillWord => {
+                // 先跳过光标前的空白, 再删除到上一个空白之后
+                let before = &self.buffer[..self.cursor];
+                let end = before.iter().rposition(|c| *c != ' ').map_or(0, |i| i + 1);
+                let start = before[..end]
+                    .iter()
+                    .rposition(|c| *c == ' ')
+                    .map_or(0, |i| i + 1);
+                self.buffer.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::End => self.cursor = self.buffer.len(),
+            Key::Up => {
+                let index = match self.browsing {
+                    Some(0) => return None,
+                    Some(index) => index - 1,
+                    None if self.history.is_empty() => return None,
+                    None => {
+                        self.draft = core::mem::take(&mut self.buffer);
+                        self.history.len() - 1
+                    }
+                };
+                self.show_history(index);
+            }
+            Key::Down => match self.browsing {
+                Some(index) if index + 1 < self.history.len() => self.show_history(index + 1),
+                Some(_) => {
+                    self.browsing = None;
+                    self.buffer = core::mem::take(&mut self.draft);
+                    self.cursor = self.buffer.len();
+                }
+                None => {}
+            },
+            Key::Enter => {
+                let line = self.line();
+                self.history.push(&line);
+                self.reset();
+                return Some(line);
+            }
+            // 放弃的行不进入历史
+            Key::Interrupt => self.reset(),
+        }
+        None
+    }
+
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.draft.clear();
+        self.cursor = 0;
+        self.browsing = None;
+    }
+
+    fn show_history(&mut self, index: usize) {
+        self.browsing = Some(index);
+        self.buffer = self.history.get(index).unwrap_or("").chars().collect();
+        self.cursor = self.buffer.len();
+    }
+
+    /// 宽度为`width`时显示的部分和光标所在列. 超出宽度时截断显示, 光标在末尾附近时向左滚动
+    pub fn visible(&self, width: usize) -> (String, usize) {
+        let start = self.cursor.saturating_sub(width.saturating_sub(1));
+        let text = self.buffer.iter().skip(start).take(width).collect();
+        (text, self.cursor - start)
+    }
+}
+
+#[cfg(test)]
+fn type_keys(editor: &mut LineEditor, text: &str) {
+    for c in text.chars() {
+        editor.handle(Key::Char(c));
+    }
+}
+
+#[test_case]
+fn test_cursor_editing() {
+    let mut editor = LineEditor::new(64);
+    type_keys(&mut editor, "hexdump 0 16");
+    assert_eq!(editor.cursor(), 12);
+
+    editor.handle(Key::Home);
+    editor.handle(Key::Left);
+    assert_eq!(editor.cursor(), 0);
+    editor.handle(Key::Delete);
+    assert_eq!(editor.line(), "exdump 0 16");
+    type_keys(&mut editor, "h");
+    for _ in 0..7 {
+        editor.handle(Key::Right);
+    }
+    type_keys(&mut editor, "0x1");
+    assert_eq!(editor.line(), "hexdump 0x10 16");
+    assert_eq!(editor.cursor(), 11);
+    editor.handle(Key::Backspace);
+    assert_eq!(editor.line(), "hexdump 0x0 16");
+    assert_eq!(editor.cursor(), 10);
+
+    editor.handle(Key::End);
+    editor.handle(Key::Right);
+    editor.handle(Key::Delete);
+    assert_eq!(editor.cursor(), 14);
+    // 在中间编辑后提交的是整行
+    editor.handle(Key::Left);
+    editor.handle(Key::Left);
+    assert_eq!(editor.handle(Key::Enter).as_deref(), Some("hexdump 0x0 16"));
+    assert_eq!(editor.line(), "");
+    assert_eq!(editor.cursor(), 0);
+}
+
+#[test_case]
+fn test_max_len() {
+    let mut editor = LineEditor::new(4);
+    type_keys(&mut editor, "abcdef");
+    assert_eq!(editor.line(), "abcd");
+    editor.handle(Key::Home);
+    type_keys(&mut editor, "x");
+    assert_eq!(editor.line(), "abcd");
+    assert_eq!(editor.cursor(), 0);
+}
+
+#[test_case]
+fn test_kill_editing() {
+    let mut editor = LineEditor::new(64);
+    type_keys(&mut editor, "echo  one two  ");
+    editor.handle(Key::KillWord);
+    assert_eq!(editor.line(), "echo  one ");
+    editor.handle(Key::KillWord);
+    editor.handle(Key::KillWord);
+    assert_eq!(editor.line(), "");
+    editor.handle(Key::KillWord);
+    assert_eq!(editor.cursor(), 0);
+
+    // 只删除光标前的部分
+    type_keys(&mut editor, "mem stats");
+    for _ in 0..5 {
+        editor.handle(Key::Left);
+    }
+    editor.handle(Key::KillLine);
+    assert_eq!(editor.line(), "stats");
+    assert_eq!(editor.cursor(), 0);
+    editor.handle(Key::End);
+    editor.handle(Key::Interrupt);
+    assert!(editor.is_empty());
+    assert!(editor.history().is_empty());
+}
+
+#[test_case]
+fn test_history_browsing() {
+    let mut editor = LineEditor::new(64);
+    // 空行和连续重复的行不进入历史
+    for line in ["ps", "", "mem", "mem", "ps"] {
+        type_keys(&mut editor, line);
+        editor.handle(Key::Enter);
+    }
+    assert_eq!(editor.history().len(), 3);
+
+    type_keys(&mut editor, "ech");
+    editor.handle(Key::Left);
+    editor.handle(Key::Up);
+    assert_eq!(editor.line(), "ps");
+    assert_eq!(editor.cursor(), 2);
+    editor.handle(Key::Up);
+    editor.handle(Key::Up);
+    editor.handle(Key::Up);
+    assert_eq!(editor.line(), "ps");
+    editor.handle(Key::Down);
+    assert_eq!(editor.line(), "mem");
+    editor.handle(Key::Down);
+    editor.handle(Key::Down);
+    // 回到正在编辑的行
+    assert_eq!(editor.line(), "ech");
+    assert_eq!(editor.cursor(), 3);
+    editor.handle(Key::Down);
+    assert_eq!(editor.line(), "ech");
+
+    // 修改历史行后提交, 原来的历史行不变
+    editor.handle(Key::Up);
+    type_keys(&mut editor, " -a");
+    assert_eq!(editor.handle(Key::Enter).as_deref(), Some("ps -a"));
+    assert_eq!(editor.history().get(2), Some("ps"));
+    assert_eq!(editor.history().get(3), Some("ps -a"));
+}
+
+#[test_case]
+fn test_history_capacity() {
+    let mut editor = LineEditor::new(64);
+    for i in 0..HISTORY_CAPACITY + 4 {
+        type_keys(&mut editor, &alloc::format!("echo {}", i));
+        editor.handle(Key::Enter);
+    }
+    assert_eq!(editor.history().len(), HISTORY_CAPACITY);
+    for _ in 0..HISTORY_CAPACITY + 2 {
+        editor.handle(Key::Up);
+    }
+    assert_eq!(editor.line(), "echo 4");
+}
+
+#[test_case]
+fn test_visible_window() {
+    let mut editor = LineEditor::new(64);
+    type_keys(&mut editor, "0123456789");
+    assert_eq!(editor.visible(20), (String::from("0123456789"), 10));
+    // 光标停在最后一列, 开头被截掉
+    assert_eq!(editor.visible(4), (String::from("789"), 3));
+    editor.handle(Key::Home);
+    assert_eq!(editor.visible(4), (String::from("0123"), 0));
+    editor.handle(Key::Right);
+    editor.handle(Key::Right);
+    editor.handle(Key::Right);
+    editor.handle(Key::Right);
+    assert_eq!(editor.visible(4), (String::from("1234"), 3));
+}
@@ -0,0 +1,204 @@
+//! 把键盘和串口的输入转换为行规程的按键
+
+use alloc::string::String;
+
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::Key;
+
+/// 键盘解码出的按键, 键盘需要把Ctrl加字母映射为控制字符
+pub fn key_from_decoded(key: DecodedKey) -> Option<Key> {
+    match key {
+        DecodedKey::Unicode('\n') => Some(Key::Enter),
+        DecodedKey::Unicode('\u{8}') => Some(Key::Backspace),
+        DecodedKey::Unicode('\u{3}') => Some(Key::Interrupt),
+        DecodedKey::Unicode('\u{15}') => Some(Key::KillLine),
+        DecodedKey::Unicode('\u{17}') => Some(Key::KillWord),
+        DecodedKey::Unicode('\u{7f}') | DecodedKey::RawKey(KeyCode::Delete) => Some(Key::Delete),
+        DecodedKey::Unicode(c) if c == ' ' || c.is_ascii_graphic() => Some(Key::Char(c)),
+        DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(Key::Left),
+        DecodedKey::RawKey(KeyCode::ArrowRight) => Some(Key::Right),
+        DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Key::Up),
+        DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Key::Down),
+        DecodedKey::RawKey(KeyCode::Home) => Some(Key::Home),
+        DecodedKey::RawKey(KeyCode::End) => Some(Key::End),
+        _ => None,
+    }
+}
+
+// 转义序列的解析状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    // 收到ESC
+    Escape,
+    // 收到ESC [或ESC O, 参数是第一个数字, 0表示还没有参数, UNKNOWN表示不认识的参数
+    Sequence(u8),
+}
+
+const UNKNOWN: u8 = 0xFF;
+
+/// 串口终端发送的字节流解码器
+///
+/// 回车可能是CR、LF或CRLF, CR之后紧跟的LF不再产生按键. 认识方向键和Home/End/Delete的
+/// 转义序列, 其他转义序列、控制字符和非ASCII字节被忽略
+pub struct SerialDecoder {
+    state: State,
+    after_cr: bool,
+}
+
+impl SerialDecoder {
+    pub const fn new() -> Self {
+        SerialDecoder {
+            state: State::Ground,
+            after_cr: false,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<Key> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match self.state {
+            State::Ground => {}
+            State::Escape => {
+                self.state = match byte {
+                    b'[' | b'O' => State::Sequence(0),
+                    _ => State::Ground,
+                };
+                return None;
+            }
+            State::Sequence(param) => {
+                // 参数字节之后才是结束字节
+                if (0x30..=0x3F).contains(&byte) {
+                    let first = param == 0 && byte.is_ascii_digit();
+                    self.state = State::Sequence(if first { byte } else { UNKNOWN });
+                    return None;
+                }
+                self.state = State::Ground;
+                return match (param, byte) {
+                    (0, b'A') => Some(Key::Up),
+                    (0, b'B') => Some(Key::Down),
+                    (0, b'C') => Some(Key::Right),
+                    (0, b'D') => Some(Key::Left),
+                    (0, b'H') | (b'1' | b'7', b'~') => Some(Key::Home),
+                    (0, b'F') | (b'4' | b'8', b'~') => Some(Key::End),
+                    (b'3', b'~') => Some(Key::Delete),
+                    _ => None,
+                };
+            }
+        }
+        match byte {
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => Some(Key::Enter),
+            // 退格和DEL
+            8 | 0x7F => Some(Key::Backspace),
+            3 => Some(Key::Interrupt),
+            0x15 => Some(Key::KillLine),
+            0x17 => Some(Key::KillWord),
+            0x1B => {
+                self.state = State::Escape;
+                None
+            }
+            byte if byte == b' ' || byte.is_ascii_graphic() => Some(Key::Char(char::from(byte))),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SerialDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 原始模式下按键送出的内容, 与串口终端发送的字节相同
+pub fn raw_text(key: Key) -> String {
+    let text = match key {
+        Key::Char(c) => return String::from(c),
+        Key::Enter => "\n",
+        Key::Backspace => "\x7f",
+        Key::Delete => "\x1b[3~",
+        Key::Left => "\x1b[D",
+        Key::Right => "\x1b[C",
+        Key::Up => "\x1b[A",
+        Key::Down => "\x1b[B",
+        Key::Home => "\x1b[H",
+        Key::End => "\x1b[F",
+        Key::KillLine => "\x15",
+        Key::KillWord => "\x17",
+        Key::Interrupt => "\x03",
+    };
+    String::from(text)
+}
+
+#[cfg(test)]
+fn decode(decoder: &mut SerialDecoder, bytes: &[u8]) -> alloc::vec::Vec<Key> {
+    bytes
+        .iter()
+        .filter_map(|&byte| decoder.feed(byte))
+        .collect()
+}
+
+#[test_case]
+fn test_serial_decoder() {
+    let mut decoder = SerialDecoder::new();
+    assert_eq!(
+        decode(&mut decoder, b"m\x7f\x08"),
+        [Key::Char('m'), Key::Backspace, Key::Backspace]
+    );
+    // CRLF只产生一次回车, 单独的LF和连续两个CR各自产生
+    assert_eq!(decode(&mut decoder, b"\r\n"), [Key::Enter]);
+    assert_eq!(decode(&mut decoder, b"\n"), [Key::Enter]);
+    assert_eq!(decode(&mut decoder, b"\r\r"), [Key::Enter, Key::Enter]);
+    assert_eq!(
+        decode(&mut decoder, b"\x1b[A\x1bOB\x1b[3~\x1b[1~\x1b[F"),
+        [Key::Up, Key::Down, Key::Delete, Key::Home, Key::End]
+    );
+    // 不认识的转义序列整个被忽略
+    assert_eq!(
+        decode(&mut decoder, b"\x1b[1;5Cx\x1b[200~y\x1bxz"),
+        [Key::Char('x'), Key::Char('y'), Key::Char('z')]
+    );
+    assert_eq!(
+        decode(&mut decoder, b"\x03\x15\x17\x01\xc3\xa9"),
+        [Key::Interrupt, Key::KillLine, Key::KillWord]
+    );
+}
+
+#[test_case]
+fn test_raw_text_round_trip() {
+    let keys = [
+        Key::Char('q'),
+        Key::Enter,
+        Key::Backspace,
+        Key::Delete,
+        Key::Left,
+        Key::Right,
+        Key::Up,
+        Key::Down,
+        Key::Home,
+        Key::End,
+        Key::KillLine,
+        Key::KillWord,
+        Key::Interrupt,
+    ];
+    let mut decoder = SerialDecoder::new();
+    for key in keys {
+        assert_eq!(decode(&mut decoder, raw_text(key).as_bytes()), [key]);
+    }
+}
+
+#[test_case]
+fn test_decoded_keys() {
+    let key = key_from_decoded;
+    assert_eq!(key(DecodedKey::Unicode('a')), Some(Key::Char('a')));
+    assert_eq!(key(DecodedKey::Unicode('\n')), Some(Key::Enter));
+    assert_eq!(key(DecodedKey::Unicode('\u{7f}')), Some(Key::Delete));
+    assert_eq!(key(DecodedKey::RawKey(KeyCode::ArrowUp)), Some(Key::Up));
+    assert_eq!(key(DecodedKey::RawKey(KeyCode::End)), Some(Key::End));
+    assert_eq!(key(DecodedKey::Unicode('\t')), None);
+    assert_eq!(key(DecodedKey::RawKey(KeyCode::F1)), None);
+    // Ctrl加字母映射出的控制字符
+    assert_eq!(key(DecodedKey::Unicode('\u{3}')), Some(Key::Interrupt));
+    assert_eq!(key(DecodedKey::Unicode('\u{15}')), Some(Key::KillLine));
+    assert_eq!(key(DecodedKey::Unicode('\u{17}')), Some(Key::KillWord));
+}
@@ -0,0 +1,250 @@
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::segmentation::GS;
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+use crate::syscall::SyscallFrame;
+use crate::{fault, gdt, println};
+
+global_asm!(include_str!("usermode/switch.s"), options(att_syntax));
+
+extern "C" {
+    fn usermode_call(entry: u64, user_stack: u64, code: u64, data: u64);
+    fn usermode_enter(entry: u64, user_stack: u64, code: u64, data: u64) -> !;
+    fn usermode_resume(frame: *const SyscallFrame, code: u64, data: u64) -> !;
+    fn usermode_return() -> !;
+}
+
+/// 用户代码回到内核的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// 通过exit系统调用正常结束
+    Exit {
+        code: i64,
+    },
+    Breakpoint,
+    /// 触发了异常, 程序被结束
+    Fault(FaultExit),
+}
+
+impl fmt::Display for UserExit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserExit::Exit { code } => write!(f, "exited with code {}", code),
+            UserExit::Breakpoint => write!(f, "stopped at a breakpoint"),
+            UserExit::Fault(fault) => write!(f, "killed: {}", fault),
+        }
+    }
+}
+
+/// 结束用户程序的异常
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultExit {
+    pub vector: u8,
+    /// 页错误访问的地址, 其他异常为None
+    pub addr: Option<VirtAddr>,
+    /// 触发异常的指令
+    pub rip: VirtAddr,
+    /// 没有错误码的异常为0
+    pub error_code: u64,
+}
+
+impl FaultExit {
+    pub fn name(&self) -> &'static str {
+        match self.vector {
+            fault::DIVIDE_ERROR_VECTOR => "divide error",
+            fault::INVALID_OPCODE_VECTOR => "invalid opcode",
+            fault::GENERAL_PROTECTION_VECTOR => "general protection fault",
+            fault::PAGE_FAULT_VECTOR => "page fault",
+            _ => "exception",
+        }
+    }
+
+    /// 页错误的错误码
+    pub fn page_fault_error(&self) -> Option<PageFaultErrorCode> {
+        (self.vector == fault::PAGE_FAULT_VECTOR)
+            .then(|| PageFaultErrorCode::from_bits_truncate(self.error_code))
+    }
+}
+
+impl fmt::Display for FaultExit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(addr) = self.addr {
+            write!(f, " at {:#x}", addr.as_u64())?;
+        }
+        write!(f, ", rip {:#x}", self.rip.as_u64())?;
+        if self.error_code != 0 {
+            write!(f, ", error code {:#x}", self.error_code)?;
+        }
+        Ok(())
+    }
+}
+
+// 是否有`run`保存的内核上下文可以返回
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static EXIT: Mutex<Option<UserExit>> = Mutex::new(None);
+
+/// 切换到ring 3从`entry`开始执行, 不再返回. 用户代码触发异常时停机
+///
+/// # Safety
+///
+/// 调用者需保证`entry`所在页和`user_stack`下方的栈页已映射为用户可访问
+pub unsafe fn enter(entry: VirtAddr, user_stack: VirtAddr) -> ! {
+    let (code, data) = gdt::user_selectors();
+    usermode_enter(
+        entry.as_u64(),
+        user_stack.as_u64(),
+        u64::from(code.0),
+        u64::from(data.0),
+    )
+}
+
+/// 按系统调用入口保存的寄存器回到ring 3, 如同系统调用返回了0. 用于fork出的子进程
+///
+/// RFLAGS中只保留用户代码可以修改的标志, 并总是开中断
+///
+/// # Safety
+///
+/// 调用者需保证`frame`来自同一地址空间中的系统调用
+pub unsafe fn resume(frame: &SyscallFrame) -> ! {
+    // CF、PF、AF、ZF、SF、DF和OF
+    const USER_FLAGS: u64 = 0xcd5;
+    let (code, data) = gdt::user_selectors();
+    let frame = SyscallFrame {
+        rflags: (frame.rflags & USER_FLAGS) | 0x202,
+        ..*frame
+    };
+    usermode_resume(&frame, u64::from(code.0), u64::from(data.0))
+}
+
+/// 同`enter`, 但用户代码触发异常后回到这里, 返回异常的原因
+///
+/// # Safety
+///
+/// 同`enter`. 只能在BSP上调用, 不能嵌套
+pub unsafe fn run(entry: VirtAddr, user_stack: VirtAddr) -> UserExit {
+    assert!(
+        !RUNNING.swap(true, Ordering::SeqCst),
+        "user code is already running"
+    );
+    let interrupts_enabled = interrupts::are_enabled();
+    let (code, data) = gdt::user_selectors();
+    usermode_call(
+        entry.as_u64(),
+        user_stack.as_u64(),
+        u64::from(code.0),
+        u64::from(data.0),
+    );
+    // 从异常处理函数回到这里时中断是关闭的
+    let exit = EXIT
+        .lock()
+        .take()
+        .expect("user code returned without a reason");
+    RUNNING.store(false, Ordering::SeqCst);
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    exit
+}
+
+/// 触发异常的代码所在的特权级
+pub fn privilege_level(stack_frame: &InterruptStackFrame) -> PrivilegeLevel {
+    PrivilegeLevel::from_u16((stack_frame.code_segment & 3) as u16)
+}
+
+/// 中断和异常处理函数开头创建. 来自ring 3时交换GS_BASE和KERNEL_GS_BASE,
+/// 使gs指向per-CPU数据, 返回用户代码前再换回. 处理函数不返回时(如`exit`)保持内核的GS_BASE
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let swapped = privilege_level(stack_frame) == PrivilegeLevel::Ring3;
+        if swapped {
+            unsafe { GS::swap() };
+        }
+        KernelGs { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+/// 由异常处理函数调用. 异常来自ring 3时以`UserExit::Fault`结束用户程序, 否则返回
+pub(crate) fn exit_on_user_fault(
+    stack_frame: &InterruptStackFrame,
+    vector: u8,
+    error_code: u64,
+    addr: Option<VirtAddr>,
+) {
+    if privilege_level(stack_frame) != PrivilegeLevel::Ring3 {
+        return;
+    }
+    exit(UserExit::Fault(FaultExit {
+        vector,
+        addr,
+        rip: stack_frame.instruction_pointer,
+        error_code,
+    }))
+}
+
+/// 由异常处理函数和exit系统调用调用. 当前线程运行的是进程时结束该进程,
+/// 否则结束来自ring 3的代码并回到`run`, 不是通过`run`进入的则停机
+pub(crate) fn exit(reason: UserExit) -> ! {
+    if crate::process::is_current() {
+        crate::process::exit_current(reason);
+    }
+    if !RUNNING.load(Ordering::SeqCst) {
+        println!(
+            "user code exited ({:?}) with no kernel context to return to",
+            reason
+        );
+        crate::hlt_loop();
+    }
+    *EXIT.lock() = Some(reason);
+    unsafe { usermode_return() }
+}
+
+#[test_case]
+fn test_exit_display() {
+    use alloc::string::ToString;
+
+    let fault = FaultExit {
+        vector: fault::PAGE_FAULT_VECTOR,
+        addr: Some(VirtAddr::new(8)),
+        rip: VirtAddr::new(0x7000_0040_1000),
+        error_code: 0x6,
+    };
+    assert_eq!(
+        UserExit::Fault(fault).to_string(),
+        "killed: page fault at 0x8, rip 0x700000401000, error code 0x6"
+    );
+    assert_eq!(
+        fault.page_fault_error(),
+        Some(PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE)
+    );
+    let fault = FaultExit {
+        vector: fault::INVALID_OPCODE_VECTOR,
+        addr: None,
+        error_code: 0,
+        ..fault
+    };
+    assert_eq!(fault.to_string(), "invalid opcode, rip 0x700000401000");
+    assert_eq!(fault.page_fault_error(), None);
+    assert_eq!(
+        UserExit::Exit { code: -1 }.to_string(),
+        "exited with code -1"
+    );
+}
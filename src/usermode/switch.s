@@ -0,0 +1,94 @@
+# 进入和离开ring 3. 保存内核上下文后用iretq切换到用户代码; 用户代码触发异常时,
+# 异常处理函数调用usermode_return恢复保存的上下文, 从usermode_call返回
+
+.pushsection .text
+# rdi = 入口, rsi = 用户栈顶, rdx = 用户代码段, rcx = 用户数据段
+.global usermode_call
+usermode_call:
+    push %rbx
+    push %rbp
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, usermode_kernel_rsp(%rip)
+    # 不返回, 继续执行usermode_enter
+
+# 参数同usermode_call
+.global usermode_enter
+usermode_enter:
+    push %rcx
+    push %rsi
+    # RFLAGS: IF, 第1位保留为1
+    push $0x202
+    push %rdx
+    push %rdi
+    # 清空通用寄存器, 不把内核数据留给用户代码
+    xor %eax, %eax
+    xor %ebx, %ebx
+    xor %ecx, %ecx
+    xor %edx, %edx
+    xor %esi, %esi
+    xor %edi, %edi
+    xor %ebp, %ebp
+    xor %r8d, %r8d
+    xor %r9d, %r9d
+    xor %r10d, %r10d
+    xor %r11d, %r11d
+    xor %r12d, %r12d
+    xor %r13d, %r13d
+    xor %r14d, %r14d
+    xor %r15d, %r15d
+    # 把per-CPU数据指针换到KERNEL_GS_BASE, 用户代码使用自己的GS_BASE.
+    # 交换后到iretq之前不能被中断, iretq从栈上恢复IF
+    cli
+    swapgs
+    iretq
+
+# rdi = SyscallFrame, rsi = 用户代码段, rdx = 用户数据段.
+# 恢复保存的用户寄存器后从系统调用返回, rax为0
+.global usermode_resume
+usermode_resume:
+    push %rdx
+    push 0x88(%rdi)
+    push 0x80(%rdi)
+    push %rsi
+    push 0x70(%rdi)
+    mov 0x00(%rdi), %r15
+    mov 0x08(%rdi), %r14
+    mov 0x10(%rdi), %r13
+    mov 0x18(%rdi), %r12
+    mov 0x20(%rdi), %rbp
+    mov 0x28(%rdi), %rbx
+    mov 0x30(%rdi), %r11
+    mov 0x38(%rdi), %r10
+    mov 0x40(%rdi), %r9
+    mov 0x48(%rdi), %r8
+    mov 0x58(%rdi), %rsi
+    mov 0x60(%rdi), %rdx
+    mov 0x68(%rdi), %rcx
+    mov 0x50(%rdi), %rdi
+    xor %eax, %eax
+    # 与usermode_enter相同, 换回用户的GS_BASE后直到iretq都不能被中断
+    cli
+    swapgs
+    iretq
+
+# 切回usermode_call保存的内核栈, 从usermode_call返回
+.global usermode_return
+usermode_return:
+    mov usermode_kernel_rsp(%rip), %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbp
+    pop %rbx
+    ret
+.popsection
+
+.pushsection .bss
+.balign 8
+usermode_kernel_rsp:
+    .quad 0
+.popsection
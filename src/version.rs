@@ -0,0 +1,231 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::bootinfo::{self, MemoryTotals};
+use crate::vga_buffer::Role;
+use crate::{allocator, cpu, println_role};
+
+/// 横幅每行的最大宽度, 与文本模式的列数相同
+pub const BANNER_WIDTH: usize = 80;
+
+/// 构建时确定的内核版本信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    /// 构建时的git提交, 不在git仓库中构建时为`unknown`
+    pub git_hash: &'static str,
+    pub build_date: &'static str,
+    pub allocator: &'static str,
+    pub console: &'static str,
+}
+
+static INFO: VersionInfo = VersionInfo {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+    // 由build.rs设置
+    git_hash: match option_env!("TOY_OS_GIT_HASH") {
+        Some(hash) => hash,
+        None => "unknown",
+    },
+    build_date: match option_env!("TOY_OS_BUILD_DATE") {
+        Some(date) => date,
+        None => "unknown",
+    },
+    allocator: allocator::allocator_name(),
+    console: if cfg!(feature = "vga_320x200") {
+        "framebuffer 320x200"
+    } else {
+        "vga text 80x25"
+    },
+};
+
+// 所有cargo feature及是否启用
+const FEATURES: [(&str, bool); 5] = [
+    ("initrd", cfg!(feature = "initrd")),
+    ("beep", cfg!(feature = "beep")),
+    ("vga_320x200", cfg!(feature = "vga_320x200")),
+    ("bump_allocator", cfg!(feature = "bump_allocator")),
+    (
+        "linked_list_allocator",
+        cfg!(feature = "linked_list_allocator"),
+    ),
+];
+
+pub fn info() -> &'static VersionInfo {
+    &INFO
+}
+
+/// 构建时间, 从1970-01-01 00:00 UTC起的秒数, 由build.rs设置, 不知道时为0
+pub fn build_epoch() -> u64 {
+    option_env!("TOY_OS_BUILD_EPOCH")
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or(0)
+}
+
+/// 构建时启用的cargo feature
+pub fn features() -> impl Iterator<Item = &'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+}
+
+/// 横幅的内容, 与运行环境分开以便测试
+pub struct Banner<'a> {
+    pub info: &'a VersionInfo,
+    pub cpu: Option<&'a str>,
+    pub memory: MemoryTotals,
+    pub heap_size: usize,
+    pub features: &'a [&'a str],
+}
+
+/// 生成横幅的各行及其颜色. 行数固定, 远少于25行; 过长的行截断到`BANNER_WIDTH`并以`...`结尾
+pub fn render_banner(banner: &Banner) -> Vec<(Role, String)> {
+    let info = banner.info;
+    let features = if banner.features.is_empty() {
+        String::from("none")
+    } else {
+        banner.features.join(", ")
+    };
+    let mut lines = Vec::new();
+    let mut line = |role: Role, args: core::fmt::Arguments| {
+        let mut text = String::new();
+        let _ = text.write_fmt(args);
+        lines.push((role, truncate(text, BANNER_WIDTH)));
+    };
+    line(
+        Role::Status,
+        format_args!("==== {} {} ====", info.name, info.version),
+    );
+    line(
+        Role::Info,
+        format_args!("  commit   {}, built {}", info.git_hash, info.build_date),
+    );
+    line(
+        Role::Normal,
+        format_args!("  cpu      {}", banner.cpu.unwrap_or("unknown")),
+    );
+    line(
+        Role::Normal,
+        format_args!(
+            "  memory   {} MiB usable of {} MiB in {} regions",
+            banner.memory.usable >> 20,
+            banner.memory.total >> 20,
+            banner.memory.regions
+        ),
+    );
+    line(
+        Role::Normal,
+        format_args!(
+            "  heap     {} KiB, {} allocator",
+            banner.heap_size / 1024,
+            info.allocator
+        ),
+    );
+    line(
+        Role::Normal,
+        format_args!("  console  {}", info.console),
+    );
+    line(Role::Warn, format_args!("  features {}", features));
+    lines
+}
+
+// 按字符截断, 不会切开多字节字符
+fn truncate(mut text: String, width: usize) -> String {
+    const ELLIPSIS: &str = "...";
+    if text.chars().count() > width {
+        let end = text
+            .char_indices()
+            .nth(width - ELLIPSIS.len())
+            .map_or(text.len(), |(i, _)| i);
+        text.truncate(end);
+        text.push_str(ELLIPSIS);
+    }
+    text
+}
+
+/// 按当前主题的颜色打印启动横幅
+pub fn print_banner() {
+    let features: Vec<&str> = features().collect();
+    let banner = Banner {
+        info: info(),
+        cpu: cpu::brand_string(),
+        memory: bootinfo::memory_totals(),
+        heap_size: allocator::HEAP_SIZE,
+        features: &features,
+    };
+    for (role, line) in render_banner(&banner) {
+        println_role!(role, "{}", line);
+    }
+}
+
+#[test_case]
+fn test_version_info() {
+    let info = info();
+    for field in [
+        info.name,
+        info.version,
+        info.git_hash,
+        info.build_date,
+        info.allocator,
+        info.console,
+    ] {
+        assert!(!field.is_empty(), "{:?}", info);
+    }
+    assert_eq!(info.name, "toy_os");
+}
+
+#[test_case]
+fn test_banner_width() {
+    const LONG: &str = "Intel(R) Xeon(R) Platinum 8480+ CPU @ 2.00GHz with a very long \
+        vendor suffix that does not fit on one line";
+    let long_info = VersionInfo {
+        name: LONG,
+        version: LONG,
+        git_hash: LONG,
+        build_date: LONG,
+        allocator: LONG,
+        console: LONG,
+    };
+    let features = [LONG; 8];
+    let memory = MemoryTotals {
+        regions: usize::MAX,
+        total: u64::MAX,
+        usable: u64::MAX,
+        in_use: 0,
+        reserved: 0,
+    };
+    // 多字节字符
+    let symbols = "\u{00ae}\u{2122}".repeat(100);
+    for cpu in [Some(LONG), Some(symbols.as_str()), None] {
+        let banner = Banner {
+            info: &long_info,
+            cpu,
+            memory,
+            heap_size: usize::MAX,
+            features: &features,
+        };
+        let lines = render_banner(&banner);
+        assert!(lines.len() <= 25);
+        for (_, line) in &lines {
+            assert!(line.chars().count() <= BANNER_WIDTH, "{}", line);
+        }
+        assert!(lines[2]
+            .1
+            .ends_with(if cpu.is_some() { "..." } else { "unknown" }));
+    }
+
+    // 不需要截断的行保持原样
+    let banner = Banner {
+        info: info(),
+        cpu: Some("QEMU Virtual CPU"),
+        memory,
+        heap_size: 100 * 1024,
+        features: &[],
+    };
+    let lines = render_banner(&banner);
+    assert_eq!(lines[2].1, "  cpu      QEMU Virtual CPU");
+    assert_eq!(lines[6].1, "  features none");
+}
@@ -0,0 +1,547 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::{self, Future};
+use core::pin::Pin;
+
+use spin::Mutex;
+
+use crate::error::KernelError;
+use crate::fat::{self, FatFs};
+use crate::ramfs::{self, Ramfs};
+use crate::shell::{self, CmdError, CommandFuture};
+
+pub mod devfs;
+pub mod disk;
+pub mod path;
+pub mod procfs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// 文件系统或设备不支持写入
+    ReadOnly,
+    InvalidPath,
+    /// 新位置为负数或溢出
+    InvalidSeek,
+    AlreadyMounted,
+    AlreadyExists,
+    /// 设备上没有空间
+    NoSpace,
+    /// 文件系统内部的错误, 如块设备读取失败或数据损坏
+    Io,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Device,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// 文件的字节数, 目录和设备为0
+    pub len: usize,
+}
+
+impl Metadata {
+    fn new(is_dir: bool, len: usize) -> Metadata {
+        let file_type = if is_dir {
+            FileType::Directory
+        } else {
+            FileType::File
+        };
+        Metadata { file_type, len }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// 文件系统的异步操作. 磁盘上的文件系统等待设备时, 执行器继续运行其他任务
+pub type VfsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, KernelError>> + Send + 'a>>;
+
+/// 已经有结果的操作, 供内存中的文件系统使用
+pub fn ready<'a, T: Send + 'a>(result: Result<T, KernelError>) -> VfsFuture<'a, T> {
+    Box::pin(future::ready(result))
+}
+
+/// 文件系统中打开的文件、目录或设备
+pub trait Node: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// 从`offset`开始读取到`buf`, 返回读取的字节数, 到达末尾时返回0
+    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> VfsFuture<'a, usize>;
+
+    /// 从`offset`开始写入`buf`, 返回写入的字节数
+    fn write_at<'a>(&'a self, _offset: usize, _buf: &'a [u8]) -> VfsFuture<'a, usize> {
+        ready(Err(VfsError::ReadOnly.into()))
+    }
+
+    fn readdir(&self) -> VfsFuture<'_, Vec<DirEntry>> {
+        ready(Err(VfsError::NotADirectory.into()))
+    }
+}
+
+/// 可以挂载的文件系统, `path`是相对于文件系统根目录的规范化路径, 以"/"开头
+///
+/// 错误以`KernelError`返回, 文件系统自己的错误作为上下文, 不在这里丢掉原因
+pub trait FileSystem: Sync {
+    fn open<'a>(&'static self, path: &'a str) -> VfsFuture<'a, Box<dyn Node>>;
+
+    fn metadata<'a>(&'static self, path: &'a str) -> VfsFuture<'a, Metadata> {
+        Box::pin(async move { Ok(self.open(path).await?.metadata()) })
+    }
+
+    fn readdir<'a>(&'static self, path: &'a str) -> VfsFuture<'a, Vec<DirEntry>> {
+        Box::pin(async move { self.open(path).await?.readdir().await })
+    }
+
+    /// 在已有的目录中创建空文件并打开
+    fn create<'a>(&'static self, _path: &'a str) -> VfsFuture<'a, Box<dyn Node>> {
+        ready(Err(VfsError::ReadOnly.into()))
+    }
+
+    /// 删除文件
+    fn remove<'a>(&'static self, _path: &'a str) -> VfsFuture<'a, ()> {
+        ready(Err(VfsError::ReadOnly.into()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+/// 打开的文件, 每个句柄有自己的读写位置
+pub struct File {
+    node: Box<dyn Node>,
+    offset: usize,
+}
+
+impl File {
+    pub fn metadata(&self) -> Metadata {
+        self.node.metadata()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// 从当前位置读取并前移, 到达末尾时返回0
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, KernelError> {
+        if self.metadata().is_dir() {
+            return Err(VfsError::IsADirectory.into());
+        }
+        let len = self.node.read_at(self.offset, buf).await?;
+        self.offset += len;
+        Ok(len)
+    }
+
+    /// 在当前位置写入并前移
+    pub async fn write(&mut self, buf: &[u8]) -> Result<usize, KernelError> {
+        if self.metadata().is_dir() {
+            return Err(VfsError::IsADirectory.into());
+        }
+        let len = self.node.write_at(self.offset, buf).await?;
+        self.offset += len;
+        Ok(len)
+    }
+
+    /// 移动读写位置并返回新位置, 可以移到文件末尾之后
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<usize, KernelError> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.offset, delta),
+            SeekFrom::End(delta) => (self.metadata().len, delta),
+        };
+        self.offset = base
+            .checked_add_signed(delta)
+            .ok_or(VfsError::InvalidSeek)?;
+        Ok(self.offset)
+    }
+
+    pub async fn readdir(&self) -> Result<Vec<DirEntry>, KernelError> {
+        self.node.readdir().await
+    }
+}
+
+struct Mount {
+    path: String,
+    fs: &'static dyn FileSystem,
+}
+
+/// 挂载表, 路径由挂载点最深的文件系统处理
+pub struct Vfs {
+    mounts: Mutex<Vec<Mount>>,
+}
+
+impl Vfs {
+    pub const fn new() -> Vfs {
+        Vfs {
+            mounts: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 把`fs`挂载到`path`, 挂载点不必在上一级文件系统中存在
+    pub fn mount(&self, path: &str, fs: &'static dyn FileSystem) -> Result<(), KernelError> {
+        let path = path::normalize(path)?;
+        let mut mounts = self.mounts.lock();
+        if mounts.iter().any(|mount| mount.path == path) {
+            return Err(VfsError::AlreadyMounted.into());
+        }
+        mounts.push(Mount { path, fs });
+        Ok(())
+    }
+
+    pub async fn open(&self, path: &str) -> Result<File, KernelError> {
+        let path = path::normalize(path)?;
+        let (fs, rest) = self.resolve(&path)?;
+        Ok(File {
+            node: fs.open(rest).await?,
+            offset: 0,
+        })
+    }
+
+    /// 创建文件, 已经存在时返回`AlreadyExists`
+    pub async fn create(&self, path: &str) -> Result<File, KernelError> {
+        let path = path::normalize(path)?;
+        let (fs, rest) = self.resolve(&path)?;
+        Ok(File {
+            node: fs.create(rest).await?,
+            offset: 0,
+        })
+    }
+
+    pub async fn remove(&self, path: &str) -> Result<(), KernelError> {
+        let path = path::normalize(path)?;
+        let (fs, rest) = self.resolve(&path)?;
+        fs.remove(rest).await
+    }
+
+    pub async fn metadata(&self, path: &str) -> Result<Metadata, KernelError> {
+        let path = path::normalize(path)?;
+        let (fs, rest) = self.resolve(&path)?;
+        fs.metadata(rest).await
+    }
+
+    /// 目录中的各项, 直接位于其中的挂载点显示为目录
+    pub async fn readdir(&self, path: &str) -> Result<Vec<DirEntry>, KernelError> {
+        let path = path::normalize(path)?;
+        let (fs, rest) = self.resolve(&path)?;
+        let mut entries = fs.readdir(rest).await?;
+        for mount in self.mounts.lock().iter() {
+            match path::split_last(&mount.path) {
+                Some((parent, name)) if parent == path => {
+                    entries.retain(|entry| entry.name != name);
+                    entries.push(DirEntry {
+                        name: name.into(),
+                        metadata: Metadata::new(true, 0),
+                    });
+                }
+                _ => {}
+            }
+        }
+        Ok(entries)
+    }
+
+    // 找到挂载点最深的文件系统, 返回它和剩余的路径
+    fn resolve<'p>(&self, path: &'p str) -> Result<(&'static dyn FileSystem, &'p str), VfsError> {
+        self.mounts
+            .lock()
+            .iter()
+            .filter_map(|mount| {
+                path::strip_prefix(path, &mount.path).map(|rest| (mount.path.len(), mount.fs, rest))
+            })
+            .max_by_key(|(depth, _, _)| *depth)
+            .map(|(_, fs, rest)| (fs, rest))
+            .ok_or(VfsError::NotFound)
+    }
+}
+
+impl Default for Vfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Node for ramfs::File<'static> {
+    fn metadata(&self) -> Metadata {
+        Metadata::new(self.is_dir(), self.len())
+    }
+
+    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> VfsFuture<'a, usize> {
+        ready(Ok(ramfs::File::read_at(self, offset, buf)))
+    }
+
+    fn readdir(&self) -> VfsFuture<'_, Vec<DirEntry>> {
+        if !self.is_dir() {
+            return ready(Err(VfsError::NotADirectory.into()));
+        }
+        ready(Ok(self
+            .entries()
+            .map(|entry| DirEntry {
+                name: entry.name.into(),
+                metadata: Metadata::new(entry.is_dir, entry.len),
+            })
+            .collect()))
+    }
+}
+
+impl FileSystem for Ramfs<'static> {
+    fn open<'a>(&'static self, path: &'a str) -> VfsFuture<'a, Box<dyn Node>> {
+        let file = Ramfs::open(self, path).ok_or(VfsError::NotFound.into());
+        ready(file.map(|file| Box::new(file) as Box<dyn Node>))
+    }
+}
+
+impl Node for fat::File<'static> {
+    fn metadata(&self) -> Metadata {
+        Metadata::new(self.is_dir(), self.len())
+    }
+
+    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> VfsFuture<'a, usize> {
+        Box::pin(async move { Ok(fat::File::read_at(self, offset, buf).await?) })
+    }
+
+    fn write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> VfsFuture<'a, usize> {
+        Box::pin(async move { Ok(fat::File::write_at(self, offset, buf).await?) })
+    }
+
+    fn readdir(&self) -> VfsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            if !self.is_dir() {
+                return Err(VfsError::NotADirectory.into());
+            }
+            Ok(self
+                .entries()
+                .await?
+                .into_iter()
+                .map(|entry| DirEntry {
+                    metadata: Metadata::new(entry.is_dir, entry.len),
+                    name: entry.name,
+                })
+                .collect())
+        })
+    }
+}
+
+impl FileSystem for FatFs<'static> {
+    fn open<'a>(&'static self, path: &'a str) -> VfsFuture<'a, Box<dyn Node>> {
+        Box::pin(async move { Ok(Box::new(FatFs::open(self, path).await?) as Box<dyn Node>) })
+    }
+
+    fn create<'a>(&'static self, path: &'a str) -> VfsFuture<'a, Box<dyn Node>> {
+        Box::pin(async move { Ok(Box::new(FatFs::create(self, path).await?) as Box<dyn Node>) })
+    }
+
+    fn remove<'a>(&'static self, path: &'a str) -> VfsFuture<'a, ()> {
+        Box::pin(async move { Ok(FatFs::remove(self, path).await?) })
+    }
+}
+
+static VFS: Vfs = Vfs::new();
+
+/// 把initrd挂载到"/", 设备文件挂载到"/dev", 内核状态挂载到"/proc", 需要在`ramfs::init`之后调用
+pub fn init() -> Result<(), KernelError> {
+    if let Some(ramfs) = ramfs::get() {
+        VFS.mount("/", ramfs)?;
+    }
+    VFS.mount("/dev", &devfs::DevFs)?;
+    VFS.mount("/proc", &procfs::ProcFs)
+}
+
+/// 注册`cat`命令
+pub fn register_commands() {
+    shell::register_async_command("cat", "cat <path>...: print files", cat_command)
+        .expect("duplicate vfs command");
+}
+
+// 不是有效UTF-8的字节显示为替换字符
+fn cat_command<'a>(args: &'a [&'a str], out: &'a mut (dyn fmt::Write + Send)) -> CommandFuture<'a> {
+    Box::pin(async move {
+        if args.is_empty() {
+            return Err(CmdError::Usage("cat <path>..."));
+        }
+        let mut buf = [0u8; 256];
+        for path in args {
+            let mut file = open(path).await?;
+            loop {
+                match file.read(&mut buf).await? {
+                    0 => break,
+                    len => write!(out, "{}", String::from_utf8_lossy(&buf[..len]))?,
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+pub fn mount(path: &str, fs: &'static dyn FileSystem) -> Result<(), KernelError> {
+    VFS.mount(path, fs)
+}
+
+pub async fn open(path: &str) -> Result<File, KernelError> {
+    VFS.open(path).await
+}
+
+pub async fn create(path: &str) -> Result<File, KernelError> {
+    VFS.create(path).await
+}
+
+pub async fn remove(path: &str) -> Result<(), KernelError> {
+    VFS.remove(path).await
+}
+
+pub async fn metadata(path: &str) -> Result<Metadata, KernelError> {
+    VFS.metadata(path).await
+}
+
+pub async fn readdir(path: &str) -> Result<Vec<DirEntry>, KernelError> {
+    VFS.readdir(path).await
+}
+
+#[cfg(test)]
+fn fixture_vfs() -> Vfs {
+    let ramfs = Box::leak(Box::new(Ramfs::parse(ramfs::FIXTURE).unwrap()));
+    let vfs = Vfs::new();
+    vfs.mount("/", ramfs).unwrap();
+    vfs.mount("/dev", &devfs::DevFs).unwrap();
+    vfs
+}
+
+#[cfg(test)]
+fn names(entries: &[DirEntry]) -> Vec<&str> {
+    entries.iter().map(|entry| entry.name.as_str()).collect()
+}
+
+#[test_case]
+fn test_mount_table() {
+    crate::task::complete_now(async {
+        let vfs = fixture_vfs();
+        let mut buf = [0u8; 32];
+        for path in [
+            "/docs/readme.txt",
+            "/dev/../docs//readme.txt",
+            "docs/./readme.txt",
+        ] {
+            let mut file = vfs.open(path).await.unwrap();
+            assert_eq!(file.read(&mut buf).await, Ok(14), "{}", path);
+            assert_eq!(&buf[..14], b"ramfs fixture\n");
+        }
+        assert_eq!(
+            names(&vfs.readdir("/").await.unwrap()),
+            ["docs", "empty", "data", "long", "dev"]
+        );
+        assert_eq!(
+            names(&vfs.readdir("/dev/").await.unwrap()),
+            ["console", "null"]
+        );
+        assert_eq!(
+            vfs.metadata("/dev/null")
+                .await
+                .map(|metadata| metadata.file_type),
+            Ok(FileType::Device)
+        );
+        assert_eq!(
+            vfs.metadata("/data/big.bin")
+                .await
+                .map(|metadata| metadata.len),
+            Ok(1300)
+        );
+        assert_eq!(
+            vfs.open("/missing").await.err(),
+            Some(VfsError::NotFound.into())
+        );
+        assert_eq!(
+            vfs.open("/dev/missing").await.err(),
+            Some(VfsError::NotFound.into())
+        );
+        assert_eq!(
+            vfs.readdir("/empty").await.err(),
+            Some(VfsError::NotADirectory.into())
+        );
+        assert_eq!(
+            vfs.mount("//dev/", &devfs::DevFs),
+            Err(VfsError::AlreadyMounted.into())
+        );
+
+        // 挂载点最深的文件系统优先, 第二份fixture的根目录没有readme.txt
+        let overlay = Box::leak(Box::new(Ramfs::parse(ramfs::FIXTURE).unwrap()));
+        vfs.mount("/docs", overlay).unwrap();
+        assert_eq!(
+            vfs.open("/docs/readme.txt").await.err(),
+            Some(VfsError::NotFound.into())
+        );
+        assert!(vfs.open("/docs/docs/readme.txt").await.is_ok());
+        assert_eq!(
+            names(&vfs.readdir("/").await.unwrap()),
+            ["empty", "data", "long", "dev", "docs"]
+        );
+        assert!(Vfs::new().open("/").await.is_err());
+    });
+}
+
+#[test_case]
+fn test_file_handle() {
+    crate::task::complete_now(async {
+        let vfs = fixture_vfs();
+        let mut file = vfs.open("/data/big.bin").await.unwrap();
+        let mut buf = [0u8; 512];
+        assert_eq!(file.read(&mut buf).await, Ok(512));
+        assert_eq!(file.read(&mut buf).await, Ok(512));
+        assert_eq!(buf[0], (512 % 251) as u8);
+        assert_eq!(file.read(&mut buf).await, Ok(276));
+        assert_eq!(file.read(&mut buf).await, Ok(0));
+        assert_eq!(file.offset(), 1300);
+
+        assert_eq!(file.seek(SeekFrom::Start(10)), Ok(10));
+        assert_eq!(file.seek(SeekFrom::Current(-5)), Ok(5));
+        assert_eq!(file.read(&mut buf[..1]).await, Ok(1));
+        assert_eq!(buf[0], 5);
+        assert_eq!(file.seek(SeekFrom::End(-1)), Ok(1299));
+        assert_eq!(file.read(&mut buf).await, Ok(1));
+        assert_eq!(file.seek(SeekFrom::End(100)), Ok(1400));
+        assert_eq!(file.read(&mut buf).await, Ok(0));
+        assert_eq!(
+            file.seek(SeekFrom::Current(-1401)),
+            Err(VfsError::InvalidSeek.into())
+        );
+        assert_eq!(file.offset(), 1400);
+
+        // 两个句柄的位置互不影响
+        let mut other = vfs.open("/data/big.bin").await.unwrap();
+        assert_eq!(other.read(&mut buf[..1]).await, Ok(1));
+        assert_eq!(buf[0], 0);
+
+        assert_eq!(file.write(b"x").await, Err(VfsError::ReadOnly.into()));
+        assert_eq!(
+            vfs.create("/new.txt").await.err(),
+            Some(VfsError::ReadOnly.into())
+        );
+        assert_eq!(
+            vfs.remove("/data/big.bin").await,
+            Err(VfsError::ReadOnly.into())
+        );
+        let mut dir = vfs.open("/docs").await.unwrap();
+        assert_eq!(dir.read(&mut buf).await, Err(VfsError::IsADirectory.into()));
+        assert_eq!(
+            names(&dir.readdir().await.unwrap()),
+            ["readme.txt", "nested"]
+        );
+    });
+}
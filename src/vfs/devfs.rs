@@ -0,0 +1,101 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{ready, DirEntry, FileSystem, FileType, Metadata, Node, VfsError, VfsFuture};
+#[cfg(test)]
+use crate::task;
+
+/// 设备文件系统, 通常挂载到"/dev"
+pub struct DevFs;
+
+const DEVICES: [&str; 2] = ["console", "null"];
+
+const DEVICE: Metadata = Metadata {
+    file_type: FileType::Device,
+    len: 0,
+};
+
+struct Root;
+
+/// 写入的内容经过`print!`输出, 读取时没有数据
+struct Console;
+
+/// 丢弃写入的内容, 读取时总是位于末尾
+struct Null;
+
+impl Node for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata::new(true, 0)
+    }
+
+    fn read_at<'a>(&'a self, _offset: usize, _buf: &'a mut [u8]) -> VfsFuture<'a, usize> {
+        ready(Ok(0))
+    }
+
+    fn readdir(&self) -> VfsFuture<'_, Vec<DirEntry>> {
+        ready(Ok(DEVICES
+            .iter()
+            .map(|&name| DirEntry {
+                name: name.into(),
+                metadata: DEVICE,
+            })
+            .collect()))
+    }
+}
+
+impl Node for Console {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn read_at<'a>(&'a self, _offset: usize, _buf: &'a mut [u8]) -> VfsFuture<'a, usize> {
+        ready(Ok(0))
+    }
+
+    // 不是有效UTF-8的字节显示为替换字符
+    fn write_at<'a>(&'a self, _offset: usize, buf: &'a [u8]) -> VfsFuture<'a, usize> {
+        crate::print!("{}", String::from_utf8_lossy(buf));
+        ready(Ok(buf.len()))
+    }
+}
+
+impl Node for Null {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn read_at<'a>(&'a self, _offset: usize, _buf: &'a mut [u8]) -> VfsFuture<'a, usize> {
+        ready(Ok(0))
+    }
+
+    fn write_at<'a>(&'a self, _offset: usize, buf: &'a [u8]) -> VfsFuture<'a, usize> {
+        ready(Ok(buf.len()))
+    }
+}
+
+impl FileSystem for DevFs {
+    fn open<'a>(&'static self, path: &'a str) -> VfsFuture<'a, Box<dyn Node>> {
+        ready(match path {
+            "/" => Ok(Box::new(Root)),
+            "/console" => Ok(Box::new(Console)),
+            "/null" => Ok(Box::new(Null)),
+            _ => Err(VfsError::NotFound.into()),
+        })
+    }
+}
+
+#[test_case]
+fn test_devfs() {
+    task::complete_now(async {
+        let null = DevFs.open("/null").await.unwrap();
+        assert_eq!(null.write_at(0, &[0; 100]).await, Ok(100));
+        assert_eq!(null.read_at(0, &mut [0; 16]).await, Ok(0));
+        let console = DevFs.open("/console").await.unwrap();
+        assert_eq!(console.write_at(0, b"devfs console test\n").await, Ok(19));
+        assert_eq!(console.metadata().file_type, FileType::Device);
+        let root = DevFs.open("/").await.unwrap();
+        assert_eq!(root.readdir().await.unwrap().len(), DEVICES.len());
+        assert!(DevFs.open("/tty").await.is_err());
+    });
+}
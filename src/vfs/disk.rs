@@ -0,0 +1,56 @@
+//! 把主通道从盘上的FAT卷挂载到"/disk", 每次启动在`/disk/boot.log`末尾追加一行
+//!
+//! 卷的起始扇区由命令行的`disk_start`指定, 默认为0; 测试盘上的FAT16卷从第2048扇区开始.
+//! ATA只能轮询, 读写交给"disk"线程执行, 等待磁盘的任务不占用执行器
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec;
+
+use super::{SeekFrom, VfsError};
+use crate::ata::{self, Position};
+use crate::block::{BlockDevice, IoThread, Partition};
+use crate::error::KernelError;
+use crate::fat::FatFs;
+use crate::{cmdline, version};
+
+pub const MOUNT_POINT: &str = "/disk";
+pub const BOOT_LOG: &str = "/disk/boot.log";
+
+/// 挂载FAT卷, 没有从盘时返回`NotFound`. 文件系统在整个运行期间有效
+pub async fn mount() -> Result<(), KernelError> {
+    let drive = ata::drive(Position::Slave).ok_or(VfsError::NotFound)?;
+    let drive: &'static ata::Drive = Box::leak(Box::new(drive));
+    let start = cmdline::get_u64("disk_start").unwrap_or(0);
+    let sectors = BlockDevice::sector_count(drive).saturating_sub(start);
+    let partition = Partition::new(drive, start, sectors)?;
+    let io = IoThread::spawn("disk", Box::leak(Box::new(partition)))?;
+    let fs = Box::leak(Box::new(FatFs::mount(io).await?));
+    super::mount(MOUNT_POINT, fs)
+}
+
+/// 在`BOOT_LOG`末尾追加本次启动的序号和内核版本, 文件不存在时创建. 返回启动序号
+pub async fn append_boot_log() -> Result<usize, KernelError> {
+    let mut file = match super::open(BOOT_LOG).await {
+        Err(KernelError::NotFound(_)) => super::create(BOOT_LOG).await?,
+        file => file?,
+    };
+    // 每次启动一行
+    let mut data = vec![0; file.metadata().len];
+    let mut read = 0;
+    while read < data.len() {
+        match file.read(&mut data[read..]).await? {
+            0 => break,
+            len => read += len,
+        }
+    }
+    let boot = data[..read].iter().filter(|&&b| b == b'\n').count() + 1;
+    let info = version::info();
+    let line = format!(
+        "boot {}: {} {} ({}) built {}\n",
+        boot, info.name, info.version, info.git_hash, info.build_date
+    );
+    file.seek(SeekFrom::End(0))?;
+    file.write(line.as_bytes()).await?;
+    Ok(boot)
+}
@@ -0,0 +1,92 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::VfsError;
+
+/// 把路径规范化为以"/"开头的绝对路径: 合并重复的"/", 去掉"."和末尾的"/",
+/// ".."回到上一级, 在根目录时停在根目录. 空路径无效, 不以"/"开头的路径视为相对于根目录
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    if path.is_empty() {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for part in &parts {
+        normalized.push('/');
+        normalized.push_str(part);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// 规范化后的`path`位于`prefix`之下时, 返回相对`prefix`的部分, 仍以"/"开头
+pub fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if prefix == "/" {
+        return Some(path);
+    }
+    match path.strip_prefix(prefix)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// 规范化路径的上一级目录和最后一个部分, 根目录没有上一级
+pub fn split_last(path: &str) -> Option<(&str, &str)> {
+    let index = path.rfind('/')?;
+    let name = &path[index + 1..];
+    if name.is_empty() {
+        return None;
+    }
+    Some((if index == 0 { "/" } else { &path[..index] }, name))
+}
+
+#[test_case]
+fn test_normalize() {
+    let cases = [
+        ("/", "/"),
+        ("//", "/"),
+        (".", "/"),
+        ("/a/b/c", "/a/b/c"),
+        ("a/b", "/a/b"),
+        ("/a//b///c/", "/a/b/c"),
+        ("/a/./b/.", "/a/b"),
+        ("/a/b/..", "/a"),
+        ("/a/../b", "/b"),
+        ("/a/../../b", "/b"),
+        ("/../..", "/"),
+        ("../a", "/a"),
+        ("/a/b/../../..", "/"),
+        ("/a/.../b", "/a/.../b"),
+        ("/a/..b/c", "/a/..b/c"),
+    ];
+    for (path, expected) in cases {
+        assert_eq!(normalize(path).as_deref(), Ok(expected), "{:?}", path);
+    }
+    assert_eq!(normalize(""), Err(VfsError::InvalidPath));
+}
+
+#[test_case]
+fn test_strip_prefix() {
+    assert_eq!(strip_prefix("/disk/a", "/"), Some("/disk/a"));
+    assert_eq!(strip_prefix("/disk/a", "/disk"), Some("/a"));
+    assert_eq!(strip_prefix("/disk", "/disk"), Some("/"));
+    // 按路径的部分比较
+    assert_eq!(strip_prefix("/diskette", "/disk"), None);
+    assert_eq!(strip_prefix("/dev", "/disk"), None);
+
+    assert_eq!(split_last("/"), None);
+    assert_eq!(split_last("/dev"), Some(("/", "dev")));
+    assert_eq!(split_last("/a/b/c"), Some(("/a/b", "c")));
+}
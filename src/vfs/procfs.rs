@@ -0,0 +1,302 @@
+//! 内核状态的只读视图, 通常挂载到"/proc"
+//!
+//! 每个文件由一个生成函数在打开时渲染到缓冲区, 同一个句柄读到的内容不会变化.
+//! 生成函数只使用各模块返回的副本, 渲染期间不持有它们的锁
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use super::{ready, DirEntry, FileSystem, Metadata, Node, VfsError, VfsFuture};
+use crate::error::KernelError;
+use crate::interrupts::{self, PIC_1_OFFSET};
+use crate::process::{self, ProcessInfo, Resource};
+use crate::sched::{self, Entity};
+use crate::{allocator, memory, task, thread, time, version};
+
+/// 内核状态文件系统
+pub struct ProcFs;
+
+type Generator = fn(&mut String) -> fmt::Result;
+
+const FILES: [(&str, Generator); 5] = [
+    ("uptime", uptime),
+    ("meminfo", meminfo),
+    ("interrupts", interrupts),
+    ("tasks", tasks),
+    ("version", version),
+];
+
+// 进程目录中的文件
+const PROCESS_FILES: [&str; 1] = ["status"];
+
+/// 打开时生成的内容
+struct Snapshot {
+    data: Vec<u8>,
+}
+
+impl Snapshot {
+    fn render(generate: impl FnOnce(&mut String) -> fmt::Result) -> Result<Snapshot, VfsError> {
+        let mut text = String::new();
+        generate(&mut text).map_err(|_| VfsError::Io)?;
+        Ok(Snapshot {
+            data: text.into_bytes(),
+        })
+    }
+}
+
+impl Node for Snapshot {
+    fn metadata(&self) -> Metadata {
+        Metadata::new(false, self.data.len())
+    }
+
+    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> VfsFuture<'a, usize> {
+        let data = self.data.get(offset..).unwrap_or(&[]);
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        ready(Ok(len))
+    }
+}
+
+/// 目录的内容也在打开时确定
+struct Dir {
+    entries: Vec<DirEntry>,
+}
+
+impl Node for Dir {
+    fn metadata(&self) -> Metadata {
+        Metadata::new(true, 0)
+    }
+
+    fn read_at<'a>(&'a self, _offset: usize, _buf: &'a mut [u8]) -> VfsFuture<'a, usize> {
+        ready(Ok(0))
+    }
+
+    fn readdir(&self) -> VfsFuture<'_, Vec<DirEntry>> {
+        ready(Ok(self.entries.clone()))
+    }
+}
+
+fn entry(name: String, is_dir: bool) -> DirEntry {
+    DirEntry {
+        name,
+        metadata: Metadata::new(is_dir, 0),
+    }
+}
+
+// "/<pid>"对应的进程, 不是数字或进程不存在时返回None
+fn find_process(name: &str) -> Option<ProcessInfo> {
+    let pid: u64 = name.parse().ok()?;
+    process::list()
+        .into_iter()
+        .find(|info| info.pid.as_u64() == pid)
+}
+
+impl FileSystem for ProcFs {
+    fn open<'a>(&'static self, path: &'a str) -> VfsFuture<'a, Box<dyn Node>> {
+        ready(open(path))
+    }
+}
+
+// 内容都在打开时生成, 不需要等待
+fn open(path: &str) -> Result<Box<dyn Node>, KernelError> {
+    let mut parts = path.split('/').filter(|part| !part.is_empty());
+    let node: Box<dyn Node> = match (parts.next(), parts.next(), parts.next()) {
+        (None, _, _) => {
+            let files = FILES.iter().map(|(name, _)| entry((*name).into(), false));
+            let processes = process::list()
+                .into_iter()
+                .map(|info| entry(format!("{}", info.pid), true));
+            Box::new(Dir {
+                entries: files.chain(processes).collect(),
+            })
+        }
+        (Some(name), None, _) => match FILES.iter().find(|(file, _)| *file == name) {
+            Some((_, generate)) => Box::new(Snapshot::render(*generate)?),
+            None => {
+                find_process(name).ok_or(VfsError::NotFound)?;
+                Box::new(Dir {
+                    entries: PROCESS_FILES
+                        .iter()
+                        .map(|name| entry((*name).into(), false))
+                        .collect(),
+                })
+            }
+        },
+        (Some(name), Some(_), _) if FILES.iter().any(|(file, _)| *file == name) => {
+            return Err(VfsError::NotADirectory.into())
+        }
+        (Some(name), Some("status"), None) => {
+            let info = find_process(name).ok_or(VfsError::NotFound)?;
+            Box::new(Snapshot::render(|out| status(out, &info))?)
+        }
+        _ => return Err(VfsError::NotFound.into()),
+    };
+    Ok(node)
+}
+
+// 秒数, 精确到毫秒
+fn uptime(out: &mut String) -> fmt::Result {
+    let ms = time::uptime().ms;
+    writeln!(out, "{}.{:03}", ms / 1000, ms % 1000)
+}
+
+fn meminfo(out: &mut String) -> fmt::Result {
+    let heap = allocator::heap_stats();
+    writeln!(out, "HeapTotal:       {} B", heap.size)?;
+    writeln!(out, "HeapUsed:        {} B", heap.used)?;
+    writeln!(out, "HeapAllocations: {}", heap.allocations)?;
+    writeln!(out, "HeapAllocator:   {}", allocator::allocator_name())?;
+    if let Some(frames) = memory::frame_stats() {
+        writeln!(out, "FramesTotal:     {}", frames.total)?;
+        writeln!(out, "FramesAllocated: {}", frames.allocated)?;
+        writeln!(out, "FramesShared:    {}", frames.shared)?;
+    }
+    Ok(())
+}
+
+// 每个IRQ一行, 包括还没有收到过的
+fn interrupts(out: &mut String) -> fmt::Result {
+    writeln!(out, "{:>6}  {:<5}COUNT", "VECTOR", "IRQ")?;
+    for (irq, count) in interrupts::irq_counts().iter().enumerate() {
+        writeln!(
+            out,
+            "{:>6}  {:<5}{}",
+            usize::from(PIC_1_OFFSET) + irq,
+            irq,
+            count
+        )?;
+    }
+    Ok(())
+}
+
+// 线程按时间片轮转, 任务由执行器poll, CPU%是最近几秒的占用, 还没有样本时为"-"
+fn tasks(out: &mut String) -> fmt::Result {
+    let usage = sched::cpu_usage();
+    let percent = |entity: Entity| match &usage {
+        Some(usage) => {
+            let percent = usage
+                .entities
+                .iter()
+                .find(|used| used.entity == entity)
+                .map_or(0, |used| used.percent);
+            format!("{}%", percent)
+        }
+        None => "-".into(),
+    };
+    if let Some(usage) = &usage {
+        writeln!(out, "idle: {}%", usage.idle_percent)?;
+    }
+    writeln!(
+        out,
+        "{:<7}{:>4}  {:<12}{:<8}{:<9}{:>5}",
+        "KIND", "ID", "NAME", "PRIO", "STATE", "CPU%"
+    )?;
+    for info in thread::threads() {
+        let id = info.id.as_u64();
+        let state = if info.running { "running" } else { "ready" };
+        writeln!(
+            out,
+            "{:<7}{:>4}  {:<12}{:<8}{:<9}{:>5}",
+            "thread",
+            id,
+            info.name,
+            info.priority.name(),
+            state,
+            percent(Entity::Thread(id))
+        )?;
+    }
+    for info in task::tasks() {
+        let polls = format!("{} polls", info.polls);
+        writeln!(
+            out,
+            "{:<7}{:>4}  {:<12}{:<8}{:<9}{:>5}",
+            "task",
+            info.id,
+            info.name,
+            "-",
+            polls,
+            percent(Entity::Task(info.id))
+        )?;
+    }
+    Ok(())
+}
+
+fn version(out: &mut String) -> fmt::Result {
+    let info = version::info();
+    writeln!(
+        out,
+        "{} {} ({}) built {}",
+        info.name, info.version, info.git_hash, info.build_date
+    )
+}
+
+// 资源一行一种, 使用量和上限, 进程结束后使用量为0
+fn status(out: &mut String, info: &ProcessInfo) -> fmt::Result {
+    writeln!(out, "Name:     {}", info.name)?;
+    writeln!(out, "Pid:      {}", info.pid)?;
+    match &info.exit {
+        Some(exit) => writeln!(out, "State:    {}", exit)?,
+        None => writeln!(out, "State:    running")?,
+    }
+    for resource in Resource::ALL {
+        let name = format!("{}:", resource.name());
+        writeln!(
+            out,
+            "{:<10}{} / {}",
+            name,
+            info.usage.get(resource),
+            info.limits.get(resource)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn read_all(node: &dyn Node) -> String {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        match task::complete_now(node.read_at(data.len(), &mut buf)).unwrap() {
+            0 => break,
+            len => data.extend_from_slice(&buf[..len]),
+        }
+    }
+    String::from_utf8(data).unwrap()
+}
+
+#[test_case]
+fn test_procfs_files() {
+    let root = open("/").unwrap();
+    let names: Vec<_> = task::complete_now(root.readdir())
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    for (name, _) in FILES {
+        assert!(names.iter().any(|n| n == name), "{} not listed", name);
+        let file = open(&format!("/{}", name)).unwrap();
+        assert!(!file.metadata().is_dir());
+        assert_eq!(read_all(&*file).len(), file.metadata().len);
+    }
+    assert!(read_all(&*open("/meminfo").unwrap()).starts_with("HeapTotal:"));
+    assert!(read_all(&*open("/version").unwrap()).contains(version::info().version));
+    assert!(read_all(&*open("/tasks").unwrap()).contains("KIND"));
+}
+
+#[test_case]
+fn test_procfs_not_found() {
+    for path in ["/nope", "/0", "/999999/status", "/uptime2", "/1x/status"] {
+        assert!(
+            matches!(open(path), Err(KernelError::NotFound(_))),
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        open("/uptime/status").err(),
+        Some(VfsError::NotADirectory.into())
+    );
+}
@@ -1,7 +1,23 @@
 use core::fmt;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use conquer_once::spin::OnceCell;
 use volatile::Volatile;
-use lazy_static::lazy_static;
-use spin::Mutex;
+
+use crate::bench_case;
+use crate::bootinfo::{self, FramebufferInfo};
+use crate::framebuffer;
+use crate::percpu;
+use crate::selftest::{self, Check, Outcome};
+use crate::shell::{self, args, CmdError};
+use crate::sync::{IrqMutex, PanicRelease, TrackedMutex, TrackedMutexGuard};
+
+pub mod console;
+pub mod cp437;
+mod theme;
+
+pub use console::ConsoleId;
+pub use theme::{Role, Theme};
 
 #[repr(u8)]
 #[allow(dead_code)]
@@ -33,6 +49,17 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    // 交换前景色和背景色
+    fn inverted(self) -> ColorCode {
+        ColorCode(self.0.rotate_left(4))
+    }
+
+    // (前景色, 背景色)
+    fn colors(self) -> (Color, Color) {
+        let color = |index: u8| COLOR_NAMES[usize::from(index & 0xF)].1;
+        (color(self.0), color(self.0 >> 4))
+    }
 }
 
 #[repr(C)]
@@ -42,20 +69,40 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_WIDTH: usize = 80;
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+/// 写入时删除前一个字符
+pub const BACKSPACE: u8 = 0x08;
+pub const BUFFER_HEIGHT: usize = 25;
 const VGA_BUFFER_ADDR: usize = 0xb8000;
 
 // 一屏
 #[repr(transparent)]
 struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_WIDTH],
+    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+// 图形模式下0xb8000不再被VGA解码, 文本改写到内存中, 读取屏幕内容的代码不受影响
+static mut SHADOW_BUFFER: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] = [[ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0),
+}; BUFFER_WIDTH]; BUFFER_HEIGHT];
+
+// 双缓冲时屏幕内容在内存中的副本
+static mut BACK_BUFFER: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] = [[ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0),
+}; BUFFER_WIDTH]; BUFFER_HEIGHT];
+
 pub struct Writer {
-    char_numbers: usize,
+    // 光标所在行列, column_position == BUFFER_WIDTH表示本行已写满, 写下一个字符前再换行
+    row_position: usize,
+    column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    // 启用双缓冲时与buffer的内容保持一致, 读取时代替buffer
+    back: Option<&'static mut [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT]>,
+    // 虚拟控制台保留滚出屏幕的行
+    history: Option<console::Scrollback>,
 }
 
 impl Writer {
@@ -63,72 +110,70 @@ impl Writer {
         match byte {
             // 换行符直接调用new_line方法“新开一行”
             b'\n' => self.new_line(),
+            // 退格删除本行的前一个字符, 不回到上一行
+            BACKSPACE => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                    let blank = ScreenChar {
+                        ascii_character: b' ',
+                        color_code: self.color_code,
+                    };
+                    self.write_cell(self.row_position, self.column_position, blank);
+                }
+            }
 
             byte => {
-                // 计算当前位置
-                let mut row = self.char_numbers / BUFFER_WIDTH;
-                let col = self.char_numbers % BUFFER_WIDTH;
-
-                // 行数溢出,置为最后一行
-                if row >= (BUFFER_HEIGHT - 1) {
-                    // 最后一行一行的最后一个字符,换行
-                    if col >= BUFFER_WIDTH - 1 {
-                        self.new_line();
-                        // 由于换行将最后一行上移,因此要打印到倒数第二行
-                        row = BUFFER_HEIGHT - 2;
-                        // 抵消换行时将char_numbers置为下一行行首
-                        self.char_numbers -= 1;
-                    } else {
-                        row = BUFFER_HEIGHT - 1;
-                    }
+                // 本行已满时才换行, 避免恰好80个字符后的换行符多出一个空行
+                if self.column_position >= BUFFER_WIDTH {
+                    self.new_line();
                 }
 
+                let row = self.row_position;
+                let col = self.column_position;
+
                 // 写入字符串
                 let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
+                self.write_cell(row, col, ScreenChar {
                     ascii_character: byte,
                     color_code,
                 });
 
-                // 字符数+1
-                self.char_numbers += 1;
+                self.column_position += 1;
             }
         }
     }
 
     fn new_line(&mut self) {
-        // 先防止整数溢出 字符数到达最大值-一页的范围内时，就对一页字符数取模，防止整数溢出
-        if self.char_numbers >= (usize::MAX - BUFFER_WIDTH * BUFFER_HEIGHT) {
-            // 与一页字符数取模
-            self.char_numbers = self.char_numbers % (BUFFER_WIDTH * BUFFER_HEIGHT);
-        }
-
-        // 换行 字符数 + 80 减去本行的字符数(self.char_numbers % BUFFER_WIDTH 计算出新行有多少个字符)
-        self.char_numbers = self.char_numbers + BUFFER_WIDTH - self.char_numbers % BUFFER_WIDTH;
-
-        // 若到达最大高度,所有字符上移一行,并清空最后一行
-        if self.char_numbers / BUFFER_WIDTH >= BUFFER_HEIGHT - 1 {
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+        } else {
+            // 已在最后一行, 所有字符上移一行,并清空最后一行
+            // 滚出屏幕的一行留在回滚历史中
+            if let Some(mut history) = self.history.take() {
+                history.push(core::array::from_fn(|col| self.read_cell(0, col)));
+                self.history = Some(history);
+            }
             for row in 1..BUFFER_HEIGHT {
                 for col in 0..BUFFER_WIDTH {
-                    let character = self.buffer.chars[row][col].read();
+                    let character = self.read_cell(row, col);
                     //上移一行
-                    self.buffer.chars[row - 1][col].write(character);
+                    self.write_cell(row - 1, col, character);
                 }
             }
 
             // 清空最后一行
             self.clear_row(BUFFER_HEIGHT - 1);
         }
+        self.column_position = 0;
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // ascii byte
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-
-                // not part of ascii
-                _ => self.write_byte(0xfe),
+        for c in s.chars() {
+            match c {
+                '\n' => self.write_byte(b'\n'),
+                '\u{8}' => self.write_byte(BACKSPACE),
+                // 字体中没有的字符显示为■
+                c => self.write_byte(cp437::translate(c).unwrap_or(0xfe)),
             }
         }
     }
@@ -142,37 +187,734 @@ impl Writer {
 
         // 将最后一行都设置为空白字符
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            self.write_cell(row, col, blank);
+        }
+    }
+
+    /// 清空整屏, 光标回到左上角
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.row_position = 0;
+        self.column_position = 0;
+    }
+
+    /// 清空当前行并从行首写入`text`, 超出一行的部分不显示. `cursor`列反色显示
+    pub fn rewrite_line(&mut self, text: &str, cursor: Option<usize>) {
+        self.rewrite_with_prompt("", self.color_code, text, cursor);
+    }
+
+    /// 与`rewrite_line`相同, 行首先以指定颜色写入`prompt`
+    pub fn rewrite_prompt_line(
+        &mut self,
+        prompt: &str,
+        (foreground, background): (Color, Color),
+        text: &str,
+        cursor: Option<usize>,
+    ) {
+        self.rewrite_with_prompt(prompt, ColorCode::new(foreground, background), text, cursor);
+    }
+
+    fn rewrite_with_prompt(
+        &mut self,
+        prompt: &str,
+        prompt_color: ColorCode,
+        text: &str,
+        cursor: Option<usize>,
+    ) {
+        let row = self.row_position;
+        self.clear_row(row);
+        self.column_position = 0;
+        let color_code = core::mem::replace(&mut self.color_code, prompt_color);
+        self.write_within_line(prompt);
+        self.color_code = color_code;
+        self.write_within_line(text);
+        if let Some(col) = cursor.filter(|&col| col < BUFFER_WIDTH) {
+            self.invert_cell(row, col);
+        }
+    }
+
+    // 写到行尾为止, 不换行
+    fn write_within_line(&mut self, text: &str) {
+        for c in text.chars() {
+            if self.column_position >= BUFFER_WIDTH {
+                break;
+            }
+            self.write_byte(cp437::translate(c).unwrap_or(0xfe));
+        }
+    }
+
+    /// 之后写入的字符使用的颜色
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// 以指定的颜色写入, 之后恢复原来的颜色
+    pub fn write_colored(
+        &mut self,
+        (foreground, background): (Color, Color),
+        args: fmt::Arguments,
+    ) -> fmt::Result {
+        use core::fmt::Write;
+
+        let colored = ColorCode::new(foreground, background);
+        let color_code = core::mem::replace(&mut self.color_code, colored);
+        let result = self.write_fmt(args);
+        self.color_code = color_code;
+        result
+    }
+
+    fn invert_cell(&mut self, row: usize, col: usize) {
+        let mut character = self.read_cell(row, col);
+        character.color_code = character.color_code.inverted();
+        self.write_cell(row, col, character);
+    }
+
+    fn read_cell(&self, row: usize, col: usize) -> ScreenChar {
+        match &self.back {
+            Some(back) => back[row][col],
+            None => self.buffer.chars[row][col].read(),
         }
     }
+
+    // 双缓冲时跳过内容没有变化的字符
+    fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        if let Some(back) = self.back.as_mut() {
+            if back[row][col] == character {
+                return;
+            }
+            back[row][col] = character;
+        }
+        self.buffer.chars[row][col].write(character);
+    }
+
+    /// 在内存中保留屏幕内容的副本. 显存访问很慢, 双缓冲时滚屏不再读取显存, 也不重写没有变化的字符
+    pub fn set_double_buffered(&mut self, enabled: bool) {
+        if !enabled {
+            self.back = None;
+            return;
+        }
+        if self.back.is_some() {
+            return;
+        }
+        // 只有持有WRITER锁时才会访问, 同一时刻只有这一个引用
+        let back = unsafe { &mut *addr_of_mut!(BACK_BUFFER) };
+        for (row, line) in back.iter_mut().enumerate() {
+            for (col, cell) in line.iter_mut().enumerate() {
+                *cell = self.buffer.chars[row][col].read();
+            }
+        }
+        self.back = Some(back);
+    }
+
+    // 只在切换控制台时调用一次. 影子缓冲区本身就在内存中, 不再需要双缓冲
+    fn use_shadow_buffer(&mut self) {
+        self.back = None;
+        self.buffer = unsafe { &mut *(addr_of_mut!(SHADOW_BUFFER) as *mut Buffer) };
+        self.clear_screen();
+    }
+
+    /// 读取屏幕上指定位置的字符
+    pub fn read_byte(&self, row: usize, col: usize) -> u8 {
+        self.read_cell(row, col).ascii_character
+    }
+
+    /// 读取屏幕上的一整行
+    pub fn read_row(&self, row: usize) -> [u8; BUFFER_WIDTH] {
+        let mut line = [0; BUFFER_WIDTH];
+        for (col, byte) in line.iter_mut().enumerate() {
+            *byte = self.read_byte(row, col);
+        }
+        line
+    }
+
+    /// 在当前行写入`pattern`, 从显存(不是双缓冲的副本)读回后恢复这一行, 返回读回的是否一致
+    pub fn check_readback(&mut self, pattern: &str) -> bool {
+        let row = self.row_position;
+        let column = self.column_position;
+        let saved: [ScreenChar; BUFFER_WIDTH] =
+            core::array::from_fn(|col| self.read_cell(row, col));
+        self.rewrite_line(pattern, None);
+        let matches = pattern
+            .bytes()
+            .take(BUFFER_WIDTH)
+            .enumerate()
+            .all(|(col, byte)| self.buffer.chars[row][col].read().ascii_character == byte);
+        for (col, cell) in saved.into_iter().enumerate() {
+            self.write_cell(row, col, cell);
+        }
+        self.column_position = column;
+        matches
+    }
 }
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        #[cfg(any(test, feature = "console-recording"))]
+        record(s);
+
         self.write_string(s);
         Ok(())
     }
 }
 
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        char_numbers: 0,
-        color_code: ColorCode::new(Color::Green, Color::Black),
-        buffer: unsafe { &mut *(VGA_BUFFER_ADDR as *mut Buffer) },
+// 供测试检查的输出记录, 在持有WRITER锁时追加, 因此记录顺序与屏幕上的顺序一致.
+// 只在单元测试和console-recording特性下编译, 正常的内核写屏幕时不多获取一个锁
+#[cfg(any(test, feature = "console-recording"))]
+static RECORDER: spin::Mutex<Option<alloc::string::String>> = spin::Mutex::new(None);
+// 没有在记录时不获取RECORDER
+#[cfg(any(test, feature = "console-recording"))]
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(any(test, feature = "console-recording"))]
+fn record(s: &str) {
+    if !RECORDING.load(Ordering::Acquire) {
+        return;
+    }
+    if let Some(transcript) = RECORDER.lock().as_mut() {
+        // 不扩容, 写满后丢弃后续输出, 避免测试耗尽堆
+        if transcript.len() + s.len() <= transcript.capacity() {
+            transcript.push_str(s);
+        }
+    }
+}
+
+/// 开始记录之后所有处理器写入WRITER的输出, 最多记录`capacity`字节. 供测试使用
+#[cfg(any(test, feature = "console-recording"))]
+pub fn start_recording(capacity: usize) {
+    use x86_64::instructions::interrupts;
+
+    let transcript = alloc::string::String::with_capacity(capacity);
+    interrupts::without_interrupts(|| {
+        *RECORDER.lock() = Some(transcript);
     });
+    RECORDING.store(true, Ordering::Release);
+}
+
+/// 停止记录并返回记录的内容
+#[cfg(any(test, feature = "console-recording"))]
+pub fn stop_recording() -> alloc::string::String {
+    use x86_64::instructions::interrupts;
+
+    RECORDING.store(false, Ordering::Release);
+    interrupts::without_interrupts(|| RECORDER.lock().take())
+        .expect("recording was not started")
+}
+
+// 把内存中的一屏当作Buffer使用
+fn memory_buffer(
+    cells: &'static mut [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+) -> &'static mut Buffer {
+    // Buffer和Volatile都是repr(transparent)
+    unsafe { &mut *(cells as *mut _ as *mut Buffer) }
+}
+
+static WRITER: OnceCell<TrackedMutex<console::Consoles>> = OnceCell::uninit();
+
+/// 创建文本控制台, 由`toy_os::init`调用. 重复调用会panic
+pub fn init() {
+    WRITER
+        .try_init_once(|| {
+            TrackedMutex::new(
+                "vga_buffer::WRITER",
+                console::Consoles::new(
+                    unsafe { &mut *(VGA_BUFFER_ADDR as *mut Buffer) },
+                    console::memory(),
+                    ColorCode::new(Theme::CLASSIC.normal, Theme::CLASSIC.background),
+                ),
+            )
+        })
+        .expect("vga_buffer::init called twice");
+}
+
+/// 文本控制台, 必须在`init`之后使用. `print!`在初始化之前也可以使用
+///
+/// 锁住的是所有虚拟控制台, 解引用为前台控制台的Writer
+pub fn writer() -> &'static TrackedMutex<console::Consoles> {
+    WRITER
+        .try_get()
+        .expect("vga_buffer::writer used before vga_buffer::init")
+}
+
+/// 交换指定位置字符的前景色和背景色, 用于绘制光标
+pub fn invert_cell(row: usize, col: usize) {
+    writer().lock().invert_cell(row, col);
+}
+
+/// 开关文本控制台的双缓冲, 见`Writer::set_double_buffered`
+pub fn set_double_buffered(enabled: bool) {
+    writer().lock().set_double_buffered(enabled);
+}
+
+/// 清空两种控制台上前台虚拟控制台的屏幕
+pub fn clear_screen() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        writer().lock().clear();
+        if backend() == Backend::Framebuffer {
+            framebuffer::clear();
+        }
+    });
+}
+
+/// 在两种控制台上重写外壳控制台的当前行, 用于行编辑
+pub fn rewrite_line(text: &str, cursor: Option<usize>) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut consoles = writer().lock();
+        consoles.writer(ConsoleId::Shell).rewrite_line(text, cursor);
+        if backend() == Backend::Framebuffer && consoles.on_screen(ConsoleId::Shell) {
+            framebuffer::rewrite_line(text, cursor);
+        }
+    });
+}
+
+/// 与`rewrite_line`相同, 行首的`prompt`使用当前主题中提示符的颜色
+pub fn rewrite_prompt_line(prompt: &str, text: &str, cursor: Option<usize>) {
+    use x86_64::instructions::interrupts;
+
+    let colors = theme().colors(Role::Prompt);
+    interrupts::without_interrupts(|| {
+        let mut consoles = writer().lock();
+        consoles
+            .writer(ConsoleId::Shell)
+            .rewrite_prompt_line(prompt, colors, text, cursor);
+        if backend() == Backend::Framebuffer && consoles.on_screen(ConsoleId::Shell) {
+            framebuffer::rewrite_prompt_line(prompt, colors, text, cursor);
+        }
+    });
+}
+
+/// 设置两种控制台之后输出的颜色, 对所有虚拟控制台有效
+pub fn set_color(foreground: Color, background: Color) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        writer().lock().set_color(foreground, background);
+        if backend() == Backend::Framebuffer {
+            framebuffer::set_color(foreground, background);
+        }
+    });
+}
+
+static THEME: IrqMutex<Theme> = IrqMutex::new("vga_buffer::THEME", Theme::CLASSIC);
+
+/// 当前的主题
+pub fn theme() -> Theme {
+    *THEME.lock()
+}
+
+/// 切换主题, 之后的普通输出使用新主题的颜色, 屏幕上已有的内容不变
+pub fn set_theme(theme: Theme) {
+    *THEME.lock() = theme;
+    set_color(theme.normal, theme.background);
+}
+
+/// 注册`clear`、`color`和`theme`命令
+pub fn register_commands() {
+    shell::register_command("clear", "clear the screen", |_, _| {
+        clear_screen();
+        Ok(())
+    })
+    .expect("duplicate console command");
+    shell::register_command(
+        "color",
+        "color <fg> <bg>: set text colors, by name or 0-15",
+        color_command,
+    )
+    .expect("duplicate console command");
+    shell::register_command(
+        "theme",
+        "theme [name]: list the color themes or switch to one",
+        theme_command,
+    )
+    .expect("duplicate console command");
+}
+
+const COLOR_NAMES: [(&str, Color); 16] = [
+    ("black", Color::Black),
+    ("blue", Color::Blue),
+    ("green", Color::Green),
+    ("cyan", Color::Cyan),
+    ("red", Color::Red),
+    ("magenta", Color::Magenta),
+    ("brown", Color::Brown),
+    ("lightgray", Color::LightGray),
+    ("darkgray", Color::DarkGray),
+    ("lightblue", Color::LightBlue),
+    ("lightgreen", Color::LightGreen),
+    ("lightcyan", Color::LightCyan),
+    ("lightred", Color::LightRed),
+    ("pink", Color::Pink),
+    ("yellow", Color::Yellow),
+    ("white", Color::White),
+];
+
+// 颜色名不区分大小写, 也可以用0-15表示
+fn parse_color(arg: &str) -> Result<Color, CmdError> {
+    if let Ok(index) = args::parse_number(arg) {
+        let index = usize::try_from(index).map_err(|_| CmdError::UnknownColor)?;
+        return COLOR_NAMES
+            .get(index)
+            .map(|&(_, color)| color)
+            .ok_or(CmdError::UnknownColor);
+    }
+    COLOR_NAMES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(arg))
+        .map(|&(_, color)| color)
+        .ok_or(CmdError::UnknownColor)
+}
+
+fn color_command(args: &[&str], _out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    let [foreground, background] = args else {
+        return Err(CmdError::Usage("color <fg> <bg>"));
+    };
+    set_color(parse_color(foreground)?, parse_color(background)?);
+    Ok(())
+}
+
+fn theme_command(args: &[&str], out: &mut dyn fmt::Write) -> Result<(), CmdError> {
+    match args {
+        [] => {
+            let current = theme().name;
+            for theme in Theme::BUILTIN {
+                let marker = if theme.name == current { "*" } else { " " };
+                writeln!(out, "{} {}", marker, theme.name)?;
+            }
+            Ok(())
+        }
+        [name] => {
+            set_theme(Theme::by_name(name).ok_or(CmdError::UnknownTheme)?);
+            Ok(())
+        }
+        _ => Err(CmdError::Usage("theme [name]")),
+    }
+}
+
+/// 文本输出的后端
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    VgaText,
+    /// 在帧缓冲上绘制, 同时保留文本缓冲区的内容
+    Framebuffer,
+}
+
+/// 有帧缓冲时使用像素控制台
+pub fn select_backend(framebuffer: Option<&FramebufferInfo>) -> Backend {
+    match framebuffer {
+        Some(_) => Backend::Framebuffer,
+        None => Backend::VgaText,
+    }
+}
+
+static FRAMEBUFFER_CONSOLE: AtomicBool = AtomicBool::new(false);
+
+pub fn backend() -> Backend {
+    if FRAMEBUFFER_CONSOLE.load(Ordering::SeqCst) {
+        Backend::Framebuffer
+    } else {
+        Backend::VgaText
+    }
+}
+
+/// `print!`输出的去向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConsoleTarget {
+    /// 屏幕, 即文本缓冲区和可能存在的帧缓冲
+    Vga,
+    Serial,
+    Both,
+}
+
+impl ConsoleTarget {
+    /// 解析命令行中`console=`的值
+    pub fn parse(name: &str) -> Option<ConsoleTarget> {
+        match name {
+            "vga" => Some(ConsoleTarget::Vga),
+            "serial" => Some(ConsoleTarget::Serial),
+            "both" => Some(ConsoleTarget::Both),
+            _ => None,
+        }
+    }
+}
+
+static CONSOLE_TARGET: AtomicU8 = AtomicU8::new(ConsoleTarget::Vga as u8);
+
+pub fn console_target() -> ConsoleTarget {
+    match CONSOLE_TARGET.load(Ordering::Relaxed) {
+        1 => ConsoleTarget::Serial,
+        2 => ConsoleTarget::Both,
+        _ => ConsoleTarget::Vga,
+    }
+}
+
+/// 选择`print!`的输出去向. 外壳的输入行总是绘制在屏幕上
+pub fn set_console_target(target: ConsoleTarget) {
+    CONSOLE_TARGET.store(target as u8, Ordering::Relaxed);
+}
+
+static CPU_PREFIX: AtomicBool = AtomicBool::new(false);
+
+/// 开关`print!`输出的每行开头的`[cpuN] `, 用于看出多个处理器的输出如何交错. 内核日志中不加前缀
+pub fn set_cpu_prefix(enabled: bool) {
+    CPU_PREFIX.store(enabled, Ordering::Relaxed);
+}
+
+// 需要加前缀时为当前处理器的编号
+fn cpu_prefix() -> Option<u32> {
+    CPU_PREFIX.load(Ordering::Relaxed).then(percpu::cpu_id)
+}
+
+/// 在每行开头写入`[cpuN] `的Write适配器, `at_line_start`在多次输出之间记录是否停在行首
+pub(crate) struct PrefixWriter<'a, W: fmt::Write> {
+    pub out: W,
+    pub cpu: Option<u32>,
+    pub at_line_start: &'a mut bool,
+}
+
+impl<W: fmt::Write> fmt::Write for PrefixWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if let (Some(cpu), true) = (self.cpu, *self.at_line_start) {
+                write!(self.out, "[cpu{}] ", cpu)?;
+            }
+            self.out.write_str(line)?;
+            *self.at_line_start = line.ends_with('\n');
+        }
+        Ok(())
+    }
+}
+
+// 以`PrefixWriter`格式化`args`, 从行首开始时第一行也加前缀
+struct Prefixed<'a> {
+    args: fmt::Arguments<'a>,
+    cpu: u32,
+    at_line_start: bool,
+}
+
+impl fmt::Display for Prefixed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use core::fmt::Write;
+
+        let mut at_line_start = self.at_line_start;
+        PrefixWriter {
+            out: f,
+            cpu: Some(self.cpu),
+            at_line_start: &mut at_line_start,
+        }
+        .write_fmt(self.args)
+    }
+}
+
+/// panic路径在输出之前调用, 使屏幕和串口的锁可以获取, 见`TrackedMutex::release_for_panic`.
+/// 从其他处理器取走锁时输出警告
+pub fn unlock_for_panic() {
+    let screen = match WRITER.try_get() {
+        Ok(writer) => unsafe { writer.release_for_panic() },
+        Err(_) => PanicRelease::Free,
+    };
+    let serial = crate::serial::unlock_for_panic();
+    for (name, release) in [("console", screen), ("serial", serial)] {
+        match release {
+            PanicRelease::Stolen(Some(cpu)) => {
+                crate::println_role!(Role::Warn, "panic: took the {} lock from CPU {}", name, cpu)
+            }
+            PanicRelease::Stolen(None) => {
+                crate::println_role!(Role::Warn, "panic: took the {} lock from another CPU", name)
+            }
+            PanicRelease::Free | PanicRelease::Own => {}
+        }
+    }
+}
+
+/// 根据bootloader提供的信息选择控制台, 需要先调用`bootinfo::init`
+pub fn init_console() {
+    if select_backend(bootinfo::framebuffer().as_ref()) == Backend::Framebuffer && framebuffer::init() {
+        writer().lock().use_shadow_buffer();
+        FRAMEBUFFER_CONSOLE.store(true, Ordering::SeqCst);
+    }
 }
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
+    print_colored(ConsoleId::Log, None, args);
+}
+
+/// 登记文本控制台的自检
+pub fn register_selftests() {
+    static READBACK: Check = Check::new("vga", readback_selftest);
+    selftest::register(&READBACK).expect("duplicate console selftest");
+}
+
+fn readback_selftest() -> Outcome {
+    use x86_64::instructions::interrupts;
+
+    let Ok(writer) = WRITER.try_get() else {
+        return Outcome::Skipped("console not initialized".into());
+    };
+    if interrupts::without_interrupts(|| writer.lock().check_readback("selftest: vga 0123456789")) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail("text written to the VGA buffer did not read back".into())
+    }
+}
+
+/// 以`role`在当前主题中的颜色输出, 之后恢复原来的颜色. 串口上没有颜色
+pub fn print_role(role: Role, args: fmt::Arguments) {
+    print_role_on(ConsoleId::Log, role, args);
+}
+
+/// 与`print_role`相同, 输出到虚拟控制台`console`
+pub fn print_role_on(console: ConsoleId, role: Role, args: fmt::Arguments) {
+    // 控制台初始化之前不访问主题
+    let colors = WRITER.try_get().ok().map(|_| theme().colors(role));
+    print_colored(console, colors, args);
+}
+
+fn print_colored(console: ConsoleId, colors: Option<(Color, Color)>, args: fmt::Arguments) {
     use x86_64::instructions::interrupts;
 
-    // 在闭包执行时禁用中断
+    crate::klog::write(args);
+    if !crate::console::record(args) {
+        return;
+    }
+    let Ok(writer) = WRITER.try_get() else {
+        early_print(args);
+        return;
+    };
+    // 在闭包执行时禁用中断, 这里只读写RFLAGS.IF, 不依赖IDT/PIC初始化
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        let target = console_target();
+        let cpu = cpu_prefix();
+        if target != ConsoleTarget::Serial {
+            write_screen(writer, console, colors, cpu, args);
+        }
+        if target != ConsoleTarget::Vga {
+            crate::serial::print_prefixed(cpu, args);
+        }
     });
 }
 
+/// 以`role`的颜色只输出到日志控制台, 不受`console=`的影响. 控制台初始化之前不输出
+pub fn print_screen(role: Role, args: fmt::Arguments) {
+    let Ok(writer) = WRITER.try_get() else {
+        return;
+    };
+    let colors = theme().colors(role);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write_screen(writer, ConsoleId::Log, Some(colors), None, args);
+    });
+}
+
+// `cpu`不为None时每行加上前缀, 是否位于行首按控制台的光标判断
+fn write_screen(
+    writer: &TrackedMutex<console::Consoles>,
+    console: ConsoleId,
+    colors: Option<(Color, Color)>,
+    cpu: Option<u32>,
+    args: fmt::Arguments,
+) {
+    let mut consoles = writer.lock();
+    match cpu {
+        Some(cpu) => {
+            let prefixed = Prefixed {
+                args,
+                cpu,
+                at_line_start: consoles.writer(console).column_position == 0,
+            };
+            write_locked(consoles, console, colors, format_args!("{}", prefixed));
+        }
+        None => write_locked(consoles, console, colors, args),
+    }
+}
+
+// 写入锁住的控制台, 释放WRITER之后再绘制帧缓冲
+fn write_locked(
+    mut consoles: TrackedMutexGuard<console::Consoles>,
+    console: ConsoleId,
+    colors: Option<(Color, Color)>,
+    args: fmt::Arguments,
+) {
+    use core::fmt::Write;
+
+    match colors {
+        Some(colors) => consoles
+            .writer(console)
+            .write_colored(colors, args)
+            .unwrap(),
+        None => consoles.writer(console).write_fmt(args).unwrap(),
+    }
+    let on_screen = consoles.on_screen(console);
+    drop(consoles);
+    // 帧缓冲只显示前台控制台的输出
+    if on_screen && backend() == Backend::Framebuffer {
+        framebuffer::write_fmt(colors, args);
+    }
+}
+
+// 切换控制台或翻看回滚历史后按屏幕上的字符重绘帧缓冲, 调用者需已关闭中断
+//
+// 只重绘到光标为止, 之后的输出接在帧缓冲上相同的位置. 每行末尾的空白不绘制
+fn repaint_framebuffer(consoles: &console::Consoles) {
+    let (last_row, cursor) = match consoles.view() {
+        0 => (consoles.row_position, Some(consoles.column_position)),
+        _ => (BUFFER_HEIGHT - 1, None),
+    };
+    framebuffer::clear();
+    for row in 0..=last_row {
+        let cells: [ScreenChar; BUFFER_WIDTH] =
+            core::array::from_fn(|col| consoles.visible_cell(row, col));
+        let end = match cursor.filter(|_| row == last_row) {
+            Some(column) => column.min(BUFFER_WIDTH),
+            None => cells
+                .iter()
+                .rposition(|cell| cell.ascii_character != b' ')
+                .map_or(0, |col| col + 1),
+        };
+        let text = cells.map(|cell| cell.ascii_character);
+        let mut start = 0;
+        // 同色的一段一起绘制
+        while start < end {
+            let color_code = cells[start].color_code;
+            let len = cells[start..end]
+                .iter()
+                .take_while(|cell| cell.color_code == color_code)
+                .count();
+            framebuffer::write_bytes(Some(color_code.colors()), &text[start..start + len]);
+            start += len;
+        }
+        if row != last_row {
+            framebuffer::write_bytes(None, b"\n");
+        }
+    }
+}
+
+// 控制台初始化之前的输出: 串口已经可用时输出到串口, 否则写到屏幕首行
+fn early_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    if crate::serial::is_initialized() {
+        crate::serial::_print(args);
+    } else {
+        let _ = crate::earlycon::Vga::new().write_fmt(args);
+    }
+}
+
+/// 与`print!`相同的输出, 用于需要`fmt::Write`的地方
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        _print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
@@ -184,9 +926,329 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// 以主题中`role`的颜色输出一行: `println_role!(Role::Error, "...")`
+#[macro_export]
+macro_rules! println_role {
+    ($role:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::print_role($role, format_args!("{}\n", format_args!($($arg)*)))
+    );
+}
+
+#[test_case]
+fn test_backend_selection() {
+    assert_eq!(select_backend(None), Backend::VgaText);
+    let mode = FramebufferInfo {
+        address: x86_64::PhysAddr::new(0xA0000),
+        width: 320,
+        height: 200,
+        pitch: 320,
+        bytes_per_pixel: 1,
+        format: bootinfo::PixelFormat::Indexed8,
+    };
+    assert_eq!(select_backend(Some(&mode)), Backend::Framebuffer);
+
+    // 测试内核也按bootloader的配置选择了控制台
+    let expected = if cfg!(feature = "vga_320x200") {
+        Backend::Framebuffer
+    } else {
+        Backend::VgaText
+    };
+    assert_eq!(backend(), expected);
+}
+
+#[test_case]
+fn test_console_target() {
+    use x86_64::instructions::interrupts;
+
+    fn printed_to_screen(marker: &str) -> bool {
+        println!("{}", marker);
+        interrupts::without_interrupts(|| {
+            let writer = writer().lock();
+            (0..BUFFER_HEIGHT).any(|row| {
+                writer.read_row(row)
+                    .windows(marker.len())
+                    .any(|window| window == marker.as_bytes())
+            })
+        })
+    }
+
+    assert_eq!(ConsoleTarget::parse("both"), Some(ConsoleTarget::Both));
+    assert_eq!(ConsoleTarget::parse("lpt"), None);
+    // 启动时按命令行选择了输出去向
+    let chosen = crate::cmdline::get("console")
+        .and_then(ConsoleTarget::parse)
+        .unwrap_or(ConsoleTarget::Vga);
+    assert_eq!(console_target(), chosen);
+    assert_eq!(
+        printed_to_screen("console target: boot"),
+        chosen != ConsoleTarget::Serial
+    );
+
+    set_console_target(ConsoleTarget::Serial);
+    let on_screen = printed_to_screen("console target: serial only");
+    set_console_target(ConsoleTarget::Both);
+    assert!(!on_screen);
+    assert!(printed_to_screen("console target: both"));
+    set_console_target(chosen);
+}
+
 #[test_case]
 fn test_print_many_characters() {
     for i in 0..1024 {
         println!("print test:{}", i);
     }
-}
\ No newline at end of file
+}
+
+// 以下测试在关中断并持有锁的情况下进行, 避免时钟中断的输出干扰屏幕内容
+#[cfg(test)]
+fn with_locked_writer(f: impl FnOnce(&mut Writer)) {
+    let mut writer = writer().lock();
+    writer.clear_screen();
+    f(&mut writer);
+}
+
+#[cfg(test)]
+fn row_starts_with(writer: &Writer, row: usize, prefix: &str) -> bool {
+    writer.read_row(row).starts_with(prefix.as_bytes())
+}
+
+#[cfg(test)]
+fn row_is_blank(writer: &Writer, row: usize) -> bool {
+    writer.read_row(row).iter().all(|&b| b == b' ')
+}
+
+#[test_case]
+fn test_scroll_full_screen_of_lines() {
+    use core::fmt::Write;
+
+    with_locked_writer(|writer| {
+        for i in 0..BUFFER_HEIGHT {
+            writeln!(writer, "line {:02}", i).unwrap();
+        }
+
+        // line 00滚出屏幕, line 01位于顶部, 最后一行为光标所在的空行
+        assert!(row_starts_with(writer, 0, "line 01"));
+        for row in 0..BUFFER_HEIGHT - 1 {
+            let mut expected = [0u8; 7];
+            expected.copy_from_slice(b"line 00");
+            expected[5] = b'0' + ((row + 1) / 10) as u8;
+            expected[6] = b'0' + ((row + 1) % 10) as u8;
+            assert_eq!(&writer.read_row(row)[..7], &expected);
+        }
+        assert!(row_is_blank(writer, BUFFER_HEIGHT - 1));
+    });
+}
+
+#[test_case]
+fn test_full_width_line_has_no_blank_row() {
+    with_locked_writer(|writer| {
+        let full = [b'x'; BUFFER_WIDTH];
+        for &b in full.iter() {
+            writer.write_byte(b);
+        }
+        writer.write_string("\nshort\n");
+
+        assert_eq!(writer.read_row(0), full);
+        assert!(row_starts_with(writer, 1, "short"));
+        assert!(row_is_blank(writer, 2));
+    });
+}
+
+#[test_case]
+fn test_scroll_after_full_screen() {
+    with_locked_writer(|writer| {
+        for row in 0..BUFFER_HEIGHT {
+            for _ in 0..BUFFER_WIDTH {
+                writer.write_byte(b'a' + row as u8);
+            }
+        }
+        let last_row = writer.read_row(BUFFER_HEIGHT - 1);
+
+        writer.write_byte(b'!');
+
+        assert_eq!(writer.read_row(BUFFER_HEIGHT - 2), last_row);
+        assert_eq!(writer.read_byte(BUFFER_HEIGHT - 1, 0), b'!');
+        assert!(writer.read_row(BUFFER_HEIGHT - 1)[1..].iter().all(|&b| b == b' '));
+        // 第一行滚出屏幕
+        assert_eq!(writer.read_row(0), [b'b'; BUFFER_WIDTH]);
+    });
+}
+
+#[test_case]
+fn test_backspace() {
+    with_locked_writer(|writer| {
+        writer.write_string("abc\x08\x08d");
+        assert!(row_starts_with(writer, 0, "ad "));
+        // 行首的退格没有效果
+        writer.write_string("\n\x08e");
+        assert!(row_starts_with(writer, 1, "e "));
+    });
+}
+
+#[test_case]
+fn test_rewrite_line() {
+    with_locked_writer(|writer| {
+        writer.write_string("line above\n> hexdump 0 16");
+        writer.rewrite_line("> ps", Some(4));
+        assert!(row_starts_with(writer, 0, "line above "));
+        assert!(row_starts_with(writer, 1, "> ps "));
+        assert!(writer.read_row(1)[4..].iter().all(|&b| b == b' '));
+        let cursor = writer.buffer.chars[1][4].read();
+        assert_eq!(cursor.color_code, writer.color_code.inverted());
+        // 超出一行的部分被截断, 不换行
+        let long = [b'x'; BUFFER_WIDTH + 5];
+        writer.rewrite_line(core::str::from_utf8(&long).unwrap(), None);
+        assert_eq!(writer.read_row(1), [b'x'; BUFFER_WIDTH]);
+        assert!(row_is_blank(writer, 2));
+    });
+}
+
+#[test_case]
+fn test_check_readback_restores_line() {
+    with_locked_writer(|writer| {
+        writer.write_string("line above\n> mem");
+        assert!(writer.check_readback("selftest pattern"));
+        assert!(row_starts_with(writer, 1, "> mem "));
+        // 之后的输出接在原来的位置
+        writer.write_string(" stats");
+        assert!(row_starts_with(writer, 1, "> mem stats "));
+    });
+}
+
+#[test_case]
+fn test_set_color() {
+    with_locked_writer(|writer| {
+        writer.set_color(Color::Yellow, Color::Blue);
+        writer.write_byte(b'x');
+        let cell = writer.buffer.chars[0][0].read();
+        writer.set_color(Color::Green, Color::Black);
+        assert_eq!(cell.color_code, ColorCode::new(Color::Yellow, Color::Blue));
+    });
+}
+
+#[test_case]
+fn test_rewrite_prompt_line() {
+    with_locked_writer(|writer| {
+        let normal = writer.color_code;
+        writer.rewrite_prompt_line("> ", (Color::Yellow, Color::Blue), "ls", Some(3));
+        assert!(row_starts_with(writer, 0, "> ls "));
+        let prompt = ColorCode::new(Color::Yellow, Color::Blue);
+        assert_eq!(writer.read_cell(0, 0).color_code, prompt);
+        assert_eq!(writer.read_cell(0, 2).color_code, normal);
+        assert_eq!(writer.read_cell(0, 3).color_code, normal.inverted());
+        assert_eq!(writer.color_code, normal);
+    });
+}
+
+// 在屏幕上查找`text`, 返回第一个字符的位置
+#[cfg(test)]
+fn find_text(writer: &Writer, text: &str) -> Option<(usize, usize)> {
+    (0..BUFFER_HEIGHT).rev().find_map(|row| {
+        let line = writer.read_row(row);
+        let col = line
+            .windows(text.len())
+            .position(|window| window == text.as_bytes())?;
+        Some((row, col))
+    })
+}
+
+#[test_case]
+fn test_theme_switch() {
+    use crate::log::Level;
+
+    set_theme(Theme::CLASSIC);
+    crate::log!(Level::Warn, "theme test: classic warning");
+    set_theme(Theme::LIGHT);
+    crate::log!(Level::Warn, "theme test: light warning");
+    crate::log!(Level::Error, "theme test: light error");
+    crate::println!("theme test: light normal");
+    let cells = {
+        let writer = writer().lock();
+        ["classic warning", "light warning", "light error", "light normal"].map(|text| {
+            let (row, col) = find_text(&writer, text).expect(text);
+            writer.read_cell(row, col).color_code
+        })
+    };
+    set_theme(Theme::CLASSIC);
+    // 切换之前的输出保持原来的颜色
+    let expected = [
+        (Theme::CLASSIC.warn, Theme::CLASSIC.background),
+        (Theme::LIGHT.warn, Theme::LIGHT.background),
+        (Theme::LIGHT.error, Theme::LIGHT.background),
+        (Theme::LIGHT.normal, Theme::LIGHT.background),
+    ]
+    .map(|(foreground, background)| ColorCode::new(foreground, background));
+    assert_eq!(cells, expected);
+    assert_eq!(theme(), Theme::CLASSIC);
+}
+
+#[test_case]
+fn test_double_buffered_scroll() {
+    with_locked_writer(|writer| {
+        writer.set_double_buffered(true);
+        for row in 0..BUFFER_HEIGHT + 2 {
+            writer.write_byte(b'a' + row as u8);
+            writer.write_byte(b'\n');
+        }
+        writer.write_byte(b'!');
+        // 滚屏后显存与副本一致
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(writer.buffer.chars[row][col].read(), writer.read_cell(row, col));
+            }
+        }
+        assert!(row_starts_with(writer, 0, "d "));
+        assert!(row_starts_with(writer, BUFFER_HEIGHT - 1, "! "));
+        writer.set_double_buffered(false);
+        assert!(row_starts_with(writer, 0, "d "));
+    });
+}
+
+#[test_case]
+fn test_parse_color() {
+    assert_eq!(parse_color("yellow"), Ok(Color::Yellow));
+    assert_eq!(parse_color("LightBlue"), Ok(Color::LightBlue));
+    assert_eq!(parse_color("4"), Ok(Color::Red));
+    assert_eq!(parse_color("0xf"), Ok(Color::White));
+    assert_eq!(parse_color("16"), Err(CmdError::UnknownColor));
+    assert_eq!(parse_color("purple"), Err(CmdError::UnknownColor));
+}
+
+#[test_case]
+fn test_cpu_prefix() {
+    use alloc::string::String;
+    use core::fmt::Write;
+
+    // 前缀只加在行首, 跨多次写入的行只有一个前缀
+    let mut out = String::new();
+    let mut at_line_start = true;
+    let mut prefixed = PrefixWriter {
+        out: &mut out,
+        cpu: Some(2),
+        at_line_start: &mut at_line_start,
+    };
+    write!(prefixed, "a\nb").unwrap();
+    write!(prefixed, "c\n\nd").unwrap();
+    assert_eq!(out, "[cpu2] a\n[cpu2] bc\n[cpu2] \n[cpu2] d");
+    assert!(!at_line_start);
+
+    let transcript = x86_64::instructions::interrupts::without_interrupts(|| {
+        start_recording(256);
+        set_cpu_prefix(true);
+        // 先换行, 保证下一行从行首开始
+        println!();
+        println!("prefixed {}", 1);
+        set_cpu_prefix(false);
+        println!("plain");
+        stop_recording()
+    });
+    assert!(transcript.ends_with("[cpu0] prefixed 1\nplain\n"));
+}
+
+// 整屏滚动的耗时, 每次迭代换行BUFFER_HEIGHT次
+bench_case!(bench_full_screen_scroll, 50, 50_000_000, || {
+    let mut writer = writer().lock();
+    for _ in 0..BUFFER_HEIGHT {
+        writer.new_line();
+    }
+});
\ No newline at end of file
@@ -0,0 +1,544 @@
+//! 虚拟控制台
+//!
+//! 屏幕上同时只显示一个控制台: Alt+F1是外壳, Alt+F2是内核日志, `print!`和日志写到后者.
+//! 每个控制台有自己的屏幕内容、光标、颜色和回滚历史. 前台控制台的Writer直接写显存,
+//! 后台控制台写到自己的内存中, 切换时再把内容复制到显存. Shift+PageUp/PageDown翻看滚出屏幕的行,
+//! 翻看期间前台控制台的输出同样写到内存中, 回到底部时显示出来. Ctrl+L清空前台控制台
+
+use core::fmt;
+
+use super::{Buffer, ColorCode, ScreenChar, Writer, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use crate::keyboard::Hotkey;
+
+/// 每个控制台保留的滚出屏幕的行数
+pub const SCROLLBACK_LINES: usize = 200;
+/// Shift+PageUp/PageDown一次滚动的行数
+pub const SCROLL_STEP: usize = BUFFER_HEIGHT / 2;
+
+/// 虚拟控制台的编号
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleId {
+    /// Alt+F1, 外壳的输入和命令的输出
+    Shell,
+    /// Alt+F2, `print!`和内核日志. 启动时在前台
+    Log,
+}
+
+impl ConsoleId {
+    pub const ALL: [ConsoleId; 2] = [ConsoleId::Shell, ConsoleId::Log];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+// 未使用的内存, 控制台创建时会清空屏幕, 回滚历史只读取写入过的行
+const EMPTY: ScreenChar = ScreenChar {
+    ascii_character: 0,
+    color_code: ColorCode(0),
+};
+
+/// 滚出屏幕顶部的行, 写满后覆盖最旧的
+pub(super) struct Scrollback {
+    lines: &'static mut [Line],
+    // 最旧的一行的位置
+    start: usize,
+    len: usize,
+}
+
+impl Scrollback {
+    fn new(lines: &'static mut [Line]) -> Self {
+        Scrollback {
+            lines,
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub(super) fn push(&mut self, line: Line) {
+        let capacity = self.lines.len();
+        if capacity == 0 {
+            return;
+        }
+        if self.len < capacity {
+            self.lines[(self.start + self.len) % capacity] = line;
+            self.len += 1;
+        } else {
+            self.lines[self.start] = line;
+            self.start = (self.start + 1) % capacity;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    // 第`index`行, 0是最旧的
+    fn line(&self, index: usize) -> &Line {
+        &self.lines[(self.start + index) % self.lines.len()]
+    }
+}
+
+/// 一个虚拟控制台: 屏幕内容、光标和颜色由自己的Writer保存, 回滚历史也在Writer中
+pub struct VirtualConsole {
+    writer: Writer,
+    // 在前台时保存自己的内存; 在后台时Writer就写在这块内存上, 这里为None
+    spare: Option<&'static mut Buffer>,
+}
+
+impl VirtualConsole {
+    fn new(
+        screen: &'static mut Buffer,
+        history: &'static mut [Line],
+        color_code: ColorCode,
+    ) -> Self {
+        let mut writer = Writer {
+            row_position: 0,
+            column_position: 0,
+            color_code,
+            buffer: screen,
+            back: None,
+            history: Some(Scrollback::new(history)),
+        };
+        writer.clear_screen();
+        VirtualConsole {
+            writer,
+            spare: None,
+        }
+    }
+
+    pub fn writer(&self) -> &Writer {
+        &self.writer
+    }
+
+    /// 滚出屏幕的行数, 最多`SCROLLBACK_LINES`
+    pub fn scrollback_len(&self) -> usize {
+        self.writer.history.as_ref().map_or(0, Scrollback::len)
+    }
+
+    // 把屏幕内容移到自己的内存, 返回显存. 调用者需先关闭双缓冲
+    fn detach(&mut self) -> &'static mut Buffer {
+        let memory = self.spare.take().expect("console is not on screen");
+        copy_buffer(self.writer.buffer, memory);
+        core::mem::replace(&mut self.writer.buffer, memory)
+    }
+
+    // 把屏幕内容复制到显存, 之后直接写显存
+    fn attach(&mut self, display: &'static mut Buffer) {
+        assert!(self.spare.is_none(), "console is already on screen");
+        copy_buffer(self.writer.buffer, display);
+        self.spare = Some(core::mem::replace(&mut self.writer.buffer, display));
+    }
+}
+
+fn copy_buffer(from: &Buffer, to: &mut Buffer) {
+    for (from, to) in from.chars.iter().zip(to.chars.iter_mut()) {
+        for (from, to) in from.iter().zip(to.iter_mut()) {
+            to.write(from.read());
+        }
+    }
+}
+
+/// 所有虚拟控制台, 由`vga_buffer::writer()`的锁保护
+///
+/// 解引用为前台控制台的Writer, 见`Consoles::on_screen`
+pub struct Consoles {
+    consoles: [VirtualConsole; ConsoleId::ALL.len()],
+    active: ConsoleId,
+    // 向上翻过的行数
+    view: usize,
+    // 翻看回滚历史时显存不属于任何控制台, 保存在这里
+    display: Option<&'static mut Buffer>,
+    // 前台控制台是否使用双缓冲, 切换时交给新的前台控制台
+    double_buffered: bool,
+}
+
+impl Consoles {
+    // 日志控制台在前台, 接着写显存上已有的内容
+    pub(super) fn new(
+        display: &'static mut Buffer,
+        memory: [(&'static mut Buffer, &'static mut [Line]); 2],
+        color_code: ColorCode,
+    ) -> Self {
+        let [shell, mut log] =
+            memory.map(|(screen, history)| VirtualConsole::new(screen, history, color_code));
+        log.spare = Some(core::mem::replace(&mut log.writer.buffer, display));
+        Consoles {
+            consoles: [shell, log],
+            active: ConsoleId::Log,
+            view: 0,
+            display: None,
+            double_buffered: false,
+        }
+    }
+
+    /// 前台的控制台
+    pub fn active(&self) -> ConsoleId {
+        self.active
+    }
+
+    /// 向上翻过的行数, 0表示显示的是屏幕的最新内容
+    pub fn view(&self) -> usize {
+        self.view
+    }
+
+    pub fn console(&self, id: ConsoleId) -> &VirtualConsole {
+        &self.consoles[id.index()]
+    }
+
+    pub fn writer(&mut self, id: ConsoleId) -> &mut Writer {
+        &mut self.consoles[id.index()].writer
+    }
+
+    /// `id`的输出是否立即显示在屏幕上
+    pub fn on_screen(&self, id: ConsoleId) -> bool {
+        id == self.active && self.view == 0
+    }
+
+    /// 屏幕上显示的一行字符, 翻看回滚历史时是历史中的行
+    pub fn visible_row(&self, row: usize) -> [u8; BUFFER_WIDTH] {
+        core::array::from_fn(|col| self.visible_cell(row, col).ascii_character)
+    }
+
+    pub(super) fn visible_cell(&self, row: usize, col: usize) -> ScreenChar {
+        match &self.display {
+            Some(display) => display.chars[row][col].read(),
+            None => self.console(self.active).writer.read_cell(row, col),
+        }
+    }
+
+    /// 切换到`id`, 恢复它的屏幕内容和光标. 正在翻看回滚历史时回到底部
+    pub fn switch(&mut self, id: ConsoleId) {
+        if self.on_screen(id) {
+            return;
+        }
+        let display = self.take_display();
+        self.active = id;
+        self.view = 0;
+        let console = &mut self.consoles[id.index()];
+        console.attach(display);
+        console.writer.set_double_buffered(self.double_buffered);
+    }
+
+    /// 回到回滚历史的底部
+    pub fn scroll_to_bottom(&mut self) {
+        self.switch(self.active);
+    }
+
+    /// 向上翻看`lines`行, 最多翻到回滚历史的开头
+    pub fn scroll_up(&mut self, lines: usize) {
+        let history = self.console(self.active).scrollback_len();
+        let view = (self.view + lines).min(history);
+        if view == self.view {
+            return;
+        }
+        if self.display.is_none() {
+            self.display = Some(self.take_display());
+        }
+        self.view = view;
+        self.render_view();
+    }
+
+    /// 向下翻看`lines`行, 到底部时恢复前台控制台的显示
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.view == 0 {
+            return;
+        }
+        let view = self.view.saturating_sub(lines);
+        if view == 0 {
+            self.scroll_to_bottom();
+        } else {
+            self.view = view;
+            self.render_view();
+        }
+    }
+
+    /// 清空前台控制台的屏幕
+    pub fn clear(&mut self) {
+        self.scroll_to_bottom();
+        self.writer(self.active).clear_screen();
+    }
+
+    /// 之后所有控制台的输出使用的颜色
+    pub fn set_color(&mut self, foreground: super::Color, background: super::Color) {
+        for console in self.consoles.iter_mut() {
+            console.writer.set_color(foreground, background);
+        }
+    }
+
+    /// 前台控制台的双缓冲, 见`Writer::set_double_buffered`
+    pub fn set_double_buffered(&mut self, enabled: bool) {
+        self.double_buffered = enabled;
+        if self.display.is_none() {
+            let active = self.active;
+            self.writer(active).set_double_buffered(enabled);
+        }
+    }
+
+    // 显存改为影子缓冲区, 只在切换到帧缓冲时调用一次
+    pub(super) fn use_shadow_buffer(&mut self) {
+        self.scroll_to_bottom();
+        let active = self.active;
+        self.writer(active).use_shadow_buffer();
+    }
+
+    /// 执行快捷键
+    pub fn handle(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::Switch(id) => self.switch(id),
+            Hotkey::ScrollUp => self.scroll_up(SCROLL_STEP),
+            Hotkey::ScrollDown => self.scroll_down(SCROLL_STEP),
+            Hotkey::Clear => self.clear(),
+        }
+    }
+
+    // 从前台控制台或翻看的状态取回显存
+    fn take_display(&mut self) -> &'static mut Buffer {
+        if let Some(display) = self.display.take() {
+            return display;
+        }
+        let console = &mut self.consoles[self.active.index()];
+        console.writer.set_double_buffered(false);
+        console.detach()
+    }
+
+    // 回滚历史和屏幕内容连成一列, 显示从底部向上`view`行处结束的一屏
+    fn render_view(&mut self) {
+        let display = self
+            .display
+            .as_mut()
+            .expect("scrollback view without the display");
+        let writer = &self.consoles[self.active.index()].writer;
+        let Some(history) = writer.history.as_ref() else {
+            return;
+        };
+        let first = history.len() - self.view;
+        for (row, cells) in display.chars.iter_mut().enumerate() {
+            let line = first + row;
+            for (col, cell) in cells.iter_mut().enumerate() {
+                let character = if line < history.len() {
+                    history.line(line)[col]
+                } else {
+                    writer.read_cell(line - history.len(), col)
+                };
+                cell.write(character);
+            }
+        }
+    }
+}
+
+impl core::ops::Deref for Consoles {
+    type Target = Writer;
+
+    fn deref(&self) -> &Writer {
+        &self.console(self.active).writer
+    }
+}
+
+impl core::ops::DerefMut for Consoles {
+    fn deref_mut(&mut self) -> &mut Writer {
+        let active = self.active;
+        self.writer(active)
+    }
+}
+
+// 控制台的内存, 只在`vga_buffer::init`中取用一次
+static mut SCREENS: [[Line; BUFFER_HEIGHT]; 2] = [[[EMPTY; BUFFER_WIDTH]; BUFFER_HEIGHT]; 2];
+static mut HISTORY: [[Line; SCROLLBACK_LINES]; 2] = [[[EMPTY; BUFFER_WIDTH]; SCROLLBACK_LINES]; 2];
+
+pub(super) fn memory() -> [(&'static mut Buffer, &'static mut [Line]); 2] {
+    let screens = unsafe { &mut *core::ptr::addr_of_mut!(SCREENS) };
+    let history = unsafe { &mut *core::ptr::addr_of_mut!(HISTORY) };
+    let [shell_screen, log_screen] = screens;
+    let [shell_history, log_history] = history;
+    [
+        (super::memory_buffer(shell_screen), shell_history),
+        (super::memory_buffer(log_screen), log_history),
+    ]
+}
+
+/// 执行快捷键, 由键盘中断调用
+pub fn handle_hotkey(hotkey: Hotkey) {
+    match hotkey {
+        // 帧缓冲也要清空
+        Hotkey::Clear => super::clear_screen(),
+        hotkey => update(|consoles| consoles.handle(hotkey)),
+    }
+}
+
+/// 切换到`id`, 外壳启动时切换到自己的控制台
+pub fn switch(id: ConsoleId) {
+    update(|consoles| consoles.switch(id));
+}
+
+/// 回到回滚历史的底部, 除快捷键以外的按键都会调用
+pub fn scroll_to_bottom() {
+    update(|consoles| {
+        if consoles.view() != 0 {
+            consoles.scroll_to_bottom();
+        }
+    });
+}
+
+/// panic时显示日志控制台. 锁正被持有时放弃, 帧缓冲的锁也可能正被持有, 不重绘
+pub fn on_panic() {
+    if let Ok(writer) = WRITER.try_get() {
+        if let Some(mut consoles) = writer.try_lock() {
+            consoles.switch(ConsoleId::Log);
+        }
+    }
+}
+
+// 屏幕上的内容变了时重绘帧缓冲
+fn update(f: impl FnOnce(&mut Consoles)) {
+    let Ok(writer) = WRITER.try_get() else {
+        return;
+    };
+    let mut consoles = writer.lock();
+    let before = (consoles.active(), consoles.view());
+    f(&mut consoles);
+    if super::backend() == super::Backend::Framebuffer
+        && before != (consoles.active(), consoles.view())
+    {
+        super::repaint_framebuffer(&consoles);
+    }
+}
+
+/// 输出到指定的控制台, 与`print!`一样写入内核日志并按`console=`输出到串口
+pub struct Output(pub ConsoleId);
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        super::print_colored(self.0, None, format_args!("{}", s));
+        Ok(())
+    }
+}
+
+// 测试用的控制台, 显存也是内存. 测试依次运行, 同一时刻只有一组
+#[cfg(test)]
+pub(crate) fn test_consoles() -> Consoles {
+    const HISTORY_LINES: usize = 32;
+    static mut DISPLAY: [Line; BUFFER_HEIGHT] = [[EMPTY; BUFFER_WIDTH]; BUFFER_HEIGHT];
+    static mut SCREENS: [[Line; BUFFER_HEIGHT]; 2] = [[[EMPTY; BUFFER_WIDTH]; BUFFER_HEIGHT]; 2];
+    static mut HISTORY: [[Line; HISTORY_LINES]; 2] = [[[EMPTY; BUFFER_WIDTH]; HISTORY_LINES]; 2];
+
+    let display = unsafe { &mut *core::ptr::addr_of_mut!(DISPLAY) };
+    let [shell_screen, log_screen] = unsafe { &mut *core::ptr::addr_of_mut!(SCREENS) };
+    let [shell_history, log_history] = unsafe { &mut *core::ptr::addr_of_mut!(HISTORY) };
+    let color_code = ColorCode::new(super::Color::LightGray, super::Color::Black);
+    let display = super::memory_buffer(display);
+    let mut consoles = Consoles::new(
+        display,
+        [
+            (super::memory_buffer(shell_screen), shell_history),
+            (super::memory_buffer(log_screen), log_history),
+        ],
+        color_code,
+    );
+    consoles.clear();
+    consoles
+}
+
+#[cfg(test)]
+fn visible_starts_with(consoles: &Consoles, row: usize, prefix: &str) -> bool {
+    consoles.visible_row(row).starts_with(prefix.as_bytes())
+}
+
+#[test_case]
+fn test_log_output_behind_shell() {
+    use core::fmt::Write;
+
+    let mut consoles = test_consoles();
+    assert_eq!(consoles.active(), ConsoleId::Log);
+    consoles.writer(ConsoleId::Log).write_string("boot log\n");
+    consoles.switch(ConsoleId::Shell);
+    assert_eq!(consoles.active(), ConsoleId::Shell);
+    // 外壳的控制台是空的, 日志没有带过来
+    assert!(consoles.visible_row(0).iter().all(|&b| b == b' '));
+    consoles.writer(ConsoleId::Shell).write_string("> ls");
+    let before: [[u8; BUFFER_WIDTH]; BUFFER_HEIGHT] =
+        core::array::from_fn(|row| consoles.visible_row(row));
+
+    for i in 0..BUFFER_HEIGHT + 5 {
+        writeln!(consoles.writer(ConsoleId::Log), "log line {:02}", i).unwrap();
+    }
+    // 后台的日志不影响屏幕
+    let after: [[u8; BUFFER_WIDTH]; BUFFER_HEIGHT] =
+        core::array::from_fn(|row| consoles.visible_row(row));
+    assert_eq!(after, before);
+
+    // 切换回来后看到积累的日志, 光标在最后一行
+    consoles.switch(ConsoleId::Log);
+    assert!(visible_starts_with(&consoles, 0, "log line 06"));
+    assert!(visible_starts_with(
+        &consoles,
+        BUFFER_HEIGHT - 2,
+        "log line 29"
+    ));
+    assert_eq!(consoles.console(ConsoleId::Log).scrollback_len(), 7);
+    consoles.writer(ConsoleId::Log).write_string("tail");
+    assert!(visible_starts_with(&consoles, BUFFER_HEIGHT - 1, "tail "));
+
+    // 外壳的屏幕和光标都恢复了
+    consoles.switch(ConsoleId::Shell);
+    assert!(visible_starts_with(&consoles, 0, "> ls "));
+    consoles.writer(ConsoleId::Shell).write_string(" -l");
+    assert!(visible_starts_with(&consoles, 0, "> ls -l "));
+}
+
+#[test_case]
+fn test_scrollback_view() {
+    use core::fmt::Write;
+
+    let mut consoles = test_consoles();
+    for i in 0..40 {
+        writeln!(consoles.writer(ConsoleId::Log), "line {:02}", i).unwrap();
+    }
+    // line 00到line 15滚出了屏幕
+    assert!(visible_starts_with(&consoles, 0, "line 16"));
+    consoles.handle(Hotkey::ScrollUp);
+    assert_eq!(consoles.view(), SCROLL_STEP);
+    assert!(visible_starts_with(&consoles, 0, "line 04"));
+    assert!(visible_starts_with(&consoles, SCROLL_STEP, "line 16"));
+    assert!(!consoles.on_screen(ConsoleId::Log));
+
+    // 翻看期间的输出不改变屏幕
+    consoles.writer(ConsoleId::Log).write_string("new\n");
+    assert!(visible_starts_with(&consoles, 0, "line 04"));
+    consoles.scroll_up(100);
+    assert_eq!(consoles.view(), 17);
+    assert!(visible_starts_with(&consoles, 0, "line 00"));
+
+    consoles.handle(Hotkey::ScrollDown);
+    assert!(visible_starts_with(&consoles, 0, "line 12"));
+    consoles.scroll_down(100);
+    assert_eq!(consoles.view(), 0);
+    assert!(visible_starts_with(&consoles, BUFFER_HEIGHT - 2, "new "));
+    consoles.writer(ConsoleId::Log).write_string("more");
+    assert!(visible_starts_with(&consoles, BUFFER_HEIGHT - 1, "more "));
+
+    // 切换控制台也回到底部
+    consoles.scroll_up(3);
+    consoles.switch(ConsoleId::Log);
+    assert_eq!(consoles.view(), 0);
+    consoles.handle(Hotkey::Clear);
+    assert!((0..BUFFER_HEIGHT).all(|row| consoles.visible_row(row).iter().all(|&b| b == b' ')));
+}
+
+#[test_case]
+fn test_double_buffering_follows_active_console() {
+    let mut consoles = test_consoles();
+    consoles.set_double_buffered(true);
+    assert!(consoles.console(ConsoleId::Log).writer.back.is_some());
+    consoles.switch(ConsoleId::Shell);
+    assert!(consoles.console(ConsoleId::Log).writer.back.is_none());
+    assert!(consoles.console(ConsoleId::Shell).writer.back.is_some());
+    consoles.writer(ConsoleId::Shell).write_string("> ");
+    assert!(visible_starts_with(&consoles, 0, "> "));
+    consoles.scroll_up(1);
+    consoles.switch(ConsoleId::Log);
+    consoles.set_double_buffered(false);
+    assert!(consoles.console(ConsoleId::Log).writer.back.is_none());
+}
@@ -0,0 +1,83 @@
+//! 文本模式字符集(代码页437)与Unicode的对应
+//!
+//! 载入的字体带有Unicode表时按字体的表对应, 见`vga_font::load`
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::IrqMutex;
+
+// 0x80到0xFF依次对应的字符
+const UPPER_HALF: &str = "ÇüéâäàåçêëèïîìÄÅ\
+                          ÉæÆôöòûùÿÖÜ¢£¥₧ƒ\
+                          áíóúñÑªº¿⌐¬½¼¡«»\
+                          ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+                          └┴┬├─┼╞╟╚╔╩╦╠═╬╧\
+                          ╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+                          αßΓπΣσµτΦΘΩδ∞φε∩\
+                          ≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// 可显示的ASCII字符原样对应, 其他字符在表中查找, 没有对应时返回None
+pub fn from_char(c: char) -> Option<u8> {
+    if c == ' ' || c.is_ascii_graphic() {
+        return Some(c as u8);
+    }
+    let index = UPPER_HALF.chars().position(|upper| upper == c)?;
+    Some(0x80 + index as u8)
+}
+
+// 当前字体的Unicode表, 按字符排序
+static FONT_TABLE: IrqMutex<Vec<(char, u8)>> = IrqMutex::new("cp437::FONT_TABLE", Vec::new());
+// 使用FONT_TABLE而不是代码页437, 没有载入字体时输出不加锁
+static FONT_AWARE: AtomicBool = AtomicBool::new(false);
+
+/// 改为按`table`中(字符, 字形)的对应输出, None恢复代码页437. 同一字符对应多个字形时使用第一个
+pub fn set_font_table(table: Option<&[(char, u8)]>) {
+    let mut font_table = FONT_TABLE.lock();
+    font_table.clear();
+    if let Some(table) = table {
+        font_table.extend_from_slice(table);
+        // 稳定排序保留了每个字符的第一个字形
+        font_table.sort_by_key(|&(c, _)| c);
+        font_table.dedup_by_key(|&mut (c, _)| c);
+    }
+    FONT_AWARE.store(table.is_some(), Ordering::Release);
+}
+
+/// 字符在当前字体中的字形, 没有对应时返回None
+pub fn translate(c: char) -> Option<u8> {
+    if !FONT_AWARE.load(Ordering::Acquire) {
+        return from_char(c);
+    }
+    let table = FONT_TABLE.lock();
+    let index = table.binary_search_by_key(&c, |&(c, _)| c).ok()?;
+    Some(table[index].1)
+}
+
+#[test_case]
+fn test_cp437() {
+    assert_eq!(UPPER_HALF.chars().count(), 128);
+    assert_eq!(from_char('a'), Some(b'a'));
+    assert_eq!(from_char('é'), Some(0x82));
+    assert_eq!(from_char('É'), Some(0x90));
+    assert_eq!(from_char('ß'), Some(0xE1));
+    assert_eq!(from_char('\u{a0}'), Some(0xFF));
+    assert_eq!(from_char('€'), None);
+    assert_eq!(from_char('\t'), None);
+}
+
+#[test_case]
+fn test_font_table() {
+    // 结束时恢复原来的表
+    let aware = FONT_AWARE.load(Ordering::Acquire);
+    let saved = FONT_TABLE.lock().clone();
+    set_font_table(Some(&[('→', 0x1A), ('a', 0x61), ('é', 0x82), ('a', 0x41)]));
+    assert_eq!(translate('→'), Some(0x1A));
+    assert_eq!(translate('a'), Some(0x61));
+    assert_eq!(translate('é'), Some(0x82));
+    assert_eq!(translate('b'), None);
+    set_font_table(None);
+    assert_eq!(translate('b'), Some(b'b'));
+    assert_eq!(translate('→'), None);
+    set_font_table(aware.then_some(&saved[..]));
+}
@@ -0,0 +1,111 @@
+//! 控制台配色. 日志、外壳和panic信息按用途取颜色, 切换主题后之后的输出随之改变
+
+use super::Color;
+
+/// 颜色的用途
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// 普通输出
+    Normal,
+    /// 外壳的提示符
+    Prompt,
+    /// 状态信息, 例如启动横幅的标题
+    Status,
+    Info,
+    Warn,
+    Error,
+    /// panic信息, 使用单独的背景色
+    Panic,
+}
+
+/// 各用途的前景色, 除panic外共用同一背景色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub name: &'static str,
+    pub background: Color,
+    pub normal: Color,
+    pub prompt: Color,
+    pub status: Color,
+    pub info: Color,
+    pub warn: Color,
+    pub error: Color,
+    pub panic_fg: Color,
+    pub panic_bg: Color,
+}
+
+impl Theme {
+    /// 黑底绿字, 默认主题
+    pub const CLASSIC: Theme = Theme {
+        name: "classic",
+        background: Color::Black,
+        normal: Color::Green,
+        prompt: Color::LightGreen,
+        status: Color::LightCyan,
+        info: Color::White,
+        warn: Color::Yellow,
+        error: Color::LightRed,
+        panic_fg: Color::White,
+        panic_bg: Color::Red,
+    };
+
+    /// 浅灰底黑字. 文本模式的背景色只能使用前8种颜色
+    pub const LIGHT: Theme = Theme {
+        name: "light",
+        background: Color::LightGray,
+        normal: Color::Black,
+        prompt: Color::Blue,
+        status: Color::Magenta,
+        info: Color::DarkGray,
+        warn: Color::Brown,
+        error: Color::Red,
+        panic_fg: Color::Yellow,
+        panic_bg: Color::Blue,
+    };
+
+    /// 内置的主题
+    pub const BUILTIN: [Theme; 2] = [Theme::CLASSIC, Theme::LIGHT];
+
+    /// 按名称查找内置主题, 不区分大小写
+    pub fn by_name(name: &str) -> Option<Theme> {
+        Theme::BUILTIN
+            .into_iter()
+            .find(|theme| theme.name.eq_ignore_ascii_case(name))
+    }
+
+    /// `role`使用的前景色和背景色
+    pub fn colors(&self, role: Role) -> (Color, Color) {
+        let foreground = match role {
+            Role::Normal => self.normal,
+            Role::Prompt => self.prompt,
+            Role::Status => self.status,
+            Role::Info => self.info,
+            Role::Warn => self.warn,
+            Role::Error => self.error,
+            Role::Panic => return (self.panic_fg, self.panic_bg),
+        };
+        (foreground, self.background)
+    }
+}
+
+#[test_case]
+fn test_theme_lookup() {
+    assert_eq!(Theme::by_name("Light"), Some(Theme::LIGHT));
+    assert_eq!(Theme::by_name("classic"), Some(Theme::CLASSIC));
+    assert_eq!(Theme::by_name("solarized"), None);
+    assert_eq!(
+        Theme::CLASSIC.colors(Role::Normal),
+        (Color::Green, Color::Black)
+    );
+    assert_eq!(
+        Theme::LIGHT.colors(Role::Warn),
+        (Color::Brown, Color::LightGray)
+    );
+    assert_eq!(
+        Theme::LIGHT.colors(Role::Panic),
+        (Color::Yellow, Color::Blue)
+    );
+    // 文本模式的背景色没有闪烁位
+    for theme in Theme::BUILTIN {
+        assert!((theme.background as u8) < 8 && (theme.panic_bg as u8) < 8);
+    }
+}
@@ -0,0 +1,360 @@
+//! VGA文本模式的字体
+//!
+//! 字形存放在显存第2平面的字体RAM中, 每个字形占32行. 写入时暂时让定序器和图形控制器按平面顺序访问0xA0000,
+//! 写完后恢复原来的寄存器值. 屏幕的行数是400条扫描线除以字体的行数, 8行的字体得到80x50,
+//! 控制台仍然只使用前25行
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::bootinfo;
+use crate::error::KernelError;
+use crate::io::{HardwareBus, PortBus};
+use crate::shell::{self, CmdError, CommandFuture};
+use crate::vfs;
+use crate::vga_buffer::{self, cp437, Backend, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+pub mod psf;
+
+pub use psf::{Font, PsfError};
+
+const SEQ_INDEX: u16 = 0x3C4;
+const GC_INDEX: u16 = 0x3CE;
+const CRTC_INDEX: u16 = 0x3D4;
+
+const SEQ_RESET: u8 = 0x00;
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+const GC_READ_MAP: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+const CRTC_MAX_SCAN_LINE: u8 = 0x09;
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+// 这三个寄存器的低5位是扫描线
+const SCAN_LINE_MASK: u8 = 0x1F;
+
+// 定序器复位时停止刷新显存, 修改寄存器期间的显存内容不受影响
+const SEQ_SYNC_RESET: u8 = 0x01;
+const SEQ_RUNNING: u8 = 0x03;
+
+const FONT_RAM: u64 = 0xA0000;
+const TEXT_MEMORY: u64 = 0xB8000;
+const SLOT_HEIGHT: usize = 32;
+const SCAN_LINES: usize = 400;
+// 空格, 浅灰色前景
+const BLANK_CELL: u16 = 0x0720;
+
+/// 字体文件所在的目录, 其中的`<名称>.psf`可以用名称选择
+pub const FONT_DIR: &str = "/etc/fonts";
+/// 80x25需要最多16行的字形
+pub const MAX_TEXT_HEIGHT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    Psf(PsfError),
+    Vfs(KernelError),
+    /// 控制台在帧缓冲上, 没有字体RAM
+    NotTextMode,
+    /// 字形的行数超过`MAX_TEXT_HEIGHT`, 屏幕容不下25行
+    TooTall(usize),
+}
+
+impl From<PsfError> for FontError {
+    fn from(err: PsfError) -> Self {
+        FontError::Psf(err)
+    }
+}
+
+impl From<KernelError> for FontError {
+    fn from(err: KernelError) -> Self {
+        FontError::Vfs(err)
+    }
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::Psf(err) => write!(f, "{}", err),
+            FontError::Vfs(err) => write!(f, "{}", err),
+            FontError::NotTextMode => write!(f, "the console is not in VGA text mode"),
+            FontError::TooTall(height) => write!(
+                f,
+                "{}-line glyphs do not fit 25 rows, at most {}",
+                height, MAX_TEXT_HEIGHT
+            ),
+        }
+    }
+}
+
+// 当前字体的名称, 还没有载入过字体时为None
+static ACTIVE: Mutex<Option<String>> = Mutex::new(None);
+
+fn read_register(bus: &mut impl PortBus, index_port: u16, index: u8) -> u8 {
+    bus.write_u8(index_port, index);
+    bus.read_u8(index_port + 1)
+}
+
+fn write_register(bus: &mut impl PortBus, index_port: u16, index: u8, value: u8) {
+    bus.write_u8(index_port, index);
+    bus.write_u8(index_port + 1, value);
+}
+
+/// 在`f`执行期间把0xA0000映射到第2平面并关闭奇偶寻址, 之后恢复原来的设置
+pub fn with_plane2<B: PortBus, R>(bus: &mut B, f: impl FnOnce() -> R) -> R {
+    let map_mask = read_register(bus, SEQ_INDEX, SEQ_MAP_MASK);
+    let memory_mode = read_register(bus, SEQ_INDEX, SEQ_MEMORY_MODE);
+    let read_map = read_register(bus, GC_INDEX, GC_READ_MAP);
+    let mode = read_register(bus, GC_INDEX, GC_MODE);
+    let misc = read_register(bus, GC_INDEX, GC_MISC);
+
+    write_register(bus, SEQ_INDEX, SEQ_RESET, SEQ_SYNC_RESET);
+    // 只写第2平面, 按顺序寻址
+    write_register(bus, SEQ_INDEX, SEQ_MAP_MASK, 0x04);
+    write_register(bus, SEQ_INDEX, SEQ_MEMORY_MODE, 0x07);
+    write_register(bus, SEQ_INDEX, SEQ_RESET, SEQ_RUNNING);
+    // 读第2平面, 显存映射到0xA0000-0xAFFFF
+    write_register(bus, GC_INDEX, GC_READ_MAP, 0x02);
+    write_register(bus, GC_INDEX, GC_MODE, 0x00);
+    write_register(bus, GC_INDEX, GC_MISC, 0x04);
+
+    let result = f();
+
+    write_register(bus, SEQ_INDEX, SEQ_RESET, SEQ_SYNC_RESET);
+    write_register(bus, SEQ_INDEX, SEQ_MAP_MASK, map_mask);
+    write_register(bus, SEQ_INDEX, SEQ_MEMORY_MODE, memory_mode);
+    write_register(bus, SEQ_INDEX, SEQ_RESET, SEQ_RUNNING);
+    write_register(bus, GC_INDEX, GC_READ_MAP, read_map);
+    write_register(bus, GC_INDEX, GC_MODE, mode);
+    write_register(bus, GC_INDEX, GC_MISC, misc);
+    result
+}
+
+/// 设置每个字符的扫描线数, 光标放在字符底部倒数第3到第2行, 与BIOS在16行时的形状相同
+pub fn set_char_height(bus: &mut impl PortBus, height: usize) {
+    let lines = [
+        (CRTC_MAX_SCAN_LINE, height - 1),
+        (CRTC_CURSOR_START, height.saturating_sub(3)),
+        (CRTC_CURSOR_END, height.saturating_sub(2)),
+    ];
+    for (index, line) in lines {
+        let value = read_register(bus, CRTC_INDEX, index);
+        write_register(
+            bus,
+            CRTC_INDEX,
+            index,
+            (value & !SCAN_LINE_MASK) | line as u8,
+        );
+    }
+}
+
+/// 把`font`写入字体RAM并按它的行数设置屏幕. 字体带有Unicode表时之后的输出按表转换字符, 否则按代码页437
+pub fn load(font: &Font) -> Result<(), FontError> {
+    if vga_buffer::backend() != Backend::VgaText {
+        return Err(FontError::NotTextMode);
+    }
+    if font.height() > MAX_TEXT_HEIGHT {
+        return Err(FontError::TooTall(font.height()));
+    }
+    let offset = bootinfo::physical_memory_offset();
+    let font_ram = (offset + FONT_RAM).as_mut_ptr::<u8>();
+    // 持有控制台的锁(同时关中断), 切换平面期间没有输出写到文本显存
+    let _console = vga_buffer::writer().lock();
+    let mut bus = unsafe { HardwareBus::new() };
+    with_plane2(&mut bus, || {
+        for index in 0..=u8::MAX {
+            let glyph = font.glyph(index);
+            for row in 0..SLOT_HEIGHT {
+                let bits = glyph.get(row).copied().unwrap_or(0);
+                // 字体RAM在整个物理内存的映射之内
+                unsafe {
+                    font_ram
+                        .add(usize::from(index) * SLOT_HEIGHT + row)
+                        .write_volatile(bits)
+                };
+            }
+        }
+    });
+    set_char_height(&mut bus, font.height());
+    blank_extra_rows(offset, SCAN_LINES / font.height());
+    cp437::set_font_table(font.unicode());
+    *ACTIVE.lock() = Some(font.name.clone());
+    Ok(())
+}
+
+// 控制台之外的行不会被写入, 清空以免显示显存中的残留内容
+fn blank_extra_rows(offset: VirtAddr, rows: usize) {
+    let text = (offset + TEXT_MEMORY).as_mut_ptr::<u16>();
+    for cell in BUFFER_HEIGHT * BUFFER_WIDTH..rows * BUFFER_WIDTH {
+        // 80x50只用到显存的前8000字节
+        unsafe { text.add(cell).write_volatile(BLANK_CELL) };
+    }
+}
+
+/// 读取并解析`path`处的字体文件
+pub async fn read(path: &str, name: &str) -> Result<Font, FontError> {
+    let mut file = vfs::open(path).await?;
+    let mut data = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        match file.read(&mut buf).await? {
+            0 => break,
+            len => data.extend_from_slice(&buf[..len]),
+        }
+    }
+    Ok(Font::parse(name, &data)?)
+}
+
+/// 按名称找到字体: 内置的`default`(8x16)和`default8`(8x8), 或者`FONT_DIR`中的`<名称>.psf`
+pub async fn find(name: &str) -> Result<Font, FontError> {
+    match name {
+        "default" => Ok(Font::builtin(16)),
+        "default8" => Ok(Font::builtin(8)),
+        name => read(&format!("{}/{}.psf", FONT_DIR, name), name).await,
+    }
+}
+
+/// 当前字体的名称, 还没有载入过字体(仍是BIOS的字体)时为None
+pub fn active() -> Option<String> {
+    ACTIVE.lock().clone()
+}
+
+/// 注册`font`命令
+pub fn register_commands() {
+    shell::register_async_command(
+        "font",
+        "font [name]: list console fonts or load one",
+        font_command,
+    )
+    .expect("duplicate font command");
+}
+
+// 不带参数时列出内置字体和字体目录中的文件, 当前的字体标有`*`
+fn font_command<'a>(
+    args: &'a [&'a str],
+    out: &'a mut (dyn fmt::Write + Send),
+) -> CommandFuture<'a> {
+    Box::pin(async move {
+        match args {
+            [] => {
+                let active = active();
+                let mut names = alloc::vec![String::from("default"), String::from("default8")];
+                // 没有字体目录时只有内置字体
+                if let Ok(entries) = vfs::readdir(FONT_DIR).await {
+                    names.extend(entries.into_iter().filter_map(|entry| {
+                        let name = entry.name.strip_suffix(".psf")?;
+                        (!entry.metadata.is_dir()).then(|| name.into())
+                    }));
+                }
+                for name in names {
+                    let marker = if active.as_ref() == Some(&name) {
+                        '*'
+                    } else {
+                        ' '
+                    };
+                    writeln!(out, "{} {}", marker, name)?;
+                }
+                Ok(())
+            }
+            [name] => {
+                load(&find(name).await?)?;
+                Ok(())
+            }
+            _ => Err(CmdError::Usage("font [name]")),
+        }
+    })
+}
+
+#[test_case]
+fn test_plane2_sequence() {
+    use crate::io::mock::{MockBus, PortWrite::U8};
+
+    let mut bus = MockBus::new();
+    // 文本模式下的原值
+    bus.respond(0x3C5, &[0x03, 0x02])
+        .respond(0x3CF, &[0x00, 0x10, 0x0E]);
+    let mut called = false;
+    with_plane2(&mut bus, || called = true);
+    assert!(called);
+    assert!(bus.finished());
+    let index_and_data = |port: u16, index: u8, value: u8| [U8(port, index), U8(port + 1, value)];
+    let mut expected = Vec::new();
+    for (port, index) in [(0x3C4, 2), (0x3C4, 4), (0x3CE, 4), (0x3CE, 5), (0x3CE, 6)] {
+        expected.push(U8(port, index));
+    }
+    let registers = [
+        // 切换到第2平面
+        (0x3C4, 0, 0x01),
+        (0x3C4, 2, 0x04),
+        (0x3C4, 4, 0x07),
+        (0x3C4, 0, 0x03),
+        (0x3CE, 4, 0x02),
+        (0x3CE, 5, 0x00),
+        (0x3CE, 6, 0x04),
+        // 恢复
+        (0x3C4, 0, 0x01),
+        (0x3C4, 2, 0x03),
+        (0x3C4, 4, 0x02),
+        (0x3C4, 0, 0x03),
+        (0x3CE, 4, 0x00),
+        (0x3CE, 5, 0x10),
+        (0x3CE, 6, 0x0E),
+    ];
+    for (port, index, value) in registers {
+        expected.extend(index_and_data(port, index, value));
+    }
+    assert_eq!(bus.writes(), expected);
+
+    // 8行的字体, 保留寄存器的高3位
+    let mut bus = MockBus::new();
+    bus.respond(0x3D5, &[0x4F, 0x2D, 0x0E]);
+    set_char_height(&mut bus, 8);
+    assert_eq!(
+        bus.writes(),
+        [
+            U8(0x3D4, 0x09),
+            U8(0x3D4, 0x09),
+            U8(0x3D5, 0x47),
+            U8(0x3D4, 0x0A),
+            U8(0x3D4, 0x0A),
+            U8(0x3D5, 0x25),
+            U8(0x3D4, 0x0B),
+            U8(0x3D4, 0x0B),
+            U8(0x3D5, 0x06),
+        ]
+    );
+}
+
+#[test_case]
+fn test_load_default_font() {
+    if vga_buffer::backend() != Backend::VgaText {
+        return;
+    }
+    let rows = |console: &vga_buffer::console::Consoles| -> Vec<[u8; BUFFER_WIDTH]> {
+        (0..BUFFER_HEIGHT)
+            .map(|row| console.read_row(row))
+            .collect()
+    };
+    // 32行的字体能解析, 但屏幕容不下
+    let mut tall = alloc::vec![0x36, 0x04, 0, 32];
+    tall.resize(4 + 256 * 32, 0);
+    let tall = Font::parse("tall", &tall).unwrap();
+    assert_eq!(load(&tall), Err(FontError::TooTall(32)));
+
+    let before = rows(&vga_buffer::writer().lock());
+    assert_eq!(load(&Font::builtin(16)), Ok(()));
+    assert_eq!(active().as_deref(), Some("default"));
+    // 换字体不改变文本显存的内容, 之后的输出照常写入
+    assert_eq!(rows(&vga_buffer::writer().lock()), before);
+    assert!(vga_buffer::writer().lock().check_readback("font: readback"));
+    // 内置字体只有ASCII
+    assert_eq!(cp437::translate('é'), None);
+    assert_eq!(cp437::translate('A'), Some(b'A'));
+    cp437::set_font_table(None);
+}
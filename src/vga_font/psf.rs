@@ -0,0 +1,417 @@
+//! PSF1和PSF2格式的点阵字体
+//!
+//! 文本模式的字形宽8像素, 每行一个字节, 最高位是最左边的像素. 只使用前256个字形
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::framebuffer::font as builtin;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER_LEN: usize = 32;
+const PSF2_HAS_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+/// 载入到字体RAM的字形数
+pub const GLYPHS: usize = 256;
+/// 字体RAM中每个字形占32行
+pub const MAX_HEIGHT: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    BadMagic,
+    /// 文件在头部、字形或Unicode表的中途结束
+    Truncated,
+    UnsupportedVersion(u32),
+    /// 文本模式只能显示8像素宽、最多32行的字形
+    GlyphSize {
+        width: u32,
+        height: u32,
+    },
+    /// PSF2头部的字形字节数与宽高不符
+    GlyphBytes(u32),
+    /// 不足256个字形
+    GlyphCount(u32),
+    /// 不是有效的码点或UTF-8
+    BadUnicodeTable,
+}
+
+impl fmt::Display for PsfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PsfError::BadMagic => write!(f, "not a PSF1 or PSF2 font"),
+            PsfError::Truncated => write!(f, "font file is truncated"),
+            PsfError::UnsupportedVersion(version) => {
+                write!(f, "unsupported PSF2 version {}", version)
+            }
+            PsfError::GlyphSize { width, height } => {
+                write!(
+                    f,
+                    "{}x{} glyphs, text mode needs 8 pixels wide and at most 32 high",
+                    width, height
+                )
+            }
+            PsfError::GlyphBytes(bytes) => {
+                write!(f, "{} bytes per glyph do not match the size", bytes)
+            }
+            PsfError::GlyphCount(count) => write!(f, "{} glyphs, at least 256 needed", count),
+            PsfError::BadUnicodeTable => write!(f, "malformed unicode table"),
+        }
+    }
+}
+
+/// 256个8像素宽的字形, 以及可选的Unicode表
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Font {
+    pub name: String,
+    height: usize,
+    // GLYPHS个字形, 每个height字节
+    glyphs: Vec<u8>,
+    // (字符, 字形), 只包含前256个字形
+    unicode: Option<Vec<(char, u8)>>,
+}
+
+// 按顺序读取文件内容, 越界时返回Truncated
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PsfError> {
+        if len > self.data.len() {
+            return Err(PsfError::Truncated);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, PsfError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PsfError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, PsfError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+impl Font {
+    /// 解析PSF1或PSF2文件
+    pub fn parse(name: &str, data: &[u8]) -> Result<Font, PsfError> {
+        if data.starts_with(&PSF1_MAGIC) {
+            Font::parse_psf1(name, data)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            Font::parse_psf2(name, data)
+        } else {
+            Err(PsfError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(name: &str, data: &[u8]) -> Result<Font, PsfError> {
+        let mut reader = Reader { data };
+        reader.take(PSF1_MAGIC.len())?;
+        let mode = reader.u8()?;
+        let height = reader.u8()?;
+        if !(1..=MAX_HEIGHT as u8).contains(&height) {
+            return Err(PsfError::GlyphSize {
+                width: 8,
+                height: u32::from(height),
+            });
+        }
+        let height = usize::from(height);
+        let count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyphs = reader.take(count * height)?;
+        let unicode = if mode & PSF1_MODE_HAS_TABLE != 0 {
+            let mut table = Vec::new();
+            for glyph in 0..count {
+                let mut in_sequence = false;
+                loop {
+                    match reader.u16()? {
+                        PSF1_SEPARATOR => break,
+                        PSF1_START_SEQUENCE => in_sequence = true,
+                        // 组合序列不能用一个字形显示
+                        _ if in_sequence => {}
+                        value => {
+                            let c = char::from_u32(u32::from(value))
+                                .ok_or(PsfError::BadUnicodeTable)?;
+                            push_mapping(&mut table, c, glyph);
+                        }
+                    }
+                }
+            }
+            Some(table)
+        } else {
+            None
+        };
+        Ok(Font::new(name, height, &glyphs[..GLYPHS * height], unicode))
+    }
+
+    fn parse_psf2(name: &str, data: &[u8]) -> Result<Font, PsfError> {
+        let mut reader = Reader { data };
+        reader.take(PSF2_MAGIC.len())?;
+        let version = reader.u32()?;
+        if version != 0 {
+            return Err(PsfError::UnsupportedVersion(version));
+        }
+        let header_len = reader.u32()? as usize;
+        let flags = reader.u32()?;
+        let count = reader.u32()?;
+        let bytes_per_glyph = reader.u32()?;
+        let height = reader.u32()?;
+        let width = reader.u32()?;
+        if width != 8 || !(1..=MAX_HEIGHT as u32).contains(&height) {
+            return Err(PsfError::GlyphSize { width, height });
+        }
+        let height = height as usize;
+        if bytes_per_glyph as usize != height {
+            return Err(PsfError::GlyphBytes(bytes_per_glyph));
+        }
+        if (count as usize) < GLYPHS {
+            return Err(PsfError::GlyphCount(count));
+        }
+        // 头部可能比已知的字段长
+        let mut reader = Reader { data };
+        reader.take(header_len.max(PSF2_HEADER_LEN))?;
+        let glyphs = reader.take(
+            (count as usize)
+                .checked_mul(height)
+                .ok_or(PsfError::Truncated)?,
+        )?;
+        let unicode = if flags & PSF2_HAS_TABLE != 0 {
+            let mut table = Vec::new();
+            for glyph in 0..count as usize {
+                let entry = reader
+                    .data
+                    .iter()
+                    .position(|&byte| byte == PSF2_SEPARATOR)
+                    .ok_or(PsfError::Truncated)?;
+                let entry = reader.take(entry + 1)?;
+                // 组合序列从0xFE开始, 只取之前的单个字符
+                let single = match entry.iter().position(|&byte| byte == PSF2_START_SEQUENCE) {
+                    Some(start) => &entry[..start],
+                    None => &entry[..entry.len() - 1],
+                };
+                let text = core::str::from_utf8(single).map_err(|_| PsfError::BadUnicodeTable)?;
+                for c in text.chars() {
+                    push_mapping(&mut table, c, glyph);
+                }
+            }
+            Some(table)
+        } else {
+            None
+        };
+        Ok(Font::new(name, height, &glyphs[..GLYPHS * height], unicode))
+    }
+
+    fn new(name: &str, height: usize, glyphs: &[u8], unicode: Option<Vec<(char, u8)>>) -> Font {
+        Font {
+            name: name.into(),
+            height,
+            glyphs: glyphs.to_vec(),
+            unicode,
+        }
+    }
+
+    /// 内置的字体, 由帧缓冲控制台的8x8字形生成, 16行时每行重复一次. 只有ASCII字符
+    pub fn builtin(height: usize) -> Font {
+        assert!(
+            height == 8 || height == 16,
+            "builtin fonts are 8x8 and 8x16"
+        );
+        let mut glyphs = Vec::with_capacity(GLYPHS * height);
+        for byte in 0..=u8::MAX {
+            for row in builtin::glyph(byte) {
+                // 帧缓冲的字形最低位在左边
+                for _ in 0..height / builtin::GLYPH_HEIGHT {
+                    glyphs.push(row.reverse_bits());
+                }
+            }
+        }
+        let unicode = (b' '..=b'~').map(|byte| (char::from(byte), byte)).collect();
+        let name = if height == 8 { "default8" } else { "default" };
+        Font::new(name, height, &glyphs, Some(unicode))
+    }
+
+    /// 字形的行数
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// 第`index`个字形
+    pub fn glyph(&self, index: u8) -> &[u8] {
+        let start = usize::from(index) * self.height;
+        &self.glyphs[start..start + self.height]
+    }
+
+    /// 字体自带的Unicode表
+    pub fn unicode(&self) -> Option<&[(char, u8)]> {
+        self.unicode.as_deref()
+    }
+}
+
+// 256之后的字形不能载入, 它们的字符不记录
+fn push_mapping(table: &mut Vec<(char, u8)>, c: char, glyph: usize) {
+    if let Ok(glyph) = u8::try_from(glyph) {
+        table.push((c, glyph));
+    }
+}
+
+// 第i个字形的每一行都是i的低8位
+#[cfg(test)]
+fn fixture_glyphs(count: usize, height: usize) -> Vec<u8> {
+    (0..count)
+        .flat_map(|i| core::iter::repeat_n(i as u8, height))
+        .collect()
+}
+
+// 带Unicode表的PSF1字体, 0x41对应'A'和'Α', 0x82对应'é'和一个组合序列
+#[cfg(test)]
+fn psf1_fixture(height: u8) -> Vec<u8> {
+    let mut data = alloc::vec![0x36, 0x04, PSF1_MODE_HAS_TABLE, height];
+    data.extend(fixture_glyphs(256, usize::from(height)));
+    for glyph in 0..256u16 {
+        let entries: &[u16] = match glyph {
+            0x41 => &[0x41, 0x391],
+            0x82 => &[0xE9, PSF1_START_SEQUENCE, 0x65, 0x301],
+            _ => &[],
+        };
+        for value in entries.iter().chain(&[PSF1_SEPARATOR]) {
+            data.extend(value.to_le_bytes());
+        }
+    }
+    data
+}
+
+// 512个字形的PSF2字体, 头部比32字节长, Unicode表中第300个字形对应的字符被忽略
+#[cfg(test)]
+fn psf2_fixture(height: u32) -> Vec<u8> {
+    let header = [0, 36, PSF2_HAS_TABLE, 512, height, height, 8];
+    let mut data = PSF2_MAGIC.to_vec();
+    for field in header {
+        data.extend(field.to_le_bytes());
+    }
+    data.extend([0; 4]);
+    data.extend(fixture_glyphs(512, height as usize));
+    for glyph in 0..512 {
+        match glyph {
+            0xC4 => data.extend("─━".as_bytes()),
+            0xFE => data.extend("■".as_bytes()),
+            300 => data.extend("Ω".as_bytes()),
+            0x41 => {
+                data.push(b'A');
+                data.push(PSF2_START_SEQUENCE);
+                data.extend("A\u{301}".as_bytes());
+            }
+            _ => {}
+        }
+        data.push(PSF2_SEPARATOR);
+    }
+    data
+}
+
+#[test_case]
+fn test_parse_psf() {
+    let font = Font::parse("one", &psf1_fixture(16)).unwrap();
+    assert_eq!((font.name.as_str(), font.height()), ("one", 16));
+    assert_eq!(font.glyph(0x41), [0x41; 16]);
+    assert_eq!(font.glyph(0xFF), [0xFF; 16]);
+    assert_eq!(
+        font.unicode(),
+        Some(&[('A', 0x41), ('Α', 0x41), ('é', 0x82)][..])
+    );
+
+    let font = Font::parse("two", &psf2_fixture(8)).unwrap();
+    assert_eq!(font.height(), 8);
+    assert_eq!(font.glyph(0xC4), [0xC4; 8]);
+    assert_eq!(
+        font.unicode(),
+        Some(&[('A', 0x41), ('─', 0xC4), ('━', 0xC4), ('■', 0xFE)][..])
+    );
+
+    // 没有Unicode表
+    let mut data = psf1_fixture(8);
+    data[2] = 0;
+    data.truncate(4 + 256 * 8);
+    assert_eq!(Font::parse("plain", &data).unwrap().unicode(), None);
+
+    let font = Font::builtin(16);
+    assert_eq!(font.glyph(b'A').len(), 16);
+    assert_eq!(font.glyph(b'A')[0], font.glyph(b'A')[1]);
+    assert!(font.unicode().unwrap().contains(&('~', b'~')));
+}
+
+#[test_case]
+fn test_broken_psf() {
+    let psf1 = psf1_fixture(16);
+    let psf2 = psf2_fixture(16);
+    let with = |data: &[u8], offset: usize, value: u32| {
+        let mut data = data.to_vec();
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        data
+    };
+    let cases = [
+        (alloc::vec![0x36, 0x05, 0, 16], PsfError::BadMagic),
+        (b"hello".to_vec(), PsfError::BadMagic),
+        (psf1[..3].to_vec(), PsfError::Truncated),
+        (psf1[..4 + 100 * 16].to_vec(), PsfError::Truncated),
+        // Unicode表在中途结束
+        (psf1[..psf1.len() - 1].to_vec(), PsfError::Truncated),
+        (psf2[..20].to_vec(), PsfError::Truncated),
+        (psf2[..psf2.len() - 1].to_vec(), PsfError::Truncated),
+        (with(&psf2, 4, 1), PsfError::UnsupportedVersion(1)),
+        (
+            with(&psf2, 28, 9),
+            PsfError::GlyphSize {
+                width: 9,
+                height: 16,
+            },
+        ),
+        (
+            with(&psf2, 24, 40),
+            PsfError::GlyphSize {
+                width: 8,
+                height: 40,
+            },
+        ),
+        (with(&psf2, 20, 32), PsfError::GlyphBytes(32)),
+        (with(&psf2, 16, 128), PsfError::GlyphCount(128)),
+    ];
+    for (data, expected) in cases {
+        assert_eq!(Font::parse("broken", &data), Err(expected));
+    }
+    let mut zero_height = psf1.clone();
+    zero_height[3] = 0;
+    assert_eq!(
+        Font::parse("broken", &zero_height),
+        Err(PsfError::GlyphSize {
+            width: 8,
+            height: 0
+        })
+    );
+    // 代理码点和无效的UTF-8
+    let mut surrogate = psf1.clone();
+    let table = 4 + 256 * 16;
+    surrogate.splice(table..table, 0xD800u16.to_le_bytes());
+    assert_eq!(
+        Font::parse("broken", &surrogate),
+        Err(PsfError::BadUnicodeTable)
+    );
+    let mut utf8 = psf2.clone();
+    let table = 36 + 512 * 16;
+    utf8.insert(table, 0xC3);
+    assert_eq!(Font::parse("broken", &utf8), Err(PsfError::BadUnicodeTable));
+}
@@ -0,0 +1,468 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::block::request::{QueueStats, Tag};
+use crate::block::{self, AsyncBlockDevice, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::block::{BlockOp, BlockRequest, RequestHandle, RequestQueue};
+use crate::error::KernelError;
+use crate::memory;
+use crate::pci::{self, Bar};
+use crate::sync::IrqMutex;
+use crate::time;
+
+pub mod queue;
+
+use queue::{Buffer, Virtqueue};
+
+const VENDOR_ID: u16 = 0x1AF4;
+// transitional设备的legacy设备ID
+const DEVICE_ID_BLOCK_LEGACY: u16 = 0x1001;
+
+// legacy接口的I/O寄存器偏移
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+// 未启用MSI-X时设备配置从0x14开始
+const REG_BLK_CAPACITY: u16 = 0x14;
+
+// 设备状态
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+const BLK_F_RO: u32 = 1 << 5;
+
+// 请求类型和完成状态
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_S_OK: u8 = 0;
+
+const FRAME_SIZE: usize = 4096;
+// 数据经过一页大小的中转缓冲区, 每条描述符链最多8个扇区, 更大的请求依次提交多条链
+const SECTORS_PER_REQUEST: usize = FRAME_SIZE / SECTOR_SIZE;
+// 同时在设备中的请求数, 每个请求占用一条3个描述符的链和一页中转缓冲区
+const MAX_IN_FLIGHT: usize = 8;
+
+const TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    NoIoBar,
+    NoQueue,
+    OutOfMemory,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// 每个标签的请求头和状态字节在DMA页中的位置: 第i个标签从`i * SLOT_STRIDE`开始
+const SLOT_STRIDE: usize = 32;
+const HEADER_OFFSET: usize = 0;
+const STATUS_OFFSET: usize = 16;
+
+// 交给设备的一个请求
+struct Slot {
+    tag: Tag,
+    request: BlockRequest,
+    // 已完成的扇区数
+    done: usize,
+    // 正在传输的描述符链
+    head: u16,
+}
+
+struct Inner {
+    io_base: u16,
+    queue: Virtqueue,
+    // 存放各个标签的请求头和状态字节
+    request: PhysAddr,
+    // 按标签排列的数据中转缓冲区
+    bounce: Vec<PhysAddr>,
+    slots: Vec<Option<Slot>>,
+}
+
+/// legacy virtio-blk设备, 只使用第0个virtqueue
+///
+/// 请求由完成中断处理: 中断处理函数取出used ring中的链, 提交请求的下一部分或唤醒等待者.
+/// 没有中断线时请求在提交时同步完成
+pub struct VirtioBlk {
+    // 中断处理函数也会获取, 持有期间不能等待
+    inner: IrqMutex<Inner>,
+    requests: RequestQueue,
+    capacity: u64,
+    read_only: bool,
+    irq: Option<u8>,
+}
+
+static DEVICE: Once<VirtioBlk> = Once::new();
+
+static ISR_PORT: AtomicU16 = AtomicU16::new(0);
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+fn read32(io_base: u16, reg: u16) -> u32 {
+    unsafe { Port::<u32>::new(io_base + reg).read() }
+}
+
+fn write32(io_base: u16, reg: u16, value: u32) {
+    unsafe { Port::<u32>::new(io_base + reg).write(value) }
+}
+
+fn read16(io_base: u16, reg: u16) -> u16 {
+    unsafe { Port::<u16>::new(io_base + reg).read() }
+}
+
+fn write16(io_base: u16, reg: u16, value: u16) {
+    unsafe { Port::<u16>::new(io_base + reg).write(value) }
+}
+
+fn write8(io_base: u16, reg: u16, value: u8) {
+    unsafe { Port::<u8>::new(io_base + reg).write(value) }
+}
+
+/// 查找并初始化第一个virtio-blk设备, 没有设备时什么也不做. 需要堆和DMA内存
+pub fn init() -> Result<(), VirtioError> {
+    let Some(pci_device) = pci::devices()
+        .find(|d| d.vendor_id == VENDOR_ID && d.device_id == DEVICE_ID_BLOCK_LEGACY)
+    else {
+        return Ok(());
+    };
+    let io_base = match pci_device.bars[0] {
+        Bar::Io { port, .. } => port as u16,
+        _ => return Err(VirtioError::NoIoBar),
+    };
+    pci::enable_bus_master(pci_device);
+
+    // 复位后依次设置ACKNOWLEDGE和DRIVER
+    write8(io_base, REG_DEVICE_STATUS, 0);
+    write8(io_base, REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+    write8(io_base, REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    // 不需要任何可选特性, 只据此判断设备是否只读
+    let features = read32(io_base, REG_DEVICE_FEATURES);
+    write32(io_base, REG_GUEST_FEATURES, 0);
+
+    match setup(io_base, features, pci_device.interrupt_line) {
+        Ok(device) => {
+            DEVICE.call_once(|| device);
+            write8(
+                io_base,
+                REG_DEVICE_STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+            );
+            Ok(())
+        }
+        Err(err) => {
+            write8(io_base, REG_DEVICE_STATUS, STATUS_FAILED);
+            Err(err)
+        }
+    }
+}
+
+fn setup(io_base: u16, features: u32, interrupt_line: u8) -> Result<VirtioBlk, VirtioError> {
+    write16(io_base, REG_QUEUE_SELECT, 0);
+    let size = read16(io_base, REG_QUEUE_SIZE);
+    // 至少要放下一条请求链
+    if size < 3 {
+        return Err(VirtioError::NoQueue);
+    }
+    let depth = MAX_IN_FLIGHT.min(usize::from(size) / 3);
+
+    let queue_frames = queue::layout_size(size).div_ceil(FRAME_SIZE);
+    let queue_frame =
+        memory::allocate_dma_frames(queue_frames).map_err(|_| VirtioError::OutOfMemory)?;
+    let request = memory::allocate_dma_frames(1).map_err(|_| VirtioError::OutOfMemory)?;
+    let bounce = (0..depth)
+        .map(|_| memory::allocate_dma_frames(1).map(|frame| frame.start_address()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| VirtioError::OutOfMemory)?;
+
+    let queue_base = memory::phys_to_virt(queue_frame.start_address()).as_mut_ptr();
+    let queue = unsafe { Virtqueue::new(queue_base, size) };
+    write32(io_base, REG_QUEUE_PFN, (queue_frame.start_address().as_u64() >> 12) as u32);
+
+    let capacity = u64::from(read32(io_base, REG_BLK_CAPACITY))
+        | u64::from(read32(io_base, REG_BLK_CAPACITY + 4)) << 32;
+
+    // 0和0xFF表示没有分配中断线, 退回轮询
+    let irq = if (1..16).contains(&interrupt_line)
+        && crate::interrupts::register_irq(interrupt_line, on_interrupt).is_ok()
+    {
+        ISR_PORT.store(io_base + REG_ISR_STATUS, Ordering::SeqCst);
+        crate::interrupts::unmask_irq(interrupt_line);
+        Some(interrupt_line)
+    } else {
+        None
+    };
+
+    Ok(VirtioBlk {
+        inner: IrqMutex::new(
+            "virtio::Inner",
+            Inner {
+                io_base,
+                queue,
+                request: request.start_address(),
+                bounce,
+                slots: (0..depth).map(|_| None).collect(),
+            },
+        ),
+        requests: RequestQueue::new(depth),
+        capacity,
+        read_only: features & BLK_F_RO != 0,
+        irq,
+    })
+}
+
+// 读取ISR会清除设备的中断. 初始化完成之前到达的中断只计数
+fn on_interrupt() {
+    let port = ISR_PORT.load(Ordering::SeqCst);
+    if port != 0 && unsafe { Port::<u8>::new(port).read() } != 0 {
+        INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+        if let Some(device) = DEVICE.get() {
+            device.reap();
+        }
+    }
+}
+
+/// 已初始化的virtio-blk设备
+pub fn device() -> Option<&'static VirtioBlk> {
+    DEVICE.get()
+}
+
+impl VirtioBlk {
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// 设备使用的中断线, 为None时通过轮询等待请求完成
+    pub fn irq(&self) -> Option<u8> {
+        self.irq
+    }
+
+    /// 收到的设备中断次数
+    pub fn interrupt_count(&self) -> usize {
+        INTERRUPTS.load(Ordering::SeqCst)
+    }
+
+    /// 请求队列的统计
+    pub fn queue_stats(&self) -> QueueStats {
+        self.requests.stats()
+    }
+
+    // 把等待中的请求交给设备, 直到标签用完
+    fn kick(&self) {
+        let mut failed = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            while let Some((tag, request)) = self.requests.start() {
+                inner.slots[tag.index()] = Some(Slot {
+                    tag,
+                    request,
+                    done: 0,
+                    head: 0,
+                });
+                if let Err(err) = inner.issue(tag.index()) {
+                    inner.slots[tag.index()] = None;
+                    failed.push((tag, Err(err)));
+                }
+            }
+        }
+        for (tag, result) in failed {
+            self.requests
+                .complete(tag, result.map_err(KernelError::from));
+        }
+    }
+
+    // 处理used ring中完成的链, 在完成中断和轮询中调用
+    fn reap(&self) {
+        let mut finished = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            while let Some((head, _)) = inner.queue.pop_used() {
+                let index = inner
+                    .slots
+                    .iter()
+                    .position(|slot| slot.as_ref().is_some_and(|slot| slot.head == head));
+                if let Some(index) = index {
+                    finished.extend(inner.advance(index));
+                }
+            }
+        }
+        if finished.is_empty() {
+            return;
+        }
+        // 在设备锁外唤醒等待者, 再用空出的标签提交新请求
+        for (tag, result) in finished {
+            self.requests
+                .complete(tag, result.map_err(KernelError::from));
+        }
+        self.kick();
+    }
+
+    // 同步等待请求完成. 有中断时在等待期间hlt, 否则轮询used ring
+    fn wait(&self, mut handle: RequestHandle) -> Result<Vec<u8>, KernelError> {
+        let mut result = None;
+        time::wait_until(TIMEOUT_MS, || {
+            if self.irq.is_none() {
+                self.reap();
+                result = handle.take();
+                return result.is_some();
+            }
+            // 关中断后再检查一次, 避免完成中断在检查和hlt之间到达
+            interrupts::disable();
+            result = handle.take();
+            if result.is_some() {
+                interrupts::enable();
+                return true;
+            }
+            crate::sched::halt();
+            false
+        })
+        .map_err(|_| BlockError::Timeout)?;
+        result.ok_or(BlockError::Timeout)?
+    }
+}
+
+impl Inner {
+    // 提交标签`index`的请求中下一段最多8个扇区
+    fn issue(&mut self, index: usize) -> Result<(), BlockError> {
+        let slot = self.slots[index].as_ref().expect("issuing a free slot");
+        let start = slot.done * SECTOR_SIZE;
+        let len = (slot.request.buf.len() - start).min(SECTORS_PER_REQUEST * SECTOR_SIZE);
+        let kind = match slot.request.op {
+            BlockOp::Read => BLK_T_IN,
+            BlockOp::Write => BLK_T_OUT,
+        };
+        let header = RequestHeader {
+            kind,
+            reserved: 0,
+            sector: slot.request.lba + slot.done as u64,
+        };
+        let request_addr = self.request.as_u64() + (index * SLOT_STRIDE) as u64;
+        let request: *mut u8 = memory::phys_to_virt(PhysAddr::new(request_addr)).as_mut_ptr();
+        let bounce = self.bounce[index];
+        unsafe {
+            core::ptr::write_volatile(request.add(HEADER_OFFSET) as *mut RequestHeader, header);
+            // 设备完成后会改写状态字节
+            core::ptr::write_volatile(request.add(STATUS_OFFSET), 0xFF);
+            if kind == BLK_T_OUT {
+                let data = &slot.request.buf[start..start + len];
+                let bounce: *mut u8 = memory::phys_to_virt(bounce).as_mut_ptr();
+                core::ptr::copy_nonoverlapping(data.as_ptr(), bounce, len);
+            }
+        }
+
+        let buffers = [
+            Buffer {
+                addr: request_addr + HEADER_OFFSET as u64,
+                len: core::mem::size_of::<RequestHeader>() as u32,
+                device_writes: false,
+            },
+            Buffer {
+                addr: bounce.as_u64(),
+                len: len as u32,
+                device_writes: kind == BLK_T_IN,
+            },
+            Buffer {
+                addr: request_addr + STATUS_OFFSET as u64,
+                len: 1,
+                device_writes: true,
+            },
+        ];
+        let head = self.queue.add_chain(&buffers).ok_or(BlockError::Io)?;
+        self.slots[index].as_mut().unwrap().head = head;
+        write16(self.io_base, REG_QUEUE_NOTIFY, 0);
+        Ok(())
+    }
+
+    // 标签`index`的一段传输完成, 整个请求完成或失败时释放它并返回结果
+    fn advance(&mut self, index: usize) -> Option<(Tag, Result<Vec<u8>, BlockError>)> {
+        let status_addr = self.request + (index * SLOT_STRIDE + STATUS_OFFSET) as u64;
+        let status: *const u8 = memory::phys_to_virt(status_addr).as_ptr();
+        let status = unsafe { core::ptr::read_volatile(status) };
+        let bounce: *const u8 = memory::phys_to_virt(self.bounce[index]).as_ptr();
+        let slot = self.slots[index].as_mut().expect("advancing a free slot");
+        let start = slot.done * SECTOR_SIZE;
+        let len = (slot.request.buf.len() - start).min(SECTORS_PER_REQUEST * SECTOR_SIZE);
+        let result = if status != BLK_S_OK {
+            Some(Err(BlockError::Io))
+        } else {
+            if slot.request.op == BlockOp::Read {
+                let data = &mut slot.request.buf[start..start + len];
+                unsafe { core::ptr::copy_nonoverlapping(bounce, data.as_mut_ptr(), len) };
+            }
+            slot.done += len / SECTOR_SIZE;
+            if slot.done == slot.request.count() {
+                Some(Ok(()))
+            } else {
+                self.issue(index).err().map(Err)
+            }
+        };
+        let result = result?;
+        let slot = self.slots[index].take().unwrap();
+        Some((slot.tag, result.map(|()| slot.request.buf)))
+    }
+}
+
+impl AsyncBlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn submit(&self, request: BlockRequest) -> RequestHandle {
+        if request.op == BlockOp::Write && self.read_only {
+            return RequestHandle::ready(Err(BlockError::ReadOnly.into()));
+        }
+        let checked = block::check_request(
+            self.capacity,
+            request.lba,
+            request.count(),
+            request.buf.len(),
+        );
+        if let Err(err) = checked {
+            return RequestHandle::ready(Err(err.into()));
+        }
+        let handle = self.requests.submit(request);
+        self.kick();
+        if self.irq.is_none() {
+            return RequestHandle::ready(self.wait(handle));
+        }
+        handle
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_sectors(&self, lba: u64, count: usize, buf: &mut [u8]) -> Result<(), KernelError> {
+        block::check_request(self.capacity, lba, count, buf.len())?;
+        let data = self.wait(AsyncBlockDevice::read(self, lba, count))?;
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, count: usize, buf: &[u8]) -> Result<(), KernelError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly.into());
+        }
+        block::check_request(self.capacity, lba, count, buf.len())?;
+        self.wait(AsyncBlockDevice::write(self, lba, buf.to_vec()))
+            .map(drop)
+    }
+}
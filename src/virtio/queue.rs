@@ -0,0 +1,312 @@
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+// 描述符标志
+pub const DESC_F_NEXT: u16 = 1;
+pub const DESC_F_WRITE: u16 = 2;
+
+// legacy接口要求used ring按页对齐
+const QUEUE_ALIGN: usize = 4096;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsedElem {
+    pub id: u32,
+    pub len: u32,
+}
+
+/// 描述符链中的一个缓冲区
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    /// 设备看到的物理地址
+    pub addr: u64,
+    pub len: u32,
+    /// 设备写入(而不是读取)这个缓冲区
+    pub device_writes: bool,
+}
+
+// ring的头部: flags和idx各占2字节
+const RING_HEADER: usize = 4;
+
+/// legacy布局下一个virtqueue需要的字节数
+pub const fn layout_size(size: u16) -> usize {
+    used_offset(size) + RING_HEADER + size_of::<UsedElem>() * size as usize + 2
+}
+
+const fn avail_offset(size: u16) -> usize {
+    size_of::<Descriptor>() * size as usize
+}
+
+const fn used_offset(size: u16) -> usize {
+    let end = avail_offset(size) + RING_HEADER + 2 * size as usize + 2;
+    end.div_ceil(QUEUE_ALIGN) * QUEUE_ALIGN
+}
+
+/// 一个split virtqueue, 所有共享内存的访问都是volatile的
+pub struct Virtqueue {
+    base: *mut u8,
+    size: u16,
+    // 空闲描述符通过next字段串成链表
+    free_head: u16,
+    num_free: u16,
+    // 下一个要写入的avail ring位置, 同时也是avail.idx
+    avail_idx: u16,
+    // 下一个要读取的used ring位置
+    last_used_idx: u16,
+}
+
+// 共享内存只由持有Virtqueue的一方访问
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// # Safety
+    ///
+    /// `base`指向至少`layout_size(size)`字节且已清零的内存, `size`必须是2的幂
+    pub unsafe fn new(base: *mut u8, size: u16) -> Self {
+        let mut queue = Virtqueue {
+            base,
+            size,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size {
+            let mut desc = queue.descriptor(i);
+            desc.next = (i + 1) % size;
+            queue.set_descriptor(i, desc);
+        }
+        queue
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    fn desc_ptr(&self, index: u16) -> *mut Descriptor {
+        unsafe { (self.base as *mut Descriptor).add(usize::from(index)) }
+    }
+
+    pub fn descriptor(&self, index: u16) -> Descriptor {
+        unsafe { read_volatile(self.desc_ptr(index)) }
+    }
+
+    fn set_descriptor(&mut self, index: u16, desc: Descriptor) {
+        unsafe { write_volatile(self.desc_ptr(index), desc) }
+    }
+
+    fn avail_ring(&self) -> *mut u16 {
+        unsafe { self.base.add(avail_offset(self.size)) as *mut u16 }
+    }
+
+    fn used_ring(&self) -> *mut u16 {
+        unsafe { self.base.add(used_offset(self.size)) as *mut u16 }
+    }
+
+    /// avail ring中的第`slot`项
+    pub fn avail_entry(&self, slot: u16) -> u16 {
+        unsafe { read_volatile(self.avail_ring().add(2 + usize::from(slot % self.size))) }
+    }
+
+    /// 设备最新的avail.idx
+    pub fn avail_idx(&self) -> u16 {
+        unsafe { read_volatile(self.avail_ring().add(1)) }
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe { read_volatile(self.used_ring().add(1)) }
+    }
+
+    fn used_elem(&self, slot: u16) -> UsedElem {
+        unsafe {
+            let elems = self.used_ring().add(2) as *mut UsedElem;
+            read_volatile(elems.add(usize::from(slot % self.size)))
+        }
+    }
+
+    /// 把缓冲区串成描述符链并放入avail ring, 返回链头. 描述符不足时返回None
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.num_free) {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut desc = self.descriptor(index);
+            let next = desc.next;
+            desc.addr = buffer.addr;
+            desc.len = buffer.len;
+            desc.flags = if buffer.device_writes { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+            }
+            self.set_descriptor(index, desc);
+            if i + 1 < buffers.len() {
+                index = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.num_free -= buffers.len() as u16;
+
+        unsafe {
+            let slot = usize::from(self.avail_idx % self.size);
+            write_volatile(self.avail_ring().add(2 + slot), head);
+        }
+        // 设备必须先看到ring中的项, 再看到新的idx
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe {
+            write_volatile(self.avail_ring().add(1), self.avail_idx);
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// 取出一个设备已完成的请求, 返回链头和设备写入的字节数, 并回收描述符
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if self.used_idx() == self.last_used_idx {
+            return None;
+        }
+        // 读到idx之后才能读ring中的项
+        fence(Ordering::SeqCst);
+        let elem = self.used_elem(self.last_used_idx);
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        let head = elem.id as u16;
+        self.free_chain(head);
+        Some((head, elem.len))
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let mut desc = self.descriptor(index);
+            self.num_free += 1;
+            if desc.flags & DESC_F_NEXT == 0 {
+                desc.next = self.free_head;
+                self.set_descriptor(index, desc);
+                break;
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+    }
+
+    /// 测试用: 模拟设备完成`head`开始的链
+    #[cfg(test)]
+    pub fn device_complete(&mut self, head: u16, len: u32) {
+        let idx = self.used_idx();
+        unsafe {
+            let elems = self.used_ring().add(2) as *mut UsedElem;
+            write_volatile(
+                elems.add(usize::from(idx % self.size)),
+                UsedElem { id: u32::from(head), len },
+            );
+            write_volatile(self.used_ring().add(1), idx.wrapping_add(1));
+        }
+    }
+
+    /// 测试用: 让ring的索引从`idx`开始, 以便覆盖16位回绕
+    #[cfg(test)]
+    pub fn start_indices_at(&mut self, idx: u16) {
+        self.avail_idx = idx;
+        self.last_used_idx = idx;
+        unsafe {
+            write_volatile(self.avail_ring().add(1), idx);
+            write_volatile(self.used_ring().add(1), idx);
+        }
+    }
+}
+
+// 测试用的页对齐内存, 代替DMA帧
+#[cfg(test)]
+struct TestMemory {
+    ptr: *mut u8,
+    layout: core::alloc::Layout,
+}
+
+#[cfg(test)]
+impl Drop for TestMemory {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+#[cfg(test)]
+fn test_queue(size: u16) -> (TestMemory, Virtqueue) {
+    let layout = core::alloc::Layout::from_size_align(layout_size(size), QUEUE_ALIGN).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    assert!(!ptr.is_null());
+    let queue = unsafe { Virtqueue::new(ptr, size) };
+    (TestMemory { ptr, layout }, queue)
+}
+
+#[test_case]
+fn test_layout_size() {
+    // 描述符表2048字节, avail ring 262字节, used ring从下一页开始
+    assert_eq!(used_offset(128), 4096);
+    assert_eq!(layout_size(128), 4096 + 6 + 8 * 128);
+    assert_eq!(used_offset(256), 8192);
+}
+
+#[test_case]
+fn test_descriptor_chain() {
+    let (_memory, mut queue) = test_queue(8);
+    let buffers = [
+        Buffer { addr: 0x1000, len: 16, device_writes: false },
+        Buffer { addr: 0x2000, len: 512, device_writes: true },
+        Buffer { addr: 0x3000, len: 1, device_writes: true },
+    ];
+    let head = queue.add_chain(&buffers).unwrap();
+    assert_eq!(queue.num_free(), 5);
+    assert_eq!(queue.avail_idx(), 1);
+    assert_eq!(queue.avail_entry(0), head);
+
+    let first = queue.descriptor(head);
+    assert_eq!((first.addr, first.len, first.flags), (0x1000, 16, DESC_F_NEXT));
+    let second = queue.descriptor(first.next);
+    assert_eq!((second.addr, second.len), (0x2000, 512));
+    assert_eq!(second.flags, DESC_F_NEXT | DESC_F_WRITE);
+    let third = queue.descriptor(second.next);
+    assert_eq!((third.addr, third.len, third.flags), (0x3000, 1, DESC_F_WRITE));
+
+    // 描述符不足时拒绝, 而不是覆盖正在使用的描述符
+    let many = [buffers[0]; 6];
+    assert_eq!(queue.add_chain(&many), None);
+}
+
+#[test_case]
+fn test_used_ring_wrapping() {
+    let (_memory, mut queue) = test_queue(4);
+    let buffers = [
+        Buffer { addr: 0x1000, len: 16, device_writes: false },
+        Buffer { addr: 0x2000, len: 1, device_writes: true },
+    ];
+    // 从接近u16::MAX的位置开始, 同时覆盖ring回绕和索引回绕
+    queue.start_indices_at(u16::MAX - 5);
+    assert_eq!(queue.pop_used(), None);
+    for round in 0..12u32 {
+        let head = queue.add_chain(&buffers).unwrap();
+        queue.device_complete(head, round);
+        assert_eq!(queue.pop_used(), Some((head, round)));
+        assert_eq!(queue.pop_used(), None);
+        assert_eq!(queue.num_free(), 4);
+    }
+    assert_eq!(queue.avail_idx(), (u16::MAX - 5).wrapping_add(12));
+}
@@ -0,0 +1,219 @@
+//! 推迟执行的工作, 让中断处理函数只做最少的事
+//!
+//! 中断处理函数用`defer`把函数和参数放入无锁的定长队列, 执行器在hlt之前取出执行.
+//! `defer_unique`用令牌合并重复提交: 工作执行之前再次提交不会重复入队.
+//! 队列已满或尚未初始化时工作被丢弃并计入溢出次数
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+
+/// 队列容量
+pub const QUEUE_SIZE: usize = 128;
+
+enum Work {
+    Call(fn(usize), usize),
+    Unique(fn(), &'static Token),
+}
+
+static QUEUE: OnceCell<ArrayQueue<Work>> = OnceCell::uninit();
+static OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+
+/// 合并重复提交的令牌, 每种工作使用一个静态的令牌
+pub struct Token {
+    queued: AtomicBool,
+}
+
+impl Token {
+    pub const fn new() -> Self {
+        Token {
+            queued: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for Token {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 分配队列, 由`toy_os::init_memory`在堆初始化后调用. 重复调用会panic
+pub fn init() {
+    QUEUE
+        .try_init_once(|| ArrayQueue::new(QUEUE_SIZE))
+        .expect("workqueue::init called twice");
+}
+
+/// 推迟执行`func(arg)`, 可以在中断中调用
+pub fn defer(func: fn(usize), arg: usize) {
+    push(Work::Call(func, arg));
+}
+
+/// 推迟执行`func`, `token`对应的工作已在队列中等待时不再入队
+pub fn defer_unique(token: &'static Token, func: fn()) {
+    if token.queued.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    push(Work::Unique(func, token));
+}
+
+fn push(work: Work) {
+    let Ok(queue) = QUEUE.try_get() else {
+        overflow(work);
+        return;
+    };
+    if let Err(work) = queue.push(work) {
+        overflow(work);
+    }
+}
+
+fn overflow(work: Work) {
+    if let Work::Unique(_, token) = work {
+        token.queued.store(false, Ordering::Release);
+    }
+    OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 执行队列中的工作, 返回执行的个数
+///
+/// 最多执行调用时已在队列中的个数, 工作在执行中再次提交自己时不会一直循环
+pub fn run_pending() -> usize {
+    let Ok(queue) = QUEUE.try_get() else {
+        return 0;
+    };
+    let mut count = 0;
+    for _ in 0..queue.len() {
+        let Some(work) = queue.pop() else {
+            break;
+        };
+        match work {
+            Work::Call(func, arg) => func(arg),
+            Work::Unique(func, token) => {
+                // 先清除标记, 执行期间的再次提交会重新入队
+                token.queued.store(false, Ordering::Release);
+                func();
+            }
+        }
+        count += 1;
+    }
+    count
+}
+
+/// 队列中是否没有等待执行的工作
+pub fn is_empty() -> bool {
+    QUEUE.try_get().map_or(true, |queue| queue.is_empty())
+}
+
+/// 等待执行的工作个数
+pub fn pending() -> usize {
+    QUEUE.try_get().map_or(0, |queue| queue.len())
+}
+
+/// 因队列已满或尚未初始化而丢弃的工作个数
+pub fn overflows() -> u64 {
+    OVERFLOWS.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_items_run_exactly_once() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicU8;
+    use x86_64::instructions::interrupts;
+
+    const ITEMS: usize = 1000;
+    static COUNTS: [AtomicU8; ITEMS] = [const { AtomicU8::new(0) }; ITEMS];
+    fn record(index: usize) {
+        COUNTS[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    let before = overflows();
+    let done = Arc::new(AtomicBool::new(false));
+    let producer_done = done.clone();
+    // 生产者线程关中断提交, 和中断处理函数的环境相同, 期间测试线程可能被时钟中断切换过来执行
+    crate::thread::spawn("producer", crate::thread::Priority::Normal, move || {
+        for index in 0..ITEMS {
+            while pending() >= QUEUE_SIZE / 2 {
+                crate::thread::yield_now();
+            }
+            interrupts::without_interrupts(|| defer(record, index));
+        }
+        producer_done.store(true, Ordering::Release);
+    })
+    .unwrap();
+    while !done.load(Ordering::Acquire) || !is_empty() {
+        run_pending();
+        crate::thread::yield_now();
+    }
+    // 等生产者线程退出
+    while Arc::strong_count(&done) > 1 {
+        crate::thread::yield_now();
+    }
+    assert_eq!(overflows(), before);
+    for (index, count) in COUNTS.iter().enumerate() {
+        assert_eq!(
+            count.load(Ordering::Relaxed),
+            1,
+            "item {} ran {} times",
+            index,
+            count.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[test_case]
+fn test_defer_unique_coalesces() {
+    static TOKEN: Token = Token::new();
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+    fn work() {
+        RUNS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    for _ in 0..100 {
+        defer_unique(&TOKEN, work);
+    }
+    run_pending();
+    run_pending();
+    assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+    // 执行之后再次提交会再执行一次
+    defer_unique(&TOKEN, work);
+    run_pending();
+    assert_eq!(RUNS.load(Ordering::Relaxed), 2);
+}
+
+#[test_case]
+fn test_overflow_is_counted() {
+    use x86_64::instructions::interrupts;
+
+    static RAN: [AtomicBool; QUEUE_SIZE] = [const { AtomicBool::new(false) }; QUEUE_SIZE];
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    fn record(index: usize) {
+        // 按提交的顺序执行
+        assert_eq!(NEXT.fetch_add(1, Ordering::Relaxed), index as u64);
+        RAN[index].store(true, Ordering::Relaxed);
+    }
+
+    // 关中断, 时钟中断不会同时提交工作
+    interrupts::without_interrupts(|| {
+        while !is_empty() {
+            run_pending();
+        }
+        let before = overflows();
+        let mut accepted = 0;
+        while overflows() == before {
+            defer(record, accepted);
+            accepted += 1;
+        }
+        accepted -= 1;
+        assert_eq!(accepted, QUEUE_SIZE);
+        for index in 0..10 {
+            defer(record, QUEUE_SIZE + index);
+        }
+        assert_eq!(overflows(), before + 11);
+        assert_eq!(pending(), QUEUE_SIZE);
+        assert_eq!(run_pending(), QUEUE_SIZE);
+        assert!(is_empty());
+        assert!(RAN.iter().all(|ran| ran.load(Ordering::Relaxed)));
+    });
+}
@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use toy_os::println;
+
+/// 不调用init, 验证没有IDT/GDT时也能正常输出
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// 控制台初始化之前print!直接写到屏幕首行
+#[test_case]
+fn test_early_print() {
+    const TEXT: &[u8] = b"test_early_print output";
+    println!("{}", core::str::from_utf8(TEXT).unwrap());
+    let row = 0xb8000 as *const u16;
+    for col in 0..80 {
+        let character = unsafe { row.add(col).read_volatile() } as u8;
+        // 换行清除了本行余下的部分
+        let expected = TEXT.get(col).copied().unwrap_or(b' ');
+        assert_eq!(character, expected, "column {}", col);
+    }
+}
+
+// 只加载IDT, 断点异常的信息通过earlycon写到屏幕首行
+#[test_case]
+fn test_early_exception() {
+    const PREFIX: &[u8] = b"EXCEPTION: BREAKPOINT during early boot at 0x";
+    assert!(toy_os::earlycon::active());
+    toy_os::interrupts::init_idt();
+    x86_64::instructions::interrupts::int3();
+    let row = 0xb8000 as *const u16;
+    for (col, &expected) in PREFIX.iter().enumerate() {
+        let character = unsafe { row.add(col).read_volatile() } as u8;
+        assert_eq!(character, expected, "column {}", col);
+    }
+}
+
+#[test_case]
+fn test_println() {
+    println!("test_println output");
+}
@@ -0,0 +1,153 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::ata::{self, Position};
+use toy_os::block::{BlockDevice, BlockError, Inline, Partition, SECTOR_SIZE};
+use toy_os::error::Context;
+use toy_os::fat::{FatFs, FatType};
+use toy_os::{thread, virtio};
+
+// 与build.rs生成的镜像保持一致
+const IMAGE_SECTORS: u64 = 2048;
+const SIGNATURE: &[u8] = b"TOY_OS ATA TEST IMAGE";
+// ATA测试盘在IMAGE_SECTORS之后附加的FAT16卷
+const FAT16_SECTORS: u64 = 16384;
+const ATA_SECTORS: u64 = IMAGE_SECTORS + FAT16_SECTORS;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn ata_drive() -> ata::Drive {
+    ata::drive(Position::Slave).expect("test image not attached as primary slave")
+}
+
+fn virtio_drive() -> &'static virtio::VirtioBlk {
+    virtio::device().expect("test image not attached as virtio-blk")
+}
+
+// 两种驱动通过同一个接口执行相同的检查
+fn check_prepared_sector(device: &dyn BlockDevice, sectors: u64) {
+    assert_eq!(device.sector_count(), sectors);
+
+    let mut buf = [0u8; SECTOR_SIZE];
+    device.read_sectors(0, 1, &mut buf).unwrap();
+    assert_eq!(&buf[..SIGNATURE.len()], SIGNATURE);
+    for (i, &byte) in buf[SIGNATURE.len()..].iter().enumerate() {
+        assert_eq!(byte, i as u8);
+    }
+}
+
+fn check_write_read_back(device: &dyn BlockDevice) {
+    // 跨越virtio中转缓冲区的边界
+    let mut pattern = [0u8; 10 * SECTOR_SIZE];
+    for (i, byte) in pattern.iter_mut().enumerate() {
+        *byte = (i * 7 + 3) as u8;
+    }
+    device.write_sectors(100, 10, &pattern).unwrap();
+
+    let mut buf = [0u8; 10 * SECTOR_SIZE];
+    device.read_sectors(100, 10, &mut buf).unwrap();
+    assert_eq!(buf, pattern);
+}
+
+fn check_buffer_length(device: &dyn BlockDevice, sectors: u64) {
+    let mut buf = [0u8; SECTOR_SIZE];
+    assert_eq!(
+        device.read_sectors(0, 2, &mut buf),
+        Err(BlockError::BufferSize.into())
+    );
+    // 设备的错误作为上下文返回
+    let err = device.read_sectors(sectors, 1, &mut buf).unwrap_err();
+    assert_eq!(err, BlockError::OutOfRange.into());
+    assert_eq!(err.context(), Context::Block(BlockError::OutOfRange));
+}
+
+#[test_case]
+fn test_ata_identify() {
+    assert!(ata_drive().model().starts_with("QEMU"));
+}
+
+#[test_case]
+fn test_ata_read_prepared_sector() {
+    check_prepared_sector(&ata_drive(), ATA_SECTORS);
+}
+
+#[test_case]
+fn test_ata_write_read_back() {
+    check_write_read_back(&ata_drive());
+}
+
+#[test_case]
+fn test_ata_buffer_length_checked() {
+    check_buffer_length(&ata_drive(), ATA_SECTORS);
+    let mut buf = [0u8; SECTOR_SIZE];
+    assert_eq!(
+        ata_drive().read_sectors(0, 2, &mut buf),
+        Err(ata::AtaError::BufferSize)
+    );
+}
+
+#[test_case]
+fn test_virtio_read_prepared_sector() {
+    check_prepared_sector(virtio_drive(), IMAGE_SECTORS);
+}
+
+#[test_case]
+fn test_virtio_write_read_back() {
+    check_write_read_back(virtio_drive());
+}
+
+#[test_case]
+fn test_virtio_buffer_length_checked() {
+    check_buffer_length(virtio_drive(), IMAGE_SECTORS);
+}
+
+#[test_case]
+fn test_ata_fat_partition() {
+    let drive = ata_drive();
+    let partition = Partition::new(&drive, IMAGE_SECTORS, FAT16_SECTORS).unwrap();
+    assert!(Partition::new(&drive, IMAGE_SECTORS, FAT16_SECTORS + 1).is_err());
+    let device = Inline(&partition);
+    thread::block_on(async {
+        let fs = FatFs::mount(&device)
+            .await
+            .expect("failed to mount FAT16 volume");
+        assert_eq!(fs.fat_type(), FatType::Fat16);
+
+        let hello = fs.open("/HELLO.TXT").await.unwrap();
+        let mut buf = [0u8; 64];
+        let len = hello.read_at(0, &mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello from fat\n");
+
+        // 长文件名和对应的短文件名
+        let root = fs.open("/").await.unwrap().entries().await.unwrap();
+        let names: Vec<_> = root
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.short_name.as_str()))
+            .collect();
+        assert!(names.contains(&("HELLO.TXT", "HELLO.TXT")));
+        assert!(names.contains(&("Long File Name.txt", "LONGFI~1.TXT")));
+        let docs = fs.open("docs").await.unwrap().entries().await.unwrap();
+        assert!(docs.iter().any(|entry| entry.short_name == "AMUCHL~1.BIN"
+            && entry.name == "A much longer name that needs several LFN entries.bin"));
+    });
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::alloc::Layout;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use toy_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// 之后的panic来自封存后的分配
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    serial_print!("bootmem_seal::alloc_after_seal...\t");
+    EXPECT_PANIC.store(true, Ordering::SeqCst);
+    toy_os::bootmem::alloc(Layout::new::<u64>());
+    serial_println!("[failed]\n");
+    serial_println!("Error: bootmem allocation after init_heap did not panic\n");
+    exit_qemu(QemuExitCode::Failed);
+    toy_os::hlt_loop();
+}
+
+// 保存panic信息的前一部分, 用于检查内容
+struct Message {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !EXPECT_PANIC.load(Ordering::SeqCst) {
+        toy_os::test_panic_handler(info);
+    }
+    let mut message = Message {
+        bytes: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.bytes[..message.len]).unwrap_or("");
+    // 报告的是调用者的位置, 而不是bootmem内部
+    if !message.contains("after seal") || !message.contains("tests/bootmem_seal.rs") {
+        toy_os::test_panic_handler(info);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
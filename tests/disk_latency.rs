@@ -0,0 +1,150 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::format;
+use bootloader::{entry_point, BootInfo};
+use core::fmt;
+use core::panic::PanicInfo;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use futures_util::StreamExt;
+use toy_os::ata::{self, Position};
+use toy_os::block::{IoThread, Partition};
+use toy_os::error::KernelError;
+use toy_os::fat::FatFs;
+use toy_os::task::executor::Executor;
+use toy_os::task::Task;
+use toy_os::thread::{self, Priority};
+use toy_os::vfs;
+use toy_os::{
+    exit_qemu, keyboard, print, profile, ps2, serial_print, serial_println, time, QemuExitCode,
+};
+
+// ATA测试盘上FAT16卷的位置, 与build.rs保持一致
+const FAT16_START: u64 = 2048;
+const FAT16_SECTORS: u64 = 16384;
+// 'a'按下和松开的扫描码
+const KEY_DOWN: u8 = 0x1E;
+const KEY_UP: u8 = 0x9E;
+const KEYS: u64 = 50;
+// 按键之间的间隔, 让每次按键都落在磁盘读取的中间
+const KEY_INTERVAL_MS: u64 = 10;
+// 磁盘负载下的延迟上限
+const P90_LIMIT_US: u64 = 2_000;
+const MAX_LIMIT_US: u64 = 20_000;
+
+static ECHOED: AtomicBool = AtomicBool::new(false);
+static PASSES: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    serial_print!("disk_latency::echo_under_disk_load...\t");
+    let drive = ata::drive(Position::Slave).expect("test image not attached as primary slave");
+    let drive: &'static ata::Drive = Box::leak(Box::new(drive));
+    let partition = Box::leak(Box::new(
+        Partition::new(drive, FAT16_START, FAT16_SECTORS).unwrap(),
+    ));
+    let io = IoThread::spawn("disk", partition).unwrap();
+    let before = io.queue().stats().completed;
+    profile::reset();
+
+    thread::spawn("typist", Priority::Normal, || {
+        for _ in 0..KEYS {
+            thread::sleep_ms(KEY_INTERVAL_MS);
+            if let Err(err) =
+                ps2::inject_scancode(KEY_DOWN).and_then(|()| ps2::inject_scancode(KEY_UP))
+            {
+                fail(format_args!("cannot inject scancodes: {:?}", err));
+            }
+        }
+    })
+    .unwrap();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::named("echo", async {
+        let mut keys = pin!(keyboard::keys());
+        for _ in 0..KEYS {
+            let key = time::timeout(time::secs_to_ticks(5), keys.next()).await;
+            match key {
+                Ok(Some(key)) => print!("{:?}", key),
+                _ => break,
+            }
+        }
+        ECHOED.store(true, Ordering::SeqCst);
+    }));
+    executor.spawn(Task::named("disk", async move {
+        match FatFs::mount(io).await {
+            Ok(fs) => vfs::mount("/disk", Box::leak(Box::new(fs))).unwrap(),
+            Err(err) => fail(format_args!("failed to mount FAT16 volume: {:?}", err)),
+        }
+        while !ECHOED.load(Ordering::SeqCst) {
+            if let Err(err) = read_volume().await {
+                fail(format_args!("reading the FAT volume failed: {:?}", err));
+            }
+            PASSES.fetch_add(1, Ordering::SeqCst);
+        }
+    }));
+    executor.run_until_idle();
+
+    let latency = profile::input_latency();
+    let requests = io.queue().stats().completed - before;
+    if latency.count() != KEYS {
+        fail(format_args!(
+            "{} of {} keys reached the echo task",
+            latency.count(),
+            KEYS
+        ));
+    }
+    // 至少有几遍读取和按键交错, 否则没有检验到磁盘负载
+    if PASSES.load(Ordering::SeqCst) < 2 || requests < 100 {
+        fail(format_args!(
+            "only {} passes and {} disk requests during the test",
+            PASSES.load(Ordering::SeqCst),
+            requests
+        ));
+    }
+    let hz = time::tsc_frequency().expect("TSC not calibrated");
+    let micros = |cycles: u64| cycles.saturating_mul(1_000_000) / hz;
+    let p90 = micros(latency.percentile(90));
+    let max = micros(latency.max_cycles);
+    if p90 > P90_LIMIT_US || max > MAX_LIMIT_US {
+        fail(format_args!(
+            "input latency p90 {}us max {}us with {} disk requests",
+            p90, max, requests
+        ));
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
+
+// 经过VFS读取卷上的文件. 每遍访问的块比缓存的槽位多, 大部分扇区都要从磁盘读取
+async fn read_volume() -> Result<(), KernelError> {
+    let mut buf = [0u8; 512];
+    for i in 0..20 {
+        let mut file = vfs::open(&format!("/disk/MANY/FILE{:02}.TXT", i)).await?;
+        file.read(&mut buf).await?;
+    }
+    let mut long =
+        vfs::open("/disk/DOCS/A much longer name that needs several LFN entries.bin").await?;
+    while long.read(&mut buf).await? > 0 {}
+    Ok(())
+}
+
+fn fail(message: fmt::Arguments) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", message);
+    exit_qemu(QemuExitCode::Failed);
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
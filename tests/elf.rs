@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::cpu::msr::Efer;
+use toy_os::elf::{self, ElfError};
+use toy_os::memory::{self, AddressSpace, MemError};
+use toy_os::thread;
+use toy_os::usermode::{self, UserExit};
+use toy_os::vga_buffer::{self, BUFFER_HEIGHT};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn on_screen(message: &str) -> bool {
+    interrupts::without_interrupts(|| {
+        let writer = vga_buffer::writer().lock();
+        (0..BUFFER_HEIGHT).any(|row| {
+            let line = writer.read_row(row);
+            line.windows(message.len())
+                .any(|window| window == message.as_bytes())
+        })
+    })
+}
+
+// initrd中的/bin/hello由userspace/hello构建
+#[test_case]
+fn test_run_hello() {
+    if !cfg!(feature = "initrd") {
+        return;
+    }
+    const MESSAGE: &str = "hello from an ELF binary";
+    // 加载到内核页表的用户空间, 由当前线程运行
+    let mut kernel = AddressSpace::kernel();
+    let program = thread::block_on(elf::load_path(&mut kernel, "/bin/hello"))
+        .expect("failed to load /bin/hello");
+    let exit = unsafe { usermode::run(program.entry, program.stack_top) };
+    // 退出码1表示bss没有清零或data的初值不对
+    assert_eq!(exit, UserExit::Exit { code: 0 });
+    assert!(on_screen(MESSAGE), "user program output not on screen");
+
+    let text = memory::page_flags(program.entry).unwrap();
+    assert!(text.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!text.contains(PageTableFlags::WRITABLE));
+    assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+    let stack = memory::page_flags(program.stack_top - 8u64).unwrap();
+    assert!(stack.contains(PageTableFlags::WRITABLE));
+    assert_eq!(
+        stack.contains(PageTableFlags::NO_EXECUTE),
+        Efer::read().nx_enabled()
+    );
+
+    // 地址已被第一次加载占用
+    assert!(matches!(
+        thread::block_on(elf::load_path(&mut kernel, "/bin/hello")),
+        Err(ElfError::MapFailed(MemError::Overlap(_)))
+    ));
+}
+
+#[test_case]
+fn test_load_errors() {
+    let mut space = AddressSpace::new().unwrap();
+    assert_eq!(
+        thread::block_on(elf::load_path(&mut space, "/no/such/program")),
+        Err(ElfError::Io(toy_os::vfs::VfsError::NotFound.into()))
+    );
+    if cfg!(feature = "initrd") {
+        assert_eq!(
+            thread::block_on(elf::load_path(&mut space, "/etc/motd")),
+            Err(ElfError::BadMagic)
+        );
+    }
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use toy_os::cpu::msr;
+use toy_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// 之后的panic来自恢复区域之外的异常
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    serial_print!("fatal_fault::fault_outside_recovery...\t");
+    // 恢复区域内的一般保护异常不致命
+    if msr::try_read(0xDEAD_BEEF).is_some() {
+        fail("bogus MSR was readable");
+    }
+    EXPECT_PANIC.store(true, Ordering::SeqCst);
+    unsafe { msr::read(0xDEAD_BEEF) };
+    fail("fault outside the recovery region was not fatal");
+}
+
+fn fail(message: &str) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", message);
+    exit_qemu(QemuExitCode::Failed);
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !EXPECT_PANIC.load(Ordering::SeqCst) {
+        toy_os::test_panic_handler(info);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::allocator::{self, HEAP_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+// 分配总量超过堆大小, 要求释放的内存能被复用
+#[test_case]
+fn many_boxes() {
+    #[cfg(feature = "alloc-track")]
+    let checkpoint = toy_os::alloc_track::checkpoint();
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    #[cfg(feature = "alloc-track")]
+    toy_os::alloc_track::assert_no_leaks(checkpoint);
+}
+
+// bump分配器在有存活分配时无法回收, 不运行此测试
+#[cfg(not(feature = "bump_allocator"))]
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn heap_stats_track_allocations() {
+    let before = allocator::heap_stats();
+    assert_eq!(before.size, HEAP_SIZE);
+    let data = core::hint::black_box(Box::new([0u8; 1000]));
+    let during = allocator::heap_stats();
+    assert_eq!(during.used, before.used + 1000);
+    assert_eq!(during.allocations, before.allocations + 1);
+    drop(data);
+    assert_eq!(allocator::heap_stats(), before);
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use toy_os::{exit_qemu, serial_print, serial_println, symbols, QemuExitCode};
+
+entry_point!(main);
+
+// 之后的panic来自释放时的哨兵检查
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+#[inline(never)]
+fn allocate_victim() -> Box<[u8]> {
+    Box::new([0u8; 24])
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    serial_print!("heap_canary::overrun_detected_at_free...\t");
+    let mut victim = core::hint::black_box(allocate_victim());
+    let len = victim.len();
+    unsafe { victim.as_mut_ptr().add(len).write(0x41) };
+    EXPECT_PANIC.store(true, Ordering::SeqCst);
+    drop(victim);
+    serial_println!("[failed]\n");
+    serial_println!("Error: one-byte overrun was not detected at free\n");
+    exit_qemu(QemuExitCode::Failed);
+    toy_os::hlt_loop();
+}
+
+// 保存panic信息的前一部分, 用于检查内容
+struct Message {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !EXPECT_PANIC.load(Ordering::SeqCst) {
+        toy_os::test_panic_handler(info);
+    }
+    let mut message = Message {
+        bytes: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.bytes[..message.len]).unwrap_or("");
+    let attributed = symbols::kernel_symbols().is_none() || message.contains("allocate_victim");
+    if !(message.contains("heap corruption: canary after 24-byte allocation") && attributed) {
+        toy_os::test_panic_handler(info);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
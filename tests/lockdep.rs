@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use toy_os::sync::IrqMutex;
+use toy_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static LOCK_A: IrqMutex<u32> = IrqMutex::new("lockdep_test_a", 0);
+static LOCK_B: IrqMutex<u32> = IrqMutex::new("lockdep_test_b", 0);
+
+// 之后的panic来自lockdep的报告
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    toy_os::init();
+    serial_print!("lockdep::ab_ba_ordering...\t");
+    {
+        let _a = LOCK_A.lock();
+        let _b = LOCK_B.lock();
+    }
+    // 单处理器上依次执行不会死锁, 与上面并发时才会. lockdep应在获取A之前报告
+    let _b = LOCK_B.lock();
+    EXPECT_PANIC.store(true, Ordering::SeqCst);
+    let _a = LOCK_A.lock();
+    serial_println!("[failed]\n");
+    serial_println!("Error: BA ordering after AB was not reported\n");
+    exit_qemu(QemuExitCode::Failed);
+    toy_os::hlt_loop();
+}
+
+// 保存panic信息的前一部分, 用于检查内容
+struct Message {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !EXPECT_PANIC.load(Ordering::SeqCst) {
+        toy_os::test_panic_handler(info);
+    }
+    let mut message = Message {
+        bytes: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.bytes[..message.len]).unwrap_or("");
+    if !(message.contains("lock order violation")
+        && message.contains("acquiring lockdep_test_a while holding lockdep_test_b"))
+    {
+        toy_os::test_panic_handler(info);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
@@ -0,0 +1,216 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::future;
+use futures_util::stream::StreamExt;
+use toy_os::net::tcp::{TcpError, TcpSocket};
+use toy_os::net::udp::UdpSocket;
+use toy_os::net::{self, Ipv4Addr, MacAddress};
+use toy_os::task::executor::Executor;
+use toy_os::task::Task;
+use toy_os::time;
+
+// QEMU user模式网络的默认地址
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+// build.rs放在TFTP目录中的文件
+const TFTP_FILE: (&str, &[u8]) = ("hello.txt", b"hello from the host\n");
+const TFTP_PORT: u16 = 69;
+const TFTP_RRQ: u16 = 1;
+const TFTP_DATA: u16 = 3;
+const TFTP_ACK: u16 = 4;
+// guestfwd把发往这个地址的连接交给输出build.rs生成的响应的命令
+const HTTP_SERVER: ([u8; 4], u16) = ([10, 0, 2, 100], 80);
+const HTTP_BODY: &[u8] = b"hello over tcp\n";
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// 广播询问网关的MAC地址
+fn arp_request(mac: MacAddress) -> [u8; 42] {
+    let mut frame = [0u8; 42];
+    frame[0..6].copy_from_slice(&MacAddress::BROADCAST.0);
+    frame[6..12].copy_from_slice(&mac.0);
+    frame[12..14].copy_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+    // 以太网/IPv4, 地址长度6和4
+    frame[14..20].copy_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4]);
+    frame[20..22].copy_from_slice(&ARP_REQUEST.to_be_bytes());
+    frame[22..28].copy_from_slice(&mac.0);
+    frame[28..32].copy_from_slice(&GUEST_IP);
+    frame[38..42].copy_from_slice(&GATEWAY_IP);
+    frame
+}
+
+fn is_arp_reply(frame: &net::Frame, mac: MacAddress) -> bool {
+    frame.ethertype() == Some(ETHERTYPE_ARP)
+        && frame.len() >= 42
+        && frame[20..22] == ARP_REPLY.to_be_bytes()
+        && frame[28..32] == GATEWAY_IP
+        && frame[32..38] == mac.0
+}
+
+#[test_case]
+fn test_arp_reply() {
+    static REPLIED: AtomicBool = AtomicBool::new(false);
+
+    let device = net::e1000::device().expect("e1000 not found");
+    let mac = device.mac_address();
+    assert!(device.link_up());
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        let mut frames = net::rx_frames();
+        net::send(&arp_request(mac)).expect("transmit failed");
+        let reply = time::timeout(time::secs_to_ticks(2), async {
+            while let Some(frame) = frames.next().await {
+                if is_arp_reply(&frame, mac) {
+                    return true;
+                }
+            }
+            false
+        });
+        REPLIED.store(reply.await == Ok(true), Ordering::SeqCst);
+    }));
+    executor.run_until_idle();
+
+    assert!(REPLIED.load(Ordering::SeqCst), "no ARP reply from the gateway");
+    assert!(device.irq().is_none() || device.interrupt_count() > 0);
+}
+
+#[test_case]
+fn test_send_rejects_invalid_length() {
+    assert_eq!(net::send(&[]), Err(net::TxError::InvalidLength));
+    assert_eq!(net::send(&[0; net::MAX_FRAME_SIZE + 1]), Err(net::TxError::InvalidLength));
+}
+
+// 在协议栈任务运行期间执行`test`, 最多等待10秒
+fn run_with_stack(test: impl Future<Output = ()> + 'static) {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        let stack = Box::pin(net::stack::run());
+        let test = Box::pin(time::timeout(time::secs_to_ticks(10), test));
+        let _ = future::select(stack, test).await;
+    }));
+    executor.run_until_idle();
+}
+
+#[test_case]
+fn test_dhcp_lease() {
+    static LEASED: AtomicBool = AtomicBool::new(false);
+
+    run_with_stack(async {
+        let lease = net::dhcp::acquire().await.expect("dhcp failed");
+        assert_eq!(lease.ip, Ipv4Addr::from(GUEST_IP));
+        assert_eq!(lease.gateway, Some(Ipv4Addr::from(GATEWAY_IP)));
+        LEASED.store(true, Ordering::SeqCst);
+    });
+
+    assert!(LEASED.load(Ordering::SeqCst), "no DHCP lease");
+    let config = net::config().unwrap();
+    assert_eq!(config.ip, Ipv4Addr::from(GUEST_IP));
+    assert_eq!(config.netmask, Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(config.gateway, Ipv4Addr::from(GATEWAY_IP));
+}
+
+#[test_case]
+fn test_udp_exchange() {
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    let mac = net::mac_address().unwrap();
+    let gateway = Ipv4Addr::from(GATEWAY_IP);
+    net::configure(Ipv4Addr::from(GUEST_IP), Ipv4Addr::new(255, 255, 255, 0), gateway, mac);
+    run_with_stack(async move {
+        // 以octet模式读取文件, 确认第一个数据块时发往回复所用的端口
+        let socket = UdpSocket::bind(0).unwrap();
+        let mut request = Vec::from(TFTP_RRQ.to_be_bytes());
+        request.extend_from_slice(TFTP_FILE.0.as_bytes());
+        request.extend_from_slice(b"\0octet\0");
+        socket.send_to(&request, gateway, TFTP_PORT).await.expect("send failed");
+
+        let mut buf = [0u8; 600];
+        let (length, source, port) = socket.recv_from(&mut buf).await;
+        assert_eq!(source, gateway);
+        assert_eq!(buf[0..4], [0, TFTP_DATA as u8, 0, 1]);
+        assert_eq!(&buf[4..length], TFTP_FILE.1);
+
+        let mut ack = [0u8; 4];
+        ack[0..2].copy_from_slice(&TFTP_ACK.to_be_bytes());
+        ack[2..4].copy_from_slice(&1u16.to_be_bytes());
+        socket.send_to(&ack, gateway, port).await.expect("send failed");
+        RECEIVED.store(true, Ordering::SeqCst);
+    });
+
+    assert!(RECEIVED.load(Ordering::SeqCst), "no reply from the TFTP server");
+}
+
+#[test_case]
+fn test_udp_bind_collision() {
+    let socket = UdpSocket::bind(5000).unwrap();
+    assert_eq!(UdpSocket::bind(5000).err(), Some(net::udp::UdpError::AddressInUse));
+    drop(socket);
+    assert!(UdpSocket::bind(5000).is_ok());
+}
+
+#[test_case]
+fn test_http_get() {
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    let mac = net::mac_address().unwrap();
+    let gateway = Ipv4Addr::from(GATEWAY_IP);
+    net::configure(Ipv4Addr::from(GUEST_IP), Ipv4Addr::new(255, 255, 255, 0), gateway, mac);
+    run_with_stack(async move {
+        let server = Ipv4Addr::from(HTTP_SERVER.0);
+        let response = net::http::get(server, HTTP_SERVER.1, "/")
+            .await
+            .expect("GET failed");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, HTTP_BODY);
+        RECEIVED.store(true, Ordering::SeqCst);
+    });
+
+    assert!(RECEIVED.load(Ordering::SeqCst), "no HTTP response");
+}
+
+#[test_case]
+fn test_tcp_connection_refused() {
+    static REFUSED: AtomicBool = AtomicBool::new(false);
+
+    let mac = net::mac_address().unwrap();
+    let gateway = Ipv4Addr::from(GATEWAY_IP);
+    net::configure(Ipv4Addr::from(GUEST_IP), Ipv4Addr::new(255, 255, 255, 0), gateway, mac);
+    run_with_stack(async move {
+        // 主机上没有监听的端口, user模式网络回复RST
+        let result = TcpSocket::connect(gateway, 9).await;
+        assert_eq!(result.err(), Some(TcpError::ConnectionRefused));
+        REFUSED.store(true, Ordering::SeqCst);
+    });
+
+    assert!(REFUSED.load(Ordering::SeqCst), "connect did not fail");
+}
@@ -0,0 +1,248 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::cell::RefCell;
+use core::future::Future;
+use core::panic::PanicInfo;
+use toy_os::elf::ElfError;
+use toy_os::layout;
+use toy_os::memory;
+use toy_os::process::{self, Pid, SpawnError, WaitError};
+use toy_os::shell;
+use toy_os::task::executor::Executor;
+use toy_os::task::Task;
+use toy_os::thread;
+use toy_os::usermode::UserExit;
+use toy_os::vfs::VfsError;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// 在新的执行器中运行`future`直到完成
+fn block_on<T: 'static>(future: impl Future<Output = T> + 'static) -> T {
+    let result = Rc::new(RefCell::new(None));
+    let slot = result.clone();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        *slot.borrow_mut() = Some(future.await);
+    }));
+    executor.run_until_idle();
+    let value = result.borrow_mut().take();
+    value.expect("future did not complete")
+}
+
+// 同时运行两个/bin/hello, 等待它们结束, 返回两者的退出原因
+fn run_two_hellos() -> [UserExit; 2] {
+    let first = thread::block_on(process::spawn("/bin/hello")).expect("failed to spawn /bin/hello");
+    let second =
+        thread::block_on(process::spawn("/bin/hello")).expect("failed to spawn /bin/hello");
+    assert_ne!(first, second);
+    let pids: Vec<Pid> = process::list().iter().map(|info| info.pid).collect();
+    assert!(pids.contains(&first) && pids.contains(&second));
+
+    let exits = block_on(async move {
+        let first = process::wait(first).await.unwrap();
+        let second = process::wait(second).await.unwrap();
+        [first, second]
+    });
+    // 已经等待过的进程从列表中删除
+    assert!(process::list().is_empty());
+    assert_eq!(
+        block_on(process::wait(first)),
+        Err(WaitError::NoSuchProcess)
+    );
+    exits
+}
+
+#[test_case]
+fn test_concurrent_processes() {
+    if !cfg!(feature = "initrd") {
+        return;
+    }
+    const EXIT: UserExit = UserExit::Exit { code: 0 };
+    // 第一次运行可能为新的内核栈地址分配页表
+    assert_eq!(run_two_hellos(), [EXIT; 2]);
+    let baseline = memory::frame_stats().unwrap().allocated;
+    assert_eq!(run_two_hellos(), [EXIT; 2]);
+    // 地址空间、用户页和内核栈都已释放
+    assert_eq!(memory::frame_stats().unwrap().allocated, baseline);
+}
+
+// 运行/bin/forktest, 等待父子两个进程结束, 返回两者的退出原因
+fn run_forktest() -> [UserExit; 2] {
+    let parent =
+        thread::block_on(process::spawn("/bin/forktest")).expect("failed to spawn /bin/forktest");
+    block_on(async move {
+        let parent_exit = process::wait(parent).await.unwrap();
+        // 子进程在父进程结束前已经创建
+        let child = process::list()
+            .iter()
+            .map(|info| info.pid)
+            .find(|&pid| pid != parent)
+            .expect("forktest did not fork");
+        let child_exit = process::wait(child).await.unwrap();
+        [parent_exit, child_exit]
+    })
+}
+
+#[test_case]
+fn test_fork_copies_on_write() {
+    if !cfg!(feature = "initrd") {
+        return;
+    }
+    const EXIT: UserExit = UserExit::Exit { code: 0 };
+    // 父进程的缓冲区没有看到子进程的写入, 子进程看到了自己的写入
+    assert_eq!(run_forktest(), [EXIT; 2]);
+    let baseline = memory::frame_stats().unwrap();
+    assert_eq!(run_forktest(), [EXIT; 2]);
+    let after = memory::frame_stats().unwrap();
+    // 共享的帧和复制出的帧都已释放
+    assert_eq!(after.allocated, baseline.allocated);
+    assert_eq!(after.shared, 0);
+    assert!(process::list().is_empty());
+}
+
+// 运行/bin/mmaptest并等待它结束
+fn run_mmaptest() -> UserExit {
+    let pid =
+        thread::block_on(process::spawn("/bin/mmaptest")).expect("failed to spawn /bin/mmaptest");
+    block_on(async move { process::wait(pid).await.unwrap() })
+}
+
+#[test_case]
+fn test_mmap_maps_on_first_touch() {
+    if !cfg!(feature = "initrd") {
+        return;
+    }
+    // mmaptest检查完所有页后取消映射, 再次访问时由页错误结束
+    let exit = run_mmaptest();
+    let UserExit::Fault(fault) = exit else {
+        panic!("mmaptest did not fault after munmap: {:?}", exit);
+    };
+    assert!(layout::USER.contains(fault.addr.unwrap().as_u64()));
+    let error_code = fault.page_fault_error().unwrap();
+    assert!(!error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+    let baseline = memory::frame_stats().unwrap().allocated;
+    assert!(matches!(run_mmaptest(), UserExit::Fault(_)));
+    // 按需映射的帧在munmap时释放, 页表随地址空间释放
+    assert_eq!(memory::frame_stats().unwrap().allocated, baseline);
+}
+
+// 运行/bin/crash, 它被结束后检查ps显示的状态, 然后等待它
+fn run_crash() -> UserExit {
+    let pid = thread::block_on(process::spawn("/bin/crash")).expect("failed to spawn /bin/crash");
+    let exited = || {
+        process::list()
+            .iter()
+            .any(|info| info.pid == pid && info.exit.is_some())
+    };
+    for _ in 0..100 {
+        if exited() {
+            break;
+        }
+        thread::sleep_ms(10);
+    }
+    let mut ps = String::new();
+    thread::block_on(shell::dispatch("ps", &mut ps)).unwrap();
+    assert!(
+        ps.lines()
+            .any(|line| line.contains("crash") && line.contains("killed: page fault at 0x8,")),
+        "{}",
+        ps
+    );
+    block_on(async move { process::wait(pid).await.unwrap() })
+}
+
+#[test_case]
+fn test_faulting_process_is_killed() {
+    if !cfg!(feature = "initrd") {
+        return;
+    }
+    let exit = run_crash();
+    let UserExit::Fault(fault) = exit else {
+        panic!("crash was not killed: {:?}", exit);
+    };
+    assert_eq!(fault.addr, Some(VirtAddr::new(8)));
+    assert!(layout::USER.contains(fault.rip.as_u64()));
+    let error_code = fault.page_fault_error().expect("not a page fault");
+    let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+    assert!(error_code.contains(write));
+    let baseline = memory::frame_stats().unwrap().allocated;
+    assert_eq!(run_crash(), exit);
+    // 被结束的进程的地址空间、用户页和内核栈都已释放
+    assert_eq!(memory::frame_stats().unwrap().allocated, baseline);
+    // 内核仍然正常, 其他程序照常运行
+    let hello = thread::block_on(process::spawn("/bin/hello")).expect("failed to spawn /bin/hello");
+    assert_eq!(
+        block_on(async move { process::wait(hello).await.unwrap() }),
+        UserExit::Exit { code: 0 }
+    );
+    assert!(process::list().is_empty());
+}
+
+#[test_case]
+fn test_spawn_errors() {
+    let baseline = memory::frame_stats().unwrap().allocated;
+    assert_eq!(
+        thread::block_on(process::spawn("/no/such/program")),
+        Err(SpawnError::Load(ElfError::Io(VfsError::NotFound.into())))
+    );
+    if cfg!(feature = "initrd") {
+        assert_eq!(
+            thread::block_on(process::spawn("/etc/motd")),
+            Err(SpawnError::Load(ElfError::BadMagic))
+        );
+    }
+    // 加载失败时新建的页表也被释放
+    assert_eq!(memory::frame_stats().unwrap().allocated, baseline);
+}
+
+#[test_case]
+fn test_run_and_ps_commands() {
+    if !cfg!(feature = "initrd") {
+        return;
+    }
+    let mut out = String::new();
+    thread::block_on(shell::dispatch("run /bin/hello", &mut out)).unwrap();
+    let pid = out
+        .trim()
+        .strip_prefix("started process ")
+        .expect("unexpected run output");
+
+    let mut ps = String::new();
+    thread::block_on(shell::dispatch("ps", &mut ps)).unwrap();
+    assert!(
+        ps.lines()
+            .any(|line| line.split_whitespace().take(2).eq([pid, "hello"])),
+        "{}",
+        ps
+    );
+    // run留下的等待任务在进程结束后把它从列表中删除
+    Executor::new().run_until_idle();
+    assert!(process::list().is_empty());
+
+    let mut out = String::new();
+    assert!(thread::block_on(shell::dispatch("run", &mut out)).is_err());
+}
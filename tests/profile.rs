@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt;
+use core::panic::PanicInfo;
+use toy_os::{exit_qemu, println, profile, serial_print, serial_println, vga_buffer, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    serial_print!("profile::print_stress...\t");
+    let single = stress();
+    if single.0 != "vga_buffer::WRITER" {
+        fail(format_args!(
+            "longest critical section was {}, not the VGA writer",
+            single.0
+        ));
+    }
+    vga_buffer::set_double_buffered(true);
+    let double = stress();
+    vga_buffer::set_double_buffered(false);
+    if double.1 >= single.1 {
+        fail(format_args!(
+            "double buffering did not shorten the critical section ({} >= {} cycles)",
+            double.1, single.1
+        ));
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
+
+// 每行都会滚屏, 返回期间最长的临界区
+fn stress() -> (&'static str, u64) {
+    profile::reset();
+    for i in 0..500 {
+        println!(
+            "{:04} the quick brown fox jumps over the lazy dog, again and again and again",
+            i
+        );
+    }
+    profile::longest_critical_section().unwrap_or(("none", 0))
+}
+
+fn fail(message: fmt::Arguments) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", message);
+    exit_qemu(QemuExitCode::Failed);
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::cell::RefCell;
+use core::future::Future;
+use core::panic::PanicInfo;
+use toy_os::memory::{self, FrameUsage};
+use toy_os::process::{self, Pid};
+use toy_os::task::executor::Executor;
+use toy_os::task::Task;
+use toy_os::usermode::UserExit;
+use toy_os::{thread, vfs};
+
+// limittest设置的页数和子进程数上限, 与userspace/limittest一致
+const PAGE_LIMIT: u64 = 64;
+const CHILD_LIMIT: u64 = 1;
+// limittest不是初始进程时的退出码
+const NOT_INIT: UserExit = UserExit::Exit { code: 10 };
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// 在新的执行器中运行`future`直到完成
+fn block_on<T: 'static>(future: impl Future<Output = T> + 'static) -> T {
+    let result = Rc::new(RefCell::new(None));
+    let slot = result.clone();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        *slot.borrow_mut() = Some(future.await);
+    }));
+    executor.run_until_idle();
+    let value = result.borrow_mut().take();
+    value.expect("future did not complete")
+}
+
+// 等待`pid`结束但不从列表中删除, 返回它的/proc/<pid>/status
+fn status_after_exit(pid: Pid) -> String {
+    while process::list()
+        .iter()
+        .any(|info| info.pid == pid && info.exit.is_none())
+    {
+        thread::sleep_ms(10);
+    }
+    let mut file = thread::block_on(vfs::open(&format!("/proc/{}/status", pid))).unwrap();
+    let mut data = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        match thread::block_on(file.read(&mut buf)).unwrap() {
+            0 => break,
+            len => data.extend_from_slice(&buf[..len]),
+        }
+    }
+    String::from_utf8(data).unwrap()
+}
+
+// 必须是第一个测试, limittest要作为初始进程运行
+#[test_case]
+fn test_limits_are_enforced() {
+    if !cfg!(feature = "initrd") {
+        return;
+    }
+    let user_frames = memory::frame_stats().unwrap().usage(FrameUsage::User);
+    let pid =
+        thread::block_on(process::spawn("/bin/limittest")).expect("failed to spawn /bin/limittest");
+    assert_eq!(pid.as_u64(), 1);
+    let status = status_after_exit(pid);
+    // 结束后资源都已归还, 上限是limittest设置的值
+    assert!(
+        status.contains("State:    exited with code 0"),
+        "{}",
+        status
+    );
+    assert!(status.contains("Pages:    0 / 64"), "{}", status);
+    assert!(status.contains("Files:    0 / 2"), "{}", status);
+    let exit = block_on(async move { process::wait(pid).await.unwrap() });
+    assert_eq!(exit, UserExit::Exit { code: 0 });
+    // 两个子进程继承了fork时的上限, 第一个在写时复制之后仍能映射正好剩余的页数
+    let children = process::list();
+    assert_eq!(children.len(), 2);
+    for child in children {
+        let limits = child.limits;
+        assert_eq!((limits.pages, limits.children), (PAGE_LIMIT, CHILD_LIMIT));
+        let exit = block_on(async move { process::wait(child.pid).await.unwrap() });
+        assert_eq!(exit, UserExit::Exit { code: 0 });
+    }
+    // 用户页都已释放, 内核没有受到影响
+    assert_eq!(
+        memory::frame_stats().unwrap().usage(FrameUsage::User),
+        user_frames
+    );
+}
+
+#[test_case]
+fn test_only_init_sets_limits() {
+    if !cfg!(feature = "initrd") {
+        return;
+    }
+    let pid =
+        thread::block_on(process::spawn("/bin/limittest")).expect("failed to spawn /bin/limittest");
+    let info = process::list()
+        .into_iter()
+        .find(|info| info.pid == pid)
+        .unwrap();
+    assert_eq!(info.limits, process::DEFAULT_LIMITS);
+    assert_eq!(
+        block_on(async move { process::wait(pid).await.unwrap() }),
+        NOT_INIT
+    );
+    let hello = thread::block_on(process::spawn("/bin/hello")).expect("failed to spawn /bin/hello");
+    assert_eq!(
+        block_on(async move { process::wait(hello).await.unwrap() }),
+        UserExit::Exit { code: 0 }
+    );
+    assert!(process::list().is_empty());
+}
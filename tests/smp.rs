@@ -0,0 +1,174 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use toy_os::percpu::{self, PerCpu};
+use toy_os::vga_buffer;
+use toy_os::{println, smp};
+
+// test-args中的-smp 4
+const EXPECTED_CPUS: u32 = 4;
+const LINES_PER_CPU: usize = 50;
+
+// 模拟在输出中途停下的处理器
+const HOLDER: u32 = 1;
+
+// AP都上线后再同时开始打印
+static GO: AtomicBool = AtomicBool::new(false);
+static FINISHED: AtomicU32 = AtomicU32::new(0);
+// HOLDER在HOLD之后获取WRITER并且不再释放
+static HOLD: AtomicBool = AtomicBool::new(false);
+static HELD: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// 每行都经过堆分配, 同时考验WRITER和分配器的锁
+fn print_lines(cpu_id: u32) {
+    for i in 0..LINES_PER_CPU {
+        let line = format!("cpu {} line {:03}", cpu_id, i);
+        let copy: Vec<u8> = line.bytes().collect();
+        assert_eq!(copy, line.as_bytes());
+        println!("{}", line);
+    }
+}
+
+fn stress(percpu: &'static PerCpu) {
+    while !GO.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    print_lines(percpu.cpu_id);
+    FINISHED.fetch_add(1, Ordering::SeqCst);
+    if percpu.cpu_id != HOLDER {
+        return;
+    }
+    while !HOLD.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    let _writer = vga_buffer::writer().lock();
+    HELD.store(true, Ordering::SeqCst);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn test_boot_aps() {
+    let report = smp::boot_aps_with(stress).expect("failed to start APs");
+    assert_eq!(report.timed_out, Vec::new());
+    assert_eq!(report.online.len() as u32, EXPECTED_CPUS - 1);
+    assert_eq!(smp::online_cpus(), EXPECTED_CPUS);
+    assert_eq!(percpu::get().cpu_id, 0);
+}
+
+#[test_case]
+fn test_boot_aps_twice() {
+    assert_eq!(smp::boot_aps(), Err(smp::SmpError::AlreadyStarted));
+}
+
+// 输出记录需要console-recording特性: `cargo test --test smp --features console-recording`
+#[cfg(feature = "console-recording")]
+#[test_case]
+fn test_concurrent_print() {
+    use toy_os::{print, time};
+
+    // 时钟中断的"."会接在某一行中间
+    toy_os::interrupts::set_print_ticks(false);
+    vga_buffer::set_cpu_prefix(true);
+    vga_buffer::start_recording(32 * 1024);
+    // 从行首开始, 之后每行都有前缀
+    println!();
+    GO.store(true, Ordering::SeqCst);
+    print_lines(0);
+    let aps = smp::online_cpus() - 1;
+    time::wait_until(2000, || FINISHED.load(Ordering::SeqCst) == aps)
+        .expect("APs did not finish printing");
+    let transcript = vga_buffer::stop_recording();
+    vga_buffer::set_cpu_prefix(false);
+    toy_os::interrupts::set_print_ticks(true);
+
+    // 每行必须是某一次println的完整输出, 前缀是打印它的处理器
+    let mut next = [0; EXPECTED_CPUS as usize];
+    // 跳过开头的空行
+    let lines = transcript
+        .lines()
+        .filter(|line| !matches!(line.trim_end(), "" | "[cpu0]"));
+    for text in lines {
+        let (prefix, line) = text.split_once("] ").expect("missing prefix");
+        let prefix: u32 = prefix
+            .strip_prefix("[cpu")
+            .and_then(|id| id.parse().ok())
+            .unwrap_or_else(|| panic!("bad prefix: {:?}", text));
+        let mut words = line.split(' ');
+        assert_eq!(words.next(), Some("cpu"), "torn line: {:?}", text);
+        let cpu: u32 = words
+            .next()
+            .and_then(|w| w.parse().ok())
+            .expect("bad cpu id");
+        assert_eq!(cpu, prefix, "line attributed to the wrong CPU: {:?}", text);
+        assert_eq!(words.next(), Some("line"), "torn line: {:?}", text);
+        let number: usize = words
+            .next()
+            .and_then(|w| w.parse().ok())
+            .unwrap_or_else(|| panic!("torn line: {:?}", text));
+        assert_eq!(words.next(), None, "torn line: {:?}", text);
+        // 同一处理器的行按顺序出现, 没有丢失
+        assert_eq!(number, next[cpu as usize], "out of order: {:?}", text);
+        next[cpu as usize] += 1;
+    }
+    assert_eq!(next, [LINES_PER_CPU; EXPECTED_CPUS as usize]);
+}
+
+#[cfg(feature = "console-recording")]
+#[test_case]
+fn test_panic_while_printing() {
+    use toy_os::time;
+    use x86_64::instructions::interrupts;
+
+    toy_os::interrupts::set_print_ticks(false);
+    // panic发生在本处理器持有WRITER时, 直接释放
+    let writer = vga_buffer::writer().lock();
+    vga_buffer::start_recording(1024);
+    vga_buffer::unlock_for_panic();
+    core::mem::forget(writer);
+    interrupts::enable();
+    println!("printed after releasing our own lock");
+
+    // 另一个处理器停在输出中途, 等待超时后取走它的锁而不是死锁
+    HOLD.store(true, Ordering::SeqCst);
+    time::wait_until(1000, || HELD.load(Ordering::SeqCst)).expect("holder did not take the lock");
+    assert_eq!(vga_buffer::writer().owner(), Some(HOLDER));
+    vga_buffer::unlock_for_panic();
+    println!("printed after taking the lock");
+    let transcript = vga_buffer::stop_recording();
+    toy_os::interrupts::set_print_ticks(true);
+    let lines: Vec<_> = transcript.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "printed after releasing our own lock",
+            "panic: took the console lock from CPU 1",
+            "printed after taking the lock",
+        ]
+    );
+}
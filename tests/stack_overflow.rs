@@ -4,22 +4,11 @@
 
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use toy_os::{exit_qemu, QemuExitCode, serial_println, serial_print};
-use lazy_static::lazy_static;
+use conquer_once::spin::OnceCell;
 use core::panic::PanicInfo;
 
 
-lazy_static! {
-    static ref TEST_IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(test_double_fault_handler)
-                .set_stack_index(toy_os::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-
-        idt
-    };
-}
+static TEST_IDT: OnceCell<InterruptDescriptorTable> = OnceCell::uninit();
 
 extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
@@ -27,11 +16,25 @@ extern "x86-interrupt" fn test_double_fault_handler(
 ) -> ! {
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
-    loop {}
+    toy_os::hlt_loop();
+}
+
+fn build_test_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(test_double_fault_handler)
+            .set_stack_index(toy_os::gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+
+    idt
 }
 
 pub fn init_test_idt() {
-    TEST_IDT.load();
+    TEST_IDT
+        .try_init_once(build_test_idt)
+        .expect("test IDT initialized twice");
+    TEST_IDT.try_get().unwrap().load();
 }
 
 #[no_mangle]
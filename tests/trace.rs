@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::fmt;
+use core::panic::PanicInfo;
+use toy_os::trace::{self, EventId, Record};
+use toy_os::{exit_qemu, ps2, serial_print, serial_println, time, QemuExitCode};
+
+// 'a'按下和松开的扫描码
+const KEY_DOWN: u8 = 0x1E;
+const KEY_UP: u8 = 0x9E;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    serial_print!("trace::keypress_to_echo...\t");
+    trace::clear();
+    if let Err(err) = ps2::inject_scancode(KEY_DOWN).and_then(|()| ps2::inject_scancode(KEY_UP)) {
+        fail(format_args!("cannot inject scancodes: {:?}", err));
+    }
+    // 没有按键的接收端, 键盘中断直接把字符回显到屏幕
+    let _ = time::wait_until(1000, || {
+        records()
+            .iter()
+            .any(|record| is(record, EventId::Scancode, u64::from(KEY_UP)))
+    });
+    let records = records();
+    let expected = [
+        (EventId::IrqEntry, 33),
+        (EventId::Scancode, u64::from(KEY_DOWN)),
+        (EventId::KeyDecoded, 'a' as u64),
+        (EventId::LockAcquire, trace::lock_arg("vga_buffer::WRITER")),
+        (EventId::LockRelease, trace::lock_arg("vga_buffer::WRITER")),
+        (EventId::IrqExit, 33),
+    ];
+    // 期望的事件按顺序出现, 中间可以有其他事件
+    let mut rest = records.iter();
+    for (event, arg) in expected {
+        if !rest.any(|record| is(record, event, arg)) {
+            fail(format_args!(
+                "{} {:#x} missing or out of order",
+                event.name(),
+                arg
+            ));
+        }
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
+
+fn records() -> Vec<Record> {
+    let mut records = Vec::new();
+    trace::for_each(|record| records.push(*record));
+    records
+}
+
+// 锁名的参数包含字符串地址, 同一个常量字符串可能有多份, 因此比较锁名的内容
+fn is(record: &Record, event: EventId, arg: u64) -> bool {
+    record.event_id == event as u16
+        && match event {
+            EventId::LockAcquire | EventId::LockRelease => {
+                trace::lock_name(record.arg) == trace::lock_name(arg)
+            }
+            _ => record.arg == arg,
+        }
+}
+
+fn fail(message: fmt::Arguments) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", message);
+    let _ = trace::dump(&mut toy_os::serial::Serial);
+    exit_qemu(QemuExitCode::Failed);
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
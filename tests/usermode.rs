@@ -0,0 +1,336 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use core::ptr::addr_of;
+use toy_os::allocator::HEAP_START;
+use toy_os::error::{EFAULT, ENOSYS};
+use toy_os::serial_println;
+use toy_os::layout;
+use toy_os::memory;
+use toy_os::percpu;
+use toy_os::syscall;
+use toy_os::usermode::{self, UserExit};
+use toy_os::vga_buffer::{self, BUFFER_HEIGHT};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// 用户代码页、与内核共享的数据页和两页用户栈
+const CODE: u64 = layout::USER.start;
+const SHARED: u64 = layout::USER.start + 0x1000;
+const STACK_BOTTOM: u64 = layout::USER.start + 0x10000;
+const STACK_TOP: u64 = STACK_BOTTOM + 2 * 4096;
+
+// 基准测试中每种入口的调用次数
+const BENCH_ITERATIONS: u32 = 10_000;
+
+// 与位置无关的用户程序, 运行前复制到CODE页
+global_asm!(
+    r#"
+.pushsection .text
+.global user_sum_start
+user_sum_start:
+    # 1+2+...+10写入共享页
+    mov $10, %ecx
+1:
+    add %rcx, %rax
+    loop 1b
+    movabs ${shared}, %rdi
+    mov %rax, (%rdi)
+    # 在用户栈上留一个值
+    push $0x5a5a
+    int3
+.global user_sum_end
+user_sum_end:
+
+.global user_privileged_start
+user_privileged_start:
+    cli
+.global user_privileged_end
+user_privileged_end:
+
+.global user_kernel_read_start
+user_kernel_read_start:
+    movabs ${kernel}, %rax
+    mov (%rax), %rax
+.global user_kernel_read_end
+user_kernel_read_end:
+
+# 两种进入内核的方式, 由用户程序选择
+.macro int80
+    int $0x80
+.endm
+.macro fast_syscall
+    syscall
+.endm
+
+# 依次写出消息、用内核指针调用write、调用不存在的系统调用, 结果存入共享页后退出
+.macro hello_program call_kernel
+    mov ${write}, %eax
+    mov ${stdout}, %edi
+    lea 8f(%rip), %rsi
+    mov $(9f - 8f), %edx
+    \call_kernel
+    movabs ${shared}, %rbx
+    mov %rax, (%rbx)
+    # 指向内核的指针被拒绝
+    mov ${write}, %eax
+    mov ${stdout}, %edi
+    movabs ${kernel}, %rsi
+    mov $8, %edx
+    \call_kernel
+    mov %rax, 8(%rbx)
+    # 不存在的系统调用
+    mov $999, %eax
+    \call_kernel
+    mov %rax, 16(%rbx)
+    mov ${exit}, %eax
+    mov $42, %edi
+    \call_kernel
+    # exit不返回
+    ud2
+8:
+    .ascii "hello from userspace\n"
+9:
+.endm
+
+# 调用uptime_ms {iterations}次, 消耗的cycle数存入共享页
+.macro bench_program call_kernel
+    rdtsc
+    shl $32, %rdx
+    or %rax, %rdx
+    mov %rdx, %r12
+    mov ${iterations}, %r13d
+1:
+    mov ${uptime}, %eax
+    \call_kernel
+    dec %r13d
+    jnz 1b
+    rdtsc
+    shl $32, %rdx
+    or %rdx, %rax
+    sub %r12, %rax
+    movabs ${shared}, %rdi
+    mov %rax, (%rdi)
+    mov ${exit}, %eax
+    xor %edi, %edi
+    \call_kernel
+    ud2
+.endm
+
+.global user_hello_start
+user_hello_start:
+    hello_program int80
+.global user_hello_end
+user_hello_end:
+
+.global user_hello_fast_start
+user_hello_fast_start:
+    hello_program fast_syscall
+.global user_hello_fast_end
+user_hello_fast_end:
+
+.global user_bench_start
+user_bench_start:
+    bench_program int80
+.global user_bench_end
+user_bench_end:
+
+.global user_bench_fast_start
+user_bench_fast_start:
+    bench_program fast_syscall
+.global user_bench_fast_end
+user_bench_fast_end:
+
+# 用户代码可以随意改写gs, 内核不能依赖用户的GS_BASE
+.global user_null_gs_start
+user_null_gs_start:
+    mov $0, %ax
+    mov %ax, %gs
+    mov ${uptime}, %eax
+    syscall
+    mov ${uptime}, %eax
+    int $0x80
+    mov ${exit}, %eax
+    mov $7, %edi
+    syscall
+    ud2
+.global user_null_gs_end
+user_null_gs_end:
+.popsection
+"#,
+    shared = const SHARED,
+    kernel = const HEAP_START,
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+    stdout = const syscall::STDOUT,
+    uptime = const syscall::SYS_UPTIME_MS,
+    iterations = const BENCH_ITERATIONS,
+    options(att_syntax)
+);
+
+extern "C" {
+    static user_sum_start: u8;
+    static user_sum_end: u8;
+    static user_privileged_start: u8;
+    static user_privileged_end: u8;
+    static user_kernel_read_start: u8;
+    static user_kernel_read_end: u8;
+    static user_hello_start: u8;
+    static user_hello_end: u8;
+    static user_hello_fast_start: u8;
+    static user_hello_fast_end: u8;
+    static user_bench_start: u8;
+    static user_bench_end: u8;
+    static user_bench_fast_start: u8;
+    static user_bench_fast_end: u8;
+    static user_null_gs_start: u8;
+    static user_null_gs_end: u8;
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+
+    let flags = PageTableFlags::WRITABLE;
+    memory::map_user_pages(VirtAddr::new(CODE), 2, flags).expect("failed to map user pages");
+    memory::map_user_pages(VirtAddr::new(STACK_BOTTOM), 2, flags)
+        .expect("failed to map user stack");
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// 把[start, end)之间的程序复制到用户代码页并运行
+fn run_program(start: *const u8, end: *const u8) -> UserExit {
+    let len = end as usize - start as usize;
+    assert!(len <= 4096);
+    unsafe {
+        core::ptr::copy_nonoverlapping(start, CODE as *mut u8, len);
+        usermode::run(VirtAddr::new(CODE), VirtAddr::new(STACK_TOP))
+    }
+}
+
+#[test_case]
+fn test_run_user_code() {
+    let exit = run_program(addr_of!(user_sum_start), addr_of!(user_sum_end));
+    assert_eq!(exit, UserExit::Breakpoint);
+    let shared = unsafe { (SHARED as *const u64).read_volatile() };
+    assert_eq!(shared, 55);
+    let pushed = unsafe { ((STACK_TOP - 8) as *const u64).read_volatile() };
+    assert_eq!(pushed, 0x5a5a);
+    // 回到内核后中断重新打开
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_privileged_instruction() {
+    let exit = run_program(
+        addr_of!(user_privileged_start),
+        addr_of!(user_privileged_end),
+    );
+    let UserExit::Fault(fault) = exit else {
+        panic!("unexpected exit {:?}", exit);
+    };
+    assert_eq!(fault.name(), "general protection fault");
+    assert_eq!((fault.addr, fault.error_code), (None, 0));
+    assert_eq!(fault.rip.as_u64() & !0xfff, CODE);
+}
+
+#[test_case]
+fn test_read_kernel_memory() {
+    let exit = run_program(
+        addr_of!(user_kernel_read_start),
+        addr_of!(user_kernel_read_end),
+    );
+    let UserExit::Fault(fault) = exit else {
+        panic!("unexpected exit {:?}", exit);
+    };
+    assert_eq!(fault.addr, Some(VirtAddr::new(HEAP_START as u64)));
+    let error_code = fault.page_fault_error().expect("not a page fault");
+    assert!(error_code.contains(PageFaultErrorCode::USER_MODE));
+    assert!(error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+    assert!(!error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+}
+
+// 运行写消息的程序, 返回退出原因和三次系统调用的结果
+fn run_hello(start: *const u8, end: *const u8) -> (UserExit, [i64; 3]) {
+    let exit = run_program(start, end);
+    let results = unsafe { (SHARED as *const [i64; 3]).read_volatile() };
+    (exit, results)
+}
+
+fn on_screen(message: &str) -> bool {
+    interrupts::without_interrupts(|| {
+        let writer = vga_buffer::writer().lock();
+        (0..BUFFER_HEIGHT).any(|row| {
+            let line = writer.read_row(row);
+            line.windows(message.len())
+                .any(|window| window == message.as_bytes())
+        })
+    })
+}
+
+#[test_case]
+fn test_syscalls() {
+    const MESSAGE: &str = "hello from userspace";
+    let (exit, results) = run_hello(addr_of!(user_hello_start), addr_of!(user_hello_end));
+    assert_eq!(exit, UserExit::Exit { code: 42 });
+    assert_eq!(results, [MESSAGE.len() as i64 + 1, -EFAULT, -ENOSYS]);
+    assert!(on_screen(MESSAGE), "write syscall output not on screen");
+}
+
+#[test_case]
+fn test_fast_syscalls() {
+    let slow = run_hello(addr_of!(user_hello_start), addr_of!(user_hello_end));
+    let fast = run_hello(
+        addr_of!(user_hello_fast_start),
+        addr_of!(user_hello_fast_end),
+    );
+    assert_eq!(fast, slow);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_syscall_with_null_gs() {
+    let exit = run_program(addr_of!(user_null_gs_start), addr_of!(user_null_gs_end));
+    assert_eq!(exit, UserExit::Exit { code: 7 });
+    // 回到内核后gs仍指向per-CPU数据
+    assert_eq!(percpu::get().cpu_id, 0);
+}
+
+#[test_case]
+fn bench_null_syscall() {
+    if !toy_os::bench::enabled() {
+        return;
+    }
+    let cycles = |start, end| {
+        assert_eq!(run_program(start, end), UserExit::Exit { code: 0 });
+        let total = unsafe { (SHARED as *const u64).read_volatile() };
+        total / u64::from(BENCH_ITERATIONS)
+    };
+    let int80 = cycles(addr_of!(user_bench_start), addr_of!(user_bench_end));
+    let fast = cycles(
+        addr_of!(user_bench_fast_start),
+        addr_of!(user_bench_fast_end),
+    );
+    serial_println!(
+        "bench null syscall: int 0x80 {} / syscall {} cycles/call",
+        int80,
+        fast
+    );
+}
@@ -0,0 +1,231 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::ata::{self, Position};
+use toy_os::block::{IoThread, Partition};
+use toy_os::error::{Context, KernelError};
+use toy_os::fat::{FatError, FatFs};
+use toy_os::thread;
+use toy_os::vfs::{self, SeekFrom, VfsError};
+use toy_os::vga_buffer::{self, BUFFER_HEIGHT};
+use x86_64::instructions::interrupts;
+
+// ATA测试盘上FAT16卷的位置, 与build.rs保持一致
+const FAT16_START: u64 = 2048;
+const FAT16_SECTORS: u64 = 16384;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    toy_os::init_memory(boot_info);
+    mount_disk();
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// 文件系统需要在整个运行期间有效
+fn mount_disk() {
+    let drive = ata::drive(Position::Slave).expect("test image not attached as primary slave");
+    let drive: &'static ata::Drive = Box::leak(Box::new(drive));
+    let partition = Box::leak(Box::new(
+        Partition::new(drive, FAT16_START, FAT16_SECTORS).unwrap(),
+    ));
+    let io = IoThread::spawn("disk", partition).unwrap();
+    let fs = Box::leak(Box::new(
+        thread::block_on(FatFs::mount(io)).expect("failed to mount FAT16 volume"),
+    ));
+    vfs::mount("/disk", fs).unwrap();
+}
+
+#[test_case]
+fn test_read_from_fat() {
+    thread::block_on(async {
+        let mut file = vfs::open("/disk/README.TXT").await.unwrap();
+        let mut buf = [0u8; 64];
+        let len = file.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"fat volume readme\n");
+        assert_eq!(file.read(&mut buf).await, Ok(0));
+        assert_eq!(file.seek(SeekFrom::Start(4)), Ok(4));
+        // FAT不区分大小写, 另一个句柄不影响原来的位置
+        let mut again = vfs::open("/disk/docs/../readme.txt").await.unwrap();
+        assert_eq!(again.read(&mut buf).await, Ok(len));
+        assert_eq!(file.read(&mut buf[..6]).await, Ok(6));
+        assert_eq!(&buf[..6], b"volume");
+    });
+}
+
+#[test_case]
+fn test_read_from_initrd() {
+    thread::block_on(async {
+        // initrd挂载在"/", FAT挂载在它没有的"/disk"
+        if cfg!(feature = "initrd") {
+            let mut motd = vfs::open("/etc/motd").await.unwrap();
+            let mut buf = [0u8; 16];
+            assert_eq!(motd.read(&mut buf).await, Ok(16));
+            assert!(buf.starts_with(b"Welcome"));
+        }
+        let root: Vec<_> = vfs::readdir("/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert!(root.iter().any(|name| name == "disk"));
+        assert!(root.iter().any(|name| name == "dev"));
+        assert!(vfs::metadata("/disk/DOCS").await.unwrap().is_dir());
+    });
+}
+
+#[test_case]
+fn test_write_to_console() {
+    thread::block_on(async {
+        const MESSAGE: &str = "written through /dev/console";
+        let mut console = vfs::open("/dev/console").await.unwrap();
+        assert_eq!(console.write(MESSAGE.as_bytes()).await, Ok(MESSAGE.len()));
+        assert_eq!(console.write(b"\n").await, Ok(1));
+
+        let found = interrupts::without_interrupts(|| {
+            let writer = vga_buffer::writer().lock();
+            (0..BUFFER_HEIGHT).any(|row| {
+                let line = writer.read_row(row);
+                line.windows(MESSAGE.len())
+                    .any(|window| window == MESSAGE.as_bytes())
+            })
+        });
+        assert!(found, "console output not on screen");
+
+        let mut null = vfs::open("/dev/null").await.unwrap();
+        assert_eq!(null.write(&[0; 100]).await, Ok(100));
+        assert_eq!(null.read(&mut [0; 16]).await, Ok(0));
+        assert_eq!(
+            vfs::create("/dev/new").await.err(),
+            Some(VfsError::ReadOnly.into())
+        );
+    });
+}
+
+#[test_case]
+fn test_write_to_fat() {
+    thread::block_on(async {
+        const PATH: &str = "/disk/DOCS/Written at boot.txt";
+        // 测试盘在两次运行之间保留, 先删除上一次留下的文件
+        match vfs::remove(PATH).await {
+            Ok(()) | Err(KernelError::NotFound(_)) => {}
+            Err(err) => panic!("failed to remove {}: {:?}", PATH, err),
+        }
+        let mut file = vfs::create(PATH).await.unwrap();
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(file.write(&data).await, Ok(1000));
+        assert_eq!(file.write(b"tail").await, Ok(4));
+        assert_eq!(
+            vfs::create(PATH).await.err(),
+            Some(FatError::AlreadyExists.into())
+        );
+
+        let mut again = vfs::open("/disk/docs/written AT BOOT.TXT").await.unwrap();
+        assert_eq!(again.metadata().len, 1004);
+        let mut buf = [0u8; 1100];
+        assert_eq!(again.read(&mut buf).await, Ok(1004));
+        assert_eq!(&buf[..1000], &data[..]);
+        assert_eq!(&buf[1000..1004], b"tail");
+
+        vfs::remove(PATH).await.unwrap();
+        assert_eq!(vfs::open(PATH).await.err(), Some(FatError::NotFound.into()));
+        // FAT的错误作为上下文保留下来
+        let err = vfs::remove("/disk/DOCS").await.unwrap_err();
+        assert_eq!(err, FatError::IsADirectory.into());
+        assert_eq!(err.context(), Context::Fat(FatError::IsADirectory));
+    });
+}
+
+// 整个文件的内容, 读到末尾为止
+async fn read_to_string(file: &mut vfs::File) -> String {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 128];
+    loop {
+        match file.read(&mut buf).await.unwrap() {
+            0 => break,
+            len => data.extend_from_slice(&buf[..len]),
+        }
+    }
+    String::from_utf8(data).unwrap()
+}
+
+#[test_case]
+fn test_proc_snapshots() {
+    thread::block_on(async {
+        let mut first = vfs::open("/proc/interrupts").await.unwrap();
+        let before = read_to_string(&mut first).await;
+        // 等待几次时钟中断
+        let ticks = toy_os::interrupts::irq_counts()[0];
+        while toy_os::interrupts::irq_counts()[0] < ticks + 3 {
+            x86_64::instructions::hlt();
+        }
+        // 同一个句柄读到的仍是打开时的内容
+        first.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(read_to_string(&mut first).await, before);
+        let after = read_to_string(&mut vfs::open("/proc/interrupts").await.unwrap()).await;
+        assert_ne!(after, before);
+        // IRQ0那一行的计数增加了
+        let timer = |text: &str| -> u64 {
+            let line = text.lines().nth(1).unwrap();
+            line.split_whitespace().nth(2).unwrap().parse().unwrap()
+        };
+        assert!(timer(&after) >= timer(&before) + 3);
+
+        assert_eq!(
+            vfs::open("/proc/no-such-file").await.err(),
+            Some(VfsError::NotFound.into())
+        );
+        assert_eq!(
+            vfs::open("/proc/4242/status").await.err(),
+            Some(VfsError::NotFound.into())
+        );
+        let names: Vec<_> = vfs::readdir("/proc")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        for name in ["uptime", "meminfo", "interrupts", "tasks", "version"] {
+            assert!(names.iter().any(|n| n == name), "{} not in /proc", name);
+        }
+    });
+}
+
+#[test_case]
+fn test_cat_proc() {
+    thread::block_on(async {
+        let mut out = String::new();
+        toy_os::shell::dispatch("cat /proc/version /proc/uptime", &mut out)
+            .await
+            .unwrap();
+        let (version, uptime) = out.split_once('\n').unwrap();
+        assert!(version.starts_with(toy_os::version::info().name));
+        assert!(uptime.trim_end().contains('.'));
+        let err = toy_os::shell::dispatch("cat /proc/missing", &mut out)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            toy_os::shell::CmdError::Kernel(VfsError::NotFound.into())
+        );
+        assert_eq!(err.to_string(), "not found");
+    });
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+// 与内核syscall模块中的编号一致
+const SYS_EXIT: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const STDOUT: u64 = 1;
+
+// 用户空间之外、第一页中的地址, 没有映射
+const BAD_POINTER: usize = 8;
+
+unsafe fn syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+fn write(bytes: &[u8]) -> i64 {
+    unsafe { syscall(SYS_WRITE, STDOUT, bytes.as_ptr() as u64, bytes.len() as u64) }
+}
+
+fn exit(code: i64) -> ! {
+    unsafe {
+        syscall(SYS_EXIT, code as u64, 0, 0);
+    }
+    unreachable!("exit returned")
+}
+
+#[no_mangle]
+extern "C" fn _start() -> ! {
+    write(b"crash: writing through a bad pointer\n");
+    // 内核结束这个进程, 不会执行到exit
+    unsafe { (BAD_POINTER as *mut u64).write_volatile(0) };
+    exit(1)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    exit(-1)
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut};
+
+// 与内核syscall模块中的编号一致
+const SYS_EXIT: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_UPTIME_MS: u64 = 2;
+const SYS_FORK: u64 = 3;
+const STDOUT: u64 = 1;
+
+// 跨两页的可写数据, fork之后由子进程改写
+const LEN: usize = 6000;
+static mut BUFFER: [u8; LEN] = [0x5a; LEN];
+
+unsafe fn syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+fn write(bytes: &[u8]) -> i64 {
+    unsafe { syscall(SYS_WRITE, STDOUT, bytes.as_ptr() as u64, bytes.len() as u64) }
+}
+
+fn exit(code: i64) -> ! {
+    unsafe {
+        syscall(SYS_EXIT, code as u64, 0, 0);
+    }
+    unreachable!("exit returned")
+}
+
+fn buffer_is(value: u8) -> bool {
+    (0..LEN).all(|i| unsafe { addr_of!(BUFFER[i]).read_volatile() } == value)
+}
+
+#[no_mangle]
+extern "C" fn _start() -> ! {
+    // 栈上的值也由两边各自保存
+    let marker: u64 = 0x0f0f_0f0f;
+    let pid = unsafe { syscall(SYS_FORK, 0, 0, 0) };
+    if pid < 0 {
+        write(b"fork failed\n");
+        exit(3);
+    }
+    if pid == 0 {
+        for i in 0..LEN {
+            unsafe { addr_of_mut!(BUFFER[i]).write_volatile(0xa5) };
+        }
+        write(b"child wrote its buffer\n");
+        let ok = buffer_is(0xa5) && marker == 0x0f0f_0f0f;
+        exit(if ok { 0 } else { 2 })
+    }
+    // 等子进程运行一段时间, 它的写入不应出现在这里
+    let start = unsafe { syscall(SYS_UPTIME_MS, 0, 0, 0) };
+    while unsafe { syscall(SYS_UPTIME_MS, 0, 0, 0) } < start + 200 {
+        core::hint::spin_loop();
+    }
+    write(b"parent checked its buffer\n");
+    let ok = buffer_is(0x5a) && marker == 0x0f0f_0f0f;
+    exit(if ok { 0 } else { 1 })
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    exit(-1)
+}
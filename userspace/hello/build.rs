@@ -0,0 +1,5 @@
+fn main() {
+    let dir = env!("CARGO_MANIFEST_DIR");
+    println!("cargo:rerun-if-changed=link.ld");
+    println!("cargo:rustc-link-arg=-T{}/link.ld", dir);
+}
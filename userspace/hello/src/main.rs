@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::ptr::addr_of;
+
+// 与内核syscall模块中的编号一致
+const SYS_EXIT: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const STDOUT: u64 = 1;
+
+// 加载器需要清零bss并保留data中的初值
+static mut ZEROED: [u64; 1024] = [0; 1024];
+static mut INITIALIZED: u64 = 0x1234_5678;
+
+unsafe fn syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+fn write(bytes: &[u8]) -> i64 {
+    unsafe { syscall(SYS_WRITE, STDOUT, bytes.as_ptr() as u64, bytes.len() as u64) }
+}
+
+fn exit(code: i64) -> ! {
+    unsafe {
+        syscall(SYS_EXIT, code as u64, 0, 0);
+    }
+    unreachable!("exit returned")
+}
+
+#[no_mangle]
+extern "C" fn _start() -> ! {
+    write(b"hello from an ELF binary\n");
+    let zeroed = (0..1024).all(|i| unsafe { addr_of!(ZEROED[i]).read_volatile() } == 0);
+    let initialized = unsafe { addr_of!(INITIALIZED).read_volatile() } == 0x1234_5678;
+    exit(if zeroed && initialized { 0 } else { 1 })
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    exit(-1)
+}
@@ -0,0 +1,191 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+// 与内核syscall和process模块中的编号一致
+const SYS_EXIT: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_UPTIME_MS: u64 = 2;
+const SYS_FORK: u64 = 3;
+const SYS_MMAP: u64 = 4;
+const SYS_MUNMAP: u64 = 5;
+const SYS_OPEN: u64 = 6;
+const SYS_CLOSE: u64 = 8;
+const SYS_SETRLIMIT: u64 = 9;
+const RLIMIT_PAGES: u64 = 0;
+const RLIMIT_FILES: u64 = 1;
+const RLIMIT_CHILDREN: u64 = 2;
+const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+const EAGAIN: i64 = -11;
+const ENOMEM: i64 = -12;
+const EACCES: i64 = -13;
+const EINVAL: i64 = -22;
+const EMFILE: i64 = -24;
+const STDOUT: u64 = 1;
+
+// 不是初始进程时的退出码
+const NOT_INIT: i64 = 10;
+
+const PAGE_SIZE: u64 = 4096;
+// 包括程序的各段和用户栈
+const PAGE_LIMIT: u64 = 64;
+// 子进程退出前等待的时间, 父进程在这期间再次fork
+const CHILD_MS: i64 = 300;
+
+unsafe fn syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+fn write(bytes: &[u8]) -> i64 {
+    unsafe { syscall(SYS_WRITE, STDOUT, bytes.as_ptr() as u64, bytes.len() as u64) }
+}
+
+fn exit(code: i64) -> ! {
+    unsafe {
+        syscall(SYS_EXIT, code as u64, 0, 0);
+    }
+    unreachable!("exit returned")
+}
+
+fn setrlimit(resource: u64, limit: u64) -> i64 {
+    unsafe { syscall(SYS_SETRLIMIT, resource, limit, 0) }
+}
+
+fn mmap(len: u64) -> i64 {
+    unsafe { syscall(SYS_MMAP, len, PROT_READ | PROT_WRITE, 0) }
+}
+
+fn munmap(addr: u64, len: u64) -> i64 {
+    unsafe { syscall(SYS_MUNMAP, addr, len, 0) }
+}
+
+fn fork() -> i64 {
+    unsafe { syscall(SYS_FORK, 0, 0, 0) }
+}
+
+fn open(path: &[u8]) -> i64 {
+    unsafe { syscall(SYS_OPEN, path.as_ptr() as u64, 0, 0) }
+}
+
+fn sleep_ms(ms: i64) {
+    let start = unsafe { syscall(SYS_UPTIME_MS, 0, 0, 0) };
+    while unsafe { syscall(SYS_UPTIME_MS, 0, 0, 0) } < start + ms {
+        core::hint::spin_loop();
+    }
+}
+
+// 写入一页, 第一次访问时由页错误映射
+fn touch(addr: u64) {
+    unsafe { (addr as *mut u8).write_volatile(0x5a) };
+}
+
+// 逐页mmap并写入, 直到达到页数上限. 返回映射的页数
+fn map_until_limit(pages: &mut [u64]) -> usize {
+    for (count, page) in pages.iter_mut().enumerate() {
+        let addr = mmap(PAGE_SIZE);
+        if addr == ENOMEM {
+            return count;
+        }
+        if addr < 0 {
+            exit(3);
+        }
+        touch(addr as u64);
+        *page = addr as u64;
+    }
+    // 上限没有起作用
+    exit(4)
+}
+
+#[no_mangle]
+extern "C" fn _start() -> ! {
+    let result = setrlimit(RLIMIT_PAGES, PAGE_LIMIT);
+    if result == EACCES {
+        write(b"limittest is not the initial process\n");
+        exit(NOT_INIT);
+    }
+    if result != 0 || setrlimit(99, 0) != EINVAL {
+        exit(1);
+    }
+
+    // 用完页数的额度后mmap返回错误, 而不是耗尽内核的帧
+    let mut pages = [0; PAGE_LIMIT as usize];
+    let mapped = map_until_limit(&mut pages);
+    if mapped == 0 || mmap(1024 * 1024) != ENOMEM {
+        exit(5);
+    }
+    // 取消映射归还额度, 正好可以再映射同样多的页
+    const FREED: usize = 4;
+    for &page in &pages[mapped - FREED..mapped] {
+        if munmap(page, PAGE_SIZE) != 0 {
+            exit(6);
+        }
+    }
+    if map_until_limit(&mut pages[mapped - FREED..]) != FREED {
+        exit(7);
+    }
+    for &page in &pages[mapped - FREED..mapped] {
+        munmap(page, PAGE_SIZE);
+    }
+    write(b"limittest hit its page limit\n");
+
+    // 子进程继承页数和上限, 写时复制不改变页数
+    if setrlimit(RLIMIT_CHILDREN, 1) != 0 {
+        exit(1);
+    }
+    let pid = fork();
+    if pid < 0 {
+        exit(8);
+    }
+    if pid == 0 {
+        for &page in &pages[..mapped - FREED] {
+            touch(page);
+        }
+        let ok = map_until_limit(&mut pages[mapped - FREED..]) == FREED;
+        sleep_ms(CHILD_MS);
+        exit(if ok { 0 } else { 20 })
+    }
+    // 子进程还在运行, 子进程数已达到上限
+    if fork() != EAGAIN {
+        exit(9);
+    }
+    sleep_ms(3 * CHILD_MS);
+    match fork() {
+        0 => exit(0),
+        pid if pid < 0 => exit(11),
+        _ => {}
+    }
+
+    // 打开的文件数达到上限, 关闭一个后又可以打开
+    if setrlimit(RLIMIT_FILES, 2) != 0 {
+        exit(1);
+    }
+    let path = b"/etc/motd\0";
+    let (first, second) = (open(path), open(path));
+    if first < 0 || second < 0 || open(path) != EMFILE {
+        exit(12);
+    }
+    if unsafe { syscall(SYS_CLOSE, first as u64, 0, 0) } != 0 || open(path) < 0 {
+        exit(13);
+    }
+    write(b"limittest hit its file limit\n");
+    exit(0)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    exit(-1)
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+// 与内核syscall和process模块中的编号一致
+const SYS_EXIT: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_MMAP: u64 = 4;
+const SYS_MUNMAP: u64 = 5;
+const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+const EINVAL: i64 = -22;
+const STDOUT: u64 = 1;
+
+const PAGE_SIZE: usize = 4096;
+const LEN: usize = 1024 * 1024;
+
+unsafe fn syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+fn write(bytes: &[u8]) -> i64 {
+    unsafe { syscall(SYS_WRITE, STDOUT, bytes.as_ptr() as u64, bytes.len() as u64) }
+}
+
+fn exit(code: i64) -> ! {
+    unsafe {
+        syscall(SYS_EXIT, code as u64, 0, 0);
+    }
+    unreachable!("exit returned")
+}
+
+fn pattern(i: usize) -> u8 {
+    (i / PAGE_SIZE) as u8 ^ i as u8
+}
+
+#[no_mangle]
+extern "C" fn _start() -> ! {
+    // 可写又可执行的区域被拒绝
+    let prot = PROT_READ | PROT_WRITE;
+    if unsafe { syscall(SYS_MMAP, LEN as u64, prot | PROT_EXEC, 0) } != EINVAL {
+        exit(1);
+    }
+    let addr = unsafe { syscall(SYS_MMAP, LEN as u64, prot, 0) };
+    if addr < 0 {
+        exit(2);
+    }
+    let memory = addr as *mut u8;
+    // 第一次访问每一页时才映射, 内容为0
+    for i in 0..LEN {
+        if unsafe { memory.add(i).read_volatile() } != 0 {
+            exit(3);
+        }
+    }
+    for i in 0..LEN {
+        unsafe { memory.add(i).write_volatile(pattern(i)) };
+    }
+    if (0..LEN).any(|i| unsafe { memory.add(i).read_volatile() } != pattern(i)) {
+        exit(4);
+    }
+    // 不是整页的范围被拒绝
+    if unsafe { syscall(SYS_MUNMAP, addr as u64 + 1, PAGE_SIZE as u64, 0) } != EINVAL {
+        exit(5);
+    }
+    if unsafe { syscall(SYS_MUNMAP, addr as u64, LEN as u64, 0) } != 0 {
+        exit(6);
+    }
+    write(b"mmaptest unmapped its memory\n");
+    // 取消映射后访问是页错误, 由内核报告
+    unsafe { memory.read_volatile() };
+    exit(7)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    exit(-1)
+}